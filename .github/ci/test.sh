@@ -12,7 +12,8 @@ export CARGO_TARGET_DIR=/ci/cache/target
 # used when pointing stm32-metapac to a CI-built one.
 export CARGO_NET_GIT_FETCH_WITH_CLI=true
 
-cargo test --manifest-path ./embassy-executor/Cargo.toml --features metadata-name
+cargo test --manifest-path ./embassy-executor/Cargo.toml --features metadata-name,metadata-size,stack-usage
+cargo test --manifest-path ./embassy-executor/Cargo.toml --features executor-sim --test sim
 cargo test --manifest-path ./embassy-futures/Cargo.toml
 cargo test --manifest-path ./embassy-sync/Cargo.toml
 cargo test --manifest-path ./embassy-embedded-hal/Cargo.toml
@@ -24,6 +25,14 @@ cargo test --manifest-path ./embassy-ptp-driver/Cargo.toml --all-features
 cargo test --manifest-path ./embassy-boot/Cargo.toml
 cargo test --manifest-path ./embassy-boot/Cargo.toml --features ed25519-dalek
 cargo test --manifest-path ./embassy-boot/Cargo.toml --features ed25519-salty
+cargo test --manifest-path ./embassy-boot/Cargo.toml --features encryption
+cargo test --manifest-path ./embassy-boot/Cargo.toml --features encryption,ed25519-dalek
+cargo test --manifest-path ./embassy-boot/Cargo.toml --features overwrite-only
+cargo test --manifest-path ./embassy-boot/Cargo.toml --features direct-xip
+cargo test --manifest-path ./embassy-boot/Cargo.toml --features health
+cargo test --manifest-path ./embassy-boot/Cargo.toml --features multi-image
+cargo test --manifest-path ./embassy-boot/Cargo.toml --features multi-image,ed25519-dalek
+cargo test --manifest-path ./embassy-boot-tool/Cargo.toml
 
 cargo test --manifest-path ./embassy-nrf/Cargo.toml --no-default-features --features nrf52840,time-driver-rtc1,gpiote
 
@@ -38,5 +47,7 @@ cargo test --manifest-path ./embassy-stm32/Cargo.toml --no-default-features --fe
 
 cargo test --manifest-path ./embassy-net-adin1110/Cargo.toml
 cargo test --manifest-path ./embassy-usb-dfu/Cargo.toml --features dfu
+cargo test --manifest-path ./embassy-boot-update/Cargo.toml
 cargo test --manifest-path ./embassy-usb-host/Cargo.toml
+cargo test --manifest-path ./embassy-usb-usbip/Cargo.toml
 cargo test --manifest-path ./embassy-net/Cargo.toml --features tcp,dhcpv4,medium-ethernet,proto-ipv6
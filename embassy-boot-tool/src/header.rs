@@ -0,0 +1,125 @@
+//! Signed image headers for `embassy-boot`.
+//!
+//! See the `embassy_boot::header` module for a description of the format. A signed image is the
+//! header, padding up to the header length, and the image. The application must be linked to
+//! start at the header length into its partition.
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use sha2::{Digest, Sha512};
+
+/// Magic at the start of an image header.
+pub const MAGIC: [u8; 4] = *b"EBH1";
+
+/// Length of the header fields, the minimum header length.
+pub const HEADER_LEN: usize = 160;
+
+/// Length of the header fields covered by the signature.
+const SIGNED_LEN: usize = 96;
+
+/// Header fields chosen when signing an image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Options {
+    /// Length of the header including padding, where the application starts.
+    pub header_len: u16,
+    /// Application-defined flags.
+    pub flags: u16,
+    /// Version of the image.
+    pub version: u32,
+    /// Security counter of the image, the device refuses images with a lower one.
+    pub security_counter: u32,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            header_len: 256,
+            flags: 0,
+            version: 0,
+            security_counter: 0,
+        }
+    }
+}
+
+/// Prepend a header to `image`, signed with `key`.
+pub fn sign(image: &[u8], key: &SigningKey, options: &Options) -> Vec<u8> {
+    assert!(options.header_len as usize >= HEADER_LEN, "header too short");
+    let image_len = u32::try_from(image.len()).expect("image too large");
+
+    let mut header = [0; HEADER_LEN];
+    header[..4].copy_from_slice(&MAGIC);
+    header[4..6].copy_from_slice(&options.header_len.to_le_bytes());
+    header[6..8].copy_from_slice(&options.flags.to_le_bytes());
+    header[8..12].copy_from_slice(&image_len.to_le_bytes());
+    header[12..16].copy_from_slice(&options.version.to_le_bytes());
+    header[16..20].copy_from_slice(&options.security_counter.to_le_bytes());
+    header[32..96].copy_from_slice(&Sha512::digest(image));
+
+    let signature = key.sign(&Sha512::digest(&header[..SIGNED_LEN]));
+    header[SIGNED_LEN..].copy_from_slice(&signature.to_bytes());
+
+    let mut out = Vec::with_capacity(options.header_len as usize + image.len());
+    out.extend_from_slice(&header);
+    // Padding isn't covered by the signature, use the erased flash value.
+    out.resize(options.header_len as usize, 0xFF);
+    out.extend_from_slice(image);
+    out
+}
+
+/// Check the header of a signed image, returning its fields.
+pub fn verify(signed: &[u8], public_key: &VerifyingKey) -> Result<Options, &'static str> {
+    let header = signed.get(..HEADER_LEN).ok_or("truncated header")?;
+    if header[..4] != MAGIC {
+        return Err("bad magic");
+    }
+    if header[20..32].iter().any(|&b| b != 0) {
+        return Err("reserved fields aren't zero");
+    }
+
+    let u32_at = |offset: usize| u32::from_le_bytes(header[offset..offset + 4].try_into().unwrap());
+    let options = Options {
+        header_len: u16::from_le_bytes([header[4], header[5]]),
+        flags: u16::from_le_bytes([header[6], header[7]]),
+        version: u32_at(12),
+        security_counter: u32_at(16),
+    };
+
+    let signature = Signature::from_bytes(header[SIGNED_LEN..].try_into().unwrap());
+    public_key
+        .verify(&Sha512::digest(&header[..SIGNED_LEN]), &signature)
+        .map_err(|_| "bad signature")?;
+
+    let image = (options.header_len as usize)
+        .checked_add(u32_at(8) as usize)
+        .and_then(|end| signed.get(options.header_len as usize..end))
+        .filter(|_| options.header_len as usize >= HEADER_LEN)
+        .ok_or("bad length")?;
+    if Sha512::digest(image)[..] != header[32..96] {
+        return Err("bad hash");
+    }
+    Ok(options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_verify() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let options = Options {
+            version: 0x0102_0003,
+            security_counter: 2,
+            ..Default::default()
+        };
+
+        let mut signed = sign(&[0xAA; 1000], &key, &options);
+        assert_eq!(signed.len(), 256 + 1000);
+        assert_eq!(verify(&signed, &key.verifying_key()), Ok(options));
+
+        let other = SigningKey::from_bytes(&[8; 32]);
+        assert_eq!(verify(&signed, &other.verifying_key()), Err("bad signature"));
+
+        signed[500] ^= 1;
+        assert_eq!(verify(&signed, &key.verifying_key()), Err("bad hash"));
+    }
+}
@@ -0,0 +1,6 @@
+#![doc = include_str!("../README.md")]
+#![warn(missing_docs)]
+
+pub mod header;
+pub mod package;
+pub mod patch;
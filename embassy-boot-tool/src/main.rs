@@ -0,0 +1,188 @@
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use clap::{Parser, Subcommand};
+use ed25519_dalek::SigningKey;
+use embassy_boot_tool::{header, package, patch};
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Compress a firmware image, or generate a delta against a base image.
+    Patch {
+        /// The new firmware image.
+        image: PathBuf,
+        /// The firmware the device is running, to generate a delta against.
+        #[arg(long)]
+        base: Option<PathBuf>,
+        /// Where to write the patch.
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Generate a signing key, and print its public key.
+    Keygen {
+        /// Where to write the private key.
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Print the public key of a signing key, to embed in the bootloader.
+    Pubkey {
+        /// The private key.
+        key: PathBuf,
+    },
+    /// Prepend a signed header to a firmware image.
+    Sign {
+        /// The firmware image, linked to start at the header length into its partition.
+        image: PathBuf,
+        /// The private key.
+        #[arg(short, long)]
+        key: PathBuf,
+        /// Version of the image.
+        #[arg(long, default_value_t = 0)]
+        version: u32,
+        /// Security counter of the image. Raise it to prevent downgrades to older images.
+        #[arg(long, default_value_t = 0)]
+        security_counter: u32,
+        /// Length of the header including padding, must satisfy the vector table alignment.
+        #[arg(long, default_value_t = 256)]
+        header_len: u16,
+        /// Application-defined flags.
+        #[arg(long, default_value_t = 0)]
+        flags: u16,
+        /// Where to write the signed image.
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Bundle the images of several cores into a multi-image update package.
+    Package {
+        /// An image, as `<ID>:<VERSION>:<PATH>`.
+        #[arg(long = "image", required = true)]
+        images: Vec<String>,
+        /// A dependency between images, as `<ID>:<DEPENDENCY ID>:<MIN VERSION>`.
+        #[arg(long = "depends")]
+        depends: Vec<String>,
+        /// Where to write the package.
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+}
+
+fn read(path: &PathBuf) -> anyhow::Result<Vec<u8>> {
+    fs::read(path).with_context(|| format!("reading {}", path.display()))
+}
+
+fn write(path: &PathBuf, data: &[u8]) -> anyhow::Result<()> {
+    fs::write(path, data).with_context(|| format!("writing {}", path.display()))
+}
+
+fn read_key(path: &PathBuf) -> anyhow::Result<SigningKey> {
+    let key = read(path)?;
+    let key = key
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("{} isn't a 32 byte key", path.display()))?;
+    Ok(SigningKey::from_bytes(&key))
+}
+
+/// Parse `<A>:<B>:<C>` into its parts.
+fn parse_triple(arg: &str) -> anyhow::Result<(&str, &str, &str)> {
+    let mut parts = arg.splitn(3, ':');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(a), Some(b), Some(c)) => Ok((a, b, c)),
+        _ => anyhow::bail!("expected three `:`-separated values in {arg}"),
+    }
+}
+
+fn print_public_key(key: &SigningKey) {
+    let bytes: Vec<_> = key
+        .verifying_key()
+        .to_bytes()
+        .iter()
+        .map(|b| format!("0x{b:02x}"))
+        .collect();
+    println!("[{}]", bytes.join(", "));
+}
+
+fn main() -> anyhow::Result<()> {
+    match Cli::parse().command {
+        Command::Patch { image, base, output } => {
+            let image = read(&image)?;
+            let base = base.as_ref().map(read).transpose()?;
+
+            let encoded = patch::encode(base.as_deref(), &image);
+            // Never ship a patch that doesn't decode back to the image.
+            let decoded = patch::decode(base.as_deref(), &encoded).map_err(anyhow::Error::msg)?;
+            anyhow::ensure!(decoded == image, "patch doesn't decode to the image");
+
+            write(&output, &encoded)?;
+            println!(
+                "{} bytes -> {} bytes ({:.1}%)",
+                image.len(),
+                encoded.len(),
+                encoded.len() as f64 * 100.0 / image.len().max(1) as f64
+            );
+        }
+        Command::Keygen { output } => {
+            let key = SigningKey::from_bytes(&rand::random());
+            write(&output, &key.to_bytes())?;
+            print_public_key(&key);
+        }
+        Command::Pubkey { key } => print_public_key(&read_key(&key)?),
+        Command::Sign {
+            image,
+            key,
+            version,
+            security_counter,
+            header_len,
+            flags,
+            output,
+        } => {
+            anyhow::ensure!(header_len as usize >= header::HEADER_LEN, "header length is too short");
+            let options = header::Options {
+                header_len,
+                flags,
+                version,
+                security_counter,
+            };
+            let signed = header::sign(&read(&image)?, &read_key(&key)?, &options);
+            write(&output, &signed)?;
+        }
+        Command::Package {
+            images,
+            depends,
+            output,
+        } => {
+            let mut images = images
+                .iter()
+                .map(|arg| {
+                    let (id, version, path) = parse_triple(arg)?;
+                    Ok(package::Image {
+                        id: id.parse()?,
+                        version: version.parse()?,
+                        depends: None,
+                        data: read(&PathBuf::from(path))?,
+                    })
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            for arg in &depends {
+                let (id, dependency, min_version) = parse_triple(arg)?;
+                let id: u8 = id.parse()?;
+                let image = images
+                    .iter_mut()
+                    .find(|image| image.id == id)
+                    .with_context(|| format!("no image {id} in the package"))?;
+                image.depends = Some((dependency.parse()?, min_version.parse()?));
+            }
+
+            let package = package::build(&images).map_err(anyhow::Error::msg)?;
+            write(&output, &package)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,119 @@
+//! Builder for `embassy-boot` multi-image update packages.
+//!
+//! See the `embassy_boot::multi_image` module for a description of the format. A package bundles
+//! the images of several cores or subsystems, which the device writes to their DFU partitions and
+//! swaps in together.
+
+/// Magic at the start of a package.
+pub const MAGIC: [u8; 4] = *b"EBMP";
+
+/// Version of the package format.
+pub const FORMAT_VERSION: u8 = 1;
+
+/// Alignment of the header and of each image in the package.
+pub const ALIGN: usize = 64;
+
+/// Maximum number of images in a package.
+pub const MAX_IMAGES: usize = 8;
+
+const HEADER_LEN: usize = 8;
+const ENTRY_LEN: usize = 16;
+const NO_DEPENDENCY: u8 = 0xFF;
+
+/// An image to put in a package.
+#[derive(Debug, Clone)]
+pub struct Image {
+    /// Index of the image on the device.
+    pub id: u8,
+    /// Version of the image.
+    pub version: u32,
+    /// Image this one depends on, and its minimum version.
+    pub depends: Option<(u8, u32)>,
+    /// The image, as written to its DFU partition.
+    pub data: Vec<u8>,
+}
+
+/// Build a package holding `images`.
+///
+/// Fails if there are too many images, if an image appears twice, or depends on itself.
+pub fn build(images: &[Image]) -> Result<Vec<u8>, &'static str> {
+    if images.is_empty() || images.len() > MAX_IMAGES {
+        return Err("a package holds 1 to 8 images");
+    }
+    for (i, image) in images.iter().enumerate() {
+        if usize::from(image.id) >= MAX_IMAGES {
+            return Err("image id out of range");
+        }
+        if images[..i].iter().any(|other| other.id == image.id) {
+            return Err("duplicate image id");
+        }
+        if image.depends.is_some_and(|(id, _)| id == image.id) {
+            return Err("image depends on itself");
+        }
+    }
+
+    let mut out = Vec::new();
+    out.extend_from_slice(&MAGIC);
+    out.push(FORMAT_VERSION);
+    out.push(images.len() as u8);
+    out.extend_from_slice(&[0; 2]);
+    for image in images {
+        let (dependency, min_version) = image.depends.unwrap_or((NO_DEPENDENCY, 0));
+        let len = u32::try_from(image.data.len()).map_err(|_| "image too large")?;
+        out.push(image.id);
+        out.push(dependency);
+        out.extend_from_slice(&[0; 2]);
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&image.version.to_le_bytes());
+        out.extend_from_slice(&min_version.to_le_bytes());
+    }
+    debug_assert_eq!(out.len(), HEADER_LEN + images.len() * ENTRY_LEN);
+    pad(&mut out);
+
+    for image in images {
+        out.extend_from_slice(&image.data);
+        pad(&mut out);
+    }
+    Ok(out)
+}
+
+/// Pad `out` with erased bytes up to the package alignment.
+fn pad(out: &mut Vec<u8>) {
+    out.resize(out.len().next_multiple_of(ALIGN), 0xFF);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build() {
+        let images = [
+            Image {
+                id: 0,
+                version: 3,
+                depends: Some((1, 2)),
+                data: vec![0xAA; 100],
+            },
+            Image {
+                id: 1,
+                version: 2,
+                depends: None,
+                data: vec![0x55; 64],
+            },
+        ];
+        let package = build(&images).unwrap();
+        assert_eq!(package.len(), 64 + 128 + 64);
+        assert_eq!(package[..8], [b'E', b'B', b'M', b'P', 1, 2, 0, 0]);
+        assert_eq!(package[8..24], [0, 1, 0, 0, 100, 0, 0, 0, 3, 0, 0, 0, 2, 0, 0, 0]);
+        assert_eq!(package[24..28], [1, 0xFF, 0, 0]);
+        assert!(package[40..64].iter().all(|&b| b == 0xFF));
+        assert_eq!(package[64..164], [0xAA; 100]);
+        assert_eq!(package[192..], [0x55; 64]);
+
+        let mut duplicate = images.clone();
+        duplicate[1].id = 0;
+        assert!(build(&duplicate).is_err());
+        assert!(build(&[]).is_err());
+    }
+}
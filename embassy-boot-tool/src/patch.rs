@@ -0,0 +1,299 @@
+//! Encoder for the `embassy-boot` patch format.
+//!
+//! See the `embassy_boot::patch` module for a description of the format. The encoder is a greedy
+//! LZ77 matcher that looks for repeated data both in the image itself and in the base image, if
+//! any. Finding matches in the base is what makes deltas small: most of a new firmware image is
+//! usually identical to the old one, only moved around.
+
+use std::collections::HashMap;
+
+/// Magic at the start of a patch stream.
+pub const MAGIC: [u8; 4] = *b"EBP1";
+
+const OP_LITERAL: u8 = 0x01;
+const OP_COPY: u8 = 0x02;
+const OP_BASE: u8 = 0x03;
+
+/// Length of the sequences used to find match candidates.
+const KEY_LEN: usize = 4;
+/// Maximum number of candidates checked per key, the most recent ones first.
+const MAX_CANDIDATES: usize = 64;
+
+/// Encode `image` as a patch, optionally against `base`.
+///
+/// Without a base, the patch is a compressed form of the image, which the device writes with
+/// `FirmwareUpdater::write_patch`. With a base, it must be written with
+/// `FirmwareUpdater::write_delta`, passing a flash containing exactly `base`.
+pub fn encode(base: Option<&[u8]>, image: &[u8]) -> Vec<u8> {
+    let len = u32::try_from(image.len()).expect("image too large");
+
+    let mut out = Vec::with_capacity(image.len() / 2);
+    out.extend_from_slice(&MAGIC);
+    out.extend_from_slice(&len.to_le_bytes());
+
+    let base = base.unwrap_or(&[]);
+    let base_index = Index::new(base);
+    let mut image_index = Index::default();
+
+    let mut literal_start = 0;
+    let mut pos = 0;
+    // Where the last base match ended: the next one is often right after.
+    let mut base_cursor = None;
+
+    while pos < image.len() {
+        let mut best = Match::default();
+
+        if let Some(cursor) = base_cursor {
+            best = best.max(Match::base(
+                cursor,
+                common_len(&base[cursor.min(base.len())..], &image[pos..]),
+            ));
+        }
+        if let Some(key) = key(image, pos) {
+            for &candidate in base_index.candidates(key) {
+                best = best.max(Match::base(candidate, common_len(&base[candidate..], &image[pos..])));
+            }
+            for &candidate in image_index.candidates(key) {
+                // The source may overlap the bytes being encoded.
+                let len = (0..image.len() - pos)
+                    .take_while(|&i| image[candidate + i] == image[pos + i])
+                    .count();
+                best = best.max(Match::copy(pos - candidate, len));
+            }
+        }
+
+        if best.saves() {
+            emit_literal(&mut out, &image[literal_start..pos]);
+            if best.op == OP_BASE {
+                base_cursor = Some(best.src + best.len);
+            }
+            best.emit(&mut out);
+            for p in pos..pos + best.len {
+                image_index.insert(image, p);
+            }
+            pos += best.len;
+            literal_start = pos;
+        } else {
+            image_index.insert(image, pos);
+            base_cursor = base_cursor.map(|cursor| cursor + 1);
+            pos += 1;
+        }
+    }
+    emit_literal(&mut out, &image[literal_start..]);
+
+    out
+}
+
+/// Positions of the sequences of [`KEY_LEN`] bytes in some data.
+#[derive(Default)]
+struct Index {
+    positions: HashMap<[u8; KEY_LEN], Vec<usize>>,
+}
+
+impl Index {
+    fn new(data: &[u8]) -> Self {
+        let mut index = Self::default();
+        for pos in 0..data.len() {
+            index.insert(data, pos);
+        }
+        index
+    }
+
+    fn insert(&mut self, data: &[u8], pos: usize) {
+        if let Some(key) = key(data, pos) {
+            self.positions.entry(key).or_default().push(pos);
+        }
+    }
+
+    fn candidates(&self, key: [u8; KEY_LEN]) -> impl Iterator<Item = &usize> {
+        self.positions
+            .get(&key)
+            .into_iter()
+            .flat_map(|positions| positions.iter().rev().take(MAX_CANDIDATES))
+    }
+}
+
+fn key(data: &[u8], pos: usize) -> Option<[u8; KEY_LEN]> {
+    data.get(pos..pos + KEY_LEN).map(|key| key.try_into().unwrap())
+}
+
+fn common_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+#[derive(Default, Clone, Copy)]
+struct Match {
+    op: u8,
+    /// Distance back for copies, offset in the base for base copies.
+    src: usize,
+    len: usize,
+}
+
+impl Match {
+    fn copy(distance: usize, len: usize) -> Self {
+        Self {
+            op: OP_COPY,
+            src: distance,
+            len,
+        }
+    }
+
+    fn base(offset: usize, len: usize) -> Self {
+        Self {
+            op: OP_BASE,
+            src: offset,
+            len,
+        }
+    }
+
+    fn cost(&self) -> usize {
+        1 + varint_len(self.src) + varint_len(self.len)
+    }
+
+    /// Whether encoding this match is shorter than encoding its bytes as a literal.
+    fn saves(&self) -> bool {
+        // Breaking a literal run costs another literal header later on.
+        self.len > self.cost() + 2
+    }
+
+    /// The match saving the most bytes.
+    fn max(self, other: Self) -> Self {
+        let saved = |m: &Self| m.len as isize - m.cost() as isize;
+        if saved(&other) > saved(&self) { other } else { self }
+    }
+
+    fn emit(&self, out: &mut Vec<u8>) {
+        out.push(self.op);
+        write_varint(out, self.src);
+        write_varint(out, self.len);
+    }
+}
+
+fn emit_literal(out: &mut Vec<u8>, data: &[u8]) {
+    if !data.is_empty() {
+        out.push(OP_LITERAL);
+        write_varint(out, data.len());
+        out.extend_from_slice(data);
+    }
+}
+
+fn varint_len(value: usize) -> usize {
+    (usize::BITS - value.leading_zeros()).max(1).div_ceil(7) as usize
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// Decode a patch in memory.
+///
+/// This is mostly useful to check patches on the host, devices decode them with `embassy-boot`.
+pub fn decode(base: Option<&[u8]>, patch: &[u8]) -> Result<Vec<u8>, &'static str> {
+    let header = patch.get(..8).ok_or("truncated header")?;
+    if header[..4] != MAGIC {
+        return Err("bad magic");
+    }
+    let len = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+    let base = base.unwrap_or(&[]);
+
+    let mut input = &patch[8..];
+    let mut image = Vec::with_capacity(len);
+    while image.len() < len {
+        let op = read_byte(&mut input)?;
+        let first = read_varint(&mut input)?;
+        match op {
+            OP_LITERAL => {
+                let data = input.get(..first).ok_or("truncated literal")?;
+                image.extend_from_slice(data);
+                input = &input[first..];
+            }
+            OP_COPY => {
+                let len = read_varint(&mut input)?;
+                let start = image
+                    .len()
+                    .checked_sub(first)
+                    .filter(|_| first > 0)
+                    .ok_or("copy out of bounds")?;
+                for i in start..start + len {
+                    image.push(image[i]);
+                }
+            }
+            OP_BASE => {
+                let len = read_varint(&mut input)?;
+                image.extend_from_slice(base.get(first..first + len).ok_or("base copy out of bounds")?);
+            }
+            _ => return Err("unknown operation"),
+        }
+    }
+
+    if image.len() != len || !input.is_empty() {
+        return Err("length mismatch");
+    }
+    Ok(image)
+}
+
+fn read_byte(input: &mut &[u8]) -> Result<u8, &'static str> {
+    let (&byte, rest) = input.split_first().ok_or("truncated operation")?;
+    *input = rest;
+    Ok(byte)
+}
+
+fn read_varint(input: &mut &[u8]) -> Result<usize, &'static str> {
+    let mut value = 0;
+    for shift in (0..32).step_by(7) {
+        let byte = read_byte(input)?;
+        value |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err("varint too long")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(seed: u32, len: usize) -> Vec<u8> {
+        // Something that compresses a bit, like code.
+        let mut state = seed;
+        (0..len)
+            .map(|i| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                if i % 3 == 0 { 0 } else { (state >> 24) as u8 % 16 }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_compress_roundtrip() {
+        for image in [vec![], vec![0xAA; 1], vec![0xFF; 10_000], image(1, 10_000)] {
+            let patch = encode(None, &image);
+            assert_eq!(decode(None, &patch).unwrap(), image);
+        }
+
+        let patch = encode(None, &[0xFF; 10_000]);
+        assert!(patch.len() < 20);
+    }
+
+    #[test]
+    fn test_delta_roundtrip() {
+        let base = image(1, 20_000);
+        let mut new = base.clone();
+        // Insert some code, shifting the rest, and change a few bytes.
+        new.splice(5_000..5_000, image(2, 300));
+        new[15_000..15_010].fill(0x42);
+
+        let patch = encode(Some(&base), &new);
+        assert_eq!(decode(Some(&base), &patch).unwrap(), new);
+        assert!(patch.len() < 1_000, "patch is {} bytes", patch.len());
+    }
+}
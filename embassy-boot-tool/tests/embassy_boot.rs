@@ -0,0 +1,189 @@
+//! Round-trips of the host formats through `embassy-boot`.
+
+use ed25519_dalek::SigningKey;
+use embassy_boot::{BlockingFirmwareUpdater, FirmwareUpdaterConfig, PackageDecoder, PatchDecoder};
+use embassy_boot_tool::{header, package, patch};
+use embedded_storage::nor_flash::{ErrorType, NorFlash, NorFlashErrorKind, ReadNorFlash};
+use sha2::{Digest, Sha512};
+
+/// Flash in memory, erased to `0xFF`.
+struct MemFlash(Vec<u8>);
+
+impl MemFlash {
+    fn new(len: usize) -> Self {
+        Self(vec![0xFF; len])
+    }
+}
+
+impl ErrorType for MemFlash {
+    type Error = NorFlashErrorKind;
+}
+
+impl ReadNorFlash for MemFlash {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        let data = self
+            .0
+            .get(offset as usize..offset as usize + bytes.len())
+            .ok_or(NorFlashErrorKind::OutOfBounds)?;
+        bytes.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.0.len()
+    }
+}
+
+impl NorFlash for MemFlash {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = 4096;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.0
+            .get_mut(from as usize..to as usize)
+            .ok_or(NorFlashErrorKind::OutOfBounds)?
+            .fill(0xFF);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.0
+            .get_mut(offset as usize..offset as usize + bytes.len())
+            .ok_or(NorFlashErrorKind::OutOfBounds)?
+            .copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+fn updater(aligned: &mut [u8]) -> BlockingFirmwareUpdater<'_, MemFlash, MemFlash> {
+    let mut updater = BlockingFirmwareUpdater::new(
+        FirmwareUpdaterConfig {
+            dfu: MemFlash::new(65536),
+            state: MemFlash::new(4096),
+        },
+        aligned,
+    );
+    updater.mark_booted().unwrap();
+    updater
+}
+
+fn assert_dfu_holds(updater: &mut BlockingFirmwareUpdater<'_, MemFlash, MemFlash>, image: &[u8]) {
+    let mut chunk_buf = [0; 256];
+    let mut hash = [0; 64];
+    updater
+        .hash::<Sha512>(image.len() as u32, &mut chunk_buf, &mut hash)
+        .unwrap();
+    assert_eq!(Sha512::digest(image)[..], hash);
+}
+
+fn test_image(seed: u32, len: usize) -> Vec<u8> {
+    let mut state = seed;
+    (0..len)
+        .map(|i| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            if i % 3 == 0 { 0 } else { (state >> 24) as u8 % 16 }
+        })
+        .collect()
+}
+
+#[test]
+fn compressed_patch() {
+    let mut image = test_image(1, 20_003);
+    image[5_000..9_000].fill(0xAA);
+    let encoded = patch::encode(None, &image);
+    assert!(encoded.len() < image.len());
+
+    let mut aligned = [0; 4];
+    let mut updater = updater(&mut aligned);
+    let mut buf = [0; 24];
+    let mut decoder = PatchDecoder::new(&mut buf);
+    for chunk in encoded.chunks(37) {
+        updater.write_patch(&mut decoder, chunk).unwrap();
+    }
+    assert!(decoder.is_done());
+    assert_eq!(decoder.image_len(), Some(image.len() as u32));
+    assert_dfu_holds(&mut updater, &image);
+}
+
+#[test]
+fn delta_patch() {
+    let base = test_image(1, 30_000);
+    let mut image = base.clone();
+    image.splice(10_000..10_000, test_image(2, 500));
+    image[20_000..20_100].fill(0x42);
+    let encoded = patch::encode(Some(&base), &image);
+    assert!(encoded.len() < 2_000);
+
+    let mut active = MemFlash::new(32768);
+    active.write(0, &base).unwrap();
+
+    let mut aligned = [0; 4];
+    let mut updater = updater(&mut aligned);
+    let mut buf = [0; 64];
+    let mut decoder = PatchDecoder::new(&mut buf);
+    for chunk in encoded.chunks(100) {
+        updater.write_delta(&mut decoder, &mut active, chunk).unwrap();
+    }
+    assert!(decoder.is_done());
+    assert_dfu_holds(&mut updater, &image);
+}
+
+#[test]
+fn signed_image() {
+    let key = SigningKey::from_bytes(&[1; 32]);
+    let public_key = key.verifying_key().to_bytes();
+    let options = header::Options {
+        version: 2,
+        security_counter: 1,
+        ..Default::default()
+    };
+    let signed = header::sign(&[0x5A; 5000], &key, &options);
+
+    let mut aligned = [0; 4];
+    let mut updater = updater(&mut aligned);
+    updater.write_firmware(0, &signed).unwrap();
+    updater.verify_image_and_mark_updated(&public_key).unwrap();
+
+    let mut tampered = signed.clone();
+    tampered[1000] ^= 1;
+    let mut aligned = [0; 4];
+    let mut updater = self::updater(&mut aligned);
+    updater.write_firmware(0, &tampered).unwrap();
+    assert!(updater.verify_image_and_mark_updated(&public_key).is_err());
+}
+
+#[test]
+fn multi_image_package() {
+    let images = [
+        package::Image {
+            id: 0,
+            version: 3,
+            depends: Some((1, 2)),
+            data: vec![0xAA; 5000],
+        },
+        package::Image {
+            id: 1,
+            version: 2,
+            depends: None,
+            data: vec![0xBB; 100],
+        },
+    ];
+    let built = package::build(&images).unwrap();
+
+    let mut decoder = PackageDecoder::new(&[1, 1]);
+    let mut decoded = [Vec::new(), Vec::new()];
+    let mut data = &built[..];
+    while let Some(segment) = decoder.next(&mut data).unwrap() {
+        assert_eq!(segment.offset, decoded[segment.image].len());
+        decoded[segment.image].extend_from_slice(segment.data);
+    }
+    assert!(decoder.is_done());
+    for (image, decoded) in images.iter().zip(decoded) {
+        let id = image.id as usize;
+        assert_eq!(decoder.image_version(id), Some(image.version));
+        assert_eq!(decoder.image_len(id), Some(image.data.len() as u32));
+        assert_eq!(decoded[..image.data.len()], image.data[..]);
+    }
+}
@@ -0,0 +1,161 @@
+use embedded_io_async::{Read, Write};
+
+use crate::Error;
+use crate::protocol::{Command, RESPONSE, Report, SIGNATURE_LEN, Status, crc32, read_frame, u32_at, write_frame};
+
+/// Number of times a request is sent when a frame is corrupted.
+const RETRIES: usize = 3;
+
+/// Host side of the update protocol.
+///
+/// This is `no_std`, so it can run on another microcontroller, or on a host with an
+/// `embedded-io-async` adapter for its serial port or socket. It doesn't time out by itself, the
+/// caller should wrap requests in a timeout and reconnect when it expires.
+pub struct UpdateClient<T> {
+    transport: T,
+    buf: [u8; 32],
+    tag: u8,
+}
+
+impl<T: Read + Write> UpdateClient<T> {
+    /// Create a client talking to a device over `transport`.
+    pub fn new(transport: T) -> Self {
+        Self {
+            transport,
+            buf: [0; 32],
+            tag: 0,
+        }
+    }
+
+    /// Release the transport.
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+
+    /// Query the progress of the update.
+    pub async fn status(&mut self) -> Result<Report, Error<T::Error>> {
+        self.request(Command::Status, &[]).await
+    }
+
+    /// Start a new update of `total` bytes.
+    pub async fn start(&mut self, total: u32) -> Result<Report, Error<T::Error>> {
+        self.request(Command::Start, &[&total.to_le_bytes()]).await
+    }
+
+    /// Continue an update of `total` bytes, whose first `offset` bytes are already written.
+    ///
+    /// The device may continue at a lower offset, returned in the report.
+    pub async fn resume(&mut self, total: u32, offset: u32) -> Result<Report, Error<T::Error>> {
+        self.request(Command::Resume, &[&total.to_le_bytes(), &offset.to_le_bytes()])
+            .await
+    }
+
+    /// Write `data` at `offset`.
+    pub async fn write(&mut self, offset: u32, data: &[u8]) -> Result<Report, Error<T::Error>> {
+        self.request(Command::Write, &[&offset.to_le_bytes(), data]).await
+    }
+
+    /// Compute the CRC-32 of `len` bytes of the DFU partition, starting at `offset`.
+    pub async fn checksum(&mut self, offset: u32, len: u32) -> Result<u32, Error<T::Error>> {
+        let payload = self
+            .exchange(Command::Checksum, &[&offset.to_le_bytes(), &len.to_le_bytes()])
+            .await?;
+        if payload.len() != 5 {
+            return Err(Error::Protocol);
+        }
+        match Status::try_from(payload[0]).map_err(|_| Error::Protocol)? {
+            Status::Ok => Ok(u32_at(payload, 1)),
+            status => Err(Error::Device(status)),
+        }
+    }
+
+    /// Verify the update and mark it to be swapped in on next boot.
+    ///
+    /// Without a `signature`, a device verifying updates checks the signed image header.
+    pub async fn finish(&mut self, signature: Option<&[u8; SIGNATURE_LEN]>) -> Result<Report, Error<T::Error>> {
+        match signature {
+            Some(signature) => self.request(Command::Finish, &[signature]).await,
+            None => self.request(Command::Finish, &[]).await,
+        }
+    }
+
+    /// Write `image` to the device and mark it as updated.
+    ///
+    /// If the device already holds part of the image, from an interrupted update, only the rest is
+    /// written. Calling this again after an error continues the update.
+    pub async fn update(
+        &mut self,
+        image: &[u8],
+        signature: Option<&[u8; SIGNATURE_LEN]>,
+    ) -> Result<(), Error<T::Error>> {
+        let total = image.len() as u32;
+        let mut report = self.status().await?;
+        if report.total != total || report.offset == 0 {
+            let offset = self.written(image, report.erase_size).await?;
+            report = match offset {
+                0 => self.start(total).await?,
+                offset => self.resume(total, offset).await?,
+            };
+        }
+
+        while report.status == Status::Ok || report.status == Status::BadOffset {
+            let offset = report.offset as usize;
+            if offset == image.len() {
+                break;
+            }
+            let len = report.max_chunk.min(total - report.offset) as usize;
+            report = self.write(report.offset, &image[offset..offset + len]).await?;
+        }
+        check(report.status)?;
+
+        check(self.finish(signature).await?.status)
+    }
+
+    /// Find how many bytes of `image` the DFU partition already holds, in whole erase blocks.
+    async fn written(&mut self, image: &[u8], erase_size: u32) -> Result<u32, Error<T::Error>> {
+        if erase_size == 0 {
+            return Err(Error::Protocol);
+        }
+        let mut offset = 0;
+        for block in image.chunks_exact(erase_size as usize) {
+            if self.checksum(offset, erase_size).await? != crc32(block) {
+                break;
+            }
+            offset += erase_size;
+        }
+        Ok(offset)
+    }
+
+    async fn request(&mut self, command: Command, payload: &[&[u8]]) -> Result<Report, Error<T::Error>> {
+        let payload = self.exchange(command, payload).await?;
+        Report::parse(payload).ok_or(Error::Protocol)
+    }
+
+    /// Send a request and return the payload of the response.
+    async fn exchange(&mut self, command: Command, payload: &[&[u8]]) -> Result<&[u8], Error<T::Error>> {
+        self.tag = self.tag.wrapping_add(1);
+        for _ in 0..RETRIES {
+            write_frame(&mut self.transport, command as u8, self.tag, payload).await?;
+            loop {
+                match read_frame(&mut self.transport, &mut self.buf).await? {
+                    // The request or the response was corrupt, send the request again.
+                    None | Some((RESPONSE, _, _)) => break,
+                    // Response to an earlier, retransmitted request.
+                    Some((_, tag, _)) if tag != self.tag => continue,
+                    Some((response, _, len)) if response == command as u8 | RESPONSE => {
+                        return Ok(&self.buf[..len]);
+                    }
+                    Some(_) => return Err(Error::Protocol),
+                }
+            }
+        }
+        Err(Error::Device(Status::BadFrame))
+    }
+}
+
+fn check<E>(status: Status) -> Result<(), Error<E>> {
+    match status {
+        Status::Ok => Ok(()),
+        status => Err(Error::Device(status)),
+    }
+}
@@ -0,0 +1,31 @@
+#![no_std]
+#![doc = include_str!("../README.md")]
+#![warn(missing_docs)]
+mod fmt;
+
+mod client;
+pub mod protocol;
+mod server;
+#[cfg(feature = "embassy-net")]
+mod tcp;
+
+pub use client::UpdateClient;
+pub use server::UpdateServer;
+#[cfg(feature = "embassy-net")]
+pub use tcp::serve_tcp;
+
+use crate::protocol::Status;
+
+/// Errors of the update protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error<E> {
+    /// Error from the transport.
+    Io(E),
+    /// The transport was closed by the peer.
+    Disconnected,
+    /// The device refused a request.
+    Device(Status),
+    /// The device sent an unexpected response.
+    Protocol,
+}
@@ -0,0 +1,292 @@
+//! Wire format of the update protocol.
+//!
+//! Requests and responses are sent as frames, with all integers little-endian:
+//!
+//! | Field   | Size | Description                                              |
+//! |---------|------|----------------------------------------------------------|
+//! | Sync    | 2    | [`SYNC`]                                                 |
+//! | Command | 1    | [`Command`], with [`RESPONSE`] set in responses          |
+//! | Tag     | 1    | Chosen by the host, and repeated in the response         |
+//! | Length  | 2    | Length of the payload                                    |
+//! | Payload | n    | Command-specific                                         |
+//! | CRC     | 4    | CRC-32 (IEEE) of the command, tag, length and payload    |
+//!
+//! Frames with a bad CRC are dropped, and the receiver looks for the next [`SYNC`]. The device
+//! answers them with a [`Status::BadFrame`] report, using command `RESPONSE`, so the host can
+//! retransmit without waiting for a timeout. The host uses a new tag for each request, to tell
+//! the response apart from the responses to retransmitted requests.
+//!
+//! | Command                | Request payload                       | Response payload     |
+//! |------------------------|---------------------------------------|----------------------|
+//! | [`Command::Status`]    | empty                                 | [`Report`]           |
+//! | [`Command::Start`]     | total length u32                      | [`Report`]           |
+//! | [`Command::Write`]     | offset u32, data                      | [`Report`]           |
+//! | [`Command::Checksum`]  | offset u32, length u32                | [`Status`] u8, CRC u32 |
+//! | [`Command::Resume`]    | total length u32, offset u32          | [`Report`]           |
+//! | [`Command::Finish`]    | empty, or ed25519 signature (64)      | [`Report`]           |
+//!
+//! Writes must be sent in order, at the offset of the last report, in chunks of at most
+//! [`Report::max_chunk`] bytes. All chunks but the last must be a multiple of the flash write
+//! size, which `max_chunk` is a multiple of. The device pads the last chunk with the erase value
+//! of the flash. [`Command::Start`] and [`Command::Resume`] are refused with
+//! [`Status::BadRequest`] if the total length doesn't fit in the DFU partition.
+//!
+//! After the device was reset, the host finds how much of the image was already written with
+//! [`Command::Checksum`] over [`Report::erase_size`] blocks, and continues with
+//! [`Command::Resume`], which rounds the offset down to an erase block boundary.
+
+use embedded_io_async::{Read, ReadExactError, Write};
+
+use crate::Error;
+
+/// Start of every frame.
+pub const SYNC: [u8; 2] = [0xEB, 0x55];
+
+/// Bit set in the command of responses.
+pub const RESPONSE: u8 = 0x80;
+
+/// Length of the frame fields around the payload.
+pub const FRAME_OVERHEAD: usize = 10;
+
+/// Length of an ed25519 signature in a [`Command::Finish`] request.
+pub const SIGNATURE_LEN: usize = 64;
+
+/// Request commands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Command {
+    /// Report the progress of the update.
+    Status = 0x01,
+    /// Start a new update of the given length.
+    Start = 0x02,
+    /// Write a chunk of the update.
+    Write = 0x03,
+    /// Compute the CRC of a range of the DFU partition.
+    Checksum = 0x04,
+    /// Continue an update interrupted by a device reset.
+    Resume = 0x05,
+    /// Verify the update and mark it to be swapped in on next boot.
+    Finish = 0x06,
+}
+
+impl TryFrom<u8> for Command {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, u8> {
+        Ok(match value {
+            0x01 => Command::Status,
+            0x02 => Command::Start,
+            0x03 => Command::Write,
+            0x04 => Command::Checksum,
+            0x05 => Command::Resume,
+            0x06 => Command::Finish,
+            other => return Err(other),
+        })
+    }
+}
+
+/// Result of a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Status {
+    /// The request succeeded.
+    Ok = 0x00,
+    /// The request frame was corrupt.
+    BadFrame = 0x01,
+    /// The command is unknown.
+    BadCommand = 0x02,
+    /// The request payload is invalid.
+    BadRequest = 0x03,
+    /// The write isn't at the current offset, the report holds the expected one.
+    BadOffset = 0x04,
+    /// No update is in progress, or the bootloader state doesn't allow updates.
+    BadState = 0x05,
+    /// Error from flash.
+    Flash = 0x06,
+    /// The update failed verification.
+    Verify = 0x07,
+}
+
+impl TryFrom<u8> for Status {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, u8> {
+        Ok(match value {
+            0x00 => Status::Ok,
+            0x01 => Status::BadFrame,
+            0x02 => Status::BadCommand,
+            0x03 => Status::BadRequest,
+            0x04 => Status::BadOffset,
+            0x05 => Status::BadState,
+            0x06 => Status::Flash,
+            0x07 => Status::Verify,
+            other => return Err(other),
+        })
+    }
+}
+
+/// Progress of the update, returned by most requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Report {
+    /// Result of the request.
+    pub status: Status,
+    /// Offset of the next write.
+    pub offset: u32,
+    /// Length of the update in progress, 0 if there is none.
+    pub total: u32,
+    /// Maximum length of the data in a write.
+    pub max_chunk: u32,
+    /// Erase size of the DFU partition, the granularity of resumes.
+    pub erase_size: u32,
+}
+
+impl Report {
+    /// Length of a serialized report.
+    pub const LEN: usize = 17;
+
+    /// Serialize the report.
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let mut bytes = [0; Self::LEN];
+        bytes[0] = self.status as u8;
+        bytes[1..5].copy_from_slice(&self.offset.to_le_bytes());
+        bytes[5..9].copy_from_slice(&self.total.to_le_bytes());
+        bytes[9..13].copy_from_slice(&self.max_chunk.to_le_bytes());
+        bytes[13..17].copy_from_slice(&self.erase_size.to_le_bytes());
+        bytes
+    }
+
+    /// Parse a report.
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != Self::LEN {
+            return None;
+        }
+        Some(Self {
+            status: Status::try_from(bytes[0]).ok()?,
+            offset: u32_at(bytes, 1),
+            total: u32_at(bytes, 5),
+            max_chunk: u32_at(bytes, 9),
+            erase_size: u32_at(bytes, 13),
+        })
+    }
+}
+
+pub(crate) fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// CRC-32 (IEEE), computed incrementally.
+#[derive(Debug, Clone, Copy)]
+pub struct Crc32(u32);
+
+impl Crc32 {
+    const TABLE: [u32; 16] = [
+        0x00000000, 0x1DB71064, 0x3B6E20C8, 0x26D930AC, 0x76DC4190, 0x6B6B51F4, 0x4DB26158, 0x5005713C, 0xEDB88320,
+        0xF00F9344, 0xD6D6A3E8, 0xCB61B38C, 0x9B64C2B0, 0x86D3D2D4, 0xA00AE278, 0xBDBDF21C,
+    ];
+
+    /// Start a new CRC.
+    pub const fn new() -> Self {
+        Self(!0)
+    }
+
+    /// Add `data` to the CRC.
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            let mut crc = self.0 ^ byte as u32;
+            crc = Self::TABLE[(crc & 0xF) as usize] ^ (crc >> 4);
+            crc = Self::TABLE[(crc & 0xF) as usize] ^ (crc >> 4);
+            self.0 = crc;
+        }
+    }
+
+    /// Finish the CRC.
+    pub const fn finish(self) -> u32 {
+        !self.0
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// CRC-32 (IEEE) of `data`.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
+
+/// Read the next frame into `buf`, returning its command, tag and payload length.
+///
+/// Returns `None` for corrupt frames, or frames that don't fit in `buf`. The payload of a frame that
+/// doesn't fit is left unread, as its length may be corrupt: the next call resynchronizes on the
+/// following [`SYNC`].
+pub(crate) async fn read_frame<T: Read>(
+    transport: &mut T,
+    buf: &mut [u8],
+) -> Result<Option<(u8, u8, usize)>, Error<T::Error>> {
+    let mut last = 0;
+    loop {
+        let mut byte = [0];
+        read_exact(transport, &mut byte).await?;
+        if [last, byte[0]] == SYNC {
+            break;
+        }
+        last = byte[0];
+    }
+
+    let mut header = [0; 4];
+    read_exact(transport, &mut header).await?;
+    let len = u16::from_le_bytes([header[2], header[3]]) as usize;
+    if len + 4 > buf.len() {
+        trace!("Dropping frame of {} bytes", len);
+        return Ok(None);
+    }
+
+    read_exact(transport, &mut buf[..len + 4]).await?;
+    let mut crc = Crc32::new();
+    crc.update(&header);
+    crc.update(&buf[..len]);
+    if crc.finish() != u32_at(buf, len) {
+        trace!("Dropping frame with bad CRC");
+        return Ok(None);
+    }
+    Ok(Some((header[0], header[1], len)))
+}
+
+/// Write a frame with the concatenation of `payload` and flush the transport.
+pub(crate) async fn write_frame<T: Write>(
+    transport: &mut T,
+    command: u8,
+    tag: u8,
+    payload: &[&[u8]],
+) -> Result<(), Error<T::Error>> {
+    let len: usize = payload.iter().map(|part| part.len()).sum();
+    let mut header = [SYNC[0], SYNC[1], command, tag, 0, 0];
+    header[4..].copy_from_slice(&(len as u16).to_le_bytes());
+    let mut crc = Crc32::new();
+    crc.update(&header[2..]);
+
+    transport.write_all(&header).await.map_err(Error::Io)?;
+    for part in payload {
+        crc.update(part);
+        transport.write_all(part).await.map_err(Error::Io)?;
+    }
+    transport
+        .write_all(&crc.finish().to_le_bytes())
+        .await
+        .map_err(Error::Io)?;
+    transport.flush().await.map_err(Error::Io)
+}
+
+async fn read_exact<T: Read>(transport: &mut T, buf: &mut [u8]) -> Result<(), Error<T::Error>> {
+    transport.read_exact(buf).await.map_err(|e| match e {
+        ReadExactError::UnexpectedEof => Error::Disconnected,
+        ReadExactError::Other(e) => Error::Io(e),
+    })
+}
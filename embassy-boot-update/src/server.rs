@@ -0,0 +1,231 @@
+use embassy_boot::{ERASE_VALUE, FirmwareUpdater, FirmwareUpdaterError};
+use embedded_io_async::{Read, Write};
+use embedded_storage_async::nor_flash::NorFlash;
+
+use crate::Error;
+use crate::protocol::{Command, Crc32, RESPONSE, Report, Status, read_frame, u32_at, write_frame};
+
+/// Device side of the update protocol.
+///
+/// The server keeps the progress of the update across calls to [`serve`](Self::serve), so an
+/// update interrupted by a disconnect continues where it stopped once the host reconnects.
+pub struct UpdateServer<'d, DFU: NorFlash, STATE: NorFlash> {
+    updater: FirmwareUpdater<'d, DFU, STATE>,
+    buf: &'d mut [u8],
+    #[cfg(feature = "_verify")]
+    public_key: &'d [u8; 32],
+    total: Option<u32>,
+    offset: u32,
+}
+
+impl<'d, DFU: NorFlash, STATE: NorFlash> UpdateServer<'d, DFU, STATE> {
+    /// Create a new update server.
+    ///
+    /// `buf` holds one request, it bounds the size of written chunks. It must be at least 72 bytes
+    /// and `DFU::WRITE_SIZE + 8` bytes long, and aligned for the DFU flash.
+    pub fn new(
+        updater: FirmwareUpdater<'d, DFU, STATE>,
+        buf: &'d mut [u8],
+        #[cfg(feature = "_verify")] public_key: &'d [u8; 32],
+    ) -> Self {
+        assert!(buf.len() >= 72 && buf.len() >= DFU::WRITE_SIZE + 8);
+        Self {
+            updater,
+            buf,
+            #[cfg(feature = "_verify")]
+            public_key,
+            total: None,
+            offset: 0,
+        }
+    }
+
+    /// Offset of the next write, and length of the update in progress, if any.
+    pub fn progress(&self) -> Option<(u32, u32)> {
+        self.total.map(|total| (self.offset, total))
+    }
+
+    /// Serve requests on `transport`, until the update is marked to be swapped in on next boot.
+    ///
+    /// Returns an error when the transport fails or is closed. The update can then be continued by
+    /// calling this again with a new connection.
+    pub async fn serve<T: Read + Write>(&mut self, transport: &mut T) -> Result<(), Error<T::Error>> {
+        loop {
+            let Some((command, tag, len)) = read_frame(transport, self.buf).await? else {
+                let report = self.report(Status::BadFrame);
+                write_frame(transport, RESPONSE, 0, &[&report.to_bytes()]).await?;
+                continue;
+            };
+
+            let status = match Command::try_from(command) {
+                Ok(Command::Checksum) => {
+                    let (status, crc) = match self.checksum(len).await {
+                        Ok(crc) => (Status::Ok, crc),
+                        Err(status) => (status, 0),
+                    };
+                    let mut response = [status as u8, 0, 0, 0, 0];
+                    response[1..].copy_from_slice(&crc.to_le_bytes());
+                    write_frame(transport, command | RESPONSE, tag, &[&response]).await?;
+                    continue;
+                }
+                Ok(command) => self.handle(command, len).await.err().unwrap_or(Status::Ok),
+                Err(_) => Status::BadCommand,
+            };
+
+            let report = self.report(status);
+            write_frame(transport, command | RESPONSE, tag, &[&report.to_bytes()]).await?;
+
+            if command == Command::Finish as u8 && status == Status::Ok {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn handle(&mut self, command: Command, len: usize) -> Result<(), Status> {
+        match command {
+            Command::Status => Ok(()),
+            Command::Start => {
+                if len != 4 {
+                    return Err(Status::BadRequest);
+                }
+                let total = u32_at(self.buf, 0);
+                if total as usize > self.updater.dfu_capacity() {
+                    return Err(Status::BadRequest);
+                }
+                self.updater.reset().await.map_err(status)?;
+                debug!("Starting update of {} bytes", total);
+                self.total = Some(total);
+                self.offset = 0;
+                Ok(())
+            }
+            Command::Resume => {
+                if len != 8 {
+                    return Err(Status::BadRequest);
+                }
+                let (total, offset) = (u32_at(self.buf, 0), u32_at(self.buf, 4));
+                if offset > total || total as usize > self.updater.dfu_capacity() {
+                    return Err(Status::BadRequest);
+                }
+                if self.total == Some(total) && self.offset == offset {
+                    return Ok(());
+                }
+
+                // The sector holding `offset` is erased again by the first write.
+                self.updater.reset().await.map_err(status)?;
+                self.total = Some(total);
+                self.offset = offset - offset % DFU::ERASE_SIZE as u32;
+                debug!("Resuming update of {} bytes at {}", total, self.offset);
+                Ok(())
+            }
+            Command::Write => {
+                let total = self.total.ok_or(Status::BadState)?;
+                if len < 4 {
+                    return Err(Status::BadRequest);
+                }
+                let offset = u32_at(self.buf, 0);
+                let data_len = len - 4;
+                if offset != self.offset {
+                    return Err(Status::BadOffset);
+                }
+                let last = offset as usize + data_len == total as usize;
+                if data_len > self.max_chunk()
+                    || offset as usize + data_len > total as usize
+                    || (!last && !data_len.is_multiple_of(DFU::WRITE_SIZE))
+                {
+                    return Err(Status::BadRequest);
+                }
+
+                let padded_len = data_len.next_multiple_of(DFU::WRITE_SIZE);
+                self.buf[len..4 + padded_len].fill(ERASE_VALUE);
+                self.updater
+                    .write_firmware(offset as usize, &self.buf[4..4 + padded_len])
+                    .await
+                    .map_err(status)?;
+                self.offset += data_len as u32;
+                Ok(())
+            }
+            Command::Finish => {
+                let total = self.total.ok_or(Status::BadState)?;
+                if self.offset != total {
+                    return Err(Status::BadState);
+                }
+                self.finish(len).await?;
+                info!("Update of {} bytes marked", total);
+                self.total = None;
+                self.offset = 0;
+                Ok(())
+            }
+            Command::Checksum => unreachable!(),
+        }
+    }
+
+    /// Verify the update, with the signature in the request or the signed image header.
+    #[cfg(feature = "_verify")]
+    async fn finish(&mut self, len: usize) -> Result<(), Status> {
+        use crate::protocol::SIGNATURE_LEN;
+
+        match len {
+            0 => self.updater.verify_image_and_mark_updated(self.public_key).await,
+            SIGNATURE_LEN => {
+                let signature: [u8; SIGNATURE_LEN] = self.buf[..SIGNATURE_LEN].try_into().unwrap();
+                self.updater
+                    .verify_and_mark_updated(self.public_key, &signature, self.offset)
+                    .await
+            }
+            _ => return Err(Status::BadRequest),
+        }
+        .map_err(status)
+    }
+
+    #[cfg(not(feature = "_verify"))]
+    async fn finish(&mut self, len: usize) -> Result<(), Status> {
+        if len != 0 {
+            return Err(Status::BadRequest);
+        }
+        self.updater.mark_updated().await.map_err(status)
+    }
+
+    async fn checksum(&mut self, len: usize) -> Result<u32, Status> {
+        if len != 8 {
+            return Err(Status::BadRequest);
+        }
+        let mut offset = u32_at(self.buf, 0);
+        let end = offset.checked_add(u32_at(self.buf, 4)).ok_or(Status::BadRequest)?;
+        if !(offset as usize).is_multiple_of(DFU::READ_SIZE) || !(end as usize).is_multiple_of(DFU::READ_SIZE) {
+            return Err(Status::BadRequest);
+        }
+
+        let chunk_len = self.buf.len() - self.buf.len() % DFU::READ_SIZE;
+        let mut crc = Crc32::new();
+        while offset < end {
+            let chunk = &mut self.buf[..chunk_len.min((end - offset) as usize)];
+            self.updater.read_dfu(offset, chunk).await.map_err(status)?;
+            crc.update(chunk);
+            offset += chunk.len() as u32;
+        }
+        Ok(crc.finish())
+    }
+
+    fn max_chunk(&self) -> usize {
+        // The buffer holds the offset, the data padded to the write size, and the CRC.
+        let capacity = self.buf.len() - 8;
+        capacity - capacity % DFU::WRITE_SIZE
+    }
+
+    fn report(&self, status: Status) -> Report {
+        Report {
+            status,
+            offset: self.offset,
+            total: self.total.unwrap_or(0),
+            max_chunk: self.max_chunk() as u32,
+            erase_size: DFU::ERASE_SIZE as u32,
+        }
+    }
+}
+
+fn status(error: FirmwareUpdaterError) -> Status {
+    match error {
+        FirmwareUpdaterError::Flash(_) => Status::Flash,
+        FirmwareUpdaterError::BadState => Status::BadState,
+        _ => Status::Verify,
+    }
+}
@@ -0,0 +1,37 @@
+use embassy_net::tcp::TcpSocket;
+use embedded_storage_async::nor_flash::NorFlash;
+
+use crate::{Error, UpdateServer};
+
+/// Serve updates over TCP on `port`, one connection at a time.
+///
+/// Connections closed or lost before the update is marked are dropped, and the update continues
+/// with the next connection. Returns once an update is marked to be swapped in on next boot, after
+/// closing the connection.
+pub async fn serve_tcp<DFU: NorFlash, STATE: NorFlash>(
+    server: &mut UpdateServer<'_, DFU, STATE>,
+    socket: &mut TcpSocket<'_>,
+    port: u16,
+) {
+    loop {
+        if let Err(e) = socket.accept(port).await {
+            warn!("Accept failed: {:?}", e);
+            socket.abort();
+            let _ = socket.flush().await;
+            continue;
+        }
+        debug!("Update connection from {:?}", socket.remote_endpoint());
+
+        match server.serve(socket).await {
+            Ok(()) => {
+                socket.close();
+                let _ = socket.flush().await;
+                return;
+            }
+            Err(Error::Io(e)) => warn!("Update connection failed: {:?}", e),
+            Err(_) => debug!("Update connection closed"),
+        }
+        socket.abort();
+        let _ = socket.flush().await;
+    }
+}
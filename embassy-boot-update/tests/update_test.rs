@@ -0,0 +1,233 @@
+#![cfg(not(feature = "_verify"))]
+
+use core::cell::RefCell;
+use core::convert::Infallible;
+
+use embassy_boot::{FirmwareUpdater, FirmwareUpdaterConfig, State};
+use embassy_boot_update::protocol::Status;
+use embassy_boot_update::{Error, UpdateClient, UpdateServer};
+use embassy_futures::join::join;
+use embassy_futures::select::select;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::pipe::Pipe;
+use embedded_storage::nor_flash::ErrorType;
+use embedded_storage_async::nor_flash::{NorFlash, ReadNorFlash};
+use futures::executor::block_on;
+
+const WRITE_SIZE: usize = 4;
+const ERASE_SIZE: usize = 256;
+const DFU_SIZE: usize = 4096;
+
+struct InMemoryFlashPartition<'a, const SIZE: usize> {
+    buffer: &'a RefCell<[u8; SIZE]>,
+}
+
+impl<const SIZE: usize> ErrorType for InMemoryFlashPartition<'_, SIZE> {
+    type Error = Infallible;
+}
+
+impl<const SIZE: usize> ReadNorFlash for InMemoryFlashPartition<'_, SIZE> {
+    const READ_SIZE: usize = WRITE_SIZE;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        let start = offset as usize;
+        bytes.copy_from_slice(&self.buffer.borrow()[start..start + bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        SIZE
+    }
+}
+
+impl<const SIZE: usize> NorFlash for InMemoryFlashPartition<'_, SIZE> {
+    const WRITE_SIZE: usize = WRITE_SIZE;
+    const ERASE_SIZE: usize = ERASE_SIZE;
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.buffer.borrow_mut()[from as usize..to as usize].fill(0xFF);
+        Ok(())
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        let start = offset as usize;
+        self.buffer.borrow_mut()[start..start + bytes.len()].copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+/// One end of an in-memory serial link.
+struct Link<'a> {
+    rx: &'a Pipe<NoopRawMutex, 64>,
+    tx: &'a Pipe<NoopRawMutex, 64>,
+    sent: usize,
+}
+
+impl<'a> Link<'a> {
+    fn new(rx: &'a Pipe<NoopRawMutex, 64>, tx: &'a Pipe<NoopRawMutex, 64>) -> Self {
+        Self { rx, tx, sent: 0 }
+    }
+}
+
+impl embedded_io_async::ErrorType for Link<'_> {
+    type Error = Infallible;
+}
+
+impl embedded_io_async::Read for Link<'_> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        Ok(self.rx.read(buf).await)
+    }
+}
+
+impl embedded_io_async::Write for Link<'_> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let n = self.tx.write(buf).await;
+        self.sent += n;
+        Ok(n)
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+struct Device {
+    dfu: RefCell<[u8; DFU_SIZE]>,
+    state: RefCell<[u8; ERASE_SIZE]>,
+}
+
+impl Device {
+    fn new() -> Self {
+        Self {
+            dfu: RefCell::new([0xFF; DFU_SIZE]),
+            state: RefCell::new([0xFF; ERASE_SIZE]),
+        }
+    }
+
+    fn updater<'a>(
+        &'a self,
+        aligned: &'a mut [u8],
+    ) -> FirmwareUpdater<'a, InMemoryFlashPartition<'a, DFU_SIZE>, InMemoryFlashPartition<'a, ERASE_SIZE>> {
+        let config = FirmwareUpdaterConfig {
+            dfu: InMemoryFlashPartition { buffer: &self.dfu },
+            state: InMemoryFlashPartition { buffer: &self.state },
+        };
+        FirmwareUpdater::new(config, aligned)
+    }
+}
+
+fn image() -> Vec<u8> {
+    (0..3001u32).map(|i| (i * 7 + i / 256) as u8).collect()
+}
+
+#[test]
+fn test_update() {
+    let device = Device::new();
+    let image = image();
+    let (to_device, to_host) = (Pipe::new(), Pipe::new());
+
+    block_on(async {
+        let mut aligned = [0; WRITE_SIZE];
+        let mut buf = [0; 128];
+        let mut server = UpdateServer::new(device.updater(&mut aligned), &mut buf);
+        let mut client = UpdateClient::new(Link::new(&to_host, &to_device));
+        let mut link = Link::new(&to_device, &to_host);
+
+        let (served, updated) = join(server.serve(&mut link), client.update(&image, None)).await;
+        served.unwrap();
+        updated.unwrap();
+        assert_eq!(server.progress(), None);
+    });
+
+    assert_eq!(&device.dfu.borrow()[..image.len()], &image[..]);
+    let mut aligned = [0; WRITE_SIZE];
+    assert_eq!(block_on(device.updater(&mut aligned).get_state()).unwrap(), State::Swap);
+}
+
+#[test]
+fn test_resume_after_reset() {
+    let device = Device::new();
+    let image = image();
+    let (to_device, to_host) = (Pipe::new(), Pipe::new());
+
+    // Write part of the image, then drop the connection and the server, as if the device was reset.
+    block_on(async {
+        let mut aligned = [0; WRITE_SIZE];
+        let mut buf = [0; 128];
+        let mut server = UpdateServer::new(device.updater(&mut aligned), &mut buf);
+        let mut client = UpdateClient::new(Link::new(&to_host, &to_device));
+        let mut link = Link::new(&to_device, &to_host);
+
+        select(server.serve(&mut link), async {
+            let mut report = client.start(image.len() as u32).await.unwrap();
+            assert_eq!(report.status, Status::Ok);
+            while report.offset < 1000 {
+                let offset = report.offset as usize;
+                let len = report.max_chunk as usize;
+                report = client.write(report.offset, &image[offset..offset + len]).await.unwrap();
+                assert_eq!(report.status, Status::Ok);
+            }
+
+            // Writes must be in order.
+            let report = client.write(0, &image[..8]).await.unwrap();
+            assert_eq!(report.status, Status::BadOffset);
+            assert!(report.offset >= 1000);
+            assert_eq!(
+                client.finish(None).await.unwrap().status,
+                Status::BadState,
+                "update is incomplete"
+            );
+        })
+        .await;
+    });
+
+    block_on(async {
+        let mut aligned = [0; WRITE_SIZE];
+        let mut buf = [0; 128];
+        let mut server = UpdateServer::new(device.updater(&mut aligned), &mut buf);
+        let mut client = UpdateClient::new(Link::new(&to_host, &to_device));
+        let mut link = Link::new(&to_device, &to_host);
+
+        // The device lost track of the update, the written blocks are found by checksum.
+        let (served, updated) = join(server.serve(&mut link), client.update(&image, None)).await;
+        served.unwrap();
+        updated.unwrap();
+        assert!(client.into_inner().sent < image.len());
+    });
+
+    assert_eq!(&device.dfu.borrow()[..image.len()], &image[..]);
+}
+
+#[test]
+fn test_corrupt_frame() {
+    let device = Device::new();
+    let (to_device, to_host) = (Pipe::<NoopRawMutex, 64>::new(), Pipe::new());
+
+    block_on(async {
+        let mut aligned = [0; WRITE_SIZE];
+        let mut buf = [0; 128];
+        let mut server = UpdateServer::new(device.updater(&mut aligned), &mut buf);
+        let mut link = Link::new(&to_device, &to_host);
+
+        select(server.serve(&mut link), async {
+            // A status request with a bad CRC, preceded by noise.
+            to_device
+                .write_all(&[0x00, 0xEB, 0xEB, 0x55, 0x01, 0, 0, 0, 1, 2, 3, 4])
+                .await;
+            let mut client = UpdateClient::new(Link::new(&to_host, &to_device));
+            // The client drops the unsolicited bad frame report and retries.
+            let report = client.status().await.unwrap();
+            assert_eq!(report.status, Status::Ok);
+            assert_eq!(report.erase_size, ERASE_SIZE as u32);
+            assert_eq!(report.max_chunk % WRITE_SIZE as u32, 0);
+            assert_eq!(client.write(0, &[0; 4]).await.unwrap().status, Status::BadState);
+            assert_eq!(client.checksum(1, 1).await, Err(Error::Device(Status::BadRequest)));
+            // Updates larger than the DFU partition are refused.
+            let too_big = DFU_SIZE as u32 + 1;
+            assert_eq!(client.start(too_big).await.unwrap().status, Status::BadRequest);
+            assert_eq!(client.resume(too_big, 0).await.unwrap().status, Status::BadRequest);
+            assert_eq!(client.status().await.unwrap().status, Status::Ok);
+        })
+        .await;
+    });
+}
@@ -14,6 +14,14 @@ pub enum BootError {
     Flash(NorFlashErrorKind),
     /// Invalid bootloader magic
     BadMagic,
+    /// Error from the image cipher.
+    Encryption,
+    /// The image header, hash or signature is invalid.
+    InvalidImage,
+    /// The image security counter is lower than the stored counter.
+    Rollback,
+    /// Error from the security counter.
+    SecurityCounter,
 }
 
 #[cfg(feature = "defmt")]
@@ -22,6 +30,10 @@ impl defmt::Format for BootError {
         match self {
             BootError::Flash(_) => defmt::write!(fmt, "BootError::Flash(_)"),
             BootError::BadMagic => defmt::write!(fmt, "BootError::BadMagic"),
+            BootError::Encryption => defmt::write!(fmt, "BootError::Encryption"),
+            BootError::InvalidImage => defmt::write!(fmt, "BootError::InvalidImage"),
+            BootError::Rollback => defmt::write!(fmt, "BootError::Rollback"),
+            BootError::SecurityCounter => defmt::write!(fmt, "BootError::SecurityCounter"),
         }
     }
 }
@@ -129,6 +141,16 @@ impl<'a, ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash>
     }
 }
 
+/// Keystream used for pages copied between the partitions of an encrypted image.
+#[derive(Clone, Copy)]
+enum Keystream {
+    /// The keystream of the update.
+    Update,
+    /// The keystream the previous image is encrypted with while the update is installed.
+    #[cfg_attr(feature = "overwrite-only", allow(dead_code))]
+    Backup,
+}
+
 /// BootLoader works with any flash implementing embedded_storage.
 pub struct BootLoader<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> {
     active: ACTIVE,
@@ -141,7 +163,12 @@ pub struct BootLoader<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> {
     /// | 1..2               | Progress validity. ERASE_VALUE means valid, !ERASE_VALUE means invalid.          |
     /// | 2..(2 + 2N)        | Progress index used while swapping                                               |
     /// | (2 + 2N)..(2 + 4N) | Progress index used while reverting
+    ///
+    /// With the `health` feature, the end of the partition holds the health records, see the
+    /// [`health`](crate::health) module.
     state: STATE,
+    #[cfg(feature = "health")]
+    max_boot_attempts: u8,
 }
 
 impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> BootLoader<ACTIVE, DFU, STATE> {
@@ -161,9 +188,21 @@ impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> BootLoader<ACTIVE, DFU, S
             active: config.active,
             dfu: config.dfu,
             state: config.state,
+            #[cfg(feature = "health")]
+            max_boot_attempts: 1,
         }
     }
 
+    /// Set how many times an update is booted before it is reverted, if it isn't marked as booted.
+    ///
+    /// The default is 1, reverting the update if it wasn't marked as booted before the next reset.
+    /// See the [`health`](crate::health) module for details.
+    #[cfg(feature = "health")]
+    pub fn set_max_boot_attempts(&mut self, attempts: u8) {
+        assert!((1..=crate::health::MAX_BOOT_ATTEMPTS).contains(&attempts));
+        self.max_boot_attempts = attempts;
+    }
+
     /// Perform necessary boot preparations like swapping images.
     ///
     /// The DFU partition is assumed to be 1 page bigger than the active partition for the swap
@@ -236,53 +275,327 @@ impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> BootLoader<ACTIVE, DFU, S
     /// |    Active |            3 |      1 |      2 |      3 |      - |
     /// |       DFU |            3 |      4 |      5 |      6 |      3 |
     ///
+    /// With the `health` feature, the update is booted again until it was booted
+    /// [`max_boot_attempts`](Self::set_max_boot_attempts) times, or marked as failed, before it is
+    /// reverted.
+    ///
+    /// ## OVERWRITE ONLY
+    ///
+    /// With the `overwrite-only` feature, the DFU partition is copied over the active partition
+    /// page by page instead of swapped, and the update is marked as booted right away. This halves
+    /// the flash wear and the time an update takes, and the DFU partition only needs to be as big
+    /// as the active partition, but a failing update can't be reverted.
+    ///
     pub fn prepare_boot(&mut self, aligned_buf: &mut [u8]) -> Result<State, BootError> {
-        const {
-            core::assert!(Self::PAGE_SIZE % ACTIVE::WRITE_SIZE as u32 == 0);
-            core::assert!(Self::PAGE_SIZE % ACTIVE::ERASE_SIZE as u32 == 0);
-            core::assert!(Self::PAGE_SIZE % DFU::WRITE_SIZE as u32 == 0);
-            core::assert!(Self::PAGE_SIZE % DFU::ERASE_SIZE as u32 == 0);
+        self.prepare_boot_with(aligned_buf, &mut |_, _, _| Ok(()))
+    }
+
+    /// Perform necessary boot preparations like swapping images, for images encrypted in the DFU
+    /// partition.
+    ///
+    /// This works like [`prepare_boot`](Self::prepare_boot), but if the update was marked as
+    /// encrypted, pages copied to the active partition are decrypted with `cipher`, and pages
+    /// copied to the DFU partition are encrypted. Updates that weren't marked as encrypted are
+    /// swapped as-is.
+    ///
+    /// The IV of an encrypted update is stored at the end of the state partition, which must be
+    /// large enough to hold it and the IV of the previous image after the progress indexes. Before
+    /// an encrypted update is swapped in, a fresh nonce for the previous image is requested from
+    /// `nonces` and stored in the state partition.
+    ///
+    /// See the [`encryption`](crate::encryption) module for details.
+    #[cfg(feature = "encryption")]
+    pub fn prepare_boot_encrypted<C: crate::encryption::ImageCipher, N: crate::encryption::NonceSource>(
+        &mut self,
+        aligned_buf: &mut [u8],
+        cipher: &mut C,
+        nonces: &mut N,
+    ) -> Result<State, BootError> {
+        use crate::encryption::{GCM_NONCE_LEN, backup_iv, backup_iv_slot, iv_slot};
+
+        let (iv_offset, iv_len) = iv_slot(self.state.capacity(), STATE::WRITE_SIZE);
+        let (backup_offset, _) = backup_iv_slot(self.state.capacity(), STATE::WRITE_SIZE);
+        let page_count = self.active.capacity() / Self::PAGE_SIZE as usize;
+        assert!((2 + progress_words(page_count)) * STATE::WRITE_SIZE <= backup_offset as usize);
+
+        let iv = self.read_iv(iv_offset, iv_len, aligned_buf)?;
+        if iv.iter().all(|&b| b == STATE_ERASE_VALUE) {
+            return self.prepare_boot(aligned_buf);
         }
 
-        // Ensure we have enough progress pages to store copy progress
-        assert_eq!(0, Self::PAGE_SIZE % aligned_buf.len() as u32);
-        assert!(aligned_buf.len() >= STATE::WRITE_SIZE);
-        assert_eq!(0, aligned_buf.len() % ACTIVE::WRITE_SIZE);
-        assert_eq!(0, aligned_buf.len() % DFU::WRITE_SIZE);
+        let mut backup = self.read_iv(backup_offset, iv_len, aligned_buf)?;
+        if backup.iter().all(|&b| b == STATE_ERASE_VALUE) && self.read_state(aligned_buf)? == State::Swap {
+            // Store the nonce before anything is encrypted with it, so that a swap resumed after a
+            // power loss uses the same one.
+            let mut nonce = [0; GCM_NONCE_LEN];
+            nonces.fill_nonce(&mut nonce).map_err(|_| BootError::Encryption)?;
+            backup = backup_iv(&nonce);
+            self.write_iv(backup_offset, iv_len, &backup, aligned_buf)?;
+        }
 
-        // Ensure our partitions are able to handle boot operations
-        assert_partitions(&self.active, &self.dfu, &self.state, Self::PAGE_SIZE);
+        self.prepare_boot_with(aligned_buf, &mut |keystream, offset, data| {
+            let iv = match keystream {
+                Keystream::Update => &iv,
+                Keystream::Backup => &backup,
+            };
+            cipher
+                .apply_keystream(iv, offset, data)
+                .map_err(|_| BootError::Encryption)
+        })
+    }
+
+    /// Read an IV from the state partition, a word at a time.
+    #[cfg(feature = "encryption")]
+    fn read_iv(
+        &mut self,
+        offset: u32,
+        len: usize,
+        aligned_buf: &mut [u8],
+    ) -> Result<[u8; crate::encryption::IV_LEN], BootError> {
+        let mut iv = [STATE_ERASE_VALUE; crate::encryption::IV_LEN];
+        let state_word = &mut aligned_buf[..STATE::WRITE_SIZE];
+        for word_offset in (0..len).step_by(STATE::WRITE_SIZE) {
+            self.state.read(offset + word_offset as u32, state_word)?;
+            if word_offset < iv.len() {
+                let n = (iv.len() - word_offset).min(STATE::WRITE_SIZE);
+                iv[word_offset..word_offset + n].copy_from_slice(&state_word[..n]);
+            }
+        }
+        Ok(iv)
+    }
+
+    /// Write an IV to the state partition, a word at a time.
+    #[cfg(feature = "encryption")]
+    fn write_iv(
+        &mut self,
+        offset: u32,
+        len: usize,
+        iv: &[u8; crate::encryption::IV_LEN],
+        aligned_buf: &mut [u8],
+    ) -> Result<(), BootError> {
+        let state_word = &mut aligned_buf[..STATE::WRITE_SIZE];
+        for word_offset in (0..len).step_by(STATE::WRITE_SIZE) {
+            state_word.fill(STATE_ERASE_VALUE);
+            if word_offset < iv.len() {
+                let n = (iv.len() - word_offset).min(STATE::WRITE_SIZE);
+                state_word[..n].copy_from_slice(&iv[word_offset..word_offset + n]);
+            }
+            self.state.write(offset + word_offset as u32, state_word)?;
+        }
+        Ok(())
+    }
+
+    /// Perform necessary boot preparations like swapping images, for signed images.
+    ///
+    /// Images must start with an [`ImageHeader`](crate::header::ImageHeader), signed with the key
+    /// matching `public_key`. Before an update is swapped in, its signature, hash and security
+    /// counter are checked, and invalid updates are discarded. The image in the active partition is
+    /// then checked the same way, and once it has been marked as booted, `counter` is raised to its
+    /// security counter so that older images are refused from then on. The counter is also raised
+    /// from the active image before an update is checked, as the active image can't be reverted to
+    /// anything older once the update is swapped in.
+    ///
+    /// Returns the state like [`prepare_boot`](Self::prepare_boot), and the header of the image to
+    /// boot, which starts `header_len` bytes into the active partition.
+    ///
+    /// See the [`header`](crate::header) module for details.
+    #[cfg(feature = "_verify")]
+    pub fn prepare_boot_verified<C: crate::SecurityCounter>(
+        &mut self,
+        aligned_buf: &mut [u8],
+        public_key: &[u8; 32],
+        counter: &mut C,
+    ) -> Result<(State, crate::header::ImageHeader), BootError> {
+        let mut min_counter = counter.read().map_err(|_| BootError::SecurityCounter)?;
+        let capacity = self.active.capacity();
+
+        // Only check updates that haven't started swapping yet, the DFU partition is modified after that.
+        if self.read_state(aligned_buf)? == State::Swap && self.current_progress(aligned_buf)? == 0 {
+            // The active image is confirmed, it can't be reverted to anything older once the update
+            // is swapped in. Raise the counter first, so that a downgrade staged after it was marked
+            // as booted, but before the next reset, is refused.
+            if let Ok(header) = verify_image(&mut self.active, capacity, aligned_buf, public_key, min_counter) {
+                min_counter = raise_security_counter(counter, min_counter, &header)?;
+            }
+
+            if let Err(e) = verify_image(&mut self.dfu, capacity, aligned_buf, public_key, min_counter) {
+                warn!("Discarding invalid update: {:?}", e);
+                self.reset_state(crate::BOOT_MAGIC, aligned_buf)?;
+            }
+        }
+
+        let state = self.prepare_boot(aligned_buf)?;
+
+        let header = verify_image(&mut self.active, capacity, aligned_buf, public_key, min_counter)?;
+        if state == State::Boot {
+            raise_security_counter(counter, min_counter, &header)?;
+        }
+
+        Ok((state, header))
+    }
+
+    fn prepare_boot_with(
+        &mut self,
+        aligned_buf: &mut [u8],
+        cipher: &mut impl FnMut(Keystream, u32, &mut [u8]) -> Result<(), BootError>,
+    ) -> Result<State, BootError> {
+        self.assert_config(aligned_buf);
 
         // Copy contents from partition N to active
         let state = self.read_state(aligned_buf)?;
         if state == State::Swap {
+            #[cfg(feature = "overwrite-only")]
+            {
+                trace!("Overwriting");
+                self.overwrite(aligned_buf, cipher)?;
+                trace!("Overwriting done");
+
+                // There is nothing to revert to, the update is booted right away.
+                self.reset_state(crate::BOOT_MAGIC, aligned_buf)?;
+            }
+
             //
             // Check if we already swapped. If we're in the swap state, this means we should revert
             // since the app has failed to mark boot as successful
             //
+            #[cfg(not(feature = "overwrite-only"))]
             if !self.is_swapped(aligned_buf)? {
                 trace!("Swapping");
-                self.swap(aligned_buf)?;
+                self.swap(aligned_buf, cipher)?;
                 trace!("Swapping done");
             } else {
+                #[cfg(feature = "health")]
+                let Some(info) = self.trial_boot(aligned_buf)? else {
+                    trace!("Booting update again");
+                    return Ok(state);
+                };
+
                 trace!("Reverting");
-                self.revert(aligned_buf)?;
+                self.revert(aligned_buf, cipher)?;
+                #[cfg(not(feature = "health"))]
+                self.reset_state(REVERT_MAGIC, aligned_buf)?;
+                #[cfg(feature = "health")]
+                {
+                    // Record the reason before the magic, so the previous image always finds it.
+                    self.clear_state(aligned_buf)?;
+                    self.write_revert_info(info, aligned_buf)?;
+                    self.write_magic(REVERT_MAGIC, aligned_buf)?;
+                }
+            }
+        }
+        Ok(state)
+    }
 
-                let state_word = &mut aligned_buf[..STATE::WRITE_SIZE];
+    /// Check the flash geometry and the aligned buffer.
+    fn assert_config(&self, aligned_buf: &[u8]) {
+        const {
+            core::assert!(Self::PAGE_SIZE % ACTIVE::WRITE_SIZE as u32 == 0);
+            core::assert!(Self::PAGE_SIZE % ACTIVE::ERASE_SIZE as u32 == 0);
+            core::assert!(Self::PAGE_SIZE % DFU::WRITE_SIZE as u32 == 0);
+            core::assert!(Self::PAGE_SIZE % DFU::ERASE_SIZE as u32 == 0);
+        }
 
-                // Invalidate progress
-                state_word.fill(!STATE_ERASE_VALUE);
-                self.state.write(STATE::WRITE_SIZE as u32, state_word)?;
+        // Ensure we have enough progress pages to store copy progress
+        assert_eq!(0, Self::PAGE_SIZE % aligned_buf.len() as u32);
+        assert!(aligned_buf.len() >= STATE::WRITE_SIZE);
+        assert_eq!(0, aligned_buf.len() % ACTIVE::WRITE_SIZE);
+        assert_eq!(0, aligned_buf.len() % DFU::WRITE_SIZE);
 
-                // Clear magic and progress
-                self.state.erase(0, self.state.capacity() as u32)?;
+        // Ensure our partitions are able to handle boot operations
+        assert_partitions(&self.active, &self.dfu, &self.state, Self::PAGE_SIZE);
+    }
+
+    /// Clear progress, and set the magic.
+    #[cfg_attr(all(feature = "health", not(feature = "multi-image")), allow(dead_code))]
+    fn reset_state(&mut self, magic: u8, aligned_buf: &mut [u8]) -> Result<(), BootError> {
+        self.clear_state(aligned_buf)?;
+        self.write_magic(magic, aligned_buf)
+    }
+
+    /// Invalidate progress, and erase the state partition.
+    fn clear_state(&mut self, aligned_buf: &mut [u8]) -> Result<(), BootError> {
+        let state_word = &mut aligned_buf[..STATE::WRITE_SIZE];
+
+        // Invalidate progress
+        state_word.fill(!STATE_ERASE_VALUE);
+        self.state.write(STATE::WRITE_SIZE as u32, state_word)?;
+
+        // Clear magic and progress
+        self.state.erase(0, self.state.capacity() as u32)?;
+        Ok(())
+    }
+
+    fn write_magic(&mut self, magic: u8, aligned_buf: &mut [u8]) -> Result<(), BootError> {
+        let state_word = &mut aligned_buf[..STATE::WRITE_SIZE];
+        state_word.fill(magic);
+        self.state.write(0, state_word)?;
+        Ok(())
+    }
+
+    /// Count a boot of the update on trial, or return why it must be reverted instead.
+    #[cfg(all(feature = "health", not(feature = "overwrite-only")))]
+    fn trial_boot(&mut self, aligned_buf: &mut [u8]) -> Result<Option<crate::health::RevertInfo>, BootError> {
+        use crate::health::{HEALTH_CHECKS, Layout, RevertInfo, RevertReason, is_set};
+
+        let layout = Layout::new(self.state.capacity(), STATE::WRITE_SIZE);
+        let state_word = &mut aligned_buf[..STATE::WRITE_SIZE];
 
-                // Set magic
-                state_word.fill(REVERT_MAGIC);
-                self.state.write(0, state_word)?;
+        // The first boot happened right after swapping, later ones are recorded.
+        let mut attempts = 1;
+        while attempts < self.max_boot_attempts {
+            self.state.read(layout.attempt(attempts), state_word)?;
+            if !is_set(state_word) {
+                break;
             }
+            attempts += 1;
         }
-        Ok(state)
+
+        let mut health = 0;
+        for check in 0..HEALTH_CHECKS {
+            self.state.read(layout.health(check), state_word)?;
+            if is_set(state_word) {
+                health |= 1 << check;
+            }
+        }
+
+        self.state.read(layout.failed, state_word)?;
+        let reason = if is_set(state_word) {
+            RevertReason::Failed
+        } else if attempts >= self.max_boot_attempts {
+            RevertReason::BootAttempts
+        } else {
+            state_word.fill(!STATE_ERASE_VALUE);
+            self.state.write(layout.attempt(attempts), state_word)?;
+            return Ok(None);
+        };
+
+        warn!("Reverting update after {} boots: {:?}", attempts, reason);
+        Ok(Some(RevertInfo {
+            reason,
+            attempts,
+            health,
+        }))
+    }
+
+    /// Record why the update was reverted, in the erased state partition.
+    #[cfg(all(feature = "health", not(feature = "overwrite-only")))]
+    fn write_revert_info(&mut self, info: crate::health::RevertInfo, aligned_buf: &mut [u8]) -> Result<(), BootError> {
+        use crate::health::Layout;
+
+        let layout = Layout::new(self.state.capacity(), STATE::WRITE_SIZE);
+        let bytes = info.to_bytes();
+        let state_word = &mut aligned_buf[..STATE::WRITE_SIZE];
+
+        // Write the first word, holding the reason, last.
+        for offset in (0..layout.revert_info_len).step_by(STATE::WRITE_SIZE).rev() {
+            state_word.fill(STATE_ERASE_VALUE);
+            if offset < bytes.len() {
+                let len = (bytes.len() - offset).min(STATE::WRITE_SIZE);
+                state_word[..len].copy_from_slice(&bytes[offset..offset + len]);
+            }
+            self.state.write(layout.revert_info + offset as u32, state_word)?;
+        }
+        Ok(())
     }
 
     /// Read the magic state from flash
@@ -301,6 +614,7 @@ impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> BootLoader<ACTIVE, DFU, S
         }
     }
 
+    #[cfg(not(feature = "overwrite-only"))]
     fn is_swapped(&mut self, aligned_buf: &mut [u8]) -> Result<bool, BootError> {
         let page_count = self.active.capacity() / Self::PAGE_SIZE as usize;
         let progress = self.current_progress(aligned_buf)?;
@@ -310,7 +624,7 @@ impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> BootLoader<ACTIVE, DFU, S
 
     fn current_progress(&mut self, aligned_buf: &mut [u8]) -> Result<usize, BootError> {
         let write_size = STATE::WRITE_SIZE as u32;
-        let max_index = ((self.state.capacity() - STATE::WRITE_SIZE) / STATE::WRITE_SIZE) - 2;
+        let max_index = ((progress_end(&self.state) - STATE::WRITE_SIZE) / STATE::WRITE_SIZE) - 2;
         let state_word = &mut aligned_buf[..write_size as usize];
 
         self.state.read(write_size, state_word)?;
@@ -343,6 +657,8 @@ impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> BootLoader<ACTIVE, DFU, S
         from_offset: u32,
         to_offset: u32,
         aligned_buf: &mut [u8],
+        keystream: Keystream,
+        cipher: &mut impl FnMut(Keystream, u32, &mut [u8]) -> Result<(), BootError>,
     ) -> Result<(), BootError> {
         if self.current_progress(aligned_buf)? <= progress_index {
             let page_size = Self::PAGE_SIZE as u32;
@@ -351,6 +667,7 @@ impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> BootLoader<ACTIVE, DFU, S
 
             for offset_in_page in (0..page_size).step_by(aligned_buf.len()) {
                 self.dfu.read(from_offset + offset_in_page as u32, aligned_buf)?;
+                cipher(keystream, to_offset + offset_in_page, aligned_buf)?;
                 self.active.write(to_offset + offset_in_page as u32, aligned_buf)?;
             }
 
@@ -359,12 +676,15 @@ impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> BootLoader<ACTIVE, DFU, S
         Ok(())
     }
 
+    #[cfg(not(feature = "overwrite-only"))]
     fn copy_page_once_to_dfu(
         &mut self,
         progress_index: usize,
         from_offset: u32,
         to_offset: u32,
         aligned_buf: &mut [u8],
+        keystream: Keystream,
+        cipher: &mut impl FnMut(Keystream, u32, &mut [u8]) -> Result<(), BootError>,
     ) -> Result<(), BootError> {
         if self.current_progress(aligned_buf)? <= progress_index {
             let page_size = Self::PAGE_SIZE as u32;
@@ -373,6 +693,7 @@ impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> BootLoader<ACTIVE, DFU, S
 
             for offset_in_page in (0..page_size).step_by(aligned_buf.len()) {
                 self.active.read(from_offset + offset_in_page as u32, aligned_buf)?;
+                cipher(keystream, from_offset + offset_in_page, aligned_buf)?;
                 self.dfu.write(to_offset + offset_in_page as u32, aligned_buf)?;
             }
 
@@ -381,7 +702,34 @@ impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> BootLoader<ACTIVE, DFU, S
         Ok(())
     }
 
-    fn swap(&mut self, aligned_buf: &mut [u8]) -> Result<(), BootError> {
+    #[cfg(feature = "overwrite-only")]
+    fn overwrite(
+        &mut self,
+        aligned_buf: &mut [u8],
+        cipher: &mut impl FnMut(Keystream, u32, &mut [u8]) -> Result<(), BootError>,
+    ) -> Result<(), BootError> {
+        let page_count = self.active.capacity() as u32 / Self::PAGE_SIZE;
+        for page_num in 0..page_count {
+            let offset = page_num * Self::PAGE_SIZE;
+            self.copy_page_once_to_active(
+                page_num as usize,
+                offset,
+                offset,
+                aligned_buf,
+                Keystream::Update,
+                cipher,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(feature = "overwrite-only"))]
+    fn swap(
+        &mut self,
+        aligned_buf: &mut [u8],
+        cipher: &mut impl FnMut(Keystream, u32, &mut [u8]) -> Result<(), BootError>,
+    ) -> Result<(), BootError> {
         let page_count = self.active.capacity() as u32 / Self::PAGE_SIZE;
         for page_num in 0..page_count {
             let progress_index = (page_num * 2) as usize;
@@ -390,19 +738,38 @@ impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> BootLoader<ACTIVE, DFU, S
             let active_from_offset = (page_count - 1 - page_num) * Self::PAGE_SIZE;
             let dfu_to_offset = (page_count - page_num) * Self::PAGE_SIZE;
             //trace!("Copy active {} to dfu {}", active_from_offset, dfu_to_offset);
-            self.copy_page_once_to_dfu(progress_index, active_from_offset, dfu_to_offset, aligned_buf)?;
+            self.copy_page_once_to_dfu(
+                progress_index,
+                active_from_offset,
+                dfu_to_offset,
+                aligned_buf,
+                Keystream::Backup,
+                cipher,
+            )?;
 
             // Copy DFU page to the active page
             let active_to_offset = (page_count - 1 - page_num) * Self::PAGE_SIZE;
             let dfu_from_offset = (page_count - 1 - page_num) * Self::PAGE_SIZE;
             //trace!("Copy dfy {} to active {}", dfu_from_offset, active_to_offset);
-            self.copy_page_once_to_active(progress_index + 1, dfu_from_offset, active_to_offset, aligned_buf)?;
+            self.copy_page_once_to_active(
+                progress_index + 1,
+                dfu_from_offset,
+                active_to_offset,
+                aligned_buf,
+                Keystream::Update,
+                cipher,
+            )?;
         }
 
         Ok(())
     }
 
-    fn revert(&mut self, aligned_buf: &mut [u8]) -> Result<(), BootError> {
+    #[cfg(not(feature = "overwrite-only"))]
+    fn revert(
+        &mut self,
+        aligned_buf: &mut [u8],
+        cipher: &mut impl FnMut(Keystream, u32, &mut [u8]) -> Result<(), BootError>,
+    ) -> Result<(), BootError> {
         let page_count = self.active.capacity() as u32 / Self::PAGE_SIZE;
         for page_num in 0..page_count {
             let progress_index = (page_count * 2 + page_num * 2) as usize;
@@ -410,18 +777,143 @@ impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> BootLoader<ACTIVE, DFU, S
             // Copy the bad active page to the DFU page
             let active_from_offset = page_num * Self::PAGE_SIZE;
             let dfu_to_offset = page_num * Self::PAGE_SIZE;
-            self.copy_page_once_to_dfu(progress_index, active_from_offset, dfu_to_offset, aligned_buf)?;
+            self.copy_page_once_to_dfu(
+                progress_index,
+                active_from_offset,
+                dfu_to_offset,
+                aligned_buf,
+                Keystream::Update,
+                cipher,
+            )?;
 
             // Copy the DFU page back to the active page
             let active_to_offset = page_num * Self::PAGE_SIZE;
             let dfu_from_offset = (page_num + 1) * Self::PAGE_SIZE;
-            self.copy_page_once_to_active(progress_index + 1, dfu_from_offset, active_to_offset, aligned_buf)?;
+            self.copy_page_once_to_active(
+                progress_index + 1,
+                dfu_from_offset,
+                active_to_offset,
+                aligned_buf,
+                Keystream::Backup,
+                cipher,
+            )?;
         }
 
         Ok(())
     }
 }
 
+#[cfg(all(feature = "multi-image", not(feature = "overwrite-only")))]
+impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> crate::multi_image::SealedImage
+    for BootLoader<ACTIVE, DFU, STATE>
+{
+    fn assert_config(&self, aligned_buf: &[u8]) {
+        BootLoader::assert_config(self, aligned_buf)
+    }
+
+    fn read_state(&mut self, aligned_buf: &mut [u8]) -> Result<State, BootError> {
+        BootLoader::read_state(self, aligned_buf)
+    }
+
+    fn reset_state(&mut self, magic: u8, aligned_buf: &mut [u8]) -> Result<(), BootError> {
+        BootLoader::reset_state(self, magic, aligned_buf)
+    }
+
+    fn is_swapped(&mut self, aligned_buf: &mut [u8]) -> Result<bool, BootError> {
+        BootLoader::is_swapped(self, aligned_buf)
+    }
+
+    fn swap(&mut self, aligned_buf: &mut [u8]) -> Result<(), BootError> {
+        BootLoader::swap(self, aligned_buf, &mut |_, _, _| Ok(()))
+    }
+
+    fn revert(&mut self, aligned_buf: &mut [u8]) -> Result<(), BootError> {
+        BootLoader::revert(self, aligned_buf, &mut |_, _, _| Ok(()))
+    }
+}
+
+#[cfg(all(feature = "multi-image", not(feature = "overwrite-only")))]
+impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> crate::multi_image::Image for BootLoader<ACTIVE, DFU, STATE> {}
+
+/// Check the header, signature, hash and security counter of the image in `flash`.
+#[cfg(feature = "_verify")]
+pub(crate) fn verify_image<F: NorFlash>(
+    flash: &mut F,
+    capacity: usize,
+    aligned_buf: &mut [u8],
+    public_key: &[u8; 32],
+    min_counter: u32,
+) -> Result<crate::header::ImageHeader, BootError> {
+    use digest::Digest;
+
+    use crate::header::{HEADER_LEN, ImageHeader};
+
+    let mut bytes = [0; HEADER_LEN];
+    read_range(flash, aligned_buf, 0, HEADER_LEN as u32, |offset, data| {
+        bytes[offset as usize..][..data.len()].copy_from_slice(data)
+    })?;
+    let header = ImageHeader::parse(&bytes, capacity).map_err(|_| BootError::InvalidImage)?;
+    header
+        .verify_signature(public_key)
+        .map_err(|_| BootError::InvalidImage)?;
+    if header.security_counter < min_counter {
+        return Err(BootError::Rollback);
+    }
+
+    let mut digest = crate::digest_adapters::Sha512::new();
+    let start = header.header_len as u32;
+    let end = start.checked_add(header.image_len).ok_or(BootError::InvalidImage)?;
+    read_range(flash, aligned_buf, start, end, |_, data| digest.update(data))?;
+    if digest.finalize().as_slice() != header.hash {
+        return Err(BootError::InvalidImage);
+    }
+
+    Ok(header)
+}
+
+/// Raise `counter` to the security counter of `header` if it's higher than `current`, returning
+/// the new value.
+#[cfg(feature = "_verify")]
+pub(crate) fn raise_security_counter<C: crate::SecurityCounter>(
+    counter: &mut C,
+    current: u32,
+    header: &crate::header::ImageHeader,
+) -> Result<u32, BootError> {
+    if header.security_counter <= current {
+        return Ok(current);
+    }
+    trace!("Raising security counter to {}", header.security_counter);
+    counter
+        .raise(header.security_counter)
+        .map_err(|_| BootError::SecurityCounter)?;
+    Ok(header.security_counter)
+}
+
+/// Read `start..end` of `flash` in chunks of `aligned_buf`, passing each chunk and its offset
+/// from `start` to `f`.
+#[cfg(feature = "_verify")]
+fn read_range<F: NorFlash>(
+    flash: &mut F,
+    aligned_buf: &mut [u8],
+    start: u32,
+    end: u32,
+    mut f: impl FnMut(u32, &[u8]),
+) -> Result<(), BootError> {
+    let chunk_len = aligned_buf.len() as u32;
+    let mut offset = start - start % chunk_len;
+    while offset < end {
+        flash.read(offset, aligned_buf)?;
+        let from = start.max(offset);
+        let to = end.min(offset + chunk_len);
+        f(
+            from - start,
+            &aligned_buf[(from - offset) as usize..(to - offset) as usize],
+        );
+        offset += chunk_len;
+    }
+    Ok(())
+}
+
 fn assert_partitions<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash>(
     active: &ACTIVE,
     dfu: &DFU,
@@ -431,8 +923,30 @@ fn assert_partitions<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash>(
     assert_eq!(active.capacity() as u32 % page_size, 0);
     assert_eq!(dfu.capacity() as u32 % page_size, 0);
     // DFU partition has to be bigger than ACTIVE partition to handle swap algorithm
+    #[cfg(not(feature = "overwrite-only"))]
     assert!(dfu.capacity() as u32 - active.capacity() as u32 >= page_size);
-    assert!(2 + 4 * (active.capacity() as u32 / page_size) <= state.capacity() as u32 / STATE::WRITE_SIZE as u32);
+    #[cfg(feature = "overwrite-only")]
+    assert!(dfu.capacity() >= active.capacity());
+    assert!(2 + progress_words(active.capacity() / page_size as usize) <= progress_end(state) / STATE::WRITE_SIZE);
+}
+
+/// End of the space available for progress indexes in the state partition.
+fn progress_end<STATE: NorFlash>(state: &STATE) -> usize {
+    #[cfg(feature = "health")]
+    return crate::health::Layout::new(state.capacity(), STATE::WRITE_SIZE).start as usize;
+    #[cfg(not(feature = "health"))]
+    state.capacity()
+}
+
+/// Number of progress words needed in the state partition for `page_count` pages.
+const fn progress_words(page_count: usize) -> usize {
+    if cfg!(feature = "overwrite-only") {
+        // One copy per page.
+        page_count
+    } else {
+        // Two copies per page, for both swapping and reverting.
+        4 * page_count
+    }
 }
 
 #[cfg(test)]
@@ -3,3 +3,36 @@ pub(crate) mod ed25519_dalek;
 
 #[cfg(feature = "ed25519-salty")]
 pub(crate) mod salty;
+
+/// The SHA-512 implementation of the enabled signature feature.
+#[cfg(feature = "ed25519-dalek")]
+pub(crate) type Sha512 = ed25519_dalek::Sha512;
+#[cfg(all(feature = "ed25519-salty", not(feature = "ed25519-dalek")))]
+pub(crate) type Sha512 = salty::Sha512;
+
+/// Verify an ed25519 signature of the SHA-512 digest of an image.
+#[cfg(feature = "_verify")]
+pub(crate) fn verify_ed25519(
+    public_key: &[u8; 32],
+    signature: &[u8; 64],
+    digest: &[u8; 64],
+) -> Result<(), signature::Error> {
+    #[cfg(feature = "ed25519-dalek")]
+    {
+        use ::ed25519_dalek::{Signature, Verifier, VerifyingKey};
+
+        let public_key = VerifyingKey::from_bytes(public_key)?;
+        let signature = Signature::from_bytes(signature);
+        public_key.verify(digest, &signature)
+    }
+    #[cfg(all(feature = "ed25519-salty", not(feature = "ed25519-dalek")))]
+    {
+        use ::salty::{PublicKey, Signature};
+
+        let public_key = PublicKey::try_from(public_key).map_err(|_| signature::Error::new())?;
+        let signature = Signature::try_from(signature).map_err(|_| signature::Error::new())?;
+        public_key
+            .verify(digest, &signature)
+            .map_err(|_| signature::Error::new())
+    }
+}
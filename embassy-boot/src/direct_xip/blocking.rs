@@ -0,0 +1,275 @@
+use digest::Digest;
+use embedded_storage::nor_flash::NorFlash;
+
+use super::{
+    CONFIRMED, DirectXipConfig, Layout, MAGIC, RECORD_MAGIC, Record, SEQUENCE, Slot, WORDS, is_set, newest,
+    parse_record, updater_state,
+};
+use crate::{FirmwareUpdaterError, STATE_ERASE_VALUE, State};
+
+/// Blocking direct-XIP updater, writing updates to the slot that isn't running.
+///
+/// This is the direct-XIP equivalent of [`BlockingFirmwareUpdater`](crate::BlockingFirmwareUpdater),
+/// see the [`direct_xip`](crate::direct_xip) module.
+pub struct BlockingDirectXipUpdater<'d, SLOT: NorFlash, STATE: NorFlash> {
+    slot_a: SLOT,
+    slot_b: SLOT,
+    state: STATE,
+    aligned: &'d mut [u8],
+    layout: Layout,
+    inactive_cleared: bool,
+    last_erased_sector_index: Option<usize>,
+}
+
+impl<'d, SLOT: NorFlash, STATE: NorFlash> BlockingDirectXipUpdater<'d, SLOT, STATE> {
+    /// Create a direct-XIP updater instance with the given slots and state partition.
+    ///
+    /// The `aligned` buffer must be at least 4 bytes and `STATE::WRITE_SIZE`, and aligned for the
+    /// STATE flash.
+    pub fn new(config: DirectXipConfig<SLOT, STATE>, aligned: &'d mut [u8]) -> Self {
+        let layout = Layout::new(
+            STATE::WRITE_SIZE,
+            STATE::ERASE_SIZE,
+            config.state.capacity(),
+            aligned.len(),
+        );
+        Self {
+            slot_a: config.slot_a,
+            slot_b: config.slot_b,
+            state: config.state,
+            aligned,
+            layout,
+            inactive_cleared: false,
+            last_erased_sector_index: None,
+        }
+    }
+
+    /// The slot the running image was booted from.
+    pub fn active_slot(&mut self) -> Result<Slot, FirmwareUpdaterError> {
+        Ok(newest(self.records()?, false))
+    }
+
+    /// The slot updates are written to.
+    pub fn inactive_slot(&mut self) -> Result<Slot, FirmwareUpdaterError> {
+        Ok(self.active_slot()?.other())
+    }
+
+    /// Obtain the current state.
+    ///
+    /// [`State::Swap`] means the running image was just installed and must be marked as booted, or
+    /// that an update is marked and waiting for a reset. [`State::Revert`] means the last update
+    /// was rejected.
+    pub fn get_state(&mut self) -> Result<State, FirmwareUpdaterError> {
+        let records = self.records()?;
+        Ok(updater_state(records, newest(records, false)))
+    }
+
+    /// Writes firmware data to the inactive slot.
+    ///
+    /// The inactive slot is no longer bootable from the first write, until it is marked as updated.
+    /// Sectors are erased as they are first written to, like with
+    /// [`BlockingFirmwareUpdater::write_firmware`](crate::BlockingFirmwareUpdater::write_firmware).
+    pub fn write_firmware(&mut self, offset: usize, data: &[u8]) -> Result<(), FirmwareUpdaterError> {
+        self.verify_booted()?;
+        let slot = self.clear_inactive()?;
+
+        let mut remaining_data = data;
+        let mut offset = offset;
+
+        while !remaining_data.is_empty() {
+            let current_sector = offset / SLOT::ERASE_SIZE;
+            let sector_start = current_sector * SLOT::ERASE_SIZE;
+            let sector_end = sector_start + SLOT::ERASE_SIZE;
+
+            if self.last_erased_sector_index != Some(current_sector) {
+                self.slot(slot).erase(sector_start as u32, sector_end as u32)?;
+                self.last_erased_sector_index = Some(current_sector);
+            }
+
+            let write_size = core::cmp::min(remaining_data.len(), sector_end - offset);
+            let (data_chunk, rest) = remaining_data.split_at(write_size);
+            self.slot(slot).write(offset as u32, data_chunk)?;
+
+            remaining_data = rest;
+            offset += write_size;
+        }
+
+        Ok(())
+    }
+
+    /// Prepare for an incoming update by erasing the entire inactive slot and returning it.
+    pub fn prepare_update(&mut self) -> Result<&mut SLOT, FirmwareUpdaterError> {
+        self.verify_booted()?;
+        let slot = self.clear_inactive()?;
+        let flash = self.slot(slot);
+        flash.erase(0, flash.capacity() as u32)?;
+
+        Ok(flash)
+    }
+
+    /// Verify the update in the inactive slot with any digest.
+    pub fn hash<D: Digest>(
+        &mut self,
+        update_len: u32,
+        chunk_buf: &mut [u8],
+        output: &mut [u8],
+    ) -> Result<(), FirmwareUpdaterError> {
+        let slot = self.inactive_slot()?;
+        let flash = self.slot(slot);
+        let mut digest = D::new();
+        for offset in (0..update_len).step_by(chunk_buf.len()) {
+            flash.read(offset, chunk_buf)?;
+            let len = core::cmp::min((update_len - offset) as usize, chunk_buf.len());
+            digest.update(&chunk_buf[..len]);
+        }
+        output.copy_from_slice(digest.finalize().as_slice());
+        Ok(())
+    }
+
+    /// Verify the update in the inactive slot given a public key, and mark it to be booted on next
+    /// boot if verify succeeds.
+    ///
+    /// The signature is expected to have been generated from a SHA-512 digest of the firmware bytes.
+    #[cfg(feature = "_verify")]
+    pub fn verify_and_mark_updated(
+        &mut self,
+        public_key: &[u8; 32],
+        signature: &[u8; 64],
+        update_len: u32,
+    ) -> Result<(), FirmwareUpdaterError> {
+        self.verify_booted()?;
+
+        let mut chunk_buf = [0; 64];
+        let mut message = [0; 64];
+        self.hash::<crate::digest_adapters::Sha512>(update_len, &mut chunk_buf, &mut message)?;
+        crate::digest_adapters::verify_ed25519(public_key, signature, &message)
+            .map_err(FirmwareUpdaterError::Signature)?;
+
+        self.install()
+    }
+
+    /// Mark the update in the inactive slot to be booted on next boot.
+    #[cfg(not(feature = "_verify"))]
+    pub fn mark_updated(&mut self) -> Result<(), FirmwareUpdaterError> {
+        self.verify_booted()?;
+        self.install()
+    }
+
+    /// Mark firmware boot successful and stop rollback on reset.
+    pub fn mark_booted(&mut self) -> Result<(), FirmwareUpdaterError> {
+        let records = self.records()?;
+        let active = newest(records, false);
+        if let Record::Trial(_) = records[active as usize] {
+            set_flag(&mut self.state, self.layout, self.aligned, active, CONFIRMED)?;
+        }
+        if let Record::Rejected(_) = records[active.other() as usize] {
+            erase_record(&mut self.state, self.layout, active.other())?;
+        }
+        Ok(())
+    }
+
+    /// Reset to initial/uninitialised state.
+    ///
+    /// After reset, the updater will behave as if no writes had
+    /// occurred, with writes beginning at offset 0 again.
+    pub fn reset(&mut self) -> Result<(), FirmwareUpdaterError> {
+        self.verify_booted()?;
+        self.last_erased_sector_index = None;
+        Ok(())
+    }
+
+    fn records(&mut self) -> Result<[Record; 2], FirmwareUpdaterError> {
+        Ok([
+            read_record(&mut self.state, self.layout, self.aligned, Slot::A)?,
+            read_record(&mut self.state, self.layout, self.aligned, Slot::B)?,
+        ])
+    }
+
+    fn verify_booted(&mut self) -> Result<(), FirmwareUpdaterError> {
+        match self.get_state()? {
+            State::Boot | State::Revert => Ok(()),
+            _ => Err(FirmwareUpdaterError::BadState),
+        }
+    }
+
+    fn slot(&mut self, slot: Slot) -> &mut SLOT {
+        match slot {
+            Slot::A => &mut self.slot_a,
+            Slot::B => &mut self.slot_b,
+        }
+    }
+
+    /// Make the inactive slot unbootable before writing to it.
+    fn clear_inactive(&mut self) -> Result<Slot, FirmwareUpdaterError> {
+        let slot = self.inactive_slot()?;
+        if !self.inactive_cleared {
+            erase_record(&mut self.state, self.layout, slot)?;
+            self.inactive_cleared = true;
+        }
+        Ok(slot)
+    }
+
+    /// Write the record of the inactive slot, newer than the active one.
+    fn install(&mut self) -> Result<(), FirmwareUpdaterError> {
+        let records = self.records()?;
+        let active = newest(records, false);
+        let slot = active.other();
+        let sequence = records[active as usize].sequence().unwrap_or(0).wrapping_add(1);
+
+        erase_record(&mut self.state, self.layout, slot)?;
+        write_word(&mut self.state, self.layout, self.aligned, slot, SEQUENCE, sequence)?;
+        write_word(&mut self.state, self.layout, self.aligned, slot, MAGIC, RECORD_MAGIC)?;
+
+        self.inactive_cleared = false;
+        self.last_erased_sector_index = None;
+        Ok(())
+    }
+}
+
+pub(super) fn read_record<STATE: NorFlash>(
+    state: &mut STATE,
+    layout: Layout,
+    aligned: &mut [u8],
+    slot: Slot,
+) -> Result<Record, STATE::Error> {
+    let buf = &mut aligned[..layout.word];
+    let mut words = [[0; 4]; WORDS];
+    let mut written = [false; WORDS];
+    for (index, (word, written)) in words.iter_mut().zip(&mut written).enumerate() {
+        state.read(layout.word(slot, index), buf)?;
+        word.copy_from_slice(&buf[..4]);
+        *written = is_set(buf);
+    }
+    Ok(parse_record(words, written))
+}
+
+pub(super) fn set_flag<STATE: NorFlash>(
+    state: &mut STATE,
+    layout: Layout,
+    aligned: &mut [u8],
+    slot: Slot,
+    flag: usize,
+) -> Result<(), STATE::Error> {
+    let buf = &mut aligned[..layout.word];
+    buf.fill(!STATE_ERASE_VALUE);
+    state.write(layout.word(slot, flag), buf)
+}
+
+fn write_word<STATE: NorFlash>(
+    state: &mut STATE,
+    layout: Layout,
+    aligned: &mut [u8],
+    slot: Slot,
+    word: usize,
+    value: u32,
+) -> Result<(), STATE::Error> {
+    let buf = &mut aligned[..layout.word];
+    buf.fill(STATE_ERASE_VALUE);
+    buf[..4].copy_from_slice(&value.to_le_bytes());
+    state.write(layout.word(slot, word), buf)
+}
+
+fn erase_record<STATE: NorFlash>(state: &mut STATE, layout: Layout, slot: Slot) -> Result<(), STATE::Error> {
+    let start = layout.record(slot);
+    state.erase(start, start + layout.sector as u32)
+}
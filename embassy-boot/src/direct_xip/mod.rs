@@ -0,0 +1,568 @@
+//! Direct-XIP (A/B) updates.
+//!
+//! Instead of copying the update over the active partition, the application runs in place from
+//! either of two slots, and the bootloader picks the slot holding the newest valid image. Updates
+//! are written to the slot that isn't running, nothing is copied at boot, and the previous image
+//! stays in place to fall back to. This suits parts executing in place from large external flash,
+//! where two slots fit easily but copying would be slow.
+//!
+//! Each slot has a record in the STATE partition, taking one erase sector, slot A first:
+//!
+//! | Word | Description                                                              |
+//! |------|--------------------------------------------------------------------------|
+//! | 0    | Sequence number of the image, one more than the image it was updated from |
+//! | 1    | Magic, written after the sequence number to commit the record            |
+//! | 2    | Attempted: the bootloader has booted the image once                      |
+//! | 3    | Confirmed: the image has been marked as booted                           |
+//! | 4    | Rejected: the image was not confirmed, and must not be booted again      |
+//!
+//! Words are 4 bytes rounded up to the STATE write size. The bootloader boots the slot with the
+//! highest sequence number that isn't rejected, or slot A if neither slot has a record. An image that
+//! is attempted but not confirmed by the next boot is rejected, and the bootloader falls back to the
+//! other slot.
+//!
+//! With signed images, `DirectXipBootLoader::prepare_boot_verified` also checks the image in the
+//! chosen slot, see the [`header`](crate::header) module. An invalid image is rejected like an
+//! unconfirmed one, and the bootloader falls back to the other slot.
+//!
+//! The application must be linked to run from the slot it is written to, which usually means
+//! building it once per slot, and sending the build for [`DirectXipUpdater::inactive_slot`].
+
+mod asynch;
+mod blocking;
+
+pub use asynch::DirectXipUpdater;
+pub use blocking::BlockingDirectXipUpdater;
+use embedded_storage::nor_flash::NorFlash;
+
+use crate::{BootError, STATE_ERASE_VALUE, State};
+
+const RECORD_MAGIC: u32 = 0xAB00_B007;
+
+const SEQUENCE: usize = 0;
+const MAGIC: usize = 1;
+const ATTEMPTED: usize = 2;
+const CONFIRMED: usize = 3;
+const REJECTED: usize = 4;
+const WORDS: usize = 5;
+
+/// One of the two image slots.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Slot {
+    /// The first slot, booted when no image has been installed yet.
+    A,
+    /// The second slot.
+    B,
+}
+
+impl Slot {
+    /// The other slot.
+    pub fn other(self) -> Self {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+}
+
+/// Direct-XIP updater flash configuration, holding the two slots and the state partition.
+pub struct DirectXipConfig<SLOT, STATE> {
+    /// The flash partition of slot A
+    pub slot_a: SLOT,
+    /// The flash partition of slot B
+    pub slot_b: SLOT,
+    /// The state flash partition
+    pub state: STATE,
+}
+
+/// The record of a slot in the STATE partition.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Record {
+    /// No image was installed, or it is being written.
+    Empty,
+    /// Installed, and waiting for the bootloader to try it.
+    Pending(u32),
+    /// Booted once, and waiting to be confirmed.
+    Trial(u32),
+    /// Confirmed by the application.
+    Confirmed(u32),
+    /// Failed to confirm.
+    Rejected(u32),
+}
+
+impl Record {
+    fn sequence(self) -> Option<u32> {
+        match self {
+            Record::Empty => None,
+            Record::Pending(seq) | Record::Trial(seq) | Record::Confirmed(seq) | Record::Rejected(seq) => Some(seq),
+        }
+    }
+}
+
+/// Location of the records in the STATE partition.
+#[derive(Clone, Copy)]
+struct Layout {
+    word: usize,
+    sector: usize,
+}
+
+impl Layout {
+    fn new(write_size: usize, erase_size: usize, capacity: usize, aligned_len: usize) -> Self {
+        let word = 4usize.next_multiple_of(write_size);
+        assert!(WORDS * word <= erase_size);
+        assert!(2 * erase_size <= capacity);
+        assert!(aligned_len >= word);
+        Self {
+            word,
+            sector: erase_size,
+        }
+    }
+
+    fn record(&self, slot: Slot) -> u32 {
+        match slot {
+            Slot::A => 0,
+            Slot::B => self.sector as u32,
+        }
+    }
+
+    fn word(&self, slot: Slot, word: usize) -> u32 {
+        self.record(slot) + (word * self.word) as u32
+    }
+}
+
+/// Parse a record from the first 4 bytes of each of its words, and whether each word is written.
+fn parse_record(words: [[u8; 4]; WORDS], written: [bool; WORDS]) -> Record {
+    if u32::from_le_bytes(words[MAGIC]) != RECORD_MAGIC {
+        return Record::Empty;
+    }
+    let seq = u32::from_le_bytes(words[SEQUENCE]);
+    if written[REJECTED] {
+        Record::Rejected(seq)
+    } else if written[CONFIRMED] {
+        Record::Confirmed(seq)
+    } else if written[ATTEMPTED] {
+        Record::Trial(seq)
+    } else {
+        Record::Pending(seq)
+    }
+}
+
+/// Whether a flag word has been written.
+fn is_set(word: &[u8]) -> bool {
+    word.iter().any(|&b| b != STATE_ERASE_VALUE)
+}
+
+/// The slot holding the newest image that may be booted, slot A if there is none.
+fn newest(records: [Record; 2], include_pending: bool) -> Slot {
+    let bootable = |record: Record| match record {
+        Record::Pending(seq) if include_pending => Some(seq),
+        Record::Trial(seq) | Record::Confirmed(seq) => Some(seq),
+        _ => None,
+    };
+    match (bootable(records[0]), bootable(records[1])) {
+        (Some(a), Some(b)) if b > a => Slot::B,
+        (None, Some(_)) => Slot::B,
+        _ => Slot::A,
+    }
+}
+
+/// The state seen by the updater, running the image in `active`.
+fn updater_state(records: [Record; 2], active: Slot) -> State {
+    match (records[active as usize], records[active.other() as usize]) {
+        (Record::Trial(_), _) | (_, Record::Pending(_)) => State::Swap,
+        _ if is_reverted(records, active) => State::Revert,
+        _ => State::Boot,
+    }
+}
+
+/// Whether a newer image than the one in `active` was rejected.
+fn is_reverted(records: [Record; 2], active: Slot) -> bool {
+    match records[active.other() as usize] {
+        Record::Rejected(rejected) => records[active as usize].sequence().is_none_or(|seq| rejected > seq),
+        _ => false,
+    }
+}
+
+/// Bootloader for direct-XIP (A/B) updates.
+///
+/// Unlike [`BootLoader`](crate::BootLoader), it never writes to the slots: it only tracks the images
+/// in the STATE partition, and tells which slot to boot.
+pub struct DirectXipBootLoader<STATE: NorFlash> {
+    state: STATE,
+}
+
+impl<STATE: NorFlash> DirectXipBootLoader<STATE> {
+    /// Create a new instance of a direct-XIP bootloader with the given state partition.
+    pub fn new(state: STATE) -> Self {
+        Self { state }
+    }
+
+    /// Pick the slot to boot, and update the records for this boot attempt.
+    ///
+    /// The `aligned_buf` must be at least 4 bytes and `STATE::WRITE_SIZE`, and aligned for the
+    /// STATE flash.
+    ///
+    /// Returns the slot to boot, and a state like [`BootLoader::prepare_boot`](crate::BootLoader::prepare_boot):
+    ///
+    /// - [`State::Swap`] when booting a new image for the first time. It must be marked as booted
+    ///   with the updater, or it is rejected on the next boot.
+    /// - [`State::Revert`] when the newest image was rejected, and the previous one is booted
+    ///   instead, until it is marked as booted. If no image is left to boot, slot A is booted
+    ///   anyway.
+    /// - [`State::Boot`] otherwise.
+    pub fn prepare_boot(&mut self, aligned_buf: &mut [u8]) -> Result<(Slot, State), BootError> {
+        let layout = Layout::new(
+            STATE::WRITE_SIZE,
+            STATE::ERASE_SIZE,
+            self.state.capacity(),
+            aligned_buf.len(),
+        );
+
+        loop {
+            let records = [
+                blocking::read_record(&mut self.state, layout, aligned_buf, Slot::A)?,
+                blocking::read_record(&mut self.state, layout, aligned_buf, Slot::B)?,
+            ];
+            let slot = newest(records, true);
+            match records[slot as usize] {
+                Record::Trial(_) => {
+                    trace!("Rejecting unconfirmed image in slot {:?}", slot);
+                    blocking::set_flag(&mut self.state, layout, aligned_buf, slot, REJECTED)?;
+                }
+                Record::Pending(_) => {
+                    trace!("Attempting new image in slot {:?}", slot);
+                    blocking::set_flag(&mut self.state, layout, aligned_buf, slot, ATTEMPTED)?;
+                    return Ok((slot, State::Swap));
+                }
+                _ if is_reverted(records, slot) => return Ok((slot, State::Revert)),
+                _ => return Ok((slot, State::Boot)),
+            }
+        }
+    }
+
+    /// Pick the slot to boot like [`prepare_boot`](Self::prepare_boot), for signed images.
+    ///
+    /// Images must start with an [`ImageHeader`](crate::header::ImageHeader), signed with the key
+    /// matching `public_key`. The signature, hash and security counter of the image in the chosen
+    /// slot are checked before booting it. An invalid image is rejected, and the other slot is tried
+    /// instead. Once the booted image has been marked as booted, `counter` is raised to its security
+    /// counter so that older images are refused from then on. The counter is also raised from the
+    /// confirmed image before a new one is tried.
+    ///
+    /// The slots are only read. The `aligned_buf` must also be a multiple of `SLOT::READ_SIZE`, and
+    /// aligned for the slots.
+    ///
+    /// Returns the slot to boot, the state, and the header of the image to boot, which starts
+    /// `header_len` bytes into the slot. If no slot holds a valid image, the error of slot A is
+    /// returned.
+    #[cfg(feature = "_verify")]
+    pub fn prepare_boot_verified<SLOT: NorFlash, C: crate::SecurityCounter>(
+        &mut self,
+        slot_a: &mut SLOT,
+        slot_b: &mut SLOT,
+        aligned_buf: &mut [u8],
+        public_key: &[u8; 32],
+        counter: &mut C,
+    ) -> Result<(Slot, State, crate::header::ImageHeader), BootError> {
+        use crate::boot_loader::{raise_security_counter, verify_image};
+
+        let layout = Layout::new(
+            STATE::WRITE_SIZE,
+            STATE::ERASE_SIZE,
+            self.state.capacity(),
+            aligned_buf.len(),
+        );
+        let mut min_counter = counter.read().map_err(|_| BootError::SecurityCounter)?;
+        let mut verify = |slot: Slot, aligned_buf: &mut [u8], min_counter: u32| {
+            let flash = match slot {
+                Slot::A => &mut *slot_a,
+                Slot::B => &mut *slot_b,
+            };
+            let capacity = flash.capacity();
+            verify_image(flash, capacity, aligned_buf, public_key, min_counter)
+        };
+
+        loop {
+            let records = [
+                blocking::read_record(&mut self.state, layout, aligned_buf, Slot::A)?,
+                blocking::read_record(&mut self.state, layout, aligned_buf, Slot::B)?,
+            ];
+            let slot = newest(records, true);
+            let state = match records[slot as usize] {
+                Record::Trial(_) => {
+                    trace!("Rejecting unconfirmed image in slot {:?}", slot);
+                    blocking::set_flag(&mut self.state, layout, aligned_buf, slot, REJECTED)?;
+                    continue;
+                }
+                Record::Pending(_) => {
+                    // The confirmed image can't be reverted to anything older once the new one is
+                    // tried, so a downgrade installed after it was marked as booted is refused.
+                    if let Record::Confirmed(_) = records[slot.other() as usize]
+                        && let Ok(header) = verify(slot.other(), aligned_buf, min_counter)
+                    {
+                        min_counter = raise_security_counter(counter, min_counter, &header)?;
+                    }
+                    State::Swap
+                }
+                _ if is_reverted(records, slot) => State::Revert,
+                _ => State::Boot,
+            };
+
+            match verify(slot, aligned_buf, min_counter) {
+                Ok(header) => {
+                    match state {
+                        State::Swap => {
+                            trace!("Attempting new image in slot {:?}", slot);
+                            blocking::set_flag(&mut self.state, layout, aligned_buf, slot, ATTEMPTED)?;
+                        }
+                        State::Boot => {
+                            raise_security_counter(counter, min_counter, &header)?;
+                        }
+                        _ => {}
+                    }
+                    return Ok((slot, state, header));
+                }
+                Err(e @ (BootError::InvalidImage | BootError::Rollback))
+                    if matches!(records[slot as usize], Record::Pending(_) | Record::Confirmed(_)) =>
+                {
+                    warn!("Rejecting invalid image in slot {:?}: {:?}", slot, e);
+                    blocking::set_flag(&mut self.state, layout, aligned_buf, slot, REJECTED)?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_storage::nor_flash::ReadNorFlash;
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::mem_flash::MemFlash;
+    use crate::test_flash::BlockingTestFlash;
+
+    type Flash = MemFlash<16384, 4096, 4>;
+
+    #[test]
+    fn test_newest() {
+        use Record::*;
+
+        assert_eq!(newest([Empty, Empty], true), Slot::A);
+        assert_eq!(newest([Confirmed(1), Pending(2)], false), Slot::A);
+        assert_eq!(newest([Confirmed(1), Pending(2)], true), Slot::B);
+        assert_eq!(newest([Trial(3), Confirmed(2)], true), Slot::A);
+        assert_eq!(newest([Rejected(3), Confirmed(2)], true), Slot::B);
+        assert_eq!(newest([Empty, Trial(1)], true), Slot::B);
+    }
+
+    #[test]
+    #[cfg(not(feature = "_verify"))]
+    fn test_update_and_revert() {
+        static ORIGINAL: [u8; 16384] = [0x55; 16384];
+        static UPDATE: [u8; 16384] = [0xAA; 16384];
+
+        let mut slot_a = Flash::default();
+        let mut slot_b = Flash::default();
+        let mut state = MemFlash::<8192, 4096, 4>::default();
+        slot_a.write(0, &ORIGINAL).unwrap();
+        let mut aligned = [0; 4];
+
+        let boot = |state: &mut MemFlash<8192, 4096, 4>| {
+            let mut aligned = [0; 4];
+            DirectXipBootLoader::new(state).prepare_boot(&mut aligned).unwrap()
+        };
+
+        // Nothing installed yet
+        assert_eq!((Slot::A, State::Boot), boot(&mut state));
+
+        let mut updater = DirectXipUpdater::new(
+            DirectXipConfig {
+                slot_a: &mut slot_a,
+                slot_b: &mut slot_b,
+                state: &mut state,
+            },
+            &mut aligned,
+        );
+        assert_eq!(Slot::B, block_on(updater.inactive_slot()).unwrap());
+        block_on(updater.write_firmware(0, &UPDATE)).unwrap();
+        block_on(updater.mark_updated()).unwrap();
+        assert_eq!(State::Swap, block_on(updater.get_state()).unwrap());
+
+        // The update is attempted once, and rejected since it isn't marked as booted
+        assert_eq!((Slot::B, State::Swap), boot(&mut state));
+        assert_eq!((Slot::A, State::Revert), boot(&mut state));
+        assert_eq!((Slot::A, State::Revert), boot(&mut state));
+
+        let mut updater = DirectXipUpdater::new(
+            DirectXipConfig {
+                slot_a: &mut slot_a,
+                slot_b: &mut slot_b,
+                state: &mut state,
+            },
+            &mut aligned,
+        );
+        assert_eq!(State::Revert, block_on(updater.get_state()).unwrap());
+        block_on(updater.mark_booted()).unwrap();
+        assert_eq!(State::Boot, block_on(updater.get_state()).unwrap());
+
+        // Try again, and mark it as booted this time
+        block_on(updater.write_firmware(0, &UPDATE)).unwrap();
+        block_on(updater.mark_updated()).unwrap();
+        assert_eq!((Slot::B, State::Swap), boot(&mut state));
+
+        let mut updater = DirectXipUpdater::new(
+            DirectXipConfig {
+                slot_a: &mut slot_a,
+                slot_b: &mut slot_b,
+                state: &mut state,
+            },
+            &mut aligned,
+        );
+        assert_eq!(Slot::B, block_on(updater.active_slot()).unwrap());
+        assert_eq!(State::Swap, block_on(updater.get_state()).unwrap());
+        block_on(updater.mark_booted()).unwrap();
+        assert_eq!((Slot::B, State::Boot), boot(&mut state));
+
+        // Slots are untouched by the bootloader
+        let mut read_buf = [0; 16384];
+        slot_a.read(0, &mut read_buf).unwrap();
+        assert_eq!(ORIGINAL, read_buf);
+        slot_b.read(0, &mut read_buf).unwrap();
+        assert_eq!(UPDATE, read_buf);
+    }
+
+    #[test]
+    #[cfg(not(feature = "_verify"))]
+    fn test_blocking_alternates_slots() {
+        let flash = BlockingTestFlash::new(crate::BootLoaderConfig {
+            active: Flash::default(),
+            dfu: Flash::default(),
+            state: MemFlash::<8192, 4096, 4>::default(),
+        });
+        let mut aligned = [0; 4];
+        let mut page = [0; 4];
+
+        for (i, expected) in [Slot::B, Slot::A, Slot::B].into_iter().enumerate() {
+            let mut updater = BlockingDirectXipUpdater::new(
+                DirectXipConfig {
+                    slot_a: flash.active(),
+                    slot_b: flash.dfu(),
+                    state: flash.state(),
+                },
+                &mut aligned,
+            );
+            assert_eq!(expected, updater.inactive_slot().unwrap());
+            updater.write_firmware(0, &[i as u8; 4096]).unwrap();
+            updater.mark_updated().unwrap();
+
+            let mut bootloader = DirectXipBootLoader::new(flash.state());
+            assert_eq!((expected, State::Swap), bootloader.prepare_boot(&mut page).unwrap());
+
+            let mut updater = BlockingDirectXipUpdater::new(
+                DirectXipConfig {
+                    slot_a: flash.active(),
+                    slot_b: flash.dfu(),
+                    state: flash.state(),
+                },
+                &mut aligned,
+            );
+            updater.mark_booted().unwrap();
+            assert_eq!((expected, State::Boot), bootloader.prepare_boot(&mut page).unwrap());
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "ed25519-dalek")]
+    fn test_verified_rejects_invalid_images() {
+        extern crate std;
+
+        use ed25519_dalek::{Digest, Sha512, Signer, SigningKey};
+
+        use crate::test_fixtures::sign;
+        use crate::{FlashSecurityCounter, SecurityCounter};
+
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let public_key = key.verifying_key().to_bytes();
+        let signed = |version: u32, security_counter| {
+            let image: std::vec::Vec<u8> = (0..5000).map(|i| (i * version) as u8).collect();
+            sign(&image, &key, version, security_counter)
+        };
+
+        let flash = BlockingTestFlash::new(crate::BootLoaderConfig {
+            active: Flash::default(),
+            dfu: Flash::default(),
+            state: MemFlash::<8192, 4096, 4>::default(),
+        });
+        let mut counter_flash = MemFlash::<4096, 4096, 4>::default();
+        let mut counter_buf = [0; 4];
+        let mut counter = FlashSecurityCounter::new(&mut counter_flash, &mut counter_buf);
+
+        let boot = |counter: &mut FlashSecurityCounter<_>| {
+            let mut page = [0; 1024];
+            DirectXipBootLoader::new(flash.state())
+                .prepare_boot_verified(&mut flash.active(), &mut flash.dfu(), &mut page, &public_key, counter)
+                .map(|(slot, state, header)| (slot, state, header.version))
+        };
+        // Install `image`, or mark the running image as booted.
+        let update = |image: Option<&[u8]>| {
+            let mut aligned = [0; 4];
+            let mut updater = BlockingDirectXipUpdater::new(
+                DirectXipConfig {
+                    slot_a: flash.active(),
+                    slot_b: flash.dfu(),
+                    state: flash.state(),
+                },
+                &mut aligned,
+            );
+            match image {
+                Some(image) => {
+                    updater.write_firmware(0, image).unwrap();
+                    let signature = key.sign(&Sha512::digest(image));
+                    updater
+                        .verify_and_mark_updated(&public_key, &signature.to_bytes(), image.len() as u32)
+                        .unwrap();
+                }
+                None => updater.mark_booted().unwrap(),
+            }
+        };
+
+        // Nothing to boot
+        assert_eq!(Err(BootError::InvalidImage), boot(&mut counter));
+
+        flash.active().write(0, &signed(1, 1)).unwrap();
+        assert_eq!(Ok((Slot::A, State::Boot, 1)), boot(&mut counter));
+        assert_eq!(1, counter.read().unwrap());
+
+        // The newest slot is corrupted, its hash doesn't match anymore
+        let mut corrupted = signed(2, 1);
+        corrupted[1000] ^= 1;
+        update(Some(&corrupted));
+        assert_eq!(Ok((Slot::A, State::Revert, 1)), boot(&mut counter));
+        update(None);
+        assert_eq!(Ok((Slot::A, State::Boot, 1)), boot(&mut counter));
+
+        // The counter is only raised once the update is marked as booted
+        update(Some(&signed(2, 3)));
+        assert_eq!(Ok((Slot::B, State::Swap, 2)), boot(&mut counter));
+        assert_eq!(1, counter.read().unwrap());
+        update(None);
+        assert_eq!(Ok((Slot::B, State::Boot, 2)), boot(&mut counter));
+        assert_eq!(3, counter.read().unwrap());
+
+        // Downgrades are rejected
+        update(Some(&signed(3, 1)));
+        assert_eq!(Ok((Slot::B, State::Revert, 2)), boot(&mut counter));
+        update(None);
+
+        // A downgrade installed right after an update is marked as booted is rejected too
+        update(Some(&signed(4, 5)));
+        assert_eq!(Ok((Slot::A, State::Swap, 4)), boot(&mut counter));
+        update(None);
+        update(Some(&signed(5, 3)));
+        assert_eq!(Ok((Slot::A, State::Revert, 4)), boot(&mut counter));
+        assert_eq!(5, counter.read().unwrap());
+    }
+}
@@ -0,0 +1,304 @@
+//! Encrypted firmware images.
+//!
+//! Images can be kept encrypted in the DFU partition, so that reading the flash holding it, often
+//! an external QSPI flash, doesn't reveal the firmware. Only the active partition holds the
+//! plaintext image.
+//!
+//! Images are encrypted with AES in counter mode: the keystream is the encryption of a 16-byte
+//! counter block, whose last 4 bytes are a big-endian block counter. The first counter block is the
+//! IV of the image. This is the keystream used by AES-GCM, so images encrypted with AES-GCM can be
+//! used as-is, with the IV returned by [`gcm_iv`].
+//!
+//! The update flow is the same as for plaintext images:
+//!
+//! - The application writes the encrypted image to the DFU partition with `write_firmware`, and
+//!   marks it with `verify_and_mark_updated_encrypted`, `verify_gcm_and_mark_updated` or
+//!   `mark_updated_encrypted`. These store the IV in the STATE partition.
+//! - The bootloader swaps images with [`BootLoader::prepare_boot_encrypted`](crate::BootLoader::prepare_boot_encrypted),
+//!   decrypting the new image into the active partition. Before swapping, the bootloader gets a
+//!   fresh nonce from a [`NonceSource`] and stores it in the STATE partition, and the previous
+//!   image is re-encrypted into the DFU partition with it. On revert, the previous image is
+//!   decrypted back, and the new image is re-encrypted to its original ciphertext.
+//!
+//! The bootloader and updater never see the key, they only need an [`ImageCipher`]. [`Aes`] is a
+//! software implementation, getting the key from a [`KeyProvider`], for example from OTP memory.
+//! Hardware AES peripherals with key slots can implement [`ImageCipher`] directly, so that the key
+//! never leaves the peripheral.
+//!
+//! Encryption provides confidentiality only: images must still be authenticated, either with a
+//! signature over the ciphertext and IV, or with the AES-GCM tag.
+
+use aes::cipher::{BlockEncrypt, KeyInit};
+use ghash::GHash;
+use ghash::universal_hash::UniversalHash;
+
+/// Length of an AES block.
+pub const BLOCK_LEN: usize = 16;
+
+/// Length of an image IV.
+pub const IV_LEN: usize = 16;
+
+/// Length of an AES-GCM nonce.
+pub const GCM_NONCE_LEN: usize = 12;
+
+/// Length of an AES-GCM tag.
+pub const GCM_TAG_LEN: usize = 16;
+
+/// Block cipher used to encrypt and decrypt images.
+pub trait ImageCipher {
+    /// Error type.
+    type Error: core::fmt::Debug;
+
+    /// Encrypt a single block in place with AES.
+    fn encrypt_block(&mut self, block: &mut [u8; BLOCK_LEN]) -> Result<(), Self::Error>;
+
+    /// Encrypt or decrypt `data`, found `offset` bytes into the image encrypted with `iv`.
+    ///
+    /// The default implementation encrypts one counter block at a time with
+    /// [`encrypt_block`](Self::encrypt_block). Implementations backed by hardware with a
+    /// counter mode should override it.
+    fn apply_keystream(&mut self, iv: &[u8; IV_LEN], offset: u32, data: &mut [u8]) -> Result<(), Self::Error> {
+        let mut offset = offset as usize;
+        let mut data = data;
+        while !data.is_empty() {
+            let mut block = counter_block(iv, (offset / BLOCK_LEN) as u32);
+            self.encrypt_block(&mut block)?;
+
+            let skip = offset % BLOCK_LEN;
+            let len = (BLOCK_LEN - skip).min(data.len());
+            let (chunk, rest) = data.split_at_mut(len);
+            for (byte, key) in chunk.iter_mut().zip(&block[skip..]) {
+                *byte ^= key;
+            }
+            data = rest;
+            offset += len;
+        }
+        Ok(())
+    }
+}
+
+impl<T: ImageCipher + ?Sized> ImageCipher for &mut T {
+    type Error = T::Error;
+
+    fn encrypt_block(&mut self, block: &mut [u8; BLOCK_LEN]) -> Result<(), Self::Error> {
+        T::encrypt_block(self, block)
+    }
+
+    fn apply_keystream(&mut self, iv: &[u8; IV_LEN], offset: u32, data: &mut [u8]) -> Result<(), Self::Error> {
+        T::apply_keystream(self, iv, offset, data)
+    }
+}
+
+/// An AES key.
+#[derive(Clone)]
+pub enum Key {
+    /// AES-128 key.
+    Aes128([u8; 16]),
+    /// AES-256 key.
+    Aes256([u8; 32]),
+}
+
+/// Source of the image encryption key.
+pub trait KeyProvider {
+    /// Error type.
+    type Error: core::fmt::Debug;
+
+    /// Get the key.
+    fn key(&mut self) -> Result<Key, Self::Error>;
+}
+
+impl KeyProvider for Key {
+    type Error = core::convert::Infallible;
+
+    fn key(&mut self) -> Result<Key, Self::Error> {
+        Ok(self.clone())
+    }
+}
+
+// There is no allocator to box the larger key schedule, and only one is ever in use.
+#[allow(clippy::large_enum_variant)]
+enum AesCipher {
+    Aes128(aes::Aes128),
+    Aes256(aes::Aes256),
+}
+
+/// Software AES, with the key from a [`KeyProvider`].
+///
+/// The key is requested on first use, and the expanded key is kept until this is dropped.
+pub struct Aes<K> {
+    keys: K,
+    cipher: Option<AesCipher>,
+}
+
+impl<K: KeyProvider> Aes<K> {
+    /// Create a cipher getting its key from `keys`.
+    pub fn new(keys: K) -> Self {
+        Self { keys, cipher: None }
+    }
+}
+
+impl<K: KeyProvider> ImageCipher for Aes<K> {
+    type Error = K::Error;
+
+    fn encrypt_block(&mut self, block: &mut [u8; BLOCK_LEN]) -> Result<(), Self::Error> {
+        let cipher = match &mut self.cipher {
+            Some(cipher) => cipher,
+            cipher => cipher.insert(match self.keys.key()? {
+                Key::Aes128(key) => AesCipher::Aes128(aes::Aes128::new(&key.into())),
+                Key::Aes256(key) => AesCipher::Aes256(aes::Aes256::new(&key.into())),
+            }),
+        };
+        match cipher {
+            AesCipher::Aes128(cipher) => cipher.encrypt_block(block.into()),
+            AesCipher::Aes256(cipher) => cipher.encrypt_block(block.into()),
+        }
+        Ok(())
+    }
+}
+
+/// The IV of an image encrypted with AES-GCM, using `nonce`.
+///
+/// AES-GCM uses the counter block following the nonce and a counter of 1 for the tag, the image
+/// starts at counter 2.
+pub fn gcm_iv(nonce: &[u8; GCM_NONCE_LEN]) -> [u8; IV_LEN] {
+    let mut iv = [0; IV_LEN];
+    iv[..GCM_NONCE_LEN].copy_from_slice(nonce);
+    iv[GCM_NONCE_LEN..].copy_from_slice(&2u32.to_be_bytes());
+    iv
+}
+
+/// Source of the nonces the previous image is encrypted with while an update is installed.
+///
+/// A new nonce is requested for every update that is swapped in. Nonces must never repeat for
+/// the same key, so this should be backed by a hardware random number generator.
+pub trait NonceSource {
+    /// Error type.
+    type Error: core::fmt::Debug;
+
+    /// Fill `nonce` with a fresh nonce.
+    fn fill_nonce(&mut self, nonce: &mut [u8; GCM_NONCE_LEN]) -> Result<(), Self::Error>;
+}
+
+impl<T: NonceSource + ?Sized> NonceSource for &mut T {
+    type Error = T::Error;
+
+    fn fill_nonce(&mut self, nonce: &mut [u8; GCM_NONCE_LEN]) -> Result<(), Self::Error> {
+        T::fill_nonce(self, nonce)
+    }
+}
+
+/// The IV used to encrypt the previous image into the DFU partition, from a fresh `nonce`.
+pub(crate) fn backup_iv(nonce: &[u8; GCM_NONCE_LEN]) -> [u8; IV_LEN] {
+    let mut iv = [0; IV_LEN];
+    iv[..GCM_NONCE_LEN].copy_from_slice(nonce);
+    iv
+}
+
+/// Offset and length of the IV slot at the end of the STATE partition.
+pub(crate) fn iv_slot(state_capacity: usize, write_size: usize) -> (u32, usize) {
+    let len = IV_LEN.next_multiple_of(write_size);
+    ((state_capacity - len) as u32, len)
+}
+
+/// Offset and length of the slot holding the IV of the previous image, right before the IV slot.
+pub(crate) fn backup_iv_slot(state_capacity: usize, write_size: usize) -> (u32, usize) {
+    let (offset, len) = iv_slot(state_capacity, write_size);
+    (offset - len as u32, len)
+}
+
+fn counter_block(iv: &[u8; IV_LEN], index: u32) -> [u8; BLOCK_LEN] {
+    let mut block = *iv;
+    let counter = u32::from_be_bytes(iv[12..].try_into().unwrap()).wrapping_add(index);
+    block[12..].copy_from_slice(&counter.to_be_bytes());
+    block
+}
+
+/// Computes the AES-GCM tag of an image, without additional authenticated data.
+pub(crate) struct GcmTag {
+    ghash: GHash,
+    len: u64,
+}
+
+impl GcmTag {
+    pub fn new<C: ImageCipher>(cipher: &mut C) -> Result<Self, C::Error> {
+        let mut h = [0; BLOCK_LEN];
+        cipher.encrypt_block(&mut h)?;
+        Ok(Self {
+            ghash: GHash::new(&h.into()),
+            len: 0,
+        })
+    }
+
+    /// Add ciphertext. All chunks but the last must be a multiple of [`BLOCK_LEN`].
+    pub fn update(&mut self, ciphertext: &[u8]) {
+        self.ghash.update_padded(ciphertext);
+        self.len += ciphertext.len() as u64;
+    }
+
+    /// Check the tag of the image.
+    pub fn verify<C: ImageCipher>(
+        mut self,
+        cipher: &mut C,
+        nonce: &[u8; GCM_NONCE_LEN],
+        tag: &[u8; GCM_TAG_LEN],
+    ) -> Result<bool, C::Error> {
+        let mut lengths = [0; BLOCK_LEN];
+        lengths[8..].copy_from_slice(&(self.len * 8).to_be_bytes());
+        self.ghash.update_padded(&lengths);
+
+        // The tag is masked with the counter block before the image.
+        let mut mask = [0; BLOCK_LEN];
+        mask[..GCM_NONCE_LEN].copy_from_slice(nonce);
+        mask[GCM_NONCE_LEN..].copy_from_slice(&1u32.to_be_bytes());
+        cipher.encrypt_block(&mut mask)?;
+
+        let expected = self.ghash.finalize();
+        let diff = expected
+            .iter()
+            .zip(mask)
+            .zip(tag)
+            .fold(0, |diff, ((expected, mask), tag)| diff | (expected ^ mask ^ tag));
+        Ok(diff == 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use aes_gcm::aead::AeadInPlace;
+    use aes_gcm::{Aes128Gcm, KeyInit as _};
+
+    use super::*;
+
+    const KEY: [u8; 16] = [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f,
+    ];
+
+    #[test]
+    fn test_gcm_compatible() {
+        let nonce = [7; GCM_NONCE_LEN];
+        let plaintext: [u8; 100] = core::array::from_fn(|i| i as u8);
+
+        let mut expected = plaintext;
+        let expected_tag = Aes128Gcm::new(&KEY.into())
+            .encrypt_in_place_detached(&nonce.into(), &[], &mut expected)
+            .unwrap();
+
+        let mut cipher = Aes::new(Key::Aes128(KEY));
+        let mut ciphertext = plaintext;
+        // At an offset, in pieces not aligned to blocks.
+        let (a, b) = ciphertext.split_at_mut(21);
+        cipher.apply_keystream(&gcm_iv(&nonce), 0, a).unwrap();
+        cipher.apply_keystream(&gcm_iv(&nonce), 21, b).unwrap();
+        assert_eq!(ciphertext, expected);
+
+        let mut tag = GcmTag::new(&mut cipher).unwrap();
+        tag.update(&ciphertext[..64]);
+        tag.update(&ciphertext[64..]);
+        assert!(tag.verify(&mut cipher, &nonce, &expected_tag.into()).unwrap());
+
+        let mut tag = GcmTag::new(&mut cipher).unwrap();
+        ciphertext[50] ^= 1;
+        tag.update(&ciphertext);
+        assert!(!tag.verify(&mut cipher, &nonce, &expected_tag.into()).unwrap());
+    }
+}
@@ -3,9 +3,10 @@ use digest::Digest;
 use embassy_embedded_hal::flash::partition::Partition;
 #[cfg(target_os = "none")]
 use embassy_sync::blocking_mutex::raw::NoopRawMutex;
-use embedded_storage_async::nor_flash::NorFlash;
+use embedded_storage_async::nor_flash::{NorFlash, ReadNorFlash};
 
 use super::FirmwareUpdaterConfig;
+use crate::patch::{Action, Geometry, PatchDecoder, Source};
 use crate::{BOOT_MAGIC, DFU_DETACH_MAGIC, FirmwareUpdaterError, STATE_ERASE_VALUE, SWAP_MAGIC, State};
 
 /// FirmwareUpdater is an application API for interacting with the BootLoader without the ability to
@@ -61,6 +62,11 @@ impl<'d, DFU: NorFlash, STATE: NorFlash> FirmwareUpdater<'d, DFU, STATE> {
         }
     }
 
+    /// Capacity of the DFU partition, the maximum length of an update.
+    pub fn dfu_capacity(&self) -> usize {
+        self.dfu.capacity()
+    }
+
     /// Obtain the current state.
     ///
     /// This is useful to check if the bootloader has just done a swap, in order
@@ -84,65 +90,121 @@ impl<'d, DFU: NorFlash, STATE: NorFlash> FirmwareUpdater<'d, DFU, STATE> {
     #[cfg(feature = "_verify")]
     pub async fn verify_and_mark_updated(
         &mut self,
-        _public_key: &[u8; 32],
-        _signature: &[u8; 64],
-        _update_len: u32,
+        public_key: &[u8; 32],
+        signature: &[u8; 64],
+        update_len: u32,
     ) -> Result<(), FirmwareUpdaterError> {
-        assert!(_update_len <= self.dfu.capacity() as u32);
+        self.verify_signature(public_key, signature, update_len, &[]).await?;
+        self.state.mark_updated().await
+    }
+
+    /// Verify a signed image in DFU given a public key, and mark it to be swapped in on next boot
+    /// if verify succeeds.
+    ///
+    /// The image must start with an [`ImageHeader`](crate::header::ImageHeader), whose signature
+    /// and hash are checked. The security counter is checked by the bootloader. See the
+    /// [`header`](crate::header) module for details.
+    #[cfg(feature = "_verify")]
+    pub async fn verify_image_and_mark_updated(&mut self, public_key: &[u8; 32]) -> Result<(), FirmwareUpdaterError> {
+        use crate::header::{HEADER_LEN, ImageHeader};
 
         self.state.verify_booted().await?;
 
-        #[cfg(feature = "ed25519-dalek")]
-        {
-            use ed25519_dalek::{Signature, SignatureError, Verifier, VerifyingKey};
+        let mut bytes = [0; HEADER_LEN];
+        self.dfu.read(0, &mut bytes).await?;
+        let header = ImageHeader::parse(&bytes, self.dfu.capacity()).map_err(FirmwareUpdaterError::Header)?;
+        header
+            .verify_signature(public_key)
+            .map_err(FirmwareUpdaterError::Signature)?;
+
+        let mut digest = crate::digest_adapters::Sha512::new();
+        let mut chunk_buf = [0; 64];
+        let start = header.header_len as u32;
+        let end = start
+            .checked_add(header.image_len)
+            .ok_or(FirmwareUpdaterError::Header(crate::header::HeaderError::BadLength))?;
+        for offset in (start..end).step_by(chunk_buf.len()) {
+            self.dfu.read(offset, &mut chunk_buf).await?;
+            let len = core::cmp::min((end - offset) as usize, chunk_buf.len());
+            digest.update(&chunk_buf[..len]);
+        }
+        if digest.finalize().as_slice() != header.hash {
+            return Err(FirmwareUpdaterError::Signature(signature::Error::new()));
+        }
 
-            use crate::digest_adapters::ed25519_dalek::Sha512;
+        self.state.mark_updated().await
+    }
 
-            let into_signature_error = |e: SignatureError| FirmwareUpdaterError::Signature(e.into());
+    /// Verify an encrypted image in DFU given a public key, and mark it to be decrypted and
+    /// swapped in on next boot if verify succeeds.
+    ///
+    /// This works like [`verify_and_mark_updated`](Self::verify_and_mark_updated), but the signature
+    /// is expected to have been generated from a SHA-512 digest of the encrypted firmware bytes
+    /// followed by the IV. See the [`encryption`](crate::encryption) module for details.
+    #[cfg(all(feature = "_verify", feature = "encryption"))]
+    pub async fn verify_and_mark_updated_encrypted(
+        &mut self,
+        public_key: &[u8; 32],
+        signature: &[u8; 64],
+        update_len: u32,
+        iv: &[u8; crate::encryption::IV_LEN],
+    ) -> Result<(), FirmwareUpdaterError> {
+        self.verify_signature(public_key, signature, update_len, iv).await?;
+        self.state.mark_updated_encrypted(iv).await
+    }
 
-            let public_key = VerifyingKey::from_bytes(_public_key).map_err(into_signature_error)?;
-            let signature = Signature::from_bytes(_signature);
+    /// Verify the AES-GCM tag of an encrypted image in DFU, and mark it to be decrypted and
+    /// swapped in on next boot if verify succeeds.
+    ///
+    /// The image must have been encrypted with AES-GCM using `nonce` and no additional
+    /// authenticated data, with the key of `cipher`.
+    #[cfg(feature = "encryption")]
+    pub async fn verify_gcm_and_mark_updated<C: crate::encryption::ImageCipher>(
+        &mut self,
+        cipher: &mut C,
+        nonce: &[u8; crate::encryption::GCM_NONCE_LEN],
+        update_len: u32,
+        tag: &[u8; crate::encryption::GCM_TAG_LEN],
+    ) -> Result<(), FirmwareUpdaterError> {
+        use crate::encryption::{GcmTag, gcm_iv};
 
-            let mut chunk_buf = [0; 64];
-            let mut message = [0; 64];
-            self.hash::<Sha512>(_update_len, &mut chunk_buf, &mut message).await?;
+        assert!(update_len <= self.dfu.capacity() as u32);
 
-            public_key.verify(&message, &signature).map_err(into_signature_error)?;
-            return self.state.mark_updated().await;
+        self.state.verify_booted().await?;
+
+        let into_encryption_error = |_| FirmwareUpdaterError::Encryption;
+        let mut gcm = GcmTag::new(cipher).map_err(into_encryption_error)?;
+        let mut chunk_buf = [0; 64];
+        for offset in (0..update_len).step_by(chunk_buf.len()) {
+            self.dfu.read(offset, &mut chunk_buf).await?;
+            let len = core::cmp::min((update_len - offset) as usize, chunk_buf.len());
+            gcm.update(&chunk_buf[..len]);
+        }
+        if !gcm.verify(cipher, nonce, tag).map_err(into_encryption_error)? {
+            return Err(FirmwareUpdaterError::Signature(signature::Error::new()));
         }
-        #[cfg(feature = "ed25519-salty")]
-        {
-            use salty::{PublicKey, Signature};
 
-            use crate::digest_adapters::salty::Sha512;
-            use crate::fmt::Bytes;
+        self.state.mark_updated_encrypted(&gcm_iv(nonce)).await
+    }
 
-            fn into_signature_error<E>(_: E) -> FirmwareUpdaterError {
-                FirmwareUpdaterError::Signature(signature::Error::default())
-            }
+    #[cfg(feature = "_verify")]
+    async fn verify_signature(
+        &mut self,
+        public_key: &[u8; 32],
+        signature: &[u8; 64],
+        update_len: u32,
+        suffix: &[u8],
+    ) -> Result<(), FirmwareUpdaterError> {
+        assert!(update_len <= self.dfu.capacity() as u32);
 
-            let public_key = PublicKey::try_from(_public_key).map_err(into_signature_error)?;
-            let signature = Signature::try_from(_signature).map_err(into_signature_error)?;
-
-            let mut message = [0; 64];
-            let mut chunk_buf = [0; 64];
-            self.hash::<Sha512>(_update_len, &mut chunk_buf, &mut message).await?;
-
-            let r = public_key.verify(&message, &signature);
-            trace!(
-                "Verifying with public key {}, signature {} and message {} yields ok: {}",
-                Bytes(&public_key.to_bytes()),
-                Bytes(&signature.to_bytes()),
-                Bytes(&message),
-                r.is_ok()
-            );
-            r.map_err(into_signature_error)?;
-            return self.state.mark_updated().await;
-        }
-        #[cfg(not(any(feature = "ed25519-dalek", feature = "ed25519-salty")))]
-        {
-            Err(FirmwareUpdaterError::Signature(signature::Error::new()))
-        }
+        self.state.verify_booted().await?;
+
+        let mut chunk_buf = [0; 64];
+        let mut message = [0; 64];
+        self.hash_with_suffix::<crate::digest_adapters::Sha512>(update_len, suffix, &mut chunk_buf, &mut message)
+            .await?;
+
+        crate::digest_adapters::verify_ed25519(public_key, signature, &message).map_err(FirmwareUpdaterError::Signature)
     }
 
     /// Verify the update in DFU with any digest.
@@ -151,6 +213,16 @@ impl<'d, DFU: NorFlash, STATE: NorFlash> FirmwareUpdater<'d, DFU, STATE> {
         update_len: u32,
         chunk_buf: &mut [u8],
         output: &mut [u8],
+    ) -> Result<(), FirmwareUpdaterError> {
+        self.hash_with_suffix::<D>(update_len, &[], chunk_buf, output).await
+    }
+
+    async fn hash_with_suffix<D: Digest>(
+        &mut self,
+        update_len: u32,
+        suffix: &[u8],
+        chunk_buf: &mut [u8],
+        output: &mut [u8],
     ) -> Result<(), FirmwareUpdaterError> {
         let mut digest = D::new();
         for offset in (0..update_len).step_by(chunk_buf.len()) {
@@ -158,6 +230,7 @@ impl<'d, DFU: NorFlash, STATE: NorFlash> FirmwareUpdater<'d, DFU, STATE> {
             let len = core::cmp::min((update_len - offset) as usize, chunk_buf.len());
             digest.update(&chunk_buf[..len]);
         }
+        digest.update(suffix);
         output.copy_from_slice(digest.finalize().as_slice());
         Ok(())
     }
@@ -179,6 +252,17 @@ impl<'d, DFU: NorFlash, STATE: NorFlash> FirmwareUpdater<'d, DFU, STATE> {
         self.state.mark_updated().await
     }
 
+    /// Mark to trigger decrypting and swapping in the image encrypted with `iv` on next boot.
+    ///
+    /// See the [`encryption`](crate::encryption) module for details.
+    #[cfg(all(not(feature = "_verify"), feature = "encryption"))]
+    pub async fn mark_updated_encrypted(
+        &mut self,
+        iv: &[u8; crate::encryption::IV_LEN],
+    ) -> Result<(), FirmwareUpdaterError> {
+        self.state.mark_updated_encrypted(iv).await
+    }
+
     /// Mark to trigger USB DFU on next boot.
     pub async fn mark_dfu(&mut self) -> Result<(), FirmwareUpdaterError> {
         self.state.verify_booted().await?;
@@ -256,6 +340,68 @@ impl<'d, DFU: NorFlash, STATE: NorFlash> FirmwareUpdater<'d, DFU, STATE> {
         Ok(())
     }
 
+    /// Writes a chunk of a compressed firmware patch to the device.
+    ///
+    /// The patch is decoded into the DFU partition as it arrives, see the [`patch`](crate::patch)
+    /// module for the format. Call this with consecutive chunks of the patch, using the same
+    /// `decoder` for the whole update, until [`PatchDecoder::is_done`] returns true. The decoded
+    /// image can then be verified with `verify_and_mark_updated`, using
+    /// [`PatchDecoder::image_len`] as the update length.
+    ///
+    /// Patches referencing a base image must be written with [`write_delta`](Self::write_delta)
+    /// instead.
+    pub async fn write_patch(
+        &mut self,
+        decoder: &mut PatchDecoder<'_>,
+        data: &[u8],
+    ) -> Result<(), FirmwareUpdaterError> {
+        self.decode_patch(decoder, None::<&mut DFU>, data).await
+    }
+
+    /// Writes a chunk of a firmware patch against a base image to the device.
+    ///
+    /// Like [`write_patch`](Self::write_patch), but the patch may also copy data from `base`.
+    /// This is typically the active partition, which holds the firmware the patch was generated
+    /// against. `base` is only read from.
+    pub async fn write_delta<BASE: ReadNorFlash>(
+        &mut self,
+        decoder: &mut PatchDecoder<'_>,
+        base: &mut BASE,
+        data: &[u8],
+    ) -> Result<(), FirmwareUpdaterError> {
+        self.decode_patch(decoder, Some(base), data).await
+    }
+
+    async fn decode_patch<BASE: ReadNorFlash>(
+        &mut self,
+        decoder: &mut PatchDecoder<'_>,
+        mut base: Option<&mut BASE>,
+        mut data: &[u8],
+    ) -> Result<(), FirmwareUpdaterError> {
+        let geometry = Geometry {
+            dfu_capacity: self.dfu.capacity(),
+            dfu_write_size: DFU::WRITE_SIZE,
+            dfu_read_size: DFU::READ_SIZE,
+            base_capacity: base.as_ref().map(|base| base.capacity()),
+            base_read_size: BASE::READ_SIZE,
+        };
+
+        loop {
+            match decoder
+                .poll(&mut data, &geometry)
+                .map_err(FirmwareUpdaterError::Patch)?
+            {
+                Action::NeedInput | Action::Done => return Ok(()),
+                Action::Write { offset, data } => self.write_firmware(offset as usize, data).await?,
+                Action::Read { source, offset, buf } => match (source, base.as_mut()) {
+                    (Source::Dfu, _) => self.dfu.read(offset, buf).await?,
+                    (Source::Base, Some(base)) => base.read(offset, buf).await?,
+                    (Source::Base, None) => unreachable!(),
+                },
+            }
+        }
+    }
+
     /// Prepare for an incoming DFU update by erasing the entire DFU area and
     /// returning its `Partition`.
     ///
@@ -331,23 +477,146 @@ impl<'d, STATE: NorFlash> FirmwareState<'d, STATE> {
 
     /// Mark to trigger firmware swap on next boot.
     pub async fn mark_updated(&mut self) -> Result<(), FirmwareUpdaterError> {
-        self.set_magic(SWAP_MAGIC).await
+        self.set_magic(SWAP_MAGIC, None).await
+    }
+
+    /// Mark to trigger decrypting and swapping in the image encrypted with `iv` on next boot.
+    #[cfg(feature = "encryption")]
+    pub async fn mark_updated_encrypted(
+        &mut self,
+        iv: &[u8; crate::encryption::IV_LEN],
+    ) -> Result<(), FirmwareUpdaterError> {
+        self.set_magic(SWAP_MAGIC, Some(iv)).await
     }
 
     /// Mark to trigger USB DFU on next boot.
     pub async fn mark_dfu(&mut self) -> Result<(), FirmwareUpdaterError> {
-        self.set_magic(DFU_DETACH_MAGIC).await
+        self.set_magic(DFU_DETACH_MAGIC, None).await
     }
 
     /// Mark firmware boot successful and stop rollback on reset.
     pub async fn mark_booted(&mut self) -> Result<(), FirmwareUpdaterError> {
-        self.set_magic(BOOT_MAGIC).await
+        self.set_magic(BOOT_MAGIC, None).await
+    }
+
+    /// Report that health check `check` of the update on trial passed.
+    ///
+    /// Reported checks are recorded if the update is reverted, see the [`health`](crate::health)
+    /// module. `check` must be lower than [`HEALTH_CHECKS`](crate::health::HEALTH_CHECKS).
+    #[cfg(feature = "health")]
+    pub async fn report_health(&mut self, check: u8) -> Result<(), FirmwareUpdaterError> {
+        let offset = self.health_layout().health(check);
+        self.set_record(offset).await
     }
 
-    async fn set_magic(&mut self, magic: u8) -> Result<(), FirmwareUpdaterError> {
+    /// Health checks reported by the update on trial, with bit `n` set for check `n`.
+    #[cfg(feature = "health")]
+    pub async fn health(&mut self) -> Result<u8, FirmwareUpdaterError> {
+        let layout = self.health_layout();
+        let mut health = 0;
+        for check in 0..crate::health::HEALTH_CHECKS {
+            if self.is_record_set(layout.health(check)).await? {
+                health |= 1 << check;
+            }
+        }
+        Ok(health)
+    }
+
+    /// Mark the update on trial as failed, so that it is reverted on next boot.
+    #[cfg(feature = "health")]
+    pub async fn mark_failed(&mut self) -> Result<(), FirmwareUpdaterError> {
+        let offset = self.health_layout().failed;
+        self.set_record(offset).await
+    }
+
+    /// Number of times the update on trial was booted, including the current boot.
+    #[cfg(feature = "health")]
+    pub async fn boot_attempts(&mut self) -> Result<u8, FirmwareUpdaterError> {
+        let layout = self.health_layout();
+        let mut attempts = 1;
+        while attempts < crate::health::MAX_BOOT_ATTEMPTS && self.is_record_set(layout.attempt(attempts)).await? {
+            attempts += 1;
+        }
+        Ok(attempts)
+    }
+
+    /// Why the last update was reverted, if it was.
+    ///
+    /// The record is kept until the next update is marked.
+    #[cfg(feature = "health")]
+    pub async fn last_revert(&mut self) -> Result<Option<crate::RevertInfo>, FirmwareUpdaterError> {
+        let bytes = self.read_revert_info().await?;
+        Ok(crate::RevertInfo::parse(&bytes))
+    }
+
+    #[cfg(feature = "health")]
+    fn health_layout(&self) -> crate::health::Layout {
+        crate::health::Layout::new(self.state.capacity(), STATE::WRITE_SIZE)
+    }
+
+    /// Set a record word of the update on trial.
+    #[cfg(feature = "health")]
+    async fn set_record(&mut self, offset: u32) -> Result<(), FirmwareUpdaterError> {
+        if self.get_state().await? != State::Swap {
+            return Err(FirmwareUpdaterError::BadState);
+        }
+        if !self.is_record_set(offset).await? {
+            self.aligned.fill(!STATE_ERASE_VALUE);
+            self.state.write(offset, &self.aligned[..STATE::WRITE_SIZE]).await?;
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "health")]
+    async fn is_record_set(&mut self, offset: u32) -> Result<bool, FirmwareUpdaterError> {
+        self.state.read(offset, self.aligned).await?;
+        Ok(crate::health::is_set(&self.aligned[..STATE::WRITE_SIZE]))
+    }
+
+    #[cfg(feature = "health")]
+    async fn read_revert_info(&mut self) -> Result<[u8; crate::health::REVERT_INFO_LEN], FirmwareUpdaterError> {
+        let layout = self.health_layout();
+        let mut bytes = [0; crate::health::REVERT_INFO_LEN];
+        for offset in (0..bytes.len()).step_by(STATE::WRITE_SIZE) {
+            self.state
+                .read(layout.revert_info + offset as u32, self.aligned)
+                .await?;
+            let len = (bytes.len() - offset).min(STATE::WRITE_SIZE);
+            bytes[offset..offset + len].copy_from_slice(&self.aligned[..len]);
+        }
+        Ok(bytes)
+    }
+
+    /// Write the revert record in the erased state partition, its first word last.
+    #[cfg(feature = "health")]
+    async fn write_revert_info(
+        &mut self,
+        bytes: &[u8; crate::health::REVERT_INFO_LEN],
+    ) -> Result<(), FirmwareUpdaterError> {
+        let layout = self.health_layout();
+        for offset in (0..layout.revert_info_len).step_by(STATE::WRITE_SIZE).rev() {
+            let word = &mut self.aligned[..STATE::WRITE_SIZE];
+            word.fill(STATE_ERASE_VALUE);
+            if offset < bytes.len() {
+                let len = (bytes.len() - offset).min(STATE::WRITE_SIZE);
+                word[..len].copy_from_slice(&bytes[offset..offset + len]);
+            }
+            self.state.write(layout.revert_info + offset as u32, word).await?;
+        }
+        Ok(())
+    }
+
+    async fn set_magic(&mut self, magic: u8, iv: Option<&[u8; 16]>) -> Result<(), FirmwareUpdaterError> {
         self.state.read(0, &mut self.aligned).await?;
 
         if self.aligned[..STATE::WRITE_SIZE].iter().any(|&b| b != magic) {
+            // Keep the record of the last revert until the next update.
+            #[cfg(feature = "health")]
+            let revert_info = match magic {
+                SWAP_MAGIC => None,
+                _ => Some(self.read_revert_info().await?).filter(|bytes| crate::RevertInfo::parse(bytes).is_some()),
+            };
+
             // Read progress validity
             if STATE::READ_SIZE <= 2 * STATE::WRITE_SIZE {
                 self.state.read(STATE::WRITE_SIZE as u32, &mut self.aligned).await?;
@@ -371,6 +640,29 @@ impl<'d, STATE: NorFlash> FirmwareState<'d, STATE> {
             // Clear magic and progress
             self.state.erase(0, self.state.capacity() as u32).await?;
 
+            // Store the IV of an encrypted image before the magic, so that the bootloader never
+            // sees the magic without it.
+            if let Some(iv) = iv {
+                // The IV slot is at the end of the partition.
+                let write_size = STATE::WRITE_SIZE;
+                let len = iv.len().next_multiple_of(write_size);
+                let offset = (self.state.capacity() - len) as u32;
+                for chunk_offset in (0..len).step_by(write_size) {
+                    let word = &mut self.aligned[..write_size];
+                    word.fill(STATE_ERASE_VALUE);
+                    if chunk_offset < iv.len() {
+                        let chunk_len = (iv.len() - chunk_offset).min(write_size);
+                        word[..chunk_len].copy_from_slice(&iv[chunk_offset..chunk_offset + chunk_len]);
+                    }
+                    self.state.write(offset + chunk_offset as u32, word).await?;
+                }
+            }
+
+            #[cfg(feature = "health")]
+            if let Some(bytes) = revert_info {
+                self.write_revert_info(&bytes).await?;
+            }
+
             // Set magic
             self.aligned.fill(magic);
             self.state.write(0, &self.aligned[..STATE::WRITE_SIZE]).await?;
@@ -381,6 +673,10 @@ impl<'d, STATE: NorFlash> FirmwareState<'d, STATE> {
 
 #[cfg(test)]
 mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
     use embassy_embedded_hal::flash::partition::Partition;
     use embassy_sync::blocking_mutex::raw::NoopRawMutex;
     use embassy_sync::mutex::Mutex;
@@ -388,7 +684,9 @@ mod tests {
     use sha1::{Digest, Sha1};
 
     use super::*;
+    use crate::PatchError;
     use crate::mem_flash::MemFlash;
+    use crate::test_fixtures::Patch;
 
     #[test]
     fn can_verify_sha1() {
@@ -481,4 +779,124 @@ mod tests {
 
         assert_eq!(Sha1::digest(update).as_slice(), hash);
     }
+
+    fn test_image(seed: u32, len: usize) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|i| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                if i % 3 == 0 { 0 } else { (state >> 24) as u8 % 16 }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn can_write_compressed_patch() {
+        let flash = Mutex::<NoopRawMutex, _>::new(MemFlash::<131072, 1024, 8>::default());
+        let state = Partition::new(&flash, 0, 4096);
+        let dfu = Partition::new(&flash, 65536, 65536);
+        let mut aligned = [0; 8];
+
+        let mut image = test_image(1, 20_003);
+        image[5_000..9_000].fill(0xAA);
+        image.copy_within(1_000..2_000, 12_000);
+        let patch = Patch::new(image.len())
+            .literal(&image[..5_000])
+            .literal(&[0xAA])
+            .copy(1, 3_999)
+            .literal(&image[9_000..12_000])
+            .copy(11_000, 1_000)
+            .literal(&image[13_000..])
+            .finish();
+        assert!(patch.len() < image.len());
+
+        let mut updater = FirmwareUpdater::new(FirmwareUpdaterConfig { dfu, state }, &mut aligned);
+        let mut buf = [0; 24];
+        let mut decoder = PatchDecoder::new(&mut buf);
+        for chunk in patch.chunks(37) {
+            block_on(updater.write_patch(&mut decoder, chunk)).unwrap();
+        }
+        assert!(decoder.is_done());
+        assert_eq!(decoder.image_len(), Some(image.len() as u32));
+
+        let mut chunk_buf = [0; 64];
+        let mut hash = [0; 20];
+        block_on(updater.hash::<Sha1>(image.len() as u32, &mut chunk_buf, &mut hash)).unwrap();
+        assert_eq!(Sha1::digest(&image).as_slice(), hash);
+    }
+
+    #[test]
+    fn can_write_delta() {
+        let flash = Mutex::<NoopRawMutex, _>::new(MemFlash::<131072, 1024, 8>::default());
+        let state = Partition::new(&flash, 0, 4096);
+        let mut active = Partition::new(&flash, 4096, 61440);
+        let dfu = Partition::new(&flash, 65536, 65536);
+        let mut aligned = [0; 8];
+
+        let base = test_image(1, 30_000);
+        let mut image = base.clone();
+        image.splice(10_000..10_000, test_image(2, 500));
+        image[20_000..20_100].fill(0x42);
+        let patch = Patch::new(image.len())
+            .base(0, 10_000)
+            .literal(&image[10_000..10_500])
+            .base(10_000, 9_500)
+            .literal(&[0x42])
+            .copy(1, 99)
+            .base(19_600, 10_400)
+            .finish();
+        assert!(patch.len() < 2_000);
+
+        block_on(active.erase(0, 30_720)).unwrap();
+        block_on(active.write(0, &base)).unwrap();
+
+        let mut updater = FirmwareUpdater::new(FirmwareUpdaterConfig { dfu, state }, &mut aligned);
+        let mut buf = [0; 64];
+        let mut decoder = PatchDecoder::new(&mut buf);
+        for chunk in patch.chunks(100) {
+            block_on(updater.write_delta(&mut decoder, &mut active, chunk)).unwrap();
+        }
+        assert!(decoder.is_done());
+
+        let mut chunk_buf = [0; 64];
+        let mut hash = [0; 20];
+        block_on(updater.hash::<Sha1>(image.len() as u32, &mut chunk_buf, &mut hash)).unwrap();
+        assert_eq!(Sha1::digest(&image).as_slice(), hash);
+
+        // A delta can't be applied without its base.
+        let mut buf = [0; 64];
+        let mut decoder = PatchDecoder::new(&mut buf);
+        block_on(updater.reset()).unwrap();
+        assert!(matches!(
+            block_on(updater.write_patch(&mut decoder, &patch)),
+            Err(FirmwareUpdaterError::Patch(PatchError::MissingBase))
+        ));
+    }
+
+    #[test]
+    fn rejects_patch_out_of_bounds() {
+        let flash = Mutex::<NoopRawMutex, _>::new(MemFlash::<131072, 1024, 8>::default());
+        let state = Partition::new(&flash, 0, 4096);
+        let mut active = Partition::new(&flash, 4096, 61440);
+        let dfu = Partition::new(&flash, 65536, 65536);
+        let mut aligned = [0; 8];
+        let mut updater = FirmwareUpdater::new(FirmwareUpdaterConfig { dfu, state }, &mut aligned);
+
+        // Maximal operands, whose sums would wrap around on 32-bit targets.
+        let max = u32::MAX as usize;
+        for patch in [
+            Patch::new(100).base(max, 2).finish(),
+            Patch::new(100).base(1, max).finish(),
+            Patch::new(100).literal(&[0]).copy(1, max).finish(),
+            Patch::new(100).literal(&[0]).copy(max, 1).finish(),
+        ] {
+            block_on(updater.reset()).unwrap();
+            let mut buf = [0; 64];
+            let mut decoder = PatchDecoder::new(&mut buf);
+            assert!(matches!(
+                block_on(updater.write_delta(&mut decoder, &mut active, &patch)),
+                Err(FirmwareUpdaterError::Patch(PatchError::OutOfBounds))
+            ));
+        }
+    }
 }
@@ -5,6 +5,9 @@ pub use asynch::{FirmwareState, FirmwareUpdater};
 pub use blocking::{BlockingFirmwareState, BlockingFirmwareUpdater};
 use embedded_storage::nor_flash::{NorFlashError, NorFlashErrorKind};
 
+use crate::header::HeaderError;
+use crate::patch::PatchError;
+
 /// Firmware updater flash configuration holding the two flashes used by the updater
 ///
 /// If only a single flash is actually used, then that flash should be partitioned into two partitions before use.
@@ -26,6 +29,12 @@ pub enum FirmwareUpdaterError {
     Signature(signature::Error),
     /// Bad state.
     BadState,
+    /// Invalid firmware patch.
+    Patch(PatchError),
+    /// Error from the image cipher.
+    Encryption,
+    /// Invalid image header.
+    Header(HeaderError),
 }
 
 #[cfg(feature = "defmt")]
@@ -35,6 +44,9 @@ impl defmt::Format for FirmwareUpdaterError {
             FirmwareUpdaterError::Flash(_) => defmt::write!(fmt, "FirmwareUpdaterError::Flash(_)"),
             FirmwareUpdaterError::Signature(_) => defmt::write!(fmt, "FirmwareUpdaterError::Signature(_)"),
             FirmwareUpdaterError::BadState => defmt::write!(fmt, "FirmwareUpdaterError::BadState"),
+            FirmwareUpdaterError::Patch(e) => defmt::write!(fmt, "FirmwareUpdaterError::Patch({})", e),
+            FirmwareUpdaterError::Encryption => defmt::write!(fmt, "FirmwareUpdaterError::Encryption"),
+            FirmwareUpdaterError::Header(e) => defmt::write!(fmt, "FirmwareUpdaterError::Header({})", e),
         }
     }
 }
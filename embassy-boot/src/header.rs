@@ -0,0 +1,126 @@
+//! Signed image headers.
+//!
+//! A signed image starts with a header, holding the image length and version, a SHA-512 hash of
+//! the image and an ed25519 signature of the header. The application is linked to start right after
+//! the header, at `header_len` bytes into the partition, which must satisfy the vector table
+//! alignment of the target.
+//!
+//! The header format is, with all integers little-endian:
+//!
+//! | Offset | Size | Field                                                                |
+//! |--------|------|----------------------------------------------------------------------|
+//! | 0      | 4    | Magic, `EBH1`                                                        |
+//! | 4      | 2    | Header length, including the padding before the image               |
+//! | 6      | 2    | Flags, not interpreted by the bootloader                            |
+//! | 8      | 4    | Image length, not including the header                              |
+//! | 12     | 4    | Image version                                                        |
+//! | 16     | 4    | Security counter                                                     |
+//! | 20     | 12   | Reserved, must be zero                                               |
+//! | 32     | 64   | SHA-512 hash of the image                                            |
+//! | 96     | 64   | ed25519 signature of the SHA-512 digest of the first 96 bytes        |
+//!
+//! The security counter protects against downgrades: the bootloader refuses images whose security
+//! counter is lower than the one stored in a [`SecurityCounter`](crate::SecurityCounter), and
+//! raises the stored counter once an image is marked as booted. Typically, the security counter is
+//! only increased by releases fixing vulnerabilities, so that other downgrades remain possible.
+//!
+//! Signed images are produced on the host with `embassy-boot-tool sign`, the bootloader validates
+//! them with [`BootLoader::prepare_boot_verified`](crate::BootLoader::prepare_boot_verified), or
+//! `DirectXipBootLoader::prepare_boot_verified` for direct-XIP, and the application with
+//! `FirmwareUpdater::verify_image_and_mark_updated`.
+
+/// Magic at the start of an image header.
+pub const HEADER_MAGIC: [u8; 4] = *b"EBH1";
+
+/// Length of the header fields, the minimum header length.
+pub const HEADER_LEN: usize = 160;
+
+/// Length of the header fields covered by the signature.
+#[cfg(feature = "_verify")]
+const SIGNED_LEN: usize = 96;
+
+/// Errors when parsing an image header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum HeaderError {
+    /// The image doesn't start with a header.
+    BadMagic,
+    /// The header or image length is invalid, or the image doesn't fit in the partition.
+    BadLength,
+    /// The reserved fields aren't zero.
+    Reserved,
+}
+
+/// A parsed image header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageHeader {
+    /// Length of the header, the image starts at this offset.
+    pub header_len: u16,
+    /// Application-defined flags.
+    pub flags: u16,
+    /// Length of the image, not including the header.
+    pub image_len: u32,
+    /// Version of the image.
+    pub version: u32,
+    /// Security counter of the image.
+    pub security_counter: u32,
+    /// SHA-512 hash of the image.
+    pub hash: [u8; 64],
+    /// Signature of the header.
+    pub signature: [u8; 64],
+}
+
+impl ImageHeader {
+    /// Parse a header at the start of a partition of `capacity` bytes.
+    pub fn parse(bytes: &[u8; HEADER_LEN], capacity: usize) -> Result<Self, HeaderError> {
+        let u16_at = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+        let u32_at = |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+
+        if bytes[..4] != HEADER_MAGIC {
+            return Err(HeaderError::BadMagic);
+        }
+        if bytes[20..32].iter().any(|&b| b != 0) {
+            return Err(HeaderError::Reserved);
+        }
+
+        let header = Self {
+            header_len: u16_at(4),
+            flags: u16_at(6),
+            image_len: u32_at(8),
+            version: u32_at(12),
+            security_counter: u32_at(16),
+            hash: bytes[32..96].try_into().unwrap(),
+            signature: bytes[96..160].try_into().unwrap(),
+        };
+        let end = (header.header_len as u32).checked_add(header.image_len);
+        if (header.header_len as usize) < HEADER_LEN || end.is_none_or(|end| end as usize > capacity) {
+            return Err(HeaderError::BadLength);
+        }
+        Ok(header)
+    }
+
+    /// Serialize the header fields.
+    pub fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0; HEADER_LEN];
+        bytes[..4].copy_from_slice(&HEADER_MAGIC);
+        bytes[4..6].copy_from_slice(&self.header_len.to_le_bytes());
+        bytes[6..8].copy_from_slice(&self.flags.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.image_len.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.version.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.security_counter.to_le_bytes());
+        bytes[32..96].copy_from_slice(&self.hash);
+        bytes[96..].copy_from_slice(&self.signature);
+        bytes
+    }
+
+    /// Verify the signature of the header with `public_key`.
+    ///
+    /// This doesn't check the image against [`hash`](Self::hash).
+    #[cfg(feature = "_verify")]
+    pub fn verify_signature(&self, public_key: &[u8; 32]) -> Result<(), signature::Error> {
+        use digest::Digest;
+
+        let digest = crate::digest_adapters::Sha512::digest(&self.to_bytes()[..SIGNED_LEN]);
+        crate::digest_adapters::verify_ed25519(public_key, &self.signature, &digest.into())
+    }
+}
@@ -0,0 +1,141 @@
+//! Boot attempts, health reports and revert reasons.
+//!
+//! By default, an update is reverted if the application didn't mark it as booted before the next
+//! reset. With the `health` feature, the bootloader instead counts the boots of the update in the
+//! STATE partition, and only reverts it after
+//! [`max_boot_attempts`](crate::BootLoader::set_max_boot_attempts) boots, so that an unrelated
+//! reset, like a brown-out during the first boot, doesn't throw the update away.
+//!
+//! While the update is on trial, the application can report progress with
+//! `FirmwareState::report_health`, e.g. once the network is up, or give up with
+//! `FirmwareState::mark_failed`, which reverts the update on the next reset. When the bootloader
+//! reverts an update, it records why, with the boot attempts and health checks reached, and the
+//! previous image can read it with `FirmwareState::last_revert` to report it. The record is kept
+//! until the next update is marked.
+//!
+//! These records are stored at the end of the STATE partition, before the IVs of encrypted images:
+//!
+//! | Words                   | Description                                          |
+//! |-------------------------|------------------------------------------------------|
+//! | `MAX_BOOT_ATTEMPTS - 1` | Boot attempts after the first one, one word each     |
+//! | `HEALTH_CHECKS`         | Health checks reported by the update, one word each  |
+//! | 1                       | Set when the update was marked as failed             |
+//! | 4 bytes                 | Reason of the last revert                            |
+//! | 16 bytes                | IV of the previous image while an update is swapped  |
+//! | 16 bytes                | IV of an encrypted update                            |
+//!
+//! The STATE partition must be large enough to hold them after the progress indexes.
+
+use crate::STATE_ERASE_VALUE;
+
+/// Number of health checks the application can report.
+pub const HEALTH_CHECKS: u8 = 8;
+
+/// Maximum number of boot attempts of an update.
+pub const MAX_BOOT_ATTEMPTS: u8 = 16;
+
+/// Length of an IV of encrypted images, see `encryption::iv_slot` and `encryption::backup_iv_slot`.
+const IV_LEN: usize = 16;
+
+/// Length of a serialized [`RevertInfo`].
+pub(crate) const REVERT_INFO_LEN: usize = 4;
+
+/// Why an update was reverted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RevertReason {
+    /// The update wasn't marked as booted within the allowed boot attempts.
+    BootAttempts,
+    /// The update was marked as failed by the application.
+    Failed,
+}
+
+/// Record of the last revert.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RevertInfo {
+    /// Why the update was reverted.
+    pub reason: RevertReason,
+    /// Number of times the update was booted.
+    pub attempts: u8,
+    /// Health checks reported by the update, with bit `n` set for check `n`.
+    pub health: u8,
+}
+
+impl RevertInfo {
+    #[cfg_attr(feature = "overwrite-only", allow(dead_code))]
+    pub(crate) fn to_bytes(self) -> [u8; REVERT_INFO_LEN] {
+        let code = match self.reason {
+            RevertReason::BootAttempts => 0x01,
+            RevertReason::Failed => 0x02,
+        };
+        [code, self.attempts, self.health, !code]
+    }
+
+    pub(crate) fn parse(bytes: &[u8; REVERT_INFO_LEN]) -> Option<Self> {
+        let reason = match bytes[0] {
+            0x01 => RevertReason::BootAttempts,
+            0x02 => RevertReason::Failed,
+            _ => return None,
+        };
+        (bytes[3] == !bytes[0]).then_some(Self {
+            reason,
+            attempts: bytes[1],
+            health: bytes[2],
+        })
+    }
+}
+
+/// Offsets of the health records in the STATE partition.
+#[derive(Clone, Copy)]
+pub(crate) struct Layout {
+    word: usize,
+    /// Start of the records, the end of the space available for progress indexes.
+    pub start: u32,
+    health: u32,
+    pub failed: u32,
+    pub revert_info: u32,
+    pub revert_info_len: usize,
+}
+
+impl Layout {
+    pub fn new(capacity: usize, write_size: usize) -> Self {
+        let revert_info_len = REVERT_INFO_LEN.next_multiple_of(write_size);
+        let ivs_len = 2 * IV_LEN.next_multiple_of(write_size);
+        let records = (MAX_BOOT_ATTEMPTS - 1 + HEALTH_CHECKS + 1) as usize * write_size;
+        assert!(
+            capacity >= ivs_len + revert_info_len + records,
+            "STATE partition too small for health records"
+        );
+
+        let revert_info = capacity - ivs_len - revert_info_len;
+        let failed = revert_info - write_size;
+        let health = failed - HEALTH_CHECKS as usize * write_size;
+        let start = health - (MAX_BOOT_ATTEMPTS - 1) as usize * write_size;
+        Self {
+            word: write_size,
+            start: start as u32,
+            health: health as u32,
+            failed: failed as u32,
+            revert_info: revert_info as u32,
+            revert_info_len,
+        }
+    }
+
+    /// Offset of the word recording the boot attempt after `attempts` boots.
+    pub fn attempt(&self, attempts: u8) -> u32 {
+        assert!((1..MAX_BOOT_ATTEMPTS).contains(&attempts));
+        self.start + (attempts - 1) as u32 * self.word as u32
+    }
+
+    /// Offset of the word recording health check `check`.
+    pub fn health(&self, check: u8) -> u32 {
+        assert!(check < HEALTH_CHECKS);
+        self.health + check as u32 * self.word as u32
+    }
+}
+
+/// Whether a record word was written.
+pub(crate) fn is_set(word: &[u8]) -> bool {
+    word.iter().any(|&b| b != STATE_ERASE_VALUE)
+}
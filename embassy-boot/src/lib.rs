@@ -11,12 +11,28 @@ mod fmt;
 
 mod boot_loader;
 mod digest_adapters;
+#[cfg(feature = "direct-xip")]
+pub mod direct_xip;
+#[cfg(feature = "encryption")]
+pub mod encryption;
 mod firmware_updater;
+pub mod header;
+#[cfg(feature = "health")]
+pub mod health;
 #[cfg(test)]
 mod mem_flash;
+#[cfg(all(feature = "multi-image", not(feature = "overwrite-only")))]
+pub mod multi_image;
+pub mod patch;
+mod security_counter;
+#[cfg(test)]
+mod test_fixtures;
 #[cfg(test)]
 mod test_flash;
 
+#[cfg(all(feature = "multi-image", feature = "overwrite-only"))]
+compile_error!("The `multi-image` feature needs the swap strategy, it can't be used with `overwrite-only`");
+
 // The expected value of the flash after an erase
 // TODO: Use the value provided by NorFlash when available
 #[cfg(not(feature = "flash-erase-zero"))]
@@ -24,11 +40,23 @@ pub(crate) const STATE_ERASE_VALUE: u8 = 0xFF;
 #[cfg(feature = "flash-erase-zero")]
 pub(crate) const STATE_ERASE_VALUE: u8 = 0x00;
 
+/// The value of erased flash bytes, `0x00` with the `flash-erase-zero` feature and `0xFF` otherwise.
+pub const ERASE_VALUE: u8 = STATE_ERASE_VALUE;
+
 pub use boot_loader::{BootError, BootLoader, BootLoaderConfig};
+#[cfg(feature = "direct-xip")]
+pub use direct_xip::{BlockingDirectXipUpdater, DirectXipBootLoader, DirectXipConfig, DirectXipUpdater, Slot};
 pub use firmware_updater::{
     BlockingFirmwareState, BlockingFirmwareUpdater, FirmwareState, FirmwareUpdater, FirmwareUpdaterConfig,
     FirmwareUpdaterError,
 };
+pub use header::{HeaderError, ImageHeader};
+#[cfg(feature = "health")]
+pub use health::{RevertInfo, RevertReason};
+#[cfg(all(feature = "multi-image", not(feature = "overwrite-only")))]
+pub use multi_image::{BlockingMultiImageUpdater, MultiBootLoader, MultiImageUpdater, PackageDecoder, PackageError};
+pub use patch::{PatchDecoder, PatchError};
+pub use security_counter::{FlashSecurityCounter, SecurityCounter};
 
 pub(crate) const REVERT_MAGIC: u8 = 0xC0;
 pub(crate) const BOOT_MAGIC: u8 = 0xD0;
@@ -135,7 +163,7 @@ mod tests {
     }
 
     #[test]
-    #[cfg(not(feature = "_verify"))]
+    #[cfg(not(any(feature = "_verify", feature = "overwrite-only")))]
     fn test_swap_state() {
         // The flashes and buffers used here are large, run on a thread with a bigger stack.
         std::thread::Builder::new()
@@ -146,7 +174,7 @@ mod tests {
             .unwrap();
     }
 
-    #[cfg(not(feature = "_verify"))]
+    #[cfg(not(any(feature = "_verify", feature = "overwrite-only")))]
     fn test_swap_state_inner() {
         const FIRMWARE_SIZE: usize = 57344;
         let flash = AsyncTestFlash::new(BootLoaderConfig {
@@ -227,7 +255,150 @@ mod tests {
     }
 
     #[test]
-    #[cfg(not(feature = "_verify"))]
+    #[cfg(all(feature = "health", not(any(feature = "_verify", feature = "overwrite-only"))))]
+    fn test_health_revert() {
+        const FIRMWARE_SIZE: usize = 16384;
+        let flash = BlockingTestFlash::new(BootLoaderConfig {
+            active: MemFlash::<FIRMWARE_SIZE, 4096, 4>::default(),
+            dfu: MemFlash::<20480, 4096, 4>::default(),
+            state: MemFlash::<4096, 4096, 4>::default(),
+        });
+
+        const ORIGINAL: [u8; FIRMWARE_SIZE] = [0x55; FIRMWARE_SIZE];
+        const UPDATE: [u8; FIRMWARE_SIZE] = [0xAA; FIRMWARE_SIZE];
+        let mut aligned = [0; 4];
+        let mut page = [0; 4096];
+        let mut read_buf = [0; FIRMWARE_SIZE];
+
+        flash.active().erase(0, ORIGINAL.len() as u32).unwrap();
+        flash.active().write(0, &ORIGINAL).unwrap();
+
+        let update = |aligned: &mut [u8]| {
+            let mut updater = BlockingFirmwareUpdater::new(
+                FirmwareUpdaterConfig {
+                    dfu: flash.dfu(),
+                    state: flash.state(),
+                },
+                aligned,
+            );
+            updater.write_firmware(0, &UPDATE).unwrap();
+            updater.mark_updated().unwrap();
+        };
+        update(&mut aligned);
+
+        let mut bootloader = BootLoader::new(BootLoaderConfig {
+            active: flash.active(),
+            dfu: flash.dfu(),
+            state: flash.state(),
+        });
+        bootloader.set_max_boot_attempts(3);
+
+        assert_eq!(State::Swap, bootloader.prepare_boot(&mut page).unwrap());
+        let mut state = BlockingFirmwareState::new(flash.state(), &mut aligned);
+        assert_eq!(1, state.boot_attempts().unwrap());
+        state.report_health(2).unwrap();
+        assert_eq!(0b100, state.health().unwrap());
+
+        // The update is booted again until it runs out of attempts
+        assert_eq!(State::Swap, bootloader.prepare_boot(&mut page).unwrap());
+        let mut state = BlockingFirmwareState::new(flash.state(), &mut aligned);
+        assert_eq!(2, state.boot_attempts().unwrap());
+        assert_eq!(State::Swap, bootloader.prepare_boot(&mut page).unwrap());
+        let mut state = BlockingFirmwareState::new(flash.state(), &mut aligned);
+        assert_eq!(3, state.boot_attempts().unwrap());
+        assert_eq!(None, state.last_revert().unwrap());
+
+        assert_eq!(State::Swap, bootloader.prepare_boot(&mut page).unwrap());
+        assert_eq!(State::Revert, bootloader.prepare_boot(&mut page).unwrap());
+        flash.active().read(0, &mut read_buf).unwrap();
+        assert_eq!(ORIGINAL, read_buf);
+
+        // The previous image finds why the update was reverted, even after marking itself as booted
+        let expected = RevertInfo {
+            reason: RevertReason::BootAttempts,
+            attempts: 3,
+            health: 0b100,
+        };
+        let mut state = BlockingFirmwareState::new(flash.state(), &mut aligned);
+        assert_eq!(Some(expected), state.last_revert().unwrap());
+        state.mark_booted().unwrap();
+        assert_eq!(Some(expected), state.last_revert().unwrap());
+        assert!(matches!(state.report_health(0), Err(FirmwareUpdaterError::BadState)));
+        assert_eq!(State::Boot, bootloader.prepare_boot(&mut page).unwrap());
+
+        // A new update clears the record, and is reverted as soon as it's marked as failed
+        update(&mut aligned);
+        let mut state = BlockingFirmwareState::new(flash.state(), &mut aligned);
+        assert_eq!(None, state.last_revert().unwrap());
+        assert_eq!(State::Swap, bootloader.prepare_boot(&mut page).unwrap());
+        let mut state = BlockingFirmwareState::new(flash.state(), &mut aligned);
+        state.mark_failed().unwrap();
+
+        assert_eq!(State::Swap, bootloader.prepare_boot(&mut page).unwrap());
+        assert_eq!(State::Revert, bootloader.prepare_boot(&mut page).unwrap());
+        flash.active().read(0, &mut read_buf).unwrap();
+        assert_eq!(ORIGINAL, read_buf);
+        let mut state = BlockingFirmwareState::new(flash.state(), &mut aligned);
+        assert_eq!(
+            Some(RevertInfo {
+                reason: RevertReason::Failed,
+                attempts: 1,
+                health: 0,
+            }),
+            state.last_revert().unwrap()
+        );
+    }
+
+    #[test]
+    #[cfg(all(feature = "overwrite-only", not(feature = "_verify")))]
+    fn test_overwrite_state() {
+        const FIRMWARE_SIZE: usize = 16384;
+        let flash = AsyncTestFlash::new(BootLoaderConfig {
+            active: MemFlash::<FIRMWARE_SIZE, 4096, 4>::default(),
+            // Overwriting doesn't need a spare page in the DFU partition.
+            dfu: MemFlash::<FIRMWARE_SIZE, 4096, 4>::default(),
+            state: MemFlash::<4096, 4096, 4>::default(),
+        });
+
+        const ORIGINAL: [u8; FIRMWARE_SIZE] = [0x55; FIRMWARE_SIZE];
+        const UPDATE: [u8; FIRMWARE_SIZE] = [0xAA; FIRMWARE_SIZE];
+        let mut aligned = [0; 4];
+
+        block_on(flash.active().erase(0, ORIGINAL.len() as u32)).unwrap();
+        block_on(flash.active().write(0, &ORIGINAL)).unwrap();
+
+        let mut updater = FirmwareUpdater::new(
+            FirmwareUpdaterConfig {
+                dfu: flash.dfu(),
+                state: flash.state(),
+            },
+            &mut aligned,
+        );
+        block_on(updater.write_firmware(0, &UPDATE)).unwrap();
+        block_on(updater.mark_updated()).unwrap();
+
+        let flash = flash.into_blocking();
+        let mut bootloader = BootLoader::new(BootLoaderConfig {
+            active: flash.active(),
+            dfu: flash.dfu(),
+            state: flash.state(),
+        });
+
+        let mut page = [0; 1024];
+        assert_eq!(State::Swap, bootloader.prepare_boot(&mut page).unwrap());
+
+        let mut read_buf = [0; FIRMWARE_SIZE];
+        flash.active().read(0, &mut read_buf).unwrap();
+        assert_eq!(UPDATE, read_buf);
+
+        // There is nothing to revert to, the update keeps booting
+        assert_eq!(State::Boot, bootloader.prepare_boot(&mut page).unwrap());
+        flash.active().read(0, &mut read_buf).unwrap();
+        assert_eq!(UPDATE, read_buf);
+    }
+
+    #[test]
+    #[cfg(not(any(feature = "_verify", feature = "overwrite-only")))]
     fn test_swap_state_active_page_biggest() {
         const FIRMWARE_SIZE: usize = 12288;
         let flash = AsyncTestFlash::new(BootLoaderConfig {
@@ -272,7 +443,7 @@ mod tests {
     }
 
     #[test]
-    #[cfg(not(feature = "_verify"))]
+    #[cfg(not(any(feature = "_verify", feature = "overwrite-only")))]
     fn test_swap_state_dfu_page_biggest() {
         const FIRMWARE_SIZE: usize = 12288;
         let flash = AsyncTestFlash::new(BootLoaderConfig {
@@ -315,6 +486,102 @@ mod tests {
         assert_eq!(ORIGINAL, read_buf);
     }
 
+    #[test]
+    #[cfg(all(feature = "encryption", not(any(feature = "_verify", feature = "overwrite-only"))))]
+    fn test_swap_encrypted() {
+        use crate::encryption::{Aes, GCM_NONCE_LEN, ImageCipher, Key, NonceSource};
+
+        struct Nonces(u8);
+
+        impl NonceSource for Nonces {
+            type Error = core::convert::Infallible;
+
+            fn fill_nonce(&mut self, nonce: &mut [u8; GCM_NONCE_LEN]) -> Result<(), Self::Error> {
+                self.0 += 1;
+                nonce.fill(self.0);
+                Ok(())
+            }
+        }
+
+        const FIRMWARE_SIZE: usize = 12288;
+        let flash = BlockingTestFlash::new(BootLoaderConfig {
+            active: MemFlash::<FIRMWARE_SIZE, 4096, 4>::default(),
+            dfu: MemFlash::<16384, 4096, 4>::default(),
+            state: MemFlash::<4096, 4096, 4>::default(),
+        });
+
+        let mut cipher = Aes::new(Key::Aes128([0x2b; 16]));
+        let mut nonces = Nonces(0);
+        let iv = [0x42; 16];
+
+        let original: [u8; FIRMWARE_SIZE] = core::array::from_fn(|i| i as u8);
+        let update: [u8; FIRMWARE_SIZE] = core::array::from_fn(|i| (i / 3) as u8);
+        let mut encrypted = update;
+        cipher.apply_keystream(&iv, 0, &mut encrypted).unwrap();
+
+        flash.active().erase(0, FIRMWARE_SIZE as u32).unwrap();
+        flash.active().write(0, &original).unwrap();
+
+        let mut aligned = [0; 4];
+        let mut updater = BlockingFirmwareUpdater::new(
+            FirmwareUpdaterConfig {
+                dfu: flash.dfu(),
+                state: flash.state(),
+            },
+            &mut aligned,
+        );
+        updater.write_firmware(0, &encrypted).unwrap();
+        updater.mark_updated_encrypted(&iv).unwrap();
+
+        let mut bootloader = BootLoader::new(BootLoaderConfig {
+            active: flash.active(),
+            dfu: flash.dfu(),
+            state: flash.state(),
+        });
+
+        let mut page = [0; 1024];
+        assert_eq!(
+            State::Swap,
+            bootloader
+                .prepare_boot_encrypted(&mut page, &mut cipher, &mut nonces)
+                .unwrap()
+        );
+
+        let mut read_buf = [0; FIRMWARE_SIZE];
+        flash.active().read(0, &mut read_buf).unwrap();
+        assert_eq!(update, read_buf);
+        // The previous firmware is encrypted in DFU
+        flash.dfu().read(4096, &mut read_buf).unwrap();
+        assert_ne!(original, read_buf);
+        cipher
+            .apply_keystream(&encryption::backup_iv(&[1; GCM_NONCE_LEN]), 0, &mut read_buf)
+            .unwrap();
+        assert_eq!(original, read_buf);
+
+        // Running again should cause a revert
+        assert_eq!(
+            State::Swap,
+            bootloader
+                .prepare_boot_encrypted(&mut page, &mut cipher, &mut nonces)
+                .unwrap()
+        );
+        assert_eq!(
+            State::Revert,
+            bootloader
+                .prepare_boot_encrypted(&mut page, &mut cipher, &mut nonces)
+                .unwrap()
+        );
+
+        // The revert uses the nonce stored before the swap
+        assert_eq!(1, nonces.0);
+
+        flash.active().read(0, &mut read_buf).unwrap();
+        assert_eq!(original, read_buf);
+        // The update is re-encrypted to the original ciphertext
+        flash.dfu().read(0, &mut read_buf).unwrap();
+        assert_eq!(encrypted, read_buf);
+    }
+
     #[test]
     #[cfg(feature = "_verify")]
     fn test_verify() {
@@ -368,4 +635,103 @@ mod tests {
             .is_ok()
         );
     }
+
+    #[test]
+    #[cfg(all(feature = "ed25519-dalek", not(feature = "overwrite-only")))]
+    fn test_signed_image() {
+        extern crate std;
+
+        use ed25519_dalek::SigningKey;
+
+        use crate::test_fixtures::sign;
+
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let public_key = key.verifying_key().to_bytes();
+        let signed = |version, security_counter| {
+            let image: std::vec::Vec<u8> = (0..5000).map(|i| (i * version) as u8).collect();
+            let mut signed = sign(&image, &key, version, security_counter);
+            signed.resize(8192, 0xFF);
+            signed
+        };
+
+        let flash = BlockingTestFlash::new(BootLoaderConfig {
+            active: MemFlash::<8192, 4096, 4>::default(),
+            dfu: MemFlash::<12288, 4096, 4>::default(),
+            state: MemFlash::<4096, 4096, 4>::default(),
+        });
+        let mut counter_flash = MemFlash::<4096, 4096, 4>::default();
+        let mut counter_buf = [0; 4];
+        let mut counter = FlashSecurityCounter::new(&mut counter_flash, &mut counter_buf);
+        let mut page = [0; 1024];
+        let mut aligned = [0; 4];
+
+        let mut boot = |counter: &mut FlashSecurityCounter<_>| {
+            let mut bootloader = BootLoader::new(BootLoaderConfig {
+                active: flash.active(),
+                dfu: flash.dfu(),
+                state: flash.state(),
+            });
+            bootloader
+                .prepare_boot_verified(&mut page, &public_key, counter)
+                .map(|(state, header)| (state, header.version))
+        };
+        let mut update = |image: &[u8], mark_booted: bool| {
+            let mut updater = BlockingFirmwareUpdater::new(
+                FirmwareUpdaterConfig {
+                    dfu: flash.dfu(),
+                    state: flash.state(),
+                },
+                &mut aligned,
+            );
+            if mark_booted {
+                updater.mark_booted().unwrap();
+            } else {
+                updater.write_firmware(0, image).unwrap();
+                updater.verify_image_and_mark_updated(&public_key).unwrap();
+            }
+        };
+
+        flash.active().write(0, &signed(1, 1)).unwrap();
+        assert_eq!(Ok((State::Boot, 1)), boot(&mut counter));
+        assert_eq!(1, counter.read().unwrap());
+
+        // The counter is only raised once the update is marked as booted
+        update(&signed(2, 3), false);
+        assert_eq!(Ok((State::Swap, 2)), boot(&mut counter));
+        assert_eq!(1, counter.read().unwrap());
+        update(&[], true);
+        assert_eq!(Ok((State::Boot, 2)), boot(&mut counter));
+        assert_eq!(3, counter.read().unwrap());
+
+        // Downgrades are discarded
+        update(&signed(1, 1), false);
+        assert_eq!(Ok((State::Boot, 2)), boot(&mut counter));
+
+        // Versions with the same security counter are fine
+        update(&signed(3, 3), false);
+        assert_eq!(Ok((State::Swap, 3)), boot(&mut counter));
+
+        // A downgrade staged right after an update is marked as booted is discarded too
+        update(&[], true);
+        update(&signed(4, 5), false);
+        assert_eq!(Ok((State::Swap, 4)), boot(&mut counter));
+        update(&[], true);
+        update(&signed(5, 3), false);
+        assert_eq!(Ok((State::Boot, 4)), boot(&mut counter));
+        assert_eq!(5, counter.read().unwrap());
+
+        // Tampered images are refused by the updater
+        let mut image = signed(4, 3);
+        image[100] ^= 1;
+        let mut updater = BlockingFirmwareUpdater::new(
+            FirmwareUpdaterConfig {
+                dfu: flash.dfu(),
+                state: flash.state(),
+            },
+            &mut aligned,
+        );
+        updater.mark_booted().unwrap();
+        updater.write_firmware(0, &image).unwrap();
+        assert!(updater.verify_image_and_mark_updated(&public_key).is_err());
+    }
 }
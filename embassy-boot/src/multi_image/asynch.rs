@@ -0,0 +1,246 @@
+use digest::Digest;
+use embedded_storage_async::nor_flash::NorFlash;
+
+use super::{Group, IMAGES, Layout, MAGIC, MAX_IMAGES, STARTED, TRIAL, is_set};
+use crate::{BOOT_MAGIC, FirmwareUpdaterError, STATE_ERASE_VALUE, SWAP_MAGIC, State};
+
+/// Multi-image updater, writing the images of an update to their DFU partitions.
+///
+/// This is the multi-image equivalent of [`FirmwareUpdater`](crate::FirmwareUpdater),
+/// see the [`multi_image`](crate::multi_image) module. It only owns the group STATE partition, the
+/// DFU partition of each image is passed to the methods writing and reading it.
+pub struct MultiImageUpdater<'d, STATE: NorFlash> {
+    state: STATE,
+    aligned: &'d mut [u8],
+    layout: Layout,
+    last_erased_sector_index: [Option<usize>; MAX_IMAGES],
+    #[cfg(feature = "_verify")]
+    verified: u32,
+}
+
+impl<'d, STATE: NorFlash> MultiImageUpdater<'d, STATE> {
+    /// Create a multi-image updater instance with the given group state partition.
+    ///
+    /// The `aligned` buffer must be at least 4 bytes and `STATE::WRITE_SIZE`, and aligned for the
+    /// STATE flash.
+    pub fn new(state: STATE, aligned: &'d mut [u8]) -> Self {
+        let layout = Layout::new(STATE::WRITE_SIZE, STATE::ERASE_SIZE, state.capacity(), aligned.len());
+        Self {
+            state,
+            aligned,
+            layout,
+            last_erased_sector_index: [None; MAX_IMAGES],
+            #[cfg(feature = "_verify")]
+            verified: 0,
+        }
+    }
+
+    /// Obtain the current state.
+    ///
+    /// This is useful to check if the bootloader has just swapped the images of an update, in order
+    /// to do verifications and self-tests before marking them as booted.
+    pub async fn get_state(&mut self) -> Result<State, FirmwareUpdaterError> {
+        Ok(read_group(&mut self.state, self.layout, self.aligned).await?.state)
+    }
+
+    /// Writes firmware data to the DFU partition of `image`.
+    ///
+    /// Sectors are erased as they are first written to, like with
+    /// [`FirmwareUpdater::write_firmware`](crate::FirmwareUpdater::write_firmware).
+    pub async fn write_firmware<DFU: NorFlash>(
+        &mut self,
+        image: usize,
+        dfu: &mut DFU,
+        offset: usize,
+        data: &[u8],
+    ) -> Result<(), FirmwareUpdaterError> {
+        assert!(image < MAX_IMAGES);
+        self.verify_booted().await?;
+        #[cfg(feature = "_verify")]
+        {
+            self.verified &= !(1 << image);
+        }
+
+        let mut remaining_data = data;
+        let mut offset = offset;
+
+        while !remaining_data.is_empty() {
+            let current_sector = offset / DFU::ERASE_SIZE;
+            let sector_start = current_sector * DFU::ERASE_SIZE;
+            let sector_end = sector_start + DFU::ERASE_SIZE;
+
+            if self.last_erased_sector_index[image] != Some(current_sector) {
+                dfu.erase(sector_start as u32, sector_end as u32).await?;
+                self.last_erased_sector_index[image] = Some(current_sector);
+            }
+
+            let write_size = core::cmp::min(remaining_data.len(), sector_end - offset);
+            let (data_chunk, rest) = remaining_data.split_at(write_size);
+            dfu.write(offset as u32, data_chunk).await?;
+
+            remaining_data = rest;
+            offset += write_size;
+        }
+
+        Ok(())
+    }
+
+    /// Verify the image in a DFU partition with any digest.
+    pub async fn hash<DFU: NorFlash, D: Digest>(
+        &mut self,
+        dfu: &mut DFU,
+        update_len: u32,
+        chunk_buf: &mut [u8],
+        output: &mut [u8],
+    ) -> Result<(), FirmwareUpdaterError> {
+        let mut digest = D::new();
+        for offset in (0..update_len).step_by(chunk_buf.len()) {
+            dfu.read(offset, chunk_buf).await?;
+            let len = core::cmp::min((update_len - offset) as usize, chunk_buf.len());
+            digest.update(&chunk_buf[..len]);
+        }
+        output.copy_from_slice(digest.finalize().as_slice());
+        Ok(())
+    }
+
+    /// Verify the signed image of `image` in its DFU partition given a public key, and return its
+    /// header.
+    ///
+    /// The image must start with an [`ImageHeader`](crate::header::ImageHeader), whose signature and
+    /// hash are checked. Only verified images can be marked as updated.
+    #[cfg(feature = "_verify")]
+    pub async fn verify_image<DFU: NorFlash>(
+        &mut self,
+        image: usize,
+        dfu: &mut DFU,
+        public_key: &[u8; 32],
+    ) -> Result<crate::header::ImageHeader, FirmwareUpdaterError> {
+        use crate::header::{HEADER_LEN, ImageHeader};
+
+        assert!(image < MAX_IMAGES);
+        let mut bytes = [0; HEADER_LEN];
+        dfu.read(0, &mut bytes).await?;
+        let header = ImageHeader::parse(&bytes, dfu.capacity()).map_err(FirmwareUpdaterError::Header)?;
+        header
+            .verify_signature(public_key)
+            .map_err(FirmwareUpdaterError::Signature)?;
+
+        let mut digest = crate::digest_adapters::Sha512::new();
+        let mut chunk_buf = [0; 64];
+        let start = header.header_len as u32;
+        let end = start + header.image_len;
+        for offset in (start..end).step_by(chunk_buf.len()) {
+            dfu.read(offset, &mut chunk_buf).await?;
+            let len = core::cmp::min((end - offset) as usize, chunk_buf.len());
+            digest.update(&chunk_buf[..len]);
+        }
+        if digest.finalize().as_slice() != header.hash {
+            return Err(FirmwareUpdaterError::Signature(signature::Error::new()));
+        }
+
+        self.verified |= 1 << image;
+        Ok(header)
+    }
+
+    /// Mark the images in the `images` bitmask to be swapped together on next boot.
+    ///
+    /// With a signing feature, every image must have been verified with
+    /// `verify_image` since it was last written.
+    pub async fn mark_updated(&mut self, images: u32) -> Result<(), FirmwareUpdaterError> {
+        assert!(images != 0 && images < 1 << MAX_IMAGES);
+        self.verify_booted().await?;
+        #[cfg(feature = "_verify")]
+        if self.verified & images != images {
+            return Err(FirmwareUpdaterError::BadState);
+        }
+
+        erase_group(&mut self.state, self.layout).await?;
+        write_word(&mut self.state, self.layout, self.aligned, IMAGES, images).await?;
+        write_magic(&mut self.state, self.layout, self.aligned, SWAP_MAGIC).await?;
+
+        self.last_erased_sector_index = [None; MAX_IMAGES];
+        Ok(())
+    }
+
+    /// Mark the running images as booted, and stop them from being reverted on reset.
+    pub async fn mark_booted(&mut self) -> Result<(), FirmwareUpdaterError> {
+        if self.get_state().await? == State::Boot {
+            return Ok(());
+        }
+        erase_group(&mut self.state, self.layout).await?;
+        write_magic(&mut self.state, self.layout, self.aligned, BOOT_MAGIC).await?;
+        Ok(())
+    }
+
+    /// Reset to initial/uninitialised state.
+    ///
+    /// After reset, the updater will behave as if no writes had
+    /// occurred, with writes beginning at offset 0 again.
+    pub async fn reset(&mut self) -> Result<(), FirmwareUpdaterError> {
+        self.verify_booted().await?;
+        self.last_erased_sector_index = [None; MAX_IMAGES];
+        #[cfg(feature = "_verify")]
+        {
+            self.verified = 0;
+        }
+        Ok(())
+    }
+
+    // Make sure we are running booted images to avoid reverting to a bad state.
+    async fn verify_booted(&mut self) -> Result<(), FirmwareUpdaterError> {
+        match self.get_state().await? {
+            State::Swap => Err(FirmwareUpdaterError::BadState),
+            _ => Ok(()),
+        }
+    }
+}
+
+async fn read_group<STATE: NorFlash>(
+    state: &mut STATE,
+    layout: Layout,
+    aligned: &mut [u8],
+) -> Result<Group, STATE::Error> {
+    let buf = &mut aligned[..layout.word];
+    state.read(layout.word(MAGIC), buf).await?;
+    let magic = State::from(&*buf);
+    state.read(layout.word(IMAGES), buf).await?;
+    let images = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
+    state.read(layout.word(STARTED), buf).await?;
+    let started = is_set(buf);
+    state.read(layout.word(TRIAL), buf).await?;
+    let trial = is_set(buf);
+    Ok(Group {
+        state: magic,
+        images,
+        started,
+        trial,
+    })
+}
+
+async fn write_word<STATE: NorFlash>(
+    state: &mut STATE,
+    layout: Layout,
+    aligned: &mut [u8],
+    word: usize,
+    value: u32,
+) -> Result<(), STATE::Error> {
+    let buf = &mut aligned[..layout.word];
+    buf.fill(STATE_ERASE_VALUE);
+    buf[..4].copy_from_slice(&value.to_le_bytes());
+    state.write(layout.word(word), buf).await
+}
+
+async fn write_magic<STATE: NorFlash>(
+    state: &mut STATE,
+    layout: Layout,
+    aligned: &mut [u8],
+    magic: u8,
+) -> Result<(), STATE::Error> {
+    let buf = &mut aligned[..layout.word];
+    buf.fill(magic);
+    state.write(layout.word(MAGIC), buf).await
+}
+
+async fn erase_group<STATE: NorFlash>(state: &mut STATE, layout: Layout) -> Result<(), STATE::Error> {
+    state.erase(0, layout.len as u32).await
+}
@@ -0,0 +1,487 @@
+//! Multi-image updates.
+//!
+//! Some parts run several images, like the application and network cores of the nRF5340, or the
+//! application and radio coprocessor of the STM32WB. Each image has its own ACTIVE, DFU and STATE
+//! partitions, possibly on different flash devices shared with
+//! `embassy_embedded_hal::flash::partition`, and is handled by a [`BootLoader`](crate::BootLoader)
+//! like single-image updates. A [`MultiBootLoader`] swaps the images of an update together: the
+//! update is only booted once every image was swapped, and if it isn't marked as booted, every image
+//! is reverted.
+//!
+//! The images of an update are selected by a group STATE partition, shared by all images, which
+//! holds 4 words of 4 bytes rounded up to the STATE write size:
+//!
+//! | Word | Description                                                                       |
+//! |------|-----------------------------------------------------------------------------------|
+//! | 0    | Magic, like the STATE partition of a single image                                 |
+//! | 1    | Bitmask of the images of the update, bit N for the image at index N               |
+//! | 2    | Started: the images were prepared to be swapped                                   |
+//! | 3    | Trial: every image was swapped, and the update was booted                         |
+//!
+//! The STATE partition of each image only holds the swap progress, and is only written by the
+//! bootloader. The updater writes the images to their DFU partitions with
+//! [`MultiImageUpdater::write_firmware`], and marks them to be swapped with
+//! [`MultiImageUpdater::mark_updated`]. Swapping and reverting resume where they stopped after a
+//! power failure, like with a single image.
+//!
+//! Updates can be shipped as a package holding several images, built on the host with
+//! `embassy-boot-tool package`, and decoded on the device with a [`PackageDecoder`]. Each image in a
+//! package can depend on a minimum version of another image, which must be in the package or already
+//! installed.
+//!
+//! # Package format
+//!
+//! All integers are little-endian. The header and each image are padded with `0xFF` to a multiple of
+//! [`PACKAGE_ALIGN`] bytes, so that writes of the images stay aligned.
+//!
+//! | Field     | Size      | Description                              |
+//! |-----------|-----------|------------------------------------------|
+//! | magic     | 4         | `b"EBMP"`                                |
+//! | version   | 1         | Version of the format, 1                 |
+//! | count     | 1         | Number of images, 1 to [`MAX_IMAGES`]    |
+//! | reserved  | 2         |                                          |
+//! | entries   | 16 each   | One entry per image, see below           |
+//! | images    | ...       | The images, in the order of the entries  |
+//!
+//! | Field       | Size | Description                                               |
+//! |-------------|------|-----------------------------------------------------------|
+//! | image       | 1    | Index of the image                                        |
+//! | dependency  | 1    | Index of the image this one depends on, `0xFF` for none   |
+//! | reserved    | 2    |                                                           |
+//! | len         | 4    | Length of the image in bytes, without padding             |
+//! | version     | 4    | Version of the image                                      |
+//! | min_version | 4    | Minimum version of the dependency                         |
+//!
+//! Images are swapped without decryption, and each update is booted once before it is reverted,
+//! regardless of the `encryption` and `health` features.
+
+mod asynch;
+mod blocking;
+mod package;
+
+pub use asynch::MultiImageUpdater;
+pub use blocking::BlockingMultiImageUpdater;
+use embedded_storage::nor_flash::NorFlash;
+pub use package::{PACKAGE_ALIGN, PACKAGE_MAGIC, PackageDecoder, PackageError, Segment};
+
+use crate::{BOOT_MAGIC, BootError, REVERT_MAGIC, STATE_ERASE_VALUE, SWAP_MAGIC, State};
+
+/// Maximum number of images handled by a multi-image bootloader.
+pub const MAX_IMAGES: usize = 8;
+
+const MAGIC: usize = 0;
+const IMAGES: usize = 1;
+const STARTED: usize = 2;
+const TRIAL: usize = 3;
+const WORDS: usize = 4;
+
+/// Operations of a [`BootLoader`](crate::BootLoader) used by the multi-image bootloader.
+pub(crate) trait SealedImage {
+    fn assert_config(&self, aligned_buf: &[u8]);
+    fn read_state(&mut self, aligned_buf: &mut [u8]) -> Result<State, BootError>;
+    fn reset_state(&mut self, magic: u8, aligned_buf: &mut [u8]) -> Result<(), BootError>;
+    fn is_swapped(&mut self, aligned_buf: &mut [u8]) -> Result<bool, BootError>;
+    fn swap(&mut self, aligned_buf: &mut [u8]) -> Result<(), BootError>;
+    fn revert(&mut self, aligned_buf: &mut [u8]) -> Result<(), BootError>;
+}
+
+/// An image handled by a [`MultiBootLoader`], implemented by [`BootLoader`](crate::BootLoader).
+#[allow(private_bounds)]
+pub trait Image: SealedImage {}
+
+/// Location of the words in the group STATE partition.
+#[derive(Clone, Copy)]
+struct Layout {
+    word: usize,
+    /// Length of the erased range, in whole erase sectors.
+    len: usize,
+}
+
+impl Layout {
+    fn new(write_size: usize, erase_size: usize, capacity: usize, aligned_len: usize) -> Self {
+        let word = 4usize.next_multiple_of(write_size);
+        let len = (WORDS * word).next_multiple_of(erase_size);
+        assert!(len <= capacity);
+        assert!(aligned_len >= word);
+        Self { word, len }
+    }
+
+    fn word(&self, word: usize) -> u32 {
+        (word * self.word) as u32
+    }
+}
+
+/// The content of the group STATE partition.
+struct Group {
+    state: State,
+    images: u32,
+    started: bool,
+    trial: bool,
+}
+
+/// Whether a flag word has been written.
+fn is_set(word: &[u8]) -> bool {
+    word.iter().any(|&b| b != STATE_ERASE_VALUE)
+}
+
+/// Bootloader for updates of several images, swapped and reverted together.
+///
+/// See the [`multi_image`](crate::multi_image) module.
+pub struct MultiBootLoader<STATE: NorFlash> {
+    state: STATE,
+}
+
+impl<STATE: NorFlash> MultiBootLoader<STATE> {
+    /// Create a new instance of a multi-image bootloader with the given group state partition.
+    pub fn new(state: STATE) -> Self {
+        Self { state }
+    }
+
+    /// Perform necessary boot preparations like swapping images.
+    ///
+    /// `images` are the bootloaders of each image, indexed like in
+    /// [`MultiImageUpdater::mark_updated`]. The `aligned_buf` must satisfy the requirements of
+    /// [`BootLoader::prepare_boot`](crate::BootLoader::prepare_boot) for every image, and be at
+    /// least 4 bytes and the write size of the group STATE partition.
+    ///
+    /// Returns [`State::Swap`] when booting an update after swapping its images, and when booting the
+    /// previous images after reverting an update that wasn't marked as booted, like
+    /// [`BootLoader::prepare_boot`](crate::BootLoader::prepare_boot).
+    pub fn prepare_boot(&mut self, images: &mut [&mut dyn Image], aligned_buf: &mut [u8]) -> Result<State, BootError> {
+        assert!(images.len() <= MAX_IMAGES);
+        let layout = Layout::new(
+            STATE::WRITE_SIZE,
+            STATE::ERASE_SIZE,
+            self.state.capacity(),
+            aligned_buf.len(),
+        );
+        for image in images.iter() {
+            image.assert_config(aligned_buf);
+        }
+
+        let group = blocking::read_group(&mut self.state, layout, aligned_buf)?;
+        if group.state != State::Swap {
+            return Ok(group.state);
+        }
+
+        let updated = |index: usize| group.images & (1 << index) != 0;
+        if !group.trial {
+            if !group.started {
+                // Clear the progress of previous updates, so that every image is swapped again.
+                for (_, image) in images.iter_mut().enumerate().filter(|(index, _)| updated(*index)) {
+                    image.reset_state(SWAP_MAGIC, aligned_buf)?;
+                }
+                blocking::set_flag(&mut self.state, layout, aligned_buf, STARTED)?;
+            }
+
+            for (index, image) in images.iter_mut().enumerate().filter(|(index, _)| updated(*index)) {
+                if !image.is_swapped(aligned_buf)? {
+                    trace!("Swapping image {}", index);
+                    image.swap(aligned_buf)?;
+                }
+            }
+            blocking::set_flag(&mut self.state, layout, aligned_buf, TRIAL)?;
+        } else {
+            for (index, image) in images.iter_mut().enumerate().filter(|(index, _)| updated(*index)) {
+                // Reverted images are marked as booted, so they aren't reverted twice.
+                if image.read_state(aligned_buf)? == State::Swap {
+                    trace!("Reverting image {}", index);
+                    image.revert(aligned_buf)?;
+                    image.reset_state(BOOT_MAGIC, aligned_buf)?;
+                }
+            }
+            blocking::erase_group(&mut self.state, layout)?;
+            blocking::write_magic(&mut self.state, layout, aligned_buf, REVERT_MAGIC)?;
+        }
+        Ok(State::Swap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use embedded_storage::nor_flash::NorFlashErrorKind;
+
+    use super::*;
+    use crate::mem_flash::MemFlash;
+    use crate::test_fixtures::{PackageImage, package as build};
+    use crate::{BootLoader, BootLoaderConfig, FirmwareUpdaterError};
+
+    type Active0 = MemFlash<16384, 4096, 4>;
+    type Dfu0 = MemFlash<20480, 4096, 4>;
+    type Active1 = MemFlash<4096, 512, 8>;
+    type Dfu1 = MemFlash<4608, 512, 8>;
+    type ImageState = MemFlash<4096, 512, 8>;
+    type GroupState = MemFlash<4096, 4096, 4>;
+
+    /// The flashes of two images with different geometries, and the group state.
+    #[derive(Default)]
+    struct Flash {
+        active0: Active0,
+        dfu0: Dfu0,
+        state0: ImageState,
+        active1: Active1,
+        dfu1: Dfu1,
+        state1: ImageState,
+        group: GroupState,
+    }
+
+    /// An image failing to swap, like a power failure in the middle of an update.
+    struct Failing<'a>(&'a mut dyn Image);
+
+    impl SealedImage for Failing<'_> {
+        fn assert_config(&self, aligned_buf: &[u8]) {
+            self.0.assert_config(aligned_buf)
+        }
+
+        fn read_state(&mut self, aligned_buf: &mut [u8]) -> Result<State, BootError> {
+            self.0.read_state(aligned_buf)
+        }
+
+        fn reset_state(&mut self, magic: u8, aligned_buf: &mut [u8]) -> Result<(), BootError> {
+            self.0.reset_state(magic, aligned_buf)
+        }
+
+        fn is_swapped(&mut self, aligned_buf: &mut [u8]) -> Result<bool, BootError> {
+            self.0.is_swapped(aligned_buf)
+        }
+
+        fn swap(&mut self, _aligned_buf: &mut [u8]) -> Result<(), BootError> {
+            Err(BootError::Flash(NorFlashErrorKind::Other))
+        }
+
+        fn revert(&mut self, aligned_buf: &mut [u8]) -> Result<(), BootError> {
+            self.0.revert(aligned_buf)
+        }
+    }
+
+    impl Image for Failing<'_> {}
+
+    fn boot(flash: &mut Flash, fail_image1: bool) -> Result<State, BootError> {
+        let mut image0 = BootLoader::new(BootLoaderConfig {
+            active: &mut flash.active0,
+            dfu: &mut flash.dfu0,
+            state: &mut flash.state0,
+        });
+        let mut image1 = BootLoader::new(BootLoaderConfig {
+            active: &mut flash.active1,
+            dfu: &mut flash.dfu1,
+            state: &mut flash.state1,
+        });
+        let mut failing = Failing(&mut image1);
+        let image1: &mut dyn Image = if fail_image1 { &mut failing } else { &mut image1 };
+        let mut aligned = [0; 8];
+        MultiBootLoader::new(&mut flash.group).prepare_boot(&mut [&mut image0, image1], &mut aligned)
+    }
+
+    fn build_package(version: u32, min_version: u32) -> Vec<u8> {
+        build(&[
+            PackageImage {
+                id: 0,
+                version,
+                depends: Some((1, min_version)),
+                data: std::vec![version as u8; 16384],
+            },
+            PackageImage {
+                id: 1,
+                version,
+                depends: None,
+                data: std::vec![!version as u8; 4000],
+            },
+        ])
+    }
+
+    #[cfg(not(feature = "_verify"))]
+    fn contents(flash: &mut Flash) -> (u8, u8) {
+        use embedded_storage::nor_flash::ReadNorFlash;
+
+        let mut buf0 = [0; 16384];
+        flash.active0.read(0, &mut buf0).unwrap();
+        let mut buf1 = [0; 4000];
+        flash.active1.read(0, &mut buf1).unwrap();
+        assert!(buf0.iter().all(|&b| b == buf0[0]));
+        assert!(buf1.iter().all(|&b| b == buf1[0]));
+        (buf0[0], buf1[0])
+    }
+
+    #[test]
+    fn test_decode_package() {
+        let package = build_package(3, 2);
+
+        for chunk_len in [1, 8, 100, 256, package.len()] {
+            let mut decoder = PackageDecoder::new(&[1, 1]);
+            let mut images = [Vec::new(), Vec::new()];
+            for chunk in package.chunks(chunk_len) {
+                let mut chunk = chunk;
+                while let Some(segment) = decoder.next(&mut chunk).unwrap() {
+                    assert_eq!(images[segment.image].len(), segment.offset);
+                    images[segment.image].extend_from_slice(segment.data);
+                }
+            }
+            assert!(decoder.is_done());
+            assert_eq!(0b11, decoder.images());
+            assert_eq!(Some(4000), decoder.image_len(1));
+            assert_eq!(Some(3), decoder.image_version(0));
+            assert_eq!(images[0], [3; 16384]);
+            assert_eq!(images[1][..4000], [!3; 4000]);
+            assert!(images[1][4000..].iter().all(|&b| b == 0xFF));
+        }
+
+        let decode = |package: &[u8], installed: &[u32]| {
+            let mut decoder = PackageDecoder::new(installed);
+            let mut data = package;
+            while decoder.next(&mut data)?.is_some() {}
+            Ok(decoder.is_done())
+        };
+
+        // Dependencies are satisfied by the package, or the installed images
+        assert_eq!(Err(PackageError::Dependency), decode(&build_package(3, 4), &[1, 1]));
+        let only_app = build(&[PackageImage {
+            id: 0,
+            version: 3,
+            depends: Some((1, 2)),
+            data: std::vec![0; 64],
+        }]);
+        assert_eq!(Err(PackageError::Dependency), decode(&only_app, &[1, 1]));
+        assert_eq!(Ok(true), decode(&only_app, &[1, 2]));
+
+        assert_eq!(Err(PackageError::UnknownImage), decode(&package, &[1]));
+        assert_eq!(Err(PackageError::BadMagic), decode(&[0; 64], &[1, 1]));
+        let mut trailing = package.clone();
+        trailing.push(0);
+        assert_eq!(Err(PackageError::TrailingData), decode(&trailing, &[1, 1]));
+    }
+
+    #[test]
+    #[cfg(not(feature = "_verify"))]
+    fn test_update_and_revert() {
+        use futures::executor::block_on;
+
+        let mut flash = Flash::default();
+        flash.active0.write(0, &[0x55; 16384]).unwrap();
+        flash.active1.write(0, &[0x66; 4096]).unwrap();
+        let mut aligned = [0; 4];
+
+        let update = |flash: &mut Flash, aligned: &mut [u8], version: u32| {
+            let mut updater = MultiImageUpdater::new(&mut flash.group, aligned);
+            let mut decoder = PackageDecoder::new(&[1, 1]);
+            for chunk in build_package(version, 1).chunks(256) {
+                let mut chunk = chunk;
+                while let Some(segment) = decoder.next(&mut chunk).unwrap() {
+                    let data = segment.data;
+                    match segment.image {
+                        0 => block_on(updater.write_firmware(0, &mut flash.dfu0, segment.offset, data)),
+                        _ => block_on(updater.write_firmware(1, &mut flash.dfu1, segment.offset, data)),
+                    }
+                    .unwrap();
+                }
+            }
+            block_on(updater.mark_updated(decoder.images())).unwrap();
+            assert_eq!(State::Swap, block_on(updater.get_state()).unwrap());
+        };
+
+        assert_eq!(Ok(State::Boot), boot(&mut flash, false));
+
+        // Both images are swapped, and reverted together since the update isn't marked as booted
+        update(&mut flash, &mut aligned, 2);
+        assert_eq!(Ok(State::Swap), boot(&mut flash, false));
+        assert_eq!((2, !2), contents(&mut flash));
+        assert_eq!(Ok(State::Swap), boot(&mut flash, false));
+        assert_eq!((0x55, 0x66), contents(&mut flash));
+        assert_eq!(Ok(State::Revert), boot(&mut flash, false));
+
+        let mut updater = MultiImageUpdater::new(&mut flash.group, &mut aligned);
+        block_on(updater.mark_booted()).unwrap();
+        assert_eq!(State::Boot, block_on(updater.get_state()).unwrap());
+
+        // Swapping resumes where it stopped, without swapping the first image back
+        update(&mut flash, &mut aligned, 3);
+        assert!(boot(&mut flash, true).is_err());
+        assert_eq!(Ok(State::Swap), boot(&mut flash, false));
+        assert_eq!((3, !3), contents(&mut flash));
+
+        let mut updater = MultiImageUpdater::new(&mut flash.group, &mut aligned);
+        block_on(updater.mark_booted()).unwrap();
+        assert_eq!(Ok(State::Boot), boot(&mut flash, false));
+        assert_eq!((3, !3), contents(&mut flash));
+    }
+
+    #[test]
+    #[cfg(not(feature = "_verify"))]
+    fn test_blocking_update_without_reset() {
+        let mut flash = Flash::default();
+        let mut aligned = [0; 4];
+
+        for version in [1, 2] {
+            let mut updater = BlockingMultiImageUpdater::new(&mut flash.group, &mut aligned);
+            updater
+                .write_firmware(0, &mut flash.dfu0, 0, &[version; 16384])
+                .unwrap();
+            updater
+                .write_firmware(1, &mut flash.dfu1, 0, &[!version; 4096])
+                .unwrap();
+            updater.mark_updated(0b11).unwrap();
+            assert!(matches!(
+                updater.write_firmware(0, &mut flash.dfu0, 0, &[0; 4]),
+                Err(FirmwareUpdaterError::BadState)
+            ));
+
+            assert_eq!(Ok(State::Swap), boot(&mut flash, false));
+            assert_eq!((version, !version), contents(&mut flash));
+
+            // The next update is marked right after marking this one as booted, without a reset
+            let mut updater = BlockingMultiImageUpdater::new(&mut flash.group, &mut aligned);
+            updater.mark_booted().unwrap();
+        }
+
+        // Only the images of an update are swapped
+        let mut updater = BlockingMultiImageUpdater::new(&mut flash.group, &mut aligned);
+        updater.write_firmware(1, &mut flash.dfu1, 0, &[0x42; 4096]).unwrap();
+        updater.mark_updated(0b10).unwrap();
+        assert_eq!(Ok(State::Swap), boot(&mut flash, false));
+        assert_eq!((2, 0x42), contents(&mut flash));
+    }
+
+    #[test]
+    #[cfg(feature = "ed25519-dalek")]
+    fn test_verify_images() {
+        use ed25519_dalek::SigningKey;
+
+        use crate::test_fixtures::sign;
+
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let public_key = key.verifying_key().to_bytes();
+        let signed0 = sign(&[0xAA; 8000], &key, 2, 0);
+        let signed1 = sign(&[0xBB; 3000], &key, 2, 0);
+
+        let mut flash = Flash::default();
+        let mut aligned = [0; 4];
+        let mut updater = BlockingMultiImageUpdater::new(&mut flash.group, &mut aligned);
+        updater.write_firmware(0, &mut flash.dfu0, 0, &signed0).unwrap();
+        updater.write_firmware(1, &mut flash.dfu1, 0, &signed1).unwrap();
+
+        // Every image must be verified before the update is marked
+        assert_eq!(
+            2,
+            updater.verify_image(0, &mut flash.dfu0, &public_key).unwrap().version
+        );
+        assert!(matches!(
+            updater.mark_updated(0b11),
+            Err(FirmwareUpdaterError::BadState)
+        ));
+        updater.verify_image(1, &mut flash.dfu1, &public_key).unwrap();
+
+        // Writing an image again invalidates its verification
+        updater.write_firmware(1, &mut flash.dfu1, 0, &[0; 64]).unwrap();
+        assert!(updater.verify_image(1, &mut flash.dfu1, &public_key).is_err());
+        assert!(matches!(
+            updater.mark_updated(0b11),
+            Err(FirmwareUpdaterError::BadState)
+        ));
+        updater.mark_updated(0b01).unwrap();
+        assert_eq!(Ok(State::Swap), boot(&mut flash, false));
+    }
+}
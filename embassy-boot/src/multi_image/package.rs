@@ -0,0 +1,245 @@
+use super::MAX_IMAGES;
+
+/// Magic at the start of a multi-image package.
+pub const PACKAGE_MAGIC: [u8; 4] = *b"EBMP";
+
+/// Alignment of the header and of each image in a package.
+pub const PACKAGE_ALIGN: usize = 64;
+
+const FORMAT_VERSION: u8 = 1;
+const HEADER_LEN: usize = 8;
+const ENTRY_LEN: usize = 16;
+const NO_DEPENDENCY: u8 = 0xFF;
+
+/// Errors while decoding a multi-image package.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PackageError {
+    /// The package doesn't start with [`PACKAGE_MAGIC`].
+    BadMagic,
+    /// The header has an unknown version, a wrong number of images, or an image appears twice.
+    Malformed,
+    /// The package holds an image, or depends on an image, the device doesn't have.
+    UnknownImage,
+    /// An image depends on a newer version of another image than the package or the device has.
+    Dependency,
+    /// More data was written after the last image.
+    TrailingData,
+}
+
+/// A chunk of an image in a package, to be written to its DFU partition.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Segment<'a> {
+    /// Index of the image.
+    pub image: usize,
+    /// Offset of the data in the image.
+    pub offset: usize,
+    /// The data, including the padding after the end of the image.
+    pub data: &'a [u8],
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Entry {
+    image: u8,
+    dependency: u8,
+    len: u32,
+    version: u32,
+    min_version: u32,
+}
+
+impl Entry {
+    fn parse(bytes: &[u8; ENTRY_LEN]) -> Self {
+        let word = |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        Self {
+            image: bytes[0],
+            dependency: bytes[1],
+            len: word(4),
+            version: word(8),
+            min_version: word(12),
+        }
+    }
+
+    fn padded_len(&self) -> usize {
+        (self.len as usize).next_multiple_of(PACKAGE_ALIGN)
+    }
+}
+
+/// Decoder state for a multi-image package.
+///
+/// Pass the package to [`next`](Self::next) as it arrives, and write the returned segments with
+/// [`MultiImageUpdater::write_firmware`](super::MultiImageUpdater::write_firmware). Once the package
+/// is done, mark the images it holds as updated with
+/// [`MultiImageUpdater::mark_updated`](super::MultiImageUpdater::mark_updated). The dependencies are
+/// checked once the header is decoded, before any segment is returned.
+///
+/// Segments are aligned to the write size of the DFU partitions as long as the chunks passed to the
+/// decoder are.
+pub struct PackageDecoder<'d> {
+    installed: &'d [u32],
+    /// Number of bytes of the package consumed so far.
+    pos: usize,
+    /// Bytes of the header or entry being decoded.
+    buf: [u8; ENTRY_LEN],
+    count: usize,
+    entries: [Entry; MAX_IMAGES],
+}
+
+impl<'d> PackageDecoder<'d> {
+    /// Create a decoder for a new package.
+    ///
+    /// `installed` holds the version of each image installed on the device, indexed like the images
+    /// of the [`MultiBootLoader`](super::MultiBootLoader), to check the dependencies of the package.
+    pub fn new(installed: &'d [u32]) -> Self {
+        assert!(installed.len() <= MAX_IMAGES);
+        Self {
+            installed,
+            pos: 0,
+            buf: [0; ENTRY_LEN],
+            count: 0,
+            entries: [Entry::default(); MAX_IMAGES],
+        }
+    }
+
+    /// Decode the next segment of an image from `data`, advancing `data` past the consumed bytes.
+    ///
+    /// Returns `None` once all of `data` was consumed.
+    pub fn next<'a>(&mut self, data: &mut &'a [u8]) -> Result<Option<Segment<'a>>, PackageError> {
+        loop {
+            if data.is_empty() {
+                return Ok(None);
+            }
+
+            let entries_end = HEADER_LEN + self.count * ENTRY_LEN;
+            if self.pos < HEADER_LEN || (self.count > 0 && self.pos < entries_end) {
+                let (start, len) = match self.pos.checked_sub(HEADER_LEN) {
+                    None => (0, HEADER_LEN),
+                    Some(offset) => (HEADER_LEN + offset / ENTRY_LEN * ENTRY_LEN, ENTRY_LEN),
+                };
+                let filled = self.pos - start;
+                let n = (len - filled).min(data.len());
+                self.buf[filled..filled + n].copy_from_slice(&data[..n]);
+                *data = &data[n..];
+                self.pos += n;
+                if filled + n == len {
+                    self.parse(start)?;
+                }
+                continue;
+            }
+
+            let data_start = self.data_start();
+            if self.pos < data_start {
+                let n = (data_start - self.pos).min(data.len());
+                *data = &data[n..];
+                self.pos += n;
+                continue;
+            }
+
+            let mut start = data_start;
+            for entry in &self.entries[..self.count] {
+                let end = start + entry.padded_len();
+                if self.pos < end {
+                    let n = (end - self.pos).min(data.len());
+                    let (chunk, rest) = data.split_at(n);
+                    *data = rest;
+                    let offset = self.pos - start;
+                    self.pos += n;
+                    return Ok(Some(Segment {
+                        image: entry.image as usize,
+                        offset,
+                        data: chunk,
+                    }));
+                }
+                start = end;
+            }
+            return Err(PackageError::TrailingData);
+        }
+    }
+
+    /// Bitmask of the images in the package, once the header was decoded.
+    pub fn images(&self) -> u32 {
+        self.entries[..self.count]
+            .iter()
+            .fold(0, |images, entry| images | 1 << entry.image)
+    }
+
+    /// Length of `image` in the package without padding, once the header was decoded.
+    pub fn image_len(&self, image: usize) -> Option<u32> {
+        self.entry(image).map(|entry| entry.len)
+    }
+
+    /// Version of `image` in the package, once the header was decoded.
+    pub fn image_version(&self, image: usize) -> Option<u32> {
+        self.entry(image).map(|entry| entry.version)
+    }
+
+    /// Check whether the whole package was decoded.
+    pub fn is_done(&self) -> bool {
+        self.count > 0
+            && self.pos
+                == self.entries[..self.count]
+                    .iter()
+                    .fold(self.data_start(), |end, entry| end + entry.padded_len())
+    }
+
+    fn entry(&self, image: usize) -> Option<&Entry> {
+        self.entries[..self.count]
+            .iter()
+            .find(|entry| entry.image as usize == image)
+    }
+
+    /// Offset of the first image, after the header and its padding.
+    fn data_start(&self) -> usize {
+        (HEADER_LEN + self.count * ENTRY_LEN).next_multiple_of(PACKAGE_ALIGN)
+    }
+
+    /// Parse the header or entry at `start`, held in `buf`.
+    fn parse(&mut self, start: usize) -> Result<(), PackageError> {
+        if start == 0 {
+            if self.buf[..4] != PACKAGE_MAGIC {
+                return Err(PackageError::BadMagic);
+            }
+            let count = self.buf[5] as usize;
+            if self.buf[4] != FORMAT_VERSION || count == 0 || count > MAX_IMAGES {
+                return Err(PackageError::Malformed);
+            }
+            self.count = count;
+            return Ok(());
+        }
+
+        let index = (start - HEADER_LEN) / ENTRY_LEN;
+        let entry = Entry::parse(&self.buf);
+        if entry.image as usize >= self.installed.len() {
+            return Err(PackageError::UnknownImage);
+        }
+        if entry.image == entry.dependency || self.entries[..index].iter().any(|e| e.image == entry.image) {
+            return Err(PackageError::Malformed);
+        }
+        self.entries[index] = entry;
+
+        if index + 1 == self.count {
+            self.check_dependencies()?;
+        }
+        Ok(())
+    }
+
+    fn check_dependencies(&self) -> Result<(), PackageError> {
+        for entry in &self.entries[..self.count] {
+            if entry.dependency == NO_DEPENDENCY {
+                continue;
+            }
+            let dependency = entry.dependency as usize;
+            let version = match self.entry(dependency) {
+                Some(dependency) => dependency.version,
+                None => *self.installed.get(dependency).ok_or(PackageError::UnknownImage)?,
+            };
+            if version < entry.min_version {
+                warn!(
+                    "Image {} needs version {} of image {}, but got {}",
+                    entry.image, entry.min_version, dependency, version
+                );
+                return Err(PackageError::Dependency);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,452 @@
+//! Compressed and delta-encoded firmware updates.
+//!
+//! Instead of the raw image, [`FirmwareUpdater::write_patch`](crate::FirmwareUpdater::write_patch)
+//! and [`FirmwareUpdater::write_delta`](crate::FirmwareUpdater::write_delta) accept a patch
+//! stream, which is decoded into the DFU partition as it arrives. The stream can reference bytes
+//! already written to the DFU partition (LZ77-style compression), and bytes of a base image, usually
+//! the currently active firmware (binary delta). Both the decoded image and the base are read back
+//! from flash, so decoding only needs a small scratch buffer, regardless of the image size.
+//!
+//! The reconstructed image is what ends up in the DFU partition, so signature verification with
+//! `verify_and_mark_updated` works the same as with raw images.
+//!
+//! Patches are produced on the host, for example with `embassy-boot-tool`.
+//!
+//! # Format
+//!
+//! All integers are little-endian. `varint` is an unsigned LEB128-encoded `u32`.
+//!
+//! | Field     | Size    | Description                                       |
+//! |-----------|---------|---------------------------------------------------|
+//! | magic     | 4       | `b"EBP1"`                                         |
+//! | image_len | 4       | Length of the decoded image in bytes              |
+//! | ops       | ...     | Operations, until `image_len` bytes were decoded  |
+//!
+//! Each operation starts with a tag byte:
+//!
+//! | Tag    | Operands                         | Description                                               |
+//! |--------|----------------------------------|-----------------------------------------------------------|
+//! | `0x01` | `len: varint`, `len` bytes       | Append the following bytes                                |
+//! | `0x02` | `dist: varint`, `len: varint`    | Append `len` bytes copied from `dist` bytes back          |
+//! | `0x03` | `offset: varint`, `len: varint`  | Append `len` bytes copied from the base image at `offset` |
+//!
+//! Copies from the image itself may overlap the bytes they produce, to encode runs.
+
+use crate::STATE_ERASE_VALUE;
+
+/// Magic at the start of a patch stream.
+pub const PATCH_MAGIC: [u8; 4] = *b"EBP1";
+
+const HEADER_LEN: usize = 8;
+
+const OP_LITERAL: u8 = 0x01;
+const OP_COPY: u8 = 0x02;
+const OP_BASE: u8 = 0x03;
+
+/// Errors while decoding a patch stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PatchError {
+    /// The stream doesn't start with [`PATCH_MAGIC`].
+    BadMagic,
+    /// The stream contains an unknown operation or an invalid varint.
+    Malformed,
+    /// An operation references data outside of the decoded image or the base image.
+    OutOfBounds,
+    /// The decoded image doesn't fit in the DFU partition.
+    TooLarge,
+    /// The stream references a base image, but none was provided.
+    MissingBase,
+    /// More data was written after the image was fully decoded.
+    TrailingData,
+    /// The decoder buffer is smaller than the flash geometry requires, see [`PatchDecoder::new`].
+    BufferTooSmall,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Source {
+    Dfu,
+    Base,
+}
+
+/// Flash geometry the decoder must respect.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Geometry {
+    pub dfu_capacity: usize,
+    pub dfu_write_size: usize,
+    pub dfu_read_size: usize,
+    pub base_capacity: Option<usize>,
+    pub base_read_size: usize,
+}
+
+/// I/O the decoder needs before it can make progress.
+pub(crate) enum Action<'a> {
+    /// All input was consumed.
+    NeedInput,
+    /// Write `data` to the DFU partition at `offset`.
+    Write { offset: u32, data: &'a [u8] },
+    /// Read from `source` at `offset` into `buf`.
+    Read {
+        source: Source,
+        offset: u32,
+        buf: &'a mut [u8],
+    },
+    /// The image was fully decoded and written.
+    Done,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    Tag,
+    Operand {
+        tag: u8,
+        index: u8,
+        first: u32,
+        value: u32,
+        shift: u32,
+    },
+    Literal {
+        remaining: u32,
+    },
+    Copy {
+        source: Source,
+        offset: u32,
+        remaining: u32,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Pending {
+    None,
+    /// `len` bytes were written from the start of the buffer.
+    Written {
+        len: usize,
+    },
+    /// The bytes were read at `buf[staged..]`, the wanted ones start `skip` bytes in.
+    Read {
+        skip: usize,
+        len: usize,
+    },
+}
+
+/// Decoder state for a patch stream.
+///
+/// Create one per update and pass it to every call of
+/// [`FirmwareUpdater::write_patch`](crate::FirmwareUpdater::write_patch) or
+/// [`FirmwareUpdater::write_delta`](crate::FirmwareUpdater::write_delta). To restart an
+/// interrupted update, create a new decoder and start over from the beginning of the stream.
+pub struct PatchDecoder<'d> {
+    buf: &'d mut [u8],
+    /// Offset in the image of `buf[0]`.
+    buf_offset: usize,
+    /// Number of decoded bytes in `buf`.
+    staged: usize,
+    header: [u8; HEADER_LEN],
+    header_len: usize,
+    image_len: usize,
+    op: Op,
+    pending: Pending,
+    done: bool,
+}
+
+impl<'d> PatchDecoder<'d> {
+    /// Create a decoder, using `buf` to stage decoded data before writing it to flash.
+    ///
+    /// `buf` must be at least the DFU write size plus twice the largest read size of the DFU
+    /// and base flashes, otherwise decoding fails with [`PatchError::BufferTooSmall`]. Larger
+    /// buffers mean fewer, larger flash operations.
+    pub fn new(buf: &'d mut [u8]) -> Self {
+        Self {
+            buf,
+            buf_offset: 0,
+            staged: 0,
+            header: [0; HEADER_LEN],
+            header_len: 0,
+            image_len: 0,
+            op: Op::Tag,
+            pending: Pending::None,
+            done: false,
+        }
+    }
+
+    /// Length of the decoded image, once the header was received.
+    ///
+    /// This is the length to pass to `verify_and_mark_updated` or `hash`.
+    pub fn image_len(&self) -> Option<u32> {
+        (self.header_len == HEADER_LEN).then_some(self.image_len as u32)
+    }
+
+    /// Number of bytes of the image decoded so far.
+    pub fn decoded(&self) -> u32 {
+        // Excluding the padding of the last write block.
+        self.position().min(self.image_len) as u32
+    }
+
+    /// Returns whether the whole image was decoded and written to flash.
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    fn position(&self) -> usize {
+        self.buf_offset + self.staged
+    }
+
+    fn complete_pending(&mut self) {
+        match core::mem::replace(&mut self.pending, Pending::None) {
+            Pending::None => {}
+            Pending::Written { len } => {
+                self.buf.copy_within(len..self.staged, 0);
+                self.buf_offset += len;
+                self.staged -= len;
+            }
+            Pending::Read { skip, len } => {
+                let start = self.staged;
+                self.buf.copy_within(start + skip..start + skip + len, start);
+                self.staged += len;
+                if let Op::Copy { offset, remaining, .. } = &mut self.op {
+                    *offset += len as u32;
+                    *remaining -= len as u32;
+                    if *remaining == 0 {
+                        self.op = Op::Tag;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Write out all complete write blocks in the buffer.
+    fn flush(&mut self, geometry: &Geometry) -> Action<'_> {
+        let len = self.staged - self.staged % geometry.dfu_write_size;
+        self.pending = Pending::Written { len };
+        Action::Write {
+            offset: self.buf_offset as u32,
+            data: &self.buf[..len],
+        }
+    }
+
+    /// Decode as much of `input` as possible, advancing it past the consumed bytes.
+    pub(crate) fn poll(&mut self, input: &mut &[u8], geometry: &Geometry) -> Result<Action<'_>, PatchError> {
+        if self.buf.len() < geometry.dfu_write_size + 2 * geometry.dfu_read_size.max(geometry.base_read_size) {
+            return Err(PatchError::BufferTooSmall);
+        }
+
+        self.complete_pending();
+
+        loop {
+            if self.done {
+                return if input.is_empty() {
+                    Ok(Action::Done)
+                } else {
+                    Err(PatchError::TrailingData)
+                };
+            }
+
+            if self.header_len < HEADER_LEN {
+                let Some((&byte, rest)) = input.split_first() else {
+                    return Ok(Action::NeedInput);
+                };
+                *input = rest;
+                self.header[self.header_len] = byte;
+                self.header_len += 1;
+                if self.header_len == HEADER_LEN {
+                    if self.header[..4] != PATCH_MAGIC {
+                        return Err(PatchError::BadMagic);
+                    }
+                    self.image_len = u32::from_le_bytes(self.header[4..].try_into().unwrap()) as usize;
+                    if self.image_len > geometry.dfu_capacity {
+                        return Err(PatchError::TooLarge);
+                    }
+                }
+                continue;
+            }
+
+            if self.position() == self.image_len {
+                if !matches!(self.op, Op::Tag) {
+                    return Err(PatchError::Malformed);
+                }
+                if self.staged == 0 {
+                    self.done = true;
+                    continue;
+                }
+                // Pad the last write block.
+                let padded = self.staged.next_multiple_of(geometry.dfu_write_size);
+                self.buf[self.staged..padded].fill(STATE_ERASE_VALUE);
+                self.staged = padded;
+                self.done = true;
+                let action = self.flush(geometry);
+                return Ok(action);
+            }
+
+            if self.staged == self.buf.len() {
+                return Ok(self.flush(geometry));
+            }
+
+            match self.op {
+                Op::Tag => {
+                    let Some((&tag, rest)) = input.split_first() else {
+                        return Ok(Action::NeedInput);
+                    };
+                    *input = rest;
+                    if !matches!(tag, OP_LITERAL | OP_COPY | OP_BASE) {
+                        return Err(PatchError::Malformed);
+                    }
+                    self.op = Op::Operand {
+                        tag,
+                        index: 0,
+                        first: 0,
+                        value: 0,
+                        shift: 0,
+                    };
+                }
+                Op::Operand {
+                    tag,
+                    index,
+                    first,
+                    value,
+                    shift,
+                } => {
+                    let Some((&byte, rest)) = input.split_first() else {
+                        return Ok(Action::NeedInput);
+                    };
+                    *input = rest;
+
+                    if shift > 28 || (shift == 28 && byte & 0x70 != 0) {
+                        return Err(PatchError::Malformed);
+                    }
+                    let value = value | ((byte & 0x7f) as u32) << shift;
+                    if byte & 0x80 != 0 {
+                        self.op = Op::Operand {
+                            tag,
+                            index,
+                            first,
+                            value,
+                            shift: shift + 7,
+                        };
+                        continue;
+                    }
+
+                    self.op = match (tag, index) {
+                        (OP_LITERAL, _) => Op::Literal { remaining: value },
+                        (_, 0) => Op::Operand {
+                            tag,
+                            index: 1,
+                            first: value,
+                            value: 0,
+                            shift: 0,
+                        },
+                        (OP_COPY, _) => {
+                            if first == 0 || first as usize > self.position() {
+                                return Err(PatchError::OutOfBounds);
+                            }
+                            Op::Copy {
+                                source: Source::Dfu,
+                                offset: (self.position() - first as usize) as u32,
+                                remaining: value,
+                            }
+                        }
+                        _ => {
+                            let Some(base_capacity) = geometry.base_capacity else {
+                                return Err(PatchError::MissingBase);
+                            };
+                            // In u64, operands up to u32::MAX would overflow usize on 32-bit targets.
+                            if first as u64 + value as u64 > base_capacity as u64 {
+                                return Err(PatchError::OutOfBounds);
+                            }
+                            Op::Copy {
+                                source: Source::Base,
+                                offset: first,
+                                remaining: value,
+                            }
+                        }
+                    };
+
+                    let remaining = match self.op {
+                        Op::Literal { remaining } | Op::Copy { remaining, .. } => remaining,
+                        _ => continue,
+                    };
+                    if self.position() as u64 + remaining as u64 > self.image_len as u64 {
+                        return Err(PatchError::OutOfBounds);
+                    }
+                    if remaining == 0 {
+                        self.op = Op::Tag;
+                    }
+                }
+                Op::Literal { remaining } => {
+                    if input.is_empty() {
+                        return Ok(Action::NeedInput);
+                    }
+                    let len = (remaining as usize).min(input.len()).min(self.buf.len() - self.staged);
+                    let (data, rest) = input.split_at(len);
+                    self.buf[self.staged..self.staged + len].copy_from_slice(data);
+                    self.staged += len;
+                    *input = rest;
+
+                    let remaining = remaining - len as u32;
+                    self.op = if remaining == 0 {
+                        Op::Tag
+                    } else {
+                        Op::Literal { remaining }
+                    };
+                }
+                Op::Copy {
+                    source,
+                    offset,
+                    remaining,
+                } => {
+                    let offset = offset as usize;
+
+                    // Copy from the part of the image that is still in the buffer.
+                    if source == Source::Dfu && offset >= self.buf_offset {
+                        let from = offset - self.buf_offset;
+                        let len = (remaining as usize).min(self.buf.len() - self.staged);
+                        // Byte by byte, the source may overlap the destination.
+                        for i in 0..len {
+                            self.buf[self.staged + i] = self.buf[from + i];
+                        }
+                        self.staged += len;
+
+                        let remaining = remaining - len as u32;
+                        self.op = if remaining == 0 {
+                            Op::Tag
+                        } else {
+                            Op::Copy {
+                                source,
+                                offset: (offset + len) as u32,
+                                remaining,
+                            }
+                        };
+                        continue;
+                    }
+
+                    // Otherwise, read it from flash into the free part of the buffer.
+                    let read_size = match source {
+                        Source::Dfu => geometry.dfu_read_size,
+                        Source::Base => geometry.base_read_size,
+                    };
+                    let mut len = remaining as usize;
+                    if source == Source::Dfu {
+                        // Only what was already written to flash.
+                        len = len.min(self.buf_offset - offset);
+                    }
+
+                    let aligned = offset - offset % read_size;
+                    let skip = offset - aligned;
+                    let free = self.buf.len() - self.staged;
+                    let free = free - free % read_size;
+                    if free <= skip {
+                        return Ok(self.flush(geometry));
+                    }
+                    let len = len.min(free - skip);
+                    let read_len = (skip + len).next_multiple_of(read_size);
+
+                    self.pending = Pending::Read { skip, len };
+                    return Ok(Action::Read {
+                        source,
+                        offset: aligned as u32,
+                        buf: &mut self.buf[self.staged..self.staged + read_len],
+                    });
+                }
+            }
+        }
+    }
+}
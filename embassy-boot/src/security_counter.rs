@@ -0,0 +1,85 @@
+use embedded_storage::nor_flash::{NorFlash, NorFlashError, NorFlashErrorKind};
+
+use crate::STATE_ERASE_VALUE;
+
+/// Monotonic counter protecting against firmware downgrades.
+///
+/// The bootloader refuses images whose [security counter](crate::header::ImageHeader::security_counter)
+/// is lower than this counter, and raises it when an image with a higher one is marked as booted.
+/// Implementations must make sure that the counter can't be lowered, for example by storing it in
+/// OTP memory.
+pub trait SecurityCounter {
+    /// Error type.
+    type Error: core::fmt::Debug;
+
+    /// Read the counter.
+    fn read(&mut self) -> Result<u32, Self::Error>;
+
+    /// Raise the counter to `value`, which is higher than its current value.
+    fn raise(&mut self, value: u32) -> Result<(), Self::Error>;
+}
+
+/// Security counter stored in flash or OTP memory, as a tally of written words.
+///
+/// Raising the counter writes one word per increment, and the flash is never erased, so the
+/// counter can only go up as long as the region can't be erased by the application. This makes it
+/// suitable for OTP memory exposed as a [`NorFlash`], or for a flash region write-protected by the
+/// bootloader. It can hold values up to `capacity / WRITE_SIZE`.
+///
+/// The region must be erased before first use, and must not overlap the STATE partition, which is
+/// erased by the bootloader.
+pub struct FlashSecurityCounter<'d, F> {
+    flash: F,
+    aligned: &'d mut [u8],
+}
+
+impl<'d, F: NorFlash> FlashSecurityCounter<'d, F> {
+    /// Create a security counter in `flash`.
+    ///
+    /// The `aligned` buffer must be at least `F::WRITE_SIZE` long, and aligned for the flash.
+    pub fn new(flash: F, aligned: &'d mut [u8]) -> Self {
+        assert!(aligned.len() >= F::WRITE_SIZE);
+        Self { flash, aligned }
+    }
+
+    fn is_written(&mut self, index: usize) -> Result<bool, F::Error> {
+        let word = &mut self.aligned[..F::WRITE_SIZE];
+        self.flash.read((index * F::WRITE_SIZE) as u32, word)?;
+        Ok(word.iter().any(|&b| b != STATE_ERASE_VALUE))
+    }
+}
+
+impl<F: NorFlash> SecurityCounter for FlashSecurityCounter<'_, F> {
+    type Error = NorFlashErrorKind;
+
+    fn read(&mut self) -> Result<u32, Self::Error> {
+        // Written words are always a prefix of the region.
+        let mut low = 0;
+        let mut high = self.flash.capacity() / F::WRITE_SIZE;
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if self.is_written(mid).map_err(|e| e.kind())? {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        Ok(low as u32)
+    }
+
+    fn raise(&mut self, value: u32) -> Result<(), Self::Error> {
+        let current = self.read()? as usize;
+        if value as usize > self.flash.capacity() / F::WRITE_SIZE {
+            return Err(NorFlashErrorKind::OutOfBounds);
+        }
+
+        let word = &mut self.aligned[..F::WRITE_SIZE];
+        word.fill(!STATE_ERASE_VALUE);
+        for index in current..value as usize {
+            self.flash
+                .write((index * F::WRITE_SIZE) as u32, word)
+                .map_err(|e| e.kind())?;
+        }
+        Ok(())
+    }
+}
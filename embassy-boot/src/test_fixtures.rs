@@ -0,0 +1,120 @@
+//! Minimal builders for the formats produced by `embassy-boot-tool`, to create test inputs.
+//!
+//! These only cover what the tests need. Round-trips with the real tool are tested in
+//! `embassy-boot-tool`.
+
+extern crate std;
+
+use std::vec::Vec;
+
+/// Builder for a patch stream, see the [`patch`](crate::patch) module.
+pub(crate) struct Patch {
+    out: Vec<u8>,
+}
+
+impl Patch {
+    pub(crate) fn new(image_len: usize) -> Self {
+        let mut out = Vec::new();
+        out.extend_from_slice(&crate::patch::PATCH_MAGIC);
+        out.extend_from_slice(&(image_len as u32).to_le_bytes());
+        Self { out }
+    }
+
+    /// Append `data` as is.
+    pub(crate) fn literal(mut self, data: &[u8]) -> Self {
+        self.out.push(0x01);
+        self.varint(data.len());
+        self.out.extend_from_slice(data);
+        self
+    }
+
+    /// Append `len` bytes copied from `dist` bytes back in the image.
+    pub(crate) fn copy(mut self, dist: usize, len: usize) -> Self {
+        self.out.push(0x02);
+        self.varint(dist);
+        self.varint(len);
+        self
+    }
+
+    /// Append `len` bytes copied from the base image at `offset`.
+    pub(crate) fn base(mut self, offset: usize, len: usize) -> Self {
+        self.out.push(0x03);
+        self.varint(offset);
+        self.varint(len);
+        self
+    }
+
+    pub(crate) fn finish(self) -> Vec<u8> {
+        self.out
+    }
+
+    fn varint(&mut self, mut value: usize) {
+        while value >= 0x80 {
+            self.out.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.out.push(value as u8);
+    }
+}
+
+/// Prepend a signed header to `image`, padded to 256 bytes, see the [`header`](crate::header)
+/// module.
+#[cfg(feature = "ed25519-dalek")]
+pub(crate) fn sign(image: &[u8], key: &ed25519_dalek::SigningKey, version: u32, security_counter: u32) -> Vec<u8> {
+    use ed25519_dalek::{Digest, Sha512, Signer};
+
+    use crate::header::{HEADER_LEN, HEADER_MAGIC};
+
+    const HEADER_PADDED_LEN: u16 = 256;
+    const SIGNED_LEN: usize = 96;
+
+    let mut header = [0; HEADER_LEN];
+    header[..4].copy_from_slice(&HEADER_MAGIC);
+    header[4..6].copy_from_slice(&HEADER_PADDED_LEN.to_le_bytes());
+    header[8..12].copy_from_slice(&(image.len() as u32).to_le_bytes());
+    header[12..16].copy_from_slice(&version.to_le_bytes());
+    header[16..20].copy_from_slice(&security_counter.to_le_bytes());
+    header[32..96].copy_from_slice(&Sha512::digest(image));
+    let signature = key.sign(&Sha512::digest(&header[..SIGNED_LEN]));
+    header[SIGNED_LEN..].copy_from_slice(&signature.to_bytes());
+
+    let mut out = Vec::from(header);
+    out.resize(HEADER_PADDED_LEN as usize, 0xFF);
+    out.extend_from_slice(image);
+    out
+}
+
+/// An image in a multi-image package.
+#[cfg(all(feature = "multi-image", not(feature = "overwrite-only")))]
+pub(crate) struct PackageImage {
+    pub id: u8,
+    pub version: u32,
+    pub depends: Option<(u8, u32)>,
+    pub data: Vec<u8>,
+}
+
+/// Build a multi-image package, see the [`multi_image`](crate::multi_image) module.
+#[cfg(all(feature = "multi-image", not(feature = "overwrite-only")))]
+pub(crate) fn package(images: &[PackageImage]) -> Vec<u8> {
+    use crate::multi_image::{PACKAGE_ALIGN, PACKAGE_MAGIC};
+
+    let pad = |out: &mut Vec<u8>| out.resize(out.len().next_multiple_of(PACKAGE_ALIGN), 0xFF);
+
+    let mut out = Vec::new();
+    out.extend_from_slice(&PACKAGE_MAGIC);
+    out.extend_from_slice(&[1, images.len() as u8, 0, 0]);
+    for image in images {
+        let (dependency, min_version) = image.depends.unwrap_or((0xFF, 0));
+        out.extend_from_slice(&[image.id, dependency, 0, 0]);
+        out.extend_from_slice(&(image.data.len() as u32).to_le_bytes());
+        out.extend_from_slice(&image.version.to_le_bytes());
+        out.extend_from_slice(&min_version.to_le_bytes());
+    }
+    pad(&mut out);
+
+    for image in images {
+        out.extend_from_slice(&image.data);
+        pad(&mut out);
+    }
+    out
+}
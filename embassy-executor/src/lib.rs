@@ -68,6 +68,9 @@ pub use spawner::*;
 mod metadata;
 pub use metadata::*;
 
+#[cfg(feature = "stack-usage")]
+pub mod stack;
+
 /// Implementation details for embassy macros.
 /// Do not use. Used for macros and HALs only. Not covered by semver guarantees.
 #[doc(hidden)]
@@ -2,20 +2,32 @@
 use core::cell::Cell;
 use core::future::{Future, poll_fn};
 #[cfg(feature = "scheduler-priority")]
-use core::sync::atomic::{AtomicU8, Ordering};
+use core::sync::atomic::AtomicU8;
+#[cfg(all(feature = "stack-usage", not(feature = "platform-avr")))]
+use core::sync::atomic::AtomicUsize;
+#[cfg(any(feature = "scheduler-priority", feature = "stack-usage"))]
+use core::sync::atomic::Ordering;
 use core::task::Poll;
 
 #[cfg(feature = "metadata-name")]
 use critical_section::Mutex;
+#[cfg(all(feature = "stack-usage", feature = "platform-avr"))]
+use portable_atomic::AtomicUsize;
 
 use crate::raw;
 #[cfg(feature = "scheduler-deadline")]
 use crate::raw::Deadline;
+#[cfg(feature = "metadata-size")]
+use crate::raw::util::SyncUnsafeCell;
 
 /// Metadata associated with a task.
 pub struct Metadata {
     #[cfg(feature = "metadata-name")]
     name: Mutex<Cell<Option<&'static str>>>,
+    #[cfg(feature = "metadata-size")]
+    size: SyncUnsafeCell<usize>,
+    #[cfg(feature = "stack-usage")]
+    stack_high_water_mark: AtomicUsize,
     #[cfg(feature = "scheduler-priority")]
     priority: AtomicU8,
     #[cfg(feature = "scheduler-deadline")]
@@ -27,6 +39,10 @@ impl Metadata {
         Self {
             #[cfg(feature = "metadata-name")]
             name: Mutex::new(Cell::new(None)),
+            #[cfg(feature = "metadata-size")]
+            size: SyncUnsafeCell::new(0),
+            #[cfg(feature = "stack-usage")]
+            stack_high_water_mark: AtomicUsize::new(0),
             #[cfg(feature = "scheduler-priority")]
             priority: AtomicU8::new(0),
             // NOTE: The deadline is set to zero to allow the initializer to reside in `.bss`. This
@@ -40,6 +56,9 @@ impl Metadata {
         #[cfg(feature = "metadata-name")]
         critical_section::with(|cs| self.name.borrow(cs).set(None));
 
+        #[cfg(feature = "stack-usage")]
+        self.stack_high_water_mark.store(0, Ordering::Relaxed);
+
         #[cfg(feature = "scheduler-priority")]
         self.set_priority(0);
 
@@ -75,6 +94,36 @@ impl Metadata {
         critical_section::with(|cs| self.name.borrow(cs).set(Some(name)))
     }
 
+    /// Get the size in bytes of this task's storage.
+    ///
+    /// This is the RAM statically reserved for one instance of the task: its future, which holds
+    /// all state kept across `.await` points, plus the executor's per-task header. Multiply by
+    /// the task's `pool_size` to get the total.
+    #[cfg(feature = "metadata-size")]
+    pub fn size(&self) -> usize {
+        unsafe { self.size.get() }
+    }
+
+    /// Safety: must only be called while initializing a task, before it is spawned.
+    #[cfg(feature = "metadata-size")]
+    pub(crate) unsafe fn set_size(&self, size: usize) {
+        self.size.set(size)
+    }
+
+    /// Get the maximum number of stack bytes this task used while being polled.
+    ///
+    /// This stays zero until [`stack::init()`](crate::stack::init) is called for the stack it runs on.
+    /// See the [`stack`](crate::stack) module for details.
+    #[cfg(feature = "stack-usage")]
+    pub fn stack_high_water_mark(&self) -> usize {
+        self.stack_high_water_mark.load(Ordering::Relaxed)
+    }
+
+    #[cfg(feature = "stack-usage")]
+    pub(crate) fn record_stack_usage(&self, used: usize) {
+        crate::stack::raise(&self.stack_high_water_mark, used)
+    }
+
     /// Get this task's priority.
     #[cfg(feature = "scheduler-priority")]
     pub fn priority(&self) -> u8 {
@@ -1,28 +1,34 @@
 #[cfg(feature = "executor-interrupt")]
 compile_error!("`executor-interrupt` is not supported with `platform-std`.");
 
+#[cfg(any(feature = "executor-thread", feature = "executor-sim"))]
+use std::sync::{Condvar, Mutex};
+
+#[cfg(any(feature = "executor-thread", feature = "executor-sim"))]
+struct StdPender;
+
+#[cfg(any(feature = "executor-thread", feature = "executor-sim"))]
+impl crate::pender::Pender for StdPender {
+    fn pend(context: *mut ()) {
+        let signaler: &'static Signaler = unsafe { std::mem::transmute(context) };
+        signaler.signal()
+    }
+}
+
+#[cfg(any(feature = "executor-thread", feature = "executor-sim"))]
+pender_impl!(StdPender);
+
 #[cfg(feature = "executor-thread")]
 pub use thread::*;
 #[cfg(feature = "executor-thread")]
 mod thread {
     use std::marker::PhantomData;
-    use std::sync::{Condvar, Mutex};
 
     pub use embassy_executor_macros::main_std as main;
 
+    use super::Signaler;
     use crate::{Spawner, raw};
 
-    struct StdPender;
-
-    impl crate::pender::Pender for StdPender {
-        fn pend(context: *mut ()) {
-            let signaler: &'static Signaler = unsafe { std::mem::transmute(context) };
-            signaler.signal()
-        }
-    }
-
-    pender_impl!(StdPender);
-
     /// Single-threaded std-based executor.
     pub struct Executor {
         inner: raw::Executor,
@@ -81,32 +87,186 @@ mod thread {
             }
         }
     }
+}
+
+#[cfg(feature = "executor-sim")]
+pub use sim::*;
+#[cfg(feature = "executor-sim")]
+mod sim {
+    use core::cell::Cell;
+    use std::marker::PhantomData;
+
+    use embassy_time::{Instant, MockDriver};
 
-    struct Signaler {
-        mutex: Mutex<bool>,
-        condvar: Condvar,
+    use super::Signaler;
+    use crate::{Spawner, raw};
+
+    /// Deterministic simulation executor running on virtual time.
+    ///
+    /// This executor is meant for tests. It polls tasks until none of them is ready anymore,
+    /// then advances `embassy-time`'s [`MockDriver`] straight to the next scheduled timer,
+    /// so code using `Timer`, `Ticker`, timeouts etc. runs without waiting for real time to pass.
+    ///
+    /// Every batch of ready tasks is polled in a pseudo-random order derived from the `seed`
+    /// given to [`SimExecutor::new`]. Running with several seeds is a cheap way to shake out
+    /// ordering-dependent bugs, and a failing seed reproduces the exact same interleaving
+    /// every time. Scheduler features (`scheduler-priority`, `scheduler-deadline`) are ignored.
+    ///
+    /// The [`MockDriver`] is global, so only one simulation should run at a time in a given
+    /// process. Tests using this executor should not run in parallel.
+    pub struct SimExecutor {
+        inner: raw::Executor,
+        not_send: PhantomData<*mut ()>,
+        signaler: &'static Signaler,
+        rng: Cell<u64>,
     }
 
-    impl Signaler {
-        fn new() -> Self {
+    impl SimExecutor {
+        /// Create a new SimExecutor, shuffling ready tasks according to `seed`.
+        pub fn new(seed: u64) -> Self {
+            let signaler = Box::leak(Box::new(Signaler::new()));
             Self {
-                mutex: Mutex::new(false),
-                condvar: Condvar::new(),
+                inner: raw::Executor::new(signaler as *mut Signaler as *mut ()),
+                not_send: PhantomData,
+                signaler,
+                rng: Cell::new(splitmix64(seed)),
             }
         }
 
-        fn wait(&self) {
-            let mut signaled = self.mutex.lock().unwrap();
-            while !*signaled {
-                signaled = self.condvar.wait(signaled).unwrap();
+        /// Get a spawner that spawns tasks in this executor.
+        pub fn spawner(&'static self) -> Spawner {
+            self.inner.spawner()
+        }
+
+        /// Poll tasks until none of them is ready, without advancing time.
+        pub fn run_until_idle(&'static self) {
+            while self.signaler.take() {
+                unsafe { self.inner.inner.poll_ordered(|batch| self.shuffle(batch)) };
             }
-            *signaled = false;
         }
 
-        fn signal(&self) {
-            let mut signaled = self.mutex.lock().unwrap();
-            *signaled = true;
-            self.condvar.notify_one();
+        /// Run all ready tasks, then advance time to the next scheduled timer.
+        ///
+        /// Returns the new current time, or `None` if no timer is scheduled. In that case,
+        /// no task can make progress anymore unless woken from outside the simulation.
+        pub fn step(&'static self) -> Option<Instant> {
+            self.run_until_idle();
+            let now = MockDriver::get().advance_to_next_alarm();
+            self.run_until_idle();
+            now
         }
+
+        /// Run the executor until a flag is raised.
+        ///
+        /// The `init` closure is called with a [`Spawner`] that spawns tasks on
+        /// this executor. Use it to spawn the initial task(s).
+        ///
+        /// `done` is checked every time the executor runs out of ready tasks, before time
+        /// is advanced to the next timer.
+        ///
+        /// # Panics
+        ///
+        /// Panics if no task is ready and no timer is scheduled before `done` returns `true`,
+        /// since the simulation can never make progress again.
+        pub fn run_until(&'static self, init: impl FnOnce(Spawner), mut done: impl FnMut() -> bool) {
+            init(self.spawner());
+
+            loop {
+                self.run_until_idle();
+
+                if done() {
+                    break;
+                }
+
+                if MockDriver::get().advance_to_next_alarm().is_none() {
+                    panic!("simulation stalled: no task is ready and no timer is scheduled");
+                }
+            }
+        }
+
+        /// Run the executor until virtual time reaches `deadline`.
+        ///
+        /// Time is left at `deadline` on return, even if no timer was scheduled for it.
+        pub fn run_until_instant(&'static self, deadline: Instant) {
+            let driver = MockDriver::get();
+            loop {
+                self.run_until_idle();
+
+                match driver.next_alarm() {
+                    Some(at) if at <= deadline => {
+                        driver.advance_to_next_alarm();
+                    }
+                    _ => break,
+                }
+            }
+
+            let now = Instant::now();
+            if now < deadline {
+                driver.advance(deadline - now);
+                self.run_until_idle();
+            }
+        }
+
+        fn shuffle<T>(&self, batch: &mut [T]) {
+            // Fisher-Yates
+            for i in (1..batch.len()).rev() {
+                let j = (self.next_u64() % (i as u64 + 1)) as usize;
+                batch.swap(i, j);
+            }
+        }
+
+        fn next_u64(&self) -> u64 {
+            // xorshift64*
+            let mut x = self.rng.get();
+            x ^= x >> 12;
+            x ^= x << 25;
+            x ^= x >> 27;
+            self.rng.set(x);
+            x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+        }
+    }
+
+    fn splitmix64(seed: u64) -> u64 {
+        let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        // xorshift state must not be zero
+        (z ^ (z >> 31)) | 1
+    }
+}
+
+#[cfg(any(feature = "executor-thread", feature = "executor-sim"))]
+struct Signaler {
+    mutex: Mutex<bool>,
+    condvar: Condvar,
+}
+
+#[cfg(any(feature = "executor-thread", feature = "executor-sim"))]
+impl Signaler {
+    fn new() -> Self {
+        Self {
+            mutex: Mutex::new(false),
+            condvar: Condvar::new(),
+        }
+    }
+
+    #[cfg(feature = "executor-thread")]
+    fn wait(&self) {
+        let mut signaled = self.mutex.lock().unwrap();
+        while !*signaled {
+            signaled = self.condvar.wait(signaled).unwrap();
+        }
+        *signaled = false;
+    }
+
+    #[cfg(feature = "executor-sim")]
+    fn take(&self) -> bool {
+        core::mem::take(&mut *self.mutex.lock().unwrap())
+    }
+
+    fn signal(&self) {
+        let mut signaled = self.mutex.lock().unwrap();
+        *signaled = true;
+        self.condvar.notify_one();
     }
 }
@@ -324,6 +324,8 @@ impl<F: Future + 'static> AvailableTask<F> {
     fn initialize_impl<S>(self, future: impl FnOnce() -> F) -> SpawnToken<S> {
         unsafe {
             self.task.raw.metadata.reset();
+            #[cfg(feature = "metadata-size")]
+            self.task.raw.metadata.set_size(mem::size_of::<TaskStorage<F>>());
             self.task.raw.poll_fn.set(Some(TaskStorage::<F>::poll));
             self.task.future.write_in_place(future);
 
@@ -440,6 +442,8 @@ impl Pender {
 pub(crate) struct SyncExecutor {
     run_queue: RunQueue,
     pender: Pender,
+    #[cfg(feature = "stack-usage")]
+    pub(crate) stack: crate::stack::ExecutorStack,
 }
 
 impl SyncExecutor {
@@ -447,6 +451,8 @@ impl SyncExecutor {
         Self {
             run_queue: RunQueue::new(),
             pender,
+            #[cfg(feature = "stack-usage")]
+            stack: crate::stack::ExecutorStack::new(),
         }
     }
 
@@ -486,12 +492,47 @@ impl SyncExecutor {
         #[cfg(feature = "_any_trace")]
         trace::poll_start(self);
 
+        #[cfg(feature = "stack-usage")]
+        let start = crate::stack::stack_pointer();
+
         self.run_queue.dequeue_all(|p| {
             let task = p.header();
 
             #[cfg(feature = "_any_trace")]
             trace::task_exec_begin(self, &p);
 
+            // Run the task
+            #[cfg(feature = "stack-usage")]
+            self.stack
+                .measure(start, &task.metadata, || task.poll_fn.get().unwrap_unchecked()(p));
+            #[cfg(not(feature = "stack-usage"))]
+            task.poll_fn.get().unwrap_unchecked()(p);
+
+            #[cfg(feature = "_any_trace")]
+            trace::task_exec_end(self, &p);
+        });
+
+        #[cfg(feature = "_any_trace")]
+        trace::executor_idle(self)
+    }
+
+    /// Like [`poll`](Self::poll), but each batch of queued tasks is handed to `order` before
+    /// being polled, so that it can be permuted.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`poll`](Self::poll).
+    #[cfg(feature = "executor-sim")]
+    pub(crate) unsafe fn poll_ordered(&'static self, order: impl FnMut(&mut [TaskRef])) {
+        #[cfg(feature = "_any_trace")]
+        trace::poll_start(self);
+
+        self.run_queue.dequeue_all_ordered(order, |p| {
+            let task = p.header();
+
+            #[cfg(feature = "_any_trace")]
+            trace::task_exec_begin(self, &p);
+
             // Run the task
             task.poll_fn.get().unwrap_unchecked()(p);
 
@@ -174,6 +174,24 @@ impl RunQueue {
             on_task(taskref);
         }
     }
+
+    /// # Ordered runqueue
+    ///
+    /// Empty the queue, let `order` permute the batch of dequeued tasks, then call `on_task`
+    /// for each of them in the resulting order. Scheduler features are not taken into account.
+    #[cfg(feature = "executor-sim")]
+    pub(crate) fn dequeue_all_ordered(&self, mut order: impl FnMut(&mut [TaskRef]), on_task: impl Fn(TaskRef)) {
+        if self.definitely_empty() {
+            return;
+        }
+
+        let mut batch: std::vec::Vec<TaskRef> = self.stack.take_all().collect();
+        order(&mut batch);
+        for taskref in batch {
+            run_dequeue(&taskref);
+            on_task(taskref);
+        }
+    }
 }
 
 /// atomic state does not require a cs...
@@ -176,6 +176,15 @@ impl Spawner {
     pub fn executor_id(&self) -> usize {
         self.executor.id()
     }
+
+    /// Return the maximum number of stack bytes this Spawner's Executor used while polling.
+    ///
+    /// This stays zero until [`stack::init()`](crate::stack::init) is called for the executor's stack.
+    /// See the [`stack`](crate::stack) module for details.
+    #[cfg(feature = "stack-usage")]
+    pub fn stack_high_water_mark(&self) -> usize {
+        self.executor.inner.stack.high_water_mark()
+    }
 }
 
 /// Handle to spawn tasks into an executor from any thread.
@@ -225,4 +234,13 @@ impl SendSpawner {
         mem::forget(token);
         unsafe { self.executor.spawn(header) }
     }
+
+    /// Return the maximum number of stack bytes this SendSpawner's Executor used while polling.
+    ///
+    /// This stays zero until [`stack::init()`](crate::stack::init) is called for the executor's stack.
+    /// See the [`stack`](crate::stack) module for details.
+    #[cfg(feature = "stack-usage")]
+    pub fn stack_high_water_mark(&self) -> usize {
+        self.executor.stack.high_water_mark()
+    }
 }
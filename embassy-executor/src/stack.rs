@@ -0,0 +1,269 @@
+//! Stack usage measurement.
+//!
+//! This module measures how much stack the executors and their tasks use, so that stacks can be
+//! sized from measurements instead of guesswork. It works by "painting" the unused part of the
+//! stack with a known pattern, and later looking for the lowest address where the pattern was
+//! overwritten.
+//!
+//! To use it, call [`init()`] with the bounds of the stack as early as possible, before starting
+//! any executor. On multi-core chips, or with several threads, call it once on each stack that
+//! runs an executor; up to [`MAX_STACKS`] stacks can be registered. After that:
+//!
+//! - [`usage()`] reports the high-water mark of the current stack.
+//! - [`Spawner::stack_high_water_mark()`](crate::Spawner::stack_high_water_mark) and
+//!   [`SendSpawner::stack_high_water_mark()`](crate::SendSpawner::stack_high_water_mark)
+//!   report the deepest stack usage of a single executor, measured from the stack pointer
+//!   at the time it started polling.
+//! - [`Metadata::stack_high_water_mark()`](crate::Metadata::stack_high_water_mark) reports the
+//!   deepest stack usage of a single task, measured from the stack pointer at the time it was
+//!   polled.
+//!
+//! Executors polled on a stack that wasn't registered with [`init()`] are not measured, and
+//! their high-water marks stay zero.
+//!
+//! Measurements re-paint the stack after every task poll, which costs time proportional to the
+//! stack depth the poll reached. This is a debugging aid, it's not recommended to leave it
+//! enabled in production.
+//!
+//! To keep that cost bounded, the end of the used stack is found by scanning down from the stack
+//! pointer until [`CLEAN_RUN`] bytes in a row still hold the pattern. A stack frame holding a
+//! larger buffer that is never written (e.g. a `MaybeUninit` array) can therefore make a
+//! measurement too low. [`usage()`] scans the whole stack and doesn't have this limitation.
+//!
+//! Note that interrupts use the same stack as the code they preempt on most platforms. The stack
+//! usage of an executor or task therefore includes the stack used by interrupt handlers (and
+//! interrupt executors) that preempted it while it was polling.
+//!
+//! This assumes the stack grows downwards, which is the case for all supported platforms.
+
+#[cfg(not(feature = "platform-avr"))]
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+
+#[cfg(feature = "platform-avr")]
+use portable_atomic::AtomicUsize;
+
+use crate::Metadata;
+
+/// Maximum number of stacks that can be registered with [`init()`].
+pub const MAX_STACKS: usize = 4;
+
+/// Number of consecutive painted bytes that ends the scan for the end of the used stack.
+pub const CLEAN_RUN: usize = 512;
+
+/// Pattern the unused stack is painted with.
+const PAINT: u32 = 0xCCCC_CCCC;
+
+/// Bytes left untouched below the current stack pointer when painting, to stay clear of the
+/// frame of the painting function itself.
+const MARGIN: usize = 256;
+
+/// A stack registered with [`init()`]. Unused while `bottom` is zero.
+struct Stack {
+    bottom: AtomicUsize,
+    top: AtomicUsize,
+    high_water_mark: AtomicUsize,
+}
+
+impl Stack {
+    const fn new() -> Self {
+        Self {
+            bottom: AtomicUsize::new(0),
+            top: AtomicUsize::new(0),
+            high_water_mark: AtomicUsize::new(0),
+        }
+    }
+
+    fn bounds(&self) -> Option<(usize, usize)> {
+        let bottom = self.bottom.load(Ordering::Acquire);
+        (bottom != 0).then(|| (bottom, self.top.load(Ordering::Relaxed)))
+    }
+
+    fn record(&self, top: usize, lowest: usize) {
+        raise(&self.high_water_mark, top - lowest);
+    }
+}
+
+static STACKS: [Stack; MAX_STACKS] = [const { Stack::new() }; MAX_STACKS];
+
+/// Find the registered stack containing `sp`.
+fn find(sp: usize) -> Option<(&'static Stack, usize, usize)> {
+    STACKS.iter().find_map(|stack| match stack.bounds() {
+        Some((bottom, top)) if (bottom..top).contains(&sp) => Some((stack, bottom, top)),
+        _ => None,
+    })
+}
+
+/// Stack usage report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StackUsage {
+    /// Total size of the stack in bytes.
+    pub size: usize,
+    /// Maximum number of bytes ever used.
+    pub used: usize,
+}
+
+impl StackUsage {
+    /// Number of bytes that were never used.
+    pub fn free(&self) -> usize {
+        self.size - self.used
+    }
+}
+
+/// Initialize stack usage measurement for the current stack, painting its unused part.
+///
+/// `bottom` is the lowest address of the stack, `top` is the address right above the highest
+/// word of the stack (i.e. the initial stack pointer). With `cortex-m-rt`, these are typically
+/// the `_stack_end` and `_stack_start` linker symbols.
+///
+/// # Panics
+///
+/// Panics if the current stack pointer is not within `bottom..top`, or if [`MAX_STACKS`] stacks
+/// are already registered.
+///
+/// # Safety
+///
+/// `bottom..top` must be the stack of the current thread of execution, and must not be used
+/// for anything else. This must be called before any executor is started on this stack.
+pub unsafe fn init(bottom: *mut u32, top: *mut u32) {
+    let bottom = bottom as usize;
+    let top = top as usize;
+    assert!(bottom != 0 && bottom <= top);
+    assert!(
+        (bottom..top).contains(&stack_pointer()),
+        "not called on the given stack"
+    );
+
+    critical_section::with(|_| {
+        let stack = STACKS
+            .iter()
+            .find(|s| match s.bounds() {
+                None => true,
+                Some((b, _)) => b == bottom,
+            })
+            .expect("too many stacks registered");
+        stack.top.store(top, Ordering::Relaxed);
+        stack.high_water_mark.store(0, Ordering::Relaxed);
+        stack.bottom.store(bottom, Ordering::Release);
+    });
+
+    paint(bottom);
+}
+
+/// Get the usage of the current stack since [`init()`] was called for it.
+///
+/// Returns `None` if [`init()`] was not called for the current stack.
+pub fn usage() -> Option<StackUsage> {
+    let (stack, bottom, top) = find(stack_pointer())?;
+
+    let used = (top - lowest_dirty(bottom)).max(stack.high_water_mark.load(Ordering::Relaxed));
+    Some(StackUsage {
+        size: top - bottom,
+        used,
+    })
+}
+
+/// Stack high-water mark of a single executor.
+pub(crate) struct ExecutorStack {
+    high_water_mark: AtomicUsize,
+}
+
+impl ExecutorStack {
+    pub(crate) const fn new() -> Self {
+        Self {
+            high_water_mark: AtomicUsize::new(0),
+        }
+    }
+
+    pub(crate) fn high_water_mark(&self) -> usize {
+        self.high_water_mark.load(Ordering::Relaxed)
+    }
+
+    /// Poll a task with `f`, recording how deep into the stack it went for the task and for the
+    /// executor. `start` is the stack pointer when the executor started polling.
+    ///
+    /// Must not be called reentrantly for the same executor, which `poll` already guarantees.
+    #[inline(always)]
+    pub(crate) fn measure(&self, start: usize, task: &Metadata, f: impl FnOnce()) {
+        let task_start = stack_pointer();
+        let Some((stack, bottom, top)) = find(task_start) else {
+            // Not a registered stack, painting it could overwrite live data.
+            f();
+            return;
+        };
+
+        f();
+
+        let lowest = lowest_dirty_below(bottom, task_start);
+        task.record_stack_usage(task_start.saturating_sub(lowest));
+        raise(&self.high_water_mark, start.saturating_sub(lowest));
+
+        // Remember the stack high-water mark before painting over it.
+        stack.record(top, lowest);
+
+        unsafe { paint(lowest) };
+    }
+}
+
+/// Raise `mark` to `value` if it's higher.
+///
+/// Not atomic, because `fetch_max` isn't available on all targets. An interrupt executor raising
+/// the same mark in between can have its value overwritten, which only makes the mark less
+/// accurate.
+pub(crate) fn raise(mark: &AtomicUsize, value: usize) {
+    if value > mark.load(Ordering::Relaxed) {
+        mark.store(value, Ordering::Relaxed);
+    }
+}
+
+/// Approximate the current stack pointer with the address of a local.
+#[inline(always)]
+pub(crate) fn stack_pointer() -> usize {
+    let marker = 0u8;
+    core::hint::black_box(&marker) as *const u8 as usize
+}
+
+fn is_painted(addr: usize) -> bool {
+    unsafe { (addr as *const u32).read_volatile() == PAINT }
+}
+
+/// Find the lowest stack address that no longer holds the paint pattern, scanning the whole
+/// stack from `bottom`.
+fn lowest_dirty(bottom: usize) -> usize {
+    let mut addr = (bottom + 3) & !3;
+    let sp = stack_pointer();
+    while addr < sp && is_painted(addr) {
+        addr += 4;
+    }
+    addr
+}
+
+/// Find the lowest stack address that no longer holds the paint pattern, scanning down from
+/// `from` until [`CLEAN_RUN`] bytes in a row are painted.
+fn lowest_dirty_below(bottom: usize, from: usize) -> usize {
+    let bottom = (bottom + 3) & !3;
+    let mut addr = from & !3;
+    let mut lowest = addr;
+    while addr > bottom && lowest - addr < CLEAN_RUN {
+        addr -= 4;
+        if !is_painted(addr) {
+            lowest = addr;
+        }
+    }
+    lowest
+}
+
+/// Paint the stack from `from` up to a safe distance below the current stack pointer.
+///
+/// Interrupts may fire while painting and push their frames below the stack pointer. This is
+/// harmless: they have returned by the time painting resumes.
+#[inline(never)]
+unsafe fn paint(from: usize) {
+    let limit = stack_pointer().saturating_sub(MARGIN) & !3;
+    let mut addr = (from + 3) & !3;
+    while addr < limit {
+        (addr as *mut u32).write_volatile(PAINT);
+        addr += 4;
+    }
+}
@@ -0,0 +1,183 @@
+#![cfg(feature = "executor-sim")]
+
+use std::boxed::Box;
+use std::future::poll_fn;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::Poll;
+
+use embassy_executor::{SimExecutor, task};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::{Duration, Instant, Ticker, Timer};
+
+// The `MockDriver` is global, so simulations must not run concurrently.
+static SERIAL: Mutex<()> = Mutex::new(());
+
+fn setup(seed: u64) -> (&'static SimExecutor, MutexGuard<'static, ()>) {
+    let guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let executor = &*Box::leak(Box::new(SimExecutor::new(seed)));
+    (executor, guard)
+}
+
+#[derive(Clone)]
+struct Trace {
+    trace: Arc<Mutex<Vec<u32>>>,
+}
+
+impl Trace {
+    fn new() -> Self {
+        Self {
+            trace: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    fn push(&self, value: u32) {
+        self.trace.lock().unwrap().push(value)
+    }
+
+    fn get(&self) -> Vec<u32> {
+        self.trace.lock().unwrap().clone()
+    }
+}
+
+async fn yield_now() {
+    let mut yielded = false;
+    poll_fn(|cx| {
+        if yielded {
+            Poll::Ready(())
+        } else {
+            yielded = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+#[test]
+fn sim_timer_uses_virtual_time() {
+    #[task]
+    async fn sleeper(trace: Trace) {
+        Timer::after(Duration::from_secs(3600)).await;
+        trace.push(1);
+    }
+
+    let (executor, _guard) = setup(0);
+    let trace = Trace::new();
+    let start = Instant::now();
+
+    executor.run_until(
+        |spawner| spawner.spawn(sleeper(trace.clone()).unwrap()),
+        || !trace.get().is_empty(),
+    );
+
+    assert_eq!(Instant::now() - start, Duration::from_secs(3600));
+}
+
+#[test]
+fn sim_ticker_and_channel() {
+    static CHANNEL: Channel<CriticalSectionRawMutex, u32, 1> = Channel::new();
+
+    #[task]
+    async fn producer() {
+        let mut ticker = Ticker::every(Duration::from_millis(100));
+        for i in 0..5 {
+            ticker.next().await;
+            CHANNEL.send(i).await;
+        }
+    }
+
+    #[task]
+    async fn consumer(trace: Trace) {
+        for _ in 0..5 {
+            let value = CHANNEL.receive().await;
+            trace.push(value);
+        }
+    }
+
+    let (executor, _guard) = setup(1);
+    let trace = Trace::new();
+    let start = Instant::now();
+
+    executor.run_until(
+        |spawner| {
+            spawner.spawn(producer().unwrap());
+            spawner.spawn(consumer(trace.clone()).unwrap());
+        },
+        || trace.get().len() == 5,
+    );
+
+    assert_eq!(trace.get(), [0, 1, 2, 3, 4]);
+    assert_eq!(Instant::now() - start, Duration::from_millis(500));
+}
+
+#[test]
+fn sim_run_until_instant() {
+    #[task]
+    async fn ticker(trace: Trace) {
+        let mut ticker = Ticker::every(Duration::from_secs(1));
+        for i in 0..10 {
+            ticker.next().await;
+            trace.push(i);
+        }
+    }
+
+    let (executor, _guard) = setup(2);
+    let trace = Trace::new();
+    let start = Instant::now();
+
+    executor.spawner().spawn(ticker(trace.clone()).unwrap());
+    executor.run_until_instant(start + Duration::from_millis(3500));
+    assert_eq!(trace.get(), [0, 1, 2]);
+    assert_eq!(Instant::now(), start + Duration::from_millis(3500));
+
+    while executor.step().is_some() {}
+    assert_eq!(trace.get(), [0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+    assert_eq!(Instant::now(), start + Duration::from_secs(10));
+}
+
+#[test]
+fn sim_seed_is_reproducible() {
+    #[task(pool_size = 4)]
+    async fn worker(id: u32, trace: Trace) {
+        for _ in 0..3 {
+            trace.push(id);
+            yield_now().await;
+        }
+    }
+
+    fn run(seed: u64) -> Vec<u32> {
+        let (executor, _guard) = setup(seed);
+        let trace = Trace::new();
+        executor.run_until(
+            |spawner| {
+                for id in 0..4 {
+                    spawner.spawn(worker(id, trace.clone()).unwrap());
+                }
+            },
+            || trace.get().len() == 12,
+        );
+        // let the workers exit so the pool can be reused.
+        executor.run_until_idle();
+        trace.get()
+    }
+
+    for seed in 0..8 {
+        assert_eq!(run(seed), run(seed));
+    }
+
+    let orderings: Vec<_> = (0..8).map(run).collect();
+    assert!(orderings.iter().any(|o| *o != orderings[0]));
+}
+
+#[test]
+#[should_panic(expected = "simulation stalled")]
+fn sim_stall_panics() {
+    #[task]
+    async fn stuck() {
+        poll_fn(|_| Poll::<()>::Pending).await
+    }
+
+    let (executor, _guard) = setup(3);
+    executor.run_until(|spawner| spawner.spawn(stuck().unwrap()), || false);
+}
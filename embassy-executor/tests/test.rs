@@ -361,3 +361,95 @@ fn task_metadata() {
     executor.spawner().spawn(task1(None).unwrap());
     unsafe { executor.poll() };
 }
+
+#[cfg(feature = "metadata-size")]
+#[test]
+fn task_metadata_size() {
+    #[task]
+    async fn small() {}
+
+    #[task]
+    async fn large() {
+        let buf = [0u8; 1024];
+        poll_fn(|_| Poll::Ready(())).await;
+        core::hint::black_box(&buf);
+    }
+
+    let (executor, _) = setup();
+
+    let token = small().unwrap();
+    let small_size = token.metadata().size();
+    executor.spawner().spawn(token);
+
+    let token = large().unwrap();
+    let large_size = token.metadata().size();
+    executor.spawner().spawn(token);
+
+    unsafe { executor.poll() };
+
+    assert!(small_size > 0);
+    assert!(large_size >= small_size + 1024);
+}
+
+#[cfg(feature = "stack-usage")]
+#[test]
+fn stack_high_water_mark() {
+    use std::sync::OnceLock;
+
+    use embassy_executor::{Metadata, stack};
+
+    static DEEP: OnceLock<&'static Metadata> = OnceLock::new();
+    static SHALLOW: OnceLock<&'static Metadata> = OnceLock::new();
+
+    #[task]
+    async fn deep() {
+        {
+            let buf = [0x55u8; 2048];
+            core::hint::black_box(&buf);
+        }
+        let _ = DEEP.set(Metadata::for_current_task().await);
+    }
+
+    #[task]
+    async fn shallow() {
+        let _ = SHALLOW.set(Metadata::for_current_task().await);
+    }
+
+    std::thread::spawn(|| {
+        // Measure a region of this thread's stack, well below the current frame.
+        let marker = 0u8;
+        let sp = core::hint::black_box(&marker) as *const u8 as usize;
+        let top = (sp + 64) & !3;
+        let bottom = top - 256 * 1024;
+        unsafe { stack::init(bottom as *mut u32, top as *mut u32) };
+
+        let (executor, _) = setup();
+        executor.spawner().spawn(deep().unwrap());
+        executor.spawner().spawn(shallow().unwrap());
+        unsafe { executor.poll() };
+
+        let deep = DEEP.get().unwrap().stack_high_water_mark();
+        let shallow = SHALLOW.get().unwrap().stack_high_water_mark();
+        assert!(deep >= 2048);
+        assert!(shallow < 2048);
+        assert!(executor.spawner().stack_high_water_mark() >= deep);
+
+        let usage = stack::usage().unwrap();
+        assert_eq!(usage.size, 256 * 1024);
+        assert!(usage.used >= deep);
+    })
+    .join()
+    .unwrap();
+
+    // This thread's stack isn't registered, so it's not measured.
+    assert!(stack::usage().is_none());
+    let (executor, _) = setup();
+    #[task]
+    async fn unmeasured() {
+        let buf = [0x55u8; 2048];
+        core::hint::black_box(&buf);
+    }
+    executor.spawner().spawn(unmeasured().unwrap());
+    unsafe { executor.poll() };
+    assert_eq!(executor.spawner().stack_high_water_mark(), 0);
+}
@@ -330,6 +330,11 @@ impl<'d> BufferedUarte<'d> {
         self.tx.set_baudrate(baudrate);
     }
 
+    /// Adjust the baud rate and parity to the provided configuration.
+    pub fn set_config(&mut self, config: &Config) {
+        self.tx.set_config(config);
+    }
+
     /// Split the UART in reader and writer parts.
     ///
     /// This allows reading and writing concurrently from independent tasks.
@@ -543,6 +548,14 @@ impl<'d> BufferedUarteTx<'d> {
     pub fn set_baudrate(&mut self, baudrate: Baudrate) {
         self.r.baudrate().write(|w| w.set_baudrate(baudrate));
     }
+
+    /// Adjust the baud rate and parity to the provided configuration.
+    ///
+    /// This also affects the RX half of the UART.
+    pub fn set_config(&mut self, config: &Config) {
+        self.r.config().modify(|w| w.set_parity(config.parity));
+        self.r.baudrate().write(|w| w.set_baudrate(config.baudrate));
+    }
 }
 
 impl<'a> Drop for BufferedUarteTx<'a> {
@@ -884,6 +897,26 @@ impl core::fmt::Display for Error {
 }
 impl core::error::Error for Error {}
 
+impl<'d> embassy_embedded_hal::SetConfig for BufferedUarte<'d> {
+    type Config = Config;
+    type ConfigError = ();
+
+    fn set_config(&mut self, config: &Self::Config) -> Result<(), Self::ConfigError> {
+        self.set_config(config);
+        Ok(())
+    }
+}
+
+impl<'d> embassy_embedded_hal::SetConfig for BufferedUarteTx<'d> {
+    type Config = Config;
+    type ConfigError = ();
+
+    fn set_config(&mut self, config: &Self::Config) -> Result<(), Self::ConfigError> {
+        self.set_config(config);
+        Ok(())
+    }
+}
+
 mod _embedded_io {
     use super::*;
 
@@ -238,6 +238,11 @@ impl<'d, U: UarteInstance> BufferedUarte<'d, U> {
         r.baudrate().write(|w| w.set_baudrate(baudrate));
     }
 
+    /// Adjust the baud rate and parity to the provided configuration.
+    pub fn set_config(&mut self, config: &Config) {
+        self.tx.set_config(config);
+    }
+
     /// Split the UART in reader and writer parts.
     ///
     /// This allows reading and writing concurrently from independent tasks.
@@ -425,6 +430,15 @@ impl<'d, U: UarteInstance> BufferedUarteTx<'d, U> {
             Poll::Ready(Ok(()))
         })
     }
+
+    /// Adjust the baud rate and parity to the provided configuration.
+    ///
+    /// This also affects the RX half of the UART.
+    pub fn set_config(&mut self, config: &Config) {
+        let r = U::regs();
+        r.config().modify(|w| w.set_parity(config.parity));
+        r.baudrate().write(|w| w.set_baudrate(config.baudrate));
+    }
 }
 
 impl<'a, U: UarteInstance> Drop for BufferedUarteTx<'a, U> {
@@ -610,6 +624,26 @@ impl<'a, U: UarteInstance> Drop for BufferedUarteRx<'a, U> {
     }
 }
 
+impl<'d, U: UarteInstance> embassy_embedded_hal::SetConfig for BufferedUarte<'d, U> {
+    type Config = Config;
+    type ConfigError = ();
+
+    fn set_config(&mut self, config: &Self::Config) -> Result<(), Self::ConfigError> {
+        self.set_config(config);
+        Ok(())
+    }
+}
+
+impl<'d, U: UarteInstance> embassy_embedded_hal::SetConfig for BufferedUarteTx<'d, U> {
+    type Config = Config;
+    type ConfigError = ();
+
+    fn set_config(&mut self, config: &Self::Config) -> Result<(), Self::ConfigError> {
+        self.set_config(config);
+        Ok(())
+    }
+}
+
 mod _embedded_io {
     use super::*;
 
@@ -291,21 +291,7 @@ impl<'d, V: VbusDetect> driver::Bus for Bus<'d, V> {
     }
 
     fn endpoint_set_stalled(&mut self, ep_addr: EndpointAddress, stalled: bool) {
-        let regs = self.regs;
-        if ep_addr.index() == 0 {
-            if stalled {
-                regs.tasks_ep0stall().write_value(1);
-            }
-        } else {
-            regs.epstall().write(|w| {
-                w.set_ep(ep_addr.index() as u8 & 0b111);
-                w.set_io(match ep_addr.direction() {
-                    Direction::In => vals::Io::In,
-                    Direction::Out => vals::Io::Out,
-                });
-                w.set_stall(stalled);
-            });
-        }
+        set_stalled(self.regs, ep_addr, stalled);
     }
 
     fn endpoint_is_stalled(&mut self, ep_addr: EndpointAddress) -> bool {
@@ -464,6 +450,24 @@ impl<'d, Dir> Endpoint<'d, Dir> {
     }
 }
 
+/// Stall or unstall an endpoint, for the bus and the endpoints.
+fn set_stalled(regs: pac::usbd::Usbd, ep_addr: EndpointAddress, stalled: bool) {
+    if ep_addr.index() == 0 {
+        if stalled {
+            regs.tasks_ep0stall().write_value(1);
+        }
+    } else {
+        regs.epstall().write(|w| {
+            w.set_ep(ep_addr.index() as u8 & 0b111);
+            w.set_io(match ep_addr.direction() {
+                Direction::In => vals::Io::In,
+                Direction::Out => vals::Io::Out,
+            });
+            w.set_stall(stalled);
+        });
+    }
+}
+
 impl<'d, Dir: EndpointDir> driver::Endpoint for Endpoint<'d, Dir> {
     fn info(&self) -> &EndpointInfo {
         &self.info
@@ -472,6 +476,11 @@ impl<'d, Dir: EndpointDir> driver::Endpoint for Endpoint<'d, Dir> {
     async fn wait_enabled(&mut self) {
         self.wait_enabled_state(true).await
     }
+
+    fn stall(&mut self) -> Result<(), Unsupported> {
+        set_stalled(self.regs, self.info.addr, true);
+        Ok(())
+    }
 }
 
 #[allow(private_bounds)]
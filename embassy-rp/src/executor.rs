@@ -9,6 +9,9 @@
 //! macro using
 //!
 //! `#[embassy_executor::main(executor = "embassy_rp::executor::Executor",  entry = "cortex_m_rt::entry")]`
+//!
+//! With the `executor-load-balancing` feature, the executors also track how busy each core is,
+//! and a [`BalancedSpawner`] can spawn tasks on whichever core is least loaded.
 
 #[cfg(any(feature = "executor-thread", feature = "executor-interrupt"))]
 struct RpPender;
@@ -129,6 +132,9 @@ mod thread {
 
             loop {
                 unsafe {
+                    #[cfg(feature = "executor-load-balancing")]
+                    super::balance::measure(|| self.inner.poll());
+                    #[cfg(not(feature = "executor-load-balancing"))]
                     self.inner.poll();
                     asm!("wfe");
                 };
@@ -198,6 +204,9 @@ mod interrupt {
         /// - You must not call this before calling `start()`.
         pub unsafe fn on_interrupt(&'static self) {
             let executor = unsafe { (&*self.executor.get()).assume_init_ref() };
+            #[cfg(feature = "executor-load-balancing")]
+            super::balance::measure(|| executor.poll());
+            #[cfg(not(feature = "executor-load-balancing"))]
             executor.poll();
         }
 
@@ -259,3 +268,312 @@ mod interrupt {
         }
     }
 }
+
+#[cfg(feature = "executor-load-balancing")]
+pub use balance::{BalancedSpawner, load};
+#[cfg(feature = "executor-load-balancing")]
+mod balance {
+    use core::sync::atomic::{AtomicBool, AtomicU32, Ordering, fence};
+
+    use embassy_executor::{SendSpawner, SpawnToken};
+
+    use super::window::{Window, pick};
+    use crate::multicore::{CoreId, current_core};
+    #[cfg(feature = "rp2040")]
+    use crate::pac::TIMER;
+    #[cfg(feature = "_rp235x")]
+    use crate::pac::TIMER0 as TIMER;
+
+    /// Load accounting of one core.
+    ///
+    /// Only the core itself writes its entry, with interrupts disabled. The other core may read
+    /// it at any time, so the entry is protected by a sequence lock: `seq` is odd while an update
+    /// is in progress, and readers retry until they see the same even value before and after
+    /// reading the fields.
+    struct CoreLoad {
+        seq: AtomicU32,
+        window_start: AtomicU32,
+        busy: AtomicU32,
+        load: AtomicU32,
+    }
+
+    impl CoreLoad {
+        const fn new() -> Self {
+            Self {
+                seq: AtomicU32::new(0),
+                window_start: AtomicU32::new(0),
+                busy: AtomicU32::new(0),
+                load: AtomicU32::new(0),
+            }
+        }
+
+        fn read(&self) -> Window {
+            loop {
+                let seq = self.seq.load(Ordering::Acquire);
+                if seq & 1 == 0 {
+                    let window = Window {
+                        start: self.window_start.load(Ordering::Relaxed),
+                        busy: self.busy.load(Ordering::Relaxed),
+                        load: self.load.load(Ordering::Relaxed),
+                    };
+                    fence(Ordering::Acquire);
+                    if self.seq.load(Ordering::Relaxed) == seq {
+                        return window;
+                    }
+                }
+                core::hint::spin_loop();
+            }
+        }
+
+        /// Must only be called by the core owning the entry, with interrupts disabled.
+        fn write(&self, window: &Window) {
+            let seq = self.seq.load(Ordering::Relaxed);
+            self.seq.store(seq.wrapping_add(1), Ordering::Relaxed);
+            fence(Ordering::Release);
+            self.window_start.store(window.start, Ordering::Relaxed);
+            self.busy.store(window.busy, Ordering::Relaxed);
+            self.load.store(window.load, Ordering::Relaxed);
+            self.seq.store(seq.wrapping_add(2), Ordering::Release);
+        }
+    }
+
+    static LOAD: [CoreLoad; 2] = [CoreLoad::new(), CoreLoad::new()];
+    static TIE_BREAK: AtomicBool = AtomicBool::new(false);
+
+    fn now() -> u32 {
+        TIMER.timerawl().read()
+    }
+
+    /// Run `f`, accounting the time it takes as busy time for the current core.
+    #[inline(always)]
+    pub(super) fn measure(f: impl FnOnce()) {
+        let start = now();
+        f();
+        let end = now();
+
+        let load = &LOAD[current_core() as usize];
+        cortex_m::interrupt::free(|_| {
+            let mut window = load.read();
+            window.account(start, end);
+            load.write(&window);
+        });
+    }
+
+    /// Get the recent load of a core, in percent.
+    ///
+    /// This is the fraction of time the embassy-rp executors on that core spent polling tasks,
+    /// averaged over the last few milliseconds. Time spent in interrupt executors is counted
+    /// twice if they preempt the thread executor while it is polling, so the value may
+    /// overestimate the load of cores running several executors.
+    pub fn load(core: CoreId) -> u8 {
+        LOAD[core as usize].read().load_at(now()) as u8
+    }
+
+    /// Spawner that balances tasks between the executors of both cores.
+    ///
+    /// Each spawned task goes to the executor of the core that is currently least loaded,
+    /// as reported by [`load()`]. Once spawned, a task stays on the executor it was spawned on
+    /// until it finishes, so this works best for tasks that are short-lived or spawned
+    /// repeatedly, like processing jobs.
+    ///
+    /// The executors must be the ones from this module ([`Executor`](super::Executor) or
+    /// [`InterruptExecutor`](super::InterruptExecutor)), since they are the ones measuring
+    /// the load.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// static BALANCER: OnceLock<BalancedSpawner> = OnceLock::new();
+    ///
+    /// // on core 1
+    /// executor1.run(|spawner| CORE1_SPAWNER.signal(spawner.make_send()));
+    ///
+    /// // on core 0
+    /// let core1 = CORE1_SPAWNER.wait().await;
+    /// let balancer = BALANCER.get_or_init(|| BalancedSpawner::new(spawner.make_send(), core1));
+    /// balancer.spawn(crunch_numbers(job).unwrap());
+    /// ```
+    #[derive(Copy, Clone)]
+    pub struct BalancedSpawner {
+        spawners: [SendSpawner; 2],
+    }
+
+    impl BalancedSpawner {
+        /// Create a new balanced spawner from spawners for the executors on core 0 and core 1.
+        pub fn new(core0: SendSpawner, core1: SendSpawner) -> Self {
+            Self {
+                spawners: [core0, core1],
+            }
+        }
+
+        /// Get the core that is currently least loaded.
+        ///
+        /// Ties are broken by alternating between cores, so that tasks spawned in a burst
+        /// are spread evenly before their load shows up in the measurements.
+        pub fn least_loaded(&self) -> CoreId {
+            let load0 = load(CoreId::Core0);
+            let load1 = load(CoreId::Core1);
+            let tie = TIE_BREAK.load(Ordering::Relaxed);
+            let (core, tie) = pick(load0, load1, tie);
+            TIE_BREAK.store(tie, Ordering::Relaxed);
+            core
+        }
+
+        /// Spawn a task on the least loaded core.
+        ///
+        /// Returns the core the task was spawned on.
+        pub fn spawn<S: Send>(&self, token: SpawnToken<S>) -> CoreId {
+            let core = self.least_loaded();
+            self.spawn_on(core, token);
+            core
+        }
+
+        /// Spawn a task on a given core.
+        pub fn spawn_on<S: Send>(&self, core: CoreId, token: SpawnToken<S>) {
+            self.spawners[core as usize].spawn(token)
+        }
+
+        /// Get the spawner for the executor on a given core.
+        pub fn spawner(&self, core: CoreId) -> SendSpawner {
+            self.spawners[core as usize]
+        }
+    }
+}
+
+/// Load accounting, kept apart from the hardware access so that it can be tested on the host.
+#[cfg(any(feature = "executor-load-balancing", test))]
+mod window {
+    use crate::multicore::CoreId;
+
+    /// Length of the window over which the load of a core is averaged, in microseconds.
+    const WINDOW_US: u32 = 10_000;
+
+    /// Busy time of a core in the current window, and its load in the last complete window.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub(super) struct Window {
+        /// Timer value at the start of the current window.
+        pub start: u32,
+        /// Busy time in the current window, in microseconds.
+        pub busy: u32,
+        /// Load of the last complete window, in percent.
+        pub load: u32,
+    }
+
+    impl Window {
+        /// Account a poll that ran from `start` to `end`.
+        pub fn account(&mut self, start: u32, end: u32) {
+            let busy = self.busy.saturating_add(end.wrapping_sub(start));
+            let elapsed = end.wrapping_sub(self.start);
+            if elapsed >= WINDOW_US {
+                self.load = percent(busy, elapsed);
+                self.busy = 0;
+                self.start = end;
+            } else {
+                self.busy = busy;
+            }
+        }
+
+        /// Get the load at time `now`.
+        pub fn load_at(&self, now: u32) -> u32 {
+            let elapsed = now.wrapping_sub(self.start);
+            if elapsed >= WINDOW_US {
+                // The core hasn't polled since the window ended, so it has been mostly idle:
+                // account the idle time instead of reporting a stale value.
+                percent(self.busy, elapsed)
+            } else {
+                self.load
+            }
+        }
+    }
+
+    fn percent(busy: u32, elapsed: u32) -> u32 {
+        (busy.min(elapsed) as u64 * 100 / elapsed as u64) as u32
+    }
+
+    /// Pick the least loaded core. Ties go to core 1 if `tie` is set, and flip `tie` so that
+    /// they alternate.
+    pub(super) fn pick(load0: u8, load1: u8, tie: bool) -> (CoreId, bool) {
+        if load0 < load1 {
+            (CoreId::Core0, tie)
+        } else if load1 < load0 {
+            (CoreId::Core1, tie)
+        } else if tie {
+            (CoreId::Core1, false)
+        } else {
+            (CoreId::Core0, true)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn window_accounting() {
+            let mut window = Window {
+                start: 0,
+                busy: 0,
+                load: 0,
+            };
+
+            // Polls within the window only add up busy time.
+            window.account(1_000, 3_000);
+            window.account(5_000, 6_000);
+            assert_eq!(window.busy, 3_000);
+            assert_eq!(window.load, 0);
+            assert_eq!(window.load_at(9_000), 0);
+
+            // The poll ending the window publishes its load and starts a new one.
+            window.account(9_000, 10_000);
+            assert_eq!(
+                window,
+                Window {
+                    start: 10_000,
+                    busy: 0,
+                    load: 40
+                }
+            );
+            assert_eq!(window.load_at(15_000), 40);
+
+            // A core that stopped polling decays towards idle.
+            window.account(10_000, 12_500);
+            assert_eq!(window.load_at(20_000), 25);
+            assert_eq!(window.load_at(60_000), 5);
+        }
+
+        #[test]
+        fn window_wraps() {
+            let mut window = Window {
+                start: u32::MAX - 4_999,
+                busy: 0,
+                load: 0,
+            };
+            window.account(u32::MAX, 5_000);
+            assert_eq!(window.load, 50);
+            assert_eq!(window.start, 5_000);
+        }
+
+        #[test]
+        fn load_is_capped() {
+            let window = Window {
+                start: 0,
+                busy: u32::MAX,
+                load: 0,
+            };
+            assert_eq!(window.load_at(20_000), 100);
+        }
+
+        #[test]
+        fn pick_least_loaded() {
+            assert_eq!(pick(10, 20, false), (CoreId::Core0, false));
+            assert_eq!(pick(20, 10, true), (CoreId::Core1, true));
+
+            // Ties alternate.
+            let (core, tie) = pick(30, 30, false);
+            assert_eq!(core, CoreId::Core0);
+            let (core, tie) = pick(30, 30, tie);
+            assert_eq!(core, CoreId::Core1);
+            assert_eq!(pick(30, 30, tie).0, CoreId::Core0);
+        }
+    }
+}
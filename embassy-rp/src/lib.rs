@@ -29,8 +29,14 @@ pub mod bootsel;
 pub mod clocks;
 pub(crate) mod datetime;
 pub mod dma;
-#[cfg(any(feature = "executor-thread", feature = "executor-interrupt"))]
+#[cfg(any(feature = "executor-thread", feature = "executor-interrupt", test))]
 pub mod executor;
+
+#[cfg(all(
+    feature = "executor-load-balancing",
+    not(any(feature = "executor-thread", feature = "executor-interrupt"))
+))]
+compile_error!("The `executor-load-balancing` feature requires `executor-thread` or `executor-interrupt`.");
 pub mod flash;
 #[cfg(feature = "rp2040")]
 mod float;
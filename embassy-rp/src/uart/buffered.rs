@@ -177,6 +177,13 @@ impl BufferedUart {
         self.tx.set_baudrate(baudrate);
     }
 
+    /// Reconfigure the baud rate, word length, stop bits and parity on runtime.
+    ///
+    /// The pin inversion settings are only applied when the UART is created, and are ignored here.
+    pub fn set_config(&mut self, config: &Config) {
+        self.tx.set_config(config);
+    }
+
     /// Split into separate RX and TX handles.
     pub fn split(self) -> (BufferedUartTx, BufferedUartRx) {
         (self.tx, self.rx)
@@ -487,6 +494,14 @@ impl BufferedUartTx {
     pub fn set_baudrate<'d>(&mut self, baudrate: u32) {
         super::Uart::<'d, Async>::set_baudrate_inner(self.info, baudrate);
     }
+
+    /// Reconfigure the baud rate, word length, stop bits and parity on runtime.
+    ///
+    /// This also affects the RX half of the UART. The pin inversion settings are only applied when the
+    /// UART is created, and are ignored here.
+    pub fn set_config<'d>(&mut self, config: &Config) {
+        super::Uart::<'d, Async>::set_config_inner(self.info, config);
+    }
 }
 
 impl Drop for BufferedUartRx {
@@ -627,6 +642,26 @@ impl embedded_io::Error for Error {
     }
 }
 
+impl embassy_embedded_hal::SetConfig for BufferedUart {
+    type Config = Config;
+    type ConfigError = ();
+
+    fn set_config(&mut self, config: &Self::Config) -> Result<(), Self::ConfigError> {
+        self.set_config(config);
+        Ok(())
+    }
+}
+
+impl embassy_embedded_hal::SetConfig for BufferedUartTx {
+    type Config = Config;
+    type ConfigError = ();
+
+    fn set_config(&mut self, config: &Self::Config) -> Result<(), Self::ConfigError> {
+        self.set_config(config);
+        Ok(())
+    }
+}
+
 impl embedded_io_async::ErrorType for BufferedUart {
     type Error = Error;
 }
@@ -225,6 +225,14 @@ impl<'d, M: Mode> UartTx<'d, M> {
         Timer::after_micros(wait_usecs).await;
         regs.uartlcr_h().write_clear(|w| w.set_brk(true));
     }
+
+    /// Reconfigure the baud rate, word length, stop bits and parity on runtime.
+    ///
+    /// This also affects the RX half of the UART. The pin inversion settings are only applied when the
+    /// UART is created, and are ignored here.
+    pub fn set_config(&mut self, config: &Config) {
+        Uart::<'d, M>::set_config_inner(self.info, config);
+    }
 }
 
 impl<'d> UartTx<'d, Blocking> {
@@ -976,17 +984,8 @@ impl<'d, M: Mode> Uart<'d, M> {
 
         Self::set_baudrate_inner(info, config.baudrate);
 
-        let (pen, eps) = match config.parity {
-            Parity::ParityNone => (false, false),
-            Parity::ParityOdd => (true, false),
-            Parity::ParityEven => (true, true),
-        };
-
         r.uartlcr_h().write(|w| {
-            w.set_wlen(config.data_bits.bits());
-            w.set_stp2(config.stop_bits == StopBits::STOP2);
-            w.set_pen(pen);
-            w.set_eps(eps);
+            Self::set_frame_format(w, &config);
             w.set_fen(true);
         });
 
@@ -1057,12 +1056,44 @@ impl<'d, M: Mode> Uart<'d, M> {
         res.unwrap()
     }
 
+    fn set_frame_format(w: &mut crate::pac::uart::regs::UartlcrH, config: &Config) {
+        let (pen, eps) = match config.parity {
+            Parity::ParityNone => (false, false),
+            Parity::ParityOdd => (true, false),
+            Parity::ParityEven => (true, true),
+        };
+
+        w.set_wlen(config.data_bits.bits());
+        w.set_stp2(config.stop_bits == StopBits::STOP2);
+        w.set_pen(pen);
+        w.set_eps(eps);
+    }
+
     /// sets baudrate on runtime
     pub fn set_baudrate(&mut self, baudrate: u32) {
         Self::set_baudrate_inner(self.tx.info, baudrate);
     }
 
+    /// Reconfigure the baud rate, word length, stop bits and parity on runtime.
+    ///
+    /// The pin inversion settings are only applied when the UART is created, and are ignored here.
+    pub fn set_config(&mut self, config: &Config) {
+        Self::set_config_inner(self.tx.info, config);
+    }
+
+    fn set_config_inner(info: &Info, config: &Config) {
+        Self::write_baudrate(info, config.baudrate);
+        Self::lcr_modify(info, |w| Self::set_frame_format(w, config));
+    }
+
     fn set_baudrate_inner(info: &Info, baudrate: u32) {
+        Self::write_baudrate(info, baudrate);
+
+        // The divisor registers are only updated by a write to the line control register.
+        Self::lcr_modify(info, |_| {});
+    }
+
+    fn write_baudrate(info: &Info, baudrate: u32) {
         let r = info.regs;
 
         let clk_base = crate::clocks::clk_peri_freq();
@@ -1082,8 +1113,6 @@ impl<'d, M: Mode> Uart<'d, M> {
         // Load PL011's baud divisor registers
         r.uartibrd().write_value(pac::uart::regs::Uartibrd(baud_ibrd));
         r.uartfbrd().write_value(pac::uart::regs::Uartfbrd(baud_fbrd));
-
-        Self::lcr_modify(info, |_| {});
     }
 }
 
@@ -1157,6 +1186,26 @@ impl<'d> Uart<'d, Async> {
     }
 }
 
+impl<'d, M: Mode> embassy_embedded_hal::SetConfig for UartTx<'d, M> {
+    type Config = Config;
+    type ConfigError = ();
+
+    fn set_config(&mut self, config: &Self::Config) -> Result<(), Self::ConfigError> {
+        self.set_config(config);
+        Ok(())
+    }
+}
+
+impl<'d, M: Mode> embassy_embedded_hal::SetConfig for Uart<'d, M> {
+    type Config = Config;
+    type ConfigError = ();
+
+    fn set_config(&mut self, config: &Self::Config) -> Result<(), Self::ConfigError> {
+        self.set_config(config);
+        Ok(())
+    }
+}
+
 impl<'d, M: Mode> embedded_hal_02::serial::Read<u8> for UartRx<'d, M> {
     type Error = Error;
     fn read(&mut self) -> Result<u8, nb::Error<Self::Error>> {
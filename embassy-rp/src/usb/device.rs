@@ -403,28 +403,7 @@ impl<'d, T: Instance> driver::Bus for Bus<'d, T> {
     }
 
     fn endpoint_set_stalled(&mut self, ep_addr: EndpointAddress, stalled: bool) {
-        let n = ep_addr.index();
-
-        if n == 0 {
-            T::regs().ep_stall_arm().modify(|w| {
-                if ep_addr.is_in() {
-                    w.set_ep0_in(stalled);
-                } else {
-                    w.set_ep0_out(stalled);
-                }
-            });
-        }
-
-        let ctrl = if ep_addr.is_in() {
-            T::dpram().ep_in_buffer_control(n)
-        } else {
-            T::dpram().ep_out_buffer_control(n)
-        };
-
-        ctrl.modify(|w| w.set_stall(stalled));
-
-        let wakers = if ep_addr.is_in() { &EP_IN_WAKERS } else { &EP_OUT_WAKERS };
-        wakers[n].wake();
+        set_stalled::<T>(ep_addr, stalled);
     }
 
     fn endpoint_is_stalled(&mut self, ep_addr: EndpointAddress) -> bool {
@@ -488,6 +467,32 @@ pub struct Endpoint<'d, T: Instance, D> {
     buf: EndpointBuffer<T>,
 }
 
+/// Stall or unstall an endpoint, for the bus and the endpoints.
+fn set_stalled<T: Instance>(ep_addr: EndpointAddress, stalled: bool) {
+    let n = ep_addr.index();
+
+    if n == 0 {
+        T::regs().ep_stall_arm().modify(|w| {
+            if ep_addr.is_in() {
+                w.set_ep0_in(stalled);
+            } else {
+                w.set_ep0_out(stalled);
+            }
+        });
+    }
+
+    let ctrl = if ep_addr.is_in() {
+        T::dpram().ep_in_buffer_control(n)
+    } else {
+        T::dpram().ep_out_buffer_control(n)
+    };
+
+    ctrl.modify(|w| w.set_stall(stalled));
+
+    let wakers = if ep_addr.is_in() { &EP_IN_WAKERS } else { &EP_OUT_WAKERS };
+    wakers[n].wake();
+}
+
 impl<'d, T: Instance> driver::Endpoint for Endpoint<'d, T, In> {
     fn info(&self) -> &EndpointInfo {
         &self.info
@@ -504,6 +509,11 @@ impl<'d, T: Instance> driver::Endpoint for Endpoint<'d, T, In> {
         .await;
         trace!("wait_enabled IN OK");
     }
+
+    fn stall(&mut self) -> Result<(), Unsupported> {
+        set_stalled::<T>(self.info.addr, true);
+        Ok(())
+    }
 }
 
 impl<'d, T: Instance> driver::Endpoint for Endpoint<'d, T, Out> {
@@ -522,6 +532,11 @@ impl<'d, T: Instance> driver::Endpoint for Endpoint<'d, T, Out> {
         .await;
         trace!("wait_enabled OUT OK");
     }
+
+    fn stall(&mut self) -> Result<(), Unsupported> {
+        set_stalled::<T>(self.info.addr, true);
+        Ok(())
+    }
 }
 
 impl<'d, T: Instance> driver::EndpointOut for Endpoint<'d, T, Out> {
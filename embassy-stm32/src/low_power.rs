@@ -398,6 +398,16 @@ pub unsafe fn sleep(cs: CriticalSection) {
     }
 }
 
+/// Measured drift of the RTC relative to the time driver timer, in parts per billion.
+///
+/// Positive values mean the RTC runs fast relative to the timer. Time spent in stop mode is
+/// corrected by this amount. Returns `None` until the first measurement completes, which takes
+/// about a minute spent running, outside of stop mode.
+#[cfg(all(feature = "low-power-drift-compensation", not(feature = "_lp-time-driver")))]
+pub fn stop_clock_drift_ppb() -> Option<i32> {
+    get_driver().stop_clock_drift_ppb()
+}
+
 trait_set::trait_set! {
     /// Peripheral that can be suspended
     #[allow(private_bounds)]
@@ -30,6 +30,14 @@ impl Rtc {
 
     pub(super) fn reset_epoch(&mut self) {
         self.epoch = self.calc_epoch();
+
+        #[cfg(all(feature = "low-power-drift-compensation", not(feature = "_lp-time-driver")))]
+        crate::time_driver::get_driver().restart_stop_clock_drift();
+    }
+
+    /// Time elapsed on the RTC since the epoch, with millisecond resolution.
+    pub(crate) fn instant(&self) -> embassy_time::Instant {
+        Instant::from_millis((self.millis_from_unix_epoch() - self.epoch).try_into().unwrap())
     }
 
     /// Start the wakeup alarm and with a duration that is as close to but less than the requested duration
@@ -100,7 +108,7 @@ impl Rtc {
             });
         }
 
-        Instant::from_millis((self.millis_from_unix_epoch() - self.epoch).try_into().unwrap())
+        self.instant()
     }
 
     pub(super) fn enable_wakeup_line(&mut self) {
@@ -321,6 +321,10 @@ impl Rtc {
                 });
             });
         });
+
+        // The shift moves the RTC readings, which would look like drift.
+        #[cfg(all(feature = "low-power-drift-compensation", not(feature = "_lp-time-driver")))]
+        crate::time_driver::get_driver().restart_stop_clock_drift();
     }
 
     /// Check if daylight savings time is active.
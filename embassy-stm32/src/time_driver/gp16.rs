@@ -8,6 +8,8 @@ use core::sync::atomic::{AtomicU32, Ordering, compiler_fence};
 use critical_section::CriticalSection;
 use embassy_sync::blocking_mutex::Mutex;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+#[cfg(feature = "low-power-drift-compensation")]
+use embassy_time_driver::timebase::{DriftEstimator, Timebase};
 use embassy_time_driver::{Driver, TICK_HZ};
 use embassy_time_queue_utils::Queue;
 use stm32_metapac::timer::TimGp16;
@@ -89,6 +91,38 @@ fn calc_period_counter(ticks: u64) -> (u32, u16) {
     (2 * (ticks >> 16) as u32 + (ticks as u16 >= 0x8000) as u32, ticks as u16)
 }
 
+/// Resolution of RTC readings, which are in milliseconds.
+#[cfg(feature = "low-power-drift-compensation")]
+const RTC_READING_HZ: u32 = 1000;
+
+/// Minimum running time covered by a drift measurement. With millisecond RTC readings, a single
+/// run of this length gives a resolution of about 17ppm, measurements over many short runs are
+/// noisier.
+#[cfg(feature = "low-power-drift-compensation")]
+const DRIFT_WINDOW: u64 = 60 * TICK_HZ;
+
+/// Largest plausible drift between the RTC and the timer. LSI is only specified within tens of
+/// percent on some families, anything further off comes from a stalled or adjusted RTC.
+#[cfg(feature = "low-power-drift-compensation")]
+const MAX_DRIFT_PPB: i32 = 500_000_000;
+
+/// Converts time measured by the RTC while in stop mode to ticks of the timer.
+///
+/// The timer is clocked from HSI/HSE/PLL, the RTC from LSE/LSI, and they don't agree exactly.
+/// If time spent in stop mode were converted with the nominal RTC frequency, `Instant` would
+/// advance at a different rate in run and stop mode. Instead, the RTC frequency is measured
+/// against the timer every time we enter stop mode, and stop mode durations are converted with
+/// the measured frequency.
+///
+/// Only the time spent running is measured, from resuming to pausing the timer: in stop mode, the
+/// time of the timer is derived from the RTC itself. Measurements spanning a change of the RTC
+/// epoch are discarded.
+#[cfg(feature = "low-power-drift-compensation")]
+struct StopClock {
+    timebase: Timebase,
+    drift: DriftEstimator,
+}
+
 pub(crate) struct RtcDriver {
     /// Number of 2^15 periods elapsed since boot.
     period: AtomicU32,
@@ -98,6 +132,8 @@ pub(crate) struct RtcDriver {
     #[cfg(feature = "low-power")]
     /// The minimum pause time beyond which the executor will enter a low-power state.
     min_stop_pause: Mutex<CriticalSectionRawMutex, Cell<embassy_time::Duration>>,
+    #[cfg(feature = "low-power-drift-compensation")]
+    stop_clock: Mutex<CriticalSectionRawMutex, RefCell<StopClock>>,
     queue: Mutex<CriticalSectionRawMutex, RefCell<Queue>>,
 }
 
@@ -108,6 +144,11 @@ embassy_time_driver::time_driver_impl!(static DRIVER: RtcDriver = RtcDriver {
     rtc: Mutex::const_new(CriticalSectionRawMutex::new(), RefCell::new(None)),
     #[cfg(feature = "low-power")]
     min_stop_pause: Mutex::const_new(CriticalSectionRawMutex::new(), Cell::new(embassy_time::Duration::from_millis(0))),
+    #[cfg(feature = "low-power-drift-compensation")]
+    stop_clock: Mutex::const_new(CriticalSectionRawMutex::new(), RefCell::new(StopClock {
+        timebase: Timebase::new(RTC_READING_HZ),
+        drift: DriftEstimator::new(RTC_READING_HZ, TICK_HZ as u32, DRIFT_WINDOW, MAX_DRIFT_PPB),
+    })),
     queue: Mutex::new(RefCell::new(Queue::new()))
 });
 
@@ -309,12 +350,25 @@ impl super::LPTimeDriver for RtcDriver {
         if time_until_next_alarm < self.min_stop_pause.borrow(cs).get() {
             Err(())
         } else {
-            self.rtc
-                .borrow(cs)
-                .borrow_mut()
-                .as_mut()
-                .unwrap()
-                .start_wakeup_alarm(time_until_next_alarm);
+            let mut rtc = self.rtc.borrow(cs).borrow_mut();
+            let rtc = rtc.as_mut().unwrap();
+
+            #[cfg(feature = "low-power-drift-compensation")]
+            {
+                let rtc_now = rtc.instant().as_millis();
+                let now = self.now();
+
+                let mut stop_clock = self.stop_clock.borrow(cs).borrow_mut();
+                if let Some(ppb) = stop_clock.drift.sample(rtc_now, now) {
+                    trace!("rtc: measured drift relative to timer: {} ppb", ppb);
+                    stop_clock.timebase.set_ppb(rtc_now, ppb);
+                }
+                // The timer follows the RTC until it resumes.
+                stop_clock.drift.restart();
+                stop_clock.timebase.set_base(rtc_now, now);
+            }
+
+            rtc.start_wakeup_alarm(time_until_next_alarm);
 
             regs_gp16().cr1().modify(|w| w.set_cen(false));
             Ok(())
@@ -324,16 +378,22 @@ impl super::LPTimeDriver for RtcDriver {
     fn resume_time(&self, cs: CriticalSection) {
         assert!(!regs_gp16().cr1().read().cen());
 
-        self.set_time(
-            self.rtc
-                .borrow(cs)
-                .borrow_mut()
-                .as_mut()
-                .unwrap()
-                .stop_wakeup_alarm()
-                .as_ticks(),
-            cs,
-        );
+        let rtc_now = self.rtc.borrow(cs).borrow_mut().as_mut().unwrap().stop_wakeup_alarm();
+
+        #[cfg(feature = "low-power-drift-compensation")]
+        let now = self.stop_clock.borrow(cs).borrow().timebase.ticks(rtc_now.as_millis());
+        #[cfg(not(feature = "low-power-drift-compensation"))]
+        let now = rtc_now.as_ticks();
+
+        self.set_time(now, cs);
+
+        // Both clocks run freely from here, start measuring.
+        #[cfg(feature = "low-power-drift-compensation")]
+        self.stop_clock
+            .borrow(cs)
+            .borrow_mut()
+            .drift
+            .sample(rtc_now.as_millis(), self.now());
 
         regs_gp16().cr1().modify(|w| w.set_cen(true));
     }
@@ -363,6 +423,20 @@ impl Driver for RtcDriver {
     }
 }
 
+#[cfg(feature = "low-power-drift-compensation")]
+impl RtcDriver {
+    /// Measured drift of the RTC relative to the timer, in parts per billion.
+    pub(crate) fn stop_clock_drift_ppb(&self) -> Option<i32> {
+        critical_section::with(|cs| self.stop_clock.borrow(cs).borrow().drift.ppb())
+    }
+
+    /// Leave the RTC readings since the last one out of the drift measurement, after the RTC epoch
+    /// moved.
+    pub(crate) fn restart_stop_clock_drift(&self) {
+        critical_section::with(|cs| self.stop_clock.borrow(cs).borrow_mut().drift.restart())
+    }
+}
+
 pub(crate) const fn get_driver() -> &'static RtcDriver {
     &DRIVER
 }
@@ -8,6 +8,12 @@ pub use _version::*;
 use crate::interrupt::typelevel::Interrupt;
 use crate::rcc;
 
+/// Smallest BESL requested by the host for the L1 sleep state of LPM with which the bus lets the chip
+/// stop. Index 8 is 3 ms, enough to wake up from stop mode, start the HSE and lock the PLL before the
+/// host talks to the device again. Advertise it with `Lpm::deep_besl` of `embassy-usb`.
+#[cfg(feature = "low-power")]
+const STOP_MIN_BESL: u8 = 8;
+
 /// clock, power initialization stuff that's common for USB and OTG.
 fn common_init<T: Instance>() {
     // Check the USB clock is enabled and running at exactly 48 MHz.
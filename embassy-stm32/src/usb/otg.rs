@@ -12,7 +12,7 @@ use embassy_usb_synopsys_otg::{
 
 use crate::gpio::{AfType, OutputType, Speed};
 use crate::interrupt::typelevel::Interrupt;
-use crate::rcc::{self, RccPeripheral};
+use crate::rcc::{self, MaybeWakeGuard, RccPeripheral};
 use crate::{Peri, interrupt};
 
 /// Interrupt handler.
@@ -257,6 +257,8 @@ impl<'d, T: Instance> embassy_usb_driver::Driver<'d> for Driver<'d, T> {
                 phantom: PhantomData,
                 inner: bus,
                 inited: false,
+                can_stop: false,
+                wake_guard: T::RCC_INFO.wake_guard().into(),
             },
             cp,
         )
@@ -264,10 +266,22 @@ impl<'d, T: Instance> embassy_usb_driver::Driver<'d> for Driver<'d, T> {
 }
 
 /// USB bus.
+///
+/// With the `low-power` feature, the bus keeps the chip out of stop mode while the link is active. On STM32F4
+/// and STM32L4, it lets the chip stop while the bus is suspended, since the EXTI wakeup line of the core then
+/// wakes it up when the host resumes the link. In the L1 sleep state of LPM, the chip only stops if the host
+/// requested a BESL of at least 3 ms, which the device can ask for with `Lpm::deep_besl`. Remote wakeup works
+/// from stop mode too, from a task woken up by another interrupt.
+///
+/// The driver doesn't set up the wakeup line on the other families, where the bus keeps the chip out of stop
+/// mode even while the bus is suspended.
 pub struct Bus<'d, T: Instance> {
     phantom: PhantomData<&'d mut T>,
     inner: OtgBus<'d>,
     inited: bool,
+    /// The wakeup line is set up, so the chip can stop while the link is suspended.
+    can_stop: bool,
+    wake_guard: MaybeWakeGuard,
 }
 
 impl<'d, T: Instance> Bus<'d, T> {
@@ -362,10 +376,17 @@ impl<'d, T: Instance> Bus<'d, T> {
             0x0000_5000 | 0x0000_6100 => self.inner.config_v5(),
             _ => unimplemented!("Unknown USB core id {:X}", core_id),
         }
+
+        #[cfg(feature = "low-power")]
+        {
+            self.can_stop = enable_wakeup_line::<T>();
+        }
     }
 
     fn disable(&mut self) {
         T::Interrupt::disable();
+        #[cfg(all(feature = "low-power", stm32f4))]
+        T::WakeupInterrupt::disable();
 
         rcc::disable::<T>();
         self.inited = false;
@@ -376,6 +397,54 @@ impl<'d, T: Instance> Bus<'d, T> {
     }
 }
 
+/// Lets the core wake the chip up from stop mode when the host resumes the link. Returns `false` on the
+/// families where the driver doesn't set it up.
+#[cfg(feature = "low-power")]
+fn enable_wakeup_line<T: Instance>() -> bool {
+    cfg_if::cfg_if! {
+        if #[cfg(stm32f4)] {
+            // Configurable lines, raising the wakeup interrupt of the core.
+            let line = if T::HIGH_SPEED { EXTI_WAKEUP_LINE_HS } else { EXTI_WAKEUP_LINE_FS };
+            critical_section::with(|_| {
+                crate::pac::EXTI.rtsr(0).modify(|w| w.set_line(line, true));
+                crate::pac::EXTI.imr(0).modify(|w| w.set_line(line, true));
+            });
+            T::WakeupInterrupt::unpend();
+            unsafe { T::WakeupInterrupt::enable() };
+            true
+        } else if #[cfg(stm32l4)] {
+            // Direct line, waking the chip up with the global interrupt of the core.
+            critical_section::with(|_| crate::pac::EXTI.imr(0).modify(|w| w.set_line(17, true)));
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(all(feature = "low-power", stm32f4))]
+const EXTI_WAKEUP_LINE_FS: usize = 18;
+#[cfg(all(feature = "low-power", stm32f4))]
+const EXTI_WAKEUP_LINE_HS: usize = 20;
+
+#[cfg(all(feature = "rt", feature = "low-power", stm32f4))]
+foreach_interrupt! {
+    (USB_OTG_FS, otg, $block:ident, WKUP, $irq:ident) => {
+        #[interrupt]
+        #[allow(non_snake_case)]
+        unsafe fn $irq() {
+            crate::pac::EXTI.pr(0).write(|w| w.set_line(EXTI_WAKEUP_LINE_FS, true));
+        }
+    };
+    (USB_OTG_HS, otg, $block:ident, WKUP, $irq:ident) => {
+        #[interrupt]
+        #[allow(non_snake_case)]
+        unsafe fn $irq() {
+            crate::pac::EXTI.pr(0).write(|w| w.set_line(EXTI_WAKEUP_LINE_HS, true));
+        }
+    };
+}
+
 impl<'d, T: Instance> embassy_usb_driver::Bus for Bus<'d, T> {
     async fn poll(&mut self) -> Event {
         if !self.inited {
@@ -383,7 +452,14 @@ impl<'d, T: Instance> embassy_usb_driver::Bus for Bus<'d, T> {
             self.inited = true;
         }
 
-        self.inner.poll().await
+        let event = self.inner.poll().await;
+        match event {
+            Event::Suspend | Event::PowerRemoved if self.can_stop => self.wake_guard.disable(),
+            #[cfg(feature = "low-power")]
+            Event::Sleep { besl, .. } if self.can_stop && besl >= super::STOP_MIN_BESL => self.wake_guard.disable(),
+            _ => self.wake_guard.enable(),
+        }
+        event
     }
 
     fn endpoint_set_stalled(&mut self, ep_addr: EndpointAddress, stalled: bool) {
@@ -407,7 +483,18 @@ impl<'d, T: Instance> embassy_usb_driver::Bus for Bus<'d, T> {
         self.inner.disable().await
     }
 
+    fn set_lpm_enabled(&mut self, enabled: bool) -> Result<(), Unsupported> {
+        // The core registers are only accessible once the peripheral is powered.
+        if !self.inited {
+            self.init();
+            self.inited = true;
+        }
+
+        self.inner.set_lpm_enabled(enabled)
+    }
+
     async fn remote_wakeup(&mut self) -> Result<(), Unsupported> {
+        self.wake_guard.enable();
         self.inner.remote_wakeup().await
     }
 }
@@ -422,6 +509,10 @@ trait SealedInstance {
     const HIGH_SPEED: bool;
     const FIFO_DEPTH_WORDS: u16;
 
+    /// Interrupt of the EXTI line of the wakeup event of the core.
+    #[cfg(all(feature = "low-power", stm32f4))]
+    type WakeupInterrupt: interrupt::typelevel::Interrupt;
+
     fn regs() -> Otg;
     fn state() -> State<'static>;
 }
@@ -499,6 +590,9 @@ foreach_interrupt!(
         impl SealedInstance for crate::peripherals::USB_OTG_FS {
             const HIGH_SPEED: bool = false;
 
+            #[cfg(all(feature = "low-power", stm32f4))]
+            type WakeupInterrupt = crate::_generated::peripheral_interrupts::USB_OTG_FS::WKUP;
+
             cfg_if::cfg_if! {
                 if #[cfg(stm32f1)] {
                     const FIFO_DEPTH_WORDS: u16 = 128;
@@ -593,6 +687,9 @@ foreach_interrupt!(
         impl SealedInstance for crate::peripherals::USB_OTG_HS {
             const HIGH_SPEED: bool = true;
 
+            #[cfg(all(feature = "low-power", stm32f4))]
+            type WakeupInterrupt = crate::_generated::peripheral_interrupts::USB_OTG_HS::WKUP;
+
             cfg_if::cfg_if! {
                 if #[cfg(any(
                     stm32f2,
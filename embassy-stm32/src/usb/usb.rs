@@ -20,7 +20,7 @@ pub use usb_host::*;
 use crate::pac::USBRAM;
 use crate::pac::usb::regs;
 use crate::pac::usb::vals::{EpType, Stat};
-use crate::rcc::RccPeripheral;
+use crate::rcc::{MaybeWakeGuard, RccPeripheral};
 use crate::{Peri, interrupt};
 
 /// Interrupt handler.
@@ -53,6 +53,25 @@ impl<T: Instance> interrupt::typelevel::Handler<T::Interrupt> for InterruptHandl
             BUS_WAKER.wake();
         }
 
+        #[cfg(any(usb_v3, usb_v4))]
+        if istr.l1req() {
+            //trace!("USB IRQ: l1req");
+            IRQ_SLEEP.store(true, Ordering::Relaxed);
+            // The L1 sleep state is handled like suspend.
+            regs.cntr().modify(|w| {
+                w.set_fsusp(true);
+                w.set_lpmode(true);
+            });
+
+            // Write 0 to clear.
+            let mut clear = regs::Istr(!0);
+            clear.set_l1req(false);
+            regs.istr().write_value(clear);
+
+            // Wake main thread.
+            BUS_WAKER.wake();
+        }
+
         if istr.wkup() {
             //trace!("USB IRQ: wkup");
             IRQ_RESUME.store(true, Ordering::Relaxed);
@@ -135,6 +154,8 @@ static EP_OUT_WAKERS: [AtomicWaker; EP_COUNT] = [const { AtomicWaker::new() }; E
 static IRQ_RESET: AtomicBool = AtomicBool::new(false);
 static IRQ_SUSPEND: AtomicBool = AtomicBool::new(false);
 static IRQ_RESUME: AtomicBool = AtomicBool::new(false);
+#[cfg(any(usb_v3, usb_v4))]
+static IRQ_SLEEP: AtomicBool = AtomicBool::new(false);
 
 fn convert_type(t: EndpointType) -> EpType {
     match t {
@@ -558,6 +579,9 @@ impl<'d, T: Instance> driver::Driver<'d> for Driver<'d, T> {
                 phantom: PhantomData,
                 ep_types,
                 inited: false,
+                #[cfg(any(usb_v3, usb_v4))]
+                sleeping: false,
+                wake_guard: T::RCC_INFO.wake_guard().into(),
             },
             ControlPipe {
                 _marker: PhantomData,
@@ -570,15 +594,27 @@ impl<'d, T: Instance> driver::Driver<'d> for Driver<'d, T> {
 }
 
 /// USB bus.
+///
+/// With the `low-power` feature, the bus keeps the chip out of stop mode while the link is active. It lets
+/// the chip stop while the bus is suspended, since the peripheral then wakes it up with its direct EXTI line
+/// when the host resumes the link. Remote wakeup works from stop mode too, from a task woken up by another
+/// interrupt.
+///
+/// In the L1 sleep state of LPM, the chip only stops if the host requested a BESL of at least 3 ms, which
+/// the device can ask for with `Lpm::deep_besl`. Shorter ones leave too little time to restart the clocks.
 pub struct Bus<'d, T: Instance> {
     phantom: PhantomData<&'d mut T>,
     ep_types: [EpType; EP_COUNT - 1],
     inited: bool,
+    /// The link is in the L1 sleep state of LPM.
+    #[cfg(any(usb_v3, usb_v4))]
+    sleeping: bool,
+    wake_guard: MaybeWakeGuard,
 }
 
 impl<'d, T: Instance> driver::Bus for Bus<'d, T> {
     async fn poll(&mut self) -> Event {
-        poll_fn(move |cx| {
+        let event = poll_fn(|cx| {
             BUS_WAKER.register(cx.waker());
 
             // TODO: implement VBUS detection.
@@ -589,6 +625,16 @@ impl<'d, T: Instance> driver::Bus for Bus<'d, T> {
 
             let regs = T::regs();
 
+            #[cfg(any(usb_v3, usb_v4))]
+            if IRQ_SLEEP.load(Ordering::Acquire) {
+                IRQ_SLEEP.store(false, Ordering::Relaxed);
+                let lpmcsr = regs.lpmcsr().read();
+                return Poll::Ready(Event::Sleep {
+                    remote_wakeup: lpmcsr.remwake(),
+                    besl: lpmcsr.besl(),
+                });
+            }
+
             if IRQ_RESUME.load(Ordering::Acquire) {
                 IRQ_RESUME.store(false, Ordering::Relaxed);
                 return Poll::Ready(Event::Resume);
@@ -633,52 +679,23 @@ impl<'d, T: Instance> driver::Bus for Bus<'d, T> {
 
             Poll::Pending
         })
-        .await
+        .await;
+
+        #[cfg(any(usb_v3, usb_v4))]
+        {
+            self.sleeping = matches!(event, Event::Sleep { .. });
+        }
+        match event {
+            Event::Suspend | Event::PowerRemoved => self.wake_guard.disable(),
+            #[cfg(feature = "low-power")]
+            Event::Sleep { besl, .. } if besl >= super::STOP_MIN_BESL => self.wake_guard.disable(),
+            Event::Reset | Event::Resume | Event::PowerDetected | Event::Sleep { .. } => self.wake_guard.enable(),
+        }
+        event
     }
 
     fn endpoint_set_stalled(&mut self, ep_addr: EndpointAddress, stalled: bool) {
-        // This can race, so do a retry loop.
-        let reg = T::regs().epr(ep_addr.index() as _);
-        match ep_addr.direction() {
-            Direction::In => {
-                loop {
-                    let r = reg.read();
-                    match r.stat_tx() {
-                        Stat::Disabled => break, // if disabled, stall does nothing.
-                        Stat::Stall => break,    // done!
-                        _ => {
-                            let want_stat = match stalled {
-                                false => Stat::Nak,
-                                true => Stat::Stall,
-                            };
-                            let mut w = invariant(r);
-                            w.set_stat_tx(Stat::from_bits(r.stat_tx().to_bits() ^ want_stat.to_bits()));
-                            reg.write_value(w);
-                        }
-                    }
-                }
-                EP_IN_WAKERS[ep_addr.index()].wake();
-            }
-            Direction::Out => {
-                loop {
-                    let r = reg.read();
-                    match r.stat_rx() {
-                        Stat::Disabled => break, // if disabled, stall does nothing.
-                        Stat::Stall => break,    // done!
-                        _ => {
-                            let want_stat = match stalled {
-                                false => Stat::Valid,
-                                true => Stat::Stall,
-                            };
-                            let mut w = invariant(r);
-                            w.set_stat_rx(Stat::from_bits(r.stat_rx().to_bits() ^ want_stat.to_bits()));
-                            reg.write_value(w);
-                        }
-                    }
-                }
-                EP_OUT_WAKERS[ep_addr.index()].wake();
-            }
-        }
+        set_stalled::<T>(ep_addr, stalled);
     }
 
     fn endpoint_is_stalled(&mut self, ep_addr: EndpointAddress) -> bool {
@@ -738,8 +755,42 @@ impl<'d, T: Instance> driver::Bus for Bus<'d, T> {
     async fn enable(&mut self) {}
     async fn disable(&mut self) {}
 
+    fn set_lpm_enabled(&mut self, enabled: bool) -> Result<(), Unsupported> {
+        #[cfg(any(usb_v3, usb_v4))]
+        {
+            let regs = T::regs();
+            regs.lpmcsr().write(|w| {
+                w.set_lpmen(enabled);
+                w.set_lpmack(enabled);
+            });
+            regs.cntr().modify(|w| w.set_l1reqm(enabled));
+            Ok(())
+        }
+
+        #[cfg(not(any(usb_v3, usb_v4)))]
+        {
+            let _ = enabled;
+            Err(Unsupported)
+        }
+    }
+
     async fn remote_wakeup(&mut self) -> Result<(), Unsupported> {
+        self.wake_guard.enable();
         let regs = T::regs();
+
+        // Resume from the L1 sleep state of LPM. The peripheral drives the 50 µs resume signaling and
+        // clears L1RESUME by itself.
+        #[cfg(any(usb_v3, usb_v4))]
+        if core::mem::take(&mut self.sleeping) {
+            regs.cntr().modify(|w| {
+                w.set_lpmode(false);
+                w.set_l1resume(true);
+            });
+            while regs.cntr().read().l1resume() {}
+            regs.cntr().modify(|w| w.set_fsusp(false));
+            return Ok(());
+        }
+
         // Wake transceiver from low-power mode
         regs.cntr().modify(|w| w.set_lpmode(false));
         // Drive K-state while FSUSP is still set
@@ -793,6 +844,52 @@ enum PacketBuffer {
     Tx,
 }
 
+/// Stall or unstall an endpoint, for the bus and the endpoints.
+fn set_stalled<T: Instance>(ep_addr: EndpointAddress, stalled: bool) {
+    // This can race, so do a retry loop.
+    let reg = T::regs().epr(ep_addr.index() as _);
+    match ep_addr.direction() {
+        Direction::In => {
+            loop {
+                let r = reg.read();
+                match r.stat_tx() {
+                    Stat::Disabled => break, // if disabled, stall does nothing.
+                    Stat::Stall => break,    // done!
+                    _ => {
+                        let want_stat = match stalled {
+                            false => Stat::Nak,
+                            true => Stat::Stall,
+                        };
+                        let mut w = invariant(r);
+                        w.set_stat_tx(Stat::from_bits(r.stat_tx().to_bits() ^ want_stat.to_bits()));
+                        reg.write_value(w);
+                    }
+                }
+            }
+            EP_IN_WAKERS[ep_addr.index()].wake();
+        }
+        Direction::Out => {
+            loop {
+                let r = reg.read();
+                match r.stat_rx() {
+                    Stat::Disabled => break, // if disabled, stall does nothing.
+                    Stat::Stall => break,    // done!
+                    _ => {
+                        let want_stat = match stalled {
+                            false => Stat::Valid,
+                            true => Stat::Stall,
+                        };
+                        let mut w = invariant(r);
+                        w.set_stat_rx(Stat::from_bits(r.stat_rx().to_bits() ^ want_stat.to_bits()));
+                        reg.write_value(w);
+                    }
+                }
+            }
+            EP_OUT_WAKERS[ep_addr.index()].wake();
+        }
+    }
+}
+
 /// USB endpoint.
 pub struct Endpoint<'d, T: Instance, D> {
     _marker: PhantomData<(&'d mut T, D)>,
@@ -875,6 +972,11 @@ impl<'d, T: Instance> driver::Endpoint for Endpoint<'d, T, In> {
         .await;
         trace!("wait_enabled IN OK");
     }
+
+    fn stall(&mut self) -> Result<(), Unsupported> {
+        set_stalled::<T>(self.info.addr, true);
+        Ok(())
+    }
 }
 
 impl<'d, T: Instance> driver::Endpoint for Endpoint<'d, T, Out> {
@@ -897,6 +999,11 @@ impl<'d, T: Instance> driver::Endpoint for Endpoint<'d, T, Out> {
         .await;
         trace!("wait_enabled OUT OK");
     }
+
+    fn stall(&mut self) -> Result<(), Unsupported> {
+        set_stalled::<T>(self.info.addr, true);
+        Ok(())
+    }
 }
 
 impl<'d, T: Instance> driver::EndpointOut for Endpoint<'d, T, Out> {
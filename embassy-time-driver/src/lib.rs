@@ -108,6 +108,7 @@
 use core::task::Waker;
 
 mod tick;
+pub mod timebase;
 
 /// Ticks per second of the global timebase.
 ///
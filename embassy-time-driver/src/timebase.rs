@@ -0,0 +1,353 @@
+//! Helpers for drivers whose tick source can change at runtime.
+//!
+//! Some drivers can't count ticks with a single hardware timer running at [`TICK_HZ`]. For
+//! example, a low-power driver may count with a fast timer while the chip is active, and with a
+//! 32.768kHz RTC or LPTIM while it's in stop mode. The two clocks never agree exactly, and the
+//! fast clock usually drifts with temperature.
+//!
+//! - [`Timebase`] converts the count of a hardware counter running at any frequency to ticks. The
+//!   source frequency and its correction can be changed at runtime without time jumping backwards.
+//! - [`DriftEstimator`] measures the frequency error of one clock relative to another, which can
+//!   be fed back into a [`Timebase`] with [`Timebase::set_ppb()`].
+//!
+//! Both are plain data: drivers are expected to keep them in a mutex, next to the rest of their
+//! state.
+
+use crate::TICK_HZ;
+
+const PPB: i128 = 1_000_000_000;
+
+/// Linear mapping from the count of a hardware counter to ticks.
+///
+/// The mapping is anchored at a base: a counter value and the tick value it corresponds to. Every
+/// change of frequency re-anchors the mapping at the current count, so ticks stay continuous and
+/// monotonic across changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timebase {
+    base_count: u64,
+    base_ticks: u64,
+    hz: u32,
+    ppb: i32,
+    /// Ticks per count, 32.32 fixed point.
+    scale: u64,
+}
+
+impl Timebase {
+    /// Create a timebase for a counter running at `hz`, where count 0 is tick 0.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `hz` is zero, or so low that one count is 2^32 ticks or more.
+    pub const fn new(hz: u32) -> Self {
+        Self {
+            base_count: 0,
+            base_ticks: 0,
+            hz,
+            ppb: 0,
+            scale: scale(hz, 0),
+        }
+    }
+
+    /// Nominal frequency of the counter, in Hz.
+    pub const fn hz(&self) -> u32 {
+        self.hz
+    }
+
+    /// Frequency correction of the counter, in parts per billion.
+    ///
+    /// Positive values mean the counter runs faster than its nominal frequency.
+    pub const fn ppb(&self) -> i32 {
+        self.ppb
+    }
+
+    /// Convert a counter value to ticks, rounding down.
+    ///
+    /// Counter values before the base saturate to the base.
+    pub fn ticks(&self, count: u64) -> u64 {
+        let delta = count.saturating_sub(self.base_count) as u128;
+        let delta = (delta * self.scale as u128) >> 32;
+        self.base_ticks.saturating_add(delta.try_into().unwrap_or(u64::MAX))
+    }
+
+    /// Convert ticks to the first counter value at which [`ticks()`](Self::ticks) returns at least
+    /// `ticks`.
+    ///
+    /// This is the value to program in a compare register to get an alarm at `ticks`. Ticks before
+    /// the base saturate to the base.
+    pub fn count_at(&self, ticks: u64) -> u64 {
+        let delta = (ticks.saturating_sub(self.base_ticks) as u128) << 32;
+        let delta = delta.div_ceil(self.scale as u128);
+        self.base_count.saturating_add(delta.try_into().unwrap_or(u64::MAX))
+    }
+
+    /// Re-anchor the mapping so that counter value `count` corresponds to `ticks`.
+    ///
+    /// This is used to resynchronize with another clock, for example when resuming from stop mode.
+    /// To keep time monotonic, `ticks` must not be less than any value returned so far.
+    pub fn set_base(&mut self, count: u64, ticks: u64) {
+        self.base_count = count;
+        self.base_ticks = ticks;
+    }
+
+    /// Switch to another counter running at `hz`.
+    ///
+    /// `count` is the current value of the old counter, `new_count` the current value of the new
+    /// one. Returns the tick value at the time of the switch, which the new counter continues from.
+    /// The frequency correction is reset to zero, since it was specific to the old counter.
+    pub fn switch(&mut self, count: u64, hz: u32, new_count: u64) -> u64 {
+        let ticks = self.ticks(count);
+        self.hz = hz;
+        self.ppb = 0;
+        self.scale = scale(hz, 0);
+        self.set_base(new_count, ticks);
+        ticks
+    }
+
+    /// Set the frequency correction of the counter, in parts per billion.
+    ///
+    /// `count` is the current value of the counter. The correction only applies from `count` on,
+    /// so the ticks already elapsed are unchanged.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ppb` is -1_000_000_000 or less, which would mean the counter doesn't advance.
+    pub fn set_ppb(&mut self, count: u64, ppb: i32) {
+        let ticks = self.ticks(count);
+        self.ppb = ppb;
+        self.scale = scale(self.hz, ppb);
+        self.set_base(count, ticks);
+    }
+}
+
+/// Ticks per count, 32.32 fixed point, for a counter at `hz` corrected by `ppb`.
+const fn scale(hz: u32, ppb: i32) -> u64 {
+    assert!(hz != 0, "timebase frequency must not be zero");
+    assert!(ppb as i128 > -PPB, "timebase frequency correction must be above -100%");
+    let num = ((TICK_HZ as u128) << 32) * PPB as u128;
+    let den = hz as u128 * (PPB + ppb as i128) as u128;
+    let scale = num / den;
+    assert!(
+        scale <= u64::MAX as u128,
+        "timebase frequency too low for the tick rate"
+    );
+    scale as u64
+}
+
+/// Estimates the frequency error of a clock relative to a reference clock.
+///
+/// Feed it simultaneous readings of both clocks with [`sample()`](Self::sample). The intervals
+/// between samples add up to a measurement; once the reference has advanced by at least the
+/// configured window over them, it computes the error over the window and starts a new one.
+/// Successive measurements are smoothed with an exponential moving average, weighing the newest
+/// one 1/4.
+///
+/// Intervals where the clocks didn't both run freely, for example because one of them was derived
+/// from the other or was adjusted, must be left out with [`restart()`](Self::restart).
+///
+/// The resolution of a measurement is roughly one count of the coarser clock per interval, so
+/// windows must be long when comparing against a slow clock. Measurements where the clock didn't
+/// advance, or that are off by more than the configured bound, are discarded.
+#[derive(Debug, Clone)]
+pub struct DriftEstimator {
+    hz: u32,
+    ref_hz: u32,
+    window: u64,
+    max_ppb: i32,
+    start: Option<(u64, u64)>,
+    /// Counts of the clock and the reference summed over the intervals of the current window.
+    elapsed: (u64, u64),
+    ppb: Option<i32>,
+}
+
+impl DriftEstimator {
+    /// Create an estimator for a clock with nominal frequency `hz`, against a reference clock at
+    /// `ref_hz`. Measurements span at least `window` counts of the reference.
+    ///
+    /// Measurements further than `max_ppb` from the nominal frequency are discarded as bogus. It
+    /// should be the worst error the clocks are specified for.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_ppb` is not between 0 and 1_000_000_000 (exclusive).
+    pub const fn new(hz: u32, ref_hz: u32, window: u64, max_ppb: i32) -> Self {
+        assert!(
+            max_ppb > 0 && max_ppb < PPB as i32,
+            "drift bound must be between 0 and 1_000_000_000 ppb"
+        );
+        Self {
+            hz,
+            ref_hz,
+            window,
+            max_ppb,
+            start: None,
+            elapsed: (0, 0),
+            ppb: None,
+        }
+    }
+
+    /// Add a reading of the clock (`count`) and the reference (`ref_count`), taken at the same time.
+    ///
+    /// The interval since the previous sample is added to the measurement, unless
+    /// [`restart()`](Self::restart) was called in between. Returns the updated estimate, in parts
+    /// per billion, if this sample completed a window. Positive values mean the clock runs faster
+    /// than the reference says it should.
+    pub fn sample(&mut self, count: u64, ref_count: u64) -> Option<i32> {
+        let (start, ref_start) = self.start.replace((count, ref_count))?;
+
+        let delta = self.elapsed.0.saturating_add(count.saturating_sub(start));
+        let ref_delta = self.elapsed.1.saturating_add(ref_count.saturating_sub(ref_start));
+        if ref_delta < self.window || ref_delta == 0 {
+            self.elapsed = (delta, ref_delta);
+            return None;
+        }
+        self.elapsed = (0, 0);
+
+        // A clock that didn't advance, or is way off, is stalled or was adjusted: the window
+        // doesn't measure its frequency.
+        if delta == 0 {
+            return None;
+        }
+        let measured = delta as i128 * self.ref_hz as i128 * PPB / (ref_delta as i128 * self.hz as i128) - PPB;
+        if measured.unsigned_abs() > self.max_ppb as u128 {
+            return None;
+        }
+        let measured = measured as i64;
+
+        let ppb = match self.ppb {
+            Some(ppb) => ppb as i64 + (measured - ppb as i64) / 4,
+            None => measured,
+        } as i32;
+        self.ppb = Some(ppb);
+        Some(ppb)
+    }
+
+    /// Leave the time since the last sample out of the measurement, keeping the estimate.
+    ///
+    /// Call this when one of the clocks was stopped or adjusted, or derived from the other one.
+    /// The next sample starts a new interval.
+    pub fn restart(&mut self) {
+        self.start = None;
+    }
+
+    /// Current estimate in parts per billion, if at least one window was completed.
+    pub fn ppb(&self) -> Option<i32> {
+        self.ppb
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identity() {
+        let tb = Timebase::new(TICK_HZ as u32);
+        for count in [0, 1, 12345, 1 << 40] {
+            assert_eq!(tb.ticks(count), count);
+            assert_eq!(tb.count_at(count), count);
+        }
+    }
+
+    #[test]
+    fn test_count_at_is_first_count() {
+        let mut tb = Timebase::new(32_768);
+        tb.set_ppb(0, -35_000);
+        for ticks in (0..10_000_000).step_by(997) {
+            let count = tb.count_at(ticks);
+            assert!(tb.ticks(count) >= ticks);
+            assert!(count == 0 || tb.ticks(count - 1) < ticks);
+        }
+    }
+
+    #[test]
+    fn test_switch_is_monotonic() {
+        let mut tb = Timebase::new(32_768);
+        let before = tb.ticks(32_768 * 3 + 5);
+
+        // switch to a fast counter whose value is unrelated to the slow one.
+        let at = tb.switch(32_768 * 3 + 5, 16_000_000, 42);
+        assert_eq!(at, before);
+        assert_eq!(tb.ticks(42), before);
+        assert_eq!(tb.ticks(42 + 16_000_000), before + TICK_HZ);
+
+        let at = tb.switch(42 + 8_000_000, 32_768, 7);
+        assert_eq!(at, before + TICK_HZ / 2);
+        assert_eq!(tb.ticks(7 + 32_768), before + TICK_HZ / 2 + TICK_HZ);
+    }
+
+    #[test]
+    fn test_ppb() {
+        let mut tb = Timebase::new(1_000);
+        tb.set_ppb(1_000, 1_000_000);
+        assert_eq!(tb.ticks(1_000), TICK_HZ);
+        // the counter runs 0.1% fast: 1001 counts are one second, give or take rounding.
+        assert!(tb.ticks(1_000 + 1_001).abs_diff(2 * TICK_HZ) <= 1);
+    }
+
+    #[test]
+    fn test_drift_estimator() {
+        let mut est = DriftEstimator::new(1_000, 1_000_000, 1_000_000, 10_000_000);
+        assert_eq!(est.sample(0, 0), None);
+        assert_eq!(est.sample(500, 500_000), None);
+        assert_eq!(est.sample(1_002, 1_000_000), Some(2_000_000));
+        // smoothed towards the new measurement.
+        assert_eq!(est.sample(2_002, 2_000_000), Some(1_500_000));
+
+        est.restart();
+        assert_eq!(est.sample(5_000, 6_000_000), None);
+        assert_eq!(est.ppb(), Some(1_500_000));
+    }
+
+    #[test]
+    fn test_drift_estimator_intervals() {
+        let mut est = DriftEstimator::new(1_000, 1_000_000, 1_000_000, 10_000_000);
+        assert_eq!(est.sample(0, 0), None);
+        assert_eq!(est.sample(501, 500_000), None);
+        // the clock was adjusted while the reference ran for 3s: left out.
+        est.restart();
+        assert_eq!(est.sample(4_000, 3_500_000), None);
+        assert_eq!(est.sample(4_501, 4_000_000), Some(2_000_000));
+    }
+
+    #[test]
+    fn test_drift_estimator_stalled() {
+        let mut est = DriftEstimator::new(1_000, 1_000_000, 1_000_000, 10_000_000);
+        assert_eq!(est.sample(0, 0), None);
+        assert_eq!(est.sample(1_002, 1_000_000), Some(2_000_000));
+        // the clock stopped for a whole window: no measurement, the estimate is kept.
+        assert_eq!(est.sample(1_002, 2_000_000), None);
+        assert_eq!(est.ppb(), Some(2_000_000));
+        // and it resumes from the next window.
+        assert_eq!(est.sample(2_002, 3_000_000), Some(1_500_000));
+
+        let mut est = DriftEstimator::new(1_000, 1_000_000, 1_000_000, 10_000_000);
+        assert_eq!(est.sample(5, 0), None);
+        assert_eq!(est.sample(5, 1_000_000), None);
+        assert_eq!(est.ppb(), None);
+    }
+
+    #[test]
+    fn test_drift_estimator_extreme() {
+        let max = PPB as i32 - 1;
+        let mut est = DriftEstimator::new(1_000, 1_000, 1, max);
+        assert_eq!(est.sample(0, 0), None);
+        // the clock ran billions of times too fast.
+        assert_eq!(est.sample(u32::MAX as u64 * 1_000, 1), None);
+        // almost stopped, then almost twice too fast, within the bound.
+        assert_eq!(est.sample(u32::MAX as u64 * 1_000 + 1, 1_001), Some(-999_000_000));
+        assert_eq!(est.sample(u32::MAX as u64 * 1_000 + 2_000, 2_001), Some(-499_500_000));
+
+        let mut est = DriftEstimator::new(1_000, 1_000, 1_000, 1_000_000);
+        assert_eq!(est.sample(0, 0), None);
+        assert_eq!(est.sample(1_001, 1_000), Some(1_000_000));
+        assert_eq!(est.sample(2_001, 2_000), Some(750_000));
+        // 1.1% is more than the bound.
+        assert_eq!(est.sample(3_012, 3_000), None);
+        assert_eq!(est.ppb(), Some(750_000));
+
+        // the estimate can be applied whatever it is.
+        let mut tb = Timebase::new(1_000_000);
+        tb.set_ppb(0, -max);
+        tb.set_ppb(0, max);
+    }
+}
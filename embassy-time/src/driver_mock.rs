@@ -63,6 +63,37 @@ impl MockDriver {
             inner.queue.next_expiration(inner.now.as_ticks());
         })
     }
+
+    /// Returns the [`Instant`] of the next scheduled alarm, if any.
+    ///
+    /// Alarms that are already due are fired before the next one is looked up.
+    pub fn next_alarm(&self) -> Option<Instant> {
+        critical_section::with(|cs| {
+            let inner = &mut *self.0.borrow_ref_mut(cs);
+            match inner.queue.next_expiration(inner.now.as_ticks()) {
+                u64::MAX => None,
+                at => Some(Instant::from_ticks(at)),
+            }
+        })
+    }
+
+    /// Advances the time to the next scheduled alarm, calling its callback.
+    ///
+    /// Returns the new current time, or `None` if no alarm is scheduled. In that case,
+    /// time is left unchanged.
+    pub fn advance_to_next_alarm(&self) -> Option<Instant> {
+        critical_section::with(|cs| {
+            let inner = &mut *self.0.borrow_ref_mut(cs);
+            match inner.queue.next_expiration(inner.now.as_ticks()) {
+                u64::MAX => None,
+                at => {
+                    inner.now = Instant::from_ticks(at);
+                    inner.queue.next_expiration(at);
+                    Some(inner.now)
+                }
+            }
+        })
+    }
 }
 
 impl Driver for MockDriver {
@@ -144,4 +175,35 @@ mod tests {
         driver.advance(Duration::from_secs(1));
         assert_eq!(true, CALLBACK_CALLED.load(Ordering::Relaxed));
     }
+
+    #[test]
+    #[serial]
+    fn test_advance_to_next_alarm() {
+        setup();
+
+        static CALLBACK_CALLED: AtomicBool = AtomicBool::new(false);
+
+        struct MockWaker;
+
+        impl Wake for MockWaker {
+            fn wake(self: Arc<Self>) {
+                CALLBACK_CALLED.store(true, Ordering::Relaxed);
+            }
+        }
+        let waker = Arc::new(MockWaker).into();
+
+        let driver = MockDriver::get();
+        assert_eq!(None, driver.next_alarm());
+        assert_eq!(None, driver.advance_to_next_alarm());
+
+        let at = driver.now() + Duration::from_millis(250).as_ticks();
+        driver.schedule_wake(at, &waker);
+        assert_eq!(Some(Instant::from_ticks(at)), driver.next_alarm());
+        assert_eq!(false, CALLBACK_CALLED.load(Ordering::Relaxed));
+
+        assert_eq!(Some(Instant::from_ticks(at)), driver.advance_to_next_alarm());
+        assert_eq!(at, driver.now());
+        assert_eq!(true, CALLBACK_CALLED.load(Ordering::Relaxed));
+        assert_eq!(None, driver.next_alarm());
+    }
 }
@@ -16,6 +16,7 @@ mod delay;
 mod duration;
 mod instant;
 mod timer;
+pub mod wall_clock;
 
 #[cfg(feature = "mock-driver")]
 mod driver_mock;
@@ -35,6 +36,7 @@ pub use duration::Duration;
 pub use embassy_time_driver::TICK_HZ;
 pub use instant::Instant;
 pub use timer::{Ticker, TimeoutError, Timer, WithTimeout, try_with_timeout, with_deadline, with_timeout};
+pub use wall_clock::{DateTime, SystemTime, UtcOffset};
 
 const fn gcd(a: u64, b: u64) -> u64 {
     if b == 0 { a } else { gcd(b, a % b) }
@@ -5,6 +5,7 @@ use core::task::{Context, Poll};
 use futures_core::Stream;
 use futures_core::stream::FusedStream;
 
+use crate::wall_clock::{SystemTime, WallClockTimer};
 use crate::{Duration, Instant};
 
 /// Error returned by [`with_timeout`] and [`with_deadline`] on timeout.
@@ -173,6 +174,23 @@ impl Timer {
         Some(Self::at(expires_at))
     }
 
+    /// Expire at the specified wall-clock time.
+    ///
+    /// The deadline is converted to an [`Instant`] using the current wall-clock synchronization,
+    /// and re-computed whenever the wall clock is corrected with
+    /// [`wall_clock::synchronize()`](crate::wall_clock::synchronize). If the wall clock is not
+    /// synchronized yet, the timer waits until it is.
+    ///
+    /// Up to [`WALL_CLOCK_TIMERS`](crate::wall_clock::WALL_CLOCK_TIMERS) pending wall-clock timers
+    /// are woken as soon as the clock is corrected. Timers beyond that re-check the clock every
+    /// [`RECHECK_INTERVAL`](crate::wall_clock::RECHECK_INTERVAL), so they may expire up to that
+    /// late after a correction moves their deadline earlier.
+    ///
+    /// Will expire immediately if the time is in the past.
+    pub fn at_wall_clock(at: SystemTime) -> WallClockTimer {
+        WallClockTimer::new(at)
+    }
+
     /// Expire after the specified number of ticks.
     ///
     /// This method is a convenience wrapper for calling `Timer::after(Duration::from_ticks())`.
@@ -0,0 +1,916 @@
+//! Wall-clock time.
+//!
+//! [`Instant`] counts ticks since boot and has no relation to calendar time. This module adds
+//! a global mapping from [`Instant`] to UTC, which can be set and corrected at runtime from any
+//! source of absolute time: a hardware RTC, SNTP, GNSS, a phone over BLE, etc.
+//!
+//! - [`SystemTime`] is a point in time in UTC, counted in ticks since the Unix epoch.
+//! - [`DateTime`] is a calendar date and time of day, in UTC or shifted by a [`UtcOffset`].
+//! - [`synchronize()`] (or [`synchronize_from()`] with a [`WallClockSource`]) sets the current
+//!   time. It can be called again at any time to correct drift.
+//! - [`Timer::at_wall_clock()`](crate::Timer::at_wall_clock) waits until a given [`SystemTime`],
+//!   re-computing its deadline every time the clock is corrected.
+//!
+//! Like Unix time, [`SystemTime`] ignores leap seconds: every day is exactly 86400 seconds long.
+//! Sources that report leap seconds should be smeared or stepped by the application.
+//!
+//! The wall clock is only as accurate as the tick source, and the mapping is lost on reset.
+//! Re-synchronize after boot, and periodically if the tick source drifts.
+
+use core::cell::{Cell, RefCell};
+use core::fmt;
+use core::future::Future;
+use core::ops::{Add, AddAssign, Sub, SubAssign};
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+use critical_section::Mutex;
+
+use crate::{Duration, Instant, TICK_HZ, Timer};
+
+const SECS_PER_DAY: u64 = 86_400;
+/// Seconds since the epoch at 9999-12-31 23:59:59, the last supported second.
+const MAX_SECS: u64 = days_from_civil(9999, 12, 31) * SECS_PER_DAY + SECS_PER_DAY - 1;
+
+/// Ticks since the Unix epoch at `Instant::MIN`, if synchronized.
+static BOOT_TIME: Mutex<Cell<Option<u64>>> = Mutex::new(Cell::new(None));
+static UTC_OFFSET: Mutex<Cell<UtcOffset>> = Mutex::new(Cell::new(UtcOffset::UTC));
+static WAKERS: Mutex<RefCell<WakerSet>> = Mutex::new(RefCell::new(WakerSet::new()));
+
+/// A point in time in UTC, as a tick count since the Unix epoch (1970-01-01 00:00:00 UTC).
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SystemTime {
+    ticks: u64,
+}
+
+impl SystemTime {
+    /// The Unix epoch, 1970-01-01 00:00:00 UTC.
+    pub const UNIX_EPOCH: SystemTime = SystemTime { ticks: 0 };
+
+    /// Returns the current wall-clock time, or `None` if the wall clock was never synchronized.
+    pub fn now() -> Option<SystemTime> {
+        SystemTime::from_instant(Instant::now())
+    }
+
+    /// Converts an [`Instant`] to wall-clock time, using the current synchronization.
+    ///
+    /// Returns `None` if the wall clock was never synchronized.
+    pub fn from_instant(instant: Instant) -> Option<SystemTime> {
+        let boot = critical_section::with(|cs| BOOT_TIME.borrow(cs).get())?;
+        Some(SystemTime {
+            ticks: boot.saturating_add(instant.as_ticks()),
+        })
+    }
+
+    /// Converts this wall-clock time to an [`Instant`], using the current synchronization.
+    ///
+    /// Returns `None` if the wall clock was never synchronized, or if this time is before boot.
+    pub fn to_instant(&self) -> Option<Instant> {
+        let boot = critical_section::with(|cs| BOOT_TIME.borrow(cs).get())?;
+        Some(Instant::from_ticks(self.ticks.checked_sub(boot)?))
+    }
+
+    /// Create a `SystemTime` from a tick count since the Unix epoch.
+    pub const fn from_ticks(ticks: u64) -> Self {
+        Self { ticks }
+    }
+
+    /// Create a `SystemTime` from a second count since the Unix epoch.
+    pub const fn from_unix_secs(secs: u64) -> Self {
+        Self { ticks: secs * TICK_HZ }
+    }
+
+    /// Create a `SystemTime` from a millisecond count since the Unix epoch.
+    pub const fn from_unix_millis(millis: u64) -> Self {
+        Self {
+            ticks: Duration::from_millis(millis).as_ticks(),
+        }
+    }
+
+    /// Create a `SystemTime` from a microsecond count since the Unix epoch.
+    pub const fn from_unix_micros(micros: u64) -> Self {
+        Self {
+            ticks: Duration::from_micros(micros).as_ticks(),
+        }
+    }
+
+    /// Tick count since the Unix epoch.
+    pub const fn as_ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    /// Seconds since the Unix epoch, rounding down.
+    pub const fn as_unix_secs(&self) -> u64 {
+        self.ticks / TICK_HZ
+    }
+
+    /// Milliseconds since the Unix epoch, rounding down.
+    pub const fn as_unix_millis(&self) -> u64 {
+        Duration::from_ticks(self.ticks).as_millis()
+    }
+
+    /// Microseconds since the Unix epoch, rounding down.
+    pub const fn as_unix_micros(&self) -> u64 {
+        Duration::from_ticks(self.ticks).as_micros()
+    }
+
+    /// Duration between this time and an earlier one, or `None` if `earlier` is later.
+    pub const fn checked_duration_since(&self, earlier: SystemTime) -> Option<Duration> {
+        match self.ticks.checked_sub(earlier.ticks) {
+            Some(ticks) => Some(Duration::from_ticks(ticks)),
+            None => None,
+        }
+    }
+
+    /// Adds a Duration to self, or `None` on overflow.
+    pub fn checked_add(&self, duration: Duration) -> Option<SystemTime> {
+        self.ticks.checked_add(duration.as_ticks()).map(|ticks| Self { ticks })
+    }
+
+    /// Subtracts a Duration from self, or `None` on overflow.
+    pub fn checked_sub(&self, duration: Duration) -> Option<SystemTime> {
+        self.ticks.checked_sub(duration.as_ticks()).map(|ticks| Self { ticks })
+    }
+
+    /// Calendar date and time of this instant, shifted by `offset`.
+    pub fn to_datetime(&self, offset: UtcOffset) -> DateTime {
+        DateTime::from_system_time(*self, offset)
+    }
+}
+
+impl Add<Duration> for SystemTime {
+    type Output = SystemTime;
+
+    /// Computes `SystemTime + Duration`.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if the computed time overflows.
+    fn add(self, other: Duration) -> SystemTime {
+        self.checked_add(other)
+            .expect("overflow when adding duration to system time")
+    }
+}
+
+impl AddAssign<Duration> for SystemTime {
+    fn add_assign(&mut self, other: Duration) {
+        *self = *self + other;
+    }
+}
+
+impl Sub<Duration> for SystemTime {
+    type Output = SystemTime;
+
+    /// Computes `SystemTime - Duration`.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if the computed time overflows.
+    fn sub(self, other: Duration) -> SystemTime {
+        self.checked_sub(other)
+            .expect("overflow when subtracting duration from system time")
+    }
+}
+
+impl SubAssign<Duration> for SystemTime {
+    fn sub_assign(&mut self, other: Duration) {
+        *self = *self - other;
+    }
+}
+
+impl Sub<SystemTime> for SystemTime {
+    type Output = Duration;
+
+    /// Computes `SystemTime - SystemTime`.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if `other` is later than `self`.
+    fn sub(self, other: SystemTime) -> Duration {
+        self.checked_duration_since(other)
+            .expect("overflow when subtracting system times")
+    }
+}
+
+impl fmt::Display for SystemTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.to_datetime(UtcOffset::UTC), f)
+    }
+}
+
+/// Offset of a local time zone from UTC.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct UtcOffset {
+    seconds: i32,
+}
+
+impl UtcOffset {
+    /// The UTC time zone itself.
+    pub const UTC: UtcOffset = UtcOffset { seconds: 0 };
+
+    /// Create an offset from a number of seconds east of UTC.
+    ///
+    /// Returns `None` if the offset is not strictly within ±24 hours.
+    pub const fn from_seconds(seconds: i32) -> Option<Self> {
+        if seconds.unsigned_abs() < SECS_PER_DAY as u32 {
+            Some(Self { seconds })
+        } else {
+            None
+        }
+    }
+
+    /// Create an offset from hours and minutes east of UTC.
+    ///
+    /// Both must have the same sign, e.g. `from_hours_minutes(-3, -30)` for UTC−03:30.
+    pub const fn from_hours_minutes(hours: i8, minutes: i8) -> Option<Self> {
+        if minutes <= -60 || minutes >= 60 || (hours > 0 && minutes < 0) || (hours < 0 && minutes > 0) {
+            return None;
+        }
+        Self::from_seconds(hours as i32 * 3600 + minutes as i32 * 60)
+    }
+
+    /// Seconds east of UTC.
+    pub const fn as_seconds(&self) -> i32 {
+        self.seconds
+    }
+}
+
+impl fmt::Display for UtcOffset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.seconds < 0 { '-' } else { '+' };
+        let abs = self.seconds.unsigned_abs();
+        write!(f, "{}{:02}:{:02}", sign, abs / 3600, abs / 60 % 60)
+    }
+}
+
+/// Day of the week.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[allow(missing_docs)]
+pub enum DayOfWeek {
+    Monday,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+    Saturday,
+    Sunday,
+}
+
+/// Error returned when creating a [`DateTime`] from invalid fields.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DateTimeError {
+    /// The year is before 1970 or after 9999.
+    InvalidYear,
+    /// The month is not in `1..=12`.
+    InvalidMonth,
+    /// The day does not exist in the given month.
+    InvalidDay,
+    /// The hour is not in `0..=23`.
+    InvalidHour,
+    /// The minute is not in `0..=59`.
+    InvalidMinute,
+    /// The second is not in `0..=59`.
+    InvalidSecond,
+    /// The microsecond is not in `0..=999_999`.
+    InvalidMicrosecond,
+}
+
+impl fmt::Display for DateTimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self {
+            DateTimeError::InvalidYear => "year out of range",
+            DateTimeError::InvalidMonth => "month out of range",
+            DateTimeError::InvalidDay => "day out of range",
+            DateTimeError::InvalidHour => "hour out of range",
+            DateTimeError::InvalidMinute => "minute out of range",
+            DateTimeError::InvalidSecond => "second out of range",
+            DateTimeError::InvalidMicrosecond => "microsecond out of range",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl core::error::Error for DateTimeError {}
+
+/// A calendar date and time of day, at a given offset from UTC.
+///
+/// Uses the proleptic Gregorian calendar. Years from 1970 to 9999 are supported.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DateTime {
+    year: u16,
+    month: u8,
+    day: u8,
+    hour: u8,
+    minute: u8,
+    second: u8,
+    microsecond: u32,
+    offset: UtcOffset,
+}
+
+impl DateTime {
+    /// Create a new `DateTime` from its fields, in UTC.
+    pub const fn new(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> Result<Self, DateTimeError> {
+        if year < 1970 || year > 9999 {
+            return Err(DateTimeError::InvalidYear);
+        }
+        if month < 1 || month > 12 {
+            return Err(DateTimeError::InvalidMonth);
+        }
+        if day < 1 || day > days_in_month(year, month) {
+            return Err(DateTimeError::InvalidDay);
+        }
+        if hour > 23 {
+            return Err(DateTimeError::InvalidHour);
+        }
+        if minute > 59 {
+            return Err(DateTimeError::InvalidMinute);
+        }
+        if second > 59 {
+            return Err(DateTimeError::InvalidSecond);
+        }
+        Ok(Self {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+            microsecond: 0,
+            offset: UtcOffset::UTC,
+        })
+    }
+
+    /// Set the sub-second part of this `DateTime`.
+    pub const fn with_microsecond(mut self, microsecond: u32) -> Result<Self, DateTimeError> {
+        if microsecond > 999_999 {
+            return Err(DateTimeError::InvalidMicrosecond);
+        }
+        self.microsecond = microsecond;
+        Ok(self)
+    }
+
+    /// Interpret the fields of this `DateTime` as local time at `offset`.
+    ///
+    /// This does not change the fields, only the point in time they represent.
+    pub const fn assume_offset(mut self, offset: UtcOffset) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    /// Convert to another offset, keeping the same point in time.
+    pub fn to_offset(&self, offset: UtcOffset) -> Self {
+        Self::from_system_time(self.to_system_time(), offset)
+    }
+
+    /// The current date and time in UTC, or `None` if the wall clock was never synchronized.
+    pub fn now_utc() -> Option<Self> {
+        Some(Self::from_system_time(SystemTime::now()?, UtcOffset::UTC))
+    }
+
+    /// The current date and time at the offset set with [`set_utc_offset()`], or `None` if the
+    /// wall clock was never synchronized.
+    pub fn now_local() -> Option<Self> {
+        Some(Self::from_system_time(SystemTime::now()?, utc_offset()))
+    }
+
+    /// Calendar date and time of `time`, shifted by `offset`.
+    ///
+    /// Dates before 1970 local time (possible with negative offsets right after the epoch)
+    /// saturate to 1970-01-01 00:00:00, and dates after 9999 saturate to the last tick of
+    /// 9999-12-31 23:59:59.
+    pub fn from_system_time(time: SystemTime, offset: UtcOffset) -> Self {
+        let mut subsec_ticks = time.ticks % TICK_HZ;
+        let mut secs = (time.ticks / TICK_HZ).saturating_add_signed(offset.seconds as i64);
+        if secs > MAX_SECS {
+            secs = MAX_SECS;
+            subsec_ticks = TICK_HZ - 1;
+        }
+
+        let days = secs / SECS_PER_DAY;
+        let secs_of_day = secs % SECS_PER_DAY;
+        let (year, month, day) = civil_from_days(days);
+
+        Self {
+            year: year as u16,
+            month,
+            day,
+            hour: (secs_of_day / 3600) as u8,
+            minute: (secs_of_day / 60 % 60) as u8,
+            second: (secs_of_day % 60) as u8,
+            microsecond: Duration::from_ticks(subsec_ticks).as_micros() as u32,
+            offset,
+        }
+    }
+
+    /// The point in time this `DateTime` represents.
+    pub fn to_system_time(&self) -> SystemTime {
+        let days = days_from_civil(self.year, self.month, self.day);
+        let secs = days * SECS_PER_DAY + self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64;
+        let secs = secs.saturating_add_signed(-(self.offset.seconds as i64));
+        SystemTime::from_unix_secs(secs) + Duration::from_micros(self.microsecond as u64)
+    }
+
+    /// Year, e.g. 2024.
+    pub const fn year(&self) -> u16 {
+        self.year
+    }
+
+    /// Month, from 1 (January) to 12 (December).
+    pub const fn month(&self) -> u8 {
+        self.month
+    }
+
+    /// Day of the month, starting at 1.
+    pub const fn day(&self) -> u8 {
+        self.day
+    }
+
+    /// Hour, from 0 to 23.
+    pub const fn hour(&self) -> u8 {
+        self.hour
+    }
+
+    /// Minute, from 0 to 59.
+    pub const fn minute(&self) -> u8 {
+        self.minute
+    }
+
+    /// Second, from 0 to 59.
+    pub const fn second(&self) -> u8 {
+        self.second
+    }
+
+    /// Microsecond, from 0 to 999999.
+    pub const fn microsecond(&self) -> u32 {
+        self.microsecond
+    }
+
+    /// Offset from UTC the fields are expressed in.
+    pub const fn offset(&self) -> UtcOffset {
+        self.offset
+    }
+
+    /// Day of the week.
+    pub const fn day_of_week(&self) -> DayOfWeek {
+        // 1970-01-01 was a Thursday.
+        match (days_from_civil(self.year, self.month, self.day) + 3) % 7 {
+            0 => DayOfWeek::Monday,
+            1 => DayOfWeek::Tuesday,
+            2 => DayOfWeek::Wednesday,
+            3 => DayOfWeek::Thursday,
+            4 => DayOfWeek::Friday,
+            5 => DayOfWeek::Saturday,
+            _ => DayOfWeek::Sunday,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    /// Formats as RFC 3339, e.g. `2024-02-29T13:05:09.250000+01:00`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second, self.microsecond
+        )?;
+        if self.offset == UtcOffset::UTC {
+            f.write_str("Z")
+        } else {
+            write!(f, "{}", self.offset)
+        }
+    }
+}
+
+const fn is_leap_year(year: u16) -> bool {
+    (year.is_multiple_of(4) && !year.is_multiple_of(100)) || year.is_multiple_of(400)
+}
+
+const fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days since 1970-01-01 of a date, for years >= 1970.
+///
+/// See <http://howardhinnant.github.io/date_algorithms.html#days_from_civil>.
+const fn days_from_civil(year: u16, month: u8, day: u8) -> u64 {
+    let y = if month <= 2 { year as u64 - 1 } else { year as u64 };
+    let era = y / 400;
+    let yoe = y - era * 400;
+    let m = month as u64;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + day as u64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Date of a number of days since 1970-01-01.
+///
+/// See <http://howardhinnant.github.io/date_algorithms.html#civil_from_days>.
+const fn civil_from_days(days: u64) -> (u64, u8, u8) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Set the wall-clock time at a given [`Instant`].
+///
+/// `time` is the UTC time it was at `at`. Taking the instant separately allows compensating
+/// for the latency of the source, for example by passing the instant an SNTP response or a
+/// GNSS PPS pulse was received.
+///
+/// This can be called at any time to correct the wall clock. Pending
+/// [`Timer::at_wall_clock()`](crate::Timer::at_wall_clock) timers are woken to recompute
+/// their deadline.
+pub fn synchronize(time: SystemTime, at: Instant) {
+    // If the time is before `at` ticks since the epoch, only possible with a badly wrong
+    // source or a tick rate so high the counter wraps, pin the epoch to boot.
+    let boot = time.ticks.saturating_sub(at.as_ticks());
+    critical_section::with(|cs| {
+        BOOT_TIME.borrow(cs).set(Some(boot));
+        WAKERS.borrow_ref_mut(cs).wake();
+    });
+}
+
+/// Forget the wall-clock time, as if [`synchronize()`] had never been called.
+pub fn desynchronize() {
+    critical_section::with(|cs| {
+        BOOT_TIME.borrow(cs).set(None);
+        WAKERS.borrow_ref_mut(cs).wake();
+    });
+}
+
+/// Returns whether the wall clock has been synchronized.
+pub fn is_synchronized() -> bool {
+    critical_section::with(|cs| BOOT_TIME.borrow(cs).get().is_some())
+}
+
+/// Set the offset of the local time zone, used by [`DateTime::now_local()`].
+///
+/// This does not affect [`SystemTime`], which is always UTC.
+pub fn set_utc_offset(offset: UtcOffset) {
+    critical_section::with(|cs| UTC_OFFSET.borrow(cs).set(offset));
+}
+
+/// Get the offset of the local time zone set with [`set_utc_offset()`].
+///
+/// Defaults to [`UtcOffset::UTC`].
+pub fn utc_offset() -> UtcOffset {
+    critical_section::with(|cs| UTC_OFFSET.borrow(cs).get())
+}
+
+/// A source of absolute time, like an RTC, an SNTP client or a GNSS receiver.
+pub trait WallClockSource {
+    /// Error type of the source.
+    type Error;
+
+    /// Read the current UTC time from the source.
+    async fn now(&mut self) -> Result<SystemTime, Self::Error>;
+}
+
+/// Synchronize the wall clock from a [`WallClockSource`].
+///
+/// The read is assumed to happen halfway through the call to [`WallClockSource::now()`],
+/// which compensates for symmetric latency like a network round trip.
+///
+/// Returns the time that was read.
+pub async fn synchronize_from<S: WallClockSource>(source: &mut S) -> Result<SystemTime, S::Error> {
+    let start = Instant::now();
+    let time = source.now().await?;
+    let end = Instant::now();
+
+    let at = start + (end - start) / 2;
+    synchronize(time, at);
+    Ok(time)
+}
+
+/// Number of wall-clock timers woken immediately when the clock is corrected.
+///
+/// Timers pending at the same time beyond this number re-check the clock every
+/// [`RECHECK_INTERVAL`] instead.
+pub const WALL_CLOCK_TIMERS: usize = 8;
+
+/// Interval at which wall-clock timers beyond [`WALL_CLOCK_TIMERS`] re-check the clock.
+pub const RECHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Wakers of pending wall-clock timers, woken when the clock is corrected.
+///
+/// Each timer takes its own slot, tagged with an id so that it can tell whether it's still
+/// registered after the set was woken and its slot reused.
+struct WakerSet {
+    wakers: [Option<(u32, Waker)>; WALL_CLOCK_TIMERS],
+    next_id: u32,
+}
+
+/// Slot index and id of a registered timer.
+type Registration = (usize, u32);
+
+impl WakerSet {
+    const fn new() -> Self {
+        Self {
+            wakers: [const { None }; WALL_CLOCK_TIMERS],
+            next_id: 0,
+        }
+    }
+
+    /// Register or update the waker of a timer, returning `None` if the set is full.
+    fn register(&mut self, registration: Option<Registration>, waker: &Waker) -> Option<Registration> {
+        if let Some((index, id)) = registration
+            && let Some((slot_id, slot_waker)) = &mut self.wakers[index]
+            && *slot_id == id
+        {
+            if !slot_waker.will_wake(waker) {
+                *slot_waker = waker.clone();
+            }
+            return registration;
+        }
+
+        let index = self.wakers.iter().position(|w| w.is_none())?;
+        let id = self.next_id;
+        self.next_id = id.wrapping_add(1);
+        self.wakers[index] = Some((id, waker.clone()));
+        Some((index, id))
+    }
+
+    fn unregister(&mut self, registration: Option<Registration>) {
+        if let Some((index, id)) = registration
+            && matches!(self.wakers[index], Some((slot_id, _)) if slot_id == id)
+        {
+            self.wakers[index] = None;
+        }
+    }
+
+    fn wake(&mut self) {
+        for waker in self.wakers.iter_mut() {
+            if let Some((_, waker)) = waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// A future that completes at a given wall-clock time.
+///
+/// Created with [`Timer::at_wall_clock()`](crate::Timer::at_wall_clock).
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct WallClockTimer {
+    at: SystemTime,
+    registration: Option<Registration>,
+    timer: Option<(Instant, Timer)>,
+}
+
+impl WallClockTimer {
+    pub(crate) fn new(at: SystemTime) -> Self {
+        Self {
+            at,
+            registration: None,
+            timer: None,
+        }
+    }
+
+    fn unregister(&mut self) {
+        if self.registration.is_some() {
+            critical_section::with(|cs| WAKERS.borrow_ref_mut(cs).unregister(self.registration.take()));
+        }
+    }
+}
+
+impl Unpin for WallClockTimer {}
+
+impl Drop for WallClockTimer {
+    fn drop(&mut self) {
+        self.unregister();
+    }
+}
+
+impl Future for WallClockTimer {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let boot = critical_section::with(|cs| {
+            this.registration = WAKERS.borrow_ref_mut(cs).register(this.registration, cx.waker());
+            BOOT_TIME.borrow(cs).get()
+        });
+
+        let expires_at = boot.map(|boot| Instant::from_ticks(this.at.ticks.saturating_sub(boot)));
+        let wake_at = match (expires_at, this.registration) {
+            (Some(expires_at), Some(_)) => expires_at,
+            // Not synchronized yet: wait until we are.
+            (None, Some(_)) => {
+                this.timer = None;
+                return Poll::Pending;
+            }
+            // The waker set is full, so corrections won't wake us: re-check periodically.
+            (expires_at, None) => {
+                let now = Instant::now();
+                let recheck = match &this.timer {
+                    Some((at, _)) if *at > now && *at <= now + RECHECK_INTERVAL => *at,
+                    _ => now + RECHECK_INTERVAL,
+                };
+                expires_at.map_or(recheck, |expires_at| expires_at.min(recheck))
+            }
+        };
+
+        let (_, timer) = match &mut this.timer {
+            // Armed, and the clock wasn't corrected since.
+            Some(armed) if armed.0 == wake_at => armed,
+            _ => this.timer.insert((wake_at, Timer::at(wake_at))),
+        };
+        if Pin::new(timer).poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+
+        if Some(wake_at) == expires_at {
+            this.unregister();
+            Poll::Ready(())
+        } else {
+            // Time to re-check the clock.
+            this.timer = None;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_civil_roundtrip() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(days_from_civil(2000, 3, 1), 11_017);
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+
+        for days in 0..200_000 {
+            let (y, m, d) = civil_from_days(days);
+            assert_eq!(days_from_civil(y as u16, m, d), days);
+        }
+    }
+
+    #[test]
+    fn test_datetime_validation() {
+        assert!(DateTime::new(2024, 2, 29, 0, 0, 0).is_ok());
+        assert_eq!(DateTime::new(2023, 2, 29, 0, 0, 0), Err(DateTimeError::InvalidDay));
+        assert_eq!(DateTime::new(1900, 1, 1, 0, 0, 0), Err(DateTimeError::InvalidYear));
+        assert_eq!(DateTime::new(2024, 13, 1, 0, 0, 0), Err(DateTimeError::InvalidMonth));
+        assert_eq!(DateTime::new(2024, 1, 1, 24, 0, 0), Err(DateTimeError::InvalidHour));
+        assert_eq!(DateTime::new(2024, 1, 1, 0, 60, 0), Err(DateTimeError::InvalidMinute));
+        assert_eq!(DateTime::new(2024, 1, 1, 0, 0, 60), Err(DateTimeError::InvalidSecond));
+        assert_eq!(
+            DateTime::new(2024, 1, 1, 0, 0, 0).unwrap().with_microsecond(1_000_000),
+            Err(DateTimeError::InvalidMicrosecond)
+        );
+    }
+
+    #[test]
+    fn test_datetime_conversion() {
+        let time = SystemTime::from_unix_secs(1_709_211_909);
+        let dt = time.to_datetime(UtcOffset::UTC);
+        assert_eq!(dt, DateTime::new(2024, 2, 29, 13, 5, 9).unwrap());
+        assert_eq!(dt.day_of_week(), DayOfWeek::Thursday);
+        assert_eq!(dt.to_system_time(), time);
+
+        let offset = UtcOffset::from_hours_minutes(-3, -30).unwrap();
+        let local = time.to_datetime(offset);
+        assert_eq!(
+            local,
+            DateTime::new(2024, 2, 29, 9, 35, 9).unwrap().assume_offset(offset)
+        );
+        assert_eq!(local.to_system_time(), time);
+        assert_eq!(local.to_offset(UtcOffset::UTC), dt);
+
+        assert_eq!(
+            DateTime::new(1970, 1, 1, 0, 0, 0).unwrap().day_of_week(),
+            DayOfWeek::Thursday
+        );
+        assert_eq!(
+            DateTime::new(2000, 1, 3, 0, 0, 0).unwrap().day_of_week(),
+            DayOfWeek::Monday
+        );
+    }
+
+    #[test]
+    fn test_datetime_saturation() {
+        let max = DateTime::new(9999, 12, 31, 23, 59, 59).unwrap();
+        let dt = SystemTime::from_ticks(u64::MAX).to_datetime(UtcOffset::UTC);
+        assert_eq!(dt.with_microsecond(0).unwrap(), max);
+        assert_eq!(dt.microsecond(), Duration::from_ticks(TICK_HZ - 1).as_micros() as u32);
+        assert_eq!(SystemTime::from_unix_secs(MAX_SECS).to_datetime(UtcOffset::UTC), max);
+        assert_eq!(SystemTime::from_unix_secs(MAX_SECS + 1).to_datetime(UtcOffset::UTC), dt);
+    }
+
+    #[test]
+    fn test_utc_offset() {
+        assert_eq!(UtcOffset::from_hours_minutes(5, 45).unwrap().as_seconds(), 20_700);
+        assert_eq!(UtcOffset::from_hours_minutes(-1, 30), None);
+        assert_eq!(UtcOffset::from_hours_minutes(0, 60), None);
+        assert_eq!(UtcOffset::from_seconds(86_400), None);
+    }
+
+    #[cfg(feature = "mock-driver")]
+    #[test]
+    #[serial_test::serial]
+    fn test_synchronize() {
+        use crate::MockDriver;
+
+        let driver = MockDriver::get();
+        driver.reset();
+        desynchronize();
+
+        let mut timer = Timer::at_wall_clock(SystemTime::from_unix_secs(1_000_010));
+        let mut cx = Context::from_waker(Waker::noop());
+        assert!(SystemTime::now().is_none());
+        assert!(Pin::new(&mut timer).poll(&mut cx).is_pending());
+
+        driver.advance(Duration::from_secs(5));
+        synchronize(SystemTime::from_unix_secs(1_000_000), Instant::now());
+        assert_eq!(SystemTime::now(), Some(SystemTime::from_unix_secs(1_000_000)));
+        assert!(Pin::new(&mut timer).poll(&mut cx).is_pending());
+
+        driver.advance(Duration::from_secs(5));
+        assert_eq!(SystemTime::now(), Some(SystemTime::from_unix_secs(1_000_005)));
+        assert!(Pin::new(&mut timer).poll(&mut cx).is_pending());
+
+        // The clock was 4 seconds late, the timer should now be 1 second away.
+        synchronize(SystemTime::from_unix_secs(1_000_009), Instant::now());
+        assert!(Pin::new(&mut timer).poll(&mut cx).is_pending());
+        driver.advance(Duration::from_secs(1));
+        assert!(Pin::new(&mut timer).poll(&mut cx).is_ready());
+
+        desynchronize();
+    }
+
+    #[cfg(feature = "mock-driver")]
+    #[test]
+    #[serial_test::serial]
+    fn test_many_timers() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::task::Wake;
+
+        use crate::MockDriver;
+
+        struct CountingWaker(AtomicUsize);
+        impl Wake for CountingWaker {
+            fn wake(self: Arc<Self>) {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let driver = MockDriver::get();
+        driver.reset();
+        desynchronize();
+        synchronize(SystemTime::from_unix_secs(1_000_000), Instant::now());
+
+        let counter = Arc::new(CountingWaker(AtomicUsize::new(0)));
+        let waker = Waker::from(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let mut timers: std::vec::Vec<_> = (0..WALL_CLOCK_TIMERS + 2)
+            .map(|_| Timer::at_wall_clock(SystemTime::from_unix_secs(1_000_010)))
+            .collect();
+        for _ in 0..3 {
+            for timer in timers.iter_mut() {
+                assert!(Pin::new(timer).poll(&mut cx).is_pending());
+            }
+        }
+        // Polling more timers than there are slots doesn't make them wake each other.
+        assert_eq!(counter.0.load(Ordering::Relaxed), 0);
+
+        // Timers beyond the capacity still notice corrections, after the re-check interval.
+        synchronize(SystemTime::from_unix_secs(1_000_009), Instant::now());
+        assert_eq!(counter.0.load(Ordering::Relaxed), WALL_CLOCK_TIMERS);
+        driver.advance(Duration::from_secs(1));
+        for timer in timers.iter_mut() {
+            while Pin::new(&mut *timer).poll(&mut cx).is_pending() {
+                driver.advance(RECHECK_INTERVAL);
+            }
+        }
+
+        // Completed and dropped timers free their slots.
+        drop(timers);
+        critical_section::with(|cs| assert!(WAKERS.borrow_ref(cs).wakers.iter().all(|w| w.is_none())));
+
+        desynchronize();
+    }
+}
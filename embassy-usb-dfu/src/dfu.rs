@@ -1,21 +1,32 @@
 //! DFU bootloader part of DFU logic
-use embassy_boot::{AlignedBuffer, BlockingFirmwareUpdater, FirmwareUpdaterError};
+use embassy_boot::header::HEADER_LEN;
+use embassy_boot::{AlignedBuffer, BlockingFirmwareUpdater, ERASE_VALUE, FirmwareUpdaterError, ImageHeader};
 use embassy_usb::class::dfu::consts::{DfuAttributes, Status};
 /// Re-export DfuState from embassy-usb for convenience.
 pub use embassy_usb::class::dfu::dfu_mode::DfuState as UsbDfuState;
 use embassy_usb::class::dfu::dfu_mode::{self, DfuState};
 use embassy_usb::driver::Driver;
 use embassy_usb::{Builder, FunctionBuilder};
-use embedded_storage::nor_flash::{NorFlash, NorFlashErrorKind};
+use embedded_storage::nor_flash::{NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash};
 
 use crate::Reset;
 
 /// Internal handler for USB DFU firmware updates.
 ///
 /// This implements the `embassy_usb::class::dfu::dfu_mode::Handler` trait,
-/// providing the firmware write logic using `BlockingFirmwareUpdater`.
-pub struct FirmwareHandler<'d, DFU: NorFlash, STATE: NorFlash, RST: Reset, const BLOCK_SIZE: usize> {
+/// providing the firmware write logic using `BlockingFirmwareUpdater`, and
+/// optionally uploads of the active firmware.
+pub struct FirmwareHandler<
+    'd,
+    DFU: NorFlash,
+    STATE: NorFlash,
+    RST: Reset,
+    const BLOCK_SIZE: usize,
+    ACTIVE: ReadNorFlash = DFU,
+> {
     updater: BlockingFirmwareUpdater<'d, DFU, STATE>,
+    active: Option<ACTIVE>,
+    upload_len: usize,
     offset: usize,
     buf: AlignedBuffer<BLOCK_SIZE>,
     reset: RST,
@@ -35,6 +46,8 @@ impl<'d, DFU: NorFlash, STATE: NorFlash, RST: Reset, const BLOCK_SIZE: usize>
     ) -> Self {
         Self {
             updater,
+            active: None,
+            upload_len: 0,
             offset: 0,
             buf: AlignedBuffer([0; BLOCK_SIZE]),
             reset,
@@ -45,20 +58,87 @@ impl<'d, DFU: NorFlash, STATE: NorFlash, RST: Reset, const BLOCK_SIZE: usize>
     }
 }
 
-fn firmware_error_to_status(e: FirmwareUpdaterError) -> Status {
+impl<'d, DFU: NorFlash, STATE: NorFlash, RST: Reset, const BLOCK_SIZE: usize, ACTIVE: ReadNorFlash>
+    FirmwareHandler<'d, DFU, STATE, RST, BLOCK_SIZE, ACTIVE>
+{
+    /// Create a new firmware handler, which uploads the firmware in the `active` partition.
+    pub fn new_with_upload(
+        updater: BlockingFirmwareUpdater<'d, DFU, STATE>,
+        active: ACTIVE,
+        reset: RST,
+        #[cfg(feature = "_verify")] public_key: &'static [u8; 32],
+    ) -> Self {
+        Self {
+            updater,
+            active: Some(active),
+            upload_len: 0,
+            offset: 0,
+            buf: AlignedBuffer([0; BLOCK_SIZE]),
+            reset,
+
+            #[cfg(feature = "_verify")]
+            public_key,
+        }
+    }
+}
+
+pub(crate) fn flash_error_to_status(e: NorFlashErrorKind) -> Status {
     match e {
-        FirmwareUpdaterError::Flash(e) => match e {
-            NorFlashErrorKind::NotAligned => Status::ErrWrite,
-            NorFlashErrorKind::OutOfBounds => Status::ErrAddress,
-            _ => Status::ErrUnknown,
-        },
-        FirmwareUpdaterError::Signature(_) => Status::ErrVerify,
+        NorFlashErrorKind::NotAligned => Status::ErrWrite,
+        NorFlashErrorKind::OutOfBounds => Status::ErrAddress,
+        _ => Status::ErrUnknown,
+    }
+}
+
+pub(crate) fn firmware_error_to_status(e: FirmwareUpdaterError) -> Status {
+    match e {
+        FirmwareUpdaterError::Flash(e) => flash_error_to_status(e),
+        FirmwareUpdaterError::Signature(_) | FirmwareUpdaterError::Encryption => Status::ErrVerify,
+        FirmwareUpdaterError::Patch(_) | FirmwareUpdaterError::Header(_) => Status::ErrFile,
         FirmwareUpdaterError::BadState => Status::ErrUnknown,
     }
 }
 
-impl<'d, DFU: NorFlash, STATE: NorFlash, RST: Reset, const BLOCK_SIZE: usize> dfu_mode::Handler
-    for FirmwareHandler<'d, DFU, STATE, RST, BLOCK_SIZE>
+/// Verify the `len` bytes written to the DFU partition and mark them to be swapped in.
+///
+/// With verification, the last 64 bytes are the signature of the update.
+pub(crate) fn mark_updated<DFU: NorFlash, STATE: NorFlash>(
+    updater: &mut BlockingFirmwareUpdater<'_, DFU, STATE>,
+    len: usize,
+    #[cfg(feature = "_verify")] public_key: &[u8; 32],
+) -> Result<(), Status> {
+    #[cfg(feature = "_verify")]
+    let update_res: Result<(), FirmwareUpdaterError> = {
+        const SIGNATURE_LEN: usize = 64;
+
+        let mut signature = [0; SIGNATURE_LEN];
+        let update_len = len.checked_sub(SIGNATURE_LEN).ok_or(Status::ErrNotDone)? as u32;
+
+        updater
+            .read_dfu(update_len, &mut signature)
+            .and_then(|_| updater.verify_and_mark_updated(public_key, &signature, update_len))
+    };
+
+    #[cfg(not(feature = "_verify"))]
+    let update_res = {
+        let _ = len;
+        updater.mark_updated()
+    };
+
+    match update_res {
+        Ok(_) => {
+            info!("Update complete");
+            Ok(())
+        }
+        Err(e) => {
+            error!("Error completing update: {}", e);
+            Err(firmware_error_to_status(e))
+        }
+    }
+}
+
+impl<'d, DFU: NorFlash, STATE: NorFlash, RST: Reset, const BLOCK_SIZE: usize, ACTIVE: ReadNorFlash> dfu_mode::Handler
+    for FirmwareHandler<'d, DFU, STATE, RST, BLOCK_SIZE, ACTIVE>
 {
     fn start(&mut self) -> Result<(), Status> {
         info!("Download starting");
@@ -90,33 +170,38 @@ impl<'d, DFU: NorFlash, STATE: NorFlash, RST: Reset, const BLOCK_SIZE: usize> df
 
     fn finish(&mut self) -> Result<(), Status> {
         debug!("Receiving final transfer");
+        mark_updated(
+            &mut self.updater,
+            self.offset,
+            #[cfg(feature = "_verify")]
+            self.public_key,
+        )
+    }
 
-        #[cfg(feature = "_verify")]
-        let update_res: Result<(), FirmwareUpdaterError> = {
-            const SIGNATURE_LEN: usize = 64;
-
-            let mut signature = [0; SIGNATURE_LEN];
-            let update_len = (self.offset - SIGNATURE_LEN) as u32;
-
-            self.updater.read_dfu(update_len, &mut signature).and_then(|_| {
-                self.updater
-                    .verify_and_mark_updated(self.public_key, &signature, update_len)
-            })
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<usize, Status> {
+        let Some(active) = self.active.as_mut() else {
+            error!("Upload not supported");
+            return Err(Status::ErrStalledPkt);
         };
 
-        #[cfg(not(feature = "_verify"))]
-        let update_res = self.updater.mark_updated();
-
-        match update_res {
-            Ok(_) => {
-                info!("Update complete");
-                Ok(())
-            }
-            Err(e) => {
-                error!("Error completing update: {}", e);
-                Err(firmware_error_to_status(e))
-            }
+        let into_status = |e: ACTIVE::Error| {
+            error!("Error reading firmware");
+            flash_error_to_status(e.kind())
+        };
+        if offset == 0 {
+            self.upload_len = firmware_len(active, buf).map_err(into_status)?;
+            info!("Uploading {} bytes of firmware", self.upload_len);
         }
+
+        let len = buf.len().min(self.upload_len.saturating_sub(offset));
+        // The end of the firmware may not be aligned to the read size.
+        let read_len = len
+            .next_multiple_of(ACTIVE::READ_SIZE)
+            .min(buf.len())
+            .min(active.capacity().saturating_sub(offset));
+        debug!("Reading {} bytes at {}", len, offset);
+        active.read(offset as u32, &mut buf[..read_len]).map_err(into_status)?;
+        Ok(len)
     }
 
     fn system_reset(&mut self) {
@@ -124,9 +209,34 @@ impl<'d, DFU: NorFlash, STATE: NorFlash, RST: Reset, const BLOCK_SIZE: usize> df
     }
 }
 
+/// Length of the firmware in `active`: the image described by its header if it starts with one,
+/// or up to the last byte that isn't erased otherwise. `buf` is used to read the flash.
+fn firmware_len<F: ReadNorFlash>(active: &mut F, buf: &mut [u8]) -> Result<usize, F::Error> {
+    let mut header = [0; HEADER_LEN];
+    if active.capacity() >= HEADER_LEN {
+        active.read(0, &mut header)?;
+        if let Ok(header) = ImageHeader::parse(&header, active.capacity()) {
+            return Ok(header.header_len as usize + header.image_len as usize);
+        }
+    }
+
+    let chunk_len = buf.len() - buf.len() % F::READ_SIZE;
+    let mut end = active.capacity();
+    while end > 0 {
+        let start = end.saturating_sub(chunk_len);
+        let chunk = &mut buf[..end - start];
+        active.read(start as u32, chunk)?;
+        if let Some(last) = chunk.iter().rposition(|&b| b != ERASE_VALUE) {
+            return Ok(start + last + 1);
+        }
+        end = start;
+    }
+    Ok(0)
+}
+
 /// Convenience type alias for the DFU state with firmware handler.
-pub type State<'d, DFU, STATE, RST, const BLOCK_SIZE: usize> =
-    DfuState<FirmwareHandler<'d, DFU, STATE, RST, BLOCK_SIZE>>;
+pub type State<'d, DFU, STATE, RST, const BLOCK_SIZE: usize, ACTIVE = DFU> =
+    DfuState<FirmwareHandler<'d, DFU, STATE, RST, BLOCK_SIZE, ACTIVE>>;
 
 /// Create a new DFU state instance.
 ///
@@ -147,16 +257,56 @@ pub fn new_state<'d, DFU: NorFlash, STATE: NorFlash, RST: Reset, const BLOCK_SIZ
     DfuState::new(handler, attrs)
 }
 
+/// Create a new DFU state instance, which uploads the firmware in the `active` partition.
+///
+/// This works like [`new_state`], uploads are enabled by setting `DfuAttributes::CAN_UPLOAD` in
+/// `attrs`. Reading back the firmware with `dfu-util -U` is then possible, so don't enable this on
+/// devices whose firmware must be kept secret.
+///
+/// Uploads stop at the end of the image if the firmware starts with an [`ImageHeader`], and after
+/// the last programmed byte of the partition otherwise.
+pub fn new_state_with_upload<
+    'd,
+    DFU: NorFlash,
+    STATE: NorFlash,
+    ACTIVE: ReadNorFlash,
+    RST: Reset,
+    const BLOCK_SIZE: usize,
+>(
+    updater: BlockingFirmwareUpdater<'d, DFU, STATE>,
+    active: ACTIVE,
+    attrs: DfuAttributes,
+    reset: RST,
+    #[cfg(feature = "_verify")] public_key: &'static [u8; 32],
+) -> State<'d, DFU, STATE, RST, BLOCK_SIZE, ACTIVE> {
+    let handler = FirmwareHandler::new_with_upload(
+        updater,
+        active,
+        reset,
+        #[cfg(feature = "_verify")]
+        public_key,
+    );
+    DfuState::new(handler, attrs)
+}
+
 /// An implementation of the USB DFU 1.1 protocol
 ///
 /// This function will add a DFU interface descriptor to the provided Builder, and register the provided Control as a handler for the USB device
-/// The handler is responsive to DFU GetState, GetStatus, Abort, and ClrStatus commands, as well as Download if configured by the user.
+/// The handler is responsive to DFU GetState, GetStatus, Abort, and ClrStatus commands, as well as Download and Upload if configured by the user.
 ///
 /// Once the host has initiated a DFU download operation, the chunks sent by the host will be written to the DFU partition.
 /// Once the final sync in the manifestation phase has been received, the handler will trigger a system reset to swap the new firmware.
-pub fn usb_dfu<'d, D: Driver<'d>, DFU: NorFlash, STATE: NorFlash, RST: Reset, const BLOCK_SIZE: usize>(
+pub fn usb_dfu<
+    'd,
+    D: Driver<'d>,
+    DFU: NorFlash,
+    STATE: NorFlash,
+    RST: Reset,
+    const BLOCK_SIZE: usize,
+    ACTIVE: ReadNorFlash,
+>(
     builder: &mut Builder<'d, D>,
-    state: &'d mut State<'d, DFU, STATE, RST, BLOCK_SIZE>,
+    state: &'d mut State<'d, DFU, STATE, RST, BLOCK_SIZE, ACTIVE>,
     func_modifier: impl Fn(&mut FunctionBuilder<'_, 'd, D>),
 ) {
     dfu_mode::usb_dfu(builder, state, BLOCK_SIZE, func_modifier);
@@ -0,0 +1,277 @@
+//! DfuSe bootloader part of DFU logic
+//!
+//! DfuSe exposes several memories on the alternate settings of one DFU interface, see
+//! `embassy_usb::class::dfu::dfuse`. Alternate setting 0 is the firmware: downloads are written to
+//! the DFU partition and swapped in after leaving DFU mode, and uploads read the active partition.
+//! The following alternate settings are [`Memory`] regions, like a configuration partition or an
+//! external flash, which are written in place.
+use embassy_boot::{AlignedBuffer, BlockingFirmwareUpdater};
+use embassy_usb::class::dfu::consts::{DfuAttributes, Status};
+use embassy_usb::class::dfu::dfuse::{self, DfuState};
+use embassy_usb::driver::Driver;
+use embassy_usb::{Builder, FunctionBuilder};
+use embedded_storage::nor_flash::{NorFlash, NorFlashError, ReadNorFlash};
+
+use crate::Reset;
+use crate::dfu::{firmware_error_to_status, flash_error_to_status, mark_updated};
+
+/// A memory exposed on a DfuSe alternate setting, next to the firmware.
+pub trait Memory {
+    /// Erase the page holding `offset`.
+    fn erase(&mut self, offset: u32) -> Result<(), Status>;
+
+    /// Write `data` at `offset`, in an erased page.
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Status>;
+
+    /// Read up to `buf.len()` bytes at `offset`, returning the number of bytes read.
+    ///
+    /// Reading fewer bytes ends the upload, at the end of the memory.
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<usize, Status>;
+}
+
+/// A [`Memory`] backed by a NOR flash.
+///
+/// Chunks are padded to the write size of the flash, using a buffer of `BLOCK_SIZE` bytes, which
+/// must be at least the transfer size of the DFU interface.
+pub struct FlashMemory<F: NorFlash, const BLOCK_SIZE: usize> {
+    flash: F,
+    buf: AlignedBuffer<BLOCK_SIZE>,
+}
+
+impl<F: NorFlash, const BLOCK_SIZE: usize> FlashMemory<F, BLOCK_SIZE> {
+    /// Create a new flash memory.
+    pub fn new(flash: F) -> Self {
+        Self {
+            flash,
+            buf: AlignedBuffer([0; BLOCK_SIZE]),
+        }
+    }
+}
+
+impl<F: NorFlash, const BLOCK_SIZE: usize> Memory for FlashMemory<F, BLOCK_SIZE> {
+    fn erase(&mut self, offset: u32) -> Result<(), Status> {
+        let page = offset - offset % F::ERASE_SIZE as u32;
+        self.flash
+            .erase(page, page + F::ERASE_SIZE as u32)
+            .map_err(|e| match flash_error_to_status(e.kind()) {
+                Status::ErrWrite => Status::ErrErase,
+                status => status,
+            })
+    }
+
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Status> {
+        let len = data.len().next_multiple_of(F::WRITE_SIZE);
+        if len > BLOCK_SIZE {
+            error!("USB data len exceeded block size");
+            return Err(Status::ErrUnknown);
+        }
+
+        let buf = &mut self.buf.as_mut()[..len];
+        buf[..data.len()].copy_from_slice(data);
+        buf[data.len()..].fill(0xFF);
+        self.flash
+            .write(offset, buf)
+            .map_err(|e| flash_error_to_status(e.kind()))
+    }
+
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<usize, Status> {
+        let len = buf.len().min(self.flash.capacity().saturating_sub(offset as usize));
+        self.flash
+            .read(offset, &mut buf[..len])
+            .map_err(|e| flash_error_to_status(e.kind()))?;
+        Ok(len)
+    }
+}
+
+/// Internal handler for USB DfuSe firmware updates.
+///
+/// This implements the `embassy_usb::class::dfu::dfuse::Handler` trait, writing the firmware using
+/// `BlockingFirmwareUpdater`, and forwarding the other alternate settings to their [`Memory`].
+pub struct DfuseHandler<'d, DFU: NorFlash, STATE: NorFlash, ACTIVE: ReadNorFlash, RST: Reset, const BLOCK_SIZE: usize> {
+    updater: BlockingFirmwareUpdater<'d, DFU, STATE>,
+    active: ACTIVE,
+    memories: &'d mut [&'d mut dyn Memory],
+    /// End of the firmware written to the DFU partition, if any.
+    written: Option<usize>,
+    buf: AlignedBuffer<BLOCK_SIZE>,
+    reset: RST,
+
+    #[cfg(feature = "_verify")]
+    public_key: &'static [u8; 32],
+}
+
+impl<'d, DFU: NorFlash, STATE: NorFlash, ACTIVE: ReadNorFlash, RST: Reset, const BLOCK_SIZE: usize>
+    DfuseHandler<'d, DFU, STATE, ACTIVE, RST, BLOCK_SIZE>
+{
+    /// Create a new DfuSe handler.
+    ///
+    /// The firmware is written with `updater` and read from `active`, and alternate setting `n`
+    /// exposes `memories[n - 1]`.
+    pub fn new(
+        updater: BlockingFirmwareUpdater<'d, DFU, STATE>,
+        active: ACTIVE,
+        memories: &'d mut [&'d mut dyn Memory],
+        reset: RST,
+        #[cfg(feature = "_verify")] public_key: &'static [u8; 32],
+    ) -> Self {
+        Self {
+            updater,
+            active,
+            memories,
+            written: None,
+            buf: AlignedBuffer([0; BLOCK_SIZE]),
+            reset,
+
+            #[cfg(feature = "_verify")]
+            public_key,
+        }
+    }
+
+    fn memory(&mut self, alt: u8) -> Result<&mut dyn Memory, Status> {
+        match self.memories.get_mut(alt as usize - 1) {
+            Some(memory) => Ok(&mut **memory),
+            None => Err(Status::ErrTarget),
+        }
+    }
+}
+
+impl<'d, DFU: NorFlash, STATE: NorFlash, ACTIVE: ReadNorFlash, RST: Reset, const BLOCK_SIZE: usize> dfuse::Handler
+    for DfuseHandler<'d, DFU, STATE, ACTIVE, RST, BLOCK_SIZE>
+{
+    fn erase(&mut self, alt: u8, offset: u32) -> Result<(), Status> {
+        match alt {
+            // The updater erases the DFU partition as it is written.
+            0 => Ok(()),
+            alt => self.memory(alt)?.erase(offset),
+        }
+    }
+
+    fn write(&mut self, alt: u8, offset: u32, data: &[u8]) -> Result<(), Status> {
+        if alt != 0 {
+            debug!(
+                "Writing {} bytes at {} of alternate setting {}",
+                data.len(),
+                offset,
+                alt
+            );
+            return self.memory(alt)?.write(offset, data);
+        }
+
+        let len = data.len().next_multiple_of(DFU::WRITE_SIZE);
+        if len > BLOCK_SIZE {
+            error!("USB data len exceeded block size");
+            return Err(Status::ErrUnknown);
+        }
+
+        let buf = &mut self.buf.as_mut()[..len];
+        buf[..data.len()].copy_from_slice(data);
+        buf[data.len()..].fill(0xFF);
+
+        debug!("Writing {} bytes at {}", data.len(), offset);
+        match self.updater.write_firmware(offset as usize, buf) {
+            Ok(_) => {
+                let end = offset as usize + data.len();
+                self.written = Some(self.written.map_or(end, |written| written.max(end)));
+                Ok(())
+            }
+            Err(e) => {
+                error!("Error writing firmware: {:?}", e);
+                Err(firmware_error_to_status(e))
+            }
+        }
+    }
+
+    fn read(&mut self, alt: u8, offset: u32, buf: &mut [u8]) -> Result<usize, Status> {
+        if alt != 0 {
+            return self.memory(alt)?.read(offset, buf);
+        }
+
+        let len = buf.len().min(self.active.capacity().saturating_sub(offset as usize));
+        debug!("Reading {} bytes at {}", len, offset);
+        self.active.read(offset, &mut buf[..len]).map_err(|e| {
+            error!("Error reading firmware");
+            flash_error_to_status(e.kind())
+        })?;
+        Ok(len)
+    }
+
+    fn finish(&mut self) -> Result<(), Status> {
+        // Only memories were written, there is no update to swap in.
+        let Some(len) = self.written.take() else {
+            return Ok(());
+        };
+
+        debug!("Marking {} bytes of firmware as updated", len);
+        mark_updated(
+            &mut self.updater,
+            len,
+            #[cfg(feature = "_verify")]
+            self.public_key,
+        )
+    }
+
+    fn system_reset(&mut self) {
+        self.reset.sys_reset()
+    }
+}
+
+/// Convenience type alias for the DfuSe state with firmware handler.
+pub type State<'d, DFU, STATE, ACTIVE, RST, const BLOCK_SIZE: usize> =
+    DfuState<'d, DfuseHandler<'d, DFU, STATE, ACTIVE, RST, BLOCK_SIZE>>;
+
+/// Create a new DfuSe state instance.
+///
+/// `layouts` are the memory layouts of the alternate settings, starting with the firmware, then one
+/// for each of `memories`. They hold the address range the host writes to, so the firmware
+/// layout must start at the address the firmware is linked at, for tools that check it. For
+/// example, for 256K of firmware and 32K of configuration:
+///
+/// ```text
+/// ["@Firmware/0x08000000/64*004Kg", "@Config/0x08060000/8*004Kg"]
+/// ```
+///
+/// Uploads of the firmware and memories are enabled by setting `DfuAttributes::CAN_UPLOAD` in
+/// `attrs`.
+pub fn new_state<'d, DFU: NorFlash, STATE: NorFlash, ACTIVE: ReadNorFlash, RST: Reset, const BLOCK_SIZE: usize>(
+    updater: BlockingFirmwareUpdater<'d, DFU, STATE>,
+    active: ACTIVE,
+    memories: &'d mut [&'d mut dyn Memory],
+    layouts: &'d [&'d str],
+    attrs: DfuAttributes,
+    reset: RST,
+    #[cfg(feature = "_verify")] public_key: &'static [u8; 32],
+) -> State<'d, DFU, STATE, ACTIVE, RST, BLOCK_SIZE> {
+    assert_eq!(layouts.len(), memories.len() + 1);
+    let handler = DfuseHandler::new(
+        updater,
+        active,
+        memories,
+        reset,
+        #[cfg(feature = "_verify")]
+        public_key,
+    );
+    DfuState::new(handler, attrs, layouts)
+}
+
+/// An implementation of the DfuSe protocol
+///
+/// This function will add a DFU interface descriptor, with an alternate setting for the firmware and
+/// each memory, to the provided Builder, and register the provided Control as a handler for the USB device.
+///
+/// Once the host leaves DFU mode, firmware written to the DFU partition is marked to be swapped in,
+/// and the handler will trigger a system reset after the manifestation phase.
+pub fn usb_dfu<
+    'd,
+    D: Driver<'d>,
+    DFU: NorFlash,
+    STATE: NorFlash,
+    ACTIVE: ReadNorFlash,
+    RST: Reset,
+    const BLOCK_SIZE: usize,
+>(
+    builder: &mut Builder<'d, D>,
+    state: &'d mut State<'d, DFU, STATE, ACTIVE, RST, BLOCK_SIZE>,
+    func_modifier: impl Fn(&mut FunctionBuilder<'_, 'd, D>),
+) {
+    dfuse::usb_dfu(builder, state, BLOCK_SIZE, func_modifier);
+}
@@ -12,6 +12,8 @@ pub mod consts {
 pub mod dfu;
 #[cfg(all(feature = "dfu", not(feature = "application")))]
 pub use self::dfu::*;
+#[cfg(feature = "dfu")]
+pub mod dfuse;
 
 #[cfg(feature = "application")]
 pub mod application;
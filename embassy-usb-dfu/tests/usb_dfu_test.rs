@@ -2,13 +2,14 @@ use core::cell::RefCell;
 use core::convert::Infallible;
 
 use dfu_core::DfuIo;
-use embassy_boot::{BlockingFirmwareUpdater, FirmwareUpdaterConfig};
+use embassy_boot::header::HEADER_LEN;
+use embassy_boot::{BlockingFirmwareUpdater, FirmwareUpdaterConfig, ImageHeader};
 use embassy_usb::Handler;
 use embassy_usb::class::dfu::dfu_mode::Handler as DfuModeHandler;
 use embassy_usb::control::{InResponse, OutResponse, Recipient, Request as ControlRequest, RequestType};
 use embassy_usb::driver::Direction;
 use embassy_usb_dfu::consts::DfuAttributes;
-use embassy_usb_dfu::{Reset, UsbDfuState, new_state};
+use embassy_usb_dfu::{Reset, UsbDfuState, new_state, new_state_with_upload};
 use embedded_storage::nor_flash::{ErrorType, NorFlash, ReadNorFlash};
 
 const READ_WRITE_SIZE: usize = 8;
@@ -194,3 +195,82 @@ fn test_usb_dfu_will_detach() {
 fn test_usb_dfu() {
     usb_dfu(DfuAttributes::CAN_DOWNLOAD);
 }
+
+fn upload<const ACTIVE_SIZE: usize>(firmware: [u8; ACTIVE_SIZE]) -> Vec<u8> {
+    let mut aligned_buffer = [0; READ_WRITE_SIZE];
+
+    const BLOCK_SIZE: usize = 128;
+
+    let dfu_buffer = RefCell::new([0; ACTIVE_SIZE]);
+    let dfu_partition = InMemoryFlashPartition { buffer: &dfu_buffer };
+    let state_buffer = RefCell::new([0; { READ_WRITE_SIZE * 2 }]);
+    let state_partition = InMemoryFlashPartition { buffer: &state_buffer };
+    let active_buffer = RefCell::new(firmware);
+    let active_partition = InMemoryFlashPartition { buffer: &active_buffer };
+    let fw_config = FirmwareUpdaterConfig {
+        dfu: dfu_partition,
+        state: state_partition,
+    };
+    let updater = BlockingFirmwareUpdater::new(fw_config, &mut aligned_buffer);
+
+    let dfu_attributes = DfuAttributes::CAN_DOWNLOAD | DfuAttributes::CAN_UPLOAD;
+    let functional_descriptor = dfu_core::functional_descriptor::FunctionalDescriptor {
+        can_download: true,
+        can_upload: true,
+        manifestation_tolerant: false,
+        will_detach: false,
+        detach_timeout: 10,
+        transfer_size: BLOCK_SIZE as u16,
+        dfu_version: (1, 1),
+    };
+    let dfu_state =
+        new_state_with_upload::<_, _, _, _, BLOCK_SIZE>(updater, active_partition, dfu_attributes, NoopReset {});
+    let dfu = InMemoryDfu {
+        functional_descriptor,
+        dfu_state: RefCell::new(dfu_state),
+    };
+
+    // The upload ends with a short frame.
+    let mut upload = Vec::new();
+    for block in 0.. {
+        let mut buf = [0; BLOCK_SIZE];
+        let n = dfu.read_control(0xA1, 2, block, &mut buf).unwrap();
+        upload.extend_from_slice(&buf[..n]);
+        if n < BLOCK_SIZE {
+            break;
+        }
+    }
+    upload
+}
+
+#[test]
+fn test_usb_dfu_upload() {
+    // The whole partition is programmed.
+    let mut firmware = [0; 320];
+    firmware.iter_mut().enumerate().for_each(|(i, b)| *b = i as u8);
+    assert_eq!(upload(firmware), firmware);
+
+    // The upload stops after the last programmed byte.
+    let mut firmware = [0xFF; 512];
+    firmware[..200].iter_mut().enumerate().for_each(|(i, b)| *b = i as u8);
+    firmware[100] = 0xFF;
+    assert_eq!(upload(firmware), firmware[..200]);
+}
+
+#[test]
+fn test_usb_dfu_upload_image_header() {
+    // The upload stops at the end of the image, even if it ends with erased bytes.
+    let header = ImageHeader {
+        header_len: 256,
+        flags: 0,
+        image_len: 100,
+        version: 1,
+        security_counter: 0,
+        hash: [0; 64],
+        signature: [0; 64],
+    };
+    let mut firmware = [0xFF; 1024];
+    firmware[..HEADER_LEN].copy_from_slice(&header.to_bytes());
+    firmware[256..300].fill(0x42);
+    assert_eq!(upload(firmware), firmware[..356]);
+}
@@ -0,0 +1,206 @@
+#![cfg(not(feature = "_verify"))]
+
+use core::cell::RefCell;
+use core::convert::Infallible;
+
+use embassy_boot::{BlockingFirmwareUpdater, FirmwareUpdaterConfig, State as BootState};
+use embassy_usb::Handler;
+use embassy_usb::class::dfu::dfuse::Handler as DfuseModeHandler;
+use embassy_usb::control::{InResponse, OutResponse, Recipient, Request as ControlRequest, RequestType};
+use embassy_usb::driver::Direction;
+use embassy_usb_dfu::Reset;
+use embassy_usb_dfu::consts::DfuAttributes;
+use embassy_usb_dfu::dfuse::{FlashMemory, Memory, new_state};
+use embedded_storage::nor_flash::{ErrorType, NorFlash, ReadNorFlash};
+
+const READ_WRITE_SIZE: usize = 8;
+const BLOCK_SIZE: usize = 32;
+
+const DFU_DNLOAD: u8 = 1;
+const DFU_UPLOAD: u8 = 2;
+const DFU_GETSTATUS: u8 = 3;
+const DFU_CLRSTATUS: u8 = 4;
+const DFU_ABORT: u8 = 6;
+
+const LAYOUTS: &[&str] = &["@Firmware/0x08000000/04*064 g", "@Config/0x08010000/01*064 g"];
+
+struct InMemoryFlashPartition<'a, const SIZE: usize> {
+    buffer: &'a RefCell<[u8; SIZE]>,
+}
+
+impl<'a, const SIZE: usize> ReadNorFlash for InMemoryFlashPartition<'a, SIZE> {
+    const READ_SIZE: usize = READ_WRITE_SIZE;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        let start = offset as usize;
+        bytes.copy_from_slice(&self.buffer.borrow()[start..start + bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.buffer.borrow().len()
+    }
+}
+
+impl<'a, const SIZE: usize> ErrorType for InMemoryFlashPartition<'a, SIZE> {
+    type Error = Infallible;
+}
+
+impl<'a, const SIZE: usize> NorFlash for InMemoryFlashPartition<'a, SIZE> {
+    const WRITE_SIZE: usize = READ_WRITE_SIZE;
+
+    const ERASE_SIZE: usize = READ_WRITE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        let (from, to) = (from as usize, to as usize);
+        self.buffer.borrow_mut()[from..to].fill(0);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        let start = offset as usize;
+        self.buffer.borrow_mut()[start..start + bytes.len()].copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+struct NoopReset {}
+
+impl Reset for NoopReset {
+    fn sys_reset(&self) {
+        // noop
+    }
+}
+
+fn request(direction: Direction, request: u8, value: u16, length: usize) -> ControlRequest {
+    ControlRequest {
+        direction,
+        request_type: RequestType::Class,
+        recipient: Recipient::Interface,
+        request,
+        value,
+        index: 0,
+        length: length as u16,
+    }
+}
+
+fn control_out(handler: &mut impl Handler, req: u8, value: u16, data: &[u8]) {
+    let res = handler.control_out(request(Direction::Out, req, value, data.len()), data);
+    assert!(matches!(res, Some(OutResponse::Accepted)));
+}
+
+fn control_in(handler: &mut impl Handler, req: u8, value: u16, length: usize) -> Vec<u8> {
+    let mut buf = [0; 64];
+    match handler.control_in(request(Direction::In, req, value, length), &mut buf) {
+        Some(InResponse::Accepted(data)) => data.to_vec(),
+        _ => panic!("control in rejected"),
+    }
+}
+
+/// Send a DfuSe command, and return the status and state once it's done.
+fn command(handler: &mut impl Handler, command: u8, address: u32) -> (u8, u8) {
+    let mut data = vec![command];
+    data.extend_from_slice(&address.to_le_bytes());
+    control_out(handler, DFU_DNLOAD, 0, &data);
+    get_status(handler)
+}
+
+/// Poll the status until the device isn't busy, and return the status and state.
+fn get_status(handler: &mut impl Handler) -> (u8, u8) {
+    loop {
+        let status = control_in(handler, DFU_GETSTATUS, 0, 6);
+        // dfuDNBUSY
+        if status[4] != 4 {
+            return (status[0], status[4]);
+        }
+    }
+}
+
+#[test]
+fn test_usb_dfuse() {
+    let mut aligned_buffer = [0; READ_WRITE_SIZE];
+
+    let dfu_buffer = RefCell::new([0; 256]);
+    let state_buffer = RefCell::new([0; { READ_WRITE_SIZE * 2 }]);
+    let firmware: [u8; 256] = core::array::from_fn(|i| i as u8);
+    let active_buffer = RefCell::new(firmware);
+    let config_buffer = RefCell::new([0; 64]);
+
+    let updater = BlockingFirmwareUpdater::new(
+        FirmwareUpdaterConfig {
+            dfu: InMemoryFlashPartition { buffer: &dfu_buffer },
+            state: InMemoryFlashPartition { buffer: &state_buffer },
+        },
+        &mut aligned_buffer,
+    );
+    let mut config = FlashMemory::<_, BLOCK_SIZE>::new(InMemoryFlashPartition { buffer: &config_buffer });
+    let mut memories: [&mut dyn Memory; 1] = [&mut config];
+    let mut dfu = new_state::<_, _, _, _, BLOCK_SIZE>(
+        updater,
+        InMemoryFlashPartition { buffer: &active_buffer },
+        &mut memories,
+        LAYOUTS,
+        DfuAttributes::CAN_DOWNLOAD | DfuAttributes::CAN_UPLOAD,
+        NoopReset {},
+    );
+
+    // Supported commands
+    assert_eq!(control_in(&mut dfu, DFU_UPLOAD, 0, BLOCK_SIZE), [0x00, 0x21, 0x41]);
+
+    // Upload the active firmware, a block at a time
+    let mut upload = Vec::new();
+    for address in (0x0800_0000..0x0800_0100).step_by(BLOCK_SIZE) {
+        control_out(&mut dfu, DFU_ABORT, 0, &[]);
+        assert_eq!(command(&mut dfu, 0x21, address), (0, 5));
+        control_out(&mut dfu, DFU_ABORT, 0, &[]);
+        upload.extend(control_in(&mut dfu, DFU_UPLOAD, 2, BLOCK_SIZE));
+    }
+    assert_eq!(upload, firmware);
+    control_out(&mut dfu, DFU_ABORT, 0, &[]);
+
+    // Addresses outside of the memory are refused
+    assert_eq!(command(&mut dfu, 0x41, 0x0700_0000), (0x08, 10));
+    control_out(&mut dfu, DFU_CLRSTATUS, 0, &[]);
+    assert_eq!(command(&mut dfu, 0x41, 0x0800_0100), (0x08, 10));
+    control_out(&mut dfu, DFU_CLRSTATUS, 0, &[]);
+    assert_eq!(command(&mut dfu, 0x21, 0x0800_00F0), (0, 5));
+    control_out(&mut dfu, DFU_DNLOAD, 2, &[0; BLOCK_SIZE]);
+    assert_eq!(get_status(&mut dfu), (0x08, 10));
+    control_out(&mut dfu, DFU_CLRSTATUS, 0, &[]);
+
+    // Download an update
+    let update = [0xAA; 100];
+    for (i, chunk) in update.chunks(BLOCK_SIZE).enumerate() {
+        let address = 0x0800_0000 + (i * BLOCK_SIZE) as u32;
+        assert_eq!(command(&mut dfu, 0x41, address), (0, 5));
+        assert_eq!(command(&mut dfu, 0x21, address), (0, 5));
+        control_out(&mut dfu, DFU_DNLOAD, 2, chunk);
+        assert_eq!(get_status(&mut dfu), (0, 5));
+    }
+    assert_eq!(&dfu_buffer.borrow()[..update.len()], update);
+
+    // The configuration is written in place
+    let handler = dfu.handler_mut();
+    assert!(handler.erase(1, 0).is_ok());
+    assert!(handler.write(1, 8, &[1, 2, 3]).is_ok());
+    let mut buf = [0; BLOCK_SIZE];
+    assert!(handler.read(1, 0, &mut buf) == Ok(BLOCK_SIZE));
+    assert_eq!(buf[8..16], [1, 2, 3, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+    // Reads stop at the end of the memory
+    assert!(handler.read(1, 48, &mut buf) == Ok(16));
+    assert!(handler.read(2, 0, &mut buf).is_err());
+
+    // Leaving DFU mode marks the update
+    control_out(&mut dfu, DFU_DNLOAD, 2, &[]);
+    assert_eq!(get_status(&mut dfu), (0, 7));
+
+    let mut aligned_buffer = [0; READ_WRITE_SIZE];
+    let mut updater = BlockingFirmwareUpdater::new(
+        FirmwareUpdaterConfig {
+            dfu: InMemoryFlashPartition { buffer: &dfu_buffer },
+            state: InMemoryFlashPartition { buffer: &state_buffer },
+        },
+        &mut aligned_buffer,
+    );
+    assert_eq!(updater.get_state().unwrap(), BootState::Swap);
+}
@@ -237,8 +237,26 @@ pub trait Bus {
         Err(Unsupported)
     }
 
+    /// Enable or disable Link Power Management (LPM), so that the device accepts the requests of the
+    /// host to put the link in the L1 sleep state.
+    ///
+    /// When enabled, the L1 sleep state is reported with [`Event::Sleep`], and [`Bus::remote_wakeup`]
+    /// also wakes the host up from it.
+    ///
+    /// The default implementation just returns `Unsupported`.
+    ///
+    /// # Errors
+    ///
+    /// * [`Unsupported`] - This UsbBus implementation doesn't support LPM.
+    fn set_lpm_enabled(&mut self, enabled: bool) -> Result<(), Unsupported> {
+        let _ = enabled;
+        Err(Unsupported)
+    }
+
     /// Initiate a remote wakeup of the host by the device.
     ///
+    /// If the link is in the L1 sleep state, this wakes the host up from it.
+    ///
     /// # Errors
     ///
     /// * [`Unsupported`] - This UsbBus implementation doesn't support
@@ -255,6 +273,19 @@ pub trait Endpoint {
 
     /// Wait for the endpoint to be enabled.
     async fn wait_enabled(&mut self);
+
+    /// Stall the endpoint, until the host clears the halt with a `CLEAR_FEATURE` request.
+    ///
+    /// Classes use it to report errors on bulk and interrupt endpoints, like an invalid command.
+    ///
+    /// The default implementation just returns `Unsupported`.
+    ///
+    /// # Errors
+    ///
+    /// * [`Unsupported`] - This endpoint implementation doesn't support stalling.
+    fn stall(&mut self) -> Result<(), Unsupported> {
+        Err(Unsupported)
+    }
 }
 
 /// OUT Endpoint trait.
@@ -468,10 +499,23 @@ pub enum Event {
     /// has been disconnected from the USB bus.
     Suspend,
 
-    /// A USB resume request has been detected after being suspended or, in the case of self-powered
-    /// devices, the device has been connected to the USB bus.
+    /// A USB resume request has been detected after being suspended or asleep or, in the case of
+    /// self-powered devices, the device has been connected to the USB bus.
     Resume,
 
+    /// The host has put the link in the L1 sleep state of Link Power Management (LPM).
+    ///
+    /// Only reported after LPM is enabled with [`Bus::set_lpm_enabled`]. The link leaves the sleep
+    /// state with a [`Resume`](Event::Resume) or a [`Reset`](Event::Reset).
+    Sleep {
+        /// Whether the host allows the device to wake it up from the sleep state with a remote wakeup.
+        remote_wakeup: bool,
+        /// Best Effort Service Latency (BESL) requested by the host, as an index from 0 (125 µs) to
+        /// 15 (10 ms) in the table of the USB 2.0 LPM errata. It's how long the device has to get ready
+        /// when the host resumes the link, for example to restart its clocks.
+        besl: u8,
+    },
+
     /// The USB power has been detected.
     PowerDetected,
 
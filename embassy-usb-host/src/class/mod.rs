@@ -7,5 +7,6 @@ pub mod hid_report;
 pub mod hub;
 pub mod kbd;
 pub mod msc;
+pub mod net;
 pub mod uac;
 pub mod vcp;
@@ -0,0 +1,1035 @@
+//! USB network adapter host driver, for [`embassy-net`](https://crates.io/crates/embassy-net).
+//!
+//! Supports the USB Ethernet protocols used by Ethernet adapters, LTE modems and phones doing USB
+//! tethering:
+//!
+//! - CDC-ECM (Ethernet Control Model): one Ethernet frame per bulk transfer.
+//! - CDC-NCM (Network Control Model): Ethernet frames carried in NTBs (Network Transfer Blocks).
+//! - RNDIS: the protocol of Android phones, and of some modems.
+//!
+//! A [`NetHost`] sets up the network function of a device, and is then turned into an embassy-net
+//! [`Device`] and a [`Runner`] with [`NetHost::into_embassy_net_device`]. The runner moves the
+//! frames between the device and embassy-net, and tracks the link state reported by the device.
+//! It must be run in a background task, and returns when the device is disconnected.
+//!
+//! Adapters with a vendor protocol, like the ASIX AX88179 and the Realtek RTL8152, aren't
+//! supported in that mode. Many of them also have a CDC-ECM or CDC-NCM configuration, which the
+//! enumeration doesn't activate since it isn't the first one: [`select_configuration`] finds and
+//! activates it.
+//!
+//! # Example
+//!
+//! ```rust,ignore
+//! use embassy_usb_host::class::net::{NetHost, State, select_configuration};
+//!
+//! let config_len = select_configuration(&bus, &enum_info, &mut config_buf).await?;
+//! let net = NetHost::new(&bus, &config_buf[..config_len], &enum_info).await?;
+//!
+//! static STATE: StaticCell<State<1514, 4, 4>> = StaticCell::new();
+//! let (runner, device) = net.into_embassy_net_device(STATE.init(State::new()));
+//! let (stack, net_runner) = embassy_net::new(device, config, resources, seed);
+//!
+//! // Run `net_runner` in a task, then:
+//! runner.run().await;
+//! info!("Device disconnected");
+//! ```
+
+mod ncm;
+mod rndis;
+
+use core::marker::PhantomData;
+
+use aligned::{A4, Aligned};
+use embassy_futures::select::select3;
+use embassy_net_driver_channel as ch;
+use embassy_net_driver_channel::driver::LinkState;
+use embassy_time::Timer;
+use embassy_usb::control::Request;
+use embassy_usb_driver::host::{HostError, PipeError, UsbHostAllocator, UsbPipe, pipe};
+use embassy_usb_driver::{Direction as UsbDirection, EndpointAddress, EndpointInfo, EndpointType};
+
+use self::ncm::NtbParams;
+use crate::control::{ControlPipeExt, ControlType, Recipient, RequestType, SetupPacket};
+use crate::descriptor::{ConfigurationDescriptorChain, descriptor_type, lang_id};
+use crate::handler::EnumerationInfo;
+
+// USB class codes.
+const USB_CLASS_CDC: u8 = 0x02;
+const USB_CLASS_CDC_DATA: u8 = 0x0A;
+const USB_CLASS_WIRELESS: u8 = 0xE0;
+const USB_CLASS_MISC: u8 = 0xEF;
+
+const CDC_SUBCLASS_ACM: u8 = 0x02;
+const CDC_SUBCLASS_ECM: u8 = 0x06;
+const CDC_SUBCLASS_NCM: u8 = 0x0D;
+const CDC_PROTOCOL_VENDOR: u8 = 0xFF;
+const WIRELESS_SUBCLASS_RF: u8 = 0x01;
+const WIRELESS_PROTOCOL_RNDIS: u8 = 0x03;
+const MISC_SUBCLASS_RNDIS: u8 = 0x04;
+const MISC_PROTOCOL_RNDIS_ETHERNET: u8 = 0x01;
+
+// Functional descriptor subtypes (CDC 1.2 §5.2.3).
+const CDC_TYPE_UNION: u8 = 0x06;
+const CDC_TYPE_ETHERNET: u8 = 0x0F;
+const CDC_TYPE_NCM: u8 = 0x1A;
+
+// Class-specific requests (CDC 1.2 §6.2, NCM 1.0 §6.2).
+const REQ_SEND_ENCAPSULATED_COMMAND: u8 = 0x00;
+const REQ_GET_ENCAPSULATED_RESPONSE: u8 = 0x01;
+const REQ_SET_ETHERNET_PACKET_FILTER: u8 = 0x43;
+const REQ_GET_NTB_PARAMETERS: u8 = 0x80;
+const REQ_SET_NTB_INPUT_SIZE: u8 = 0x86;
+
+// Packet filter bits (ECM 1.2 §6.2.4).
+const PACKET_TYPE_ALL_MULTICAST: u16 = 1 << 1;
+const PACKET_TYPE_DIRECTED: u16 = 1 << 2;
+const PACKET_TYPE_BROADCAST: u16 = 1 << 3;
+
+// Notifications (ECM 1.2 §6.3).
+const NOTIFICATION_REQUEST_TYPE: u8 = 0xA1;
+const NOTIFY_NETWORK_CONNECTION: u8 = 0x00;
+
+// bmNetworkCapabilities of the NCM functional descriptor (NCM 1.0 §5.2.1).
+const NCM_CAP_ETHERNET_PACKET_FILTER: u8 = 1 << 0;
+const NCM_CAP_NTB_INPUT_SIZE_8: u8 = 1 << 5;
+
+/// Size of the bulk transfer buffers. The device is told not to send NTBs or RNDIS transfers
+/// bigger than this.
+const TRANSFER_SIZE: usize = 2048;
+/// Size of the buffers of the RNDIS control messages.
+const RNDIS_CONTROL_SIZE: usize = 256;
+/// RNDIS responses are polled for every 10 ms, for up to a second.
+const RNDIS_RESPONSE_POLLS: usize = 100;
+
+/// Network protocol of a USB function.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Protocol {
+    /// CDC Ethernet Control Model.
+    Ecm,
+    /// CDC Network Control Model.
+    Ncm,
+    /// Remote NDIS.
+    Rndis,
+}
+
+impl Protocol {
+    fn from_interface(class: u8, subclass: u8, protocol: u8) -> Option<Self> {
+        match (class, subclass, protocol) {
+            (USB_CLASS_CDC, CDC_SUBCLASS_ECM, _) => Some(Self::Ecm),
+            (USB_CLASS_CDC, CDC_SUBCLASS_NCM, _) => Some(Self::Ncm),
+            (USB_CLASS_CDC, CDC_SUBCLASS_ACM, CDC_PROTOCOL_VENDOR)
+            | (USB_CLASS_WIRELESS, WIRELESS_SUBCLASS_RF, WIRELESS_PROTOCOL_RNDIS)
+            | (USB_CLASS_MISC, MISC_SUBCLASS_RNDIS, MISC_PROTOCOL_RNDIS_ETHERNET) => Some(Self::Rndis),
+            _ => None,
+        }
+    }
+}
+
+/// USB network host driver error.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum NetError {
+    /// Transfer error.
+    Transfer(PipeError),
+    /// Host error while reading the configurations of the device.
+    Host(HostError),
+    /// No network function found in the device.
+    NoInterface,
+    /// Failed to allocate a pipe.
+    NoPipe,
+    /// The device returned a malformed response, or rejected a request.
+    InvalidResponse,
+}
+
+impl From<PipeError> for NetError {
+    fn from(e: PipeError) -> Self {
+        Self::Transfer(e)
+    }
+}
+
+impl From<HostError> for NetError {
+    fn from(e: HostError) -> Self {
+        match e {
+            HostError::PipeError(e) => Self::Transfer(e),
+            e => Self::Host(e),
+        }
+    }
+}
+
+impl core::fmt::Display for NetError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Transfer(_e) => write!(f, "Transfer error"),
+            Self::Host(_e) => write!(f, "Host error"),
+            Self::NoInterface => write!(f, "No network interface found"),
+            Self::NoPipe => write!(f, "No free pipe"),
+            Self::InvalidResponse => write!(f, "Invalid response"),
+        }
+    }
+}
+
+impl core::error::Error for NetError {}
+
+/// Information about a network function found in a configuration descriptor.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct NetInfo {
+    /// Network protocol.
+    pub protocol: Protocol,
+    /// Communication interface number.
+    pub comm_interface: u8,
+    /// Data interface number.
+    pub data_interface: u8,
+    /// Alternate setting of the data interface with the bulk endpoints.
+    pub data_alt_setting: u8,
+    /// Bulk IN endpoint address.
+    pub bulk_in_ep: u8,
+    /// Bulk IN max packet size.
+    pub bulk_in_mps: u16,
+    /// Bulk OUT endpoint address.
+    pub bulk_out_ep: u8,
+    /// Bulk OUT max packet size.
+    pub bulk_out_mps: u16,
+    /// Interrupt IN endpoint address of the notifications, if any.
+    pub notify_ep: Option<u8>,
+    /// Interrupt IN max packet size.
+    pub notify_mps: u16,
+    /// Interrupt IN polling interval (from endpoint descriptor).
+    pub notify_interval: u8,
+    /// Index of the string descriptor with the MAC address, 0 for RNDIS.
+    pub mac_address_string: u8,
+    /// Maximum size of the Ethernet frames, without CRC. 1514 if the device doesn't tell.
+    pub max_segment_size: u16,
+    /// Network capabilities of a CDC-NCM function, 0 for the other protocols.
+    pub network_capabilities: u8,
+}
+
+/// Find a network function in a configuration descriptor.
+pub fn find_net(config_desc: &[u8]) -> Option<NetInfo> {
+    let cfg = ConfigurationDescriptorChain::try_from_slice(config_desc).ok()?;
+
+    for comm in cfg.iter_interface() {
+        if comm.alternate_setting != 0 {
+            continue;
+        }
+        let Some(protocol) =
+            Protocol::from_interface(comm.interface_class, comm.interface_subclass, comm.interface_protocol)
+        else {
+            continue;
+        };
+
+        // Without a union descriptor, the data interface is the next one.
+        let mut data_interface = comm.interface_number + 1;
+        let mut mac_address_string = 0;
+        let mut max_segment_size = 1514;
+        let mut network_capabilities = 0;
+        for (_, desc) in comm.iter_descriptors() {
+            if desc.len() < 3 || desc[1] != descriptor_type::CS_INTERFACE {
+                continue;
+            }
+            match desc[2] {
+                CDC_TYPE_UNION if desc.len() >= 5 => data_interface = desc[4],
+                CDC_TYPE_ETHERNET if desc.len() >= 13 => {
+                    mac_address_string = desc[3];
+                    max_segment_size = u16::from_le_bytes([desc[8], desc[9]]);
+                }
+                CDC_TYPE_NCM if desc.len() >= 6 => network_capabilities = desc[5],
+                _ => {}
+            }
+        }
+        let notify = comm
+            .iter_endpoints()
+            .find(|ep| ep.ep_type() == EndpointType::Interrupt && ep.is_in());
+
+        // For CDC-ECM and CDC-NCM, the default alternate setting of the data interface has no
+        // endpoints, and selecting the one with them enables the function.
+        for data in cfg.iter_interface() {
+            if data.interface_number != data_interface || data.interface_class != USB_CLASS_CDC_DATA {
+                continue;
+            }
+            let mut bulk_in: Option<(u8, u16)> = None;
+            let mut bulk_out: Option<(u8, u16)> = None;
+            for ep in data.iter_endpoints() {
+                if ep.ep_type() == EndpointType::Bulk {
+                    if ep.is_in() {
+                        bulk_in = Some((ep.endpoint_address, ep.max_packet_size));
+                    } else {
+                        bulk_out = Some((ep.endpoint_address, ep.max_packet_size));
+                    }
+                }
+            }
+
+            if let (Some((in_ep, in_mps)), Some((out_ep, out_mps))) = (bulk_in, bulk_out) {
+                return Some(NetInfo {
+                    protocol,
+                    comm_interface: comm.interface_number,
+                    data_interface,
+                    data_alt_setting: data.alternate_setting,
+                    bulk_in_ep: in_ep,
+                    bulk_in_mps: in_mps,
+                    bulk_out_ep: out_ep,
+                    bulk_out_mps: out_mps,
+                    notify_ep: notify.map(|ep| ep.endpoint_address),
+                    notify_mps: notify.map_or(0, |ep| ep.max_packet_size),
+                    notify_interval: notify.map_or(0, |ep| ep.interval),
+                    mac_address_string,
+                    max_segment_size,
+                    network_capabilities,
+                });
+            }
+        }
+    }
+
+    None
+}
+
+/// Activate a configuration of the device with a network function.
+///
+/// The enumeration activates the first configuration of the device, which is a vendor one for some
+/// adapters. This looks for a configuration with a network function, activates it if needed, and
+/// writes its descriptor to `config_buf`. Returns the length of the descriptor, to pass to
+/// [`NetHost::new`].
+pub async fn select_configuration<'d, A: UsbHostAllocator<'d>>(
+    alloc: &A,
+    enum_info: &EnumerationInfo,
+    config_buf: &mut [u8],
+) -> Result<usize, NetError> {
+    let mut ctrl_ch = alloc_control_pipe(alloc, enum_info)?;
+    let active = ctrl_ch.active_configuration_value().await?;
+
+    for index in 0..enum_info.device_desc.num_configurations {
+        let cfg = enum_info.get_configuration(index, &mut ctrl_ch, config_buf).await?;
+        let (len, value) = (cfg.total_len as usize, cfg.configuration_value);
+        if find_net(&config_buf[..len]).is_some() {
+            if active.map(|v| v.get()) != Some(value) {
+                debug!("net: activating configuration {}", value);
+                ctrl_ch.set_configuration(value).await?;
+            }
+            return Ok(len);
+        }
+    }
+
+    Err(NetError::NoInterface)
+}
+
+/// How the Ethernet frames are carried on the bulk endpoints.
+#[derive(Copy, Clone)]
+enum Framing {
+    Ecm,
+    Ncm(NtbParams),
+    Rndis { max_transfer_size: usize },
+}
+
+/// USB network host driver.
+///
+/// Sets up the network function of a device. Turn it into an embassy-net device with
+/// [`NetHost::into_embassy_net_device`].
+pub struct NetHost<'d, A: UsbHostAllocator<'d>> {
+    ctrl_ch: A::Pipe<pipe::Control, pipe::InOut>,
+    in_ch: A::Pipe<pipe::Bulk, pipe::In>,
+    out_ch: A::Pipe<pipe::Bulk, pipe::Out>,
+    notify_ch: Option<A::Pipe<pipe::Interrupt, pipe::In>>,
+    info: NetInfo,
+    framing: Framing,
+    mac_address: [u8; 6],
+    link_up: bool,
+    _phantom: PhantomData<&'d ()>,
+}
+
+impl<'d, A: UsbHostAllocator<'d>> NetHost<'d, A> {
+    /// Create a new USB network host driver.
+    ///
+    /// Parses the config descriptor to find the network function, allocates its pipes, then
+    /// initializes the function and reads its MAC address.
+    pub async fn new(alloc: &A, config_desc: &[u8], enum_info: &EnumerationInfo) -> Result<Self, NetError> {
+        let info = find_net(config_desc).ok_or(NetError::NoInterface)?;
+        debug!("net: found {:?} function", info.protocol);
+
+        let in_ep_info = EndpointInfo {
+            addr: EndpointAddress::from_parts((info.bulk_in_ep & 0x0F) as usize, UsbDirection::In),
+            ep_type: EndpointType::Bulk,
+            max_packet_size: info.bulk_in_mps,
+            interval_ms: 0,
+        };
+
+        let out_ep_info = EndpointInfo {
+            addr: EndpointAddress::from_parts((info.bulk_out_ep & 0x0F) as usize, UsbDirection::Out),
+            ep_type: EndpointType::Bulk,
+            max_packet_size: info.bulk_out_mps,
+            interval_ms: 0,
+        };
+
+        let device_address = enum_info.device_address;
+        let split = enum_info.split();
+
+        let mut ctrl_ch = alloc_control_pipe(alloc, enum_info)?;
+        let mut in_ch = alloc
+            .alloc_pipe::<pipe::Bulk, pipe::In>(device_address, &in_ep_info, split)
+            .map_err(|_| NetError::NoPipe)?;
+        let mut out_ch = alloc
+            .alloc_pipe::<pipe::Bulk, pipe::Out>(device_address, &out_ep_info, split)
+            .map_err(|_| NetError::NoPipe)?;
+        let notify_ch = match info.notify_ep {
+            Some(ep) => {
+                let notify_ep_info = EndpointInfo {
+                    addr: EndpointAddress::from_parts((ep & 0x0F) as usize, UsbDirection::In),
+                    ep_type: EndpointType::Interrupt,
+                    max_packet_size: info.notify_mps,
+                    interval_ms: info.notify_interval,
+                };
+                Some(
+                    alloc
+                        .alloc_pipe::<pipe::Interrupt, pipe::In>(device_address, &notify_ep_info, split)
+                        .map_err(|_| NetError::NoPipe)?,
+                )
+            }
+            None => None,
+        };
+
+        // Without notifications, there's no telling when the link goes down.
+        let mut link_up = notify_ch.is_none();
+        let (framing, mac_address) = match info.protocol {
+            Protocol::Ecm => {
+                set_ethernet_packet_filter(&mut ctrl_ch, info.comm_interface).await?;
+                (
+                    Framing::Ecm,
+                    read_mac_address(&mut ctrl_ch, info.mac_address_string).await?,
+                )
+            }
+            Protocol::Ncm => {
+                let params = init_ncm(&mut ctrl_ch, &info).await?;
+                if info.network_capabilities & NCM_CAP_ETHERNET_PACKET_FILTER != 0 {
+                    set_ethernet_packet_filter(&mut ctrl_ch, info.comm_interface).await?;
+                }
+                (
+                    Framing::Ncm(params),
+                    read_mac_address(&mut ctrl_ch, info.mac_address_string).await?,
+                )
+            }
+            Protocol::Rndis => {
+                let (max_transfer_size, mac_address, connected) = init_rndis(&mut ctrl_ch, info.comm_interface).await?;
+                link_up = connected;
+                (Framing::Rndis { max_transfer_size }, mac_address)
+            }
+        };
+
+        if info.data_alt_setting != 0 {
+            set_interface(&mut ctrl_ch, info.data_interface, info.data_alt_setting).await?;
+            in_ch.reset_data_toggle();
+            out_ch.reset_data_toggle();
+        }
+
+        Ok(Self {
+            ctrl_ch,
+            in_ch,
+            out_ch,
+            notify_ch,
+            info,
+            framing,
+            mac_address,
+            link_up,
+            _phantom: PhantomData,
+        })
+    }
+
+    /// Information about the network function.
+    pub fn info(&self) -> &NetInfo {
+        &self.info
+    }
+
+    /// MAC address of the device, to use as the address of the embassy-net interface.
+    pub fn mac_address(&self) -> [u8; 6] {
+        self.mac_address
+    }
+
+    /// Obtain a driver for using the network function with [`embassy-net`](https://crates.io/crates/embassy-net).
+    ///
+    /// The hardware address of the driver is the MAC address of the device. Frames bigger than
+    /// `MTU` are dropped.
+    pub fn into_embassy_net_device<const MTU: usize, const N_RX: usize, const N_TX: usize>(
+        self,
+        state: &'d mut State<MTU, N_RX, N_TX>,
+    ) -> (Runner<'d, A, MTU>, Device<'d, MTU>) {
+        let (runner, device) = ch::new(
+            &mut state.ch_state,
+            ch::driver::HardwareAddress::Ethernet(self.mac_address),
+        );
+
+        (
+            Runner {
+                host: self,
+                rx_buf: &mut state.rx_buf[..],
+                tx_buf: &mut state.tx_buf[..],
+                ch: runner,
+            },
+            device,
+        )
+    }
+}
+
+/// Internal state for the embassy-net integration.
+pub struct State<const MTU: usize, const N_RX: usize, const N_TX: usize> {
+    ch_state: ch::State<MTU, N_RX, N_TX>,
+    rx_buf: Aligned<A4, [u8; TRANSFER_SIZE]>,
+    tx_buf: Aligned<A4, [u8; TRANSFER_SIZE]>,
+}
+
+impl<const MTU: usize, const N_RX: usize, const N_TX: usize> State<MTU, N_RX, N_TX> {
+    /// Create a new `State`.
+    pub const fn new() -> Self {
+        Self {
+            ch_state: ch::State::new(),
+            rx_buf: Aligned([0; TRANSFER_SIZE]),
+            tx_buf: Aligned([0; TRANSFER_SIZE]),
+        }
+    }
+}
+
+impl<const MTU: usize, const N_RX: usize, const N_TX: usize> Default for State<MTU, N_RX, N_TX> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Type alias for the embassy-net driver of a USB network device.
+pub type Device<'d, const MTU: usize> = embassy_net_driver_channel::Device<'d, MTU>;
+
+/// Background runner for a USB network device.
+///
+/// You must call `.run()` in a background task for the device to operate.
+pub struct Runner<'d, A: UsbHostAllocator<'d>, const MTU: usize> {
+    host: NetHost<'d, A>,
+    rx_buf: &'d mut [u8],
+    tx_buf: &'d mut [u8],
+    ch: ch::Runner<'d, MTU>,
+}
+
+impl<'d, A: UsbHostAllocator<'d>, const MTU: usize> Runner<'d, A, MTU> {
+    /// Run the USB network device.
+    ///
+    /// Returns when the device is disconnected, with the link down. Transfer errors are logged
+    /// and the frames involved dropped.
+    pub async fn run(self) {
+        let (state_chan, mut rx_chan, mut tx_chan) = self.ch.split();
+        let NetHost {
+            mut ctrl_ch,
+            mut in_ch,
+            mut out_ch,
+            notify_ch,
+            info,
+            framing,
+            link_up,
+            ..
+        } = self.host;
+        let (rx_buf, tx_buf) = (self.rx_buf, self.tx_buf);
+
+        state_chan.set_link_state(if link_up { LinkState::Up } else { LinkState::Down });
+
+        let rx_fut = async {
+            loop {
+                let n = match in_ch.request_in(rx_buf).await {
+                    Ok(n) => n,
+                    Err(PipeError::Disconnected) => return,
+                    Err(e) => {
+                        warn!("net: failed to receive: {:?}", e);
+                        continue;
+                    }
+                };
+                let data = &rx_buf[..n];
+                match framing {
+                    Framing::Ecm => receive(&mut rx_chan, data).await,
+                    Framing::Ncm(_) => match ncm::Datagrams::new(data) {
+                        Some(datagrams) => {
+                            for datagram in datagrams {
+                                receive(&mut rx_chan, datagram).await;
+                            }
+                        }
+                        None => warn!("net: invalid NTB"),
+                    },
+                    Framing::Rndis { .. } => {
+                        for frame in rndis::Packets::new(data) {
+                            receive(&mut rx_chan, frame).await;
+                        }
+                    }
+                }
+            }
+        };
+
+        let tx_fut = async {
+            let mut sequence: u16 = 0;
+            loop {
+                let p = tx_chan.tx_buf().await;
+                let result = match framing {
+                    Framing::Ecm => out_ch.request_out(&p, true).await,
+                    Framing::Ncm(params) => match ncm::write_ntb(tx_buf, &params, sequence, &p) {
+                        Some(len) => {
+                            sequence = sequence.wrapping_add(1);
+                            out_ch.request_out(&tx_buf[..len], true).await
+                        }
+                        None => {
+                            warn!("net: dropping frame of {} bytes", p.len());
+                            Ok(())
+                        }
+                    },
+                    Framing::Rndis { max_transfer_size } => {
+                        // Keep a byte for the padding below.
+                        let limit = max_transfer_size.min(TRANSFER_SIZE - 1);
+                        match rndis::write_packet(&mut tx_buf[..limit], &p) {
+                            Some(mut len) => {
+                                // RNDIS devices expect the transfers to end with a short packet,
+                                // and not all of them handle zero-length packets: pad with a byte.
+                                if len.is_multiple_of(info.bulk_out_mps as usize) {
+                                    tx_buf[len] = 0;
+                                    len += 1;
+                                }
+                                out_ch.request_out(&tx_buf[..len], false).await
+                            }
+                            None => {
+                                warn!("net: dropping frame of {} bytes", p.len());
+                                Ok(())
+                            }
+                        }
+                    }
+                };
+                p.tx_done();
+                match result {
+                    Ok(()) => {}
+                    Err(PipeError::Disconnected) => return,
+                    Err(e) => warn!("net: failed to send: {:?}", e),
+                }
+            }
+        };
+
+        let notify_fut = async {
+            let Some(mut notify_ch) = notify_ch else {
+                return core::future::pending().await;
+            };
+            let mut buf = [0u8; 64];
+            // Bytes left of a notification spanning several packets.
+            let mut skip: usize = 0;
+            loop {
+                let n = match notify_ch.request_in(&mut buf).await {
+                    Ok(n) => n,
+                    Err(PipeError::Disconnected) => return,
+                    Err(e) => {
+                        warn!("net: failed to receive notification: {:?}", e);
+                        continue;
+                    }
+                };
+
+                if let Framing::Rndis { .. } = framing {
+                    // The notification only tells that a response is available.
+                    match rndis_status(&mut ctrl_ch, info.comm_interface).await {
+                        Ok(Some(up)) => state_chan.set_link_state(if up { LinkState::Up } else { LinkState::Down }),
+                        Ok(None) => {}
+                        Err(NetError::Transfer(PipeError::Disconnected)) => return,
+                        Err(e) => warn!("net: failed to get RNDIS status: {:?}", e),
+                    }
+                    continue;
+                }
+
+                if skip > 0 {
+                    skip = skip.saturating_sub(n);
+                    continue;
+                }
+                if n >= 8 && buf[0] == NOTIFICATION_REQUEST_TYPE {
+                    let len = u16::from_le_bytes([buf[6], buf[7]]) as usize;
+                    skip = len.saturating_sub(n - 8);
+                    if buf[1] == NOTIFY_NETWORK_CONNECTION {
+                        let up = buf[2] != 0;
+                        debug!("net: link {}", if up { "up" } else { "down" });
+                        state_chan.set_link_state(if up { LinkState::Up } else { LinkState::Down });
+                    }
+                }
+            }
+        };
+
+        select3(rx_fut, tx_fut, notify_fut).await;
+        state_chan.set_link_state(LinkState::Down);
+    }
+}
+
+async fn receive<const MTU: usize>(rx_chan: &mut ch::RxRunner<'_, MTU>, frame: &[u8]) {
+    if frame.len() > MTU {
+        warn!("net: dropping frame of {} bytes", frame.len());
+        return;
+    }
+    let mut p = rx_chan.rx_buf().await;
+    p[..frame.len()].copy_from_slice(frame);
+    p.rx_done(frame.len());
+}
+
+fn alloc_control_pipe<'d, A: UsbHostAllocator<'d>>(
+    alloc: &A,
+    enum_info: &EnumerationInfo,
+) -> Result<A::Pipe<pipe::Control, pipe::InOut>, NetError> {
+    let ctrl_ep_info = EndpointInfo {
+        addr: EndpointAddress::from_parts(0, UsbDirection::In),
+        ep_type: EndpointType::Control,
+        max_packet_size: enum_info.device_desc.max_packet_size0 as u16,
+        interval_ms: 0,
+    };
+    alloc
+        .alloc_pipe::<pipe::Control, pipe::InOut>(enum_info.device_address, &ctrl_ep_info, enum_info.split())
+        .map_err(|_| NetError::NoPipe)
+}
+
+async fn set_interface<P>(ctrl: &mut P, interface: u8, alternate_setting: u8) -> Result<(), NetError>
+where
+    P: UsbPipe<pipe::Control, pipe::InOut>,
+{
+    let setup = SetupPacket {
+        request_type: RequestType {
+            direction: UsbDirection::Out,
+            control_type: ControlType::Standard,
+            recipient: Recipient::Interface,
+        },
+        request: Request::SET_INTERFACE,
+        value: alternate_setting as u16,
+        index: interface as u16,
+        length: 0,
+    };
+    ctrl.control_out(&setup.to_bytes(), &[]).await?;
+    Ok(())
+}
+
+/// Lets the directed, broadcast and multicast frames through.
+async fn set_ethernet_packet_filter<P>(ctrl: &mut P, comm_interface: u8) -> Result<(), NetError>
+where
+    P: UsbPipe<pipe::Control, pipe::InOut>,
+{
+    let filter = PACKET_TYPE_DIRECTED | PACKET_TYPE_BROADCAST | PACKET_TYPE_ALL_MULTICAST;
+    let setup = SetupPacket::class_interface_out(REQ_SET_ETHERNET_PACKET_FILTER, filter, comm_interface as u16, 0);
+    match ctrl.control_out(&setup.to_bytes(), &[]).await {
+        // The request is optional for CDC-ECM, and the default filter does.
+        Ok(()) | Err(PipeError::Stall) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Reads the MAC address from a string descriptor of 12 hexadecimal digits (ECM 1.2 §5.4).
+async fn read_mac_address<P>(ctrl: &mut P, index: u8) -> Result<[u8; 6], NetError>
+where
+    P: UsbPipe<pipe::Control, pipe::InOut>,
+{
+    if index == 0 {
+        return Err(NetError::InvalidResponse);
+    }
+
+    // Request the string in the first language of the device.
+    let mut buf = [0u8; 26];
+    let setup = SetupPacket::get_descriptor(false, descriptor_type::STRING, 0, 4);
+    let n = ctrl.control_in(&setup.to_bytes(), &mut buf[..4]).await?;
+    let lang_id = match n {
+        4 => u16::from_le_bytes([buf[2], buf[3]]),
+        _ => lang_id::english::US,
+    };
+
+    let mut setup = SetupPacket::get_descriptor(false, descriptor_type::STRING, index, buf.len() as u16);
+    setup.index = lang_id;
+    let n = ctrl.control_in(&setup.to_bytes(), &mut buf).await?;
+    parse_mac_address(&buf[..n]).ok_or(NetError::InvalidResponse)
+}
+
+fn parse_mac_address(desc: &[u8]) -> Option<[u8; 6]> {
+    if desc.len() < 26 || (desc[0] as usize) < 26 || desc[1] != descriptor_type::STRING {
+        return None;
+    }
+    let digit = |i: usize| {
+        let c = read_u16(desc, 2 + 2 * i)?;
+        char::from_u32(c as u32)?.to_digit(16).map(|d| d as u8)
+    };
+    let mut mac = [0; 6];
+    for (i, byte) in mac.iter_mut().enumerate() {
+        *byte = (digit(2 * i)? << 4) | digit(2 * i + 1)?;
+    }
+    Some(mac)
+}
+
+/// Reads the NTB parameters, and limits the size of the NTBs of the device to the transfer buffers.
+async fn init_ncm<P>(ctrl: &mut P, info: &NetInfo) -> Result<NtbParams, NetError>
+where
+    P: UsbPipe<pipe::Control, pipe::InOut>,
+{
+    let comm_interface = info.comm_interface as u16;
+    let mut buf = [0u8; NtbParams::LEN];
+    let setup = SetupPacket::class_interface_in(REQ_GET_NTB_PARAMETERS, 0, comm_interface, buf.len() as u16);
+    let n = ctrl.control_in(&setup.to_bytes(), &mut buf).await?;
+    let params = NtbParams::parse(&buf[..n]).ok_or(NetError::InvalidResponse)?;
+    trace!("net: NTB parameters {:?}", params);
+    if params.formats & 0x01 == 0 {
+        return Err(NetError::InvalidResponse);
+    }
+
+    if params.in_max_size as usize > TRANSFER_SIZE {
+        // The 8-byte form also limits the datagrams per NTB, 0 meaning no limit.
+        let mut data = [0u8; 8];
+        data[..4].copy_from_slice(&(TRANSFER_SIZE as u32).to_le_bytes());
+        let len = match info.network_capabilities & NCM_CAP_NTB_INPUT_SIZE_8 {
+            0 => 4,
+            _ => 8,
+        };
+        let setup = SetupPacket::class_interface_out(REQ_SET_NTB_INPUT_SIZE, 0, comm_interface, len as u16);
+        ctrl.control_out(&setup.to_bytes(), &data[..len]).await?;
+    }
+
+    Ok(params)
+}
+
+/// Initializes an RNDIS function. Returns the maximum size of its transfers, its MAC address and
+/// whether its link is up.
+async fn init_rndis<P>(ctrl: &mut P, comm_interface: u8) -> Result<(usize, [u8; 6], bool), NetError>
+where
+    P: UsbPipe<pipe::Control, pipe::InOut>,
+{
+    let mut msg = [0u8; 32];
+    let mut resp = [0u8; RNDIS_CONTROL_SIZE];
+
+    let len = rndis::write_initialize(&mut msg, 1, TRANSFER_SIZE as u32);
+    let n = rndis_request(
+        ctrl,
+        comm_interface,
+        &msg[..len],
+        1,
+        rndis::MSG_INITIALIZE_CMPLT,
+        &mut resp,
+    )
+    .await?;
+    let max_transfer_size = rndis::initialize_max_transfer_size(&resp[..n]).ok_or(NetError::InvalidResponse)?;
+
+    let len = rndis::write_query(&mut msg, 2, rndis::OID_802_3_PERMANENT_ADDRESS);
+    let n = rndis_request(ctrl, comm_interface, &msg[..len], 2, rndis::MSG_QUERY_CMPLT, &mut resp).await?;
+    let mac_address = rndis::query_result(&resp[..n])
+        .and_then(|value| value.get(..6)?.try_into().ok())
+        .ok_or(NetError::InvalidResponse)?;
+
+    let filter = rndis::PACKET_TYPE_DIRECTED | rndis::PACKET_TYPE_BROADCAST | rndis::PACKET_TYPE_ALL_MULTICAST;
+    let len = rndis::write_set(&mut msg, 3, rndis::OID_GEN_CURRENT_PACKET_FILTER, &filter.to_le_bytes());
+    rndis_request(ctrl, comm_interface, &msg[..len], 3, rndis::MSG_SET_CMPLT, &mut resp).await?;
+
+    // Not all devices report the media state, the link is up then.
+    let len = rndis::write_query(&mut msg, 4, rndis::OID_GEN_MEDIA_CONNECT_STATUS);
+    let link_up = rndis_request(ctrl, comm_interface, &msg[..len], 4, rndis::MSG_QUERY_CMPLT, &mut resp)
+        .await
+        .ok()
+        .and_then(|n| read_u32(rndis::query_result(&resp[..n])?, 0))
+        .is_none_or(|state| state == rndis::MEDIA_STATE_CONNECTED);
+
+    Ok((max_transfer_size as usize, mac_address, link_up))
+}
+
+/// Sends an RNDIS control message, and reads its completion into `resp`. Returns the length of
+/// the completion.
+async fn rndis_request<P>(
+    ctrl: &mut P,
+    comm_interface: u8,
+    msg: &[u8],
+    request_id: u32,
+    completion: u32,
+    resp: &mut [u8],
+) -> Result<usize, NetError>
+where
+    P: UsbPipe<pipe::Control, pipe::InOut>,
+{
+    send_encapsulated_command(ctrl, comm_interface, msg).await?;
+
+    // The device notifies when the response is available, but polling for it works with all the
+    // devices and leaves the notification endpoint to the runner.
+    for _ in 0..RNDIS_RESPONSE_POLLS {
+        let n = match get_encapsulated_response(ctrl, comm_interface, resp).await {
+            Ok(n) => n,
+            Err(NetError::Transfer(PipeError::Timeout)) => 0,
+            Err(e) => return Err(e),
+        };
+        match rndis::Message::parse(&resp[..n]) {
+            Some(rndis::Message::Completion {
+                msg_type,
+                request_id: id,
+                status,
+                ..
+            }) if msg_type == completion && id == request_id => {
+                if status != rndis::STATUS_SUCCESS {
+                    warn!("net: RNDIS request failed with status {:08x}", status);
+                    return Err(NetError::InvalidResponse);
+                }
+                return Ok(n);
+            }
+            Some(rndis::Message::Keepalive(id)) => reply_keepalive(ctrl, comm_interface, id).await?,
+            _ => Timer::after_millis(10).await,
+        }
+    }
+
+    Err(NetError::Transfer(PipeError::Timeout))
+}
+
+/// Reads a status message of an RNDIS device. Returns the new link state, if it changed.
+async fn rndis_status<P>(ctrl: &mut P, comm_interface: u8) -> Result<Option<bool>, NetError>
+where
+    P: UsbPipe<pipe::Control, pipe::InOut>,
+{
+    let mut resp = [0u8; RNDIS_CONTROL_SIZE];
+    let n = get_encapsulated_response(ctrl, comm_interface, &mut resp).await?;
+    match rndis::Message::parse(&resp[..n]) {
+        Some(rndis::Message::IndicateStatus(rndis::STATUS_MEDIA_CONNECT)) => Ok(Some(true)),
+        Some(rndis::Message::IndicateStatus(rndis::STATUS_MEDIA_DISCONNECT)) => Ok(Some(false)),
+        Some(rndis::Message::Keepalive(id)) => {
+            reply_keepalive(ctrl, comm_interface, id).await?;
+            Ok(None)
+        }
+        _ => Ok(None),
+    }
+}
+
+async fn reply_keepalive<P>(ctrl: &mut P, comm_interface: u8, request_id: u32) -> Result<(), NetError>
+where
+    P: UsbPipe<pipe::Control, pipe::InOut>,
+{
+    let mut msg = [0u8; 16];
+    let len = rndis::write_keepalive_complete(&mut msg, request_id);
+    send_encapsulated_command(ctrl, comm_interface, &msg[..len]).await
+}
+
+async fn send_encapsulated_command<P>(ctrl: &mut P, comm_interface: u8, msg: &[u8]) -> Result<(), NetError>
+where
+    P: UsbPipe<pipe::Control, pipe::InOut>,
+{
+    let setup = SetupPacket::class_interface_out(
+        REQ_SEND_ENCAPSULATED_COMMAND,
+        0,
+        comm_interface as u16,
+        msg.len() as u16,
+    );
+    ctrl.control_out(&setup.to_bytes(), msg).await?;
+    Ok(())
+}
+
+async fn get_encapsulated_response<P>(ctrl: &mut P, comm_interface: u8, buf: &mut [u8]) -> Result<usize, NetError>
+where
+    P: UsbPipe<pipe::Control, pipe::InOut>,
+{
+    let setup = SetupPacket::class_interface_in(
+        REQ_GET_ENCAPSULATED_RESPONSE,
+        0,
+        comm_interface as u16,
+        buf.len() as u16,
+    );
+    Ok(ctrl.control_in(&setup.to_bytes(), buf).await?)
+}
+
+fn read_u16(buf: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(buf.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn read_u32(buf: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(buf.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn write_u32s(buf: &mut [u8], values: &[u32]) {
+    for (chunk, value) in buf.chunks_exact_mut(4).zip(values) {
+        chunk.copy_from_slice(&value.to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Configuration of a USB Ethernet adapter: CDC-ECM communication interface with the header,
+    /// union and Ethernet functional descriptors and a notification endpoint, then a data interface
+    /// with the bulk endpoints in alternate setting 1.
+    #[rustfmt::skip]
+    const CFG_ECM: [u8; 80] = [
+        9, 0x02, 80, 0, 2, 1, 0, 0x80, 50,
+        9, 0x04, 0, 0, 1, 0x02, 0x06, 0x00, 0,
+        5, 0x24, 0x00, 0x10, 0x01,
+        5, 0x24, 0x06, 0, 1,
+        13, 0x24, 0x0F, 3, 0, 0, 0, 0, 0xEA, 0x05, 0, 0, 0,
+        7, 0x05, 0x83, 0x03, 0x10, 0x00, 8,
+        9, 0x04, 1, 0, 0, 0x0A, 0x00, 0x00, 0,
+        9, 0x04, 1, 1, 2, 0x0A, 0x00, 0x00, 0,
+        7, 0x05, 0x81, 0x02, 0x40, 0x00, 0,
+        7, 0x05, 0x02, 0x02, 0x40, 0x00, 0,
+    ];
+
+    #[test]
+    fn find_ecm() {
+        let info = find_net(&CFG_ECM).unwrap();
+        assert_eq!(info.protocol, Protocol::Ecm);
+        assert_eq!(info.comm_interface, 0);
+        assert_eq!(info.data_interface, 1);
+        assert_eq!(info.data_alt_setting, 1);
+        assert_eq!(info.bulk_in_ep, 0x81);
+        assert_eq!(info.bulk_in_mps, 64);
+        assert_eq!(info.bulk_out_ep, 0x02);
+        assert_eq!(info.bulk_out_mps, 64);
+        assert_eq!(info.notify_ep, Some(0x83));
+        assert_eq!(info.notify_mps, 16);
+        assert_eq!(info.notify_interval, 8);
+        assert_eq!(info.mac_address_string, 3);
+        assert_eq!(info.max_segment_size, 1514);
+    }
+
+    #[test]
+    fn find_ncm_and_rndis() {
+        // Same layout, with the subclass of NCM.
+        let mut cfg = CFG_ECM;
+        cfg[9 + 6] = 0x0D;
+        assert_eq!(find_net(&cfg).unwrap().protocol, Protocol::Ncm);
+
+        // RNDIS of Android phones, without union descriptor and with the endpoints in the default
+        // alternate setting of the data interface.
+        #[rustfmt::skip]
+        let rndis: [u8; 53] = [
+            9, 0x02, 53, 0, 2, 1, 0, 0x80, 50,
+            9, 0x04, 0, 0, 1, 0xE0, 0x01, 0x03, 0,
+            5, 0x24, 0x00, 0x10, 0x01,
+            7, 0x05, 0x81, 0x03, 0x08, 0x00, 9,
+            9, 0x04, 1, 0, 2, 0x0A, 0x00, 0x00, 0,
+            7, 0x05, 0x82, 0x02, 0x00, 0x02, 0,
+            7, 0x05, 0x01, 0x02, 0x00, 0x02, 0,
+        ];
+        let info = find_net(&rndis).unwrap();
+        assert_eq!(info.protocol, Protocol::Rndis);
+        assert_eq!(info.data_interface, 1);
+        assert_eq!(info.data_alt_setting, 0);
+        assert_eq!(info.bulk_in_ep, 0x82);
+        assert_eq!(info.bulk_in_mps, 512);
+        assert_eq!(info.mac_address_string, 0);
+    }
+
+    #[test]
+    fn find_net_rejects_other_functions() {
+        assert!(find_net(&[]).is_none());
+
+        // CDC-ACM serial port.
+        let mut cfg = CFG_ECM;
+        cfg[9 + 6] = 0x02;
+        assert!(find_net(&cfg).is_none());
+
+        // Union descriptor pointing to a missing interface.
+        let mut cfg = CFG_ECM;
+        cfg[27] = 2;
+        assert!(find_net(&cfg).is_none());
+
+        // Data interface without bulk OUT endpoint.
+        let mut cfg = CFG_ECM;
+        cfg[76] = 0x03;
+        assert!(find_net(&cfg).is_none());
+    }
+
+    #[test]
+    fn parse_mac_address_string() {
+        let mut desc = [0u8; 26];
+        desc[0] = 26;
+        desc[1] = 0x03;
+        for (i, c) in "0050b61234aF".bytes().enumerate() {
+            desc[2 + 2 * i] = c;
+        }
+        assert_eq!(parse_mac_address(&desc), Some([0x00, 0x50, 0xB6, 0x12, 0x34, 0xAF]));
+
+        desc[4] = b'g';
+        assert_eq!(parse_mac_address(&desc), None);
+        assert_eq!(parse_mac_address(&desc[..24]), None);
+    }
+}
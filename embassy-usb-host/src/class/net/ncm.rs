@@ -0,0 +1,285 @@
+//! CDC-NCM framing (NCM 1.0 §3): Ethernet frames carried in 16-bit NTBs.
+
+use super::{read_u16, read_u32};
+
+// NCM 1.0 §3.2.1, §3.3.1.
+const SIG_NTH16: u32 = 0x484d434e;
+const SIG_NDP16_NO_FCS: u32 = 0x304d434e;
+const SIG_NDP16_WITH_FCS: u32 = 0x314d434e;
+
+const NTH16_LEN: usize = 12;
+/// NDP16 header, one datagram pointer and the terminating null pointer.
+const NDP16_LEN: usize = 16;
+/// Bound on the NDPs followed in one NTB, so that a looping chain can't hang the host.
+const MAX_NDPS: usize = 16;
+
+/// NTB parameters of a device (NCM 1.0 §6.2.1, Table 6-3).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(super) struct NtbParams {
+    /// Bitmap of the supported NTB formats, bit 0 is NTB-16.
+    pub formats: u16,
+    /// Maximum size of the NTBs sent by the device.
+    pub in_max_size: u32,
+    /// Maximum size of the NTBs sent by the host.
+    pub out_max_size: u32,
+    pub out_divisor: u16,
+    pub out_remainder: u16,
+    pub out_alignment: u16,
+}
+
+impl NtbParams {
+    /// Size of the response to GET_NTB_PARAMETERS.
+    pub const LEN: usize = 28;
+
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        if buf.len() < Self::LEN {
+            return None;
+        }
+        Some(Self {
+            formats: read_u16(buf, 2)?,
+            in_max_size: read_u32(buf, 4)?,
+            out_max_size: read_u32(buf, 16)?,
+            out_divisor: read_u16(buf, 20)?,
+            out_remainder: read_u16(buf, 22)?,
+            out_alignment: read_u16(buf, 24)?,
+        })
+    }
+}
+
+/// Writes an NTB carrying `datagram` to `buf`, and returns its length.
+///
+/// Returns `None` if the NTB doesn't fit in `buf` or is bigger than the device accepts.
+pub(super) fn write_ntb(buf: &mut [u8], params: &NtbParams, sequence: u16, datagram: &[u8]) -> Option<usize> {
+    let ndp_index = NTH16_LEN.next_multiple_of(params.out_alignment.max(4) as usize);
+    let ndp_end = ndp_index + NDP16_LEN;
+    // The datagram must start at an offset equal to the remainder modulo the divisor.
+    let divisor = params.out_divisor.max(1) as usize;
+    let remainder = params.out_remainder as usize % divisor;
+    let datagram_index = ndp_end + (divisor + remainder - ndp_end % divisor) % divisor;
+    let len = datagram_index + datagram.len();
+    if len > buf.len() || len > params.out_max_size as usize || len > u16::MAX as usize {
+        return None;
+    }
+
+    buf[..datagram_index].fill(0);
+    buf[0..4].copy_from_slice(&SIG_NTH16.to_le_bytes());
+    buf[4..6].copy_from_slice(&(NTH16_LEN as u16).to_le_bytes());
+    buf[6..8].copy_from_slice(&sequence.to_le_bytes());
+    buf[8..10].copy_from_slice(&(len as u16).to_le_bytes());
+    buf[10..12].copy_from_slice(&(ndp_index as u16).to_le_bytes());
+
+    let ndp = &mut buf[ndp_index..ndp_end];
+    ndp[0..4].copy_from_slice(&SIG_NDP16_NO_FCS.to_le_bytes());
+    ndp[4..6].copy_from_slice(&(NDP16_LEN as u16).to_le_bytes());
+    // wNextNdpIndex and the null pointer stay 0.
+    ndp[8..10].copy_from_slice(&(datagram_index as u16).to_le_bytes());
+    ndp[10..12].copy_from_slice(&(datagram.len() as u16).to_le_bytes());
+
+    buf[datagram_index..len].copy_from_slice(datagram);
+    Some(len)
+}
+
+/// Iterator over the datagrams of an NTB received from the device.
+///
+/// Malformed datagram pointers are skipped, and a malformed NDP ends the iteration.
+pub(super) struct Datagrams<'a> {
+    ntb: &'a [u8],
+    /// Index of the current NDP, 0 once there are none left.
+    ndp: usize,
+    /// Index of the next datagram pointer in the current NDP.
+    entry: usize,
+    /// Whether the datagrams of the current NDP end with a CRC-32.
+    fcs: bool,
+    ndps: usize,
+}
+
+impl<'a> Datagrams<'a> {
+    /// Parses the NTH16 of `ntb`. Returns `None` if `ntb` doesn't start with one.
+    pub fn new(ntb: &'a [u8]) -> Option<Self> {
+        if read_u32(ntb, 0)? != SIG_NTH16 || read_u16(ntb, 4)? as usize != NTH16_LEN {
+            return None;
+        }
+        // A block length of 0 means the NTB ends with the transfer.
+        let ntb = match read_u16(ntb, 8)? as usize {
+            0 => ntb,
+            len => ntb.get(..len)?,
+        };
+        let mut datagrams = Self {
+            ntb,
+            ndp: 0,
+            entry: 0,
+            fcs: false,
+            ndps: 0,
+        };
+        datagrams.enter_ndp(read_u16(ntb, 10)? as usize);
+        Some(datagrams)
+    }
+
+    fn enter_ndp(&mut self, index: usize) {
+        self.ndp = 0;
+        if index < NTH16_LEN || !index.is_multiple_of(4) || self.ndps == MAX_NDPS {
+            return;
+        }
+        let fcs = match read_u32(self.ntb, index) {
+            Some(SIG_NDP16_NO_FCS) => false,
+            Some(SIG_NDP16_WITH_FCS) => true,
+            _ => return,
+        };
+        match read_u16(self.ntb, index + 4) {
+            Some(len) if len as usize >= NDP16_LEN && index + len as usize <= self.ntb.len() => {}
+            _ => return,
+        }
+        self.ndp = index;
+        self.entry = index + 8;
+        self.fcs = fcs;
+        self.ndps += 1;
+    }
+}
+
+impl<'a> Iterator for Datagrams<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        while self.ndp != 0 {
+            let ndp_end = self.ndp + read_u16(self.ntb, self.ndp + 4)? as usize;
+            if self.entry + 4 <= ndp_end {
+                let index = read_u16(self.ntb, self.entry)? as usize;
+                let len = read_u16(self.ntb, self.entry + 2)? as usize;
+                self.entry += 4;
+                if index != 0 && len != 0 {
+                    let len = if self.fcs { len.saturating_sub(4) } else { len };
+                    if let Some(datagram) = self.ntb.get(index..index + len) {
+                        return Some(datagram);
+                    }
+                    continue;
+                }
+            }
+            // End of this NDP, go on with the next one.
+            let next = read_u16(self.ntb, self.ndp + 6)? as usize;
+            self.enter_ndp(next);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PARAMS: NtbParams = NtbParams {
+        formats: 1,
+        in_max_size: 2048,
+        out_max_size: 2048,
+        out_divisor: 4,
+        out_remainder: 0,
+        out_alignment: 4,
+    };
+
+    #[test]
+    fn parse_ntb_params() {
+        #[rustfmt::skip]
+        let buf = [
+            28, 0, 0x01, 0x00,
+            0x00, 0x40, 0x00, 0x00, // dwNtbInMaxSize: 16384
+            4, 0, 0, 0, 4, 0, 0, 0,
+            0x00, 0x08, 0x00, 0x00, // dwNtbOutMaxSize: 2048
+            8, 0, 2, 0, 4, 0,
+            1, 0,
+        ];
+        let params = NtbParams::parse(&buf).unwrap();
+        assert_eq!(params.formats, 1);
+        assert_eq!(params.in_max_size, 16384);
+        assert_eq!(params.out_max_size, 2048);
+        assert_eq!(params.out_divisor, 8);
+        assert_eq!(params.out_remainder, 2);
+        assert_eq!(params.out_alignment, 4);
+        assert!(NtbParams::parse(&buf[..27]).is_none());
+    }
+
+    #[test]
+    fn write_then_parse_ntb() {
+        let datagram: [u8; 60] = core::array::from_fn(|i| i as u8);
+        let mut buf = [0xAA; 256];
+        let len = write_ntb(&mut buf, &PARAMS, 7, &datagram).unwrap();
+        assert_eq!(len, 12 + 16 + 60);
+        assert_eq!(&buf[..4], b"NCMH");
+        assert_eq!(read_u16(&buf, 6), Some(7));
+        assert_eq!(&buf[12..16], b"NCM0");
+
+        let mut datagrams = Datagrams::new(&buf[..len]).unwrap();
+        assert_eq!(datagrams.next(), Some(&datagram[..]));
+        assert_eq!(datagrams.next(), None);
+    }
+
+    #[test]
+    fn write_ntb_aligns_datagram() {
+        let params = NtbParams {
+            out_divisor: 16,
+            out_remainder: 2,
+            out_alignment: 8,
+            ..PARAMS
+        };
+        let mut buf = [0; 256];
+        let len = write_ntb(&mut buf, &params, 0, &[1, 2, 3]).unwrap();
+        // NDP at 16, datagram at the first index past 32 that is 2 modulo 16.
+        assert_eq!(read_u16(&buf, 10), Some(16));
+        assert_eq!(read_u16(&buf, 16 + 8), Some(34));
+        assert_eq!(len, 37);
+        assert_eq!(&buf[34..37], &[1, 2, 3]);
+    }
+
+    #[test]
+    fn write_ntb_too_big() {
+        let mut buf = [0; 2048];
+        assert!(write_ntb(&mut buf, &PARAMS, 0, &[0; 2020]).is_some());
+        assert!(write_ntb(&mut buf, &PARAMS, 0, &[0; 2021]).is_none());
+        assert!(write_ntb(&mut buf[..100], &PARAMS, 0, &[0; 80]).is_none());
+        let small = NtbParams {
+            out_max_size: 64,
+            ..PARAMS
+        };
+        assert!(write_ntb(&mut buf, &small, 0, &[0; 40]).is_none());
+    }
+
+    #[test]
+    fn parse_several_ndps() {
+        #[rustfmt::skip]
+        let ntb = [
+            // NTH16, block length 56, first NDP at 12.
+            b'N', b'C', b'M', b'H', 12, 0, 0, 0, 56, 0, 12, 0,
+            // NDP16 without CRC, two datagrams, next NDP at 32.
+            b'N', b'C', b'M', b'0', 20, 0, 32, 0, 48, 0, 2, 0, 50, 0, 1, 0, 0, 0, 0, 0,
+            // NDP16 with CRC, one datagram of 1 byte + CRC.
+            b'N', b'C', b'M', b'1', 16, 0, 0, 0, 51, 0, 5, 0, 0, 0, 0, 0,
+            // Datagrams.
+            1, 2, 3, 4, 0xC0, 0xC1, 0xC2, 0xC3,
+        ];
+        let datagrams: heapless::Vec<&[u8], 4> = Datagrams::new(&ntb).unwrap().collect();
+        assert_eq!(&datagrams[..], &[&[1, 2][..], &[3][..], &[4][..]]);
+    }
+
+    #[test]
+    fn parse_malformed_ntb() {
+        assert!(Datagrams::new(&[]).is_none());
+        assert!(Datagrams::new(b"NCMH\x0c\x00\x00\x00\x40\x00\x0c\x00").is_none());
+
+        #[rustfmt::skip]
+        let mut ntb = [
+            b'N', b'C', b'M', b'H', 12, 0, 0, 0, 0, 0, 12, 0,
+            b'N', b'C', b'M', b'0', 20, 0, 0, 0, 40, 0, 4, 0, 34, 0, 1, 0, 0, 0, 0, 0,
+            1, 2, 3, 4,
+        ];
+        // The datagram out of bounds is skipped.
+        let datagrams = Datagrams::new(&ntb).unwrap();
+        assert!(datagrams.eq([&[3][..]]));
+
+        // An NDP pointing to itself is only followed a bounded number of times.
+        ntb[18] = 12;
+        assert_eq!(Datagrams::new(&ntb).unwrap().count(), MAX_NDPS);
+
+        // Misaligned NDP.
+        ntb[10] = 13;
+        assert_eq!(Datagrams::new(&ntb).unwrap().next(), None);
+    }
+}
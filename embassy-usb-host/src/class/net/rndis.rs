@@ -0,0 +1,296 @@
+//! RNDIS messages ([MS-RNDIS] §2.2): control messages exchanged as encapsulated commands, and
+//! Ethernet frames wrapped in packet messages on the bulk endpoints.
+
+use super::{read_u32, write_u32s};
+
+// [MS-RNDIS] §2.2.1.
+const MSG_PACKET: u32 = 0x0000_0001;
+const MSG_INITIALIZE: u32 = 0x0000_0002;
+const MSG_QUERY: u32 = 0x0000_0004;
+const MSG_SET: u32 = 0x0000_0005;
+const MSG_INDICATE_STATUS: u32 = 0x0000_0007;
+const MSG_KEEPALIVE: u32 = 0x0000_0008;
+const MSG_COMPLETION: u32 = 0x8000_0000;
+
+pub(super) const MSG_INITIALIZE_CMPLT: u32 = MSG_INITIALIZE | MSG_COMPLETION;
+pub(super) const MSG_QUERY_CMPLT: u32 = MSG_QUERY | MSG_COMPLETION;
+pub(super) const MSG_SET_CMPLT: u32 = MSG_SET | MSG_COMPLETION;
+const MSG_KEEPALIVE_CMPLT: u32 = MSG_KEEPALIVE | MSG_COMPLETION;
+
+pub(super) const STATUS_SUCCESS: u32 = 0x0000_0000;
+pub(super) const STATUS_MEDIA_CONNECT: u32 = 0x4001_000B;
+pub(super) const STATUS_MEDIA_DISCONNECT: u32 = 0x4001_000C;
+
+// [MS-RNDIS] §2.2.2.
+pub(super) const OID_GEN_CURRENT_PACKET_FILTER: u32 = 0x0001_010E;
+pub(super) const OID_GEN_MEDIA_CONNECT_STATUS: u32 = 0x0001_0114;
+pub(super) const OID_802_3_PERMANENT_ADDRESS: u32 = 0x0101_0101;
+
+pub(super) const PACKET_TYPE_DIRECTED: u32 = 0x0000_0001;
+pub(super) const PACKET_TYPE_ALL_MULTICAST: u32 = 0x0000_0004;
+pub(super) const PACKET_TYPE_BROADCAST: u32 = 0x0000_0008;
+
+pub(super) const MEDIA_STATE_CONNECTED: u32 = 0;
+
+const INITIALIZE_LEN: usize = 24;
+const QUERY_LEN: usize = 28;
+const SET_LEN: usize = 28;
+const KEEPALIVE_CMPLT_LEN: usize = 16;
+const PACKET_HEADER_LEN: usize = 44;
+
+/// Writes an INITIALIZE message to `buf`, and returns its length.
+pub(super) fn write_initialize(buf: &mut [u8], request_id: u32, max_transfer_size: u32) -> usize {
+    write_u32s(
+        buf,
+        &[
+            MSG_INITIALIZE,
+            INITIALIZE_LEN as u32,
+            request_id,
+            1,
+            0,
+            max_transfer_size,
+        ],
+    );
+    INITIALIZE_LEN
+}
+
+/// Writes a QUERY message for `oid` to `buf`, and returns its length.
+pub(super) fn write_query(buf: &mut [u8], request_id: u32, oid: u32) -> usize {
+    write_u32s(buf, &[MSG_QUERY, QUERY_LEN as u32, request_id, oid, 0, 0, 0]);
+    QUERY_LEN
+}
+
+/// Writes a SET message of `oid` to `value` to `buf`, and returns its length.
+pub(super) fn write_set(buf: &mut [u8], request_id: u32, oid: u32, value: &[u8]) -> usize {
+    let len = SET_LEN + value.len();
+    // The information buffer offset is counted from the request ID.
+    write_u32s(
+        buf,
+        &[
+            MSG_SET,
+            len as u32,
+            request_id,
+            oid,
+            value.len() as u32,
+            SET_LEN as u32 - 8,
+            0,
+        ],
+    );
+    buf[SET_LEN..len].copy_from_slice(value);
+    len
+}
+
+/// Writes the reply to a KEEPALIVE message of the device to `buf`, and returns its length.
+pub(super) fn write_keepalive_complete(buf: &mut [u8], request_id: u32) -> usize {
+    write_u32s(
+        buf,
+        &[
+            MSG_KEEPALIVE_CMPLT,
+            KEEPALIVE_CMPLT_LEN as u32,
+            request_id,
+            STATUS_SUCCESS,
+        ],
+    );
+    KEEPALIVE_CMPLT_LEN
+}
+
+/// A control message received from the device.
+#[derive(Debug, PartialEq, Eq)]
+pub(super) enum Message<'a> {
+    /// Completion of a request of the host.
+    Completion {
+        msg_type: u32,
+        request_id: u32,
+        status: u32,
+        msg: &'a [u8],
+    },
+    /// Change of the status of the device.
+    IndicateStatus(u32),
+    /// Request to reply with a KEEPALIVE completion.
+    Keepalive(u32),
+}
+
+impl<'a> Message<'a> {
+    /// Parses a message, returning `None` if it is malformed or of an unknown type.
+    pub fn parse(buf: &'a [u8]) -> Option<Self> {
+        let msg_type = read_u32(buf, 0)?;
+        let len = read_u32(buf, 4)? as usize;
+        let msg = buf.get(..len)?;
+        match msg_type {
+            MSG_INDICATE_STATUS => Some(Self::IndicateStatus(read_u32(msg, 8)?)),
+            MSG_KEEPALIVE => Some(Self::Keepalive(read_u32(msg, 8)?)),
+            MSG_INITIALIZE_CMPLT | MSG_QUERY_CMPLT | MSG_SET_CMPLT => Some(Self::Completion {
+                msg_type,
+                request_id: read_u32(msg, 8)?,
+                status: read_u32(msg, 12)?,
+                msg,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the information buffer of a QUERY completion.
+pub(super) fn query_result(msg: &[u8]) -> Option<&[u8]> {
+    let len = read_u32(msg, 16)? as usize;
+    // The offset is counted from the request ID.
+    let offset = read_u32(msg, 20)? as usize + 8;
+    msg.get(offset..offset.checked_add(len)?)
+}
+
+/// Returns the maximum size of the transfers the device accepts, from an INITIALIZE completion.
+pub(super) fn initialize_max_transfer_size(msg: &[u8]) -> Option<u32> {
+    read_u32(msg, 36)
+}
+
+/// Writes a packet message carrying `frame` to `buf`, and returns its length.
+///
+/// Returns `None` if the message doesn't fit in `buf`.
+pub(super) fn write_packet(buf: &mut [u8], frame: &[u8]) -> Option<usize> {
+    let len = PACKET_HEADER_LEN + frame.len();
+    if len > buf.len() {
+        return None;
+    }
+    // The data offset is counted from the data offset field.
+    write_u32s(
+        buf,
+        &[
+            MSG_PACKET,
+            len as u32,
+            PACKET_HEADER_LEN as u32 - 8,
+            frame.len() as u32,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ],
+    );
+    buf[PACKET_HEADER_LEN..len].copy_from_slice(frame);
+    Some(len)
+}
+
+/// Iterator over the frames of the packet messages of a transfer received from the device.
+///
+/// The iteration ends at the first malformed message.
+pub(super) struct Packets<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Packets<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+}
+
+impl<'a> Iterator for Packets<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        let buf = core::mem::take(&mut self.buf);
+        if read_u32(buf, 0)? != MSG_PACKET {
+            return None;
+        }
+        let len = read_u32(buf, 4)? as usize;
+        let msg = buf.get(..len)?;
+        let offset = read_u32(msg, 8)? as usize + 8;
+        let data_len = read_u32(msg, 12)? as usize;
+        let frame = msg.get(offset..offset.checked_add(data_len)?)?;
+        self.buf = &buf[len..];
+        Some(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_set_message() {
+        let mut buf = [0; 64];
+        let len = write_set(&mut buf, 3, OID_GEN_CURRENT_PACKET_FILTER, &0x0Du32.to_le_bytes());
+        #[rustfmt::skip]
+        let expected = [
+            5, 0, 0, 0, 32, 0, 0, 0, 3, 0, 0, 0, 0x0E, 0x01, 0x01, 0x00,
+            4, 0, 0, 0, 20, 0, 0, 0, 0, 0, 0, 0, 0x0D, 0, 0, 0,
+        ];
+        assert_eq!(&buf[..len], &expected);
+    }
+
+    #[test]
+    fn parse_query_completion() {
+        #[rustfmt::skip]
+        let buf = [
+            0x04, 0, 0, 0x80, 30, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0,
+            6, 0, 0, 0, 16, 0, 0, 0,
+            0x02, 0x11, 0x22, 0x33, 0x44, 0x55,
+            // Trailing bytes of the transfer.
+            0xFF, 0xFF,
+        ];
+        let Some(Message::Completion {
+            msg_type,
+            request_id,
+            status,
+            msg,
+        }) = Message::parse(&buf)
+        else {
+            panic!("not a completion");
+        };
+        assert_eq!(msg_type, MSG_QUERY_CMPLT);
+        assert_eq!(request_id, 7);
+        assert_eq!(status, STATUS_SUCCESS);
+        assert_eq!(query_result(msg), Some(&[0x02, 0x11, 0x22, 0x33, 0x44, 0x55][..]));
+
+        // Truncated information buffer.
+        assert_eq!(query_result(&msg[..28]), None);
+        // Truncated message.
+        assert_eq!(Message::parse(&buf[..20]), None);
+    }
+
+    #[test]
+    fn parse_status_messages() {
+        let buf = [7, 0, 0, 0, 20, 0, 0, 0, 0x0B, 0x00, 0x01, 0x40, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert_eq!(
+            Message::parse(&buf),
+            Some(Message::IndicateStatus(STATUS_MEDIA_CONNECT))
+        );
+        let buf = [8, 0, 0, 0, 12, 0, 0, 0, 9, 0, 0, 0];
+        assert_eq!(Message::parse(&buf), Some(Message::Keepalive(9)));
+        let mut reply = [0; 16];
+        write_keepalive_complete(&mut reply, 9);
+        assert_eq!(reply, [8, 0, 0, 0x80, 16, 0, 0, 0, 9, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn write_then_parse_packets() {
+        let mut buf = [0; 256];
+        let len = write_packet(&mut buf, &[1, 2, 3]).unwrap();
+        assert_eq!(len, 47);
+        assert_eq!(&buf[..16], &[1, 0, 0, 0, 47, 0, 0, 0, 36, 0, 0, 0, 3, 0, 0, 0]);
+        let len2 = write_packet(&mut buf[len..], &[4, 5]).unwrap();
+
+        let mut packets = Packets::new(&buf[..len + len2]);
+        assert_eq!(packets.next(), Some(&[1, 2, 3][..]));
+        assert_eq!(packets.next(), Some(&[4, 5][..]));
+        assert_eq!(packets.next(), None);
+
+        assert!(write_packet(&mut buf[..46], &[1, 2, 3]).is_none());
+    }
+
+    #[test]
+    fn parse_malformed_packets() {
+        let mut buf = [0; 64];
+        let len = write_packet(&mut buf, &[1, 2, 3]).unwrap();
+        // Data past the end of the message.
+        buf[12] = 4;
+        assert_eq!(Packets::new(&buf[..len]).next(), None);
+        // Message longer than the transfer.
+        buf[12] = 3;
+        assert_eq!(Packets::new(&buf[..len - 1]).next(), None);
+        // Not a packet message.
+        buf[0] = 2;
+        assert_eq!(Packets::new(&buf[..len]).next(), None);
+    }
+}
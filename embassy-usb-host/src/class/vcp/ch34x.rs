@@ -0,0 +1,483 @@
+//! WCH CH340/CH341 USB ↔ UART bridge driver.
+//!
+//! Implements the CH341 serial protocol: vendor-class bulk data
+//! transport plus vendor-device control requests reading and writing the
+//! UART registers of the chip. Covers the CH340 (G, C, E, B, K, ...) and
+//! the CH341 in serial mode.
+//!
+//! The parts are single-port, so a [`Ch34xHost`] owns all the pipes.
+//! The CH343 and CH9102 use CDC-ACM and are handled by
+//! [`cdc_acm`](crate::class::cdc_acm) instead.
+//!
+//! # Example
+//!
+//! ```rust,ignore
+//! use embassy_usb_host::class::vcp::ch34x::Ch34xHost;
+//! use embassy_usb_host::class::vcp::LineCoding;
+//!
+//! let mut port = Ch34xHost::new(&bus, &config_buf[..config_len], &enum_info)?;
+//! port.enable().await?;
+//! port.set_line_coding(&LineCoding::default()).await?;
+//! port.set_control_line_state(true, true).await?;
+//!
+//! let mut buf = [0u8; 64];
+//! let n = port.read(&mut buf).await?;
+//! port.write(&buf[..n]).await?;
+//! ```
+
+use core::marker::PhantomData;
+
+use embassy_usb_driver::host::{PipeError, UsbHostAllocator, UsbPipe, pipe};
+use embassy_usb_driver::{Direction as UsbDirection, EndpointAddress, EndpointInfo, EndpointType};
+
+use super::{LineCoding, ModemStatus, Parity, StopBits, find_port};
+use crate::control::SetupPacket;
+use crate::handler::EnumerationInfo;
+
+/// WCH VID and CH34x PIDs.
+pub mod id {
+    /// Nanjing Qinheng Microelectronics (WCH) vendor ID.
+    pub const VID_WCH: u16 = 0x1A86;
+    /// CH340 product ID.
+    pub const PID_CH340: u16 = 0x7523;
+    /// CH340K product ID.
+    pub const PID_CH340K: u16 = 0x7522;
+    /// CH341 serial mode product ID.
+    pub const PID_CH341: u16 = 0x5523;
+}
+
+// Vendor requests.
+const REQ_READ_VERSION: u8 = 0x5F;
+const REQ_READ_REG: u8 = 0x95;
+const REQ_WRITE_REG: u8 = 0x9A;
+const REQ_SERIAL_INIT: u8 = 0xA1;
+const REQ_MODEM_CTRL: u8 = 0xA4;
+
+// Registers, written in pairs with the second one in the high byte.
+const REG_BREAK: u16 = 0x05;
+const REG_PRESCALER: u16 = 0x12;
+const REG_DIVISOR: u16 = 0x13;
+const REG_LCR: u16 = 0x18;
+const REG_LCR2: u16 = 0x25;
+const REG_MODEM_STATUS: u16 = 0x0706;
+
+const LCR_ENABLE_RX: u8 = 0x80;
+const LCR_ENABLE_TX: u8 = 0x40;
+const LCR_MARK_SPACE: u8 = 0x20;
+const LCR_PAR_EVEN: u8 = 0x10;
+const LCR_ENABLE_PAR: u8 = 0x08;
+const LCR_STOP_BITS_2: u8 = 0x04;
+
+/// Break register bit, active low.
+const NBREAK: u8 = 0x01;
+
+const MCR_DTR: u16 = 1 << 5;
+const MCR_RTS: u16 = 1 << 6;
+
+// Modem status bits, active low.
+const MSR_CTS: u8 = 0x01;
+const MSR_DSR: u8 = 0x02;
+const MSR_RI: u8 = 0x04;
+const MSR_DCD: u8 = 0x08;
+
+/// First chip version with the line control register.
+const VERSION_LCR: u8 = 0x30;
+
+/// Prescaler bit that sends received data without waiting for a full packet.
+const PRESCALER_NO_WAIT: u16 = 1 << 7;
+
+const CLOCK: u32 = 48_000_000;
+
+/// Divisor of [`CLOCK`] selected by prescaler `ps` and factor `fact`.
+const fn clk_div(ps: u32, fact: u32) -> u32 {
+    1 << (12 - 3 * ps - fact)
+}
+
+/// Lowest baud rate of prescaler `ps`, with the largest divisor.
+const fn min_rate(ps: u32) -> u32 {
+    CLOCK / (clk_div(ps, 1) * 512)
+}
+
+const MIN_BAUD_RATE: u32 = min_rate(0) + 1;
+const MAX_BAUD_RATE: u32 = CLOCK / (clk_div(3, 0) * 2);
+
+/// Compute the prescaler/divisor register pair for `baud`, rounded to
+/// the nearest achievable rate.
+///
+/// With `limited_prescaler`, only prescaler 3 may use the full clock, as
+/// required by the parts without break support.
+fn baud_divisor(baud: u32, limited_prescaler: bool) -> u16 {
+    let baud = baud.clamp(MIN_BAUD_RATE, MAX_BAUD_RATE);
+
+    // Highest prescaler, with the full clock, giving a divisor below 512.
+    let ps = (0..4).rev().find(|&ps| baud > min_rate(ps)).unwrap_or(0);
+    let mut fact = 1;
+    let mut clk = clk_div(ps, fact);
+    let mut div = CLOCK / (clk * baud);
+
+    // Halve the clock if the divisor is out of range.
+    if !(9..=255).contains(&div) || (ps < 3 && limited_prescaler) {
+        div /= 2;
+        clk *= 2;
+        fact = 0;
+    }
+
+    // Take the next divisor if it gets closer to the requested rate.
+    if 16 * CLOCK / (clk * div) - 16 * baud >= 16 * baud - 16 * CLOCK / (clk * (div + 1)) {
+        div += 1;
+    }
+
+    // Prefer the halved clock for even divisors.
+    if fact == 1 && div.is_multiple_of(2) {
+        div /= 2;
+        fact = 0;
+    }
+
+    (((0x100 - div) << 8) | (fact << 2) | ps) as u16
+}
+
+/// Compute the line control register value for `coding`.
+fn lcr(coding: &LineCoding) -> Option<u8> {
+    let mut lcr = LCR_ENABLE_RX | LCR_ENABLE_TX;
+    lcr |= match coding.data_bits {
+        5..=8 => coding.data_bits - 5,
+        _ => return None,
+    };
+    lcr |= match coding.parity {
+        Parity::None => 0,
+        Parity::Odd => LCR_ENABLE_PAR,
+        Parity::Even => LCR_ENABLE_PAR | LCR_PAR_EVEN,
+        Parity::Mark => LCR_ENABLE_PAR | LCR_MARK_SPACE,
+        Parity::Space => LCR_ENABLE_PAR | LCR_MARK_SPACE | LCR_PAR_EVEN,
+    };
+    lcr |= match coding.stop_bits {
+        StopBits::One => 0,
+        StopBits::Two => LCR_STOP_BITS_2,
+        StopBits::OneAndHalf => return None,
+    };
+    Some(lcr)
+}
+
+/// CH34x host driver error.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Ch34xError {
+    /// Transfer error.
+    Transfer(PipeError),
+    /// No vendor-class interface with a bulk IN/OUT pair.
+    NoInterface,
+    /// Failed to allocate a pipe.
+    NoPipe,
+    /// Device response had an unexpected length.
+    InvalidResponse,
+    /// Argument was out of range for the CH34x protocol.
+    InvalidArgument,
+    /// The chip doesn't support the operation.
+    Unsupported,
+}
+
+impl From<PipeError> for Ch34xError {
+    fn from(e: PipeError) -> Self {
+        Self::Transfer(e)
+    }
+}
+
+impl core::fmt::Display for Ch34xError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Transfer(_) => write!(f, "Transfer error"),
+            Self::NoInterface => write!(f, "No CH34x interface found"),
+            Self::NoPipe => write!(f, "No free pipe"),
+            Self::InvalidResponse => write!(f, "Invalid response from device"),
+            Self::InvalidArgument => write!(f, "Invalid argument"),
+            Self::Unsupported => write!(f, "Unsupported operation"),
+        }
+    }
+}
+
+impl core::error::Error for Ch34xError {}
+
+impl embedded_io_async::Error for Ch34xError {
+    fn kind(&self) -> embedded_io_async::ErrorKind {
+        match self {
+            Self::Transfer(e) => match e {
+                PipeError::Disconnected => embedded_io_async::ErrorKind::NotConnected,
+                PipeError::BufferOverflow => embedded_io_async::ErrorKind::OutOfMemory,
+                PipeError::Timeout => embedded_io_async::ErrorKind::TimedOut,
+                _ => embedded_io_async::ErrorKind::Other,
+            },
+            Self::NoInterface => embedded_io_async::ErrorKind::NotFound,
+            Self::NoPipe => embedded_io_async::ErrorKind::OutOfMemory,
+            Self::InvalidResponse => embedded_io_async::ErrorKind::InvalidData,
+            Self::InvalidArgument => embedded_io_async::ErrorKind::InvalidInput,
+            Self::Unsupported => embedded_io_async::ErrorKind::Unsupported,
+        }
+    }
+}
+
+/// CH34x host driver.
+pub struct Ch34xHost<'d, A: UsbHostAllocator<'d>> {
+    ctrl_ch: A::Pipe<pipe::Control, pipe::InOut>,
+    in_ch: A::Pipe<pipe::Bulk, pipe::In>,
+    out_ch: A::Pipe<pipe::Bulk, pipe::Out>,
+    version: u8,
+    /// Parts without break support, which also have a limited prescaler.
+    limited: bool,
+    _phantom: PhantomData<&'d ()>,
+}
+
+impl<'d, A: UsbHostAllocator<'d>> Ch34xHost<'d, A> {
+    /// Create a new CH34x host driver.
+    ///
+    /// Parses the config descriptor to find the bulk endpoints and
+    /// allocates the pipes. Performs no I/O; call
+    /// [`enable`](Self::enable) before use.
+    pub fn new(alloc: &A, config_desc: &[u8], enum_info: &EnumerationInfo) -> Result<Self, Ch34xError> {
+        let info = find_port(config_desc, 0).ok_or(Ch34xError::NoInterface)?;
+
+        let ctrl_ep_info = EndpointInfo {
+            addr: EndpointAddress::from_parts(0, UsbDirection::In),
+            ep_type: EndpointType::Control,
+            max_packet_size: enum_info.device_desc.max_packet_size0 as u16,
+            interval_ms: 0,
+        };
+
+        let in_ep_info = EndpointInfo {
+            addr: EndpointAddress::from_parts((info.bulk_in_ep & 0x0F) as usize, UsbDirection::In),
+            ep_type: EndpointType::Bulk,
+            max_packet_size: info.bulk_in_mps,
+            interval_ms: 0,
+        };
+
+        let out_ep_info = EndpointInfo {
+            addr: EndpointAddress::from_parts((info.bulk_out_ep & 0x0F) as usize, UsbDirection::Out),
+            ep_type: EndpointType::Bulk,
+            max_packet_size: info.bulk_out_mps,
+            interval_ms: 0,
+        };
+
+        let device_address = enum_info.device_address;
+        let split = enum_info.split();
+
+        let ctrl_ch = alloc
+            .alloc_pipe::<pipe::Control, pipe::InOut>(device_address, &ctrl_ep_info, split)
+            .map_err(|_| Ch34xError::NoPipe)?;
+        let in_ch = alloc
+            .alloc_pipe::<pipe::Bulk, pipe::In>(device_address, &in_ep_info, split)
+            .map_err(|_| Ch34xError::NoPipe)?;
+        let out_ch = alloc
+            .alloc_pipe::<pipe::Bulk, pipe::Out>(device_address, &out_ep_info, split)
+            .map_err(|_| Ch34xError::NoPipe)?;
+
+        Ok(Self {
+            ctrl_ch,
+            in_ch,
+            out_ch,
+            version: 0,
+            limited: false,
+            _phantom: PhantomData,
+        })
+    }
+
+    async fn vendor_out(&mut self, request: u8, value: u16, index: u16) -> Result<(), Ch34xError> {
+        let setup = SetupPacket::vendor_device_out(request, value, index, 0);
+        self.ctrl_ch.control_out(&setup.to_bytes(), &[]).await?;
+        Ok(())
+    }
+
+    async fn vendor_in(&mut self, request: u8, value: u16, buf: &mut [u8]) -> Result<(), Ch34xError> {
+        let setup = SetupPacket::vendor_device_in(request, value, 0, buf.len() as u16);
+        let n = self.ctrl_ch.control_in(&setup.to_bytes(), buf).await?;
+        if n != buf.len() {
+            return Err(Ch34xError::InvalidResponse);
+        }
+        Ok(())
+    }
+
+    /// Chip version, as read by [`enable`](Self::enable).
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Initialize the UART.
+    ///
+    /// Reads the chip version, probes for break support and issues
+    /// `SERIAL_INIT`. The line coding must be set again afterwards.
+    pub async fn enable(&mut self) -> Result<(), Ch34xError> {
+        let mut buf = [0u8; 2];
+        self.vendor_in(REQ_READ_VERSION, 0, &mut buf).await?;
+        self.version = buf[0];
+        debug!("ch34x: chip version {:02x}", self.version);
+
+        // Parts without the break register stall reads of it.
+        self.limited = match self.vendor_in(REQ_READ_REG, (REG_LCR << 8) | REG_BREAK, &mut buf).await {
+            Ok(()) => false,
+            Err(Ch34xError::Transfer(PipeError::Stall)) => true,
+            Err(e) => return Err(e),
+        };
+
+        self.vendor_out(REQ_SERIAL_INIT, 0, 0).await
+    }
+
+    /// Program the UART baud rate in bauds per second.
+    ///
+    /// Writes the prescaler and divisor registers. The rate is clamped
+    /// to 46 to 3000000 baud and rounded to the nearest achievable one.
+    pub async fn set_baud_rate(&mut self, baud: u32) -> Result<(), Ch34xError> {
+        if baud == 0 {
+            return Err(Ch34xError::InvalidArgument);
+        }
+        let value = baud_divisor(baud, self.limited) | PRESCALER_NO_WAIT;
+        self.vendor_out(REQ_WRITE_REG, (REG_DIVISOR << 8) | REG_PRESCALER, value)
+            .await
+    }
+
+    /// Program baud rate, data/stop bits and parity.
+    ///
+    /// Writes the baud rate registers then the line control register.
+    /// 1.5 stop bits aren't supported. Chips older than version 0x30
+    /// have no line control register and keep the 8N1 framing.
+    ///
+    /// # Cancellation
+    ///
+    /// Not cancel-safe: dropping the future between the two control
+    /// transfers leaves the device with the new baud rate but the old
+    /// framing. Re-issue the full line coding before resuming data
+    /// transfer.
+    pub async fn set_line_coding(&mut self, coding: &LineCoding) -> Result<(), Ch34xError> {
+        let lcr = lcr(coding).ok_or(Ch34xError::InvalidArgument)?;
+        self.set_baud_rate(coding.baud_rate).await?;
+        if self.version < VERSION_LCR {
+            return Ok(());
+        }
+        self.vendor_out(REQ_WRITE_REG, (REG_LCR2 << 8) | REG_LCR, lcr as u16)
+            .await
+    }
+
+    /// Drive DTR and RTS to the given levels.
+    ///
+    /// Issues `MODEM_CTRL`.
+    pub async fn set_control_line_state(&mut self, dtr: bool, rts: bool) -> Result<(), Ch34xError> {
+        let mut mcr = 0;
+        if dtr {
+            mcr |= MCR_DTR;
+        }
+        if rts {
+            mcr |= MCR_RTS;
+        }
+        // The lines are active low.
+        self.vendor_out(REQ_MODEM_CTRL, !mcr, 0).await
+    }
+
+    /// Read the modem status lines.
+    ///
+    /// Reads the modem status register. The chip doesn't report its DTR
+    /// and RTS outputs.
+    pub async fn modem_status(&mut self) -> Result<ModemStatus, Ch34xError> {
+        let mut buf = [0u8; 2];
+        self.vendor_in(REQ_READ_REG, REG_MODEM_STATUS, &mut buf).await?;
+        let msr = !buf[0];
+        let mut status = ModemStatus::empty();
+        status.set(ModemStatus::CTS, msr & MSR_CTS != 0);
+        status.set(ModemStatus::DSR, msr & MSR_DSR != 0);
+        status.set(ModemStatus::RI, msr & MSR_RI != 0);
+        status.set(ModemStatus::DCD, msr & MSR_DCD != 0);
+        Ok(status)
+    }
+
+    /// Assert or release a break condition on TX.
+    ///
+    /// Reads and rewrites the break and line control registers. Returns
+    /// [`Ch34xError::Unsupported`] on the parts without break support.
+    pub async fn set_break(&mut self, asserted: bool) -> Result<(), Ch34xError> {
+        if self.limited {
+            return Err(Ch34xError::Unsupported);
+        }
+        let reg = (REG_LCR << 8) | REG_BREAK;
+        let mut buf = [0u8; 2];
+        self.vendor_in(REQ_READ_REG, reg, &mut buf).await?;
+        if asserted {
+            buf[0] &= !NBREAK;
+            buf[1] &= !LCR_ENABLE_TX;
+        } else {
+            buf[0] |= NBREAK;
+            buf[1] |= LCR_ENABLE_TX;
+        }
+        self.vendor_out(REQ_WRITE_REG, reg, u16::from_le_bytes(buf)).await
+    }
+
+    /// Read bytes from the UART receive stream.
+    ///
+    /// # Cancellation
+    ///
+    /// Not cancel-safe: bytes already received from the device but
+    /// not yet copied into `buf` are lost if the future is dropped.
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Ch34xError> {
+        Ok(self.in_ch.request_in(buf).await?)
+    }
+
+    /// Write bytes to the UART transmit stream.
+    ///
+    /// # Cancellation
+    ///
+    /// Not cancel-safe: the remote may observe partial data if the
+    /// future is dropped mid-transfer.
+    pub async fn write(&mut self, data: &[u8]) -> Result<usize, Ch34xError> {
+        self.out_ch.request_out(data, false).await?;
+        Ok(data.len())
+    }
+}
+
+impl<'d, A: UsbHostAllocator<'d>> embedded_io_async::ErrorType for Ch34xHost<'d, A> {
+    type Error = Ch34xError;
+}
+
+impl<'d, A: UsbHostAllocator<'d>> embedded_io_async::Read for Ch34xHost<'d, A> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        Ch34xHost::read(self, buf).await
+    }
+}
+
+impl<'d, A: UsbHostAllocator<'d>> embedded_io_async::Write for Ch34xHost<'d, A> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        Ch34xHost::write(self, buf).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn baud_rate_divisors() {
+        assert_eq!(baud_divisor(9600, false), 0xB202);
+        assert_eq!(baud_divisor(115200, false), 0xCC03);
+        assert_eq!(baud_divisor(3_000_000, false), 0xFE03);
+        // Out-of-range rates are clamped.
+        assert_eq!(baud_divisor(10_000_000, false), 0xFE03);
+        assert_eq!(baud_divisor(1, false), baud_divisor(MIN_BAUD_RATE, false));
+        // Only prescaler 3 may use the full clock on limited parts.
+        assert_eq!(baud_divisor(9600, true) & 0x04, 0);
+    }
+
+    #[test]
+    fn line_control() {
+        assert_eq!(lcr(&LineCoding::default()), Some(0xC3));
+        let coding = LineCoding {
+            baud_rate: 9600,
+            data_bits: 7,
+            parity: Parity::Even,
+            stop_bits: StopBits::Two,
+        };
+        assert_eq!(lcr(&coding), Some(0xC0 | 0x02 | 0x18 | 0x04));
+        let coding = LineCoding {
+            stop_bits: StopBits::OneAndHalf,
+            ..coding
+        };
+        assert_eq!(lcr(&coding), None);
+    }
+}
@@ -48,6 +48,7 @@ use embassy_sync::mutex::Mutex;
 use embassy_usb_driver::host::{PipeError, SplitInfo, UsbHostAllocator, UsbPipe, pipe};
 use embassy_usb_driver::{Direction as UsbDirection, EndpointAddress, EndpointInfo, EndpointType};
 
+pub use super::{LineCoding, ModemStatus, Parity, StopBits};
 use crate::control::SetupPacket;
 use crate::descriptor::ConfigurationDescriptorChain;
 use crate::handler::EnumerationInfo;
@@ -60,6 +61,8 @@ pub mod id {
     pub const PID_CP210X: u16 = 0xEA60;
     /// Alternate CP210x product ID used by some SKUs.
     pub const PID_CP210X_ALT: u16 = 0xEA70;
+    /// CP2108 quad-port product ID.
+    pub const PID_CP2108: u16 = 0xEA71;
 }
 
 // AN571 §5, Table 6.
@@ -161,112 +164,6 @@ where
     Ok(BAUD_CLOCK / div as u32)
 }
 
-/// Parity setting.
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-#[repr(u8)]
-pub enum Parity {
-    /// No parity bit.
-    None = 0,
-    /// Odd parity.
-    Odd = 1,
-    /// Even parity.
-    Even = 2,
-    /// Always 1.
-    Mark = 3,
-    /// Always 0.
-    Space = 4,
-}
-
-impl Parity {
-    fn from_bits(b: u8) -> Option<Self> {
-        Some(match b {
-            0 => Self::None,
-            1 => Self::Odd,
-            2 => Self::Even,
-            3 => Self::Mark,
-            4 => Self::Space,
-            _ => return None,
-        })
-    }
-}
-
-/// Number of stop bits.
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-#[repr(u8)]
-pub enum StopBits {
-    /// 1 stop bit.
-    One = 0,
-    /// 1.5 stop bits.
-    OneAndHalf = 1,
-    /// 2 stop bits.
-    Two = 2,
-}
-
-impl StopBits {
-    fn from_bits(b: u8) -> Option<Self> {
-        Some(match b {
-            0 => Self::One,
-            1 => Self::OneAndHalf,
-            2 => Self::Two,
-            _ => return None,
-        })
-    }
-}
-
-/// Serial line parameters.
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub struct LineCoding {
-    /// Baud rate in bits per second.
-    pub baud_rate: u32,
-    /// Data bits. Legal values are 5, 6, 7 and 8.
-    pub data_bits: u8,
-    /// Parity setting.
-    pub parity: Parity,
-    /// Stop bits.
-    pub stop_bits: StopBits,
-}
-
-impl Default for LineCoding {
-    fn default() -> Self {
-        Self {
-            baud_rate: 115200,
-            data_bits: 8,
-            parity: Parity::None,
-            stop_bits: StopBits::One,
-        }
-    }
-}
-
-macro_rules! bitflags {
-    ($($tt:tt)*) => {
-        #[cfg(feature = "defmt")]
-        defmt::bitflags! { $($tt)* }
-        #[cfg(not(feature = "defmt"))]
-        bitflags::bitflags! { #[derive(Debug, Clone, PartialEq)] $($tt)* }
-    };
-}
-
-bitflags! {
-    /// Modem status byte returned by `GET_MDMSTS` (AN571 §5.10).
-    pub struct ModemStatus: u8 {
-        /// DTR output asserted.
-        const DTR = 1 << 0;
-        /// RTS output asserted.
-        const RTS = 1 << 1;
-        /// CTS input asserted.
-        const CTS = 1 << 4;
-        /// DSR input asserted.
-        const DSR = 1 << 5;
-        /// Ring indicator input asserted.
-        const RI  = 1 << 6;
-        /// Data-carrier-detect input asserted.
-        const DCD = 1 << 7;
-    }
-}
-
 bitflags! {
     /// Bitmask passed to [`Cp210xPort::purge`] (AN571 §5.27).
     pub struct PurgeMask: u16 {
@@ -0,0 +1,601 @@
+//! FTDI USB ↔ UART bridge driver.
+//!
+//! Implements the FTDI SIO protocol: vendor-class bulk data transport
+//! (one bulk IN + one bulk OUT per interface) plus vendor-device control
+//! requests for the baud rate, line format, modem signalling and break.
+//! Covers the FT232BM/R, FT-X, FT232H and the multi-port FT2232 and
+//! FT4232H parts.
+//!
+//! Every packet the chip sends on the bulk IN endpoint starts with two
+//! status bytes; [`FtdiPort::read`] strips them. The chip also sends
+//! status-only packets every latency timer period, see
+//! [`FtdiPort::set_latency_timer`].
+//!
+//! Like [`cp210x`](super::cp210x), a [`FtdiDevice`] owns the shared
+//! control pipe and each UART interface is opened as a [`FtdiPort`].
+//! [`FtdiDevice::new`] guards the control pipe with a `NoopRawMutex`; use
+//! [`FtdiDevice::new_with_raw_mutex`] with a `Sync` raw mutex to drive the
+//! ports of a multi-port part from several tasks.
+//!
+//! # Example
+//!
+//! ```rust,ignore
+//! use embassy_usb_host::class::vcp::ftdi::FtdiDevice;
+//! use embassy_usb_host::class::vcp::LineCoding;
+//!
+//! let device = FtdiDevice::new(&bus, &enum_info)?;
+//! let mut port = device.port(&config_buf[..config_len], 0)?;
+//! port.enable().await?;
+//! port.set_line_coding(&LineCoding::default()).await?;
+//! port.set_control_line_state(true, true).await?;
+//!
+//! let mut buf = [0u8; 64];
+//! let n = port.read(&mut buf).await?;
+//! port.write(&buf[..n]).await?;
+//! ```
+
+use core::marker::PhantomData;
+
+use embassy_sync::blocking_mutex::raw::{NoopRawMutex, RawMutex};
+use embassy_sync::mutex::Mutex;
+use embassy_usb_driver::host::{PipeError, SplitInfo, UsbHostAllocator, UsbPipe, pipe};
+use embassy_usb_driver::{Direction as UsbDirection, EndpointAddress, EndpointInfo, EndpointType};
+
+use super::{LineCoding, ModemStatus, find_port};
+use crate::control::SetupPacket;
+use crate::handler::EnumerationInfo;
+
+/// FTDI VID and PIDs.
+pub mod id {
+    /// Future Technology Devices International vendor ID.
+    pub const VID_FTDI: u16 = 0x0403;
+    /// FT232BM, FT232R and FT245 product ID.
+    pub const PID_FT232: u16 = 0x6001;
+    /// Dual-port FT2232C/D and FT2232H product ID.
+    pub const PID_FT2232: u16 = 0x6010;
+    /// Quad-port FT4232H product ID.
+    pub const PID_FT4232H: u16 = 0x6011;
+    /// FT232H product ID.
+    pub const PID_FT232H: u16 = 0x6014;
+    /// FT-X series (FT230X, FT231X, FT234XD, ...) product ID.
+    pub const PID_FTX: u16 = 0x6015;
+}
+
+// FTDI SIO vendor requests.
+const SIO_RESET: u8 = 0x00;
+const SIO_MODEM_CTRL: u8 = 0x01;
+const SIO_SET_BAUD_RATE: u8 = 0x03;
+const SIO_SET_DATA: u8 = 0x04;
+const SIO_GET_MODEM_STATUS: u8 = 0x05;
+const SIO_SET_LATENCY_TIMER: u8 = 0x09;
+
+const SIO_RESET_SIO: u16 = 0;
+
+/// `SET_DATA` bit asserting a break condition.
+const SET_DATA_BREAK: u16 = 1 << 14;
+
+/// Length of the status header of the bulk IN packets.
+const STATUS_LEN: usize = 2;
+
+/// Divisor fraction codes, indexed by eighths of the divisor.
+const DIV_FRAC: [u32; 8] = [0, 3, 2, 4, 1, 5, 6, 7];
+
+/// FTDI chip generation, identified from `bcdDevice`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ChipType {
+    /// FT232BM, FT245BM and unknown parts.
+    Bm,
+    /// FT2232C/D.
+    Ft2232C,
+    /// FT232R and FT245R.
+    R,
+    /// FT2232H.
+    Ft2232H,
+    /// FT4232H.
+    Ft4232H,
+    /// FT232H.
+    Ft232H,
+    /// FT-X series.
+    X,
+}
+
+impl ChipType {
+    /// Identify the chip from the `bcdDevice` field of its device descriptor.
+    pub fn from_bcd_device(bcd_device: u16) -> Self {
+        match bcd_device {
+            0x0500 => Self::Ft2232C,
+            0x0600 => Self::R,
+            0x0700 => Self::Ft2232H,
+            0x0800 | 0x3600 => Self::Ft4232H,
+            0x0900 => Self::Ft232H,
+            0x1000 => Self::X,
+            _ => Self::Bm,
+        }
+    }
+
+    /// Whether the chip has several UART interfaces, addressed by channel.
+    fn is_multi_port(self) -> bool {
+        matches!(self, Self::Ft2232C | Self::Ft2232H | Self::Ft4232H)
+    }
+
+    /// Whether the chip has the 120 MHz baud-rate clock of the Hi-Speed parts.
+    fn is_hi_speed(self) -> bool {
+        matches!(self, Self::Ft2232H | Self::Ft4232H | Self::Ft232H)
+    }
+}
+
+/// Encode the divisor of `clock / baud`, in the 14.3 fixed-point
+/// format of `SET_BAUD_RATE`.
+fn encode_divisor(clock: u32, baud: u32) -> Option<u32> {
+    let div8 = (clock * 8 + baud / 2) / baud;
+    let div = div8 >> 3;
+    if div8 < 8 || div > 0x3FFF {
+        return None;
+    }
+    // Divisors of 1 and 1.5 have dedicated encodings.
+    Some(match div | (DIV_FRAC[(div8 & 7) as usize] << 14) {
+        0x0001 => 0,
+        0x4001 => 1,
+        d => d,
+    })
+}
+
+/// Encode `baud` for `SET_BAUD_RATE`. Bit 17 selects the 120 MHz clock
+/// of the Hi-Speed parts.
+fn baud_divisor(chip: ChipType, baud: u32) -> Option<u32> {
+    if chip.is_hi_speed() && baud >= 1200 {
+        encode_divisor(12_000_000, baud).map(|d| d | (1 << 17))
+    } else {
+        encode_divisor(3_000_000, baud)
+    }
+}
+
+/// Remove the status header of each `mps`-byte packet in `buf`, and
+/// return the length of the remaining data.
+fn strip_status(buf: &mut [u8], mps: usize) -> usize {
+    let mut len = 0;
+    let mut start = 0;
+    while start < buf.len() {
+        let end = (start + mps).min(buf.len());
+        if end - start > STATUS_LEN {
+            buf.copy_within(start + STATUS_LEN..end, len);
+            len += end - start - STATUS_LEN;
+        }
+        start = end;
+    }
+    len
+}
+
+/// FTDI host driver error.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FtdiError {
+    /// Transfer error.
+    Transfer(PipeError),
+    /// No vendor-class interface at `interface_idx` with a bulk IN/OUT pair.
+    NoInterface,
+    /// Failed to allocate a pipe.
+    NoPipe,
+    /// Device response had an unexpected length.
+    InvalidResponse,
+    /// Argument was out of range for the FTDI protocol.
+    InvalidArgument,
+}
+
+impl From<PipeError> for FtdiError {
+    fn from(e: PipeError) -> Self {
+        Self::Transfer(e)
+    }
+}
+
+impl core::fmt::Display for FtdiError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Transfer(_) => write!(f, "Transfer error"),
+            Self::NoInterface => write!(f, "No FTDI interface found"),
+            Self::NoPipe => write!(f, "No free pipe"),
+            Self::InvalidResponse => write!(f, "Invalid response from device"),
+            Self::InvalidArgument => write!(f, "Invalid argument"),
+        }
+    }
+}
+
+impl core::error::Error for FtdiError {}
+
+impl embedded_io_async::Error for FtdiError {
+    fn kind(&self) -> embedded_io_async::ErrorKind {
+        match self {
+            Self::Transfer(e) => match e {
+                PipeError::Disconnected => embedded_io_async::ErrorKind::NotConnected,
+                PipeError::BufferOverflow => embedded_io_async::ErrorKind::OutOfMemory,
+                PipeError::Timeout => embedded_io_async::ErrorKind::TimedOut,
+                _ => embedded_io_async::ErrorKind::Other,
+            },
+            Self::NoInterface => embedded_io_async::ErrorKind::NotFound,
+            Self::NoPipe => embedded_io_async::ErrorKind::OutOfMemory,
+            Self::InvalidResponse => embedded_io_async::ErrorKind::InvalidData,
+            Self::InvalidArgument => embedded_io_async::ErrorKind::InvalidInput,
+        }
+    }
+}
+
+/// FTDI device — owns the shared control pipe on endpoint 0.
+///
+/// Open one [`FtdiPort`] per UART interface via [`FtdiDevice::port`].
+/// Control requests from all open ports are serialized through the
+/// device's internal async mutex; bulk I/O on different ports runs
+/// concurrently.
+pub struct FtdiDevice<'d, A, M = NoopRawMutex>
+where
+    A: UsbHostAllocator<'d>,
+    M: RawMutex,
+{
+    alloc: A,
+    ctrl: Mutex<M, A::Pipe<pipe::Control, pipe::InOut>>,
+    chip_type: ChipType,
+    device_address: u8,
+    split: Option<SplitInfo>,
+    _phantom: PhantomData<&'d ()>,
+}
+
+impl<'d, A> FtdiDevice<'d, A, NoopRawMutex>
+where
+    A: UsbHostAllocator<'d>,
+{
+    /// Allocate the device-level control pipe on endpoint 0, using a
+    /// [`NoopRawMutex`] for the shared control pipe.
+    ///
+    /// The resulting device is `!Sync`. For multi-task sharing, use
+    /// [`FtdiDevice::new_with_raw_mutex`] instead.
+    ///
+    /// Performs no I/O.
+    pub fn new(alloc: &A, enum_info: &EnumerationInfo) -> Result<Self, FtdiError> {
+        Self::new_with_raw_mutex(alloc, enum_info)
+    }
+}
+
+impl<'d, A, M> FtdiDevice<'d, A, M>
+where
+    A: UsbHostAllocator<'d>,
+    M: RawMutex,
+{
+    /// Allocate the device-level control pipe on endpoint 0, using
+    /// the caller-chosen raw mutex `M` for the shared control pipe.
+    ///
+    /// The chip generation is identified from `bcdDevice`.
+    ///
+    /// Performs no I/O.
+    pub fn new_with_raw_mutex(alloc: &A, enum_info: &EnumerationInfo) -> Result<Self, FtdiError> {
+        let ctrl_ep_info = EndpointInfo {
+            addr: EndpointAddress::from_parts(0, UsbDirection::In),
+            ep_type: EndpointType::Control,
+            max_packet_size: enum_info.device_desc.max_packet_size0 as u16,
+            interval_ms: 0,
+        };
+
+        let device_address = enum_info.device_address;
+        let split = enum_info.split();
+
+        let ctrl = alloc
+            .alloc_pipe::<pipe::Control, pipe::InOut>(device_address, &ctrl_ep_info, split)
+            .map_err(|_| FtdiError::NoPipe)?;
+
+        Ok(Self {
+            alloc: alloc.clone(),
+            ctrl: Mutex::new(ctrl),
+            chip_type: ChipType::from_bcd_device(enum_info.device_desc.bcd_device),
+            device_address,
+            split,
+            _phantom: PhantomData,
+        })
+    }
+
+    /// Chip generation of the device.
+    pub fn chip_type(&self) -> ChipType {
+        self.chip_type
+    }
+
+    /// Open the `interface_idx`-th UART port.
+    ///
+    /// Use `0` for single-port parts; `0..2` for FT2232; `0..4` for
+    /// FT4232H. Allocates the bulk pipes of the port.
+    ///
+    /// No I/O is performed with the device. The caller should call
+    /// [`FtdiPort::enable`] before transferring data.
+    ///
+    /// The driver does not validate which port is in use.
+    /// Avoid opening the same port multiple times.
+    pub fn port<'dev>(
+        &'dev self,
+        config_desc: &[u8],
+        interface_idx: u8,
+    ) -> Result<FtdiPort<'dev, 'd, A, M>, FtdiError> {
+        let info = find_port(config_desc, interface_idx).ok_or(FtdiError::NoInterface)?;
+
+        let in_ep_info = EndpointInfo {
+            addr: EndpointAddress::from_parts((info.bulk_in_ep & 0x0F) as usize, UsbDirection::In),
+            ep_type: EndpointType::Bulk,
+            max_packet_size: info.bulk_in_mps,
+            interval_ms: 0,
+        };
+
+        let out_ep_info = EndpointInfo {
+            addr: EndpointAddress::from_parts((info.bulk_out_ep & 0x0F) as usize, UsbDirection::Out),
+            ep_type: EndpointType::Bulk,
+            max_packet_size: info.bulk_out_mps,
+            interval_ms: 0,
+        };
+
+        let in_ch = self
+            .alloc
+            .alloc_pipe::<pipe::Bulk, pipe::In>(self.device_address, &in_ep_info, self.split)
+            .map_err(|_| FtdiError::NoPipe)?;
+        let out_ch = self
+            .alloc
+            .alloc_pipe::<pipe::Bulk, pipe::Out>(self.device_address, &out_ep_info, self.split)
+            .map_err(|_| FtdiError::NoPipe)?;
+
+        // Multi-port parts address their ports as channels A, B, ... (1, 2, ...).
+        let channel = if self.chip_type.is_multi_port() {
+            info.interface as u16 + 1
+        } else {
+            0
+        };
+
+        Ok(FtdiPort {
+            device: self,
+            in_ch,
+            out_ch,
+            interface: info.interface,
+            channel,
+            in_mps: info.bulk_in_mps,
+            data: 8,
+        })
+    }
+}
+
+/// A single UART port on a [`FtdiDevice`].
+///
+/// Owns the bulk IN/OUT pipes for one interface and borrows the
+/// device for control requests.
+pub struct FtdiPort<'dev, 'd, A, M = NoopRawMutex>
+where
+    A: UsbHostAllocator<'d>,
+    M: RawMutex,
+{
+    device: &'dev FtdiDevice<'d, A, M>,
+    in_ch: A::Pipe<pipe::Bulk, pipe::In>,
+    out_ch: A::Pipe<pipe::Bulk, pipe::Out>,
+    interface: u8,
+    channel: u16,
+    in_mps: u16,
+    /// Last `SET_DATA` value, without the break bit.
+    data: u16,
+}
+
+impl<'dev, 'd, A, M> FtdiPort<'dev, 'd, A, M>
+where
+    A: UsbHostAllocator<'d>,
+    M: RawMutex,
+{
+    /// USB interface number this port is bound to.
+    pub fn interface(&self) -> u8 {
+        self.interface
+    }
+
+    async fn vendor_out(&mut self, request: u8, value: u16, index: u16) -> Result<(), FtdiError> {
+        let setup = SetupPacket::vendor_device_out(request, value, index, 0);
+        let mut ctrl = self.device.ctrl.lock().await;
+        ctrl.control_out(&setup.to_bytes(), &[]).await?;
+        Ok(())
+    }
+
+    /// Reset the UART of the port.
+    ///
+    /// Issues `RESET(SIO)`, which clears the TX and RX buffers of the
+    /// port. The line settings are preserved.
+    pub async fn enable(&mut self) -> Result<(), FtdiError> {
+        self.vendor_out(SIO_RESET, SIO_RESET_SIO, self.channel).await
+    }
+
+    /// Program the UART baud rate in bauds per second.
+    ///
+    /// Issues `SET_BAUD_RATE` with the divisor of the 3 MHz baud-rate
+    /// clock, or of the 12 MHz clock of the Hi-Speed parts. The rate is
+    /// rounded to the nearest the divisor can express.
+    pub async fn set_baud_rate(&mut self, baud: u32) -> Result<(), FtdiError> {
+        if baud == 0 {
+            return Err(FtdiError::InvalidArgument);
+        }
+        let div = baud_divisor(self.device.chip_type, baud).ok_or(FtdiError::InvalidArgument)?;
+        // The bits above the 16 of `wValue` go to `wIndex`, in its high
+        // byte when its low byte selects the channel.
+        let index = match self.channel {
+            0 => (div >> 16) as u16,
+            channel => ((div >> 8) as u16 & 0xFF00) | channel,
+        };
+        self.vendor_out(SIO_SET_BAUD_RATE, div as u16, index).await
+    }
+
+    /// Program baud rate, data/stop bits and parity.
+    ///
+    /// Issues `SET_BAUD_RATE` followed by `SET_DATA`. Only 7 and 8 data
+    /// bits are supported.
+    ///
+    /// # Cancellation
+    ///
+    /// Not cancel-safe: dropping the future between the two control
+    /// transfers leaves the device with the new baud rate but the old
+    /// framing. Re-issue the full line coding before resuming data
+    /// transfer.
+    pub async fn set_line_coding(&mut self, coding: &LineCoding) -> Result<(), FtdiError> {
+        if !matches!(coding.data_bits, 7 | 8) {
+            return Err(FtdiError::InvalidArgument);
+        }
+        self.set_baud_rate(coding.baud_rate).await?;
+        let data = (coding.data_bits as u16) | ((coding.parity as u16) << 8) | ((coding.stop_bits as u16) << 11);
+        self.vendor_out(SIO_SET_DATA, data, self.channel).await?;
+        self.data = data;
+        Ok(())
+    }
+
+    /// Drive DTR and RTS to the given levels.
+    ///
+    /// Issues `MODEM_CTRL` with both the DTR and RTS masks set.
+    pub async fn set_control_line_state(&mut self, dtr: bool, rts: bool) -> Result<(), FtdiError> {
+        let value = (dtr as u16) | ((rts as u16) << 1) | (1 << 8) | (1 << 9);
+        self.vendor_out(SIO_MODEM_CTRL, value, self.channel).await
+    }
+
+    /// Read the modem status lines.
+    ///
+    /// Issues `GET_MODEM_STATUS`. The chip doesn't report its DTR and
+    /// RTS outputs.
+    pub async fn modem_status(&mut self) -> Result<ModemStatus, FtdiError> {
+        let setup = SetupPacket::vendor_device_in(SIO_GET_MODEM_STATUS, 0, self.channel, 2);
+        let mut buf = [0u8; 2];
+        let n = self
+            .device
+            .ctrl
+            .lock()
+            .await
+            .control_in(&setup.to_bytes(), &mut buf)
+            .await?;
+        if n != buf.len() {
+            return Err(FtdiError::InvalidResponse);
+        }
+        // CTS, DSR, RI and DCD are in the high nibble, as in `ModemStatus`.
+        Ok(ModemStatus::from_bits_truncate(buf[0] & 0xF0))
+    }
+
+    /// Assert or release a break condition on TX.
+    ///
+    /// Issues `SET_DATA` with the break bit and the line format of the
+    /// last [`set_line_coding`](Self::set_line_coding) (8N1 if none).
+    pub async fn set_break(&mut self, asserted: bool) -> Result<(), FtdiError> {
+        let value = if asserted {
+            self.data | SET_DATA_BREAK
+        } else {
+            self.data
+        };
+        self.vendor_out(SIO_SET_DATA, value, self.channel).await
+    }
+
+    /// Set the latency timer in milliseconds (1 to 255, 16 by default).
+    ///
+    /// The chip sends the data it received when its buffer is full, or
+    /// after the latency timer expires. Lower values reduce latency at
+    /// the cost of more, smaller transfers.
+    pub async fn set_latency_timer(&mut self, ms: u8) -> Result<(), FtdiError> {
+        if ms == 0 {
+            return Err(FtdiError::InvalidArgument);
+        }
+        self.vendor_out(SIO_SET_LATENCY_TIMER, ms as u16, self.channel).await
+    }
+
+    /// Read bytes from the UART receive stream.
+    ///
+    /// Strips the status bytes of the received packets, and waits for
+    /// more if they carried no data. `buf` should be at least the max
+    /// packet size of the bulk IN endpoint (64 bytes at full speed, 512
+    /// at high speed).
+    ///
+    /// # Cancellation
+    ///
+    /// Not cancel-safe: bytes already received from the device but
+    /// not yet copied into `buf` are lost if the future is dropped.
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize, FtdiError> {
+        loop {
+            let n = self.in_ch.request_in(buf).await?;
+            let n = strip_status(&mut buf[..n], self.in_mps as usize);
+            if n > 0 {
+                return Ok(n);
+            }
+        }
+    }
+
+    /// Write bytes to the UART transmit stream.
+    ///
+    /// # Cancellation
+    ///
+    /// Not cancel-safe: the remote may observe partial data if the
+    /// future is dropped mid-transfer.
+    pub async fn write(&mut self, data: &[u8]) -> Result<usize, FtdiError> {
+        self.out_ch.request_out(data, false).await?;
+        Ok(data.len())
+    }
+}
+
+impl<'dev, 'd, A, M> embedded_io_async::ErrorType for FtdiPort<'dev, 'd, A, M>
+where
+    A: UsbHostAllocator<'d>,
+    M: RawMutex,
+{
+    type Error = FtdiError;
+}
+
+impl<'dev, 'd, A, M> embedded_io_async::Read for FtdiPort<'dev, 'd, A, M>
+where
+    A: UsbHostAllocator<'d>,
+    M: RawMutex,
+{
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        FtdiPort::read(self, buf).await
+    }
+}
+
+impl<'dev, 'd, A, M> embedded_io_async::Write for FtdiPort<'dev, 'd, A, M>
+where
+    A: UsbHostAllocator<'d>,
+    M: RawMutex,
+{
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        FtdiPort::write(self, buf).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn baud_rate_divisors() {
+        assert_eq!(baud_divisor(ChipType::R, 9600), Some(0x4138));
+        assert_eq!(baud_divisor(ChipType::R, 115200), Some(0x001A));
+        assert_eq!(baud_divisor(ChipType::R, 3_000_000), Some(0));
+        assert_eq!(baud_divisor(ChipType::R, 2_000_000), Some(1));
+        assert_eq!(baud_divisor(ChipType::R, 6_000_000), None);
+        assert_eq!(baud_divisor(ChipType::R, 100), None);
+
+        // Hi-Speed parts use the 12 MHz clock from 1200 baud.
+        assert_eq!(baud_divisor(ChipType::Ft232H, 115200), Some(0x2_C068));
+        assert_eq!(baud_divisor(ChipType::Ft232H, 12_000_000), Some(0x2_0000));
+        assert_eq!(baud_divisor(ChipType::Ft232H, 300), Some(0x2710));
+    }
+
+    #[test]
+    fn chip_types() {
+        assert_eq!(ChipType::from_bcd_device(0x0600), ChipType::R);
+        assert_eq!(ChipType::from_bcd_device(0x0700), ChipType::Ft2232H);
+        assert_eq!(ChipType::from_bcd_device(0x0400), ChipType::Bm);
+        assert!(ChipType::Ft2232C.is_multi_port());
+        assert!(!ChipType::Ft232H.is_multi_port());
+        assert!(ChipType::Ft232H.is_hi_speed());
+    }
+
+    #[test]
+    fn strip_status_bytes() {
+        let mut buf = [0x01, 0x60, b'a', b'b', 0x01, 0x60, b'c'];
+        assert_eq!(strip_status(&mut buf, 4), 3);
+        assert_eq!(&buf[..3], b"abc");
+
+        // Status-only packet.
+        let mut buf = [0x01, 0x60];
+        assert_eq!(strip_status(&mut buf, 64), 0);
+    }
+}
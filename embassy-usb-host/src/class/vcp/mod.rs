@@ -4,6 +4,298 @@
 //! transport data over bulk pipes and expose private control requests
 //! for line configuration, flow control, and modem signalling. Unlike
 //! CDC-ACM they carry no class descriptors, so device discovery is
-//! VID/PID based.
+//! VID/PID based: [`probe`] tells which driver handles a device.
+//!
+//! All drivers share the [`LineCoding`] and [`ModemStatus`] types, and
+//! offer the same set of port methods: `enable`, `set_line_coding`,
+//! `set_control_line_state`, `set_break`, and `embedded_io_async`
+//! `Read` / `Write`.
+//!
+//! # Example
+//!
+//! ```rust,ignore
+//! use embassy_usb_host::class::vcp::{Chip, LineCoding, probe};
+//! use embassy_usb_host::class::vcp::ch34x::Ch34xHost;
+//!
+//! match probe(&enum_info) {
+//!     Some(Chip::Ch34x) => {
+//!         let mut port = Ch34xHost::new(&bus, &config_buf[..config_len], &enum_info)?;
+//!         port.enable().await?;
+//!         port.set_line_coding(&LineCoding::default()).await?;
+//!         port.set_control_line_state(true, true).await?;
+//!     }
+//!     Some(chip) => info!("{:?} adapter", chip),
+//!     None => info!("Not a USB-serial adapter"),
+//! }
+//! ```
 
+macro_rules! bitflags {
+    ($($tt:tt)*) => {
+        #[cfg(feature = "defmt")]
+        defmt::bitflags! { $($tt)* }
+        #[cfg(not(feature = "defmt"))]
+        bitflags::bitflags! { #[derive(Debug, Clone, PartialEq)] $($tt)* }
+    };
+}
+
+pub mod ch34x;
 pub mod cp210x;
+pub mod ftdi;
+pub mod pl2303;
+
+use embassy_usb_driver::EndpointType;
+
+use crate::descriptor::ConfigurationDescriptorChain;
+use crate::handler::EnumerationInfo;
+
+const VENDOR_CLASS: u8 = 0xFF;
+
+/// Parity setting.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Parity {
+    /// No parity bit.
+    None = 0,
+    /// Odd parity.
+    Odd = 1,
+    /// Even parity.
+    Even = 2,
+    /// Always 1.
+    Mark = 3,
+    /// Always 0.
+    Space = 4,
+}
+
+impl Parity {
+    fn from_bits(b: u8) -> Option<Self> {
+        Some(match b {
+            0 => Self::None,
+            1 => Self::Odd,
+            2 => Self::Even,
+            3 => Self::Mark,
+            4 => Self::Space,
+            _ => return None,
+        })
+    }
+}
+
+/// Number of stop bits.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum StopBits {
+    /// 1 stop bit.
+    One = 0,
+    /// 1.5 stop bits.
+    OneAndHalf = 1,
+    /// 2 stop bits.
+    Two = 2,
+}
+
+impl StopBits {
+    fn from_bits(b: u8) -> Option<Self> {
+        Some(match b {
+            0 => Self::One,
+            1 => Self::OneAndHalf,
+            2 => Self::Two,
+            _ => return None,
+        })
+    }
+}
+
+/// Serial line parameters.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LineCoding {
+    /// Baud rate in bits per second.
+    pub baud_rate: u32,
+    /// Data bits. Legal values are 5, 6, 7 and 8.
+    pub data_bits: u8,
+    /// Parity setting.
+    pub parity: Parity,
+    /// Stop bits.
+    pub stop_bits: StopBits,
+}
+
+impl Default for LineCoding {
+    fn default() -> Self {
+        Self {
+            baud_rate: 115200,
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+        }
+    }
+}
+
+bitflags! {
+    /// Modem status lines.
+    ///
+    /// Uses the layout of the CP210x `GET_MDMSTS` response (AN571 §5.10).
+    /// Only CP210x reports the state of its DTR and RTS outputs.
+    pub struct ModemStatus: u8 {
+        /// DTR output asserted.
+        const DTR = 1 << 0;
+        /// RTS output asserted.
+        const RTS = 1 << 1;
+        /// CTS input asserted.
+        const CTS = 1 << 4;
+        /// DSR input asserted.
+        const DSR = 1 << 5;
+        /// Ring indicator input asserted.
+        const RI  = 1 << 6;
+        /// Data-carrier-detect input asserted.
+        const DCD = 1 << 7;
+    }
+}
+
+/// USB-serial chip family, as identified by [`probe`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Chip {
+    /// Silicon Labs CP210x, handled by [`cp210x`].
+    Cp210x,
+    /// FTDI FT232, FT2232, FT4232 and FT-X, handled by [`ftdi`].
+    Ftdi,
+    /// WCH CH340 and CH341, handled by [`ch34x`].
+    Ch34x,
+    /// Prolific PL2303, handled by [`pl2303`].
+    Pl2303,
+}
+
+/// Identify the USB-serial chip of a device from its VID/PID.
+///
+/// Returns `None` if the device isn't a chip supported by one of the
+/// drivers of this module. Devices with a custom VID/PID aren't
+/// recognized; open them with the right driver directly.
+pub fn probe(enum_info: &EnumerationInfo) -> Option<Chip> {
+    let desc = &enum_info.device_desc;
+    match (desc.vendor_id, desc.product_id) {
+        (cp210x::id::VID_SILABS, cp210x::id::PID_CP210X | cp210x::id::PID_CP210X_ALT | cp210x::id::PID_CP2108) => {
+            Some(Chip::Cp210x)
+        }
+        (
+            ftdi::id::VID_FTDI,
+            ftdi::id::PID_FT232
+            | ftdi::id::PID_FT2232
+            | ftdi::id::PID_FT4232H
+            | ftdi::id::PID_FT232H
+            | ftdi::id::PID_FTX,
+        ) => Some(Chip::Ftdi),
+        (ch34x::id::VID_WCH, ch34x::id::PID_CH340 | ch34x::id::PID_CH340K | ch34x::id::PID_CH341) => Some(Chip::Ch34x),
+        (
+            pl2303::id::VID_PROLIFIC,
+            pl2303::id::PID_PL2303
+            | pl2303::id::PID_PL2303GC
+            | pl2303::id::PID_PL2303GB
+            | pl2303::id::PID_PL2303GT
+            | pl2303::id::PID_PL2303GL
+            | pl2303::id::PID_PL2303GE
+            | pl2303::id::PID_PL2303GS,
+        ) => Some(Chip::Pl2303),
+        _ => None,
+    }
+}
+
+/// Descriptor-located info for a vendor-class UART interface.
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PortInfo {
+    /// USB interface number.
+    pub interface: u8,
+    /// Bulk IN endpoint address.
+    pub bulk_in_ep: u8,
+    /// Bulk IN max packet size.
+    pub bulk_in_mps: u16,
+    /// Bulk OUT endpoint address.
+    pub bulk_out_ep: u8,
+    /// Bulk OUT max packet size.
+    pub bulk_out_mps: u16,
+    /// Interrupt IN endpoint address, if the interface has one.
+    pub interrupt_in_ep: Option<u8>,
+    /// Interrupt IN max packet size.
+    pub interrupt_in_mps: u16,
+    /// Interrupt IN polling interval (from endpoint descriptor).
+    pub interrupt_in_interval: u8,
+}
+
+/// Return the `n`th (0-indexed) vendor-class interface in `config_desc`
+/// that exposes a bulk IN + bulk OUT endpoint pair.
+pub fn find_port(config_desc: &[u8], interface_idx: u8) -> Option<PortInfo> {
+    let cfg = ConfigurationDescriptorChain::try_from_slice(config_desc).ok()?;
+
+    let mut seen = 0u8;
+    for iface in cfg.iter_interface() {
+        if iface.interface_class != VENDOR_CLASS || iface.alternate_setting != 0 {
+            continue;
+        }
+
+        let mut in_ep = None;
+        let mut out_ep = None;
+        let mut int_ep = None;
+        for ep in iface.iter_endpoints() {
+            match (ep.ep_type(), ep.is_in()) {
+                (EndpointType::Bulk, true) => in_ep = Some((ep.endpoint_address, ep.max_packet_size)),
+                (EndpointType::Bulk, false) => out_ep = Some((ep.endpoint_address, ep.max_packet_size)),
+                (EndpointType::Interrupt, true) => {
+                    int_ep = Some((ep.endpoint_address, ep.max_packet_size, ep.interval))
+                }
+                _ => {}
+            }
+        }
+
+        if let (Some((in_a, in_m)), Some((out_a, out_m))) = (in_ep, out_ep) {
+            if seen == interface_idx {
+                return Some(PortInfo {
+                    interface: iface.interface_number,
+                    bulk_in_ep: in_a,
+                    bulk_in_mps: in_m,
+                    bulk_out_ep: out_a,
+                    bulk_out_mps: out_m,
+                    interrupt_in_ep: int_ep.map(|(a, _, _)| a),
+                    interrupt_in_mps: int_ep.map_or(0, |(_, m, _)| m),
+                    interrupt_in_interval: int_ep.map_or(0, |(_, _, i)| i),
+                });
+            }
+            seen += 1;
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// PL2303 configuration: one vendor interface with an interrupt IN,
+    /// a bulk OUT and a bulk IN endpoint.
+    #[rustfmt::skip]
+    const CFG_PL2303: [u8; 39] = [
+        9, 0x02, 39, 0, 1, 1, 0, 0x80, 50,
+        9, 0x04, 0, 0, 3, 0xFF, 0x00, 0x00, 0,
+        7, 0x05, 0x81, 0x03, 0x0A, 0x00, 1,
+        7, 0x05, 0x02, 0x02, 0x40, 0x00, 0,
+        7, 0x05, 0x83, 0x02, 0x40, 0x00, 0,
+    ];
+
+    #[test]
+    fn find_vendor_port() {
+        let info = find_port(&CFG_PL2303, 0).unwrap();
+        assert_eq!(info.interface, 0);
+        assert_eq!(info.bulk_in_ep, 0x83);
+        assert_eq!(info.bulk_in_mps, 64);
+        assert_eq!(info.bulk_out_ep, 0x02);
+        assert_eq!(info.bulk_out_mps, 64);
+        assert_eq!(info.interrupt_in_ep, Some(0x81));
+        assert_eq!(info.interrupt_in_mps, 10);
+        assert_eq!(info.interrupt_in_interval, 1);
+        assert!(find_port(&CFG_PL2303, 1).is_none());
+
+        // Not a vendor-class interface.
+        let mut cfg = CFG_PL2303;
+        cfg[14] = 0x0A;
+        assert!(find_port(&cfg, 0).is_none());
+    }
+}
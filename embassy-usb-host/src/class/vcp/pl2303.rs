@@ -0,0 +1,426 @@
+//! Prolific PL2303 USB ↔ UART bridge driver.
+//!
+//! The PL2303 takes the CDC-ACM line coding, control line and break
+//! requests on its vendor-class interface, plus vendor-device requests
+//! for its initialization. Modem status changes are notified on an
+//! interrupt IN endpoint. Covers the legacy PL2303, the PL2303HX/TA/TB
+//! and the PL2303G (HXN) series.
+//!
+//! The parts are single-port, so a [`Pl2303Host`] owns all the pipes.
+//!
+//! # Example
+//!
+//! ```rust,ignore
+//! use embassy_usb_host::class::vcp::pl2303::Pl2303Host;
+//! use embassy_usb_host::class::vcp::LineCoding;
+//!
+//! let mut port = Pl2303Host::new(&bus, &config_buf[..config_len], &enum_info)?;
+//! port.enable().await?;
+//! port.set_line_coding(&LineCoding::default()).await?;
+//! port.set_control_line_state(true, true).await?;
+//!
+//! let mut buf = [0u8; 64];
+//! let n = port.read(&mut buf).await?;
+//! port.write(&buf[..n]).await?;
+//! ```
+
+use core::marker::PhantomData;
+
+use embassy_usb_driver::host::{PipeError, UsbHostAllocator, UsbPipe, pipe};
+use embassy_usb_driver::{Direction as UsbDirection, EndpointAddress, EndpointInfo, EndpointType};
+
+use super::{LineCoding, ModemStatus, Parity, StopBits, find_port};
+use crate::control::SetupPacket;
+use crate::handler::EnumerationInfo;
+
+/// Prolific VID and PL2303 PIDs.
+pub mod id {
+    /// Prolific Technology vendor ID.
+    pub const VID_PROLIFIC: u16 = 0x067B;
+    /// PL2303, PL2303HX, PL2303TA and PL2303TB product ID.
+    pub const PID_PL2303: u16 = 0x2303;
+    /// PL2303GC product ID.
+    pub const PID_PL2303GC: u16 = 0x23A3;
+    /// PL2303GB product ID.
+    pub const PID_PL2303GB: u16 = 0x23B3;
+    /// PL2303GT product ID.
+    pub const PID_PL2303GT: u16 = 0x23C3;
+    /// PL2303GL product ID.
+    pub const PID_PL2303GL: u16 = 0x23D3;
+    /// PL2303GE product ID.
+    pub const PID_PL2303GE: u16 = 0x23E3;
+    /// PL2303GS product ID.
+    pub const PID_PL2303GS: u16 = 0x23F3;
+}
+
+// CDC-ACM requests (CDC PSTN 1.2 §6.3).
+const SET_LINE_CODING: u8 = 0x20;
+const GET_LINE_CODING: u8 = 0x21;
+const SET_CONTROL_LINE_STATE: u8 = 0x22;
+const SEND_BREAK: u8 = 0x23;
+
+// Vendor requests.
+const VENDOR_READ: u8 = 0x01;
+const VENDOR_WRITE: u8 = 0x01;
+const VENDOR_READ_HXN: u8 = 0x81;
+const VENDOR_WRITE_HXN: u8 = 0x80;
+
+const HXN_RESET_REG: u16 = 0x07;
+const HXN_RESET_PIPES: u16 = 0x03;
+
+/// Offset of the UART state in the interrupt notifications.
+const UART_STATE_INDEX: usize = 8;
+const UART_DCD: u8 = 0x01;
+const UART_DSR: u8 = 0x02;
+const UART_RING: u8 = 0x08;
+const UART_CTS: u8 = 0x80;
+
+/// Rates the chips generate without a divisor.
+const STANDARD_BAUD_RATES: [u32; 25] = [
+    75, 150, 300, 600, 1200, 1800, 2400, 3600, 4800, 7200, 9600, 14400, 19200, 28800, 38400, 57600, 115200, 230400,
+    460800, 614400, 921600, 1228800, 2457600, 3000000, 6000000,
+];
+
+/// Encode `baud` for the `dwDTERate` field of `SET_LINE_CODING`.
+///
+/// The PL2303G series takes any rate directly. The other parts take the
+/// standard rates directly, and the others as a divisor of the 12 MHz
+/// clock, flagged by bit 31.
+fn encode_baud_rate(baud: u32, hxn: bool) -> [u8; 4] {
+    if hxn || STANDARD_BAUD_RATES.contains(&baud) {
+        return baud.to_le_bytes();
+    }
+
+    // baud = 12 MHz * 32 / (mantissa * 4^exponent)
+    let mut mantissa = (12_000_000 * 32 / baud).max(1);
+    let mut exponent = 0;
+    while mantissa >= 512 {
+        if exponent < 7 {
+            mantissa >>= 2;
+            exponent += 1;
+        } else {
+            mantissa = 511;
+            break;
+        }
+    }
+    [mantissa as u8, ((exponent << 1) | (mantissa >> 8)) as u8, 0, 0x80]
+}
+
+/// PL2303 host driver error.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Pl2303Error {
+    /// Transfer error.
+    Transfer(PipeError),
+    /// No vendor-class interface with bulk IN/OUT and interrupt IN endpoints.
+    NoInterface,
+    /// Failed to allocate a pipe.
+    NoPipe,
+    /// Device response had an unexpected length or out-of-range field.
+    InvalidResponse,
+    /// Argument was out of range for the PL2303 protocol.
+    InvalidArgument,
+}
+
+impl From<PipeError> for Pl2303Error {
+    fn from(e: PipeError) -> Self {
+        Self::Transfer(e)
+    }
+}
+
+impl core::fmt::Display for Pl2303Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Transfer(_) => write!(f, "Transfer error"),
+            Self::NoInterface => write!(f, "No PL2303 interface found"),
+            Self::NoPipe => write!(f, "No free pipe"),
+            Self::InvalidResponse => write!(f, "Invalid response from device"),
+            Self::InvalidArgument => write!(f, "Invalid argument"),
+        }
+    }
+}
+
+impl core::error::Error for Pl2303Error {}
+
+impl embedded_io_async::Error for Pl2303Error {
+    fn kind(&self) -> embedded_io_async::ErrorKind {
+        match self {
+            Self::Transfer(e) => match e {
+                PipeError::Disconnected => embedded_io_async::ErrorKind::NotConnected,
+                PipeError::BufferOverflow => embedded_io_async::ErrorKind::OutOfMemory,
+                PipeError::Timeout => embedded_io_async::ErrorKind::TimedOut,
+                _ => embedded_io_async::ErrorKind::Other,
+            },
+            Self::NoInterface => embedded_io_async::ErrorKind::NotFound,
+            Self::NoPipe => embedded_io_async::ErrorKind::OutOfMemory,
+            Self::InvalidResponse => embedded_io_async::ErrorKind::InvalidData,
+            Self::InvalidArgument => embedded_io_async::ErrorKind::InvalidInput,
+        }
+    }
+}
+
+/// PL2303 host driver.
+pub struct Pl2303Host<'d, A: UsbHostAllocator<'d>> {
+    ctrl_ch: A::Pipe<pipe::Control, pipe::InOut>,
+    in_ch: A::Pipe<pipe::Bulk, pipe::In>,
+    out_ch: A::Pipe<pipe::Bulk, pipe::Out>,
+    notify_ch: A::Pipe<pipe::Interrupt, pipe::In>,
+    interface: u8,
+    /// PL2303G series, with different vendor requests.
+    hxn: bool,
+    /// Original PL2303, with a different initialization value.
+    legacy: bool,
+    _phantom: PhantomData<&'d ()>,
+}
+
+impl<'d, A: UsbHostAllocator<'d>> Pl2303Host<'d, A> {
+    /// Create a new PL2303 host driver.
+    ///
+    /// Parses the config descriptor to find the endpoints and allocates
+    /// the pipes. The chip generation is identified from the device
+    /// descriptor. Performs no I/O; call [`enable`](Self::enable)
+    /// before use.
+    pub fn new(alloc: &A, config_desc: &[u8], enum_info: &EnumerationInfo) -> Result<Self, Pl2303Error> {
+        let info = find_port(config_desc, 0).ok_or(Pl2303Error::NoInterface)?;
+        let notify_ep = info.interrupt_in_ep.ok_or(Pl2303Error::NoInterface)?;
+
+        let ctrl_ep_info = EndpointInfo {
+            addr: EndpointAddress::from_parts(0, UsbDirection::In),
+            ep_type: EndpointType::Control,
+            max_packet_size: enum_info.device_desc.max_packet_size0 as u16,
+            interval_ms: 0,
+        };
+
+        let in_ep_info = EndpointInfo {
+            addr: EndpointAddress::from_parts((info.bulk_in_ep & 0x0F) as usize, UsbDirection::In),
+            ep_type: EndpointType::Bulk,
+            max_packet_size: info.bulk_in_mps,
+            interval_ms: 0,
+        };
+
+        let out_ep_info = EndpointInfo {
+            addr: EndpointAddress::from_parts((info.bulk_out_ep & 0x0F) as usize, UsbDirection::Out),
+            ep_type: EndpointType::Bulk,
+            max_packet_size: info.bulk_out_mps,
+            interval_ms: 0,
+        };
+
+        let notify_ep_info = EndpointInfo {
+            addr: EndpointAddress::from_parts((notify_ep & 0x0F) as usize, UsbDirection::In),
+            ep_type: EndpointType::Interrupt,
+            max_packet_size: info.interrupt_in_mps,
+            interval_ms: info.interrupt_in_interval,
+        };
+
+        let device_address = enum_info.device_address;
+        let split = enum_info.split();
+
+        let ctrl_ch = alloc
+            .alloc_pipe::<pipe::Control, pipe::InOut>(device_address, &ctrl_ep_info, split)
+            .map_err(|_| Pl2303Error::NoPipe)?;
+        let in_ch = alloc
+            .alloc_pipe::<pipe::Bulk, pipe::In>(device_address, &in_ep_info, split)
+            .map_err(|_| Pl2303Error::NoPipe)?;
+        let out_ch = alloc
+            .alloc_pipe::<pipe::Bulk, pipe::Out>(device_address, &out_ep_info, split)
+            .map_err(|_| Pl2303Error::NoPipe)?;
+        let notify_ch = alloc
+            .alloc_pipe::<pipe::Interrupt, pipe::In>(device_address, &notify_ep_info, split)
+            .map_err(|_| Pl2303Error::NoPipe)?;
+
+        let desc = &enum_info.device_desc;
+        Ok(Self {
+            ctrl_ch,
+            in_ch,
+            out_ch,
+            notify_ch,
+            interface: info.interface,
+            hxn: desc.product_id != id::PID_PL2303,
+            legacy: desc.device_class == 0x02 || desc.max_packet_size0 != 64,
+            _phantom: PhantomData,
+        })
+    }
+
+    async fn vendor_read(&mut self, value: u16) -> Result<u8, Pl2303Error> {
+        let request = if self.hxn { VENDOR_READ_HXN } else { VENDOR_READ };
+        let setup = SetupPacket::vendor_device_in(request, value, 0, 1);
+        let mut buf = [0u8; 1];
+        let n = self.ctrl_ch.control_in(&setup.to_bytes(), &mut buf).await?;
+        if n != buf.len() {
+            return Err(Pl2303Error::InvalidResponse);
+        }
+        Ok(buf[0])
+    }
+
+    async fn vendor_write(&mut self, value: u16, index: u16) -> Result<(), Pl2303Error> {
+        let request = if self.hxn { VENDOR_WRITE_HXN } else { VENDOR_WRITE };
+        let setup = SetupPacket::vendor_device_out(request, value, index, 0);
+        self.ctrl_ch.control_out(&setup.to_bytes(), &[]).await?;
+        Ok(())
+    }
+
+    /// Initialize the chip and reset its data pipes.
+    ///
+    /// Runs the vendor initialization sequence of the chip. The line
+    /// coding must be set again afterwards.
+    pub async fn enable(&mut self) -> Result<(), Pl2303Error> {
+        if self.hxn {
+            return self.vendor_write(HXN_RESET_REG, HXN_RESET_PIPES).await;
+        }
+
+        // The values read back don't matter, but the sequence does.
+        self.vendor_read(0x8484).await?;
+        self.vendor_write(0x0404, 0).await?;
+        self.vendor_read(0x8484).await?;
+        self.vendor_read(0x8383).await?;
+        self.vendor_read(0x8484).await?;
+        self.vendor_write(0x0404, 1).await?;
+        self.vendor_read(0x8484).await?;
+        self.vendor_read(0x8383).await?;
+        self.vendor_write(0, 1).await?;
+        self.vendor_write(1, 0).await?;
+        self.vendor_write(2, if self.legacy { 0x24 } else { 0x44 }).await?;
+
+        // Reset the data pipes.
+        self.vendor_write(8, 0).await?;
+        self.vendor_write(9, 0).await
+    }
+
+    /// Program baud rate, data/stop bits and parity.
+    ///
+    /// Issues `SET_LINE_CODING`. Except on the PL2303G series, rates
+    /// other than the standard ones are approximated with a divisor.
+    pub async fn set_line_coding(&mut self, coding: &LineCoding) -> Result<(), Pl2303Error> {
+        if coding.baud_rate == 0 || !matches!(coding.data_bits, 5..=8) {
+            return Err(Pl2303Error::InvalidArgument);
+        }
+        let mut data = [0u8; 7];
+        data[0..4].copy_from_slice(&encode_baud_rate(coding.baud_rate, self.hxn));
+        data[4] = coding.stop_bits as u8;
+        data[5] = coding.parity as u8;
+        data[6] = coding.data_bits;
+        let setup = SetupPacket::class_interface_out(SET_LINE_CODING, 0, self.interface as u16, data.len() as u16);
+        self.ctrl_ch.control_out(&setup.to_bytes(), &data).await?;
+        Ok(())
+    }
+
+    /// Read baud rate, data/stop bits and parity.
+    ///
+    /// Issues `GET_LINE_CODING`. Rates set with a divisor are returned
+    /// in their encoded form.
+    pub async fn line_coding(&mut self) -> Result<LineCoding, Pl2303Error> {
+        let mut buf = [0u8; 7];
+        let setup = SetupPacket::class_interface_in(GET_LINE_CODING, 0, self.interface as u16, buf.len() as u16);
+        let n = self.ctrl_ch.control_in(&setup.to_bytes(), &mut buf).await?;
+        if n != buf.len() {
+            return Err(Pl2303Error::InvalidResponse);
+        }
+        Ok(LineCoding {
+            baud_rate: u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]),
+            stop_bits: StopBits::from_bits(buf[4]).ok_or(Pl2303Error::InvalidResponse)?,
+            parity: Parity::from_bits(buf[5]).ok_or(Pl2303Error::InvalidResponse)?,
+            data_bits: buf[6],
+        })
+    }
+
+    /// Drive DTR and RTS to the given levels.
+    ///
+    /// Issues `SET_CONTROL_LINE_STATE`.
+    pub async fn set_control_line_state(&mut self, dtr: bool, rts: bool) -> Result<(), Pl2303Error> {
+        let value = (dtr as u16) | ((rts as u16) << 1);
+        let setup = SetupPacket::class_interface_out(SET_CONTROL_LINE_STATE, value, self.interface as u16, 0);
+        self.ctrl_ch.control_out(&setup.to_bytes(), &[]).await?;
+        Ok(())
+    }
+
+    /// Assert or release a break condition on TX.
+    ///
+    /// Issues `SEND_BREAK`.
+    pub async fn set_break(&mut self, asserted: bool) -> Result<(), Pl2303Error> {
+        let value = if asserted { 0xFFFF } else { 0 };
+        let setup = SetupPacket::class_interface_out(SEND_BREAK, value, self.interface as u16, 0);
+        self.ctrl_ch.control_out(&setup.to_bytes(), &[]).await?;
+        Ok(())
+    }
+
+    /// Wait for the next modem status notification of the device.
+    ///
+    /// The chip can't be asked for its modem status; it notifies it on
+    /// its interrupt endpoint when it changes. It doesn't report its DTR
+    /// and RTS outputs.
+    pub async fn wait_modem_status(&mut self) -> Result<ModemStatus, Pl2303Error> {
+        let mut buf = [0u8; 16];
+        loop {
+            let n = self.notify_ch.request_in(&mut buf).await?;
+            if n <= UART_STATE_INDEX {
+                continue;
+            }
+            let state = buf[UART_STATE_INDEX];
+            let mut status = ModemStatus::empty();
+            status.set(ModemStatus::DCD, state & UART_DCD != 0);
+            status.set(ModemStatus::DSR, state & UART_DSR != 0);
+            status.set(ModemStatus::RI, state & UART_RING != 0);
+            status.set(ModemStatus::CTS, state & UART_CTS != 0);
+            return Ok(status);
+        }
+    }
+
+    /// Read bytes from the UART receive stream.
+    ///
+    /// # Cancellation
+    ///
+    /// Not cancel-safe: bytes already received from the device but
+    /// not yet copied into `buf` are lost if the future is dropped.
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Pl2303Error> {
+        Ok(self.in_ch.request_in(buf).await?)
+    }
+
+    /// Write bytes to the UART transmit stream.
+    ///
+    /// # Cancellation
+    ///
+    /// Not cancel-safe: the remote may observe partial data if the
+    /// future is dropped mid-transfer.
+    pub async fn write(&mut self, data: &[u8]) -> Result<usize, Pl2303Error> {
+        self.out_ch.request_out(data, false).await?;
+        Ok(data.len())
+    }
+}
+
+impl<'d, A: UsbHostAllocator<'d>> embedded_io_async::ErrorType for Pl2303Host<'d, A> {
+    type Error = Pl2303Error;
+}
+
+impl<'d, A: UsbHostAllocator<'d>> embedded_io_async::Read for Pl2303Host<'d, A> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        Pl2303Host::read(self, buf).await
+    }
+}
+
+impl<'d, A: UsbHostAllocator<'d>> embedded_io_async::Write for Pl2303Host<'d, A> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        Pl2303Host::write(self, buf).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn baud_rate_encoding() {
+        assert_eq!(encode_baud_rate(115200, false), 115200u32.to_le_bytes());
+        assert_eq!(encode_baud_rate(250000, true), 250000u32.to_le_bytes());
+
+        // 12 MHz * 32 / (384 * 4^1)
+        assert_eq!(encode_baud_rate(250000, false), [0x80, 0x03, 0x00, 0x80]);
+        // 12 MHz * 32 / (375 * 4^0)
+        assert_eq!(encode_baud_rate(1_024_000, false), [0x77, 0x01, 0x00, 0x80]);
+        // The exponent saturates for the lowest rates.
+        assert_eq!(encode_baud_rate(1, false), [0xFF, 0x0F, 0x00, 0x80]);
+    }
+}
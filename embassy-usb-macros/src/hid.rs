@@ -0,0 +1,421 @@
+use proc_macro2::{Span, TokenStream};
+use quote::{format_ident, quote};
+use syn::meta::ParseNestedMeta;
+use syn::{Data, DeriveInput, Expr, Fields, Ident, LitInt, Type};
+
+/// Number of descriptor items generated for the report itself, at most: usage page, usage, collection,
+/// report ID and end of collection.
+const REPORT_ITEMS: usize = 5;
+/// Number of descriptor items generated for a field, at most: usage page, usage or usage minimum and
+/// maximum, logical minimum and maximum, report size, report count and the main item.
+const FIELD_ITEMS: usize = 8;
+/// Maximum size of an item, with 4 bytes of data.
+const ITEM_SIZE: usize = 5;
+
+pub fn run(item: TokenStream) -> TokenStream {
+    match derive(item) {
+        Ok(tokens) => tokens,
+        Err(error) => error.into_compile_error(),
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Scalar {
+    Bool,
+    Unsigned(usize),
+    Signed(usize),
+}
+
+impl Scalar {
+    fn parse(ty: &Type) -> Option<Self> {
+        let Type::Path(path) = ty else {
+            return None;
+        };
+        let ident = path.path.get_ident()?;
+        Some(match ident.to_string().as_str() {
+            "bool" => Self::Bool,
+            "u8" => Self::Unsigned(8),
+            "u16" => Self::Unsigned(16),
+            "u32" => Self::Unsigned(32),
+            "i8" => Self::Signed(8),
+            "i16" => Self::Signed(16),
+            "i32" => Self::Signed(32),
+            _ => return None,
+        })
+    }
+
+    fn width(self) -> usize {
+        match self {
+            Self::Bool => 1,
+            Self::Unsigned(width) | Self::Signed(width) => width,
+        }
+    }
+
+    /// Largest number of bits of a value, whose range must fit in the `i32` of the descriptor.
+    fn max_bits(self) -> usize {
+        match self {
+            Self::Unsigned(32) => 31,
+            _ => self.width(),
+        }
+    }
+
+    /// Default logical range of values of `bits` bits.
+    fn range(self, bits: usize) -> (i64, i64) {
+        match self {
+            Self::Bool => (0, 1),
+            Self::Unsigned(_) => (0, (1i64 << bits) - 1),
+            Self::Signed(_) => (-(1i64 << (bits - 1)), (1i64 << (bits - 1)) - 1),
+        }
+    }
+}
+
+#[derive(Default)]
+struct ReportAttrs {
+    kind: Option<Ident>,
+    id: Option<u8>,
+    usage_page: Option<Expr>,
+    usage: Option<Expr>,
+    collection: Option<Ident>,
+}
+
+#[derive(Default)]
+struct FieldAttrs {
+    usage_page: Option<Expr>,
+    usage: Option<Expr>,
+    usage_min: Option<Expr>,
+    usage_max: Option<Expr>,
+    logical_min: Option<Expr>,
+    logical_max: Option<Expr>,
+    bits: Option<usize>,
+    constant: bool,
+    array: bool,
+    relative: bool,
+    flags: Option<Expr>,
+}
+
+fn set<T>(meta: &ParseNestedMeta, slot: &mut Option<T>, value: T) -> syn::Result<()> {
+    if slot.is_some() {
+        return Err(meta.error("duplicate hid attribute"));
+    }
+    *slot = Some(value);
+    Ok(())
+}
+
+fn parse_report_attrs(input: &DeriveInput) -> syn::Result<ReportAttrs> {
+    let mut attrs = ReportAttrs::default();
+    for attr in input.attrs.iter().filter(|attr| attr.path().is_ident("hid")) {
+        attr.parse_nested_meta(|meta| {
+            let name = meta.path.get_ident().map(|ident| ident.to_string()).unwrap_or_default();
+            match name.as_str() {
+                "input" | "output" | "feature" => set(&meta, &mut attrs.kind, meta.path.get_ident().unwrap().clone()),
+                "id" => {
+                    let id = meta.value()?.parse::<LitInt>()?.base10_parse::<u8>()?;
+                    if id == 0 {
+                        return Err(meta.error("report ID 0 is reserved"));
+                    }
+                    set(&meta, &mut attrs.id, id)
+                }
+                "usage_page" => set(&meta, &mut attrs.usage_page, meta.value()?.parse()?),
+                "usage" => set(&meta, &mut attrs.usage, meta.value()?.parse()?),
+                "collection" => set(&meta, &mut attrs.collection, meta.value()?.parse()?),
+                _ => Err(meta.error("unknown hid attribute")),
+            }
+        })?;
+    }
+    if let Some(collection) = &attrs.collection
+        && attrs.usage.is_none()
+    {
+        return Err(syn::Error::new_spanned(collection, "a collection needs a usage"));
+    }
+    Ok(attrs)
+}
+
+fn parse_field_attrs(field: &syn::Field) -> syn::Result<FieldAttrs> {
+    let mut attrs = FieldAttrs::default();
+    for attr in field.attrs.iter().filter(|attr| attr.path().is_ident("hid")) {
+        attr.parse_nested_meta(|meta| {
+            let name = meta.path.get_ident().map(|ident| ident.to_string()).unwrap_or_default();
+            match name.as_str() {
+                "usage_page" => set(&meta, &mut attrs.usage_page, meta.value()?.parse()?),
+                "usage" => set(&meta, &mut attrs.usage, meta.value()?.parse()?),
+                "usage_min" => set(&meta, &mut attrs.usage_min, meta.value()?.parse()?),
+                "usage_max" => set(&meta, &mut attrs.usage_max, meta.value()?.parse()?),
+                "logical_min" => set(&meta, &mut attrs.logical_min, meta.value()?.parse()?),
+                "logical_max" => set(&meta, &mut attrs.logical_max, meta.value()?.parse()?),
+                "bits" => {
+                    let bits = meta.value()?.parse::<LitInt>()?.base10_parse::<usize>()?;
+                    set(&meta, &mut attrs.bits, bits)
+                }
+                "flags" => set(&meta, &mut attrs.flags, meta.value()?.parse()?),
+                "constant" => {
+                    attrs.constant = true;
+                    Ok(())
+                }
+                "array" => {
+                    attrs.array = true;
+                    Ok(())
+                }
+                "relative" => {
+                    attrs.relative = true;
+                    Ok(())
+                }
+                _ => Err(meta.error("unknown hid attribute")),
+            }
+        })?;
+    }
+    if attrs.usage.is_some() && (attrs.usage_min.is_some() || attrs.usage_max.is_some()) {
+        return Err(syn::Error::new_spanned(
+            field,
+            "a field has either a usage, or a usage minimum and maximum",
+        ));
+    }
+    if attrs.usage_min.is_some() != attrs.usage_max.is_some() {
+        return Err(syn::Error::new_spanned(
+            field,
+            "usage_min and usage_max must be set together",
+        ));
+    }
+    if attrs.flags.is_some() && (attrs.constant || attrs.array || attrs.relative) {
+        return Err(syn::Error::new_spanned(
+            field,
+            "flags replaces constant, array and relative",
+        ));
+    }
+    Ok(attrs)
+}
+
+fn derive(item: TokenStream) -> syn::Result<TokenStream> {
+    let input: DeriveInput = syn::parse2(item)?;
+    if !input.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(&input.generics, "HID reports can't be generic"));
+    }
+    let Data::Struct(data) = &input.data else {
+        return Err(syn::Error::new_spanned(&input.ident, "HID reports must be structs"));
+    };
+    let Fields::Named(fields) = &data.fields else {
+        return Err(syn::Error::new_spanned(
+            &input.ident,
+            "HID reports must have named fields",
+        ));
+    };
+
+    let name = &input.ident;
+    let attrs = parse_report_attrs(&input)?;
+
+    let hid = quote!(::embassy_usb::class::hid);
+    let (kind, main_item) = match attrs.kind.as_ref().map(|kind| kind.to_string()).as_deref() {
+        None | Some("input") => (quote!(Input), quote!(input)),
+        Some("output") => (quote!(Output), quote!(output)),
+        _ => (quote!(Feature), quote!(feature)),
+    };
+
+    let mut items = Vec::new();
+    if let Some(usage_page) = &attrs.usage_page {
+        items.push(quote!(.usage_page(#usage_page)));
+    }
+    if let Some(usage) = &attrs.usage {
+        let collection = match &attrs.collection {
+            Some(collection) => Ident::new(&snake_to_camel(&collection.to_string()), collection.span()),
+            None => Ident::new("Application", Span::call_site()),
+        };
+        items.push(quote!(.usage(#usage)));
+        items.push(quote!(.collection(#hid::descriptor::Collection::#collection)));
+    }
+    let (id, mut bits_total, mut write, check_id) = match attrs.id {
+        Some(id) => {
+            items.push(quote!(.report_id(#id)));
+            (
+                quote!(Some(#id)),
+                vec![quote!(8)],
+                vec![quote!(writer.write(#id as u32, 8);)],
+                quote!(if reader.read(8) != #id as u32 {
+                    return ::core::option::Option::None;
+                }),
+            )
+        }
+        None => (quote!(None), vec![], vec![], quote!()),
+    };
+    let mut read = Vec::new();
+    for field in &fields.named {
+        let field_name = field.ident.as_ref().unwrap();
+        let field_attrs = parse_field_attrs(field)?;
+
+        let (scalar, count) = match &field.ty {
+            Type::Array(array) => (Scalar::parse(&array.elem), Some(&array.len)),
+            ty => (Scalar::parse(ty), None),
+        };
+        let Some(scalar) = scalar else {
+            return Err(syn::Error::new_spanned(
+                &field.ty,
+                "unsupported HID report field type, expected bool, u8, u16, u32, i8, i16, i32 or an array of them",
+            ));
+        };
+        let bits = match field_attrs.bits {
+            Some(bits) => bits,
+            None if scalar.width() <= scalar.max_bits() => scalar.width(),
+            None => {
+                return Err(syn::Error::new_spanned(
+                    field,
+                    format!("u32 fields need `bits = N` with N at most {}", scalar.max_bits()),
+                ));
+            }
+        };
+        if bits == 0 || bits > scalar.max_bits() {
+            return Err(syn::Error::new_spanned(
+                field,
+                format!("bits must be between 1 and {}", scalar.max_bits()),
+            ));
+        }
+        let count = match count {
+            Some(len) => quote!((#len)),
+            None => quote!(1),
+        };
+
+        if let Some(usage_page) = &field_attrs.usage_page {
+            items.push(quote!(.usage_page(#usage_page)));
+        }
+        if let Some(usage) = &field_attrs.usage {
+            items.push(quote!(.usage(#usage)));
+        }
+        if let (Some(min), Some(max)) = (&field_attrs.usage_min, &field_attrs.usage_max) {
+            items.push(quote!(.usage_min(#min).usage_max(#max)));
+        }
+        if !field_attrs.constant || field_attrs.logical_min.is_some() || field_attrs.logical_max.is_some() {
+            let (min, max) = scalar.range(bits);
+            let min = field_attrs.logical_min.clone().unwrap_or(syn::parse_quote!(#min));
+            let max = field_attrs.logical_max.clone().unwrap_or(syn::parse_quote!(#max));
+            items.push(quote!(.logical_min(#min as i32).logical_max(#max as i32)));
+        }
+        let flags = match &field_attrs.flags {
+            Some(flags) => quote!(#flags),
+            None => {
+                let mut flags = vec![];
+                if field_attrs.constant {
+                    flags.push(quote!(CONSTANT));
+                }
+                if !field_attrs.array {
+                    flags.push(quote!(VARIABLE));
+                }
+                if field_attrs.relative {
+                    flags.push(quote!(RELATIVE));
+                }
+                quote!(#hid::descriptor::ItemFlags::DATA #(.union(#hid::descriptor::ItemFlags::#flags))*)
+            }
+        };
+        items.push(quote!(.report_size(#bits as u32).report_count(#count as u32).#main_item(#flags)));
+
+        bits_total.push(quote!(#bits * #count));
+        let from_reader = match scalar {
+            Scalar::Bool => quote!(reader.read(#bits) != 0),
+            Scalar::Unsigned(_) => {
+                let ty = format_ident!("u{}", scalar.width());
+                quote!(reader.read(#bits) as #ty)
+            }
+            Scalar::Signed(_) => {
+                let ty = format_ident!("i{}", scalar.width());
+                quote!(reader.read_signed(#bits) as #ty)
+            }
+        };
+        if matches!(field.ty, Type::Array(_)) {
+            write.push(quote! {
+                for value in &self.#field_name {
+                    writer.write(*value as u32, #bits);
+                }
+            });
+            read.push(quote!(#field_name: ::core::array::from_fn(|_| #from_reader)));
+        } else {
+            write.push(quote!(writer.write(self.#field_name as u32, #bits);));
+            read.push(quote!(#field_name: #from_reader));
+        }
+    }
+    if attrs.usage.is_some() {
+        items.push(quote!(.end_collection()));
+    }
+
+    let capacity = (REPORT_ITEMS + FIELD_ITEMS * fields.named.len()) * ITEM_SIZE;
+    let bits_total = if bits_total.is_empty() {
+        quote!(0)
+    } else {
+        quote!(#(#bits_total)+*)
+    };
+
+    Ok(quote! {
+        impl #hid::report::HidReport for #name {
+            const DESCRIPTOR: &'static [u8] = {
+                const DESCRIPTOR: #hid::descriptor::ReportDescriptor<#capacity> =
+                    #hid::descriptor::ReportDescriptor::new() #(#items)*;
+                const BYTES: [u8; DESCRIPTOR.len()] = DESCRIPTOR.to_array();
+                &BYTES
+            };
+            const KIND: #hid::report::ReportKind = #hid::report::ReportKind::#kind;
+            const ID: ::core::option::Option<u8> = #id;
+            const SIZE: usize = (#bits_total).div_ceil(8);
+
+            fn to_bytes(&self, buf: &mut [u8]) -> usize {
+                let mut writer = #hid::report::BitWriter::new(buf, Self::SIZE);
+                #(#write)*
+                Self::SIZE
+            }
+
+            fn from_bytes(data: &[u8]) -> ::core::option::Option<Self> {
+                let mut reader = #hid::report::BitReader::new(data, Self::SIZE)?;
+                #check_id
+                ::core::option::Option::Some(Self {
+                    #(#read,)*
+                })
+            }
+        }
+    })
+}
+
+fn snake_to_camel(name: &str) -> String {
+    name.split('_')
+        .map(|part| {
+            let mut chars = part.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(item: TokenStream) -> String {
+        derive(item).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn u32_bits() {
+        assert_eq!(
+            error(quote!(
+                struct Report {
+                    value: u32,
+                }
+            )),
+            "u32 fields need `bits = N` with N at most 31"
+        );
+        assert_eq!(
+            error(quote!(
+                struct Report {
+                    #[hid(bits = 32)]
+                    values: [u32; 2],
+                }
+            )),
+            "bits must be between 1 and 31"
+        );
+        assert!(
+            derive(quote!(
+                struct Report {
+                    #[hid(bits = 31)]
+                    value: u32,
+                    signed: i32,
+                }
+            ))
+            .is_ok()
+        );
+    }
+}
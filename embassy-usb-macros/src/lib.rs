@@ -0,0 +1,87 @@
+#![doc = include_str!("../README.md")]
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+
+mod hid;
+
+/// Implements `embassy_usb::class::hid::report::HidReport` for a struct, generating its report
+/// descriptor and its conversions to and from bytes.
+///
+/// The fields are laid out in the report in the order of the struct, least significant bit first,
+/// without padding. Each field is an Input, Output or Feature item, depending on the kind of the report.
+/// The supported field types are `bool`, `u8`, `u16`, `u32`, `i8`, `i16`, `i32`, and arrays of them,
+/// which are reported as several values of the same item.
+///
+/// ## Report attributes
+///
+/// The struct can have a `#[hid(...)]` attribute with:
+///
+/// * `input`, `output` or `feature`: the kind of the report. The default is `input`.
+/// * `id = N`: the report ID, sent as the first byte of the report. All the reports of an interface
+///   must either have an ID, or none.
+/// * `usage_page = expr`, `usage = expr`: wrap the report in a collection with this usage. Use them for
+///   reports that make up a function on their own, like a gamepad.
+/// * `collection = name`: the type of that collection, as a snake case `Collection` variant. The
+///   default is `application`.
+///
+/// ## Field attributes
+///
+/// Each field can have a `#[hid(...)]` attribute with:
+///
+/// * `usage_page = expr`: the usage page of the field. Otherwise it's the one of the previous field,
+///   or of the report.
+/// * `usage = expr`, or `usage_min = expr` and `usage_max = expr`: the usages of the values of the field.
+/// * `logical_min = expr`, `logical_max = expr`: the range of the values. The default is the range of
+///   the type, or of `bits`.
+/// * `bits = N`: the number of bits of each value, if less than its type. `u32` fields must have it, with
+///   at most 31 bits, since the logical maximum in the descriptor is signed.
+/// * `constant`: the field is padding. Its value is sent as is, so it should be 0.
+/// * `array`: the values are indexes in the usages of the field, like the key codes of a keyboard,
+///   instead of a value for each usage.
+/// * `relative`: the values are relative to the previous report, like the movement of a mouse.
+/// * `flags = expr`: the `ItemFlags` of the field, instead of the three above.
+///
+/// ## Example
+///
+/// ```
+/// use embassy_usb::class::hid::descriptor::{generic_desktop, usage_page};
+/// use embassy_usb::class::hid::report::HidReport;
+///
+/// #[derive(HidReport, Debug, PartialEq)]
+/// #[hid(id = 1, usage_page = usage_page::GENERIC_DESKTOP, usage = generic_desktop::GAMEPAD)]
+/// struct GamepadReport {
+///     #[hid(usage_page = usage_page::BUTTON, usage_min = 1, usage_max = 12)]
+///     buttons: [bool; 12],
+///     #[hid(constant)]
+///     padding: [bool; 4],
+///     #[hid(usage_page = usage_page::GENERIC_DESKTOP, usage_min = generic_desktop::X, usage_max = generic_desktop::Y)]
+///     axes: [i8; 2],
+///     #[hid(usage = generic_desktop::HAT_SWITCH, logical_max = 7, bits = 4)]
+///     hat: u8,
+///     #[hid(constant, bits = 4)]
+///     hat_padding: u8,
+/// }
+///
+/// let mut buttons = [false; 12];
+/// buttons[0] = true;
+/// buttons[11] = true;
+/// let report = GamepadReport {
+///     buttons,
+///     padding: [false; 4],
+///     axes: [-1, 5],
+///     hat: 3,
+///     hat_padding: 0,
+/// };
+///
+/// let mut buf = [0; GamepadReport::SIZE];
+/// assert_eq!(report.to_bytes(&mut buf), 6);
+/// assert_eq!(buf, [1, 0x01, 0x08, 0xFF, 0x05, 0x03]);
+/// assert_eq!(GamepadReport::from_bytes(&buf), Some(report));
+/// // Reports with another ID are not this one.
+/// assert_eq!(GamepadReport::from_bytes(&[2, 0, 0, 0, 0, 0]), None);
+/// ```
+#[proc_macro_derive(HidReport, attributes(hid))]
+pub fn hid_report(item: TokenStream) -> TokenStream {
+    hid::run(item.into()).into()
+}
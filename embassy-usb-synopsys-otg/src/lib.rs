@@ -43,7 +43,14 @@ where
     let ep_count = state.endpoint_count();
 
     let ints = r.gintsts().read();
-    if ints.wkupint() || ints.usbsusp() || ints.usbrst() || ints.enumdne() || ints.otgint() || ints.srqint() {
+    if ints.wkupint()
+        || ints.usbsusp()
+        || ints.lpmint()
+        || ints.usbrst()
+        || ints.enumdne()
+        || ints.otgint()
+        || ints.srqint()
+    {
         // Mask interrupts and notify `Bus` to process them
         r.gintmsk().write(|w| {
             w.set_iepint(true);
@@ -684,6 +691,7 @@ where
             Bus {
                 config: self.config,
                 inited: false,
+                lpm: false,
                 instance: self.instance,
             },
             ControlPipe {
@@ -833,6 +841,7 @@ where
     config: Config,
     instance: OtgInstance<'d, M>,
     inited: bool,
+    lpm: bool,
 }
 
 impl<'d, M> Bus<'d, M>
@@ -845,6 +854,7 @@ where
             w.set_enumdnem(true);
             w.set_usbsuspm(true);
             w.set_wuim(true);
+            w.set_lpmintm(self.lpm);
             w.set_iepint(true);
             w.set_oepint(true);
             w.set_rxflvlm(true);
@@ -1028,6 +1038,10 @@ where
             w.set_stupm(true);
         });
 
+        if self.lpm {
+            self.configure_lpm();
+        }
+
         // Unmask and clear core interrupts
         self.restore_irqs();
         r.gintsts().write_value(regs::Gintsts(0xFFFF_FFFF));
@@ -1041,6 +1055,15 @@ where
         r.dctl().write(|w| w.set_sdis(false));
     }
 
+    /// Acknowledges the LPM requests of the host if `lpm` is set, or rejects them.
+    fn configure_lpm(&mut self) {
+        self.instance.regs.glpmcfg().modify(|w| {
+            w.set_lpmen(self.lpm);
+            w.set_lpmack(self.lpm);
+            w.set_enbesl(self.lpm);
+        });
+    }
+
     fn init_fifo(&mut self) {
         trace!("init_fifo");
 
@@ -1333,6 +1356,17 @@ where
                 return Poll::Ready(Event::Suspend);
             }
 
+            if ints.lpmint() {
+                trace!("lpm sleep");
+                regs.gintsts().write(|w| w.set_lpmint(true)); // clear
+                self.restore_irqs();
+                let glpmcfg = regs.glpmcfg().read();
+                return Poll::Ready(Event::Sleep {
+                    remote_wakeup: glpmcfg.remwake(),
+                    besl: glpmcfg.besl(),
+                });
+            }
+
             if ints.wkupint() {
                 trace!("resume");
                 regs.gintsts().write(|w| w.set_wkupint(true)); // clear
@@ -1519,11 +1553,45 @@ where
         //Bus::disable(self);
     }
 
+    fn set_lpm_enabled(&mut self, enabled: bool) -> Result<(), Unsupported> {
+        self.lpm = enabled;
+        self.configure_lpm();
+
+        // Cores without LPM don't have the GLPMCFG register, which then reads as 0.
+        if enabled && !self.instance.regs.glpmcfg().read().lpmen() {
+            self.lpm = false;
+            return Err(Unsupported);
+        }
+
+        if self.inited {
+            self.restore_irqs();
+        }
+        Ok(())
+    }
+
     async fn remote_wakeup(&mut self) -> Result<(), Unsupported> {
+        let r = self.instance.regs;
+
+        // Resume from the L1 sleep state of LPM. The core drives the 50 µs resume signaling and clears
+        // RWUSIG by itself.
+        if self.lpm && r.glpmcfg().read().slpsts() {
+            // L1RSMOK is set once the link has been in L1 for 50 µs, and SLPSTS is cleared when it leaves
+            // L1. If the host resumes the link first, there's nothing left to do.
+            loop {
+                let lpm = r.glpmcfg().read();
+                if !lpm.slpsts() {
+                    return Ok(());
+                }
+                if lpm.l1rsmok() {
+                    break;
+                }
+            }
+            r.dctl().modify(|w| w.set_rwusig(true));
+            return Ok(());
+        }
+
         #[cfg(feature = "embassy-time")]
         {
-            let r = self.instance.regs;
-
             // Re-enable PHY clock gated during suspend.
             // See RM0368 §22.8 "OTG low-power modes" (STPPCLK / GATEHCLK).
             r.pcgcctl().modify(|w| {
@@ -1604,6 +1672,20 @@ where
         })
         .await
     }
+
+    fn stall(&mut self) -> Result<(), Unsupported> {
+        let index = self.info.addr.index();
+        self.mutex.lock(|| {
+            // See `Bus::endpoint_set_stalled`, a transfer in progress needs the full stop sequence.
+            if self.regs.diepctl(index).read().epena() {
+                abort_in_endpoint(self.regs, index);
+                flush_tx_fifo(self.regs, index as _);
+            }
+            self.regs.diepctl(index).modify(|w| w.set_stall(true));
+        });
+        self.state.in_waker.wake();
+        Ok(())
+    }
 }
 
 impl<'d, M> embassy_usb_driver::Endpoint for Endpoint<'d, Out, M>
@@ -1626,6 +1708,14 @@ where
         })
         .await
     }
+
+    fn stall(&mut self) -> Result<(), Unsupported> {
+        let index = self.info.addr.index();
+        self.mutex
+            .lock(|| self.regs.doepctl(index).modify(|w| w.set_stall(true)));
+        self.state.out_waker.wake();
+        Ok(())
+    }
 }
 
 impl<'d, M> embassy_usb_driver::EndpointOut for Endpoint<'d, Out, M>
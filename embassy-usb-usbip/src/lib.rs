@@ -0,0 +1,1186 @@
+#![warn(missing_docs)]
+#![doc = include_str!("../README.md")]
+
+use std::collections::VecDeque;
+use std::future::poll_fn;
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::ops::Range;
+use std::sync::{Arc, Mutex, MutexGuard, mpsc};
+use std::task::{Poll, Waker};
+use std::thread;
+use std::time::Duration;
+
+use embassy_usb_driver::{
+    Direction, EndpointAddress, EndpointAllocError, EndpointError, EndpointInfo, EndpointType, Event, Speed,
+    Unsupported,
+};
+use log::*;
+
+/// Bus ID the device is exported as, to pass to `usbip attach -b`.
+pub const BUS_ID: &str = "1-1";
+
+const ENDPOINT_COUNT: usize = 16;
+
+const USBIP_VERSION: u16 = 0x0111;
+
+const OP_REQ_DEVLIST: u16 = 0x8005;
+const OP_REP_DEVLIST: u16 = 0x0005;
+const OP_REQ_IMPORT: u16 = 0x8003;
+const OP_REP_IMPORT: u16 = 0x0003;
+
+const USBIP_CMD_SUBMIT: u32 = 1;
+const USBIP_CMD_UNLINK: u32 = 2;
+const USBIP_RET_SUBMIT: u32 = 3;
+const USBIP_RET_UNLINK: u32 = 4;
+
+const USBIP_DIR_IN: u32 = 1;
+const URB_ZERO_PACKET: u32 = 0x40;
+
+/// Upper bound on the isochronous packets of a transfer, Linux allows 1024 per URB.
+const MAX_ISO_PACKETS: usize = 1024;
+
+/// Upper bound on the length of a transfer, so that the host can't make the server allocate any amount of memory.
+/// This is well above the transfers of the Linux class drivers, e.g. 120 KiB for USB drives.
+const MAX_TRANSFER_LEN: usize = 1 << 20;
+
+const EPIPE: i32 = 32;
+const ECONNRESET: i32 = 104;
+
+/// How long to wait for the device to answer the requests describing it to the host.
+const DESCRIPTOR_TIMEOUT: Duration = Duration::from_secs(1);
+
+const DESCRIPTOR_TYPE_DEVICE: u8 = 0x01;
+const DESCRIPTOR_TYPE_CONFIGURATION: u8 = 0x02;
+const DESCRIPTOR_TYPE_INTERFACE: u8 = 0x04;
+
+/// Isochronous packet of a transfer.
+struct IsoPacket {
+    offset: u32,
+    length: u32,
+    actual_length: u32,
+}
+
+/// Where to send the result of a transfer.
+enum Reply {
+    /// The attached host.
+    Host,
+    /// The server itself, for the requests it makes to describe the device.
+    Server(mpsc::Sender<(i32, Vec<u8>)>),
+}
+
+/// A transfer submitted by the host, an URB in Linux terms.
+struct Urb {
+    seqnum: u32,
+    setup: [u8; 8],
+    dir_in: bool,
+    /// Data received from the host for OUT transfers, or data to send it for IN transfers.
+    data: Vec<u8>,
+    /// Length of the transfer, as requested by the host.
+    len: usize,
+    /// Packets of `data` not yet read by the device, for OUT transfers.
+    packets: VecDeque<Range<usize>>,
+    /// Bytes read by the device, for OUT transfers.
+    actual_length: usize,
+    iso: Vec<IsoPacket>,
+    /// Next isochronous packet to be handled by the device.
+    iso_index: usize,
+    reply: Reply,
+}
+
+impl Urb {
+    /// Splits the OUT data in packets, as the device reads them.
+    fn split_packets(&mut self, max_packet_size: usize, zero_packet: bool) {
+        if !self.dir_in {
+            let len = self.data.len();
+            if !self.iso.is_empty() {
+                self.packets = self
+                    .iso
+                    .iter()
+                    .map(|p| (p.offset as usize).min(len)..(p.offset as usize + p.length as usize).min(len))
+                    .collect();
+            } else {
+                self.packets = (0..len)
+                    .step_by(max_packet_size)
+                    .map(|start| start..(start + max_packet_size).min(len))
+                    .collect();
+                if zero_packet && len.is_multiple_of(max_packet_size) {
+                    self.packets.push_back(len..len);
+                }
+            }
+        }
+    }
+
+    fn read_packet(&mut self, buf: &mut [u8]) -> Result<usize, EndpointError> {
+        let range = self.packets.pop_front().unwrap_or(0..0);
+        let packet = &self.data[range];
+        self.actual_length += packet.len();
+        if let Some(iso) = self.iso.get_mut(self.iso_index) {
+            iso.actual_length = packet.len() as u32;
+            self.iso_index += 1;
+        }
+
+        if packet.len() > buf.len() {
+            warn!(
+                "usbip: packet of {} bytes does not fit in {} bytes",
+                packet.len(),
+                buf.len()
+            );
+            return Err(EndpointError::BufferOverflow);
+        }
+        buf[..packet.len()].copy_from_slice(packet);
+        Ok(packet.len())
+    }
+
+    /// Adds a packet to an IN transfer, and returns whether the transfer is complete.
+    fn write_packet(&mut self, buf: &[u8], max_packet_size: usize) -> bool {
+        if let Some(iso) = self.iso.get_mut(self.iso_index) {
+            let n = buf.len().min(iso.length as usize);
+            iso.actual_length = n as u32;
+            self.data.extend_from_slice(&buf[..n]);
+            self.iso_index += 1;
+            return self.iso_index == self.iso.len();
+        }
+
+        let n = buf.len().min(self.len - self.data.len());
+        self.data.extend_from_slice(&buf[..n]);
+        buf.len() < max_packet_size || self.data.len() == self.len
+    }
+
+    fn ret_submit(&self, status: i32) -> Vec<u8> {
+        let actual_length = if self.dir_in {
+            self.data.len()
+        } else {
+            self.actual_length
+        };
+
+        let mut msg = Vec::with_capacity(48 + self.data.len() + 16 * self.iso.len());
+        for word in [USBIP_RET_SUBMIT, self.seqnum, 0, 0, 0] {
+            msg.extend_from_slice(&word.to_be_bytes());
+        }
+        msg.extend_from_slice(&status.to_be_bytes());
+        msg.extend_from_slice(&(actual_length as u32).to_be_bytes());
+        // start_frame
+        msg.extend_from_slice(&0u32.to_be_bytes());
+        msg.extend_from_slice(&(self.iso.len() as u32).to_be_bytes());
+        // error_count and padding
+        msg.extend_from_slice(&[0; 12]);
+
+        if self.dir_in {
+            // The data of isochronous packets is sent back to back, without the gaps between them.
+            msg.extend_from_slice(&self.data);
+        }
+        for p in &self.iso {
+            for word in [p.offset, p.length, p.actual_length, 0] {
+                msg.extend_from_slice(&word.to_be_bytes());
+            }
+        }
+        msg
+    }
+}
+
+#[derive(Default)]
+struct EndpointState {
+    max_packet_size: usize,
+    enabled: bool,
+    stalled: bool,
+    urbs: VecDeque<Urb>,
+    waker: Option<Waker>,
+}
+
+impl EndpointState {
+    fn register(&mut self, waker: &Waker) {
+        self.waker = Some(waker.clone());
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// State shared between the server thread and the device.
+#[derive(Default)]
+struct Inner {
+    out_endpoints: [EndpointState; ENDPOINT_COUNT],
+    in_endpoints: [EndpointState; ENDPOINT_COUNT],
+    /// Control transfers, with endpoint 0 in both directions.
+    control: EndpointState,
+    /// Control transfer being handled by the device.
+    control_urb: Option<Urb>,
+    events: VecDeque<Event>,
+    bus_waker: Option<Waker>,
+    /// Messages to send to the attached host, written by its connection so that the device never waits on the
+    /// network.
+    conn: Option<mpsc::Sender<Vec<u8>>>,
+}
+
+impl Inner {
+    fn endpoint(&mut self, addr: EndpointAddress) -> &mut EndpointState {
+        match addr.direction() {
+            Direction::Out => &mut self.out_endpoints[addr.index()],
+            Direction::In => &mut self.in_endpoints[addr.index()],
+        }
+    }
+
+    fn push_event(&mut self, event: Event) {
+        self.events.push_back(event);
+        if let Some(waker) = self.bus_waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn submit(&mut self, addr: EndpointAddress, mut urb: Urb, zero_packet: bool) {
+        if addr.index() == 0 {
+            urb.split_packets(self.control.max_packet_size, false);
+            self.control.urbs.push_back(urb);
+            self.control.wake();
+            return;
+        }
+
+        let ep = self.endpoint(addr);
+        // Endpoints the device did not allocate have no packet size.
+        if ep.stalled || ep.max_packet_size == 0 {
+            self.complete(urb, -EPIPE);
+        } else {
+            urb.split_packets(ep.max_packet_size, zero_packet);
+            ep.urbs.push_back(urb);
+            ep.wake();
+        }
+    }
+
+    /// Removes a transfer the device has not completed yet, and returns whether it was found.
+    fn unlink(&mut self, seqnum: u32) -> bool {
+        if self.control_urb.as_ref().is_some_and(|urb| urb.seqnum == seqnum) {
+            self.control_urb = None;
+            return true;
+        }
+
+        let endpoints = self
+            .out_endpoints
+            .iter_mut()
+            .chain(self.in_endpoints.iter_mut())
+            .chain([&mut self.control]);
+        for ep in endpoints {
+            if let Some(i) = ep.urbs.iter().position(|urb| urb.seqnum == seqnum) {
+                ep.urbs.remove(i);
+                return true;
+            }
+        }
+        false
+    }
+
+    fn complete(&mut self, urb: Urb, status: i32) {
+        match &urb.reply {
+            Reply::Host => {
+                if let Some(conn) = &self.conn {
+                    _ = conn.send(urb.ret_submit(status));
+                }
+            }
+            // The server may have given up waiting already.
+            Reply::Server(tx) => _ = tx.send((status, urb.data)),
+        }
+    }
+
+    /// Stalls or clears the halt of an endpoint. Stalling fails its pending transfers.
+    fn set_stalled(&mut self, addr: EndpointAddress, stalled: bool) {
+        let ep = self.endpoint(addr);
+        ep.stalled = stalled;
+        if stalled {
+            let urbs = core::mem::take(&mut ep.urbs);
+            for urb in urbs {
+                self.complete(urb, -EPIPE);
+            }
+        }
+    }
+
+    /// Drops all pending transfers and disables the endpoints, when a host attaches or detaches.
+    fn reset(&mut self) {
+        for ep in self
+            .out_endpoints
+            .iter_mut()
+            .chain(self.in_endpoints.iter_mut())
+            .chain([&mut self.control])
+        {
+            ep.urbs.clear();
+            ep.enabled = false;
+            ep.stalled = false;
+            ep.wake();
+        }
+        self.control_urb = None;
+    }
+}
+
+type Shared = Arc<Mutex<Inner>>;
+
+fn lock(shared: &Shared) -> MutexGuard<'_, Inner> {
+    shared.lock().unwrap()
+}
+
+/// USB/IP driver.
+///
+/// The device is exported as soon as the driver is started, and stays available until the program exits.
+pub struct Driver {
+    listener: TcpListener,
+    speed: Speed,
+    shared: Shared,
+    allocated_out: [bool; ENDPOINT_COUNT],
+    allocated_in: [bool; ENDPOINT_COUNT],
+}
+
+impl Driver {
+    /// Create a new USB/IP driver, listening on `addr`.
+    ///
+    /// USB/IP hosts connect to port 3240 by default, so `addr` is usually `"127.0.0.1:3240"`. `speed` is the speed
+    /// the host sees the device at.
+    pub fn new(addr: impl ToSocketAddrs, speed: Speed) -> io::Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind(addr)?,
+            speed,
+            shared: Shared::default(),
+            allocated_out: [false; ENDPOINT_COUNT],
+            allocated_in: [false; ENDPOINT_COUNT],
+        })
+    }
+
+    /// Get the address the driver listens on.
+    ///
+    /// This is useful to find the port the system picked when binding to port 0.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    fn alloc_endpoint<D: Dir>(
+        &mut self,
+        ep_type: EndpointType,
+        ep_addr: Option<EndpointAddress>,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Endpoint<D>, EndpointAllocError> {
+        trace!(
+            "allocating type={:?} mps={:?} interval_ms={}, dir={:?}",
+            ep_type,
+            max_packet_size,
+            interval_ms,
+            D::dir()
+        );
+
+        let allocated = match D::dir() {
+            Direction::Out => &mut self.allocated_out,
+            Direction::In => &mut self.allocated_in,
+        };
+        let index = match ep_addr {
+            Some(addr) => {
+                let index = addr.index();
+                if index == 0 || index >= ENDPOINT_COUNT || allocated[index] {
+                    return Err(EndpointAllocError);
+                }
+                index
+            }
+            None => (1..ENDPOINT_COUNT).find(|&i| !allocated[i]).ok_or(EndpointAllocError)?,
+        };
+        allocated[index] = true;
+
+        let addr = EndpointAddress::from_parts(index, D::dir());
+        lock(&self.shared).endpoint(addr).max_packet_size = max_packet_size as usize;
+
+        Ok(Endpoint {
+            _phantom: PhantomData,
+            info: EndpointInfo {
+                addr,
+                ep_type,
+                max_packet_size,
+                interval_ms,
+            },
+            shared: self.shared.clone(),
+        })
+    }
+}
+
+impl<'a> embassy_usb_driver::Driver<'a> for Driver {
+    type EndpointOut = Endpoint<Out>;
+    type EndpointIn = Endpoint<In>;
+    type ControlPipe = ControlPipe;
+    type Bus = Bus;
+
+    fn alloc_endpoint_out(
+        &mut self,
+        ep_type: EndpointType,
+        ep_addr: Option<EndpointAddress>,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointOut, EndpointAllocError> {
+        self.alloc_endpoint(ep_type, ep_addr, max_packet_size, interval_ms)
+    }
+
+    fn alloc_endpoint_in(
+        &mut self,
+        ep_type: EndpointType,
+        ep_addr: Option<EndpointAddress>,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointIn, EndpointAllocError> {
+        self.alloc_endpoint(ep_type, ep_addr, max_packet_size, interval_ms)
+    }
+
+    fn start(self, control_max_packet_size: u16) -> (Self::Bus, Self::ControlPipe) {
+        {
+            let mut inner = lock(&self.shared);
+            inner.control.max_packet_size = control_max_packet_size as usize;
+            // The device is always plugged in, hosts reset it when attaching.
+            inner.push_event(Event::PowerDetected);
+        }
+
+        let shared = self.shared.clone();
+        let speed = self.speed;
+        let listener = self.listener;
+        thread::Builder::new()
+            .name("usbip".into())
+            .spawn(move || serve(listener, shared, speed))
+            .expect("failed to spawn the USB/IP server thread");
+
+        (
+            Bus {
+                shared: self.shared.clone(),
+            },
+            ControlPipe {
+                max_packet_size: control_max_packet_size as usize,
+                shared: self.shared,
+            },
+        )
+    }
+}
+
+/// USB bus.
+pub struct Bus {
+    shared: Shared,
+}
+
+impl embassy_usb_driver::Bus for Bus {
+    async fn enable(&mut self) {}
+
+    async fn disable(&mut self) {}
+
+    async fn poll(&mut self) -> Event {
+        poll_fn(|cx| {
+            let mut inner = lock(&self.shared);
+            match inner.events.pop_front() {
+                Some(event) => Poll::Ready(event),
+                None => {
+                    inner.bus_waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+        .await
+    }
+
+    fn endpoint_set_enabled(&mut self, ep_addr: EndpointAddress, enabled: bool) {
+        trace!("set_enabled {:?} {}", ep_addr, enabled);
+        if ep_addr.index() == 0 {
+            return;
+        }
+
+        let mut inner = lock(&self.shared);
+        let ep = inner.endpoint(ep_addr);
+        ep.enabled = enabled;
+        ep.wake();
+    }
+
+    fn endpoint_set_stalled(&mut self, ep_addr: EndpointAddress, stalled: bool) {
+        trace!("set_stalled {:?} {}", ep_addr, stalled);
+        if ep_addr.index() == 0 {
+            // The control pipe stalls by rejecting requests.
+            return;
+        }
+
+        lock(&self.shared).set_stalled(ep_addr, stalled);
+    }
+
+    fn endpoint_is_stalled(&mut self, ep_addr: EndpointAddress) -> bool {
+        if ep_addr.index() == 0 {
+            return false;
+        }
+        lock(&self.shared).endpoint(ep_addr).stalled
+    }
+
+    async fn remote_wakeup(&mut self) -> Result<(), Unsupported> {
+        Err(Unsupported)
+    }
+}
+
+trait Dir {
+    fn dir() -> Direction;
+}
+
+/// Marker type for the "IN" direction.
+pub enum In {}
+impl Dir for In {
+    fn dir() -> Direction {
+        Direction::In
+    }
+}
+
+/// Marker type for the "OUT" direction.
+pub enum Out {}
+impl Dir for Out {
+    fn dir() -> Direction {
+        Direction::Out
+    }
+}
+
+/// USB endpoint.
+pub struct Endpoint<D> {
+    _phantom: PhantomData<D>,
+    info: EndpointInfo,
+    shared: Shared,
+}
+
+impl<D> embassy_usb_driver::Endpoint for Endpoint<D> {
+    fn info(&self) -> &EndpointInfo {
+        &self.info
+    }
+
+    async fn wait_enabled(&mut self) {
+        poll_fn(|cx| {
+            let mut inner = lock(&self.shared);
+            let ep = inner.endpoint(self.info.addr);
+            if ep.enabled {
+                Poll::Ready(())
+            } else {
+                ep.register(cx.waker());
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    fn stall(&mut self) -> Result<(), Unsupported> {
+        trace!("stall {:?}", self.info.addr);
+        lock(&self.shared).set_stalled(self.info.addr, true);
+        Ok(())
+    }
+}
+
+impl embassy_usb_driver::EndpointOut for Endpoint<Out> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, EndpointError> {
+        poll_fn(|cx| {
+            let mut guard = lock(&self.shared);
+            let inner = &mut *guard;
+            let ep = inner.endpoint(self.info.addr);
+            if !ep.enabled {
+                return Poll::Ready(Err(EndpointError::Disabled));
+            }
+            let Some(urb) = ep.urbs.front_mut() else {
+                ep.register(cx.waker());
+                return Poll::Pending;
+            };
+
+            let res = urb.read_packet(buf);
+            if urb.packets.is_empty() {
+                let urb = ep.urbs.pop_front().unwrap();
+                inner.complete(urb, 0);
+            }
+            Poll::Ready(res)
+        })
+        .await
+    }
+}
+
+impl embassy_usb_driver::EndpointIn for Endpoint<In> {
+    async fn write(&mut self, buf: &[u8]) -> Result<(), EndpointError> {
+        if buf.len() > self.info.max_packet_size as usize {
+            return Err(EndpointError::BufferOverflow);
+        }
+
+        poll_fn(|cx| {
+            let mut guard = lock(&self.shared);
+            let inner = &mut *guard;
+            let ep = inner.endpoint(self.info.addr);
+            if !ep.enabled {
+                return Poll::Ready(Err(EndpointError::Disabled));
+            }
+            let max_packet_size = ep.max_packet_size;
+            let Some(urb) = ep.urbs.front_mut() else {
+                ep.register(cx.waker());
+                return Poll::Pending;
+            };
+
+            if urb.write_packet(buf, max_packet_size) {
+                let urb = ep.urbs.pop_front().unwrap();
+                inner.complete(urb, 0);
+            }
+            Poll::Ready(Ok(()))
+        })
+        .await
+    }
+}
+
+/// USB control pipe.
+pub struct ControlPipe {
+    max_packet_size: usize,
+    shared: Shared,
+}
+
+impl ControlPipe {
+    fn complete(&mut self, status: i32) {
+        let mut inner = lock(&self.shared);
+        if let Some(urb) = inner.control_urb.take() {
+            inner.complete(urb, status);
+        }
+    }
+}
+
+impl embassy_usb_driver::ControlPipe for ControlPipe {
+    fn max_packet_size(&self) -> usize {
+        self.max_packet_size
+    }
+
+    async fn setup(&mut self) -> [u8; 8] {
+        poll_fn(|cx| {
+            let mut inner = lock(&self.shared);
+            let Some(urb) = inner.control.urbs.pop_front() else {
+                inner.control.register(cx.waker());
+                return Poll::Pending;
+            };
+
+            let setup = urb.setup;
+            if let Some(previous) = inner.control_urb.replace(urb) {
+                // The device moved on without finishing the previous request.
+                inner.complete(previous, 0);
+            }
+            Poll::Ready(setup)
+        })
+        .await
+    }
+
+    async fn data_out(&mut self, buf: &mut [u8], _first: bool, _last: bool) -> Result<usize, EndpointError> {
+        let mut inner = lock(&self.shared);
+        match &mut inner.control_urb {
+            Some(urb) if !urb.packets.is_empty() => urb.read_packet(buf),
+            // The host sent less data than announced, or unlinked the transfer.
+            _ => Ok(0),
+        }
+    }
+
+    async fn data_in(&mut self, data: &[u8], _first: bool, last: bool) -> Result<(), EndpointError> {
+        {
+            let mut inner = lock(&self.shared);
+            if let Some(urb) = &mut inner.control_urb {
+                urb.write_packet(data, self.max_packet_size);
+            }
+        }
+        if last {
+            self.complete(0);
+        }
+        Ok(())
+    }
+
+    async fn accept(&mut self) {
+        self.complete(0);
+    }
+
+    async fn reject(&mut self) {
+        self.complete(-EPIPE);
+    }
+
+    async fn accept_set_address(&mut self, _addr: u8) {
+        // The host's driver handles addressing itself, the device never sees SET_ADDRESS.
+        self.complete(0);
+    }
+}
+
+/// Descriptors of the device, for the host to identify it before attaching.
+struct DeviceInfo {
+    device: Vec<u8>,
+    configuration: Vec<u8>,
+}
+
+impl DeviceInfo {
+    /// Reads the descriptors from the device, with the requests a host would make.
+    fn read(shared: &Shared) -> io::Result<Self> {
+        let device = get_descriptor(shared, DESCRIPTOR_TYPE_DEVICE, 18)?;
+        let header = get_descriptor(shared, DESCRIPTOR_TYPE_CONFIGURATION, 9)?;
+        if device.len() < 18 || header.len() < 9 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "short descriptor"));
+        }
+        let total_length = u16::from_le_bytes([header[2], header[3]]);
+        let configuration = get_descriptor(shared, DESCRIPTOR_TYPE_CONFIGURATION, total_length)?;
+        Ok(Self { device, configuration })
+    }
+
+    /// Class, subclass and protocol of the default setting of each interface.
+    fn interfaces(&self) -> impl Iterator<Item = [u8; 3]> + '_ {
+        let mut rest = &self.configuration[..];
+        core::iter::from_fn(move || {
+            while rest.len() >= 2 && rest[0] >= 2 {
+                let (desc, next) = rest.split_at((rest[0] as usize).min(rest.len()));
+                rest = next;
+                if desc[1] == DESCRIPTOR_TYPE_INTERFACE && desc.len() >= 9 && desc[3] == 0 {
+                    return Some([desc[5], desc[6], desc[7]]);
+                }
+            }
+            None
+        })
+    }
+
+    /// Encodes the device as in a `usbip_usb_device` struct.
+    fn encode(&self, speed: Speed, msg: &mut Vec<u8>) {
+        let mut path = [0u8; 256];
+        let p = format!("/sys/devices/platform/embassy-usb-usbip/usb1/{BUS_ID}");
+        path[..p.len()].copy_from_slice(p.as_bytes());
+        msg.extend_from_slice(&path);
+        let mut busid = [0u8; 32];
+        busid[..BUS_ID.len()].copy_from_slice(BUS_ID.as_bytes());
+        msg.extend_from_slice(&busid);
+
+        let speed: u32 = match speed {
+            Speed::Low => 1,
+            Speed::Full => 2,
+            Speed::High => 3,
+        };
+        // busnum, devnum, speed
+        for word in [1, 1, speed] {
+            msg.extend_from_slice(&word.to_be_bytes());
+        }
+
+        let d = &self.device;
+        // idVendor, idProduct and bcdDevice are little endian in descriptors.
+        for field in [8, 10, 12] {
+            msg.extend_from_slice(&[d[field + 1], d[field]]);
+        }
+        let c = &self.configuration;
+        msg.extend_from_slice(&[d[4], d[5], d[6], c[5], d[17], c[4]]);
+    }
+}
+
+/// Makes a GET_DESCRIPTOR request to the device, as if it came from the host.
+fn get_descriptor(shared: &Shared, descriptor_type: u8, len: u16) -> io::Result<Vec<u8>> {
+    let (tx, rx) = mpsc::channel();
+    let [len_lo, len_hi] = len.to_le_bytes();
+    let urb = Urb {
+        seqnum: 0,
+        setup: [0x80, 0x06, 0, descriptor_type, 0, 0, len_lo, len_hi],
+        dir_in: true,
+        data: Vec::new(),
+        len: len as usize,
+        packets: VecDeque::new(),
+        actual_length: 0,
+        iso: Vec::new(),
+        iso_index: 0,
+        reply: Reply::Server(tx),
+    };
+    lock(shared).submit(EndpointAddress::from_parts(0, Direction::In), urb, false);
+
+    match rx.recv_timeout(DESCRIPTOR_TIMEOUT) {
+        Ok((0, data)) => Ok(data),
+        Ok((status, _)) => Err(io::Error::other(format!("GET_DESCRIPTOR failed with status {status}"))),
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "device not running")),
+    }
+}
+
+fn op_header(code: u16, status: u32) -> Vec<u8> {
+    let mut msg = Vec::new();
+    msg.extend_from_slice(&USBIP_VERSION.to_be_bytes());
+    msg.extend_from_slice(&code.to_be_bytes());
+    msg.extend_from_slice(&status.to_be_bytes());
+    msg
+}
+
+fn serve(listener: TcpListener, shared: Shared, speed: Speed) {
+    // Each connection has its own thread, so that hosts can list the device while another one has it attached.
+    for conn in listener.incoming() {
+        let shared = shared.clone();
+        let res = conn.and_then(|conn| {
+            thread::Builder::new().name("usbip-conn".into()).spawn(move || {
+                match handle_connection(&shared, conn, speed) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => debug!("usbip: connection closed"),
+                    Err(e) => warn!("usbip: connection failed: {}", e),
+                }
+            })
+        });
+        if let Err(e) = res {
+            warn!("usbip: failed to accept connection: {}", e);
+        }
+    }
+}
+
+/// Writes the messages for the host to `conn`, until the device is detached.
+fn write_messages(mut conn: TcpStream, rx: mpsc::Receiver<Vec<u8>>) {
+    for msg in rx {
+        if let Err(e) = conn.write_all(&msg) {
+            warn!("usbip: failed to send to the host: {}", e);
+            // Also stop reading the transfers of the host.
+            _ = conn.shutdown(Shutdown::Both);
+            break;
+        }
+    }
+}
+
+fn handle_connection(shared: &Shared, mut conn: TcpStream, speed: Speed) -> io::Result<()> {
+    conn.set_nodelay(true)?;
+
+    let mut header = [0u8; 8];
+    conn.read_exact(&mut header)?;
+    match u16::from_be_bytes([header[2], header[3]]) {
+        OP_REQ_DEVLIST => {
+            let mut reply = op_header(OP_REP_DEVLIST, 0);
+            match DeviceInfo::read(shared) {
+                Ok(info) => {
+                    reply.extend_from_slice(&1u32.to_be_bytes());
+                    info.encode(speed, &mut reply);
+                    for interface in info.interfaces() {
+                        reply.extend_from_slice(&interface);
+                        reply.push(0);
+                    }
+                }
+                Err(e) => {
+                    warn!("usbip: failed to read descriptors: {}", e);
+                    reply.extend_from_slice(&0u32.to_be_bytes());
+                }
+            }
+            conn.write_all(&reply)
+        }
+        OP_REQ_IMPORT => {
+            let mut busid = [0u8; 32];
+            conn.read_exact(&mut busid)?;
+            let busid = busid.split(|&b| b == 0).next().unwrap_or(&[]);
+            let info = match busid == BUS_ID.as_bytes() {
+                true => DeviceInfo::read(shared),
+                false => Err(io::Error::new(io::ErrorKind::NotFound, "unknown bus ID")),
+            };
+            let info = match info {
+                Ok(info) => info,
+                Err(e) => {
+                    conn.write_all(&op_header(OP_REP_IMPORT, 1))?;
+                    return Err(e);
+                }
+            };
+
+            let writer = conn.try_clone()?;
+            let (tx, rx) = mpsc::channel();
+            let mut reply = op_header(OP_REP_IMPORT, 0);
+            info.encode(speed, &mut reply);
+            _ = tx.send(reply);
+            {
+                let mut inner = lock(shared);
+                if inner.conn.is_some() {
+                    drop(inner);
+                    conn.write_all(&op_header(OP_REP_IMPORT, 1))?;
+                    return Err(io::Error::new(io::ErrorKind::AddrInUse, "device already attached"));
+                }
+
+                inner.conn = Some(tx);
+                inner.reset();
+                inner.push_event(Event::Reset);
+            }
+            let writer = thread::Builder::new()
+                .name("usbip-write".into())
+                .spawn(move || write_messages(writer, rx))?;
+
+            info!("usbip: device attached");
+            let res = handle_urbs(shared, &mut conn);
+
+            {
+                let mut inner = lock(shared);
+                inner.conn = None;
+                inner.reset();
+                inner.push_event(Event::Reset);
+            }
+            // The writer stops once it sent the messages queued before the detach.
+            _ = writer.join();
+            info!("usbip: device detached");
+            res
+        }
+        code => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unknown operation {code:#06x}"),
+        )),
+    }
+}
+
+fn handle_urbs(shared: &Shared, conn: &mut TcpStream) -> io::Result<()> {
+    loop {
+        let mut header = [0u8; 48];
+        conn.read_exact(&mut header)?;
+        let word = |i: usize| u32::from_be_bytes(header[i * 4..i * 4 + 4].try_into().unwrap());
+        let (command, seqnum, direction, ep) = (word(0), word(1), word(3), word(4));
+
+        match command {
+            USBIP_CMD_SUBMIT => {
+                let (flags, len, number_of_packets) = (word(5), word(6) as usize, word(8));
+                let dir_in = direction == USBIP_DIR_IN;
+                if ep as usize >= ENDPOINT_COUNT {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid endpoint"));
+                }
+                if len > MAX_TRANSFER_LEN {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "transfer too long"));
+                }
+
+                let mut data = Vec::new();
+                if !dir_in {
+                    data.resize(len, 0);
+                    conn.read_exact(&mut data)?;
+                }
+
+                // Non-isochronous transfers have 0 or 0xffffffff packets, depending on the host.
+                let mut iso = Vec::new();
+                if number_of_packets != 0 && number_of_packets != u32::MAX {
+                    if number_of_packets as usize > MAX_ISO_PACKETS {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "too many isochronous packets",
+                        ));
+                    }
+                    for _ in 0..number_of_packets {
+                        let mut desc = [0u8; 16];
+                        conn.read_exact(&mut desc)?;
+                        let word = |i: usize| u32::from_be_bytes(desc[i * 4..i * 4 + 4].try_into().unwrap());
+                        iso.push(IsoPacket {
+                            offset: word(0),
+                            length: word(1),
+                            actual_length: 0,
+                        });
+                    }
+                }
+
+                let urb = Urb {
+                    seqnum,
+                    setup: header[40..48].try_into().unwrap(),
+                    dir_in,
+                    data,
+                    len,
+                    packets: VecDeque::new(),
+                    actual_length: 0,
+                    iso,
+                    iso_index: 0,
+                    reply: Reply::Host,
+                };
+                let dir = if dir_in { Direction::In } else { Direction::Out };
+                let addr = EndpointAddress::from_parts(ep as usize, dir);
+                lock(shared).submit(addr, urb, flags & URB_ZERO_PACKET != 0);
+            }
+            USBIP_CMD_UNLINK => {
+                let mut inner = lock(shared);
+                let status = if inner.unlink(word(5)) { -ECONNRESET } else { 0 };
+
+                let mut msg = Vec::with_capacity(48);
+                for word in [USBIP_RET_UNLINK, seqnum, 0, 0, 0] {
+                    msg.extend_from_slice(&word.to_be_bytes());
+                }
+                msg.extend_from_slice(&status.to_be_bytes());
+                msg.extend_from_slice(&[0; 24]);
+                // Sent in order with the completed transfers.
+                if let Some(conn) = &inner.conn {
+                    _ = conn.send(msg);
+                }
+            }
+            command => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown command {command}"),
+                ));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+    use embassy_futures::join::join;
+    use embassy_usb_driver::{
+        Bus as _, ControlPipe as _, Driver as _, Endpoint as _, EndpointIn as _, EndpointOut as _,
+    };
+
+    use super::*;
+
+    #[rustfmt::skip]
+    const DEVICE_DESCRIPTOR: [u8; 18] = [
+        18, 0x01, 0x00, 0x02, 0xff, 0x00, 0x00, 64, 0x34, 0x12, 0x78, 0x56, 0x00, 0x01, 0, 0, 0, 1,
+    ];
+    #[rustfmt::skip]
+    const CONFIGURATION_DESCRIPTOR: [u8; 32] = [
+        9, 0x02, 32, 0, 1, 1, 0, 0x80, 50,
+        9, 0x04, 0, 0, 2, 0xff, 0x42, 0x01, 0,
+        7, 0x05, 0x01, 0x02, 64, 0, 0,
+        7, 0x05, 0x81, 0x02, 64, 0, 0,
+    ];
+
+    /// Runs a vendor device echoing bulk packets.
+    fn spawn_echo_device() -> SocketAddr {
+        let mut driver = Driver::new("127.0.0.1:0", Speed::Full).unwrap();
+        let addr = driver.local_addr().unwrap();
+        let mut ep_out = driver.alloc_endpoint_out(EndpointType::Bulk, None, 64, 0).unwrap();
+        let mut ep_in = driver.alloc_endpoint_in(EndpointType::Bulk, None, 64, 0).unwrap();
+        let (mut bus, mut control) = driver.start(64);
+        let eps = [ep_out.info().addr, ep_in.info().addr];
+
+        thread::spawn(move || {
+            block_on(join(
+                async {
+                    loop {
+                        let setup = control.setup().await;
+                        let len = u16::from_le_bytes([setup[6], setup[7]]) as usize;
+                        match (setup[1], setup[3]) {
+                            (0x06, 0x01) => control.data_in(&DEVICE_DESCRIPTOR[..len], true, true).await.unwrap(),
+                            (0x06, 0x02) => control
+                                .data_in(&CONFIGURATION_DESCRIPTOR[..len], true, true)
+                                .await
+                                .unwrap(),
+                            (0x09, _) => {
+                                for ep in eps {
+                                    bus.endpoint_set_enabled(ep, setup[2] != 0);
+                                }
+                                control.accept().await;
+                            }
+                            _ => control.reject().await,
+                        }
+                    }
+                },
+                async {
+                    let mut buf = [0; 64];
+                    loop {
+                        ep_out.wait_enabled().await;
+                        if let Ok(n) = ep_out.read(&mut buf).await {
+                            ep_in.write(&buf[..n]).await.unwrap();
+                        }
+                    }
+                },
+            ))
+        });
+        addr
+    }
+
+    fn connect(addr: SocketAddr) -> TcpStream {
+        let conn = TcpStream::connect(addr).unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        conn
+    }
+
+    /// Attaches the device.
+    fn import(addr: SocketAddr) -> TcpStream {
+        let mut conn = connect(addr);
+        let mut msg = op_header(OP_REQ_IMPORT, 0);
+        let mut busid = [0; 32];
+        busid[..3].copy_from_slice(b"1-1");
+        msg.extend_from_slice(&busid);
+        conn.write_all(&msg).unwrap();
+        let reply = read_vec(&mut conn, 8 + 312);
+        assert_eq!(be32(&reply, 4), 0);
+        conn
+    }
+
+    fn read_vec(conn: &mut TcpStream, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        conn.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    fn be32(buf: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn submit(conn: &mut TcpStream, seqnum: u32, ep: u32, dir_in: bool, len: u32, setup: [u8; 8], data: &[u8]) {
+        let mut msg = Vec::new();
+        for word in [
+            USBIP_CMD_SUBMIT,
+            seqnum,
+            0x0001_0001,
+            dir_in as u32,
+            ep,
+            0,
+            len,
+            0,
+            0,
+            0,
+        ] {
+            msg.extend_from_slice(&word.to_be_bytes());
+        }
+        msg.extend_from_slice(&setup);
+        msg.extend_from_slice(data);
+        conn.write_all(&msg).unwrap();
+    }
+
+    /// Reads a RET_SUBMIT, and returns its status and IN data.
+    fn ret_submit(conn: &mut TcpStream, seqnum: u32, dir_in: bool) -> (i32, Vec<u8>) {
+        let header = read_vec(conn, 48);
+        assert_eq!(be32(&header, 0), USBIP_RET_SUBMIT);
+        assert_eq!(be32(&header, 4), seqnum);
+        let status = be32(&header, 20) as i32;
+        let actual_length = be32(&header, 24) as usize;
+        let data = if dir_in {
+            read_vec(conn, actual_length)
+        } else {
+            Vec::new()
+        };
+        (status, data)
+    }
+
+    #[test]
+    fn devlist_import_and_transfers() {
+        let addr = spawn_echo_device();
+
+        let mut conn = connect(addr);
+        conn.write_all(&op_header(OP_REQ_DEVLIST, 0)).unwrap();
+        let reply = read_vec(&mut conn, 8 + 4 + 312 + 4);
+        assert_eq!(be32(&reply, 4), 0);
+        assert_eq!(be32(&reply, 8), 1);
+        let device = &reply[12..];
+        assert_eq!(&device[256..259], b"1-1");
+        assert_eq!(
+            &device[300..312],
+            &[0x12, 0x34, 0x56, 0x78, 0x01, 0x00, 0xff, 0, 0, 1, 1, 1]
+        );
+        assert_eq!(&reply[324..], &[0xff, 0x42, 0x01, 0]);
+
+        let mut conn = import(addr);
+
+        // SET_CONFIGURATION
+        submit(&mut conn, 1, 0, false, 0, [0x00, 0x09, 1, 0, 0, 0, 0, 0], &[]);
+        assert_eq!(ret_submit(&mut conn, 1, false).0, 0);
+
+        // Unsupported requests stall.
+        submit(&mut conn, 2, 0, true, 2, [0x80, 0x00, 0, 0, 0, 0, 2, 0], &[]);
+        assert_eq!(ret_submit(&mut conn, 2, true).0, -EPIPE);
+
+        submit(&mut conn, 3, 1, false, 5, [0; 8], b"hello");
+        assert_eq!(ret_submit(&mut conn, 3, false).0, 0);
+        submit(&mut conn, 4, 1, true, 512, [0; 8], &[]);
+        assert_eq!(ret_submit(&mut conn, 4, true), (0, b"hello".to_vec()));
+
+        // Nothing to echo, so the transfer stays pending until unlinked.
+        submit(&mut conn, 5, 1, true, 512, [0; 8], &[]);
+        let mut msg = Vec::new();
+        for word in [USBIP_CMD_UNLINK, 6, 0x0001_0001, 0, 0, 5, 0, 0, 0, 0, 0, 0] {
+            msg.extend_from_slice(&word.to_be_bytes());
+        }
+        conn.write_all(&msg).unwrap();
+        let reply = read_vec(&mut conn, 48);
+        assert_eq!(be32(&reply, 0), USBIP_RET_UNLINK);
+        assert_eq!(be32(&reply, 4), 6);
+        assert_eq!(be32(&reply, 20) as i32, -ECONNRESET);
+    }
+
+    #[test]
+    fn devlist_while_attached_and_long_transfer() {
+        let addr = spawn_echo_device();
+        let mut conn = import(addr);
+
+        // Another host can list the device while it is attached.
+        let mut list = connect(addr);
+        list.write_all(&op_header(OP_REQ_DEVLIST, 0)).unwrap();
+        let reply = read_vec(&mut list, 8 + 4 + 312 + 4);
+        assert_eq!(be32(&reply, 8), 1);
+
+        // A transfer longer than the server accepts ends the connection, without waiting for its data.
+        submit(&mut conn, 1, 1, false, MAX_TRANSFER_LEN as u32 + 1, [0; 8], &[]);
+        assert_eq!(conn.read(&mut [0; 1]).unwrap(), 0);
+
+        // The device can be attached again.
+        import(addr);
+    }
+}
@@ -0,0 +1,394 @@
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use embassy_futures::block_on;
+use embassy_futures::join::join;
+use embassy_usb::Builder;
+use embassy_usb::class::msc::{self, BlockDevice, MscClass};
+use embassy_usb::driver::Speed;
+use embassy_usb_usbip::Driver;
+
+const BLOCK_SIZE: usize = 512;
+const BLOCK_COUNT: u64 = 16;
+
+const EPIPE: i32 = -32;
+
+const STATUS_PASSED: u8 = 0;
+const STATUS_FAILED: u8 = 1;
+const STATUS_PHASE_ERROR: u8 = 2;
+
+const TEST_UNIT_READY: [u8; 6] = [0x00, 0, 0, 0, 0, 0];
+const REQUEST_SENSE: [u8; 6] = [0x03, 0, 0, 0, 18, 0];
+const INQUIRY: [u8; 6] = [0x12, 0, 0, 0, 36, 0];
+
+struct RamDisk {
+    data: Arc<Mutex<Vec<u8>>>,
+}
+
+impl BlockDevice for RamDisk {
+    type Error = ();
+
+    fn block_size(&self) -> u32 {
+        BLOCK_SIZE as u32
+    }
+
+    fn block_count(&self) -> u64 {
+        BLOCK_COUNT
+    }
+
+    async fn read(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), Self::Error> {
+        let start = lba as usize * BLOCK_SIZE;
+        buf.copy_from_slice(&self.data.lock().unwrap()[start..start + buf.len()]);
+        Ok(())
+    }
+
+    async fn write(&mut self, lba: u64, data: &[u8]) -> Result<(), Self::Error> {
+        let start = lba as usize * BLOCK_SIZE;
+        self.data.lock().unwrap()[start..start + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+}
+
+/// Runs a drive with a single LUN backed by the returned memory, with a buffer of two blocks.
+fn spawn_drive() -> (SocketAddr, Arc<Mutex<Vec<u8>>>) {
+    let driver = Driver::new("127.0.0.1:0", Speed::Full).unwrap();
+    let addr = driver.local_addr().unwrap();
+    let data = Arc::new(Mutex::new(vec![0; BLOCK_SIZE * BLOCK_COUNT as usize]));
+    let disk = RamDisk { data: data.clone() };
+
+    thread::spawn(move || {
+        let config = embassy_usb::Config::new(0x1234, 0x5678);
+        let mut config_descriptor = [0; 256];
+        let mut bos_descriptor = [0; 256];
+        let mut control_buf = [0; 64];
+        let mut state = msc::State::new();
+        let mut builder = Builder::new(
+            driver,
+            config,
+            &mut config_descriptor,
+            &mut bos_descriptor,
+            &mut [],
+            &mut control_buf,
+        );
+        let mut class = MscClass::new(&mut builder, &mut state, msc::Config::default(), 64, 1);
+        let mut usb = builder.build();
+
+        let mut buf = [0; 2 * BLOCK_SIZE];
+        block_on(join(usb.run(), class.run(&mut [disk], &mut buf)));
+    });
+    (addr, data)
+}
+
+/// A host attached to the drive.
+struct Host {
+    conn: TcpStream,
+    seqnum: u32,
+}
+
+impl Host {
+    /// Attaches the drive and selects its configuration.
+    fn attach(addr: SocketAddr) -> Self {
+        let mut conn = TcpStream::connect(addr).unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        // OP_REQ_IMPORT
+        let mut msg = vec![0x01, 0x11, 0x80, 0x03, 0, 0, 0, 0];
+        let mut busid = [0; 32];
+        busid[..3].copy_from_slice(b"1-1");
+        msg.extend_from_slice(&busid);
+        conn.write_all(&msg).unwrap();
+        let mut reply = [0; 8 + 312];
+        conn.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply[4..8], &[0; 4]);
+
+        let mut host = Self { conn, seqnum: 0 };
+        // SET_CONFIGURATION
+        assert_eq!(host.control_out([0x00, 0x09, 1, 0, 0, 0, 0, 0]), 0);
+        host
+    }
+
+    /// Submits a transfer and waits for it, returning its status and IN data.
+    fn transfer(&mut self, ep: u32, dir_in: bool, len: usize, setup: [u8; 8], data: &[u8]) -> (i32, Vec<u8>) {
+        self.seqnum += 1;
+        let mut msg = Vec::new();
+        // USBIP_CMD_SUBMIT
+        for word in [1, self.seqnum, 0x0001_0001, dir_in as u32, ep, 0, len as u32, 0, 0, 0] {
+            msg.extend_from_slice(&u32::to_be_bytes(word));
+        }
+        msg.extend_from_slice(&setup);
+        msg.extend_from_slice(data);
+        self.conn.write_all(&msg).unwrap();
+
+        let mut header = [0; 48];
+        self.conn.read_exact(&mut header).unwrap();
+        let word = |offset: usize| u32::from_be_bytes(header[offset..offset + 4].try_into().unwrap());
+        // USBIP_RET_SUBMIT
+        assert_eq!(word(0), 3);
+        assert_eq!(word(4), self.seqnum);
+        let status = word(20) as i32;
+        let mut data = vec![0; if dir_in { word(24) as usize } else { 0 }];
+        self.conn.read_exact(&mut data).unwrap();
+        (status, data)
+    }
+
+    fn control_out(&mut self, setup: [u8; 8]) -> i32 {
+        self.transfer(0, false, 0, setup, &[]).0
+    }
+
+    fn bulk_out(&mut self, data: &[u8]) -> i32 {
+        self.transfer(1, false, data.len(), [0; 8], data).0
+    }
+
+    fn bulk_in(&mut self, len: usize) -> (i32, Vec<u8>) {
+        self.transfer(1, true, len, [0; 8], &[])
+    }
+
+    /// Runs a command, sending `data` or receiving `data_len` bytes depending on `data_in`.
+    ///
+    /// Returns the received data, and the residue and status of the CSW.
+    fn command(&mut self, data_len: usize, data_in: bool, cdb: &[u8], data: &[u8]) -> (Vec<u8>, u32, u8) {
+        let tag = self.seqnum;
+        assert_eq!(self.bulk_out(&cbw(tag, data_len, data_in, 0, cdb)), 0);
+
+        let mut received = Vec::new();
+        if data_len > 0 {
+            if data_in {
+                let (status, data) = self.bulk_in(data_len);
+                assert_eq!(status, 0);
+                received = data;
+            } else {
+                assert_eq!(data.len(), data_len);
+                assert_eq!(self.bulk_out(data), 0);
+            }
+        }
+
+        let (status, csw) = self.bulk_in(13);
+        assert_eq!(status, 0);
+        assert_eq!(csw.len(), 13);
+        assert_eq!(&csw[0..4], b"USBS");
+        assert_eq!(&csw[4..8], &tag.to_le_bytes());
+        let residue = u32::from_le_bytes(csw[8..12].try_into().unwrap());
+        (received, residue, csw[12])
+    }
+
+    /// Reads the sense data of the last failed command, as (key, asc).
+    fn sense(&mut self) -> (u8, u8) {
+        let (data, residue, status) = self.command(18, true, &REQUEST_SENSE, &[]);
+        assert_eq!((residue, status), (0, STATUS_PASSED));
+        (data[2], data[12])
+    }
+
+    /// Checks both bulk endpoints are stalled, then does a reset recovery.
+    fn reset_recovery(&mut self) {
+        assert_eq!(self.bulk_in(13).0, EPIPE);
+        assert_eq!(self.bulk_out(&[0; 31]), EPIPE);
+        // Bulk-Only Mass Storage Reset
+        assert_eq!(self.control_out([0x21, 0xFF, 0, 0, 0, 0, 0, 0]), 0);
+        self.clear_halts();
+    }
+
+    fn clear_halts(&mut self) {
+        for ep in [0x01, 0x81] {
+            assert_eq!(self.control_out([0x02, 0x01, 0, 0, ep, 0, 0, 0]), 0);
+        }
+    }
+}
+
+fn cbw(tag: u32, data_len: usize, data_in: bool, lun: u8, cdb: &[u8]) -> Vec<u8> {
+    let mut cbw = vec![0; 31];
+    cbw[0..4].copy_from_slice(b"USBC");
+    cbw[4..8].copy_from_slice(&tag.to_le_bytes());
+    cbw[8..12].copy_from_slice(&(data_len as u32).to_le_bytes());
+    cbw[12] = if data_in { 0x80 } else { 0x00 };
+    cbw[13] = lun;
+    cbw[14] = cdb.len() as u8;
+    cbw[15..15 + cdb.len()].copy_from_slice(cdb);
+    cbw
+}
+
+fn read_10(lba: u32, blocks: u16) -> [u8; 10] {
+    let mut cdb = [0x28, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    cdb[2..6].copy_from_slice(&lba.to_be_bytes());
+    cdb[7..9].copy_from_slice(&blocks.to_be_bytes());
+    cdb
+}
+
+fn write_10(lba: u32, blocks: u16) -> [u8; 10] {
+    let mut cdb = read_10(lba, blocks);
+    cdb[0] = 0x2A;
+    cdb
+}
+
+fn read_16(lba: u64, blocks: u32) -> [u8; 16] {
+    let mut cdb = [0; 16];
+    cdb[0] = 0x88;
+    cdb[2..10].copy_from_slice(&lba.to_be_bytes());
+    cdb[10..14].copy_from_slice(&blocks.to_be_bytes());
+    cdb
+}
+
+fn pattern(len: usize, seed: u8) -> Vec<u8> {
+    (0..len)
+        .map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed))
+        .collect()
+}
+
+#[test]
+fn inquiry_write_and_read() {
+    let (addr, data) = spawn_drive();
+    let mut host = Host::attach(addr);
+
+    let (inquiry, residue, status) = host.command(36, true, &INQUIRY, &[]);
+    assert_eq!((residue, status), (0, STATUS_PASSED));
+    assert_eq!(&inquiry[8..16], b"Embassy ");
+
+    let (capacity, residue, status) = host.command(8, true, &[0x25, 0, 0, 0, 0, 0, 0, 0, 0, 0], &[]);
+    assert_eq!((residue, status), (0, STATUS_PASSED));
+    assert_eq!(capacity, [0, 0, 0, 15, 0, 0, 2, 0]);
+
+    // Longer than the buffer of the class, so the data is transferred in several chunks.
+    let written = pattern(5 * BLOCK_SIZE, 7);
+    let (_, residue, status) = host.command(written.len(), false, &write_10(3, 5), &written);
+    assert_eq!((residue, status), (0, STATUS_PASSED));
+    assert_eq!(&data.lock().unwrap()[3 * BLOCK_SIZE..8 * BLOCK_SIZE], &written[..]);
+
+    let (read, residue, status) = host.command(written.len(), true, &read_10(3, 5), &[]);
+    assert_eq!((residue, status), (0, STATUS_PASSED));
+    assert_eq!(read, written);
+}
+
+#[test]
+fn invalid_cbw_stalls_until_reset_recovery() {
+    let (addr, _) = spawn_drive();
+    let mut host = Host::attach(addr);
+
+    let valid = cbw(1, 0, false, 0, &TEST_UNIT_READY);
+    let mut bad_signature = valid.clone();
+    bad_signature[0] = b'X';
+    let mut bad_flags = valid.clone();
+    bad_flags[12] = 0x01;
+    let mut no_cdb = valid.clone();
+    no_cdb[14] = 0;
+    let mut long_cdb = valid.clone();
+    long_cdb[14] = 17;
+    let invalid = [
+        valid[..30].to_vec(),
+        [&valid[..], &[0]].concat(),
+        bad_signature,
+        bad_flags,
+        cbw(1, 0, false, 1, &TEST_UNIT_READY),
+        no_cdb,
+        long_cdb,
+    ];
+
+    for cbw in invalid {
+        assert_eq!(host.bulk_out(&cbw), 0);
+        host.reset_recovery();
+        let (_, residue, status) = host.command(0, false, &TEST_UNIT_READY, &[]);
+        assert_eq!((residue, status), (0, STATUS_PASSED));
+    }
+
+    // Clearing the halts isn't enough, the drive stays stalled until the reset.
+    assert_eq!(host.bulk_out(&[0; 31]), 0);
+    assert_eq!(host.bulk_in(13).0, EPIPE);
+    host.clear_halts();
+    assert_eq!(host.bulk_out(&valid), 0);
+    host.reset_recovery();
+    let (_, residue, status) = host.command(0, false, &TEST_UNIT_READY, &[]);
+    assert_eq!((residue, status), (0, STATUS_PASSED));
+}
+
+#[test]
+fn residue_and_phase_errors() {
+    let (addr, data) = spawn_drive();
+    let original = pattern(BLOCK_SIZE * BLOCK_COUNT as usize, 3);
+    data.lock().unwrap().copy_from_slice(&original);
+    let mut host = Host::attach(addr);
+
+    // The host expects more data than the device has.
+    let (inquiry, residue, status) = host.command(64, true, &[0x12, 0, 0, 0, 64, 0], &[]);
+    assert_eq!((inquiry.len(), residue, status), (36, 28, STATUS_PASSED));
+    let (read, residue, status) = host.command(2 * BLOCK_SIZE, true, &read_10(1, 1), &[]);
+    assert_eq!((residue, status), (BLOCK_SIZE as u32, STATUS_PASSED));
+    assert_eq!(read, &original[BLOCK_SIZE..2 * BLOCK_SIZE]);
+    let (read, residue, status) = host.command(64, true, &TEST_UNIT_READY, &[]);
+    assert_eq!((read.len(), residue, status), (0, 64, STATUS_PASSED));
+
+    // The host sends more data than the device writes, the rest is discarded.
+    let written = pattern(2 * BLOCK_SIZE, 9);
+    let (_, residue, status) = host.command(written.len(), false, &write_10(0, 1), &written);
+    assert_eq!((residue, status), (BLOCK_SIZE as u32, STATUS_PASSED));
+    assert_eq!(&data.lock().unwrap()[..BLOCK_SIZE], &written[..BLOCK_SIZE]);
+    assert_eq!(
+        &data.lock().unwrap()[BLOCK_SIZE..2 * BLOCK_SIZE],
+        &original[BLOCK_SIZE..2 * BLOCK_SIZE]
+    );
+
+    // The host expects less data than the device has, the data is cut short.
+    let (read, residue, status) = host.command(BLOCK_SIZE, true, &read_10(0, 2), &[]);
+    assert_eq!((residue, status), (0, STATUS_PHASE_ERROR));
+    assert_eq!(read, &written[..BLOCK_SIZE]);
+    let (read, residue, status) = host.command(100, true, &read_10(0, 1), &[]);
+    assert_eq!((residue, status), (0, STATUS_PHASE_ERROR));
+    assert_eq!(read, &written[..100]);
+    let (_, residue, status) = host.command(0, true, &read_10(0, 1), &[]);
+    assert_eq!((residue, status), (0, STATUS_PHASE_ERROR));
+
+    // The host sends less data than the device writes. Only whole blocks are written, and the rest is left in
+    // the residue.
+    let short = pattern(BLOCK_SIZE + 88, 11);
+    let (_, residue, status) = host.command(short.len(), false, &write_10(4, 2), &short);
+    assert_eq!((residue, status), (88, STATUS_PHASE_ERROR));
+    assert_eq!(
+        &data.lock().unwrap()[4 * BLOCK_SIZE..5 * BLOCK_SIZE],
+        &short[..BLOCK_SIZE]
+    );
+    assert_eq!(
+        &data.lock().unwrap()[5 * BLOCK_SIZE..6 * BLOCK_SIZE],
+        &original[5 * BLOCK_SIZE..6 * BLOCK_SIZE]
+    );
+
+    // The host and the device disagree on the direction.
+    let (_, residue, status) = host.command(BLOCK_SIZE, false, &read_10(0, 1), &short[..BLOCK_SIZE]);
+    assert_eq!((residue, status), (BLOCK_SIZE as u32, STATUS_PHASE_ERROR));
+    let (read, residue, status) = host.command(BLOCK_SIZE, true, &write_10(0, 1), &[]);
+    assert_eq!(
+        (read.len(), residue, status),
+        (0, BLOCK_SIZE as u32, STATUS_PHASE_ERROR)
+    );
+    assert_eq!(&data.lock().unwrap()[..BLOCK_SIZE], &written[..BLOCK_SIZE]);
+
+    // The drive is still in sync with the host.
+    let (_, residue, status) = host.command(0, false, &TEST_UNIT_READY, &[]);
+    assert_eq!((residue, status), (0, STATUS_PASSED));
+}
+
+#[test]
+fn out_of_range_reads_and_writes() {
+    let (addr, data) = spawn_drive();
+    let mut host = Host::attach(addr);
+    let last = BLOCK_COUNT as u32 - 1;
+
+    let (_, residue, status) = host.command(BLOCK_SIZE, true, &read_10(last, 1), &[]);
+    assert_eq!((residue, status), (0, STATUS_PASSED));
+
+    for cdb in [&read_10(last, 2)[..], &read_10(u32::MAX, 1), &read_16(u64::MAX, 2)] {
+        let (read, residue, status) = host.command(2 * BLOCK_SIZE, true, cdb, &[]);
+        assert_eq!((read.len(), residue, status), (0, 2 * BLOCK_SIZE as u32, STATUS_FAILED));
+        // ILLEGAL REQUEST, LOGICAL BLOCK ADDRESS OUT OF RANGE
+        assert_eq!(host.sense(), (0x05, 0x21));
+    }
+
+    let written = pattern(2 * BLOCK_SIZE, 5);
+    for cdb in [write_10(last, 2), write_10(u32::MAX, 2)] {
+        let (_, residue, status) = host.command(written.len(), false, &cdb, &written);
+        assert_eq!((residue, status), (written.len() as u32, STATUS_FAILED));
+        assert_eq!(host.sense(), (0x05, 0x21));
+    }
+    assert!(data.lock().unwrap().iter().all(|&b| b == 0));
+
+    // The sense data is cleared once reported.
+    assert_eq!(host.sense(), (0x00, 0x00));
+}
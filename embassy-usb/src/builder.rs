@@ -1,13 +1,16 @@
 use heapless::Vec;
 
-use crate::config::MAX_HANDLER_COUNT;
+use crate::config::{MAX_CONFIGURATION_COUNT, MAX_HANDLER_COUNT};
 use crate::descriptor::{
     self, BosWriter, DescriptorWriter, SynchronizationType, UsageType, rewrite_config_descriptor_for_high_speed,
 };
-use crate::driver::{Driver, Endpoint, EndpointAddress, EndpointInfo, EndpointType};
+use crate::driver::{Bus, Driver, Endpoint, EndpointAddress, EndpointInfo, EndpointType};
 use crate::msos::{DeviceLevelDescriptor, FunctionLevelDescriptor, MsOsDescriptorWriter};
 use crate::types::{InterfaceNumber, StringIndex};
-use crate::{Handler, Inner, Interface, MAX_INTERFACE_COUNT, STRING_INDEX_CUSTOM_START, UsbDevice, UsbDeviceState};
+use crate::{
+    CONFIGURATION_NONE, CONFIGURATION_VALUE, Configuration, Handler, Inner, Interface, MAX_INTERFACE_COUNT,
+    STRING_INDEX_CUSTOM_START, UsbDevice, UsbDeviceState,
+};
 
 #[derive(Debug, Copy, Clone)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -30,6 +33,26 @@ pub enum UsbDeviceSpeed {
     High,
 }
 
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// Link Power Management (LPM) settings of the device.
+///
+/// The Best Effort Service Latency (BESL) values are indexes from 0 (125 µs) to 15 (10 ms) in the
+/// table of the USB 2.0 LPM errata. They tell the host how long the device needs to resume from the
+/// L1 sleep state, for example to restart its clocks. The host then drives the resume signaling at
+/// least that long when waking the device up.
+pub struct Lpm {
+    /// Recommended BESL when the device stays ready to resume quickly.
+    ///
+    /// Default: (none)
+    pub baseline_besl: Option<u8>,
+
+    /// Recommended BESL when the device saves as much power as it can.
+    ///
+    /// Default: (none)
+    pub deep_besl: Option<u8>,
+}
+
 #[derive(Debug, Copy, Clone)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[non_exhaustive]
@@ -102,6 +125,15 @@ pub struct Config<'a> {
     /// Default: `false`
     pub supports_remote_wakeup: bool,
 
+    /// Link Power Management (LPM) support, which lets the host put the link in the L1 sleep state.
+    ///
+    /// L1 is entered and left in microseconds, unlike suspend, so hosts use it whenever the link is idle.
+    /// LPM is advertised in the BOS descriptor, which requires `bcd_usb` 2.1, and only if the driver
+    /// supports it.
+    ///
+    /// Default: (none)
+    pub lpm: Option<Lpm>,
+
     /// Configures the device as a composite device with interface association descriptors.
     ///
     /// If set to `true` (default), the following fields should have the given values:
@@ -124,7 +156,7 @@ pub struct Config<'a> {
     /// See also: `max_power`
     pub self_powered: bool,
 
-    /// Maximum current drawn from the USB bus by the device, in milliamps.
+    /// Maximum current drawn from the USB bus by the device, in milliamps, in its first configuration.
     ///
     /// The default is 100 mA. If your device always uses an external power source and never draws
     /// power from the USB bus, this can be set to 0.
@@ -154,6 +186,7 @@ impl<'a> Config<'a> {
             serial_number: None,
             self_powered: false,
             supports_remote_wakeup: false,
+            lpm: None,
             composite_with_iads: true,
             max_power: 100,
         }
@@ -165,6 +198,7 @@ pub struct Builder<'d, D: Driver<'d>> {
     config: Config<'d>,
     handlers: Vec<&'d mut dyn Handler, MAX_HANDLER_COUNT>,
     interfaces: Vec<Interface, MAX_INTERFACE_COUNT>,
+    configurations: Vec<Configuration, MAX_CONFIGURATION_COUNT>,
     control_buf: &'d mut [u8],
 
     driver: D,
@@ -209,17 +243,36 @@ impl<'d, D: Driver<'d>> Builder<'d, D> {
             _ => panic!("invalid max_packet_size_0, the allowed values are 8, 16, 32 or 64"),
         }
 
+        if let Some(lpm) = config.lpm {
+            assert!(
+                matches!(config.bcd_usb, UsbVersion::TwoOne),
+                "LPM requires `bcd_usb` 2.1"
+            );
+            assert!(
+                lpm.baseline_besl.unwrap_or(0) <= 15 && lpm.deep_besl.unwrap_or(0) <= 15,
+                "The maximum allowed BESL value is 15"
+            );
+        }
+
         let mut config_descriptor = DescriptorWriter::new(config_descriptor_buf);
         let mut bos_descriptor = BosWriter::new(DescriptorWriter::new(bos_descriptor_buf));
 
-        config_descriptor.configuration(&config);
-        bos_descriptor.bos();
+        config_descriptor.configuration(&config, CONFIGURATION_VALUE, config.max_power, None);
+        bos_descriptor.bos(config.lpm);
+
+        let mut configurations = Vec::new();
+        let _ = configurations.push(Configuration {
+            descriptor_start: 0,
+            first_interface: 0,
+            first_handler: 0,
+        });
 
         Builder {
             driver,
             config,
             interfaces: Vec::new(),
             handlers: Vec::new(),
+            configurations,
             control_buf,
             next_string_index: STRING_INDEX_CUSTOM_START,
 
@@ -236,6 +289,7 @@ impl<'d, D: Driver<'d>> Builder<'d, D> {
             config,
             handlers,
             interfaces,
+            configurations,
             control_buf,
             driver,
             next_string_index: _,
@@ -246,6 +300,15 @@ impl<'d, D: Driver<'d>> Builder<'d, D> {
 
         let msos_descriptor = msos_descriptor.build(&mut bos_descriptor);
 
+        // Start the USB bus.
+        // This prevent further allocation by consuming the driver.
+        let (mut bus, control) = driver.start(config.max_packet_size_0 as u16);
+
+        if config.lpm.is_some() && bus.set_lpm_enabled(true).is_err() {
+            warn!("USB: the driver doesn't support LPM, not advertising it");
+            bos_descriptor.set_lpm(None);
+        }
+
         config_descriptor.end_configuration();
         let config_descriptor = config_descriptor.into_buf();
 
@@ -266,11 +329,9 @@ impl<'d, D: Driver<'d>> Builder<'d, D> {
         trace!("USB: msos_descriptor used: {}", msos_descriptor.len());
         trace!("USB: control_buf size: {}", control_buf.len());
 
-        // Start the USB bus.
-        // This prevent further allocation by consuming the driver.
-        let (bus, control) = driver.start(config.max_packet_size_0 as u16);
-        let device_descriptor = descriptor::device_descriptor(&config);
-        let device_qualifier_descriptor = descriptor::device_qualifier_descriptor(&config);
+        let num_configurations = configurations.len() as u8;
+        let device_descriptor = descriptor::device_descriptor(&config, num_configurations);
+        let device_qualifier_descriptor = descriptor::device_qualifier_descriptor(&config, num_configurations);
 
         UsbDevice {
             control_buf,
@@ -285,17 +346,61 @@ impl<'d, D: Driver<'d>> Builder<'d, D> {
                 msos_descriptor,
 
                 device_state: UsbDeviceState::Unpowered,
+                configuration: CONFIGURATION_NONE,
                 suspended: false,
                 remote_wakeup_enabled: false,
+                sleeping: false,
+                sleep_remote_wakeup: false,
                 self_powered: false,
                 address: 0,
                 set_address_pending: false,
                 interfaces,
+                configurations,
                 handlers,
             },
         }
     }
 
+    /// Starts a new configuration of the device.
+    ///
+    /// A device has a single configuration by default, described by [`Config`]. This ends the current
+    /// configuration: the functions, interfaces and handlers added afterwards belong to the new one. Each
+    /// configuration numbers its interfaces from 0, and the handlers of a configuration only get control
+    /// requests while the host has selected it. Endpoints can't be shared between configurations.
+    ///
+    /// `max_power` is the current drawn from the USB bus in this configuration, in milliamps.
+    ///
+    /// Returns the `bConfigurationValue` of the new configuration. Configurations are numbered from
+    /// [`CONFIGURATION_VALUE`], and the count is limited by the `max_configuration_count` compile-time setting.
+    pub fn configuration(&mut self, max_power: u16, configuration_string: Option<StringIndex>) -> u8 {
+        assert!(max_power <= 500, "The maximum allowed value for `max_power` is 500mA");
+
+        self.config_descriptor.end_configuration();
+        self.msos_descriptor.end_configuration();
+
+        let value = CONFIGURATION_VALUE + self.configurations.len() as u8;
+        assert!(
+            self.configurations
+                .push(Configuration {
+                    descriptor_start: self.config_descriptor.position(),
+                    first_interface: self.interfaces.len(),
+                    first_handler: self.handlers.len(),
+                })
+                .is_ok(),
+            "embassy-usb: configuration list full. Increase the `max_configuration_count` compile-time setting. Current value: {}",
+            MAX_CONFIGURATION_COUNT
+        );
+        self.config_descriptor
+            .configuration(&self.config, value, max_power, configuration_string);
+
+        value
+    }
+
+    /// Returns the number of interfaces in the current configuration.
+    fn interface_count(&self) -> usize {
+        self.interfaces.len() - self.configurations.last().unwrap().first_interface
+    }
+
     /// Returns the size of the control request data buffer. Can be used by
     /// classes to validate the buffer is large enough for their needs.
     pub fn control_buf_len(&self) -> usize {
@@ -309,7 +414,7 @@ impl<'d, D: Driver<'d>> Builder<'d, D> {
     ///
     /// If it's not set, no IAD descriptor is added.
     pub fn function(&mut self, class: u8, subclass: u8, protocol: u8) -> FunctionBuilder<'_, 'd, D> {
-        let first_interface = InterfaceNumber::new(self.interfaces.len() as u8);
+        let first_interface = InterfaceNumber::new(self.interface_count() as u8);
         let iface_count_index = if self.config.composite_with_iads {
             self.config_descriptor
                 .iad(first_interface, 0, class, subclass, protocol);
@@ -392,7 +497,7 @@ impl<'a, 'd, D: Driver<'d>> FunctionBuilder<'a, 'd, D> {
             self.builder.config_descriptor.buf[i] += 1;
         }
 
-        let number = self.builder.interfaces.len() as _;
+        let number = self.builder.interface_count() as _;
         let iface = Interface {
             current_alt_setting: 0,
             num_alt_settings: 0,
@@ -414,7 +519,9 @@ impl<'a, 'd, D: Driver<'d>> FunctionBuilder<'a, 'd, D> {
     /// Add an MS OS 2.0 Function Level Feature Descriptor.
     pub fn msos_feature<T: FunctionLevelDescriptor>(&mut self, desc: T) {
         if !self.builder.msos_descriptor.is_in_config_subset() {
-            self.builder.msos_descriptor.configuration(0);
+            // MS OS descriptors identify configurations by index.
+            let index = self.builder.configurations.len() - 1;
+            self.builder.msos_descriptor.configuration(index as u8);
         }
 
         if !self.builder.msos_descriptor.is_in_function_subset() {
@@ -457,7 +564,8 @@ impl<'a, 'd, D: Driver<'d>> InterfaceBuilder<'a, 'd, D> {
     ) -> InterfaceAltBuilder<'_, 'd, D> {
         let number = self.next_alt_setting_number;
         self.next_alt_setting_number += 1;
-        self.builder.interfaces[self.interface_number.0 as usize].num_alt_settings += 1;
+        let first_interface = self.builder.configurations.last().unwrap().first_interface;
+        self.builder.interfaces[first_interface + self.interface_number.0 as usize].num_alt_settings += 1;
 
         self.builder.config_descriptor.interface_alt(
             self.interface_number,
@@ -0,0 +1,436 @@
+//! Chip/Smart Card Interface Device class implementation, aka smart card readers.
+//!
+//! The reader has a single slot, backed by a [`SmartCard`], such as a secure element. It exchanges
+//! short APDUs with the host, so the card's transmission protocol and parameters are up to the
+//! [`SmartCard`], and hosts don't send TPDUs or negotiate parameters. Hosts support CCID without
+//! drivers, for example with PC/SC.
+//!
+//! There's no interrupt endpoint: the card is expected to stay in its slot, and the host checks
+//! whether it's present with the slot status.
+
+use core::mem::MaybeUninit;
+
+use crate::control::{InResponse, OutResponse, Recipient, Request, RequestType};
+use crate::driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointOut};
+use crate::types::InterfaceNumber;
+use crate::{Builder, Handler};
+
+/// This should be used as `device_class` when building the `UsbDevice`.
+pub const USB_CLASS_CCID: u8 = 0x0B;
+
+const CCID_SUBCLASS: u8 = 0x00;
+const CCID_PROTOCOL: u8 = 0x00;
+
+const DESCRIPTOR_TYPE_CCID: u8 = 0x21;
+
+const REQ_ABORT: u8 = 0x01;
+
+const PC_TO_RDR_SET_PARAMETERS: u8 = 0x61;
+const PC_TO_RDR_ICC_POWER_ON: u8 = 0x62;
+const PC_TO_RDR_ICC_POWER_OFF: u8 = 0x63;
+const PC_TO_RDR_GET_SLOT_STATUS: u8 = 0x65;
+const PC_TO_RDR_GET_PARAMETERS: u8 = 0x6C;
+const PC_TO_RDR_RESET_PARAMETERS: u8 = 0x6D;
+const PC_TO_RDR_ICC_CLOCK: u8 = 0x6E;
+const PC_TO_RDR_XFR_BLOCK: u8 = 0x6F;
+const PC_TO_RDR_ABORT: u8 = 0x72;
+
+const RDR_TO_PC_DATA_BLOCK: u8 = 0x80;
+const RDR_TO_PC_SLOT_STATUS: u8 = 0x81;
+const RDR_TO_PC_PARAMETERS: u8 = 0x82;
+
+const ICC_STATUS_ACTIVE: u8 = 0x00;
+const ICC_STATUS_INACTIVE: u8 = 0x01;
+const ICC_STATUS_NOT_PRESENT: u8 = 0x02;
+const COMMAND_STATUS_FAILED: u8 = 0x40;
+
+const ERROR_CMD_NOT_SUPPORTED: u8 = 0x00;
+const ERROR_BAD_LENGTH: u8 = 0x01;
+const ERROR_BAD_SLOT: u8 = 0x05;
+const ERROR_HW_ERROR: u8 = 0xFB;
+const ERROR_ICC_MUTE: u8 = 0xFE;
+
+const HEADER_LEN: usize = 10;
+/// Maximum length of a short APDU command: header, Lc, 255 bytes of data and Le.
+const MAX_APDU_LEN: usize = 261;
+/// Maximum length of a message, reported to the host.
+const MAX_MESSAGE_LEN: usize = HEADER_LEN + MAX_APDU_LEN;
+/// Size of the buffer receiving messages, enough for the header followed by any packet.
+const RX_BUFFER_LEN: usize = HEADER_LEN + 512;
+
+/// Default parameters of the T=0 protocol: Fi=372, Di=1, and a waiting integer of 10.
+const T0_PARAMETERS: [u8; 5] = [0x11, 0x00, 0x00, 0x0A, 0x00];
+
+/// A smart card, backing the slot of the reader.
+#[allow(async_fn_in_trait)]
+pub trait SmartCard {
+    /// Error type of the card.
+    type Error;
+
+    /// Check whether the card is present.
+    fn is_present(&self) -> bool {
+        true
+    }
+
+    /// Power the card on, write its answer to reset (ATR) to `atr`, and return its length.
+    ///
+    /// `atr` is at least 33 bytes, the maximum length of an ATR.
+    async fn power_on(&mut self, atr: &mut [u8]) -> Result<usize, Self::Error>;
+
+    /// Power the card off.
+    async fn power_off(&mut self) {}
+
+    /// Send the APDU `command` to the card, write its response APDU with the status word to
+    /// `response`, and return its length.
+    ///
+    /// `response` is at least 258 bytes, the maximum length of a short response APDU.
+    async fn transmit(&mut self, command: &[u8], response: &mut [u8]) -> Result<usize, Self::Error>;
+}
+
+impl<T: SmartCard + ?Sized> SmartCard for &mut T {
+    type Error = T::Error;
+
+    fn is_present(&self) -> bool {
+        T::is_present(self)
+    }
+
+    async fn power_on(&mut self, atr: &mut [u8]) -> Result<usize, Self::Error> {
+        T::power_on(self, atr).await
+    }
+
+    async fn power_off(&mut self) {
+        T::power_off(self).await
+    }
+
+    async fn transmit(&mut self, command: &[u8], response: &mut [u8]) -> Result<usize, Self::Error> {
+        T::transmit(self, command, response).await
+    }
+}
+
+/// Internal state for the CCID class.
+pub struct State {
+    control: MaybeUninit<Control>,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl State {
+    /// Create a new `State`.
+    pub const fn new() -> Self {
+        Self {
+            control: MaybeUninit::uninit(),
+        }
+    }
+}
+
+struct Control {
+    iface: InterfaceNumber,
+}
+
+impl Handler for Control {
+    fn control_out(&mut self, req: Request, _data: &[u8]) -> Option<OutResponse> {
+        if (req.request_type, req.recipient, req.index)
+            != (RequestType::Class, Recipient::Interface, self.iface.0 as u16)
+        {
+            return None;
+        }
+
+        match req.request {
+            REQ_ABORT => {
+                // Commands are handled one at a time, there's nothing in progress to abort. The
+                // host then sends PC_to_RDR_Abort, which completes the abort.
+                debug!("ccid: abort");
+                Some(OutResponse::Accepted)
+            }
+            _ => Some(OutResponse::Rejected),
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, _buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if (req.request_type, req.recipient, req.index)
+            != (RequestType::Class, Recipient::Interface, self.iface.0 as u16)
+        {
+            return None;
+        }
+
+        // No clock frequencies or data rates to list, the reader only exchanges APDUs.
+        Some(InResponse::Rejected)
+    }
+}
+
+/// Result of a command, before it's sent to the host.
+enum Reply {
+    /// A data block, with the length of the data.
+    DataBlock(usize),
+    /// The status of the slot.
+    SlotStatus,
+    /// The parameters of the T=0 protocol.
+    Parameters,
+    /// The command failed with an error code.
+    Error(u8),
+}
+
+/// USB Chip/Smart Card Interface Device class, with a single slot.
+pub struct CcidClass<'d, D: Driver<'d>> {
+    read_ep: D::EndpointOut,
+    write_ep: D::EndpointIn,
+    powered: bool,
+}
+
+impl<'d, D: Driver<'d>> CcidClass<'d, D> {
+    /// Creates a new CcidClass with the provided UsbBus and `max_packet_size` in bytes. For
+    /// full-speed devices, `max_packet_size` has to be 64.
+    pub fn new(builder: &mut Builder<'d, D>, state: &'d mut State, max_packet_size: u16) -> Self {
+        let mut func = builder.function(USB_CLASS_CCID, CCID_SUBCLASS, CCID_PROTOCOL);
+        let mut iface = func.interface();
+        let iface_num = iface.interface_number();
+        let mut alt = iface.alt_setting(USB_CLASS_CCID, CCID_SUBCLASS, CCID_PROTOCOL, None);
+
+        let max_message_len = (MAX_MESSAGE_LEN as u32).to_le_bytes();
+        alt.descriptor(
+            DESCRIPTOR_TYPE_CCID,
+            &[
+                0x10,               // bcdCCID (1.10)
+                0x01,               // |
+                0x00,               // bMaxSlotIndex
+                0x07,               // bVoltageSupport = 5V, 3V and 1.8V
+                0x03,               // dwProtocols = T=0 and T=1
+                0x00,               // |
+                0x00,               // |
+                0x00,               // |
+                0xFC,               // dwDefaultClock = 3.58 MHz
+                0x0D,               // |
+                0x00,               // |
+                0x00,               // |
+                0xFC,               // dwMaximumClock = 3.58 MHz
+                0x0D,               // |
+                0x00,               // |
+                0x00,               // |
+                0x00,               // bNumClockSupported
+                0x80,               // dwDataRate = 9600 bps
+                0x25,               // |
+                0x00,               // |
+                0x00,               // |
+                0x00,               // dwMaxDataRate = 115200 bps
+                0xC2,               // |
+                0x01,               // |
+                0x00,               // |
+                0x00,               // bNumDataRatesSupported
+                0xFE,               // dwMaxIFSD = 254
+                0x00,               // |
+                0x00,               // |
+                0x00,               // |
+                0x00,               // dwSynchProtocols
+                0x00,               // |
+                0x00,               // |
+                0x00,               // |
+                0x00,               // dwMechanical
+                0x00,               // |
+                0x00,               // |
+                0x00,               // |
+                0x7E,               // dwFeatures = automatic parameters, activation, voltage, clock, baud rate and
+                0x00,               // | negotiation, with short APDU level exchanges
+                0x02,               // |
+                0x00,               // |
+                max_message_len[0], // dwMaxCCIDMessageLength
+                max_message_len[1], // |
+                max_message_len[2], // |
+                max_message_len[3], // |
+                0xFF,               // bClassGetResponse = echo the class of the APDU
+                0xFF,               // bClassEnvelope = echo the class of the APDU
+                0x00,               // wLcdLayout = no LCD
+                0x00,               // |
+                0x00,               // bPINSupport = no PIN pad
+                0x01,               // bMaxCCIDBusySlots
+            ],
+        );
+
+        let read_ep = alt.endpoint_bulk_out(None, max_packet_size);
+        let write_ep = alt.endpoint_bulk_in(None, max_packet_size);
+        drop(func);
+
+        let control = state.control.write(Control { iface: iface_num });
+        builder.handler(control);
+
+        CcidClass {
+            read_ep,
+            write_ep,
+            powered: false,
+        }
+    }
+
+    /// Gets the maximum packet size in bytes.
+    pub fn max_packet_size(&self) -> u16 {
+        // The size is the same for both endpoints.
+        self.read_ep.info().max_packet_size
+    }
+
+    /// Waits for the USB host to enable this interface
+    pub async fn wait_connection(&mut self) {
+        self.read_ep.wait_enabled().await;
+    }
+
+    /// Serve the commands of the host on `card`, forever.
+    pub async fn run<C: SmartCard>(&mut self, card: &mut C) -> ! {
+        loop {
+            self.wait_connection().await;
+            info!("ccid: connected");
+
+            loop {
+                match self.command(card).await {
+                    Ok(()) => {}
+                    Err(EndpointError::Disabled) => break,
+                    Err(EndpointError::BufferOverflow) => warn!("ccid: buffer overflow"),
+                }
+            }
+            info!("ccid: disconnected");
+
+            if self.powered {
+                self.powered = false;
+                card.power_off().await;
+            }
+        }
+    }
+
+    /// Handle a command, from its message to the response.
+    async fn command<C: SmartCard>(&mut self, card: &mut C) -> Result<(), EndpointError> {
+        let mut msg = [0; RX_BUFFER_LEN];
+        let n = self.read_message(&mut msg).await?;
+        if n < HEADER_LEN {
+            warn!("ccid: message too short");
+            return Ok(());
+        }
+        let msg_type = msg[0];
+        let len = u32::from_le_bytes(msg[1..5].try_into().unwrap()) as usize;
+        let (slot, seq) = (msg[5], msg[6]);
+        trace!("ccid: message {:02x}", msg_type);
+
+        let mut resp = [0; MAX_MESSAGE_LEN];
+        let reply = if len > MAX_APDU_LEN || n < HEADER_LEN + len {
+            Reply::Error(ERROR_BAD_LENGTH)
+        } else if slot != 0 {
+            Reply::Error(ERROR_BAD_SLOT)
+        } else {
+            self.execute(
+                card,
+                msg_type,
+                &msg[HEADER_LEN..HEADER_LEN + len],
+                &mut resp[HEADER_LEN..],
+            )
+            .await
+        };
+
+        let icc_status = if !card.is_present() {
+            self.powered = false;
+            ICC_STATUS_NOT_PRESENT
+        } else if self.powered {
+            ICC_STATUS_ACTIVE
+        } else {
+            ICC_STATUS_INACTIVE
+        };
+        let (resp_type, data_len, status, error) = match reply {
+            Reply::DataBlock(len) => (RDR_TO_PC_DATA_BLOCK, len, icc_status, 0),
+            Reply::SlotStatus => (RDR_TO_PC_SLOT_STATUS, 0, icc_status, 0),
+            Reply::Parameters => {
+                resp[HEADER_LEN..][..T0_PARAMETERS.len()].copy_from_slice(&T0_PARAMETERS);
+                (RDR_TO_PC_PARAMETERS, T0_PARAMETERS.len(), icc_status, 0)
+            }
+            Reply::Error(error) => {
+                // Failed commands are answered with the response type matching the command.
+                let resp_type = match msg_type {
+                    PC_TO_RDR_ICC_POWER_ON | PC_TO_RDR_XFR_BLOCK => RDR_TO_PC_DATA_BLOCK,
+                    PC_TO_RDR_GET_PARAMETERS | PC_TO_RDR_RESET_PARAMETERS | PC_TO_RDR_SET_PARAMETERS => {
+                        RDR_TO_PC_PARAMETERS
+                    }
+                    _ => RDR_TO_PC_SLOT_STATUS,
+                };
+                (resp_type, 0, COMMAND_STATUS_FAILED | icc_status, error)
+            }
+        };
+
+        resp[0] = resp_type;
+        resp[1..5].copy_from_slice(&(data_len as u32).to_le_bytes());
+        resp[5] = slot;
+        resp[6] = seq;
+        resp[7] = status;
+        resp[8] = error;
+        // bChainParameter, bClockStatus or bProtocolNum are all 0.
+        self.write_ep.write_transfer(&resp[..HEADER_LEN + data_len], true).await
+    }
+
+    /// Execute a command on the slot, writing the data of the response to `resp`.
+    async fn execute<C: SmartCard>(&mut self, card: &mut C, msg_type: u8, data: &[u8], resp: &mut [u8]) -> Reply {
+        match msg_type {
+            PC_TO_RDR_ICC_POWER_ON => {
+                if !card.is_present() {
+                    return Reply::Error(ERROR_ICC_MUTE);
+                }
+                match card.power_on(resp).await {
+                    Ok(len) => {
+                        self.powered = true;
+                        Reply::DataBlock(len)
+                    }
+                    Err(_) => {
+                        warn!("ccid: power on failed");
+                        self.powered = false;
+                        Reply::Error(ERROR_ICC_MUTE)
+                    }
+                }
+            }
+            PC_TO_RDR_ICC_POWER_OFF => {
+                if self.powered {
+                    self.powered = false;
+                    card.power_off().await;
+                }
+                Reply::SlotStatus
+            }
+            PC_TO_RDR_XFR_BLOCK => {
+                if !self.powered || !card.is_present() {
+                    return Reply::Error(ERROR_ICC_MUTE);
+                }
+                match card.transmit(data, resp).await {
+                    Ok(len) => Reply::DataBlock(len),
+                    Err(_) => {
+                        warn!("ccid: transmit failed");
+                        Reply::Error(ERROR_HW_ERROR)
+                    }
+                }
+            }
+            PC_TO_RDR_GET_SLOT_STATUS | PC_TO_RDR_ICC_CLOCK | PC_TO_RDR_ABORT => Reply::SlotStatus,
+            // The parameters are handled by the card, and stay the default ones for the host.
+            PC_TO_RDR_GET_PARAMETERS | PC_TO_RDR_RESET_PARAMETERS | PC_TO_RDR_SET_PARAMETERS => Reply::Parameters,
+            _ => {
+                debug!("ccid: unsupported message {:02x}", msg_type);
+                Reply::Error(ERROR_CMD_NOT_SUPPORTED)
+            }
+        }
+    }
+
+    /// Read a message into `buf`, and return its length.
+    ///
+    /// Messages too long for `buf` are read to the end, and cut.
+    async fn read_message(&mut self, buf: &mut [u8; RX_BUFFER_LEN]) -> Result<usize, EndpointError> {
+        let mps = self.max_packet_size() as usize;
+        let mut n = self.read_ep.read(buf).await?;
+        if n < HEADER_LEN {
+            return Ok(n);
+        }
+        let total = HEADER_LEN.saturating_add(u32::from_le_bytes(buf[1..5].try_into().unwrap()) as usize);
+
+        let mut received = n;
+        let mut last = n;
+        while last == mps && received < total {
+            // Once the buffer is full, keep reading into its last packet to drop the rest, but keep
+            // the header.
+            let start = n.min(RX_BUFFER_LEN - mps);
+            last = self.read_ep.read(&mut buf[start..]).await?;
+            n = start + last;
+            received += last;
+        }
+        Ok(n)
+    }
+}
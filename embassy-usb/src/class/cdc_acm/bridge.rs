@@ -0,0 +1,171 @@
+//! Bridge between a CDC-ACM serial port and a UART, for USB to serial adapters.
+//!
+//! The [`UartBridge`] forwards the data of the serial port to any UART of a HAL implementing
+//! [`SetConfig`] and the `embedded-io-async` traits, for example the buffered UARTs of
+//! `embassy_stm32::usart`, `embassy_rp::uart` and `embassy_nrf::buffered_uarte`. The UART is
+//! reconfigured when the host changes the line coding, and the rest of the control requests of the
+//! host are passed to a [`BridgeHandler`].
+//!
+//! Flow control is end to end: the bridge stops reading from the host while the UART is busy
+//! sending, and stops reading from the UART while the host doesn't read from the serial port. With
+//! hardware flow control enabled in the configuration of the UART, the HAL then deasserts RTS once
+//! its buffer is full.
+
+use embassy_embedded_hal::SetConfig;
+use embassy_futures::join::join3;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::mutex::Mutex;
+use embedded_io_async::{Read, Write};
+
+use super::{Break, CdcAcmClass, ControlChanged, LineCoding, Receiver, Sender};
+use crate::driver::{Driver, EndpointError};
+
+/// Handles what the UART traits don't cover for a [`UartBridge`].
+#[allow(async_fn_in_trait)]
+pub trait BridgeHandler<T: SetConfig> {
+    /// Returns the configuration of the UART for the line coding set by the host.
+    ///
+    /// If the UART doesn't support the line coding, return `None` to keep its current configuration.
+    fn config(&mut self, line_coding: &LineCoding) -> Option<T::Config>;
+
+    /// Called when the host changes DTR or RTS, for example to drive the modem control lines of
+    /// the UART. Does nothing by default.
+    fn control_lines(&mut self, dtr: bool, rts: bool) {
+        let _ = (dtr, rts);
+    }
+
+    /// Called when the host requests a break, to send it with `tx`. Does nothing by default.
+    ///
+    /// The host can only request breaks if the class was created with
+    /// [`CdcAcmClass::new_with_send_break`].
+    ///
+    /// The data written to `tx` before the request has already been flushed.
+    async fn send_break(&mut self, tx: &mut T, request: Break) {
+        let _ = (tx, request);
+    }
+}
+
+/// Forwards a CDC-ACM serial port to a UART.
+pub struct UartBridge<'d, D: Driver<'d>> {
+    sender: Sender<'d, D>,
+    receiver: Receiver<'d, D>,
+    control: ControlChanged<'d>,
+}
+
+impl<'d, D: Driver<'d>> UartBridge<'d, D> {
+    /// Creates a bridge forwarding the serial port of `class`.
+    pub fn new(class: CdcAcmClass<'d, D>) -> Self {
+        let (sender, receiver, control) = class.split_with_control();
+        Self {
+            sender,
+            receiver,
+            control,
+        }
+    }
+
+    /// Forwards the data of the serial port to `tx`, and the data of `rx` to the serial port, forever.
+    ///
+    /// `tx` and `rx` are the halves of the UART, `tx` being the one reconfigured when the host
+    /// changes the line coding. `buf` must be at least twice the max packet size of the class.
+    ///
+    /// The data received from the UART while the device isn't connected is dropped.
+    pub async fn run<T, R, H>(&mut self, tx: &mut T, rx: &mut R, handler: &mut H, buf: &mut [u8]) -> !
+    where
+        T: SetConfig + Write,
+        R: Read,
+        H: BridgeHandler<T>,
+    {
+        let mps = self.receiver.max_packet_size() as usize;
+        assert!(buf.len() >= 2 * mps);
+        let (out_buf, in_buf) = buf.split_at_mut(mps);
+        let in_buf = &mut in_buf[..mps];
+
+        let tx = Mutex::<NoopRawMutex, _>::new(tx);
+        let receiver = &mut self.receiver;
+        let sender = &mut self.sender;
+        let control = &self.control;
+
+        let usb_to_uart = async {
+            loop {
+                receiver.wait_connection().await;
+                match receiver.read_packet(out_buf).await {
+                    Ok(n) => {
+                        if tx.lock().await.write_all(&out_buf[..n]).await.is_err() {
+                            warn!("cdc-acm bridge: UART write failed");
+                        }
+                    }
+                    Err(EndpointError::Disabled) => {}
+                    Err(EndpointError::BufferOverflow) => unreachable!(),
+                }
+            }
+        };
+
+        let uart_to_usb = async {
+            loop {
+                let n = match rx.read(in_buf).await {
+                    Ok(n) => n,
+                    Err(_) => {
+                        warn!("cdc-acm bridge: UART read failed");
+                        continue;
+                    }
+                };
+                if n == 0 {
+                    continue;
+                }
+
+                // A full packet doesn't end the transfer, so the host would hold the data until the
+                // next short packet. Follow it with a zero-length packet.
+                let mut result = sender.write_packet(&in_buf[..n]).await;
+                if result.is_ok() && n == mps {
+                    result = sender.write_packet(&[]).await;
+                }
+                match result {
+                    Ok(()) | Err(EndpointError::Disabled) => {}
+                    Err(EndpointError::BufferOverflow) => unreachable!(),
+                }
+            }
+        };
+
+        let control_fut = async {
+            let mut line_coding = None;
+            let mut lines = (false, false);
+            loop {
+                control.control_changed().await;
+
+                // The line coding goes back to its default when the device is reset, the UART keeps
+                // its configuration until the host sets a new one.
+                let new_line_coding = control.line_coding();
+                if control.line_coding_set() && line_coding != Some(new_line_coding) {
+                    line_coding = Some(new_line_coding);
+                    if let Some(config) = handler.config(&new_line_coding) {
+                        let mut tx = tx.lock().await;
+                        // Let the data sent with the previous configuration go out first.
+                        if tx.flush().await.is_err() {
+                            warn!("cdc-acm bridge: UART flush failed");
+                        }
+                        if tx.set_config(&config).is_err() {
+                            warn!("cdc-acm bridge: UART configuration failed");
+                        }
+                    }
+                }
+
+                let new_lines = (control.dtr(), control.rts());
+                if lines != new_lines {
+                    lines = new_lines;
+                    handler.control_lines(new_lines.0, new_lines.1);
+                }
+
+                if let Some(request) = control.take_break() {
+                    let mut tx = tx.lock().await;
+                    if tx.flush().await.is_err() {
+                        warn!("cdc-acm bridge: UART flush failed");
+                    }
+                    handler.send_break(&mut **tx, request).await;
+                }
+            }
+        };
+
+        join3(usb_to_uart, uart_to_usb, control_fut).await;
+        unreachable!()
+    }
+}
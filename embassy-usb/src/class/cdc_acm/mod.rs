@@ -1,4 +1,6 @@
 //! CDC-ACM class implementation, aka Serial over USB.
+//!
+//! With the `uart-bridge` feature, the `bridge` module forwards the port to a UART.
 
 use core::cell::{Cell, RefCell};
 use core::future::{Future, poll_fn};
@@ -14,6 +16,9 @@ use crate::driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointOut};
 use crate::types::InterfaceNumber;
 use crate::{Builder, Handler};
 
+#[cfg(feature = "uart-bridge")]
+pub mod bridge;
+
 /// This should be used as `device_class` when building the `UsbDevice`.
 pub const USB_CLASS_CDC: u8 = 0x02;
 
@@ -32,6 +37,7 @@ const REQ_GET_ENCAPSULATED_COMMAND: u8 = 0x01;
 const REQ_SET_LINE_CODING: u8 = 0x20;
 const REQ_GET_LINE_CODING: u8 = 0x21;
 const REQ_SET_CONTROL_LINE_STATE: u8 = 0x22;
+const REQ_SEND_BREAK: u8 = 0x23;
 
 /// CDC ACM error.
 #[derive(Clone, Debug)]
@@ -101,14 +107,17 @@ pub struct CdcAcmClass<'d, D: Driver<'d>> {
 
 struct Control<'a> {
     comm_if: InterfaceNumber,
+    send_break: bool,
     shared: &'a ControlShared,
 }
 
 /// Shared data between Control and CdcAcmClass
 struct ControlShared {
     line_coding: CriticalSectionMutex<Cell<LineCoding>>,
+    line_coding_set: AtomicBool,
     dtr: AtomicBool,
     rts: AtomicBool,
+    break_request: CriticalSectionMutex<Cell<Option<Break>>>,
 
     waker: RefCell<WakerRegistration>,
     changed: AtomicBool,
@@ -125,12 +134,14 @@ impl ControlShared {
         ControlShared {
             dtr: AtomicBool::new(false),
             rts: AtomicBool::new(false),
+            break_request: CriticalSectionMutex::new(Cell::new(None)),
             line_coding: CriticalSectionMutex::new(Cell::new(LineCoding {
                 stop_bits: StopBits::One,
                 data_bits: 8,
                 parity_type: ParityType::None,
                 data_rate: 8_000,
             })),
+            line_coding_set: AtomicBool::new(false),
             waker: RefCell::new(WakerRegistration::new()),
             changed: AtomicBool::new(false),
         }
@@ -147,6 +158,10 @@ impl ControlShared {
             }
         })
     }
+
+    fn take_break(&self) -> Option<Break> {
+        self.break_request.lock(Cell::take)
+    }
 }
 
 impl<'a> Control<'a> {
@@ -159,8 +174,10 @@ impl<'d> Handler for Control<'d> {
     fn reset(&mut self) {
         let shared = self.shared();
         shared.line_coding.lock(|x| x.set(LineCoding::default()));
+        shared.line_coding_set.store(false, Ordering::Relaxed);
         shared.dtr.store(false, Ordering::Relaxed);
         shared.rts.store(false, Ordering::Relaxed);
+        shared.break_request.lock(|x| x.set(None));
 
         shared.changed.store(true, Ordering::Relaxed);
         shared.waker.borrow_mut().wake();
@@ -188,6 +205,7 @@ impl<'d> Handler for Control<'d> {
                 };
                 let shared = self.shared();
                 shared.line_coding.lock(|x| x.set(coding));
+                shared.line_coding_set.store(true, Ordering::Relaxed);
                 debug!("Set line coding to: {:?}", coding);
 
                 shared.changed.store(true, Ordering::Relaxed);
@@ -209,6 +227,22 @@ impl<'d> Handler for Control<'d> {
 
                 Some(OutResponse::Accepted)
             }
+            REQ_SEND_BREAK if self.send_break => {
+                let request = match req.value {
+                    0 => Break::Stop,
+                    0xFFFF => Break::UntilStopped,
+                    ms => Break::Millis(ms),
+                };
+
+                let shared = self.shared();
+                shared.break_request.lock(|x| x.set(Some(request)));
+                debug!("Send break: {:?}", request);
+
+                shared.changed.store(true, Ordering::Relaxed);
+                shared.waker.borrow_mut().wake();
+
+                Some(OutResponse::Accepted)
+            }
             _ => Some(OutResponse::Rejected),
         }
     }
@@ -240,7 +274,25 @@ impl<'d> Handler for Control<'d> {
 impl<'d, D: Driver<'d>> CdcAcmClass<'d, D> {
     /// Creates a new CdcAcmClass with the provided UsbBus and `max_packet_size` in bytes. For
     /// full-speed devices, `max_packet_size` has to be one of 8, 16, 32 or 64.
+    ///
+    /// SEND_BREAK requests are rejected, use [`new_with_send_break`](Self::new_with_send_break) to
+    /// accept them.
     pub fn new(builder: &mut Builder<'d, D>, state: &'d mut State<'d>, max_packet_size: u16) -> Self {
+        Self::new_inner(builder, state, max_packet_size, false)
+    }
+
+    /// Creates a new CdcAcmClass like [`new`](Self::new), that also advertises and accepts the
+    /// SEND_BREAK request. The breaks requested by the host are then returned by `take_break`.
+    pub fn new_with_send_break(builder: &mut Builder<'d, D>, state: &'d mut State<'d>, max_packet_size: u16) -> Self {
+        Self::new_inner(builder, state, max_packet_size, true)
+    }
+
+    fn new_inner(
+        builder: &mut Builder<'d, D>,
+        state: &'d mut State<'d>,
+        max_packet_size: u16,
+        send_break: bool,
+    ) -> Self {
         assert!(builder.control_buf_len() >= 7);
 
         let mut func = builder.function(USB_CLASS_CDC, CDC_SUBCLASS_ACM, CDC_PROTOCOL_NONE);
@@ -263,10 +315,12 @@ impl<'d, D: Driver<'d>> CdcAcmClass<'d, D> {
             CS_INTERFACE,
             &[
                 CDC_TYPE_ACM, // bDescriptorSubtype
-                0x02,         // bmCapabilities:
-                              // D1: Device supports the request combination of
-                              // Set_Line_Coding, Set_Control_Line_State, Get_Line_Coding,
-                              // and the Notification Serial_State.
+                // bmCapabilities:
+                // D1: Device supports the request combination of
+                // Set_Line_Coding, Set_Control_Line_State, Get_Line_Coding,
+                // and the Notification Serial_State.
+                // D2: Device supports the request Send_Break.
+                if send_break { 0x06 } else { 0x02 },
             ],
         );
         alt.descriptor(
@@ -291,6 +345,7 @@ impl<'d, D: Driver<'d>> CdcAcmClass<'d, D> {
 
         let control = state.control.write(Control {
             shared: &state.shared,
+            send_break,
             comm_if,
         });
         builder.handler(control);
@@ -318,6 +373,12 @@ impl<'d, D: Driver<'d>> CdcAcmClass<'d, D> {
         self.control.line_coding.lock(Cell::get)
     }
 
+    /// Returns whether the host set the line coding since the device was reset. Until it does,
+    /// `line_coding` returns the default line coding.
+    pub fn line_coding_set(&self) -> bool {
+        self.control.line_coding_set.load(Ordering::Relaxed)
+    }
+
     /// Gets the DTR (data terminal ready) state
     pub fn dtr(&self) -> bool {
         self.control.dtr.load(Ordering::Relaxed)
@@ -328,6 +389,11 @@ impl<'d, D: Driver<'d>> CdcAcmClass<'d, D> {
         self.control.rts.load(Ordering::Relaxed)
     }
 
+    /// Takes the last break requested by the host, if it wasn't taken yet.
+    pub fn take_break(&self) -> Option<Break> {
+        self.control.take_break()
+    }
+
     /// Writes a single packet into the IN endpoint.
     pub async fn write_packet(&mut self, data: &[u8]) -> Result<(), EndpointError> {
         self.write_ep.write(data).await
@@ -362,7 +428,7 @@ impl<'d, D: Driver<'d>> CdcAcmClass<'d, D> {
     /// Split the class into sender, receiver and control
     ///
     /// Allows concurrently sending and receiving packets whilst monitoring for
-    /// control changes (dtr, rts)
+    /// control changes (dtr, rts, line coding and breaks)
     pub fn split_with_control(self) -> (Sender<'d, D>, Receiver<'d, D>, ControlChanged<'d>) {
         (
             Sender {
@@ -405,6 +471,17 @@ impl<'d> ControlChanged<'d> {
     pub fn line_coding(&self) -> LineCoding {
         self.control.line_coding.lock(Cell::get)
     }
+
+    /// Returns whether the host set the line coding since the device was reset. Until it does,
+    /// `line_coding` returns the default line coding.
+    pub fn line_coding_set(&self) -> bool {
+        self.control.line_coding_set.load(Ordering::Relaxed)
+    }
+
+    /// Takes the last break requested by the host, if it wasn't taken yet.
+    pub fn take_break(&self) -> Option<Break> {
+        self.control.take_break()
+    }
 }
 
 /// CDC ACM class packet sender.
@@ -428,6 +505,12 @@ impl<'d, D: Driver<'d>> Sender<'d, D> {
         self.control.line_coding.lock(Cell::get)
     }
 
+    /// Returns whether the host set the line coding since the device was reset. Until it does,
+    /// `line_coding` returns the default line coding.
+    pub fn line_coding_set(&self) -> bool {
+        self.control.line_coding_set.load(Ordering::Relaxed)
+    }
+
     /// Gets the DTR (data terminal ready) state
     pub fn dtr(&self) -> bool {
         self.control.dtr.load(Ordering::Relaxed)
@@ -489,6 +572,12 @@ impl<'d, D: Driver<'d>> Receiver<'d, D> {
         self.control.line_coding.lock(Cell::get)
     }
 
+    /// Returns whether the host set the line coding since the device was reset. Until it does,
+    /// `line_coding` returns the default line coding.
+    pub fn line_coding_set(&self) -> bool {
+        self.control.line_coding_set.load(Ordering::Relaxed)
+    }
+
     /// Gets the DTR (data terminal ready) state
     pub fn dtr(&self) -> bool {
         self.control.dtr.load(Ordering::Relaxed)
@@ -659,11 +748,23 @@ impl From<u8> for ParityType {
     }
 }
 
+/// Break condition requested by the host with a SEND_BREAK request.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Break {
+    /// Stop sending a break.
+    Stop,
+    /// Send a break for this number of milliseconds.
+    Millis(u16),
+    /// Send a break until a [`Break::Stop`] is requested.
+    UntilStopped,
+}
+
 /// Line coding parameters
 ///
 /// This is provided by the host for specifying the standard UART parameters such as baud rate. Can
 /// be ignored if you don't plan to interface with a physical UART.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct LineCoding {
     stop_bits: StopBits,
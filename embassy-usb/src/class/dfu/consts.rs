@@ -8,6 +8,13 @@ pub(crate) const DFU_PROTOCOL_DFU: u8 = 0x02;
 pub(crate) const DFU_PROTOCOL_RT: u8 = 0x01;
 pub(crate) const DESC_DFU_FUNCTIONAL: u8 = 0x21;
 
+/// DfuSe command reporting the supported commands, in an UPLOAD of block 0.
+pub(crate) const DFUSE_CMD_GET_COMMANDS: u8 = 0x00;
+/// DfuSe command setting the address pointer.
+pub(crate) const DFUSE_CMD_SET_ADDRESS: u8 = 0x21;
+/// DfuSe command erasing the page at an address, or the whole memory without an address.
+pub(crate) const DFUSE_CMD_ERASE: u8 = 0x41;
+
 #[cfg(feature = "defmt")]
 defmt::bitflags! {
     /// Attributes supported by the DFU controller.
@@ -9,7 +9,7 @@ use crate::{Builder, FunctionBuilder};
 
 /// Handler trait for DFU bootloader mode.
 ///
-/// Implement this trait to handle firmware download operations.
+/// Implement this trait to handle firmware download and upload operations.
 pub trait Handler {
     /// Called when a firmware download starts.
     ///
@@ -32,6 +32,19 @@ pub trait Handler {
     /// Returns `Ok(())` on success, or a `Status` error on failure.
     fn finish(&mut self) -> Result<(), Status>;
 
+    /// Called to read a chunk of firmware for an upload.
+    ///
+    /// This is called for each DFU_UPLOAD request when [`DfuAttributes::CAN_UPLOAD`] is set,
+    /// with `offset` the number of bytes uploaded so far. Reading fewer bytes than `buf` holds
+    /// ends the upload.
+    ///
+    /// Returns the number of bytes read on success, or a `Status` error on failure. The default
+    /// implementation rejects uploads.
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<usize, Status> {
+        let _ = (offset, buf);
+        Err(Status::ErrStalledPkt)
+    }
+
     /// Called at the end of the DFU procedure.
     ///
     /// This is typically where you would perform a system reset to boot
@@ -46,6 +59,7 @@ pub struct DfuState<H: Handler> {
     state: State,
     status: Status,
     next_block_num: usize,
+    upload_offset: usize,
 }
 
 impl<H: Handler> DfuState<H> {
@@ -57,6 +71,7 @@ impl<H: Handler> DfuState<H> {
             state: State::DfuIdle,
             status: Status::Ok,
             next_block_num: 0,
+            upload_offset: 0,
         }
     }
 
@@ -72,6 +87,7 @@ impl<H: Handler> DfuState<H> {
 
     fn reset_state(&mut self) {
         self.next_block_num = 0;
+        self.upload_offset = 0;
         self.state = State::DfuIdle;
         self.status = Status::Ok;
     }
@@ -198,8 +214,41 @@ impl<H: Handler> crate::Handler for DfuState<H> {
                 Some(InResponse::Accepted(&buf[0..1]))
             }
             Ok(Request::Upload) if self.attrs.contains(DfuAttributes::CAN_UPLOAD) => {
-                //TODO: FirmwareUpdater does not provide a way of reading the active partition, can't upload.
-                Some(InResponse::Rejected)
+                match self.state {
+                    State::DfuIdle => {
+                        info!("Upload starting");
+                        self.upload_offset = 0;
+                    }
+                    State::UploadIdle => {}
+                    _ => {
+                        error!("Unexpected UPLOAD while not idle");
+                        self.state = State::Error;
+                        self.status = Status::ErrStalledPkt;
+                        return Some(InResponse::Rejected);
+                    }
+                }
+
+                // A short frame would end the upload, so frames must fit in the control buffer.
+                let len = req.length as usize;
+                if len > buf.len() {
+                    error!("UPLOAD of {} bytes doesn't fit in the control buffer", len);
+                    self.state = State::Error;
+                    self.status = Status::ErrStalledPkt;
+                    return Some(InResponse::Rejected);
+                }
+                match self.handler.read(self.upload_offset, &mut buf[..len]) {
+                    Ok(n) => {
+                        self.upload_offset += n;
+                        // A short frame ends the upload.
+                        self.state = if n < len { State::DfuIdle } else { State::UploadIdle };
+                        Some(InResponse::Accepted(&buf[..n]))
+                    }
+                    Err(e) => {
+                        self.state = State::Error;
+                        self.status = e;
+                        Some(InResponse::Rejected)
+                    }
+                }
             }
             _ => {
                 debug!("Unknown IN request {:?}", req);
@@ -212,16 +261,26 @@ impl<H: Handler> crate::Handler for DfuState<H> {
 /// An implementation of the USB DFU 1.1 protocol
 ///
 /// This function will add a DFU interface descriptor to the provided Builder, and register the provided Control as a handler for the USB device
-/// The handler is responsive to DFU GetState, GetStatus, Abort, and ClrStatus commands, as well as Download if configured by the user.
+/// The handler is responsive to DFU GetState, GetStatus, Abort, and ClrStatus commands, as well as Download and Upload if configured by the user.
 ///
 /// Once the host has initiated a DFU download operation, the chunks sent by the host will be written to the DFU partition.
 /// Once the final sync in the manifestation phase has been received, the handler will trigger a system reset to swap the new firmware.
+///
+/// # Panics
+///
+/// Panics if `max_write_size` is larger than the control buffer of `builder`, which holds each
+/// transfer.
 pub fn usb_dfu<'d, D: Driver<'d>, H: Handler>(
     builder: &mut Builder<'d, D>,
     state: &'d mut DfuState<H>,
     max_write_size: usize,
     func_modifier: impl Fn(&mut FunctionBuilder<'_, 'd, D>),
 ) {
+    assert!(
+        max_write_size <= builder.control_buf_len(),
+        "DFU transfer size is larger than the control buffer"
+    );
+
     let mut func = builder.function(0x00, 0x00, 0x00);
 
     // Here we give users the opportunity to add their own function level MSOS headers for instance.
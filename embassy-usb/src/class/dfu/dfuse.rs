@@ -0,0 +1,449 @@
+//! DfuSe, STMicroelectronics' extension of DFU 1.1, in bootloader mode.
+//!
+//! Instead of a single firmware image, DfuSe exposes memories at explicit addresses. Each
+//! alternate setting of the DFU interface is a memory, described by its interface string:
+//!
+//! ```text
+//! @Internal Flash  /0x08000000/04*016Kg,01*064Kg,07*128Kg
+//! ```
+//!
+//! The string holds the name of the memory, its start address, and its sectors as `count*size`
+//! followed by a unit (` ` for bytes, `K` or `M`) and their attributes: `a` readable, `b` erasable,
+//! `d` writable, and `c`, `e`, `f` or `g` for their combinations. Hosts such as `dfu-util` select a
+//! memory with the alternate setting, and pick addresses from its layout:
+//!
+//! ```text
+//! dfu-util -a 0 -s 0x08000000:leave -D firmware.bin
+//! ```
+//!
+//! DFU_DNLOAD requests of block 0 carry commands, to set the address pointer or erase a page.
+//! Data blocks `n >= 2` are downloaded to, or uploaded from, the address pointer plus `n - 2`
+//! transfer sizes. The [`Handler`] sees offsets from the start address of the selected memory.
+
+use embassy_usb_driver::Driver;
+
+use super::consts::{
+    APPN_SPEC_SUBCLASS_DFU, DESC_DFU_FUNCTIONAL, DFU_PROTOCOL_DFU, DFUSE_CMD_ERASE, DFUSE_CMD_GET_COMMANDS,
+    DFUSE_CMD_SET_ADDRESS, DfuAttributes, Request, State, Status, USB_CLASS_APPN_SPEC,
+};
+use crate::control::{InResponse, OutResponse, Recipient, Request as ControlRequest, RequestType};
+use crate::types::{InterfaceNumber, StringIndex};
+use crate::{Builder, FunctionBuilder};
+
+/// Handler trait for DfuSe bootloader mode.
+///
+/// Implement this trait to access the memories exposed on each alternate setting.
+pub trait Handler {
+    /// Called to erase the page holding `offset` in the memory of alternate setting `alt`.
+    ///
+    /// Returns `Ok(())` on success, or a `Status` error on failure.
+    fn erase(&mut self, alt: u8, offset: u32) -> Result<(), Status>;
+
+    /// Called to erase the whole memory of alternate setting `alt`.
+    ///
+    /// Returns `Ok(())` on success, or a `Status` error on failure. The default implementation
+    /// rejects mass erases.
+    fn mass_erase(&mut self, alt: u8) -> Result<(), Status> {
+        let _ = alt;
+        Err(Status::ErrTarget)
+    }
+
+    /// Called to write a chunk of data at `offset` in the memory of alternate setting `alt`.
+    ///
+    /// Returns `Ok(())` on success, or a `Status` error on failure.
+    fn write(&mut self, alt: u8, offset: u32, data: &[u8]) -> Result<(), Status>;
+
+    /// Called to read a chunk of data at `offset` in the memory of alternate setting `alt`.
+    ///
+    /// Reading fewer bytes than `buf` holds ends the upload.
+    ///
+    /// Returns the number of bytes read on success, or a `Status` error on failure.
+    fn read(&mut self, alt: u8, offset: u32, buf: &mut [u8]) -> Result<usize, Status>;
+
+    /// Called when the host leaves DFU mode, with a zero-length DFU_DNLOAD.
+    ///
+    /// This is where you would typically mark downloaded firmware as ready to boot.
+    ///
+    /// Returns `Ok(())` on success, or a `Status` error on failure.
+    fn finish(&mut self) -> Result<(), Status>;
+
+    /// Called at the end of the DFU procedure.
+    ///
+    /// This is typically where you would perform a system reset to boot
+    /// the new firmware.
+    fn system_reset(&mut self);
+}
+
+/// Internal state for USB DfuSe
+pub struct DfuState<'d, H: Handler> {
+    handler: H,
+    attrs: DfuAttributes,
+    layouts: &'d [&'d str],
+    iface: Option<InterfaceNumber>,
+    first_string: Option<StringIndex>,
+    transfer_size: usize,
+    alt: u8,
+    state: State,
+    status: Status,
+    address: u32,
+}
+
+impl<'d, H: Handler> DfuState<'d, H> {
+    /// Create a new DfuSe instance to handle DFU transfers.
+    ///
+    /// Each entry of `layouts` is the interface string of an alternate setting, describing the
+    /// memory it exposes. Layouts must start with `@`, and hold the start address and the sectors
+    /// of the memory. Accesses outside of the sectors following the first start address are
+    /// refused.
+    pub fn new(handler: H, attrs: DfuAttributes, layouts: &'d [&'d str]) -> Self {
+        assert!(!layouts.is_empty() && layouts.len() <= u8::MAX as usize);
+        for layout in layouts {
+            assert!(
+                layout.starts_with('@') && start_address(layout).is_some() && memory_size(layout).is_some(),
+                "invalid DfuSe memory layout"
+            );
+        }
+
+        Self {
+            handler,
+            attrs,
+            layouts,
+            iface: None,
+            first_string: None,
+            transfer_size: 0,
+            alt: 0,
+            state: State::DfuIdle,
+            status: Status::Ok,
+            address: 0,
+        }
+    }
+
+    /// Get a mutable reference to the handler.
+    pub fn handler_mut(&mut self) -> &mut H {
+        &mut self.handler
+    }
+
+    /// Set DFU instance into firmware error
+    ///
+    /// This is typically called when the stored firmware
+    /// is corrupt and can not be booted.
+    pub fn set_to_firmware_error(&mut self) {
+        self.reset_state();
+        self.state = State::Error;
+        self.status = Status::ErrFirmware;
+    }
+
+    fn reset_state(&mut self) {
+        self.state = State::DfuIdle;
+        self.status = Status::Ok;
+    }
+
+    fn fail(&mut self, status: Status) {
+        self.state = State::Error;
+        self.status = status;
+    }
+
+    /// Offset of `address` in the selected memory, and the number of bytes after it.
+    fn offset(&self, address: u32) -> Result<(u32, u32), Status> {
+        let layout = self.layouts[self.alt as usize];
+        let start = start_address(layout).ok_or(Status::ErrAddress)?;
+        let size = memory_size(layout).ok_or(Status::ErrAddress)?;
+        let offset = address.checked_sub(start).ok_or(Status::ErrAddress)?;
+        let remaining = size.checked_sub(offset).ok_or(Status::ErrAddress)?;
+        Ok((offset, remaining))
+    }
+
+    /// Offset in the selected memory of data block `block`, numbered from 2, and the number of
+    /// bytes after it.
+    fn block_offset(&self, block: u16) -> Result<(u32, u32), Status> {
+        let address = (block as u32 - 2)
+            .checked_mul(self.transfer_size as u32)
+            .and_then(|offset| self.address.checked_add(offset))
+            .ok_or(Status::ErrAddress)?;
+        self.offset(address)
+    }
+
+    fn command(&mut self, data: &[u8]) -> Result<(), Status> {
+        let address = match data[1..] {
+            [] => None,
+            [a, b, c, d] => Some(u32::from_le_bytes([a, b, c, d])),
+            _ => return Err(Status::ErrStalledPkt),
+        };
+        match (data[0], address) {
+            (DFUSE_CMD_SET_ADDRESS, Some(address)) => {
+                debug!("Setting address pointer to {:x}", address);
+                self.address = address;
+                Ok(())
+            }
+            (DFUSE_CMD_ERASE, Some(address)) => {
+                debug!("Erasing page at {:x}", address);
+                match self.offset(address)? {
+                    (_, 0) => Err(Status::ErrAddress),
+                    (offset, _) => self.handler.erase(self.alt, offset),
+                }
+            }
+            (DFUSE_CMD_ERASE, None) => {
+                info!("Mass erase of alternate setting {}", self.alt);
+                self.handler.mass_erase(self.alt)
+            }
+            _ => Err(Status::ErrStalledPkt),
+        }
+    }
+}
+
+impl<'d, H: Handler> crate::Handler for DfuState<'d, H> {
+    fn reset(&mut self) {
+        if matches!(
+            self.state,
+            State::ManifestSync | State::Manifest | State::ManifestWaitReset
+        ) {
+            self.handler.system_reset();
+        }
+    }
+
+    fn set_alternate_setting(&mut self, iface: InterfaceNumber, alternate_setting: u8) {
+        if self.iface == Some(iface) && (alternate_setting as usize) < self.layouts.len() {
+            debug!("Selecting alternate setting {}", alternate_setting);
+            self.alt = alternate_setting;
+            self.reset_state();
+        }
+    }
+
+    fn control_out(&mut self, req: ControlRequest, data: &[u8]) -> Option<OutResponse> {
+        if (req.request_type, req.recipient) != (RequestType::Class, Recipient::Interface) {
+            return None;
+        }
+        match Request::try_from(req.request) {
+            Ok(Request::Abort) => {
+                info!("Abort requested");
+                self.reset_state();
+                Some(OutResponse::Accepted)
+            }
+            Ok(Request::Dnload) if self.attrs.contains(DfuAttributes::CAN_DOWNLOAD) => {
+                if !matches!(self.state, State::DfuIdle | State::Download) {
+                    error!("Unexpected DNLOAD while chip is waiting for a GETSTATUS");
+                    self.fail(Status::ErrUnknown);
+                    return Some(OutResponse::Rejected);
+                }
+
+                let res = match (req.value, req.length) {
+                    (_, 0) => {
+                        info!("Leaving DFU mode");
+                        self.handler.finish().map(|_| self.state = State::ManifestSync)
+                    }
+                    (0, _) => self.command(data).map(|_| self.state = State::DlSync),
+                    (1, _) => Err(Status::ErrStalledPkt),
+                    (block, _) => self
+                        .block_offset(block)
+                        .and_then(|(offset, remaining)| {
+                            if data.len() > remaining as usize {
+                                return Err(Status::ErrAddress);
+                            }
+                            self.handler.write(self.alt, offset, data)
+                        })
+                        .map(|_| self.state = State::DlSync),
+                };
+
+                match res {
+                    Ok(()) => self.status = Status::Ok,
+                    Err(Status::ErrStalledPkt) => {
+                        self.fail(Status::ErrStalledPkt);
+                        return Some(OutResponse::Rejected);
+                    }
+                    Err(e) => self.fail(e),
+                }
+                Some(OutResponse::Accepted)
+            }
+            Ok(Request::Detach) => Some(OutResponse::Accepted), // Device is already in DFU mode
+            Ok(Request::ClrStatus) => {
+                info!("Clear status requested");
+                self.reset_state();
+                Some(OutResponse::Accepted)
+            }
+            _ => {
+                debug!("Unknown OUT request {:?}", req);
+                None
+            }
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: ControlRequest, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if (req.request_type, req.recipient) != (RequestType::Class, Recipient::Interface) {
+            return None;
+        }
+        match Request::try_from(req.request) {
+            Ok(Request::GetStatus) => {
+                // Hosts expect DfuSe requests to go through the busy state, the work is already
+                // done when the status is requested.
+                match self.state {
+                    State::DlSync => self.state = State::DlBusy,
+                    State::DlBusy => self.state = State::Download,
+                    State::ManifestSync if self.attrs.contains(DfuAttributes::MANIFESTATION_TOLERANT) => {
+                        self.state = State::DfuIdle
+                    }
+                    State::ManifestSync => {
+                        self.state = State::Manifest;
+                        if self.attrs.contains(DfuAttributes::WILL_DETACH) {
+                            self.reset();
+                        }
+                    }
+                    State::Manifest if !self.attrs.contains(DfuAttributes::WILL_DETACH) => {
+                        self.state = State::ManifestWaitReset;
+                    }
+                    _ => {}
+                }
+                buf[0..6].copy_from_slice(&[self.status as u8, 0x32, 0x00, 0x00, self.state as u8, 0x00]);
+                Some(InResponse::Accepted(&buf[0..6]))
+            }
+            Ok(Request::GetState) => {
+                buf[0] = self.state as u8;
+                Some(InResponse::Accepted(&buf[0..1]))
+            }
+            Ok(Request::Upload) if self.attrs.contains(DfuAttributes::CAN_UPLOAD) => {
+                if !matches!(self.state, State::DfuIdle | State::UploadIdle) {
+                    error!("Unexpected UPLOAD while not idle");
+                    self.fail(Status::ErrStalledPkt);
+                    return Some(InResponse::Rejected);
+                }
+
+                // A short frame would end the upload, so frames must fit in the control buffer.
+                let len = req.length as usize;
+                if len > buf.len() {
+                    error!("UPLOAD of {} bytes doesn't fit in the control buffer", len);
+                    self.fail(Status::ErrStalledPkt);
+                    return Some(InResponse::Rejected);
+                }
+                let res = match req.value {
+                    0 => {
+                        let commands = [DFUSE_CMD_GET_COMMANDS, DFUSE_CMD_SET_ADDRESS, DFUSE_CMD_ERASE];
+                        let n = commands.len().min(len);
+                        buf[..n].copy_from_slice(&commands[..n]);
+                        Ok(n)
+                    }
+                    1 => Err(Status::ErrStalledPkt),
+                    // Uploads end with a short frame at the end of the memory.
+                    block => self.block_offset(block).and_then(|(offset, remaining)| {
+                        let len = len.min(remaining as usize);
+                        self.handler.read(self.alt, offset, &mut buf[..len])
+                    }),
+                };
+
+                match res {
+                    Ok(n) => {
+                        // A short frame ends the upload.
+                        self.state = if n < len { State::DfuIdle } else { State::UploadIdle };
+                        Some(InResponse::Accepted(&buf[..n]))
+                    }
+                    Err(e) => {
+                        self.fail(e);
+                        Some(InResponse::Rejected)
+                    }
+                }
+            }
+            _ => {
+                debug!("Unknown IN request {:?}", req);
+                None
+            }
+        }
+    }
+
+    fn get_string(&mut self, index: StringIndex, _lang_id: u16) -> Option<&str> {
+        let first = self.first_string?;
+        let layout = index.0.checked_sub(first.0)?;
+        self.layouts.get(layout as usize).copied()
+    }
+}
+
+/// Parse the start address of a memory layout.
+fn start_address(layout: &str) -> Option<u32> {
+    let address = layout.split('/').nth(1)?.trim();
+    let hex = address.strip_prefix("0x").or_else(|| address.strip_prefix("0X"))?;
+    u32::from_str_radix(hex, 16).ok()
+}
+
+/// Parse the size of the first memory region of a layout, the sectors following its start address.
+fn memory_size(layout: &str) -> Option<u32> {
+    let sectors = layout.split('/').nth(2)?;
+    sectors.split(',').try_fold(0u32, |size, sector| {
+        let (count, sector_size) = sector.trim().split_once('*')?;
+        let digits = sector_size.find(|c: char| !c.is_ascii_digit())?;
+        let multiplier = match sector_size.as_bytes()[digits] {
+            b' ' | b'B' => 1,
+            b'K' => 1024,
+            b'M' => 1024 * 1024,
+            _ => return None,
+        };
+        let sector_size = sector_size[..digits].parse::<u32>().ok()?.checked_mul(multiplier)?;
+        count.parse::<u32>().ok()?.checked_mul(sector_size)?.checked_add(size)
+    })
+}
+
+/// An implementation of the DfuSe protocol
+///
+/// This function will add a DFU interface descriptor, with an alternate setting for each memory
+/// layout, to the provided Builder, and register the provided Control as a handler for the USB
+/// device. The handler is responsive to DFU GetState, GetStatus, Abort, and ClrStatus commands,
+/// as well as Download and Upload if configured by the user.
+///
+/// Once the host leaves DFU mode with a zero-length download, the handler is finished, and the
+/// device is reset after the manifestation phase.
+///
+/// # Panics
+///
+/// Panics if `max_write_size` is larger than the control buffer of `builder`, which holds each
+/// transfer.
+pub fn usb_dfu<'d, D: Driver<'d>, H: Handler>(
+    builder: &mut Builder<'d, D>,
+    state: &'d mut DfuState<'d, H>,
+    max_write_size: usize,
+    func_modifier: impl Fn(&mut FunctionBuilder<'_, 'd, D>),
+) {
+    assert!(
+        max_write_size <= builder.control_buf_len(),
+        "DFU transfer size is larger than the control buffer"
+    );
+
+    let mut func = builder.function(0x00, 0x00, 0x00);
+
+    // Here we give users the opportunity to add their own function level MSOS headers for instance.
+    // This is useful when DFU functionality is part of a composite USB device.
+    func_modifier(&mut func);
+
+    let mut iface = func.interface();
+    state.iface = Some(iface.interface_number());
+    state.transfer_size = max_write_size;
+
+    let layouts = state.layouts;
+    for i in 0..layouts.len() {
+        // Strings are allocated consecutively.
+        let string = iface.string();
+        if i == 0 {
+            state.first_string = Some(string);
+        }
+
+        let mut alt = iface.alt_setting(
+            USB_CLASS_APPN_SPEC,
+            APPN_SPEC_SUBCLASS_DFU,
+            DFU_PROTOCOL_DFU,
+            Some(string),
+        );
+        // The functional descriptor follows the last alternate setting.
+        if i == layouts.len() - 1 {
+            alt.descriptor(
+                DESC_DFU_FUNCTIONAL,
+                &[
+                    state.attrs.bits(),
+                    0xc4,
+                    0x09, // 2500ms timeout, doesn't affect operation as DETACH not necessary in bootloader code
+                    (max_write_size & 0xff) as u8,
+                    ((max_write_size & 0xff00) >> 8) as u8,
+                    0x1a,
+                    0x01, // DfuSe
+                ],
+            );
+        }
+    }
+
+    drop(func);
+    builder.handler(state);
+}
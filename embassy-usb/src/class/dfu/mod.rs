@@ -1,8 +1,9 @@
 //! USB Device Firmware Upgrade (DFU) class implementation.
 //!
-//! This module provides USB DFU 1.1 protocol support, split into two modes:
+//! This module provides USB DFU 1.1 protocol support, split into these modes:
 //! - `app_mode`: Runtime mode for applications to support detach requests
-//! - `dfu_mode`: Bootloader mode for handling firmware downloads
+//! - `dfu_mode`: Bootloader mode for handling firmware downloads and uploads
+//! - `dfuse`: Bootloader mode with STMicroelectronics' DfuSe extension, exposing several memories
 
 pub mod consts;
 
@@ -10,3 +11,5 @@ pub mod consts;
 pub mod app_mode;
 /// DFU bootloader mode (firmware download).
 pub mod dfu_mode;
+/// DfuSe bootloader mode (memories on alternate settings).
+pub mod dfuse;
@@ -0,0 +1,146 @@
+//! [`embassy-net`](https://crates.io/crates/embassy-net) driver for the ECM and RNDIS class.
+
+use core::cell::Cell;
+
+use embassy_futures::select::{Either, Either3, select, select3};
+use embassy_net_driver_channel as ch;
+use embassy_net_driver_channel::driver::LinkState;
+use embassy_usb_driver::Driver;
+
+use super::EcmRndisClass;
+use crate::class::{cdc_ecm, rndis};
+
+/// Internal state for the embassy-net integration.
+pub struct State<const MTU: usize, const N_RX: usize, const N_TX: usize> {
+    ch_state: ch::State<MTU, N_RX, N_TX>,
+}
+
+impl<const MTU: usize, const N_RX: usize, const N_TX: usize> State<MTU, N_RX, N_TX> {
+    /// Create a new `State`.
+    pub const fn new() -> Self {
+        Self {
+            ch_state: ch::State::new(),
+        }
+    }
+}
+
+impl<const MTU: usize, const N_RX: usize, const N_TX: usize> Default for State<MTU, N_RX, N_TX> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The function carrying traffic.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Function {
+    Ecm,
+    Rndis,
+}
+
+/// Background runner for the ECM and RNDIS class.
+///
+/// You must call `.run()` in a background task for the class to operate.
+pub struct Runner<'d, D: Driver<'d>, const MTU: usize> {
+    ecm_tx: cdc_ecm::Sender<'d, D>,
+    ecm_rx: cdc_ecm::Receiver<'d, D>,
+    rndis_tx: rndis::Sender<'d, D>,
+    rndis_rx: rndis::Receiver<'d, D>,
+    rndis_notifier: rndis::Notifier<'d, D>,
+    ch: ch::Runner<'d, MTU>,
+}
+
+impl<'d, D: Driver<'d>, const MTU: usize> Runner<'d, D, MTU> {
+    /// Run the ECM and RNDIS class.
+    ///
+    /// You must call this in a background task for the class to operate.
+    pub async fn run(mut self) -> ! {
+        let (state_chan, mut rx_chan, mut tx_chan) = self.ch.split();
+        let active = Cell::new(None);
+        let active = &active;
+
+        let rx_fut = async move {
+            loop {
+                trace!("WAITING for connection");
+                state_chan.set_link_state(LinkState::Down);
+                active.set(None);
+
+                let function = match select(self.ecm_rx.wait_connection(), self.rndis_rx.wait_connection()).await {
+                    Either::First(r) => r.map(|_| Function::Ecm),
+                    Either::Second(r) => r.map(|_| Function::Rndis),
+                }
+                .unwrap();
+
+                trace!("Connected");
+                active.set(Some(function));
+                state_chan.set_link_state(LinkState::Up);
+
+                loop {
+                    let mut p = rx_chan.rx_buf().await;
+                    let r = match function {
+                        Function::Ecm => self.ecm_rx.read_packet(&mut p).await,
+                        Function::Rndis => self.rndis_rx.read_packet(&mut p).await,
+                    };
+                    match r {
+                        Ok(n) => p.rx_done(n),
+                        Err(e) => {
+                            warn!("error reading packet: {:?}", e);
+                            break;
+                        }
+                    };
+                }
+            }
+        };
+        let tx_fut = async move {
+            loop {
+                let p = tx_chan.tx_buf().await;
+                let r = match active.get() {
+                    Some(Function::Ecm) => self.ecm_tx.write_packet(&p).await,
+                    Some(Function::Rndis) => self.rndis_tx.write_packet(&p).await,
+                    // Not connected, drop the packet.
+                    None => Ok(()),
+                };
+                if let Err(e) = r {
+                    warn!("Failed to TX packet: {:?}", e);
+                }
+                p.tx_done();
+            }
+        };
+        match select3(rx_fut, tx_fut, self.rndis_notifier.run()).await {
+            Either3::First(x) => x,
+            Either3::Second(x) => x,
+            Either3::Third(x) => x,
+        }
+    }
+}
+
+/// Type alias for the embassy-net driver for the ECM and RNDIS class.
+pub type Device<'d, const MTU: usize> = embassy_net_driver_channel::Device<'d, MTU>;
+
+impl<'d, D: Driver<'d>> EcmRndisClass<'d, D> {
+    /// Obtain a driver for using the ECM and RNDIS class with [`embassy-net`](https://crates.io/crates/embassy-net).
+    pub fn into_embassy_net_device<const MTU: usize, const N_RX: usize, const N_TX: usize>(
+        self,
+        state: &'d mut State<MTU, N_RX, N_TX>,
+        ethernet_address: [u8; 6],
+    ) -> (Runner<'d, D, MTU>, Device<'d, MTU>) {
+        let (ecm, rndis) = self.split();
+        let (ecm_tx, ecm_rx) = ecm.split();
+        let (rndis_tx, rndis_rx, rndis_notifier) = rndis.split();
+        let (runner, device) = ch::new(
+            &mut state.ch_state,
+            ch::driver::HardwareAddress::Ethernet(ethernet_address),
+        );
+
+        (
+            Runner {
+                ecm_tx,
+                ecm_rx,
+                rndis_tx,
+                rndis_rx,
+                rndis_notifier,
+                ch: runner,
+            },
+            device,
+        )
+    }
+}
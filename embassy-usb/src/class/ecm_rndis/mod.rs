@@ -0,0 +1,79 @@
+//! Network gadget with a CDC-ECM and an RNDIS configuration.
+//!
+//! No single network class works out of the box on every host: Windows only has a built-in driver
+//! for [RNDIS](super::rndis), macOS only for [CDC-ECM](super::cdc_ecm), and some Linux and Android
+//! hosts lack [CDC-NCM](super::cdc_ncm). This exposes RNDIS and ECM in two configurations of the
+//! device, like the Linux Ethernet gadget does, and the host selects one of them. The RNDIS function
+//! has a Microsoft OS 2.0 compatible ID so Windows binds its driver to it.
+//!
+//! RNDIS is in the first configuration, since Windows always selects it. Linux prefers configurations
+//! that aren't RNDIS, and selects the ECM one. Hosts without an RNDIS driver that only consider the
+//! first configuration don't get a network interface.
+//!
+//! This needs the `max-configuration-count-2` feature, the MS OS descriptors to be enabled with
+//! [`Builder::msos_descriptor`], and the device to be a composite device with IADs, which is the
+//! default [`Config`](crate::Config).
+
+use crate::Builder;
+use crate::class::cdc_ecm::{self, CdcEcmClass};
+use crate::class::rndis::{self, RndisClass};
+use crate::driver::Driver;
+
+pub mod embassy_net;
+
+/// Internal state for the ECM and RNDIS class.
+pub struct State<'a> {
+    ecm: cdc_ecm::State<'a>,
+    rndis: rndis::State<'a>,
+}
+
+impl<'a> Default for State<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> State<'a> {
+    /// Create a new `State`.
+    pub fn new() -> Self {
+        Self {
+            ecm: cdc_ecm::State::new(),
+            rndis: rndis::State::new(),
+        }
+    }
+}
+
+/// ECM and RNDIS class, with one configuration each.
+pub struct EcmRndisClass<'d, D: Driver<'d>> {
+    ecm: CdcEcmClass<'d, D>,
+    rndis: RndisClass<'d, D>,
+}
+
+impl<'d, D: Driver<'d>> EcmRndisClass<'d, D> {
+    /// Create a new ECM and RNDIS class.
+    ///
+    /// RNDIS is added to the current configuration, which should be the first one. ECM is added to
+    /// a new configuration drawing `max_power` milliamps, started with [`Builder::configuration`],
+    /// which stays the current one. Functions the device has whatever the host must be added to both
+    /// configurations.
+    ///
+    /// `mac_address` is the MAC address of the host's side of the link, not the one of the
+    /// device's network interface. Both functions use the same.
+    pub fn new(
+        builder: &mut Builder<'d, D>,
+        state: &'d mut State<'d>,
+        mac_address: [u8; 6],
+        max_packet_size: u16,
+        max_power: u16,
+    ) -> Self {
+        let rndis = RndisClass::new(builder, &mut state.rndis, mac_address, max_packet_size);
+        builder.configuration(max_power, None);
+        let ecm = CdcEcmClass::new(builder, &mut state.ecm, mac_address, max_packet_size);
+        Self { ecm, rndis }
+    }
+
+    /// Split the class into its ECM and RNDIS functions.
+    pub fn split(self) -> (CdcEcmClass<'d, D>, RndisClass<'d, D>) {
+        (self.ecm, self.rndis)
+    }
+}
@@ -0,0 +1,436 @@
+//! Builder for HID report descriptors.
+//!
+//! See section 6.2.2 of the [HID specification](https://www.usb.org/sites/default/files/hid1_11.pdf)
+//! for the meaning of the items.
+
+/// Usage pages, from the [HID Usage Tables](https://usb.org/document-library/hid-usage-tables-16).
+pub mod usage_page {
+    /// Generic Desktop Page, see [`generic_desktop`](super::generic_desktop) for its usages.
+    pub const GENERIC_DESKTOP: u16 = 0x01;
+    /// Simulation Controls Page.
+    pub const SIMULATION: u16 = 0x02;
+    /// VR Controls Page.
+    pub const VR: u16 = 0x03;
+    /// Sport Controls Page.
+    pub const SPORT: u16 = 0x04;
+    /// Game Controls Page.
+    pub const GAME: u16 = 0x05;
+    /// Generic Device Controls Page.
+    pub const GENERIC_DEVICE: u16 = 0x06;
+    /// Keyboard/Keypad Page, whose usages are the key codes.
+    pub const KEYBOARD: u16 = 0x07;
+    /// LED Page.
+    pub const LED: u16 = 0x08;
+    /// Button Page, whose usages are the button numbers starting from 1.
+    pub const BUTTON: u16 = 0x09;
+    /// Ordinal Page.
+    pub const ORDINAL: u16 = 0x0A;
+    /// Telephony Device Page.
+    pub const TELEPHONY: u16 = 0x0B;
+    /// Consumer Page, for media keys.
+    pub const CONSUMER: u16 = 0x0C;
+    /// Digitizers Page.
+    pub const DIGITIZER: u16 = 0x0D;
+    /// Haptics Page.
+    pub const HAPTICS: u16 = 0x0E;
+    /// Unicode Page.
+    pub const UNICODE: u16 = 0x10;
+    /// Sensors Page.
+    pub const SENSORS: u16 = 0x20;
+    /// Battery System Page.
+    pub const BATTERY_SYSTEM: u16 = 0x85;
+    /// First vendor-defined page. Pages `0xFF00..=0xFFFF` are all vendor-defined.
+    pub const VENDOR_DEFINED: u16 = 0xFF00;
+}
+
+/// Usages of the Generic Desktop Page.
+pub mod generic_desktop {
+    /// Pointer.
+    pub const POINTER: u16 = 0x01;
+    /// Mouse.
+    pub const MOUSE: u16 = 0x02;
+    /// Joystick.
+    pub const JOYSTICK: u16 = 0x04;
+    /// Gamepad.
+    pub const GAMEPAD: u16 = 0x05;
+    /// Keyboard.
+    pub const KEYBOARD: u16 = 0x06;
+    /// Keypad.
+    pub const KEYPAD: u16 = 0x07;
+    /// X axis.
+    pub const X: u16 = 0x30;
+    /// Y axis.
+    pub const Y: u16 = 0x31;
+    /// Z axis.
+    pub const Z: u16 = 0x32;
+    /// Rotation around the X axis.
+    pub const RX: u16 = 0x33;
+    /// Rotation around the Y axis.
+    pub const RY: u16 = 0x34;
+    /// Rotation around the Z axis.
+    pub const RZ: u16 = 0x35;
+    /// Slider.
+    pub const SLIDER: u16 = 0x36;
+    /// Dial.
+    pub const DIAL: u16 = 0x37;
+    /// Wheel.
+    pub const WHEEL: u16 = 0x38;
+    /// Hat switch.
+    pub const HAT_SWITCH: u16 = 0x39;
+    /// System Control, for power and sleep keys.
+    pub const SYSTEM_CONTROL: u16 = 0x80;
+}
+
+/// Type of a collection.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Collection {
+    /// Items related to a single geometric point, like the axes of a pointer.
+    Physical = 0x00,
+    /// Items forming a function that the host can use on its own, like a mouse or a keyboard.
+    Application = 0x01,
+    /// Items that form a composite data structure.
+    Logical = 0x02,
+    /// Items of a report.
+    Report = 0x03,
+    /// Array of selectors.
+    NamedArray = 0x04,
+    /// Items that modify the meaning of a usage.
+    UsageSwitch = 0x05,
+    /// Items that modify the meaning of a usage, for example with a modifier key.
+    UsageModifier = 0x06,
+}
+
+#[cfg(feature = "defmt")]
+defmt::bitflags! {
+    /// Flags of an Input, Output or Feature item.
+    ///
+    /// The empty value means data, array and absolute.
+    pub struct ItemFlags: u16 {
+        /// Data, array, absolute.
+        const DATA = 0;
+        /// The field is constant, for padding.
+        const CONSTANT = 1 << 0;
+        /// Each field is a variable value, instead of an array of selected usages.
+        const VARIABLE = 1 << 1;
+        /// The values are relative to the last report, like the movement of a mouse.
+        const RELATIVE = 1 << 2;
+        /// The values wrap around when going past their extremes.
+        const WRAP = 1 << 3;
+        /// The values don't map linearly to the measured quantity.
+        const NON_LINEAR = 1 << 4;
+        /// The control doesn't return to a preferred state when not in use.
+        const NO_PREFERRED_STATE = 1 << 5;
+        /// The control has a state with no meaningful data, reported with a value out of the logical range.
+        const NULL_STATE = 1 << 6;
+        /// The value can change without the host setting it. Only for Output and Feature items.
+        const VOLATILE = 1 << 7;
+        /// The field is a stream of bytes instead of a single value.
+        const BUFFERED_BYTES = 1 << 8;
+    }
+}
+
+#[cfg(not(feature = "defmt"))]
+bitflags::bitflags! {
+    /// Flags of an Input, Output or Feature item.
+    ///
+    /// The empty value means data, array and absolute.
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub struct ItemFlags: u16 {
+        /// Data, array, absolute.
+        const DATA = 0;
+        /// The field is constant, for padding.
+        const CONSTANT = 1 << 0;
+        /// Each field is a variable value, instead of an array of selected usages.
+        const VARIABLE = 1 << 1;
+        /// The values are relative to the last report, like the movement of a mouse.
+        const RELATIVE = 1 << 2;
+        /// The values wrap around when going past their extremes.
+        const WRAP = 1 << 3;
+        /// The values don't map linearly to the measured quantity.
+        const NON_LINEAR = 1 << 4;
+        /// The control doesn't return to a preferred state when not in use.
+        const NO_PREFERRED_STATE = 1 << 5;
+        /// The control has a state with no meaningful data, reported with a value out of the logical range.
+        const NULL_STATE = 1 << 6;
+        /// The value can change without the host setting it. Only for Output and Feature items.
+        const VOLATILE = 1 << 7;
+        /// The field is a stream of bytes instead of a single value.
+        const BUFFERED_BYTES = 1 << 8;
+    }
+}
+
+const TYPE_MAIN: u8 = 0;
+const TYPE_GLOBAL: u8 = 1;
+const TYPE_LOCAL: u8 = 2;
+
+const MAIN_INPUT: u8 = 0x8;
+const MAIN_OUTPUT: u8 = 0x9;
+const MAIN_COLLECTION: u8 = 0xA;
+const MAIN_FEATURE: u8 = 0xB;
+const MAIN_END_COLLECTION: u8 = 0xC;
+
+const GLOBAL_USAGE_PAGE: u8 = 0x0;
+const GLOBAL_LOGICAL_MINIMUM: u8 = 0x1;
+const GLOBAL_LOGICAL_MAXIMUM: u8 = 0x2;
+const GLOBAL_PHYSICAL_MINIMUM: u8 = 0x3;
+const GLOBAL_PHYSICAL_MAXIMUM: u8 = 0x4;
+const GLOBAL_UNIT_EXPONENT: u8 = 0x5;
+const GLOBAL_UNIT: u8 = 0x6;
+const GLOBAL_REPORT_SIZE: u8 = 0x7;
+const GLOBAL_REPORT_ID: u8 = 0x8;
+const GLOBAL_REPORT_COUNT: u8 = 0x9;
+const GLOBAL_PUSH: u8 = 0xA;
+const GLOBAL_POP: u8 = 0xB;
+
+const LOCAL_USAGE: u8 = 0x0;
+const LOCAL_USAGE_MINIMUM: u8 = 0x1;
+const LOCAL_USAGE_MAXIMUM: u8 = 0x2;
+
+/// A HID report descriptor, built item by item.
+///
+/// All methods are `const`, so a descriptor can be built at compile time:
+///
+/// ```
+/// use embassy_usb::class::hid::descriptor::{Collection, ItemFlags, ReportDescriptor, generic_desktop, usage_page};
+///
+/// static DESCRIPTOR: ReportDescriptor<64> = ReportDescriptor::new()
+///     .usage_page(usage_page::GENERIC_DESKTOP)
+///     .usage(generic_desktop::JOYSTICK)
+///     .collection(Collection::Application)
+///     .usage(generic_desktop::X)
+///     .usage(generic_desktop::Y)
+///     .logical_min(-127)
+///     .logical_max(127)
+///     .report_size(8)
+///     .report_count(2)
+///     .input(ItemFlags::VARIABLE)
+///     .end_collection();
+///
+/// assert_eq!(DESCRIPTOR.as_bytes().len(), 21);
+/// ```
+///
+/// `N` is the capacity of the descriptor in bytes. Adding an item that doesn't fit panics, which is a
+/// compilation error when building a `const` or a `static`.
+#[derive(Copy, Clone, Debug)]
+pub struct ReportDescriptor<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+    depth: usize,
+}
+
+impl<const N: usize> Default for ReportDescriptor<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> ReportDescriptor<N> {
+    /// Creates an empty descriptor.
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+            depth: 0,
+        }
+    }
+
+    /// Returns the bytes of the descriptor.
+    ///
+    /// Panics if a collection is still open.
+    pub const fn as_bytes(&self) -> &[u8] {
+        core::assert!(self.depth == 0, "HID report descriptor has an unclosed collection");
+        self.buf.split_at(self.len).0
+    }
+
+    /// Returns the bytes of the descriptor in an array of its length, to store it without the spare capacity:
+    ///
+    /// ```
+    /// use embassy_usb::class::hid::descriptor::{ReportDescriptor, usage_page};
+    ///
+    /// const DESCRIPTOR: ReportDescriptor<256> = ReportDescriptor::new().usage_page(usage_page::BUTTON);
+    /// static BYTES: [u8; DESCRIPTOR.len()] = DESCRIPTOR.to_array();
+    /// ```
+    ///
+    /// Panics if `M` is not the length of the descriptor, or if a collection is still open.
+    pub const fn to_array<const M: usize>(&self) -> [u8; M] {
+        core::assert!(M == self.len, "HID report descriptor length mismatch");
+        let bytes = self.as_bytes();
+        let mut array = [0; M];
+        let mut i = 0;
+        while i < M {
+            array[i] = bytes[i];
+            i += 1;
+        }
+        array
+    }
+
+    /// Returns the length of the descriptor in bytes.
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if the descriptor has no items.
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Appends raw bytes, for example the descriptor of a [`HidReport`](super::report::HidReport).
+    pub const fn extend(mut self, bytes: &[u8]) -> Self {
+        core::assert!(self.len + bytes.len() <= N, "HID report descriptor buffer full");
+        let mut i = 0;
+        while i < bytes.len() {
+            self.buf[self.len + i] = bytes[i];
+            i += 1;
+        }
+        self.len += bytes.len();
+        self
+    }
+
+    const fn item(mut self, typ: u8, tag: u8, data: u32, size: usize) -> Self {
+        let size_code = match size {
+            0 => 0,
+            1 => 1,
+            2 => 2,
+            _ => 3,
+        };
+        core::assert!(self.len + 1 + size <= N, "HID report descriptor buffer full");
+        self.buf[self.len] = tag << 4 | typ << 2 | size_code;
+        let data = data.to_le_bytes();
+        let mut i = 0;
+        while i < size {
+            self.buf[self.len + 1 + i] = data[i];
+            i += 1;
+        }
+        self.len += 1 + size;
+        self
+    }
+
+    const fn unsigned(self, typ: u8, tag: u8, data: u32) -> Self {
+        let size = if data <= u8::MAX as u32 {
+            1
+        } else if data <= u16::MAX as u32 {
+            2
+        } else {
+            4
+        };
+        self.item(typ, tag, data, size)
+    }
+
+    const fn signed(self, typ: u8, tag: u8, data: i32) -> Self {
+        let size = if data >= i8::MIN as i32 && data <= i8::MAX as i32 {
+            1
+        } else if data >= i16::MIN as i32 && data <= i16::MAX as i32 {
+            2
+        } else {
+            4
+        };
+        self.item(typ, tag, data as u32, size)
+    }
+
+    /// Adds an Input item, for a field of the reports sent to the host.
+    pub const fn input(self, flags: ItemFlags) -> Self {
+        self.unsigned(TYPE_MAIN, MAIN_INPUT, flags.bits() as u32)
+    }
+
+    /// Adds an Output item, for a field of the reports received from the host.
+    pub const fn output(self, flags: ItemFlags) -> Self {
+        self.unsigned(TYPE_MAIN, MAIN_OUTPUT, flags.bits() as u32)
+    }
+
+    /// Adds a Feature item, for a field of the reports read and written by the host with control requests.
+    pub const fn feature(self, flags: ItemFlags) -> Self {
+        self.unsigned(TYPE_MAIN, MAIN_FEATURE, flags.bits() as u32)
+    }
+
+    /// Opens a collection. It must be closed with [`end_collection`](Self::end_collection).
+    pub const fn collection(mut self, collection: Collection) -> Self {
+        self.depth += 1;
+        self.unsigned(TYPE_MAIN, MAIN_COLLECTION, collection as u32)
+    }
+
+    /// Closes the last opened collection.
+    pub const fn end_collection(mut self) -> Self {
+        core::assert!(self.depth > 0, "HID report descriptor has no collection to end");
+        self.depth -= 1;
+        self.item(TYPE_MAIN, MAIN_END_COLLECTION, 0, 0)
+    }
+
+    /// Sets the usage page of the following usages.
+    pub const fn usage_page(self, page: u16) -> Self {
+        self.unsigned(TYPE_GLOBAL, GLOBAL_USAGE_PAGE, page as u32)
+    }
+
+    /// Sets the minimum value of the following fields.
+    pub const fn logical_min(self, min: i32) -> Self {
+        self.signed(TYPE_GLOBAL, GLOBAL_LOGICAL_MINIMUM, min)
+    }
+
+    /// Sets the maximum value of the following fields.
+    pub const fn logical_max(self, max: i32) -> Self {
+        self.signed(TYPE_GLOBAL, GLOBAL_LOGICAL_MAXIMUM, max)
+    }
+
+    /// Sets the physical value that the logical minimum maps to, in [`unit`](Self::unit).
+    pub const fn physical_min(self, min: i32) -> Self {
+        self.signed(TYPE_GLOBAL, GLOBAL_PHYSICAL_MINIMUM, min)
+    }
+
+    /// Sets the physical value that the logical maximum maps to, in [`unit`](Self::unit).
+    pub const fn physical_max(self, max: i32) -> Self {
+        self.signed(TYPE_GLOBAL, GLOBAL_PHYSICAL_MAXIMUM, max)
+    }
+
+    /// Sets the base 10 exponent of the unit, from -8 to 7.
+    pub const fn unit_exponent(self, exponent: i8) -> Self {
+        core::assert!(exponent >= -8 && exponent <= 7, "HID unit exponent out of range");
+        self.unsigned(TYPE_GLOBAL, GLOBAL_UNIT_EXPONENT, (exponent as u8 & 0x0F) as u32)
+    }
+
+    /// Sets the unit of the physical values, encoded as described in section 6.2.2.7 of the HID specification.
+    pub const fn unit(self, unit: u32) -> Self {
+        self.unsigned(TYPE_GLOBAL, GLOBAL_UNIT, unit)
+    }
+
+    /// Sets the size of the following fields, in bits.
+    pub const fn report_size(self, bits: u32) -> Self {
+        self.unsigned(TYPE_GLOBAL, GLOBAL_REPORT_SIZE, bits)
+    }
+
+    /// Starts a report with the given ID. The reports of an interface either all have an ID, or none has.
+    pub const fn report_id(self, id: u8) -> Self {
+        core::assert!(id != 0, "HID report ID 0 is reserved");
+        self.unsigned(TYPE_GLOBAL, GLOBAL_REPORT_ID, id as u32)
+    }
+
+    /// Sets the number of the following fields.
+    pub const fn report_count(self, count: u32) -> Self {
+        self.unsigned(TYPE_GLOBAL, GLOBAL_REPORT_COUNT, count)
+    }
+
+    /// Saves the global items on a stack.
+    pub const fn push(self) -> Self {
+        self.item(TYPE_GLOBAL, GLOBAL_PUSH, 0, 0)
+    }
+
+    /// Restores the global items saved by the last [`push`](Self::push).
+    pub const fn pop(self) -> Self {
+        self.item(TYPE_GLOBAL, GLOBAL_POP, 0, 0)
+    }
+
+    /// Adds a usage, for the next field.
+    pub const fn usage(self, usage: u16) -> Self {
+        self.unsigned(TYPE_LOCAL, LOCAL_USAGE, usage as u32)
+    }
+
+    /// Sets the first usage of a range, for the next fields.
+    pub const fn usage_min(self, usage: u16) -> Self {
+        self.unsigned(TYPE_LOCAL, LOCAL_USAGE_MINIMUM, usage as u32)
+    }
+
+    /// Sets the last usage of a range, for the next fields.
+    pub const fn usage_max(self, usage: u16) -> Self {
+        self.unsigned(TYPE_LOCAL, LOCAL_USAGE_MAXIMUM, usage as u32)
+    }
+}
@@ -1,8 +1,11 @@
 //! USB HID (Human Interface Device) class implementation.
+//!
+//! The report descriptor of an interface can be written by hand, built with
+//! [`descriptor::ReportDescriptor`], or taken from [`report::HidReport`] implementations.
 
 use core::mem::MaybeUninit;
 use core::ops::Range;
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
 
 #[cfg(feature = "usbd-hid")]
 use usbd_hid::descriptor::AsInputReport;
@@ -12,6 +15,11 @@ use crate::driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointOut};
 use crate::types::InterfaceNumber;
 use crate::{Builder, Handler};
 
+pub mod descriptor;
+pub mod report;
+
+use report::HidReport;
+
 const USB_CLASS_HID: u8 = 0x03;
 
 // HID
@@ -125,6 +133,7 @@ impl ReportId {
 pub struct State<'d> {
     control: MaybeUninit<Control<'d>>,
     out_report_offset: AtomicUsize,
+    protocol: AtomicU8,
 }
 
 impl<'d> Default for State<'d> {
@@ -139,6 +148,7 @@ impl<'d> State<'d> {
         State {
             control: MaybeUninit::uninit(),
             out_report_offset: AtomicUsize::new(0),
+            protocol: AtomicU8::new(HidProtocolMode::Report as u8),
         }
     }
 }
@@ -155,7 +165,13 @@ fn build<'d, D: Driver<'d>>(
     state: &'d mut State<'d>,
     config: Config<'d>,
     with_out_endpoint: bool,
-) -> (Option<D::EndpointOut>, D::EndpointIn, &'d AtomicUsize, InterfaceNumber) {
+) -> (
+    Option<D::EndpointOut>,
+    D::EndpointIn,
+    &'d AtomicUsize,
+    &'d AtomicU8,
+    InterfaceNumber,
+) {
     let len = config.report_descriptor.len();
 
     let mut func = builder.function(USB_CLASS_HID, config.hid_subclass as u8, config.hid_boot_protocol as u8);
@@ -200,11 +216,13 @@ fn build<'d, D: Driver<'d>>(
         if_num,
         config.report_descriptor,
         config.request_handler,
+        config.hid_subclass,
         &state.out_report_offset,
+        &state.protocol,
     ));
     builder.handler(control);
 
-    (ep_out, ep_in, &state.out_report_offset, if_num)
+    (ep_out, ep_in, &state.out_report_offset, &state.protocol, if_num)
 }
 
 impl<'d, D: Driver<'d>, const READ_N: usize, const WRITE_N: usize> HidReaderWriter<'d, D, READ_N, WRITE_N> {
@@ -214,14 +232,14 @@ impl<'d, D: Driver<'d>, const READ_N: usize, const WRITE_N: usize> HidReaderWrit
     /// HID reports, consider using [`HidWriter::new`] instead, which allocates an IN endpoint only.
     ///
     pub fn new(builder: &mut Builder<'d, D>, state: &'d mut State<'d>, config: Config<'d>) -> Self {
-        let (ep_out, ep_in, offset, if_num) = build(builder, state, config, true);
+        let (ep_out, ep_in, offset, protocol, if_num) = build(builder, state, config, true);
 
         Self {
             reader: HidReader {
                 ep_out: ep_out.unwrap(),
                 offset,
             },
-            writer: HidWriter { ep_in },
+            writer: HidWriter { ep_in, protocol },
             interface_number: if_num,
         }
     }
@@ -243,11 +261,25 @@ impl<'d, D: Driver<'d>, const READ_N: usize, const WRITE_N: usize> HidReaderWrit
         self.writer.write_serialize(r).await
     }
 
+    /// Writes a typed input report.
+    ///
+    /// See [`HidWriter::write_report`].
+    pub async fn write_report<R: HidReport>(&mut self, report: &R) -> Result<(), EndpointError> {
+        self.writer.write_report(report).await
+    }
+
     /// Writes `report` to its interrupt endpoint.
     pub async fn write(&mut self, report: &[u8]) -> Result<(), EndpointError> {
         self.writer.write(report).await
     }
 
+    /// Returns the protocol selected by the host.
+    ///
+    /// See [`HidWriter::protocol`].
+    pub fn protocol(&self) -> HidProtocolMode {
+        self.writer.protocol()
+    }
+
     /// Reads an output report from the Interrupt Out pipe.
     ///
     /// See [`HidReader::read`].
@@ -266,6 +298,7 @@ impl<'d, D: Driver<'d>, const READ_N: usize, const WRITE_N: usize> HidReaderWrit
 /// You can obtain a `HidWriter` using [`HidReaderWriter::split`].
 pub struct HidWriter<'d, D: Driver<'d>, const N: usize> {
     ep_in: D::EndpointIn,
+    protocol: &'d AtomicU8,
 }
 
 /// USB HID reader.
@@ -309,11 +342,11 @@ impl<'d, D: Driver<'d>, const N: usize> HidWriter<'d, D, N> {
     /// of CPU on the device & bandwidth on the bus. A value of 10 is reasonable for
     /// high performance uses, and a value of 255 is good for best-effort usecases.
     pub fn new(builder: &mut Builder<'d, D>, state: &'d mut State<'d>, config: Config<'d>) -> Self {
-        let (ep_out, ep_in, _offset, _) = build(builder, state, config, false);
+        let (ep_out, ep_in, _offset, protocol, _) = build(builder, state, config, false);
 
         assert!(ep_out.is_none());
 
-        Self { ep_in }
+        Self { ep_in, protocol }
     }
 
     /// Waits for the interrupt in endpoint to be enabled.
@@ -331,11 +364,28 @@ impl<'d, D: Driver<'d>, const N: usize> HidWriter<'d, D, N> {
         self.write(&buf[0..size]).await
     }
 
+    /// Writes a typed input report, whose [`SIZE`](HidReport::SIZE) must be at most `N`.
+    pub async fn write_report<R: HidReport>(&mut self, report: &R) -> Result<(), EndpointError> {
+        const { core::assert!(R::SIZE <= N, "HID report larger than the writer") };
+        let mut buf: [u8; N] = [0; N];
+        let size = report.to_bytes(&mut buf);
+        self.write(&buf[0..size]).await
+    }
+
     /// Writes `report` to its interrupt endpoint.
     pub async fn write(&mut self, report: &[u8]) -> Result<(), EndpointError> {
         assert!(report.len() <= N);
         self.ep_in.write_transfer(report, report.len() < N).await
     }
+
+    /// Returns the protocol selected by the host.
+    ///
+    /// Hosts select the boot protocol only for interfaces with the boot subclass, when they don't parse
+    /// report descriptors, for example in a BIOS. The reports must then be in the boot protocol format,
+    /// like [`report::KeyboardReport`] and [`report::MouseReport`], and have no report ID.
+    pub fn protocol(&self) -> HidProtocolMode {
+        HidProtocolMode::from(self.protocol.load(Ordering::Relaxed))
+    }
 }
 
 impl<'d, D: Driver<'d>, const N: usize> HidReader<'d, D, N> {
@@ -447,7 +497,8 @@ pub trait RequestHandler {
 
     /// Sets the current hid protocol to `protocol`.
     ///
-    /// Accepts only `Report` protocol by default.
+    /// Accepts only `Report` protocol by default. Without a request handler, the class also accepts the
+    /// `Boot` protocol for interfaces with the boot subclass.
     fn set_protocol(&mut self, protocol: HidProtocolMode) -> OutResponse {
         match protocol {
             HidProtocolMode::Report => OutResponse::Accepted,
@@ -478,7 +529,9 @@ struct Control<'d> {
     if_num: InterfaceNumber,
     report_descriptor: &'d [u8],
     request_handler: Option<&'d mut dyn RequestHandler>,
+    hid_subclass: HidSubclass,
     out_report_offset: &'d AtomicUsize,
+    protocol: &'d AtomicU8,
     hid_descriptor: [u8; 9],
 }
 
@@ -487,13 +540,17 @@ impl<'d> Control<'d> {
         if_num: InterfaceNumber,
         report_descriptor: &'d [u8],
         request_handler: Option<&'d mut dyn RequestHandler>,
+        hid_subclass: HidSubclass,
         out_report_offset: &'d AtomicUsize,
+        protocol: &'d AtomicU8,
     ) -> Self {
         Control {
             if_num,
             report_descriptor,
             request_handler,
+            hid_subclass,
             out_report_offset,
+            protocol,
             hid_descriptor: [
                 // Length of buf inclusive of size prefix
                 9,
@@ -519,6 +576,8 @@ impl<'d> Control<'d> {
 impl<'d> Handler for Control<'d> {
     fn reset(&mut self) {
         self.out_report_offset.store(0, Ordering::Release);
+        // Devices start in the report protocol after a reset.
+        self.protocol.store(HidProtocolMode::Report as u8, Ordering::Relaxed);
     }
 
     fn control_out(&mut self, req: Request, data: &[u8]) -> Option<OutResponse> {
@@ -549,14 +608,19 @@ impl<'d> Handler for Control<'d> {
             },
             HID_REQ_SET_PROTOCOL => {
                 let hid_protocol = HidProtocolMode::from(req.value as u8);
-                match (self.request_handler.as_mut(), hid_protocol) {
-                    (Some(request_handler), hid_protocol) => Some(request_handler.set_protocol(hid_protocol)),
-                    (None, HidProtocolMode::Report) => Some(OutResponse::Accepted),
+                let response = match (self.request_handler.as_mut(), hid_protocol) {
+                    (Some(request_handler), hid_protocol) => request_handler.set_protocol(hid_protocol),
+                    (None, HidProtocolMode::Report) => OutResponse::Accepted,
+                    (None, HidProtocolMode::Boot) if self.hid_subclass == HidSubclass::Boot => OutResponse::Accepted,
                     (None, HidProtocolMode::Boot) => {
                         info!("Received request to switch to Boot protocol mode, but it is disabled by default.");
-                        Some(OutResponse::Rejected)
+                        OutResponse::Rejected
                     }
+                };
+                if response == OutResponse::Accepted {
+                    self.protocol.store(hid_protocol as u8, Ordering::Relaxed);
                 }
+                Some(response)
             }
             _ => Some(OutResponse::Rejected),
         }
@@ -611,8 +675,7 @@ impl<'d> Handler for Control<'d> {
                         if let Some(request_handler) = self.request_handler.as_mut() {
                             buf[0] = request_handler.get_protocol() as u8;
                         } else {
-                            // Return `Report` protocol mode by default
-                            buf[0] = HidProtocolMode::Report as u8;
+                            buf[0] = self.protocol.load(Ordering::Relaxed);
                         }
                         Some(InResponse::Accepted(&buf[0..1]))
                     }
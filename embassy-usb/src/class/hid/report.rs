@@ -0,0 +1,338 @@
+//! Typed HID reports.
+//!
+//! A [`HidReport`] ties a report structure to the report descriptor items describing it, and converts it
+//! to and from the bytes sent on the bus. Implement it with `#[derive(HidReport)]`, which requires the
+//! `derive` feature, or by hand.
+//!
+//! This module also provides reports in the boot protocol format of keyboards and mice.
+
+#[cfg(feature = "derive")]
+pub use embassy_usb_macros::HidReport;
+
+use super::ReportId;
+use super::descriptor::{Collection, ItemFlags, ReportDescriptor, generic_desktop, usage_page};
+
+/// Type of a report.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ReportKind {
+    /// Report sent to the host, on the interrupt IN endpoint or with a GET_REPORT request.
+    Input,
+    /// Report received from the host, on the interrupt OUT endpoint or with a SET_REPORT request.
+    Output,
+    /// Report read and written by the host with GET_REPORT and SET_REPORT requests.
+    Feature,
+}
+
+/// A HID report with a known layout.
+///
+/// Several reports can share an interface if they have report IDs. The report descriptor of the
+/// interface is then the concatenation of their descriptors, for example built with
+/// [`ReportDescriptor::extend`]. The ID of a report received from the host is given by
+/// [`REPORT_ID`](Self::REPORT_ID), which can be matched against the `ReportId` of a
+/// [`RequestHandler`](super::RequestHandler) call:
+///
+/// ```
+/// use embassy_usb::class::hid::report::{HidReport, KeyboardLedsReport};
+/// use embassy_usb::class::hid::{ReportId, RequestHandler};
+/// use embassy_usb::control::OutResponse;
+///
+/// struct Handler {
+///     leds: KeyboardLedsReport,
+/// }
+///
+/// impl RequestHandler for Handler {
+///     fn set_report(&mut self, id: ReportId, data: &[u8]) -> OutResponse {
+///         match id {
+///             KeyboardLedsReport::REPORT_ID => match KeyboardLedsReport::from_bytes(data) {
+///                 Some(leds) => self.leds = leds,
+///                 None => return OutResponse::Rejected,
+///             },
+///             _ => return OutResponse::Rejected,
+///         }
+///         OutResponse::Accepted
+///     }
+/// }
+/// ```
+pub trait HidReport: Sized {
+    /// Report descriptor items describing the report.
+    const DESCRIPTOR: &'static [u8];
+    /// Type of the report.
+    const KIND: ReportKind;
+    /// ID of the report, or `None` if the interface doesn't use report IDs.
+    const ID: Option<u8>;
+    /// Size of the report in bytes, including the report ID.
+    const SIZE: usize;
+    /// The [`ReportId`] of the report in the requests of the host. It's 0 for reports without an ID.
+    const REPORT_ID: ReportId = match Self::KIND {
+        ReportKind::Input => ReportId::In(id_or_zero(Self::ID)),
+        ReportKind::Output => ReportId::Out(id_or_zero(Self::ID)),
+        ReportKind::Feature => ReportId::Feature(id_or_zero(Self::ID)),
+    };
+
+    /// Writes the report, starting with its ID, to `buf`, and returns its size.
+    ///
+    /// Panics if `buf` is smaller than [`SIZE`](Self::SIZE).
+    fn to_bytes(&self, buf: &mut [u8]) -> usize;
+
+    /// Reads a report, starting with its ID, from `data`.
+    ///
+    /// Returns `None` if `data` is too short, or starts with another report ID.
+    fn from_bytes(data: &[u8]) -> Option<Self>;
+
+    /// Reads a report from `data` if `id` is its ID, for dispatching the reports of the host.
+    fn parse(id: ReportId, data: &[u8]) -> Option<Self> {
+        if id == Self::REPORT_ID {
+            Self::from_bytes(data)
+        } else {
+            None
+        }
+    }
+}
+
+const fn id_or_zero(id: Option<u8>) -> u8 {
+    match id {
+        Some(id) => id,
+        None => 0,
+    }
+}
+
+/// Report of a keyboard, in the boot protocol format.
+///
+/// Its descriptor is the one of a boot keyboard, which also describes the [`KeyboardLedsReport`], so it
+/// can be used both in the report and boot protocols.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct KeyboardReport {
+    /// Modifier keys, one bit each: left control, shift, alt and GUI, then right control, shift, alt and GUI.
+    pub modifiers: u8,
+    /// Key codes of up to 6 pressed keys, from the Keyboard/Keypad usage page. Unused entries are 0.
+    pub keycodes: [u8; 6],
+}
+
+const KEYBOARD_DESCRIPTOR: ReportDescriptor<64> = ReportDescriptor::new()
+    .usage_page(usage_page::GENERIC_DESKTOP)
+    .usage(generic_desktop::KEYBOARD)
+    .collection(Collection::Application)
+    // Modifiers
+    .usage_page(usage_page::KEYBOARD)
+    .usage_min(0xE0)
+    .usage_max(0xE7)
+    .logical_min(0)
+    .logical_max(1)
+    .report_size(1)
+    .report_count(8)
+    .input(ItemFlags::VARIABLE)
+    // Reserved
+    .report_size(8)
+    .report_count(1)
+    .input(ItemFlags::CONSTANT)
+    // LEDs
+    .usage_page(usage_page::LED)
+    .usage_min(0x01)
+    .usage_max(0x05)
+    .report_size(1)
+    .report_count(5)
+    .output(ItemFlags::VARIABLE)
+    .report_size(3)
+    .report_count(1)
+    .output(ItemFlags::CONSTANT)
+    // Key codes
+    .usage_page(usage_page::KEYBOARD)
+    .usage_min(0x00)
+    .usage_max(0xFF)
+    .logical_max(0xFF)
+    .report_size(8)
+    .report_count(6)
+    .input(ItemFlags::DATA)
+    .end_collection();
+
+impl HidReport for KeyboardReport {
+    const DESCRIPTOR: &'static [u8] = KEYBOARD_DESCRIPTOR.as_bytes();
+    const KIND: ReportKind = ReportKind::Input;
+    const ID: Option<u8> = None;
+    const SIZE: usize = 8;
+
+    fn to_bytes(&self, buf: &mut [u8]) -> usize {
+        buf[0] = self.modifiers;
+        buf[1] = 0;
+        buf[2..8].copy_from_slice(&self.keycodes);
+        Self::SIZE
+    }
+
+    fn from_bytes(data: &[u8]) -> Option<Self> {
+        let data = data.get(..Self::SIZE)?;
+        Some(Self {
+            modifiers: data[0],
+            keycodes: data[2..8].try_into().unwrap(),
+        })
+    }
+}
+
+/// Report of the LEDs of a keyboard, sent by the host. It's described by the descriptor of [`KeyboardReport`].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct KeyboardLedsReport {
+    /// Num Lock.
+    pub num_lock: bool,
+    /// Caps Lock.
+    pub caps_lock: bool,
+    /// Scroll Lock.
+    pub scroll_lock: bool,
+    /// Compose.
+    pub compose: bool,
+    /// Kana.
+    pub kana: bool,
+}
+
+impl HidReport for KeyboardLedsReport {
+    const DESCRIPTOR: &'static [u8] = KEYBOARD_DESCRIPTOR.as_bytes();
+    const KIND: ReportKind = ReportKind::Output;
+    const ID: Option<u8> = None;
+    const SIZE: usize = 1;
+
+    fn to_bytes(&self, buf: &mut [u8]) -> usize {
+        buf[0] = self.num_lock as u8
+            | (self.caps_lock as u8) << 1
+            | (self.scroll_lock as u8) << 2
+            | (self.compose as u8) << 3
+            | (self.kana as u8) << 4;
+        Self::SIZE
+    }
+
+    fn from_bytes(data: &[u8]) -> Option<Self> {
+        let leds = *data.first()?;
+        Some(Self {
+            num_lock: leds & 1 << 0 != 0,
+            caps_lock: leds & 1 << 1 != 0,
+            scroll_lock: leds & 1 << 2 != 0,
+            compose: leds & 1 << 3 != 0,
+            kana: leds & 1 << 4 != 0,
+        })
+    }
+}
+
+/// Report of a mouse with a wheel.
+///
+/// Its first 3 bytes are in the boot protocol format, and hosts using the boot protocol ignore the
+/// wheel, so it can be used both in the report and boot protocols.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MouseReport {
+    /// Buttons, one bit each, starting with the primary button in bit 0.
+    pub buttons: u8,
+    /// Horizontal movement.
+    pub x: i8,
+    /// Vertical movement.
+    pub y: i8,
+    /// Wheel movement.
+    pub wheel: i8,
+}
+
+const MOUSE_DESCRIPTOR: ReportDescriptor<64> = ReportDescriptor::new()
+    .usage_page(usage_page::GENERIC_DESKTOP)
+    .usage(generic_desktop::MOUSE)
+    .collection(Collection::Application)
+    .usage(generic_desktop::POINTER)
+    .collection(Collection::Physical)
+    // Buttons
+    .usage_page(usage_page::BUTTON)
+    .usage_min(1)
+    .usage_max(8)
+    .logical_min(0)
+    .logical_max(1)
+    .report_size(1)
+    .report_count(8)
+    .input(ItemFlags::VARIABLE)
+    // Axes
+    .usage_page(usage_page::GENERIC_DESKTOP)
+    .usage(generic_desktop::X)
+    .usage(generic_desktop::Y)
+    .usage(generic_desktop::WHEEL)
+    .logical_min(-127)
+    .logical_max(127)
+    .report_size(8)
+    .report_count(3)
+    .input(ItemFlags::VARIABLE.union(ItemFlags::RELATIVE))
+    .end_collection()
+    .end_collection();
+
+impl HidReport for MouseReport {
+    const DESCRIPTOR: &'static [u8] = MOUSE_DESCRIPTOR.as_bytes();
+    const KIND: ReportKind = ReportKind::Input;
+    const ID: Option<u8> = None;
+    const SIZE: usize = 4;
+
+    fn to_bytes(&self, buf: &mut [u8]) -> usize {
+        buf[..Self::SIZE].copy_from_slice(&[self.buttons, self.x as u8, self.y as u8, self.wheel as u8]);
+        Self::SIZE
+    }
+
+    fn from_bytes(data: &[u8]) -> Option<Self> {
+        let data = data.get(..Self::SIZE)?;
+        Some(Self {
+            buttons: data[0],
+            x: data[1] as i8,
+            y: data[2] as i8,
+            wheel: data[3] as i8,
+        })
+    }
+}
+
+/// Writes the fields of a report, least significant bit first. Used by `#[derive(HidReport)]`.
+#[doc(hidden)]
+pub struct BitWriter<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> BitWriter<'a> {
+    /// Clears the first `size` bytes of `buf`, which must be long enough.
+    pub fn new(buf: &'a mut [u8], size: usize) -> Self {
+        assert!(buf.len() >= size, "buffer too small for HID report");
+        buf[..size].fill(0);
+        Self { buf, pos: 0 }
+    }
+
+    /// Writes the low `bits` bits of `value`.
+    pub fn write(&mut self, value: u32, bits: usize) {
+        for i in 0..bits {
+            if value >> i & 1 != 0 {
+                let pos = self.pos + i;
+                self.buf[pos / 8] |= 1 << (pos % 8);
+            }
+        }
+        self.pos += bits;
+    }
+}
+
+/// Reads the fields of a report, least significant bit first. Used by `#[derive(HidReport)]`.
+#[doc(hidden)]
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    /// Returns `None` if `data` is shorter than `size` bytes.
+    pub fn new(data: &'a [u8], size: usize) -> Option<Self> {
+        (data.len() >= size).then_some(Self { data, pos: 0 })
+    }
+
+    /// Reads an unsigned value of `bits` bits.
+    pub fn read(&mut self, bits: usize) -> u32 {
+        let mut value = 0;
+        for i in 0..bits {
+            let pos = self.pos + i;
+            value |= ((self.data[pos / 8] >> (pos % 8) & 1) as u32) << i;
+        }
+        self.pos += bits;
+        value
+    }
+
+    /// Reads a signed value of `bits` bits.
+    pub fn read_signed(&mut self, bits: usize) -> i32 {
+        let shift = 32 - bits as u32;
+        ((self.read(bits) << shift) as i32) >> shift
+    }
+}
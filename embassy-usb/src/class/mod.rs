@@ -1,9 +1,18 @@
 //! Implementations of well-known USB classes.
+pub mod ccid;
 pub mod cdc_acm;
+pub mod cdc_ecm;
 pub mod cdc_ncm;
 pub mod cmsis_dap_v2;
 pub mod dfu;
+pub mod ecm_rndis;
 pub mod hid;
 pub mod midi;
+pub mod msc;
+pub mod printer;
+pub mod rndis;
 pub mod uac1;
+pub mod uac2;
+pub mod usbtmc;
+pub mod uvc;
 pub mod web_usb;
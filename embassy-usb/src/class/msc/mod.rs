@@ -0,0 +1,543 @@
+//! USB Mass Storage class implementation, aka USB drives.
+//!
+//! This implements the Bulk-Only Transport (BOT) with the SCSI transparent command set, which
+//! hosts support without drivers. Each logical unit (LUN) of the drive is backed by a
+//! [`BlockDevice`], such as an SD card or a region of internal flash.
+//!
+//! An invalid or meaningless CBW stalls both endpoints until the host does a reset recovery, as
+//! required by BOT §6.6.1. This needs a driver that supports [`Endpoint::stall`], otherwise the CBW is
+//! ignored. When the host and the device disagree on the length or the direction of the data of a
+//! command, the data is cut short or discarded, and the mismatch is reported in the command status.
+
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::control::{InResponse, OutResponse, Recipient, Request, RequestType};
+use crate::driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointOut};
+use crate::types::InterfaceNumber;
+use crate::{Builder, Handler};
+
+mod scsi;
+
+use scsi::{Reply, Sense};
+
+/// This should be used as `device_class` when building the `UsbDevice`.
+pub const USB_CLASS_MSC: u8 = 0x08;
+
+const MSC_SUBCLASS_SCSI: u8 = 0x06;
+const MSC_PROTOCOL_BOT: u8 = 0x50;
+
+const REQ_GET_MAX_LUN: u8 = 0xFE;
+const REQ_BULK_ONLY_RESET: u8 = 0xFF;
+
+const CBW_SIGNATURE: u32 = 0x4342_5355;
+const CSW_SIGNATURE: u32 = 0x5342_5355;
+const CBW_LEN: usize = 31;
+const CSW_LEN: usize = 13;
+
+/// Maximum number of logical units of a drive.
+pub const MAX_LUNS: usize = 16;
+
+/// A block device, backing a logical unit of the drive.
+///
+/// For an SD card, wrap the `StorageDevice` of your HAL, for example
+/// `embassy_stm32::sdmmc::StorageDevice` with a block size of 512 bytes.
+#[allow(async_fn_in_trait)]
+pub trait BlockDevice {
+    /// Error type of the device.
+    type Error;
+
+    /// Size of a block in bytes.
+    ///
+    /// It must be a multiple of the max packet size of the class.
+    fn block_size(&self) -> u32;
+
+    /// Number of blocks of the device.
+    fn block_count(&self) -> u64;
+
+    /// Check whether a medium is present, for example whether an SD card is inserted.
+    fn is_present(&self) -> bool {
+        true
+    }
+
+    /// Check whether the device can be written to.
+    ///
+    /// The host sees read-only devices as write protected.
+    fn is_writable(&self) -> bool {
+        true
+    }
+
+    /// Read blocks starting at `lba` into `buf`, whose length is a multiple of the block size.
+    async fn read(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), Self::Error>;
+
+    /// Write the blocks in `data` starting at `lba`, whose length is a multiple of the block size.
+    async fn write(&mut self, lba: u64, data: &[u8]) -> Result<(), Self::Error>;
+
+    /// Write any cached data to the device.
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Called when the host ejects the medium, after flushing it.
+    ///
+    /// The medium is reported as not present until the host loads it again, or the device is
+    /// reconfigured.
+    fn eject(&mut self) {}
+}
+
+impl<T: BlockDevice + ?Sized> BlockDevice for &mut T {
+    type Error = T::Error;
+
+    fn block_size(&self) -> u32 {
+        T::block_size(self)
+    }
+
+    fn block_count(&self) -> u64 {
+        T::block_count(self)
+    }
+
+    fn is_present(&self) -> bool {
+        T::is_present(self)
+    }
+
+    fn is_writable(&self) -> bool {
+        T::is_writable(self)
+    }
+
+    async fn read(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), Self::Error> {
+        T::read(self, lba, buf).await
+    }
+
+    async fn write(&mut self, lba: u64, data: &[u8]) -> Result<(), Self::Error> {
+        T::write(self, lba, data).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        T::flush(self).await
+    }
+
+    fn eject(&mut self) {
+        T::eject(self)
+    }
+}
+
+/// Configuration of the drive, reported to the host in the INQUIRY data of each logical unit.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Config<'a> {
+    /// Vendor identification, up to 8 ASCII characters.
+    pub vendor: &'a str,
+    /// Product identification, up to 16 ASCII characters.
+    pub product: &'a str,
+    /// Product revision level, up to 4 ASCII characters.
+    pub revision: &'a str,
+    /// Whether the medium can be removed, which lets the host eject it.
+    pub removable: bool,
+}
+
+impl Default for Config<'_> {
+    fn default() -> Self {
+        Self {
+            vendor: "Embassy",
+            product: "USB drive",
+            revision: "1.0",
+            removable: true,
+        }
+    }
+}
+
+/// Internal state for the Mass Storage class.
+pub struct State<'a> {
+    control: MaybeUninit<Control<'a>>,
+    shared: ControlShared,
+}
+
+impl<'a> Default for State<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> State<'a> {
+    /// Create a new `State`.
+    pub const fn new() -> Self {
+        Self {
+            control: MaybeUninit::uninit(),
+            shared: ControlShared {
+                reset: AtomicBool::new(false),
+            },
+        }
+    }
+}
+
+/// Shared data between Control and MscClass
+struct ControlShared {
+    /// Set by a Bulk-Only Mass Storage Reset, which aborts the current command.
+    reset: AtomicBool,
+}
+
+struct Control<'a> {
+    iface: InterfaceNumber,
+    max_lun: u8,
+    shared: &'a ControlShared,
+}
+
+impl<'d> Handler for Control<'d> {
+    fn control_out(&mut self, req: Request, _data: &[u8]) -> Option<OutResponse> {
+        if (req.request_type, req.recipient, req.index)
+            != (RequestType::Class, Recipient::Interface, self.iface.0 as u16)
+        {
+            return None;
+        }
+
+        match req.request {
+            REQ_BULK_ONLY_RESET => {
+                debug!("msc: bulk-only reset");
+                self.shared.reset.store(true, Ordering::Relaxed);
+                Some(OutResponse::Accepted)
+            }
+            _ => Some(OutResponse::Rejected),
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if (req.request_type, req.recipient, req.index)
+            != (RequestType::Class, Recipient::Interface, self.iface.0 as u16)
+        {
+            return None;
+        }
+
+        match req.request {
+            REQ_GET_MAX_LUN => {
+                buf[0] = self.max_lun;
+                Some(InResponse::Accepted(&buf[..1]))
+            }
+            _ => Some(InResponse::Rejected),
+        }
+    }
+}
+
+/// Status of a command, reported to the host in the CSW.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Passed = 0x00,
+    Failed = 0x01,
+    PhaseError = 0x02,
+}
+
+/// Command Block Wrapper, sent by the host to start a command.
+struct Cbw {
+    tag: u32,
+    data_len: usize,
+    data_in: bool,
+    lun: u8,
+    cdb: [u8; 16],
+}
+
+impl Cbw {
+    /// Parse a CBW, if it is valid and meaningful for a drive with `num_luns` logical units.
+    fn parse(buf: &[u8], num_luns: usize) -> Option<Self> {
+        let word = |offset: usize| u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap());
+        if buf.len() != CBW_LEN || word(0) != CBW_SIGNATURE {
+            return None;
+        }
+        // The reserved bits of the flags, the LUN and the CDB length must be zero.
+        if buf[12] & 0x7F != 0 || buf[13] & 0xF0 != 0 || buf[14] & 0xE0 != 0 {
+            return None;
+        }
+        let lun = buf[13];
+        let cdb_len = buf[14];
+        if lun as usize >= num_luns || !(1..=16).contains(&cdb_len) {
+            return None;
+        }
+        let mut cdb = [0; 16];
+        cdb[..cdb_len as usize].copy_from_slice(&buf[15..15 + cdb_len as usize]);
+        Some(Self {
+            tag: word(4),
+            data_len: word(8) as usize,
+            data_in: buf[12] & 0x80 != 0,
+            lun,
+            cdb,
+        })
+    }
+}
+
+/// State of a logical unit.
+#[derive(Clone, Copy)]
+struct Unit {
+    sense: Sense,
+    ejected: bool,
+}
+
+impl Unit {
+    const NEW: Self = Self {
+        sense: Sense::NONE,
+        ejected: false,
+    };
+}
+
+/// USB Mass Storage class, with the Bulk-Only Transport and SCSI commands.
+pub struct MscClass<'d, D: Driver<'d>> {
+    read_ep: D::EndpointOut,
+    write_ep: D::EndpointIn,
+    shared: &'d ControlShared,
+    config: Config<'d>,
+    num_luns: usize,
+    units: [Unit; MAX_LUNS],
+    /// Set when the endpoints were stalled after an invalid CBW, until the host resets the class.
+    stalled: bool,
+}
+
+impl<'d, D: Driver<'d>> MscClass<'d, D> {
+    /// Creates a new MscClass with the provided UsbBus, `max_packet_size` in bytes, and
+    /// `num_luns` logical units. For full-speed devices, `max_packet_size` has to be 64.
+    pub fn new(
+        builder: &mut Builder<'d, D>,
+        state: &'d mut State<'d>,
+        config: Config<'d>,
+        max_packet_size: u16,
+        num_luns: usize,
+    ) -> Self {
+        assert!((1..=MAX_LUNS).contains(&num_luns));
+
+        let mut func = builder.function(USB_CLASS_MSC, MSC_SUBCLASS_SCSI, MSC_PROTOCOL_BOT);
+        let mut iface = func.interface();
+        let iface_num = iface.interface_number();
+        let mut alt = iface.alt_setting(USB_CLASS_MSC, MSC_SUBCLASS_SCSI, MSC_PROTOCOL_BOT, None);
+        let read_ep = alt.endpoint_bulk_out(None, max_packet_size);
+        let write_ep = alt.endpoint_bulk_in(None, max_packet_size);
+        drop(func);
+
+        let control = state.control.write(Control {
+            iface: iface_num,
+            max_lun: (num_luns - 1) as u8,
+            shared: &state.shared,
+        });
+        builder.handler(control);
+
+        MscClass {
+            read_ep,
+            write_ep,
+            shared: &state.shared,
+            config,
+            num_luns,
+            units: [Unit::NEW; MAX_LUNS],
+            stalled: false,
+        }
+    }
+
+    /// Gets the maximum packet size in bytes.
+    pub fn max_packet_size(&self) -> u16 {
+        // The size is the same for both endpoints.
+        self.read_ep.info().max_packet_size
+    }
+
+    /// Waits for the USB host to enable this interface
+    pub async fn wait_connection(&mut self) {
+        self.read_ep.wait_enabled().await;
+    }
+
+    /// Serve the commands of the host on `luns`, forever.
+    ///
+    /// There must be one device in `luns` per logical unit given to [`new`](Self::new). `buf`
+    /// holds the data of the commands, it must be at least 64 bytes and one block of each device.
+    /// Larger buffers transfer more blocks at once.
+    pub async fn run<L: BlockDevice>(&mut self, luns: &mut [L], buf: &mut [u8]) -> ! {
+        let mps = self.max_packet_size() as usize;
+        assert_eq!(luns.len(), self.num_luns);
+        assert!(buf.len() >= 64 && buf.len() >= mps);
+
+        loop {
+            self.wait_connection().await;
+            self.units = [Unit::NEW; MAX_LUNS];
+            self.stalled = false;
+            info!("msc: connected");
+
+            loop {
+                match self.command(luns, buf).await {
+                    Ok(()) => {}
+                    Err(EndpointError::Disabled) => break,
+                    Err(EndpointError::BufferOverflow) => warn!("msc: buffer overflow"),
+                }
+            }
+            info!("msc: disconnected");
+        }
+    }
+
+    /// Handle a command, from the CBW to the CSW.
+    async fn command<L: BlockDevice>(&mut self, luns: &mut [L], buf: &mut [u8]) -> Result<(), EndpointError> {
+        let n = self.read_ep.read(buf).await?;
+        // There's nothing to abort between commands.
+        let reset = self.shared.reset.load(Ordering::Relaxed);
+        self.shared.reset.store(false, Ordering::Relaxed);
+        if self.stalled && !reset {
+            // The host cleared the halts without a reset recovery.
+            self.stall();
+            return Ok(());
+        }
+        self.stalled = false;
+        let Some(cbw) = Cbw::parse(&buf[..n], self.num_luns) else {
+            warn!("msc: invalid CBW");
+            self.stall();
+            return Ok(());
+        };
+
+        let index = cbw.lun as usize;
+        let lun = &mut luns[index];
+        let block_size = lun.block_size() as usize;
+        assert!(block_size <= buf.len() && block_size.is_multiple_of(self.max_packet_size() as usize));
+        let unit = &mut self.units[index];
+        let (status, len) =
+            match scsi::execute(lun, &self.config, &mut unit.ejected, &mut unit.sense, &cbw.cdb, buf).await {
+                Ok(reply) => self.data(lun, index, reply, &cbw, buf).await?,
+                Err(sense) => {
+                    self.units[index].sense = sense;
+                    self.skip(&cbw, buf).await?;
+                    (Status::Failed, 0)
+                }
+            };
+
+        if self.shared.reset.load(Ordering::Relaxed) {
+            self.shared.reset.store(false, Ordering::Relaxed);
+            debug!("msc: command aborted");
+            return Ok(());
+        }
+
+        let mut csw = [0; CSW_LEN];
+        csw[0..4].copy_from_slice(&CSW_SIGNATURE.to_le_bytes());
+        csw[4..8].copy_from_slice(&cbw.tag.to_le_bytes());
+        csw[8..12].copy_from_slice(&((cbw.data_len - len) as u32).to_le_bytes());
+        csw[12] = status as u8;
+        self.write_ep.write(&csw).await
+    }
+
+    /// Transfer the data of a command, and return its status and the number of bytes transferred.
+    async fn data<L: BlockDevice>(
+        &mut self,
+        lun: &mut L,
+        index: usize,
+        reply: Reply,
+        cbw: &Cbw,
+        buf: &mut [u8],
+    ) -> Result<(Status, usize), EndpointError> {
+        let expected = cbw.data_len;
+        let data_in = match reply {
+            Reply::None => {
+                self.skip(cbw, buf).await?;
+                return Ok((Status::Passed, 0));
+            }
+            Reply::In(_) | Reply::Read { .. } => true,
+            Reply::Write { .. } => false,
+        };
+        if expected == 0 || cbw.data_in != data_in {
+            self.skip(cbw, buf).await?;
+            return Ok((Status::PhaseError, 0));
+        }
+
+        let block_size = lun.block_size() as usize;
+        let chunk_len = buf.len() / block_size * block_size;
+        match reply {
+            Reply::None => unreachable!(),
+            Reply::In(len) => {
+                let len = len.min(expected);
+                self.write_ep.write_transfer(&buf[..len], len < expected).await?;
+                Ok((Status::Passed, len))
+            }
+            Reply::Read { lba, blocks } => {
+                let total = u64::from(blocks) * block_size as u64;
+                let mut status = if total > expected as u64 {
+                    Status::PhaseError
+                } else {
+                    Status::Passed
+                };
+                // Send at most what the host expects, cutting the last block short if needed.
+                let total = total.min(expected as u64) as usize;
+                let mut sent = 0;
+                let mut lba = lba;
+                while sent < total && !self.shared.reset.load(Ordering::Relaxed) {
+                    let len = chunk_len.min(total - sent);
+                    let read_len = len.next_multiple_of(block_size);
+                    if lun.read(lba, &mut buf[..read_len]).await.is_err() {
+                        warn!("msc: read error");
+                        self.units[index].sense = Sense::UNRECOVERED_READ_ERROR;
+                        status = Status::Failed;
+                        break;
+                    }
+                    self.write_ep.write_transfer(&buf[..len], false).await?;
+                    sent += len;
+                    lba += (read_len / block_size) as u64;
+                }
+                // End the transfer early with a short packet.
+                if sent < expected && sent.is_multiple_of(self.max_packet_size() as usize) {
+                    self.write_ep.write(&[]).await?;
+                }
+                Ok((status, sent))
+            }
+            Reply::Write { lba, blocks } => {
+                let total = u64::from(blocks) * block_size as u64;
+                let mut status = if total > expected as u64 {
+                    Status::PhaseError
+                } else {
+                    Status::Passed
+                };
+                // Only write whole blocks, the rest of the data is discarded and left in the residue.
+                let total = (total.min(expected as u64) as usize) / block_size * block_size;
+                let mut received = 0;
+                let mut written = 0;
+                let mut lba = lba;
+                while received < total && !self.shared.reset.load(Ordering::Relaxed) {
+                    let len = chunk_len.min(total - received);
+                    let n = self.read_ep.read_transfer(&mut buf[..len]).await?;
+                    received += n;
+                    if n < len {
+                        // The host ended the transfer early, and has no more data to send.
+                        return Ok((Status::PhaseError, written));
+                    }
+                    if status != Status::Failed {
+                        if lun.write(lba, &buf[..len]).await.is_ok() {
+                            written += len;
+                        } else {
+                            warn!("msc: write error");
+                            self.units[index].sense = Sense::WRITE_ERROR;
+                            status = Status::Failed;
+                        }
+                    }
+                    lba += (len / block_size) as u64;
+                }
+                self.discard(expected - received, buf).await?;
+                Ok((status, written))
+            }
+        }
+    }
+
+    /// Stall both endpoints after an invalid CBW, until the host does a reset recovery.
+    fn stall(&mut self) {
+        if self.read_ep.stall().is_ok() && self.write_ep.stall().is_ok() {
+            self.stalled = true;
+        }
+    }
+
+    /// Skip the data phase of a command the device has no data for.
+    async fn skip(&mut self, cbw: &Cbw, buf: &mut [u8]) -> Result<(), EndpointError> {
+        if cbw.data_len == 0 {
+            Ok(())
+        } else if cbw.data_in {
+            self.write_ep.write(&[]).await
+        } else {
+            self.discard(cbw.data_len, buf).await
+        }
+    }
+
+    /// Read and drop `len` bytes sent by the host.
+    async fn discard(&mut self, mut len: usize, buf: &mut [u8]) -> Result<(), EndpointError> {
+        let mps = self.max_packet_size() as usize;
+        while len > 0 && !self.shared.reset.load(Ordering::Relaxed) {
+            let n = self.read_ep.read(&mut buf[..mps]).await?;
+            len = len.saturating_sub(n);
+            if n < mps {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
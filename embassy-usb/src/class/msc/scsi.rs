@@ -0,0 +1,234 @@
+//! SCSI transparent command set, as used by USB drives.
+
+use super::{BlockDevice, Config};
+
+const TEST_UNIT_READY: u8 = 0x00;
+const REQUEST_SENSE: u8 = 0x03;
+const INQUIRY: u8 = 0x12;
+const MODE_SENSE_6: u8 = 0x1A;
+const START_STOP_UNIT: u8 = 0x1B;
+const PREVENT_ALLOW_MEDIUM_REMOVAL: u8 = 0x1E;
+const READ_FORMAT_CAPACITIES: u8 = 0x23;
+const READ_CAPACITY_10: u8 = 0x25;
+const READ_10: u8 = 0x28;
+const WRITE_10: u8 = 0x2A;
+const VERIFY_10: u8 = 0x2F;
+const SYNCHRONIZE_CACHE_10: u8 = 0x35;
+const MODE_SENSE_10: u8 = 0x5A;
+const READ_16: u8 = 0x88;
+const WRITE_16: u8 = 0x8A;
+const SERVICE_ACTION_IN_16: u8 = 0x9E;
+
+const SA_READ_CAPACITY_16: u8 = 0x10;
+
+const INQUIRY_LEN: usize = 36;
+const SENSE_LEN: usize = 18;
+
+/// Sense data, reported to the host with REQUEST SENSE after a command failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Sense {
+    key: u8,
+    asc: u8,
+    ascq: u8,
+}
+
+impl Sense {
+    pub const NONE: Self = Self::new(0x00, 0x00, 0x00);
+    pub const MEDIUM_NOT_PRESENT: Self = Self::new(0x02, 0x3A, 0x00);
+    pub const UNRECOVERED_READ_ERROR: Self = Self::new(0x03, 0x11, 0x00);
+    pub const WRITE_ERROR: Self = Self::new(0x03, 0x0C, 0x00);
+    pub const INVALID_COMMAND: Self = Self::new(0x05, 0x20, 0x00);
+    pub const LBA_OUT_OF_RANGE: Self = Self::new(0x05, 0x21, 0x00);
+    pub const INVALID_FIELD_IN_CDB: Self = Self::new(0x05, 0x24, 0x00);
+    pub const WRITE_PROTECTED: Self = Self::new(0x07, 0x27, 0x00);
+
+    const fn new(key: u8, asc: u8, ascq: u8) -> Self {
+        Self { key, asc, ascq }
+    }
+}
+
+/// Data phase of a command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Reply {
+    /// No data.
+    None,
+    /// Send the first bytes of the buffer to the host.
+    In(usize),
+    /// Read blocks from the device and send them to the host.
+    Read { lba: u64, blocks: u32 },
+    /// Receive blocks from the host and write them to the device.
+    Write { lba: u64, blocks: u32 },
+}
+
+fn be16(bytes: &[u8]) -> u16 {
+    u16::from_be_bytes([bytes[0], bytes[1]])
+}
+
+fn be32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn be64(bytes: &[u8]) -> u64 {
+    u64::from_be_bytes(bytes[..8].try_into().unwrap())
+}
+
+/// Copy `s` to `dst`, padded with spaces as SCSI strings are.
+fn copy_padded(dst: &mut [u8], s: &str) {
+    dst.fill(b' ');
+    let n = s.len().min(dst.len());
+    dst[..n].copy_from_slice(&s.as_bytes()[..n]);
+}
+
+/// Execute the command in `cdb` on `lun`, building the data sent to the host in `buf`.
+///
+/// `sense` holds the sense data of the LUN, which is reported and cleared by REQUEST SENSE. Errors
+/// are stored in it by the caller.
+pub(super) async fn execute<L: BlockDevice>(
+    lun: &mut L,
+    config: &Config<'_>,
+    ejected: &mut bool,
+    sense: &mut Sense,
+    cdb: &[u8; 16],
+    buf: &mut [u8],
+) -> Result<Reply, Sense> {
+    let opcode = cdb[0];
+    trace!("msc: SCSI command {:02x}", opcode);
+
+    // Only these commands work without a medium.
+    if !matches!(
+        opcode,
+        REQUEST_SENSE | INQUIRY | START_STOP_UNIT | PREVENT_ALLOW_MEDIUM_REMOVAL
+    ) && (*ejected || !lun.is_present())
+    {
+        return Err(Sense::MEDIUM_NOT_PRESENT);
+    }
+
+    let len = match opcode {
+        TEST_UNIT_READY => return Ok(Reply::None),
+        REQUEST_SENSE => {
+            buf[..SENSE_LEN].fill(0);
+            // Current error, fixed format.
+            buf[0] = 0x70;
+            buf[2] = sense.key;
+            buf[7] = (SENSE_LEN - 8) as u8;
+            buf[12] = sense.asc;
+            buf[13] = sense.ascq;
+            *sense = Sense::NONE;
+            SENSE_LEN.min(cdb[4] as usize)
+        }
+        INQUIRY => {
+            let evpd = cdb[1] & 0x01 != 0;
+            let len = if evpd {
+                // Only the list of supported VPD pages is supported.
+                if cdb[2] != 0x00 {
+                    return Err(Sense::INVALID_FIELD_IN_CDB);
+                }
+                buf[..5].copy_from_slice(&[0x00, 0x00, 0x00, 0x01, 0x00]);
+                5
+            } else {
+                buf[..INQUIRY_LEN].fill(0);
+                // Direct access block device.
+                buf[0] = 0x00;
+                buf[1] = if config.removable { 0x80 } else { 0x00 };
+                // SPC-2
+                buf[2] = 0x04;
+                buf[3] = 0x02;
+                buf[4] = (INQUIRY_LEN - 5) as u8;
+                copy_padded(&mut buf[8..16], config.vendor);
+                copy_padded(&mut buf[16..32], config.product);
+                copy_padded(&mut buf[32..36], config.revision);
+                INQUIRY_LEN
+            };
+            len.min(be16(&cdb[3..]) as usize)
+        }
+        MODE_SENSE_6 => {
+            // Header only, no block descriptors or mode pages.
+            buf[..4].copy_from_slice(&[0x03, 0x00, write_protect(lun), 0x00]);
+            4.min(cdb[4] as usize)
+        }
+        MODE_SENSE_10 => {
+            buf[..8].copy_from_slice(&[0x00, 0x06, 0x00, write_protect(lun), 0x00, 0x00, 0x00, 0x00]);
+            8.min(be16(&cdb[7..]) as usize)
+        }
+        START_STOP_UNIT => {
+            let start = cdb[4] & 0x01 != 0;
+            let load_eject = cdb[4] & 0x02 != 0;
+            if load_eject {
+                *ejected = !start;
+                if !start {
+                    lun.flush().await.map_err(|_| Sense::WRITE_ERROR)?;
+                    lun.eject();
+                }
+            }
+            return Ok(Reply::None);
+        }
+        PREVENT_ALLOW_MEDIUM_REMOVAL => return Ok(Reply::None),
+        READ_FORMAT_CAPACITIES => {
+            let blocks = u32::try_from(lun.block_count()).unwrap_or(u32::MAX);
+            buf[..4].copy_from_slice(&[0x00, 0x00, 0x00, 0x08]);
+            buf[4..8].copy_from_slice(&blocks.to_be_bytes());
+            // Formatted media
+            buf[8..12].copy_from_slice(&(0x0200_0000 | lun.block_size()).to_be_bytes());
+            12.min(be16(&cdb[7..]) as usize)
+        }
+        READ_CAPACITY_10 => {
+            // Hosts fall back to READ CAPACITY(16) when the last LBA doesn't fit.
+            let last = u32::try_from(lun.block_count().saturating_sub(1)).unwrap_or(u32::MAX);
+            buf[..4].copy_from_slice(&last.to_be_bytes());
+            buf[4..8].copy_from_slice(&lun.block_size().to_be_bytes());
+            8
+        }
+        SERVICE_ACTION_IN_16 if cdb[1] & 0x1F == SA_READ_CAPACITY_16 => {
+            buf[..32].fill(0);
+            buf[..8].copy_from_slice(&lun.block_count().saturating_sub(1).to_be_bytes());
+            buf[8..12].copy_from_slice(&lun.block_size().to_be_bytes());
+            32.min(be32(&cdb[10..]) as usize)
+        }
+        READ_10 | WRITE_10 | READ_16 | WRITE_16 => {
+            let (lba, blocks) = match opcode {
+                READ_10 | WRITE_10 => (be32(&cdb[2..]) as u64, be16(&cdb[7..]) as u32),
+                _ => (be64(&cdb[2..]), be32(&cdb[10..])),
+            };
+            check_range(lun, lba, blocks)?;
+            if matches!(opcode, WRITE_10 | WRITE_16) && !lun.is_writable() {
+                return Err(Sense::WRITE_PROTECTED);
+            }
+            if blocks == 0 {
+                return Ok(Reply::None);
+            }
+            return Ok(match opcode {
+                READ_10 | READ_16 => Reply::Read { lba, blocks },
+                _ => Reply::Write { lba, blocks },
+            });
+        }
+        VERIFY_10 => {
+            // Without BYTCHK, there's nothing to compare, and the medium is assumed to be fine.
+            if cdb[1] & 0x02 != 0 {
+                return Err(Sense::INVALID_FIELD_IN_CDB);
+            }
+            check_range(lun, be32(&cdb[2..]) as u64, be16(&cdb[7..]) as u32)?;
+            return Ok(Reply::None);
+        }
+        SYNCHRONIZE_CACHE_10 => {
+            lun.flush().await.map_err(|_| Sense::WRITE_ERROR)?;
+            return Ok(Reply::None);
+        }
+        _ => {
+            debug!("msc: unsupported SCSI command {:02x}", opcode);
+            return Err(Sense::INVALID_COMMAND);
+        }
+    };
+    Ok(Reply::In(len))
+}
+
+/// Device-specific parameter of the mode parameter header, with the write protect bit.
+fn write_protect<L: BlockDevice>(lun: &L) -> u8 {
+    if lun.is_writable() { 0x00 } else { 0x80 }
+}
+
+fn check_range<L: BlockDevice>(lun: &L, lba: u64, blocks: u32) -> Result<(), Sense> {
+    match lba.checked_add(blocks as u64) {
+        Some(end) if end <= lun.block_count() => Ok(()),
+        _ => Err(Sense::LBA_OUT_OF_RANGE),
+    }
+}
@@ -0,0 +1,337 @@
+//! USB Printer class implementation.
+//!
+//! This implements the bidirectional interface of the USB printer class: the host sends print data
+//! on the OUT endpoint, and reads status data back from the IN endpoint. The printer identifies
+//! itself with an IEEE 1284 device ID string, which hosts use to pick a driver.
+
+use core::cell::RefCell;
+use core::future::poll_fn;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use core::task::Poll;
+
+use embassy_sync::waitqueue::WakerRegistration;
+
+use crate::control::{InResponse, OutResponse, Recipient, Request, RequestType};
+use crate::driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointOut};
+use crate::types::InterfaceNumber;
+use crate::{Builder, Handler};
+
+/// This should be used as `device_class` when building the `UsbDevice`.
+pub const USB_CLASS_PRINTER: u8 = 0x07;
+
+const PRINTER_SUBCLASS: u8 = 0x01;
+const PRINTER_PROTOCOL_BIDIRECTIONAL: u8 = 0x02;
+
+const REQ_GET_DEVICE_ID: u8 = 0x00;
+const REQ_GET_PORT_STATUS: u8 = 0x01;
+const REQ_SOFT_RESET: u8 = 0x02;
+
+/// Status of the printer, reported to the host by GET_PORT_STATUS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PortStatus {
+    /// The printer is out of paper.
+    pub paper_empty: bool,
+    /// The printer is selected, i.e. online.
+    pub selected: bool,
+    /// The printer is in an error state.
+    pub error: bool,
+}
+
+impl Default for PortStatus {
+    fn default() -> Self {
+        Self {
+            paper_empty: false,
+            selected: true,
+            error: false,
+        }
+    }
+}
+
+impl PortStatus {
+    fn to_byte(self) -> u8 {
+        (self.paper_empty as u8) << 5 | (self.selected as u8) << 4 | (!self.error as u8) << 3
+    }
+}
+
+/// Internal state for the printer class.
+pub struct State<'a> {
+    control: MaybeUninit<Control<'a>>,
+    shared: ControlShared,
+}
+
+impl<'a> Default for State<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> State<'a> {
+    /// Create a new `State`.
+    pub fn new() -> Self {
+        Self {
+            control: MaybeUninit::uninit(),
+            shared: ControlShared::default(),
+        }
+    }
+}
+
+/// Shared data between Control and `PrinterClass`
+struct ControlShared {
+    port_status: AtomicU8,
+    /// Set by a soft reset, until the application handles it.
+    soft_reset: AtomicBool,
+    waker: RefCell<WakerRegistration>,
+}
+
+impl Default for ControlShared {
+    fn default() -> Self {
+        Self {
+            port_status: AtomicU8::new(PortStatus::default().to_byte()),
+            soft_reset: AtomicBool::new(false),
+            waker: RefCell::new(WakerRegistration::new()),
+        }
+    }
+}
+
+impl ControlShared {
+    fn set_port_status(&self, status: PortStatus) {
+        self.port_status.store(status.to_byte(), Ordering::Relaxed);
+    }
+
+    async fn wait_soft_reset(&self) {
+        poll_fn(|cx| {
+            if self.soft_reset.load(Ordering::Relaxed) {
+                self.soft_reset.store(false, Ordering::Relaxed);
+                Poll::Ready(())
+            } else {
+                self.waker.borrow_mut().register(cx.waker());
+                Poll::Pending
+            }
+        })
+        .await
+    }
+}
+
+struct Control<'a> {
+    iface: InterfaceNumber,
+    device_id: &'a str,
+    shared: &'a ControlShared,
+}
+
+impl<'a> Control<'a> {
+    /// Check whether a request is for this interface.
+    ///
+    /// Printer requests put the interface number in the high byte of `wIndex`, and the alternate
+    /// setting in the low byte, though some hosts only put the interface number. Soft resets may
+    /// also be sent to the "other" recipient.
+    fn is_for_us(&self, req: &Request) -> bool {
+        let iface = self.iface.0 as u16;
+        req.request_type == RequestType::Class
+            && matches!(req.recipient, Recipient::Interface | Recipient::Other)
+            && (req.index == iface << 8 || req.index == iface)
+    }
+}
+
+impl<'d> Handler for Control<'d> {
+    fn reset(&mut self) {
+        self.shared.soft_reset.store(false, Ordering::Relaxed);
+    }
+
+    fn control_out(&mut self, req: Request, _data: &[u8]) -> Option<OutResponse> {
+        if !self.is_for_us(&req) {
+            return None;
+        }
+
+        match req.request {
+            REQ_SOFT_RESET => {
+                debug!("printer: soft reset");
+                self.shared.soft_reset.store(true, Ordering::Relaxed);
+                self.shared.waker.borrow_mut().wake();
+                Some(OutResponse::Accepted)
+            }
+            _ => Some(OutResponse::Rejected),
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if !self.is_for_us(&req) {
+            return None;
+        }
+
+        match req.request {
+            REQ_GET_DEVICE_ID => {
+                // The length includes itself.
+                let len = (2 + self.device_id.len()).min(buf.len());
+                buf[..2].copy_from_slice(&(len as u16).to_be_bytes());
+                buf[2..len].copy_from_slice(&self.device_id.as_bytes()[..len - 2]);
+                Some(InResponse::Accepted(&buf[..len]))
+            }
+            REQ_GET_PORT_STATUS => {
+                buf[0] = self.shared.port_status.load(Ordering::Relaxed);
+                Some(InResponse::Accepted(&buf[..1]))
+            }
+            _ => Some(InResponse::Rejected),
+        }
+    }
+}
+
+/// USB Printer class, with a bidirectional interface.
+pub struct PrinterClass<'d, D: Driver<'d>> {
+    read_ep: D::EndpointOut,
+    write_ep: D::EndpointIn,
+    control: &'d ControlShared,
+}
+
+impl<'d, D: Driver<'d>> PrinterClass<'d, D> {
+    /// Creates a new PrinterClass with the provided UsbBus and `max_packet_size` in bytes. For
+    /// full-speed devices, `max_packet_size` has to be 64.
+    ///
+    /// `device_id` is the IEEE 1284 device ID string, for example
+    /// `"MFG:Embassy;MDL:Receipt Printer;CMD:ESC/POS;CLS:PRINTER;"`. The control buffer given to the
+    /// [`Builder`] must fit it, plus two bytes.
+    pub fn new(
+        builder: &mut Builder<'d, D>,
+        state: &'d mut State<'d>,
+        device_id: &'d str,
+        max_packet_size: u16,
+    ) -> Self {
+        let mut func = builder.function(USB_CLASS_PRINTER, PRINTER_SUBCLASS, PRINTER_PROTOCOL_BIDIRECTIONAL);
+        let mut iface = func.interface();
+        let iface_num = iface.interface_number();
+        let mut alt = iface.alt_setting(
+            USB_CLASS_PRINTER,
+            PRINTER_SUBCLASS,
+            PRINTER_PROTOCOL_BIDIRECTIONAL,
+            None,
+        );
+        let read_ep = alt.endpoint_bulk_out(None, max_packet_size);
+        let write_ep = alt.endpoint_bulk_in(None, max_packet_size);
+        drop(func);
+
+        let control = state.control.write(Control {
+            iface: iface_num,
+            device_id,
+            shared: &state.shared,
+        });
+        builder.handler(control);
+
+        PrinterClass {
+            read_ep,
+            write_ep,
+            control: &state.shared,
+        }
+    }
+
+    /// Gets the maximum packet size in bytes.
+    pub fn max_packet_size(&self) -> u16 {
+        // The size is the same for both endpoints.
+        self.read_ep.info().max_packet_size
+    }
+
+    /// Sets the status reported to the host.
+    pub fn set_port_status(&self, status: PortStatus) {
+        self.control.set_port_status(status);
+    }
+
+    /// Waits for the host to request a soft reset.
+    ///
+    /// The printer should then drop the print data it received so far.
+    pub async fn wait_soft_reset(&self) {
+        self.control.wait_soft_reset().await;
+    }
+
+    /// Writes a single packet of status data into the IN endpoint.
+    pub async fn write_packet(&mut self, data: &[u8]) -> Result<(), EndpointError> {
+        self.write_ep.write(data).await
+    }
+
+    /// Reads a single packet of print data from the OUT endpoint.
+    pub async fn read_packet(&mut self, data: &mut [u8]) -> Result<usize, EndpointError> {
+        self.read_ep.read(data).await
+    }
+
+    /// Waits for the USB host to enable this interface
+    pub async fn wait_connection(&mut self) {
+        self.read_ep.wait_enabled().await;
+    }
+
+    /// Split the class into a sender and receiver.
+    ///
+    /// This allows concurrently sending status data and receiving print data from separate tasks.
+    pub fn split(self) -> (Sender<'d, D>, Receiver<'d, D>) {
+        (
+            Sender {
+                write_ep: self.write_ep,
+                control: self.control,
+            },
+            Receiver {
+                read_ep: self.read_ep,
+                control: self.control,
+            },
+        )
+    }
+}
+
+/// Printer class status data sender.
+///
+/// You can obtain a `Sender` with [`PrinterClass::split`]
+pub struct Sender<'d, D: Driver<'d>> {
+    write_ep: D::EndpointIn,
+    control: &'d ControlShared,
+}
+
+impl<'d, D: Driver<'d>> Sender<'d, D> {
+    /// Gets the maximum packet size in bytes.
+    pub fn max_packet_size(&self) -> u16 {
+        self.write_ep.info().max_packet_size
+    }
+
+    /// Sets the status reported to the host.
+    pub fn set_port_status(&self, status: PortStatus) {
+        self.control.set_port_status(status);
+    }
+
+    /// Writes a single packet of status data into the IN endpoint.
+    pub async fn write_packet(&mut self, data: &[u8]) -> Result<(), EndpointError> {
+        self.write_ep.write(data).await
+    }
+
+    /// Waits for the USB host to enable this interface
+    pub async fn wait_connection(&mut self) {
+        self.write_ep.wait_enabled().await;
+    }
+}
+
+/// Printer class print data receiver.
+///
+/// You can obtain a `Receiver` with [`PrinterClass::split`]
+pub struct Receiver<'d, D: Driver<'d>> {
+    read_ep: D::EndpointOut,
+    control: &'d ControlShared,
+}
+
+impl<'d, D: Driver<'d>> Receiver<'d, D> {
+    /// Gets the maximum packet size in bytes.
+    pub fn max_packet_size(&self) -> u16 {
+        self.read_ep.info().max_packet_size
+    }
+
+    /// Waits for the host to request a soft reset.
+    ///
+    /// The printer should then drop the print data it received so far.
+    pub async fn wait_soft_reset(&self) {
+        self.control.wait_soft_reset().await;
+    }
+
+    /// Reads a single packet of print data from the OUT endpoint.
+    pub async fn read_packet(&mut self, data: &mut [u8]) -> Result<usize, EndpointError> {
+        self.read_ep.read(data).await
+    }
+
+    /// Waits for the USB host to enable this interface
+    pub async fn wait_connection(&mut self) {
+        self.read_ep.wait_enabled().await;
+    }
+}
@@ -0,0 +1,697 @@
+//! RNDIS class implementation, aka Ethernet over USB for Windows.
+//!
+//! RNDIS (Remote Network Driver Interface Specification) is Microsoft's proprietary variant of
+//! CDC-ACM for network devices. Control messages are encapsulated in CDC commands and responses,
+//! and each Ethernet frame is sent as a single bulk transfer with an RNDIS message header.
+//!
+//! The function has a Microsoft OS 2.0 compatible ID, which makes Windows load its built-in RNDIS
+//! driver without an INF file. This needs the MS OS descriptors to be enabled with
+//! [`Builder::msos_descriptor`](crate::Builder::msos_descriptor).
+//!
+//! # Compatibility
+//!
+//! Windows: Supported out of the box since Windows 7, with the MS OS descriptors.
+//!
+//! Linux: Supported with the `rndis_host` driver, though some distributions disable it.
+//!
+//! macOS: NOT supported. Use [CDC-ECM](super::cdc_ecm) instead, or both with [`ecm_rndis`](super::ecm_rndis).
+
+use core::cell::RefCell;
+use core::future::poll_fn;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::Poll;
+
+use embassy_sync::waitqueue::WakerRegistration;
+
+use crate::control::{self, InResponse, OutResponse, Recipient, Request, RequestType};
+use crate::driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointOut};
+use crate::types::InterfaceNumber;
+use crate::{Builder, Handler, msos};
+
+pub mod embassy_net;
+
+/// This should be used as `device_class` when building the `UsbDevice`.
+pub const USB_CLASS_CDC: u8 = 0x02;
+
+const USB_CLASS_CDC_DATA: u8 = 0x0a;
+const CDC_SUBCLASS_ACM: u8 = 0x02;
+const CDC_PROTOCOL_VENDOR: u8 = 0xFF;
+const CDC_PROTOCOL_NONE: u8 = 0x00;
+
+const CS_INTERFACE: u8 = 0x24;
+const CDC_TYPE_HEADER: u8 = 0x00;
+const CDC_TYPE_CALL_MANAGEMENT: u8 = 0x01;
+const CDC_TYPE_ACM: u8 = 0x02;
+const CDC_TYPE_UNION: u8 = 0x06;
+
+const REQ_SEND_ENCAPSULATED_COMMAND: u8 = 0x00;
+const REQ_GET_ENCAPSULATED_RESPONSE: u8 = 0x01;
+
+const NOTIF_RESPONSE_AVAILABLE: u8 = 0x01;
+
+const MSG_PACKET: u32 = 0x0000_0001;
+const MSG_INITIALIZE: u32 = 0x0000_0002;
+const MSG_HALT: u32 = 0x0000_0003;
+const MSG_QUERY: u32 = 0x0000_0004;
+const MSG_SET: u32 = 0x0000_0005;
+const MSG_RESET: u32 = 0x0000_0006;
+const MSG_INDICATE_STATUS: u32 = 0x0000_0007;
+const MSG_KEEPALIVE: u32 = 0x0000_0008;
+/// Set in the message type of responses.
+const MSG_COMPLETION: u32 = 0x8000_0000;
+
+const STATUS_SUCCESS: u32 = 0x0000_0000;
+const STATUS_NOT_SUPPORTED: u32 = 0xC000_00BB;
+
+const OID_GEN_SUPPORTED_LIST: u32 = 0x0001_0101;
+const OID_GEN_HARDWARE_STATUS: u32 = 0x0001_0102;
+const OID_GEN_MEDIA_SUPPORTED: u32 = 0x0001_0103;
+const OID_GEN_MEDIA_IN_USE: u32 = 0x0001_0104;
+const OID_GEN_MAXIMUM_FRAME_SIZE: u32 = 0x0001_0106;
+const OID_GEN_LINK_SPEED: u32 = 0x0001_0107;
+const OID_GEN_TRANSMIT_BLOCK_SIZE: u32 = 0x0001_010A;
+const OID_GEN_RECEIVE_BLOCK_SIZE: u32 = 0x0001_010B;
+const OID_GEN_VENDOR_ID: u32 = 0x0001_010C;
+const OID_GEN_VENDOR_DESCRIPTION: u32 = 0x0001_010D;
+const OID_GEN_CURRENT_PACKET_FILTER: u32 = 0x0001_010E;
+const OID_GEN_MAXIMUM_TOTAL_SIZE: u32 = 0x0001_0111;
+const OID_GEN_MEDIA_CONNECT_STATUS: u32 = 0x0001_0114;
+const OID_GEN_PHYSICAL_MEDIUM: u32 = 0x0001_0202;
+const OID_GEN_XMIT_OK: u32 = 0x0002_0101;
+const OID_GEN_RCV_OK: u32 = 0x0002_0102;
+const OID_GEN_XMIT_ERROR: u32 = 0x0002_0103;
+const OID_GEN_RCV_ERROR: u32 = 0x0002_0104;
+const OID_GEN_RCV_NO_BUFFER: u32 = 0x0002_0105;
+const OID_802_3_PERMANENT_ADDRESS: u32 = 0x0101_0101;
+const OID_802_3_CURRENT_ADDRESS: u32 = 0x0101_0102;
+const OID_802_3_MULTICAST_LIST: u32 = 0x0101_0103;
+const OID_802_3_MAXIMUM_LIST_SIZE: u32 = 0x0101_0104;
+const OID_802_3_MAC_OPTIONS: u32 = 0x0101_0113;
+const OID_802_3_RCV_ERROR_ALIGNMENT: u32 = 0x0102_0101;
+const OID_802_3_XMIT_ONE_COLLISION: u32 = 0x0102_0102;
+const OID_802_3_XMIT_MORE_COLLISIONS: u32 = 0x0102_0103;
+
+const SUPPORTED_OIDS: [u32; 27] = [
+    OID_GEN_SUPPORTED_LIST,
+    OID_GEN_HARDWARE_STATUS,
+    OID_GEN_MEDIA_SUPPORTED,
+    OID_GEN_MEDIA_IN_USE,
+    OID_GEN_MAXIMUM_FRAME_SIZE,
+    OID_GEN_LINK_SPEED,
+    OID_GEN_TRANSMIT_BLOCK_SIZE,
+    OID_GEN_RECEIVE_BLOCK_SIZE,
+    OID_GEN_VENDOR_ID,
+    OID_GEN_VENDOR_DESCRIPTION,
+    OID_GEN_CURRENT_PACKET_FILTER,
+    OID_GEN_MAXIMUM_TOTAL_SIZE,
+    OID_GEN_MEDIA_CONNECT_STATUS,
+    OID_GEN_PHYSICAL_MEDIUM,
+    OID_GEN_XMIT_OK,
+    OID_GEN_RCV_OK,
+    OID_GEN_XMIT_ERROR,
+    OID_GEN_RCV_ERROR,
+    OID_GEN_RCV_NO_BUFFER,
+    OID_802_3_PERMANENT_ADDRESS,
+    OID_802_3_CURRENT_ADDRESS,
+    OID_802_3_MULTICAST_LIST,
+    OID_802_3_MAXIMUM_LIST_SIZE,
+    OID_802_3_MAC_OPTIONS,
+    OID_802_3_RCV_ERROR_ALIGNMENT,
+    OID_802_3_XMIT_ONE_COLLISION,
+    OID_802_3_XMIT_MORE_COLLISIONS,
+];
+
+const VENDOR_DESCRIPTION: &[u8] = b"embassy-usb RNDIS\0";
+
+/// Maximum size of an Ethernet frame, without FCS.
+const MAX_FRAME_SIZE: usize = 1514;
+/// Size of the header of `REMOTE_NDIS_PACKET_MSG`.
+const PACKET_HEADER_LEN: usize = 44;
+/// Maximum size of a data message.
+const MAX_TRANSFER_SIZE: usize = PACKET_HEADER_LEN + MAX_FRAME_SIZE;
+/// Size of the buffer for received data messages.
+///
+/// Hosts append a byte to messages filling the last packet, to avoid a ZLP.
+const RX_BUFFER_SIZE: usize = MAX_TRANSFER_SIZE + 1;
+/// Size of the buffer for control message responses, enough for the list of supported OIDs.
+const RESPONSE_BUFFER_SIZE: usize = 24 + 4 * SUPPORTED_OIDS.len();
+
+fn le32(buf: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(buf.get(offset..offset + 4)?.try_into().unwrap()))
+}
+
+/// Write `words` as little endian to the start of `buf`, and return the number of bytes written.
+fn put_words(buf: &mut [u8], words: &[u32]) -> usize {
+    for (chunk, word) in buf.chunks_exact_mut(4).zip(words) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    words.len() * 4
+}
+
+/// Internal state for the RNDIS class.
+pub struct State<'a> {
+    control: MaybeUninit<Control<'a>>,
+    shared: ControlShared,
+}
+
+impl<'a> Default for State<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> State<'a> {
+    /// Create a new `State`.
+    pub fn new() -> Self {
+        Self {
+            control: MaybeUninit::uninit(),
+            shared: ControlShared::default(),
+        }
+    }
+}
+
+/// Shared data between Control and `RndisClass`
+#[derive(Default)]
+struct ControlShared {
+    /// The host must be notified that a response to a control message is available.
+    response_available: AtomicBool,
+    response_waker: RefCell<WakerRegistration>,
+
+    /// The host initialized the device and set a packet filter, and exchanges data messages.
+    connected: AtomicBool,
+    connected_waker: RefCell<WakerRegistration>,
+}
+
+impl ControlShared {
+    fn set_connected(&self, connected: bool) {
+        if self.connected.load(Ordering::Relaxed) != connected {
+            self.connected.store(connected, Ordering::Relaxed);
+            info!("rndis: {}", if connected { "connected" } else { "disconnected" });
+            self.connected_waker.borrow_mut().wake();
+        }
+    }
+}
+
+struct Control<'a> {
+    shared: &'a ControlShared,
+    mac_addr: [u8; 6],
+    /// Link speed, in units of 100 bit/s.
+    link_speed: u32,
+    comm_if: InterfaceNumber,
+    packet_filter: u32,
+    response: [u8; RESPONSE_BUFFER_SIZE],
+    response_len: usize,
+}
+
+impl<'a> Control<'a> {
+    /// Handle a control message, and prepare its response.
+    fn handle_message(&mut self, msg: &[u8]) {
+        let (Some(msg_type), Some(request_id)) = (le32(msg, 0), le32(msg, 8)) else {
+            warn!("rndis: control message too short");
+            return;
+        };
+        trace!("rndis: control message {:08x}", msg_type);
+
+        let len = match msg_type {
+            MSG_INITIALIZE => put_words(
+                &mut self.response,
+                &[
+                    MSG_INITIALIZE | MSG_COMPLETION,
+                    52,
+                    request_id,
+                    STATUS_SUCCESS,
+                    1,                        // MajorVersion
+                    0,                        // MinorVersion
+                    0x0000_0001,              // DeviceFlags = RNDIS_DF_CONNECTIONLESS
+                    0,                        // Medium = 802.3
+                    1,                        // MaxPacketsPerTransfer
+                    MAX_TRANSFER_SIZE as u32, // MaxTransferSize
+                    0,                        // PacketAlignmentFactor
+                    0,                        // AFListOffset
+                    0,                        // AFListSize
+                ],
+            ),
+            MSG_HALT => {
+                self.packet_filter = 0;
+                self.shared.set_connected(false);
+                return;
+            }
+            MSG_QUERY => {
+                let oid = le32(msg, 12).unwrap_or(0);
+                let (status, info_len) = match self.query(oid) {
+                    Some(info_len) => (STATUS_SUCCESS, info_len),
+                    None => {
+                        debug!("rndis: unsupported query OID {:08x}", oid);
+                        (STATUS_NOT_SUPPORTED, 0)
+                    }
+                };
+                let info_offset = if info_len == 0 { 0 } else { 16 };
+                put_words(
+                    &mut self.response,
+                    &[
+                        MSG_QUERY | MSG_COMPLETION,
+                        24 + info_len as u32,
+                        request_id,
+                        status,
+                        info_len as u32,
+                        info_offset,
+                    ],
+                );
+                24 + info_len
+            }
+            MSG_SET => {
+                let oid = le32(msg, 12).unwrap_or(0);
+                let info = match (le32(msg, 16), le32(msg, 20)) {
+                    (Some(len), Some(offset)) => {
+                        msg.get(8..).and_then(|m| m.get(offset as usize..)?.get(..len as usize))
+                    }
+                    _ => None,
+                };
+                let status = match info.map(|info| self.set(oid, info)) {
+                    Some(true) => STATUS_SUCCESS,
+                    _ => {
+                        debug!("rndis: unsupported set OID {:08x}", oid);
+                        STATUS_NOT_SUPPORTED
+                    }
+                };
+                put_words(&mut self.response, &[MSG_SET | MSG_COMPLETION, 16, request_id, status])
+            }
+            MSG_RESET => put_words(
+                &mut self.response,
+                &[
+                    MSG_RESET | MSG_COMPLETION,
+                    16,
+                    STATUS_SUCCESS,
+                    1, // AddressingReset
+                ],
+            ),
+            MSG_KEEPALIVE => put_words(
+                &mut self.response,
+                &[MSG_KEEPALIVE | MSG_COMPLETION, 16, request_id, STATUS_SUCCESS],
+            ),
+            MSG_INDICATE_STATUS => return,
+            _ => {
+                warn!("rndis: unknown control message {:08x}", msg_type);
+                return;
+            }
+        };
+
+        self.response_len = len;
+        self.shared.response_available.store(true, Ordering::Relaxed);
+        self.shared.response_waker.borrow_mut().wake();
+    }
+
+    /// Write the value of `oid` after the query response header, and return its length.
+    fn query(&mut self, oid: u32) -> Option<usize> {
+        let info = &mut self.response[24..];
+        let len = match oid {
+            OID_GEN_SUPPORTED_LIST => put_words(info, &SUPPORTED_OIDS),
+            OID_GEN_VENDOR_DESCRIPTION => {
+                info[..VENDOR_DESCRIPTION.len()].copy_from_slice(VENDOR_DESCRIPTION);
+                VENDOR_DESCRIPTION.len()
+            }
+            OID_802_3_PERMANENT_ADDRESS | OID_802_3_CURRENT_ADDRESS => {
+                info[..6].copy_from_slice(&self.mac_addr);
+                6
+            }
+            _ => {
+                let value = match oid {
+                    OID_GEN_MAXIMUM_FRAME_SIZE => (MAX_FRAME_SIZE - 14) as u32,
+                    OID_GEN_LINK_SPEED => self.link_speed,
+                    OID_GEN_TRANSMIT_BLOCK_SIZE | OID_GEN_RECEIVE_BLOCK_SIZE => MAX_FRAME_SIZE as u32,
+                    OID_GEN_VENDOR_ID => 0x00FF_FFFF,
+                    OID_GEN_CURRENT_PACKET_FILTER => self.packet_filter,
+                    OID_GEN_MAXIMUM_TOTAL_SIZE => MAX_TRANSFER_SIZE as u32,
+                    OID_802_3_MAXIMUM_LIST_SIZE => 1,
+                    // 802.3 medium, connected, ready, and no statistics kept.
+                    OID_GEN_HARDWARE_STATUS
+                    | OID_GEN_MEDIA_SUPPORTED
+                    | OID_GEN_MEDIA_IN_USE
+                    | OID_GEN_MEDIA_CONNECT_STATUS
+                    | OID_GEN_PHYSICAL_MEDIUM
+                    | OID_GEN_XMIT_OK
+                    | OID_GEN_RCV_OK
+                    | OID_GEN_XMIT_ERROR
+                    | OID_GEN_RCV_ERROR
+                    | OID_GEN_RCV_NO_BUFFER
+                    | OID_802_3_MAC_OPTIONS
+                    | OID_802_3_RCV_ERROR_ALIGNMENT
+                    | OID_802_3_XMIT_ONE_COLLISION
+                    | OID_802_3_XMIT_MORE_COLLISIONS => 0,
+                    _ => return None,
+                };
+                put_words(info, &[value])
+            }
+        };
+        Some(len)
+    }
+
+    /// Set `oid` to `info`, and return whether it's supported.
+    fn set(&mut self, oid: u32, info: &[u8]) -> bool {
+        match oid {
+            OID_GEN_CURRENT_PACKET_FILTER => {
+                let Some(filter) = le32(info, 0) else {
+                    return false;
+                };
+                // We hand every frame to the network stack, which filters them anyway. The host
+                // starts exchanging data messages once it sets a filter.
+                trace!("rndis: packet filter {:08x}", filter);
+                self.packet_filter = filter;
+                self.shared.set_connected(filter != 0);
+                true
+            }
+            OID_802_3_MULTICAST_LIST => true,
+            _ => false,
+        }
+    }
+}
+
+impl<'d> Handler for Control<'d> {
+    fn reset(&mut self) {
+        self.packet_filter = 0;
+        self.response_len = 0;
+        self.shared.response_available.store(false, Ordering::Relaxed);
+        self.shared.set_connected(false);
+    }
+
+    fn control_out(&mut self, req: control::Request, data: &[u8]) -> Option<OutResponse> {
+        if (req.request_type, req.recipient, req.index)
+            != (RequestType::Class, Recipient::Interface, self.comm_if.0 as u16)
+        {
+            return None;
+        }
+
+        match req.request {
+            REQ_SEND_ENCAPSULATED_COMMAND => {
+                self.handle_message(data);
+                Some(OutResponse::Accepted)
+            }
+            _ => Some(OutResponse::Rejected),
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if (req.request_type, req.recipient, req.index)
+            != (RequestType::Class, Recipient::Interface, self.comm_if.0 as u16)
+        {
+            return None;
+        }
+
+        match req.request {
+            REQ_GET_ENCAPSULATED_RESPONSE => {
+                let len = core::mem::take(&mut self.response_len);
+                if len == 0 {
+                    // A single zero byte means there's no response available.
+                    buf[0] = 0;
+                    Some(InResponse::Accepted(&buf[..1]))
+                } else {
+                    Some(InResponse::Accepted(&self.response[..len]))
+                }
+            }
+            _ => Some(InResponse::Rejected),
+        }
+    }
+}
+
+/// RNDIS class
+pub struct RndisClass<'d, D: Driver<'d>> {
+    comm_ep: D::EndpointIn,
+    read_ep: D::EndpointOut,
+    write_ep: D::EndpointIn,
+
+    control: &'d ControlShared,
+
+    max_packet_size: usize,
+}
+
+impl<'d, D: Driver<'d>> RndisClass<'d, D> {
+    /// Create a new RNDIS class.
+    ///
+    /// `mac_address` is the MAC address of the host's side of the link, not the one of the
+    /// device's network interface.
+    pub fn new(
+        builder: &mut Builder<'d, D>,
+        state: &'d mut State<'d>,
+        mac_address: [u8; 6],
+        max_packet_size: u16,
+    ) -> Self {
+        let mut func = builder.function(USB_CLASS_CDC, CDC_SUBCLASS_ACM, CDC_PROTOCOL_VENDOR);
+        func.msos_feature(msos::CompatibleIdFeatureDescriptor::new("RNDIS", "5162001"));
+
+        // Control interface
+        let mut iface = func.interface();
+        let comm_if = iface.interface_number();
+        let data_if = u8::from(comm_if) + 1;
+        let mut alt = iface.alt_setting(USB_CLASS_CDC, CDC_SUBCLASS_ACM, CDC_PROTOCOL_VENDOR, None);
+
+        alt.descriptor(
+            CS_INTERFACE,
+            &[
+                CDC_TYPE_HEADER, // bDescriptorSubtype
+                0x10,
+                0x01, // bcdCDC (1.10)
+            ],
+        );
+        alt.descriptor(
+            CS_INTERFACE,
+            &[
+                CDC_TYPE_CALL_MANAGEMENT, // bDescriptorSubtype
+                0x00,                     // bmCapabilities
+                data_if,                  // bDataInterface
+            ],
+        );
+        alt.descriptor(
+            CS_INTERFACE,
+            &[
+                CDC_TYPE_ACM, // bDescriptorSubtype
+                0x00,         // bmCapabilities
+            ],
+        );
+        alt.descriptor(
+            CS_INTERFACE,
+            &[
+                CDC_TYPE_UNION, // bDescriptorSubtype
+                comm_if.into(), // bControlInterface
+                data_if,        // bSubordinateInterface
+            ],
+        );
+
+        // Hosts poll the notifications between every control message, keep them fast.
+        let comm_ep = alt.endpoint_interrupt_in(None, 8, 1);
+
+        // Data interface
+        let mut iface = func.interface();
+        let mut alt = iface.alt_setting(USB_CLASS_CDC_DATA, 0x00, CDC_PROTOCOL_NONE, None);
+        let read_ep = alt.endpoint_bulk_out(None, max_packet_size);
+        let write_ep = alt.endpoint_bulk_in(None, max_packet_size);
+
+        drop(func);
+
+        let link_speed = if max_packet_size >= 512 { 4_800_000 } else { 120_000 };
+        let control = state.control.write(Control {
+            shared: &state.shared,
+            mac_addr: mac_address,
+            link_speed,
+            comm_if,
+            packet_filter: 0,
+            response: [0; RESPONSE_BUFFER_SIZE],
+            response_len: 0,
+        });
+        builder.handler(control);
+
+        RndisClass {
+            comm_ep,
+            read_ep,
+            write_ep,
+            control: &state.shared,
+            max_packet_size: max_packet_size as usize,
+        }
+    }
+
+    /// Split the class into a sender, a receiver and a notifier.
+    ///
+    /// This allows concurrently sending and receiving packets from separate tasks. The notifier
+    /// must run concurrently with them for the host to get the responses to its control messages.
+    pub fn split(self) -> (Sender<'d, D>, Receiver<'d, D>, Notifier<'d, D>) {
+        (
+            Sender {
+                write_ep: self.write_ep,
+                max_packet_size: self.max_packet_size,
+            },
+            Receiver {
+                read_ep: self.read_ep,
+                control: self.control,
+            },
+            Notifier {
+                comm_ep: self.comm_ep,
+                control: self.control,
+            },
+        )
+    }
+}
+
+/// RNDIS class packet sender.
+///
+/// You can obtain a `Sender` with [`RndisClass::split`]
+pub struct Sender<'d, D: Driver<'d>> {
+    write_ep: D::EndpointIn,
+    max_packet_size: usize,
+}
+
+impl<'d, D: Driver<'d>> Sender<'d, D> {
+    /// Write a packet.
+    ///
+    /// This waits until the packet is successfully stored in the RNDIS endpoint buffers.
+    pub async fn write_packet(&mut self, data: &[u8]) -> Result<(), EndpointError> {
+        const ABS_MAX_PACKET_SIZE: usize = 512;
+
+        let total_len = PACKET_HEADER_LEN + data.len();
+
+        // Build first packet on a buffer, send next packets straight from `data`.
+        let mut buf = [0; ABS_MAX_PACKET_SIZE];
+        put_words(
+            &mut buf,
+            &[
+                MSG_PACKET,
+                total_len as u32,
+                (PACKET_HEADER_LEN - 8) as u32, // DataOffset
+                data.len() as u32,              // DataLength
+                0,                              // OOBDataOffset
+                0,                              // OOBDataLength
+                0,                              // NumOOBDataElements
+                0,                              // PerPacketInfoOffset
+                0,                              // PerPacketInfoLength
+                0,                              // VcHandle
+                0,                              // Reserved
+            ],
+        );
+
+        if total_len < self.max_packet_size {
+            // First packet is not full, just send it.
+            buf[PACKET_HEADER_LEN..total_len].copy_from_slice(data);
+            self.write_ep.write(&buf[..total_len]).await?;
+        } else {
+            let (d1, d2) = data.split_at(self.max_packet_size - PACKET_HEADER_LEN);
+
+            buf[PACKET_HEADER_LEN..self.max_packet_size].copy_from_slice(d1);
+            self.write_ep.write(&buf[..self.max_packet_size]).await?;
+            self.write_ep.write_transfer(d2, false).await?;
+
+            // Like the hosts, end a message filling the last packet with a single byte instead of
+            // a ZLP, which some RNDIS drivers don't expect.
+            if total_len.is_multiple_of(self.max_packet_size) {
+                self.write_ep.write(&[0]).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// RNDIS class packet receiver.
+///
+/// You can obtain a `Receiver` with [`RndisClass::split`]
+pub struct Receiver<'d, D: Driver<'d>> {
+    read_ep: D::EndpointOut,
+    control: &'d ControlShared,
+}
+
+impl<'d, D: Driver<'d>> Receiver<'d, D> {
+    /// Read a network packet.
+    ///
+    /// This waits until a packet is successfully received from the endpoint buffers.
+    pub async fn read_packet(&mut self, buf: &mut [u8]) -> Result<usize, EndpointError> {
+        // Retry loop
+        loop {
+            let mut msg = [0u8; RX_BUFFER_SIZE];
+            let pos = self.read_ep.read_transfer(&mut msg).await?;
+            let msg = &msg[..pos];
+
+            let (Some(msg_type), Some(data_offset), Some(data_len)) = (le32(msg, 0), le32(msg, 8), le32(msg, 12))
+            else {
+                continue;
+            };
+            if msg_type != MSG_PACKET {
+                warn!("rndis: received unexpected data message {:08x}", msg_type);
+                continue;
+            }
+
+            let Some(data) = msg[8..]
+                .get(data_offset as usize..)
+                .and_then(|data| data.get(..data_len as usize))
+            else {
+                warn!("rndis: data message has a data offset out of range");
+                continue;
+            };
+            if data.len() > buf.len() {
+                warn!("rndis: received packet too large");
+                continue;
+            }
+            buf[..data.len()].copy_from_slice(data);
+
+            return Ok(data.len());
+        }
+    }
+
+    /// Waits for the USB host to initialize the device and start exchanging packets.
+    pub async fn wait_connection(&mut self) -> Result<(), EndpointError> {
+        self.read_ep.wait_enabled().await;
+        poll_fn(|cx| {
+            if self.control.connected.load(Ordering::Relaxed) {
+                Poll::Ready(())
+            } else {
+                self.control.connected_waker.borrow_mut().register(cx.waker());
+                Poll::Pending
+            }
+        })
+        .await;
+
+        Ok(())
+    }
+}
+
+/// RNDIS class notifier, telling the host when responses to its control messages are available.
+///
+/// You can obtain a `Notifier` with [`RndisClass::split`]
+pub struct Notifier<'d, D: Driver<'d>> {
+    comm_ep: D::EndpointIn,
+    control: &'d ControlShared,
+}
+
+impl<'d, D: Driver<'d>> Notifier<'d, D> {
+    /// Run the notifier.
+    ///
+    /// You must run this concurrently with the sender and receiver for the class to operate.
+    pub async fn run(&mut self) -> ! {
+        loop {
+            self.comm_ep.wait_enabled().await;
+            poll_fn(|cx| {
+                if self.control.response_available.load(Ordering::Relaxed) {
+                    self.control.response_available.store(false, Ordering::Relaxed);
+                    Poll::Ready(())
+                } else {
+                    self.control.response_waker.borrow_mut().register(cx.waker());
+                    Poll::Pending
+                }
+            })
+            .await;
+
+            let buf = [
+                NOTIF_RESPONSE_AVAILABLE,
+                0x00,
+                0x00,
+                0x00,
+                0x00, // Reserved
+                0x00,
+                0x00,
+                0x00,
+            ];
+            match self.comm_ep.write(&buf).await {
+                Ok(()) => {}
+                Err(EndpointError::Disabled) => {} // Got disabled again, wait again.
+                Err(e) => warn!("rndis: failed to send notification: {:?}", e),
+            }
+        }
+    }
+}
@@ -0,0 +1,153 @@
+//! Audio Device Class Codes as defined in Universal Serial Bus Device Class
+//! Definition for Audio Devices, Release 2.0, Appendix A and Universal Serial
+//! Bus Device Class Definition for Audio Data Formats, Release 2.0, Appendix
+//! A.1 and A.2 (Format Type Codes and Audio Data Format Type I Bit Allocations)
+#![allow(dead_code)]
+
+/// The current version of the ADC specification (2.0)
+pub const ADC_VERSION: u16 = 0x0200;
+
+/// Audio Interface Class Code
+pub const USB_AUDIO_CLASS: u8 = 0x01;
+
+// Audio Function Subclass Code
+pub const FUNCTION_SUBCLASS_UNDEFINED: u8 = 0x00;
+
+// Audio Function Protocol Code
+pub const AF_VERSION_02_00: u8 = 0x20;
+
+// Audio Interface Subclass Codes
+pub const USB_UNDEFINED_SUBCLASS: u8 = 0x00;
+pub const USB_AUDIOCONTROL_SUBCLASS: u8 = 0x01;
+pub const USB_AUDIOSTREAMING_SUBCLASS: u8 = 0x02;
+pub const USB_MIDISTREAMING_SUBCLASS: u8 = 0x03;
+
+// Audio Interface Protocol Code
+pub const IP_VERSION_02_00: u8 = 0x20;
+
+// Audio Function Category Codes
+pub const FUNCTION_CATEGORY_UNDEFINED: u8 = 0x00;
+pub const DESKTOP_SPEAKER: u8 = 0x01;
+pub const HOME_THEATER: u8 = 0x02;
+pub const MICROPHONE: u8 = 0x03;
+pub const HEADSET: u8 = 0x04;
+pub const TELEPHONE: u8 = 0x05;
+pub const CONVERTER: u8 = 0x06;
+pub const VOICE_SOUND_RECORDER: u8 = 0x07;
+pub const IO_BOX: u8 = 0x08;
+pub const MUSICAL_INSTRUMENT: u8 = 0x09;
+pub const PRO_AUDIO: u8 = 0x0A;
+pub const AUDIO_VIDEO: u8 = 0x0B;
+pub const CONTROL_PANEL: u8 = 0x0C;
+pub const OTHER: u8 = 0xFF;
+
+// Audio Class-Specific Descriptor Types
+pub const CS_UNDEFINED: u8 = 0x20;
+pub const CS_DEVICE: u8 = 0x21;
+pub const CS_CONFIGURATION: u8 = 0x22;
+pub const CS_STRING: u8 = 0x23;
+pub const CS_INTERFACE: u8 = 0x24;
+pub const CS_ENDPOINT: u8 = 0x25;
+
+// Audio Class-Specific AC Interface Descriptor Subtypes
+pub const AC_DESCRIPTOR_UNDEFINED: u8 = 0x00;
+pub const HEADER_SUBTYPE: u8 = 0x01;
+pub const INPUT_TERMINAL: u8 = 0x02;
+pub const OUTPUT_TERMINAL: u8 = 0x03;
+pub const MIXER_UNIT: u8 = 0x04;
+pub const SELECTOR_UNIT: u8 = 0x05;
+pub const FEATURE_UNIT: u8 = 0x06;
+pub const EFFECT_UNIT: u8 = 0x07;
+pub const PROCESSING_UNIT: u8 = 0x08;
+pub const EXTENSION_UNIT: u8 = 0x09;
+pub const CLOCK_SOURCE: u8 = 0x0A;
+pub const CLOCK_SELECTOR: u8 = 0x0B;
+pub const CLOCK_MULTIPLIER: u8 = 0x0C;
+pub const SAMPLE_RATE_CONVERTER: u8 = 0x0D;
+
+// Audio Class-Specific AS Interface Descriptor Subtypes
+pub const AS_DESCRIPTOR_UNDEFINED: u8 = 0x00;
+pub const AS_GENERAL: u8 = 0x01;
+pub const FORMAT_TYPE: u8 = 0x02;
+pub const ENCODER: u8 = 0x03;
+pub const DECODER: u8 = 0x04;
+
+// Audio Class-Specific Endpoint Descriptor Subtypes
+pub const DESCRIPTOR_UNDEFINED: u8 = 0x00;
+pub const EP_GENERAL: u8 = 0x01;
+
+// Audio Class-Specific Request Codes
+pub const REQUEST_CODE_UNDEFINED: u8 = 0x00;
+pub const CUR: u8 = 0x01;
+pub const RANGE: u8 = 0x02;
+pub const MEM: u8 = 0x03;
+
+// Clock Source Control Selectors
+pub const CS_CONTROL_UNDEFINED: u8 = 0x00;
+pub const CS_SAM_FREQ_CONTROL: u8 = 0x01;
+pub const CS_CLOCK_VALID_CONTROL: u8 = 0x02;
+
+// Clock Selector Control Selectors
+pub const CX_CONTROL_UNDEFINED: u8 = 0x00;
+pub const CX_CLOCK_SELECTOR_CONTROL: u8 = 0x01;
+
+// Terminal Control Selectors
+pub const TE_CONTROL_UNDEFINED: u8 = 0x00;
+pub const TE_COPY_PROTECT_CONTROL: u8 = 0x01;
+pub const TE_CONNECTOR_CONTROL: u8 = 0x02;
+pub const TE_OVERLOAD_CONTROL: u8 = 0x03;
+pub const TE_CLUSTER_CONTROL: u8 = 0x04;
+pub const TE_UNDERFLOW_CONTROL: u8 = 0x05;
+pub const TE_OVERFLOW_CONTROL: u8 = 0x06;
+pub const TE_LATENCY_CONTROL: u8 = 0x07;
+
+// Feature Unit Control Selectors
+pub const FU_CONTROL_UNDEFINED: u8 = 0x00;
+pub const FU_MUTE_CONTROL: u8 = 0x01;
+pub const FU_VOLUME_CONTROL: u8 = 0x02;
+pub const FU_BASS_CONTROL: u8 = 0x03;
+pub const FU_MID_CONTROL: u8 = 0x04;
+pub const FU_TREBLE_CONTROL: u8 = 0x05;
+pub const FU_GRAPHIC_EQUALIZER_CONTROL: u8 = 0x06;
+pub const FU_AUTOMATIC_GAIN_CONTROL: u8 = 0x07;
+pub const FU_DELAY_CONTROL: u8 = 0x08;
+pub const FU_BASS_BOOST_CONTROL: u8 = 0x09;
+pub const FU_LOUDNESS_CONTROL: u8 = 0x0A;
+pub const FU_INPUT_GAIN_CONTROL: u8 = 0x0B;
+pub const FU_INPUT_GAIN_PAD_CONTROL: u8 = 0x0C;
+pub const FU_PHASE_INVERTER_CONTROL: u8 = 0x0D;
+pub const FU_UNDERFLOW_CONTROL: u8 = 0x0E;
+pub const FU_OVERFLOW_CONTROL: u8 = 0x0F;
+pub const FU_LATENCY_CONTROL: u8 = 0x10;
+
+// AudioStreaming Interface Control Selectors
+pub const AS_CONTROL_UNDEFINED: u8 = 0x00;
+pub const AS_ACT_ALT_SETTING_CONTROL: u8 = 0x01;
+pub const AS_VAL_ALT_SETTINGS_CONTROL: u8 = 0x02;
+pub const AS_AUDIO_DATA_FORMAT_CONTROL: u8 = 0x03;
+
+// Endpoint Control Selectors
+pub const EP_CONTROL_UNDEFINED: u8 = 0x00;
+pub const EP_PITCH_CONTROL: u8 = 0x01;
+pub const EP_DATA_OVERRUN_CONTROL: u8 = 0x02;
+pub const EP_DATA_UNDERRUN_CONTROL: u8 = 0x03;
+
+// Format Type Codes
+pub const FORMAT_TYPE_UNDEFINED: u8 = 0x00;
+pub const FORMAT_TYPE_I: u8 = 0x01;
+pub const FORMAT_TYPE_II: u8 = 0x02;
+pub const FORMAT_TYPE_III: u8 = 0x03;
+pub const FORMAT_TYPE_IV: u8 = 0x04;
+
+// Audio Data Format Type I Bit Allocations
+pub const PCM: u32 = 1 << 0;
+pub const PCM8: u32 = 1 << 1;
+pub const IEEE_FLOAT: u32 = 1 << 2;
+pub const ALAW: u32 = 1 << 3;
+pub const MULAW: u32 = 1 << 4;
+pub const TYPE_I_RAW_DATA: u32 = 1 << 31;
+
+// Control bitmap values, two bits per control
+pub const CONTROL_NONE: u8 = 0b00;
+pub const CONTROL_READ_ONLY: u8 = 0b01;
+pub const CONTROL_HOST_PROGRAMMABLE: u8 = 0b11;
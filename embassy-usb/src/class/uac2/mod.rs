@@ -0,0 +1,39 @@
+//! USB Audio Class 2.0 implementations for different applications.
+//!
+//! Unlike USB Audio Class 1.0, the 2.0 class describes the clocks of the audio function with clock
+//! source and clock selector entities, and supports high-speed USB with microframe intervals. This
+//! allows for high sample rates (up to 192 kHz and beyond), 24 or 32 bit formats, and many channels.
+//!
+//! Contains:
+//! - The `speaker` class with a single audio streaming interface (host to device)
+
+pub mod speaker;
+
+mod class_codes;
+
+pub use super::uac1::terminal_type::TerminalType;
+pub use super::uac1::{Channel, FeedbackRefresh, SampleWidth};
+
+/// The maximum number of supported audio channels.
+///
+/// Includes all twelve channels from `Channel`, plus the Master channel.
+const MAX_AUDIO_CHANNEL_COUNT: usize = 13;
+
+/// The maximum number of clock sources.
+pub const MAX_CLOCK_SOURCES: usize = 4;
+
+/// Get the spatial location bits of `channels`, as in the `bmChannelConfig` field of a channel cluster.
+///
+/// Panics if a channel appears twice.
+fn channel_config(channels: &[Channel]) -> u32 {
+    let mut config = 0;
+    for &channel in channels {
+        // The UAC2 spatial locations start with the same twelve as in UAC1.
+        let bit = 1 << channel as u32;
+        if config & bit != 0 {
+            panic!("Invalid channel config, duplicate channel {}.", bit);
+        }
+        config |= bit;
+    }
+    config
+}
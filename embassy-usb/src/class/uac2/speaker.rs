@@ -0,0 +1,815 @@
+//! USB Audio Class 2.0 - Speaker device
+//!
+//! Provides a class with a single audio streaming interface (host to device),
+//! that advertises itself as a speaker. Includes explicit sample rate feedback.
+//!
+//! Various aspects of the audio stream can be configured, for example:
+//! - clock sources and their sample rates, selected by the host with a clock selector
+//! - sample resolutions, one alternate setting of the streaming interface each
+//! - audio channel count and assignment
+//!
+//! The class provides volume and mute controls for each channel.
+
+use core::cell::{Cell, RefCell};
+use core::future::{Future, poll_fn};
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU32, Ordering};
+use core::task::Poll;
+
+use embassy_sync::blocking_mutex::CriticalSectionMutex;
+use embassy_sync::waitqueue::WakerRegistration;
+use heapless::Vec;
+
+use super::class_codes::*;
+use super::{
+    Channel, FeedbackRefresh, MAX_AUDIO_CHANNEL_COUNT, MAX_CLOCK_SOURCES, SampleWidth, TerminalType, channel_config,
+};
+use crate::control::{self, InResponse, OutResponse, Recipient, Request, RequestType};
+use crate::descriptor::{SynchronizationType, UsageType};
+use crate::driver::{Direction, Driver, Endpoint, EndpointError, EndpointIn, EndpointOut, EndpointType};
+use crate::types::InterfaceNumber;
+use crate::{Builder, Handler};
+
+/// Arbitrary unique identifier for the input unit.
+const INPUT_UNIT_ID: u8 = 0x01;
+
+/// Arbitrary unique identifier for the feature unit.
+const FEATURE_UNIT_ID: u8 = 0x02;
+
+/// Arbitrary unique identifier for the output unit.
+const OUTPUT_UNIT_ID: u8 = 0x03;
+
+/// Arbitrary unique identifier for the clock selector.
+const CLOCK_SELECTOR_ID: u8 = 0x08;
+
+/// Arbitrary unique identifier for the first clock source, the others follow.
+const CLOCK_SOURCE_ID: u8 = 0x10;
+
+// Volume settings go from -25600 to 0, in steps of 256.
+// Therefore, the volume settings are 8q8 values in units of dB.
+const VOLUME_STEPS_PER_DB: i16 = 256;
+const MIN_VOLUME_DB: i16 = -100;
+const MAX_VOLUME_DB: i16 = 0;
+
+pub use crate::class::uac1::speaker::Volume;
+
+/// Configuration of a [`Speaker`].
+pub struct Config<'d> {
+    /// The maximum packet size per (micro)frame of the streaming endpoint, for the largest sample
+    /// rate and resolution.
+    pub max_packet_size: u16,
+    /// The supported audio sample resolutions, in the order of the alternate settings of the streaming
+    /// interface.
+    pub resolutions: &'d [SampleWidth],
+    /// The advertised audio channels (up to 12). Entries must be unique.
+    pub channels: &'d [Channel],
+    /// The supported sample rates in Hz of each clock source (up to [`MAX_CLOCK_SOURCES`]), for
+    /// example one source for multiples of 44.1 kHz and one for multiples of 48 kHz. With more than
+    /// one source, the host selects the source through a clock selector.
+    pub clock_sources: &'d [&'d [u32]],
+    /// The refresh period for the feedback value.
+    pub feedback_refresh_period: FeedbackRefresh,
+}
+
+/// Internal state for the USB Audio Class.
+pub struct State<'d> {
+    control: Option<Control<'d>>,
+    shared: SharedControl<'d>,
+}
+
+impl<'d> Default for State<'d> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'d> State<'d> {
+    /// Create a new `State`.
+    pub fn new() -> Self {
+        Self {
+            control: None,
+            shared: SharedControl::default(),
+        }
+    }
+}
+
+/// Implementation of the USB audio class 2.0.
+pub struct Speaker<'d, D: Driver<'d>> {
+    /// Stream
+    pub stream: Stream<'d, D>,
+    /// Feedback
+    pub feedback: Feedback<'d, D>,
+    /// Control Monitor
+    pub control_monitor: ControlMonitor<'d>,
+}
+
+impl<'d, D: Driver<'d>> Speaker<'d, D> {
+    /// Creates a new [`Speaker`] device, split into a stream, feedback, and a control change notifier.
+    ///
+    /// The packet size should be chosen, based on the expected transfer size of samples per (micro)frame.
+    /// For example, a stereo stream at 32 bit resolution and 192 kHz sample rate yields packets of 192 byte
+    /// for high-speed USB (125 us microframe interval). As the packet size varies with feedback, the
+    /// `max_packet_size` should have room for at least one more sample per channel.
+    ///
+    /// The control buffer of the device must hold the sample rate ranges of each clock source, which
+    /// take 2 bytes plus 12 bytes per sample rate.
+    ///
+    /// Panics if the configuration has no or too many resolutions, channels or clock sources.
+    pub fn new(builder: &mut Builder<'d, D>, state: &'d mut State<'d>, config: Config<'d>) -> Self {
+        let num_clocks = config.clock_sources.len();
+        assert!((1..=MAX_CLOCK_SOURCES).contains(&num_clocks));
+        assert!(config.clock_sources.iter().all(|rates| !rates.is_empty()));
+        assert!(!config.resolutions.is_empty() && config.resolutions.len() < u8::MAX as usize);
+        assert!(!config.channels.is_empty() && config.channels.len() < MAX_AUDIO_CHANNEL_COUNT);
+
+        let mut func = builder.function(USB_AUDIO_CLASS, FUNCTION_SUBCLASS_UNDEFINED, AF_VERSION_02_00);
+
+        // Audio control interface (mandatory) [UAC2 4.7]
+        let mut interface = func.interface();
+        let control_interface = interface.interface_number();
+        let streaming_interface = InterfaceNumber(u8::from(control_interface) + 1);
+        let mut alt = interface.alt_setting(USB_AUDIO_CLASS, USB_AUDIOCONTROL_SUBCLASS, IP_VERSION_02_00, None);
+
+        // Entity topology:
+        // Clock source(s) -> (Clock selector) -> clock of the terminals
+        // Input terminal (receives audio stream) -> Feature Unit (mute and volume) -> Output terminal (e.g. towards speaker)
+
+        // =====================================
+        // Clock Source Descriptors [UAC2 4.7.2.1]
+        let mut clock_source_descriptors: Vec<[u8; 6], MAX_CLOCK_SOURCES> = Vec::new();
+        for (i, rates) in config.clock_sources.iter().enumerate() {
+            let (attributes, frequency_control) = if rates.len() > 1 {
+                (0x03, CONTROL_HOST_PROGRAMMABLE) // Internal programmable clock
+            } else {
+                (0x01, CONTROL_READ_ONLY) // Internal fixed clock
+            };
+            clock_source_descriptors
+                .push([
+                    CLOCK_SOURCE,                               // bDescriptorSubtype
+                    CLOCK_SOURCE_ID + i as u8,                  // bClockID
+                    attributes,                                 // bmAttributes
+                    frequency_control | CONTROL_READ_ONLY << 2, // bmControls (frequency, validity)
+                    0x00,                                       // bAssocTerminal (none)
+                    0x00,                                       // iClockSource (none)
+                ])
+                .unwrap();
+        }
+
+        // ======================================
+        // Clock Selector Descriptor [UAC2 4.7.2.2]
+        // Only with several clock sources.
+        let clock_id = if num_clocks > 1 {
+            CLOCK_SELECTOR_ID
+        } else {
+            CLOCK_SOURCE_ID
+        };
+        let mut clock_selector_descriptor: Vec<u8, { 5 + MAX_CLOCK_SOURCES }> =
+            Vec::from_slice(&[CLOCK_SELECTOR, CLOCK_SELECTOR_ID, num_clocks as u8]).unwrap();
+        for i in 0..num_clocks {
+            clock_selector_descriptor.push(CLOCK_SOURCE_ID + i as u8).unwrap(); // baCSourceID
+        }
+        clock_selector_descriptor.push(CONTROL_HOST_PROGRAMMABLE).unwrap(); // bmControls
+        clock_selector_descriptor.push(0x00).unwrap(); // iClockSelector (none)
+
+        // =======================================
+        // Input Terminal Descriptor [UAC2 4.7.2.4]
+        // Audio input
+        let terminal_type: u16 = TerminalType::UsbStreaming.into();
+        let channel_config = channel_config(config.channels);
+
+        let input_terminal_descriptor = [
+            INPUT_TERMINAL, // bDescriptorSubtype
+            INPUT_UNIT_ID,  // bTerminalID
+            terminal_type as u8,
+            (terminal_type >> 8) as u8,  // wTerminalType
+            0x00,                        // bAssocTerminal (none)
+            clock_id,                    // bCSourceID
+            config.channels.len() as u8, // bNrChannels
+            channel_config as u8,
+            (channel_config >> 8) as u8,
+            (channel_config >> 16) as u8,
+            (channel_config >> 24) as u8, // bmChannelConfig
+            0x00,                         // iChannelNames (none)
+            0x00,
+            0x00, // bmControls (none)
+            0x00, // iTerminal (none)
+        ];
+
+        // ========================================
+        // Output Terminal Descriptor [UAC2 4.7.2.5]
+        // Speaker output
+        let terminal_type: u16 = TerminalType::OutSpeaker.into();
+        let output_terminal_descriptor = [
+            OUTPUT_TERMINAL, // bDescriptorSubtype
+            OUTPUT_UNIT_ID,  // bTerminalID
+            terminal_type as u8,
+            (terminal_type >> 8) as u8, // wTerminalType
+            0x00,                       // bAssocTerminal (none)
+            FEATURE_UNIT_ID,            // bSourceID (the feature unit)
+            clock_id,                   // bCSourceID
+            0x00,
+            0x00, // bmControls (none)
+            0x00, // iTerminal (none)
+        ];
+
+        // =====================================
+        // Feature Unit Descriptor [UAC2 4.7.2.8]
+        // Mute and volume control
+        let controls: u32 = (CONTROL_HOST_PROGRAMMABLE | CONTROL_HOST_PROGRAMMABLE << 2) as u32;
+
+        const FEATURE_UNIT_DESCRIPTOR_SIZE: usize = 3;
+        let mut feature_unit_descriptor: Vec<u8, { FEATURE_UNIT_DESCRIPTOR_SIZE + 4 * MAX_AUDIO_CHANNEL_COUNT + 1 }> =
+            Vec::from_slice(&[
+                FEATURE_UNIT,    // bDescriptorSubtype (Feature Unit)
+                FEATURE_UNIT_ID, // bUnitID
+                INPUT_UNIT_ID,   // bSourceID
+            ])
+            .unwrap();
+
+        // Master controls (disabled, use only per-channel control), then per-channel controls
+        feature_unit_descriptor.extend_from_slice(&[0x00; 4]).unwrap();
+        for _channel in config.channels {
+            feature_unit_descriptor
+                .extend_from_slice(&controls.to_le_bytes())
+                .unwrap();
+        }
+        feature_unit_descriptor.push(0x00).unwrap(); // iFeature (none)
+
+        // ==================================================
+        // Class-specific AC Interface Descriptor [UAC2 4.7.2]
+        const DESCRIPTOR_HEADER_SIZE: usize = 2;
+        const INTERFACE_DESCRIPTOR_SIZE: usize = 7;
+
+        let mut total_descriptor_length = INTERFACE_DESCRIPTOR_SIZE + DESCRIPTOR_HEADER_SIZE;
+        for descriptor in &clock_source_descriptors {
+            total_descriptor_length += descriptor.len() + DESCRIPTOR_HEADER_SIZE;
+        }
+        if num_clocks > 1 {
+            total_descriptor_length += clock_selector_descriptor.len() + DESCRIPTOR_HEADER_SIZE;
+        }
+        for size in [
+            input_terminal_descriptor.len(),
+            feature_unit_descriptor.len(),
+            output_terminal_descriptor.len(),
+        ] {
+            total_descriptor_length += size + DESCRIPTOR_HEADER_SIZE;
+        }
+
+        let interface_descriptor: [u8; INTERFACE_DESCRIPTOR_SIZE] = [
+            HEADER_SUBTYPE, // bDescriptorSubtype (Header)
+            ADC_VERSION as u8,
+            (ADC_VERSION >> 8) as u8, // bcdADC
+            DESKTOP_SPEAKER,          // bCategory
+            total_descriptor_length as u8,
+            (total_descriptor_length >> 8) as u8, // wTotalLength
+            0x00,                                 // bmControls (no latency control)
+        ];
+
+        alt.descriptor(CS_INTERFACE, &interface_descriptor);
+        for descriptor in &clock_source_descriptors {
+            alt.descriptor(CS_INTERFACE, descriptor);
+        }
+        if num_clocks > 1 {
+            alt.descriptor(CS_INTERFACE, &clock_selector_descriptor);
+        }
+        alt.descriptor(CS_INTERFACE, &input_terminal_descriptor);
+        alt.descriptor(CS_INTERFACE, &feature_unit_descriptor);
+        alt.descriptor(CS_INTERFACE, &output_terminal_descriptor);
+
+        // =====================================================
+        // Audio streaming interface, zero-bandwidth [UAC2 4.9.1]
+        let mut interface = func.interface();
+        assert_eq!(interface.interface_number(), streaming_interface);
+        let _alt = interface.alt_setting(USB_AUDIO_CLASS, USB_AUDIOSTREAMING_SUBCLASS, IP_VERSION_02_00, None);
+
+        // ===================================================================
+        // Audio streaming interface, operational, one per resolution [UAC2 4.9.1]
+        // All of them share the same endpoints.
+        let mut endpoints = None;
+        for &resolution in config.resolutions {
+            let mut alt = interface.alt_setting(USB_AUDIO_CLASS, USB_AUDIOSTREAMING_SUBCLASS, IP_VERSION_02_00, None);
+
+            // Class-specific AS Interface Descriptor [UAC2 4.9.2]
+            alt.descriptor(
+                CS_INTERFACE,
+                &[
+                    AS_GENERAL,    // bDescriptorSubtype
+                    INPUT_UNIT_ID, // bTerminalLink
+                    0x00,          // bmControls (none)
+                    FORMAT_TYPE_I, // bFormatType
+                    PCM as u8,
+                    (PCM >> 8) as u8,
+                    (PCM >> 16) as u8,
+                    (PCM >> 24) as u8,           // bmFormats (PCM format)
+                    config.channels.len() as u8, // bNrChannels
+                    channel_config as u8,
+                    (channel_config >> 8) as u8,
+                    (channel_config >> 16) as u8,
+                    (channel_config >> 24) as u8, // bmChannelConfig
+                    0x00,                         // iChannelNames (none)
+                ],
+            );
+
+            // Type I Format Type Descriptor [UAC2 Formats 2.3.1.6]
+            alt.descriptor(
+                CS_INTERFACE,
+                &[
+                    FORMAT_TYPE,               // bDescriptorSubtype
+                    FORMAT_TYPE_I,             // bFormatType
+                    resolution as u8,          // bSubslotSize
+                    resolution.in_bit() as u8, // bBitResolution
+                ],
+            );
+
+            let (streaming_endpoint, feedback_endpoint) = endpoints.get_or_insert_with(|| {
+                let streaming_endpoint =
+                    alt.alloc_endpoint_out(EndpointType::Isochronous, None, config.max_packet_size, 1);
+                let feedback_endpoint = alt.alloc_endpoint_in(
+                    EndpointType::Isochronous,
+                    None,
+                    4, // Feedback packets are 24 bit (10.14 format) on full-speed, 32 bit (16.16 format) on high-speed.
+                    config.feedback_refresh_period as u8 + 1,
+                );
+                (streaming_endpoint, feedback_endpoint)
+            });
+
+            alt.endpoint_descriptor(
+                streaming_endpoint.info(),
+                SynchronizationType::Asynchronous,
+                UsageType::DataEndpoint,
+                &[],
+            );
+
+            // Class-specific AS Isochronous Audio Data Endpoint Descriptor [UAC2 4.10.1.2]
+            alt.descriptor(
+                CS_ENDPOINT,
+                &[
+                    EP_GENERAL, // bDescriptorSubtype (General)
+                    0x00,       // bmAttributes
+                    0x00,       // bmControls (none)
+                    0x00,       // bLockDelayUnits (undefined)
+                    0x00, 0x00, // wLockDelay (0)
+                ],
+            );
+
+            // Write the feedback endpoint descriptor after the streaming endpoint descriptor.
+            alt.endpoint_descriptor(
+                feedback_endpoint.info(),
+                SynchronizationType::NoSynchronization,
+                UsageType::FeedbackEndpoint,
+                &[],
+            );
+        }
+
+        let (streaming_endpoint, feedback_endpoint) = endpoints.unwrap();
+
+        // Free up the builder.
+        drop(func);
+
+        // Store channel and clock information
+        let shared = &mut state.shared;
+        shared.channels = config.channels;
+        shared.resolutions = config.resolutions;
+        shared.clock_sources = config.clock_sources;
+        for (rate, rates) in shared.sample_rate_hz.iter_mut().zip(config.clock_sources) {
+            *rate.get_mut() = rates[0];
+        }
+
+        state.control = Some(Control {
+            shared: &state.shared,
+            control_interface_number: control_interface,
+            streaming_interface_number: streaming_interface,
+        });
+
+        builder.handler(state.control.as_mut().unwrap());
+
+        let control = &state.shared;
+
+        Self {
+            stream: Stream { streaming_endpoint },
+            feedback: Feedback { feedback_endpoint },
+            control_monitor: ControlMonitor { shared: control },
+        }
+    }
+}
+
+/// Audio settings for the feature unit.
+///
+/// Contains volume and mute control.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+struct AudioSettings {
+    /// Channel mute states.
+    muted: [bool; MAX_AUDIO_CHANNEL_COUNT],
+    /// Channel volume levels in 8.8 format (in dB).
+    volume_8q8_db: [i16; MAX_AUDIO_CHANNEL_COUNT],
+}
+
+impl Default for AudioSettings {
+    fn default() -> Self {
+        AudioSettings {
+            muted: [false; MAX_AUDIO_CHANNEL_COUNT],
+            volume_8q8_db: [MAX_VOLUME_DB * VOLUME_STEPS_PER_DB; MAX_AUDIO_CHANNEL_COUNT],
+        }
+    }
+}
+
+struct Control<'d> {
+    control_interface_number: InterfaceNumber,
+    streaming_interface_number: InterfaceNumber,
+    shared: &'d SharedControl<'d>,
+}
+
+/// Shared data between [`Control`] and the [`Speaker`] class.
+struct SharedControl<'d> {
+    /// The collection of audio settings (volumes, mute states).
+    audio_settings: CriticalSectionMutex<Cell<AudioSettings>>,
+
+    /// Channel assignments.
+    channels: &'d [Channel],
+
+    /// Resolutions of the operational alternate settings.
+    resolutions: &'d [SampleWidth],
+
+    /// Sample rates of each clock source.
+    clock_sources: &'d [&'d [u32]],
+
+    /// The current sample rate in Hz of each clock source.
+    sample_rate_hz: [AtomicU32; MAX_CLOCK_SOURCES],
+
+    /// The index of the clock source selected by the host.
+    clock_source: AtomicU8,
+
+    /// The alternate setting of the streaming interface.
+    alternate_setting: AtomicU8,
+
+    // Notification mechanism.
+    waker: RefCell<WakerRegistration>,
+    changed: AtomicBool,
+}
+
+impl<'d> Default for SharedControl<'d> {
+    fn default() -> Self {
+        SharedControl {
+            audio_settings: CriticalSectionMutex::new(Cell::new(AudioSettings::default())),
+            channels: &[],
+            resolutions: &[],
+            clock_sources: &[],
+            sample_rate_hz: [const { AtomicU32::new(0) }; MAX_CLOCK_SOURCES],
+            clock_source: AtomicU8::new(0),
+            alternate_setting: AtomicU8::new(0),
+            waker: RefCell::new(WakerRegistration::new()),
+            changed: AtomicBool::new(false),
+        }
+    }
+}
+
+impl<'d> SharedControl<'d> {
+    fn changed(&self) -> impl Future<Output = ()> + '_ {
+        poll_fn(|context| {
+            if self.changed.load(Ordering::Relaxed) {
+                self.changed.store(false, Ordering::Relaxed);
+                Poll::Ready(())
+            } else {
+                self.waker.borrow_mut().register(context.waker());
+                Poll::Pending
+            }
+        })
+    }
+}
+
+/// Used for reading audio frames.
+pub struct Stream<'d, D: Driver<'d>> {
+    streaming_endpoint: D::EndpointOut,
+}
+
+impl<'d, D: Driver<'d>> Stream<'d, D> {
+    /// Reads a single packet from the OUT endpoint
+    pub async fn read_packet(&mut self, data: &mut [u8]) -> Result<usize, EndpointError> {
+        self.streaming_endpoint.read(data).await
+    }
+
+    /// Waits for the USB host to enable this interface
+    pub async fn wait_connection(&mut self) {
+        self.streaming_endpoint.wait_enabled().await;
+    }
+}
+
+/// Used for writing sample rate information over the feedback endpoint.
+///
+/// The feedback value is the number of samples per (micro)frame, in 10.14 format on 3 bytes for
+/// full-speed USB, and in 16.16 format on 4 bytes for high-speed USB.
+pub struct Feedback<'d, D: Driver<'d>> {
+    feedback_endpoint: D::EndpointIn,
+}
+
+impl<'d, D: Driver<'d>> Feedback<'d, D> {
+    /// Writes a single packet into the IN endpoint.
+    pub async fn write_packet(&mut self, data: &[u8]) -> Result<(), EndpointError> {
+        self.feedback_endpoint.write(data).await
+    }
+
+    /// Waits for the USB host to enable this interface.
+    pub async fn wait_connection(&mut self) {
+        self.feedback_endpoint.wait_enabled().await;
+    }
+}
+
+/// Control status change monitor
+///
+/// Await [`ControlMonitor::changed`] for being notified of configuration changes. Afterwards, the updated
+/// configuration settings can be read with [`ControlMonitor::volume`], [`ControlMonitor::sample_rate_hz`]
+/// and [`ControlMonitor::resolution`].
+pub struct ControlMonitor<'d> {
+    shared: &'d SharedControl<'d>,
+}
+
+impl<'d> ControlMonitor<'d> {
+    fn audio_settings(&self) -> AudioSettings {
+        self.shared.audio_settings.lock(|x| x.get())
+    }
+
+    fn get_logical_channel(&self, search_channel: Channel) -> Option<usize> {
+        let index = self.shared.channels.iter().position(|&c| c == search_channel)?;
+
+        // The logical channels start at one (zero is the master channel).
+        Some(index + 1)
+    }
+
+    /// Get the volume of a selected channel.
+    pub fn volume(&self, channel: Channel) -> Option<Volume> {
+        let channel_index = self.get_logical_channel(channel)?;
+
+        if self.audio_settings().muted[channel_index] {
+            return Some(Volume::Muted);
+        }
+
+        Some(Volume::DeciBel(
+            (self.audio_settings().volume_8q8_db[channel_index] as f32) / 256.0f32,
+        ))
+    }
+
+    /// Get the index of the clock source selected by the host, in [`Config::clock_sources`].
+    pub fn clock_source(&self) -> usize {
+        self.shared.clock_source.load(Ordering::Relaxed) as usize
+    }
+
+    /// Get the sample rate in Hz of the selected clock source.
+    pub fn sample_rate_hz(&self) -> u32 {
+        self.shared.sample_rate_hz[self.clock_source()].load(Ordering::Relaxed)
+    }
+
+    /// Get the sample resolution of the stream, or `None` while the host doesn't stream audio.
+    pub fn resolution(&self) -> Option<SampleWidth> {
+        match self.shared.alternate_setting.load(Ordering::Relaxed) {
+            0 => None,
+            alternate_setting => self.shared.resolutions.get(alternate_setting as usize - 1).copied(),
+        }
+    }
+
+    /// Return a future for when the control settings change.
+    pub async fn changed(&self) {
+        self.shared.changed().await;
+    }
+}
+
+impl<'d> Control<'d> {
+    fn changed(&mut self) {
+        self.shared.changed.store(true, Ordering::Relaxed);
+        self.shared.waker.borrow_mut().wake();
+    }
+
+    /// Get the index of a clock source from its entity ID.
+    fn clock_source_index(&self, entity_id: u8) -> Option<usize> {
+        let index = entity_id.checked_sub(CLOCK_SOURCE_ID)? as usize;
+        (index < self.shared.clock_sources.len()).then_some(index)
+    }
+
+    fn feature_unit_set_request(&mut self, control_selector: u8, channel_index: u8, data: &[u8]) -> OutResponse {
+        if channel_index as usize > self.shared.channels.len() {
+            debug!("Unsupported channel {}", channel_index);
+            return OutResponse::Rejected;
+        }
+
+        let mut audio_settings = self.shared.audio_settings.lock(|x| x.get());
+        match (control_selector, data) {
+            (FU_MUTE_CONTROL, [mute_state]) => {
+                debug!("Set channel {} mute state: {}", channel_index, *mute_state != 0);
+                audio_settings.muted[channel_index as usize] = *mute_state != 0;
+            }
+            (FU_VOLUME_CONTROL, [low, high]) => {
+                let volume = i16::from_le_bytes([*low, *high]);
+                debug!("Set channel {} volume: {}", channel_index, volume);
+                audio_settings.volume_8q8_db[channel_index as usize] = volume;
+            }
+            _ => return OutResponse::Rejected,
+        }
+
+        // Store updated settings
+        self.shared.audio_settings.lock(|x| x.set(audio_settings));
+        OutResponse::Accepted
+    }
+
+    fn interface_set_request(&mut self, req: control::Request, data: &[u8]) -> Option<OutResponse> {
+        let interface_number = req.index as u8;
+        let entity_index = (req.index >> 8) as u8;
+        let channel_index = req.value as u8;
+        let control_selector = (req.value >> 8) as u8;
+
+        if interface_number != self.control_interface_number.into() {
+            debug!("Unhandled interface set request for interface {}", interface_number);
+            return None;
+        }
+
+        if req.request != CUR {
+            debug!("Unsupported interface set request type {}", req.request);
+            return Some(OutResponse::Rejected);
+        }
+
+        let response = match entity_index {
+            FEATURE_UNIT_ID => self.feature_unit_set_request(control_selector, channel_index, data),
+            CLOCK_SELECTOR_ID if self.shared.clock_sources.len() > 1 => match (control_selector, data) {
+                // The input pins of the clock selector start at one.
+                (CX_CLOCK_SELECTOR_CONTROL, [pin])
+                    if (1..=self.shared.clock_sources.len()).contains(&(*pin as usize)) =>
+                {
+                    debug!("Select clock source {}", pin);
+                    self.shared.clock_source.store(pin - 1, Ordering::Relaxed);
+                    OutResponse::Accepted
+                }
+                _ => OutResponse::Rejected,
+            },
+            _ => match (self.clock_source_index(entity_index), control_selector, data) {
+                (Some(index), CS_SAM_FREQ_CONTROL, [a, b, c, d]) => {
+                    let sample_rate_hz = u32::from_le_bytes([*a, *b, *c, *d]);
+                    if self.shared.clock_sources[index].contains(&sample_rate_hz) {
+                        debug!("Set clock source {} sample rate to {} Hz", index, sample_rate_hz);
+                        self.shared.sample_rate_hz[index].store(sample_rate_hz, Ordering::Relaxed);
+                        OutResponse::Accepted
+                    } else {
+                        debug!("Unsupported sample rate {} Hz", sample_rate_hz);
+                        OutResponse::Rejected
+                    }
+                }
+                _ => {
+                    debug!("Unsupported interface set request for entity {}", entity_index);
+                    OutResponse::Rejected
+                }
+            },
+        };
+
+        if response == OutResponse::Accepted {
+            self.changed();
+        }
+        Some(response)
+    }
+
+    fn feature_unit_get_request<'r>(
+        &'r mut self,
+        request: u8,
+        control_selector: u8,
+        channel_index: u8,
+        buf: &'r mut [u8],
+    ) -> InResponse<'r> {
+        if channel_index as usize > self.shared.channels.len() {
+            return InResponse::Rejected;
+        }
+
+        let audio_settings = self.shared.audio_settings.lock(|x| x.get());
+        match (request, control_selector) {
+            (CUR, FU_MUTE_CONTROL) => {
+                buf[0] = audio_settings.muted[channel_index as usize].into();
+                InResponse::Accepted(&buf[..1])
+            }
+            (CUR, FU_VOLUME_CONTROL) => {
+                let volume = audio_settings.volume_8q8_db[channel_index as usize];
+                buf[..2].copy_from_slice(&volume.to_le_bytes());
+                InResponse::Accepted(&buf[..2])
+            }
+            (RANGE, FU_VOLUME_CONTROL) => {
+                // Layout 2 parameter block with a single subrange [UAC2 5.2.3.2]
+                buf[..2].copy_from_slice(&1u16.to_le_bytes()); // wNumSubRanges
+                buf[2..4].copy_from_slice(&(MIN_VOLUME_DB * VOLUME_STEPS_PER_DB).to_le_bytes()); // wMIN
+                buf[4..6].copy_from_slice(&(MAX_VOLUME_DB * VOLUME_STEPS_PER_DB).to_le_bytes()); // wMAX
+                buf[6..8].copy_from_slice(&VOLUME_STEPS_PER_DB.to_le_bytes()); // wRES
+                InResponse::Accepted(&buf[..8])
+            }
+            _ => InResponse::Rejected,
+        }
+    }
+
+    fn clock_source_get_request<'r>(
+        &'r mut self,
+        request: u8,
+        control_selector: u8,
+        index: usize,
+        buf: &'r mut [u8],
+    ) -> InResponse<'r> {
+        match (request, control_selector) {
+            (CUR, CS_SAM_FREQ_CONTROL) => {
+                let sample_rate_hz = self.shared.sample_rate_hz[index].load(Ordering::Relaxed);
+                buf[..4].copy_from_slice(&sample_rate_hz.to_le_bytes());
+                InResponse::Accepted(&buf[..4])
+            }
+            (RANGE, CS_SAM_FREQ_CONTROL) => {
+                // Layout 3 parameter block, with one subrange per discrete sample rate [UAC2 5.2.3.3]
+                let rates = self.shared.clock_sources[index];
+                let len = 2 + 12 * rates.len();
+                if len > buf.len() {
+                    warn!("Control buffer too small for {} sample rates", rates.len());
+                    return InResponse::Rejected;
+                }
+
+                buf[..2].copy_from_slice(&(rates.len() as u16).to_le_bytes()); // wNumSubRanges
+                for (subrange, rate) in buf[2..len].chunks_exact_mut(12).zip(rates) {
+                    subrange[0..4].copy_from_slice(&rate.to_le_bytes()); // dMIN
+                    subrange[4..8].copy_from_slice(&rate.to_le_bytes()); // dMAX
+                    subrange[8..12].copy_from_slice(&0u32.to_le_bytes()); // dRES
+                }
+                InResponse::Accepted(&buf[..len])
+            }
+            (CUR, CS_CLOCK_VALID_CONTROL) => {
+                buf[0] = 1;
+                InResponse::Accepted(&buf[..1])
+            }
+            _ => InResponse::Rejected,
+        }
+    }
+
+    fn interface_get_request<'r>(&'r mut self, req: Request, buf: &'r mut [u8]) -> Option<InResponse<'r>> {
+        let interface_number = req.index as u8;
+        let entity_index = (req.index >> 8) as u8;
+        let channel_index = req.value as u8;
+        let control_selector = (req.value >> 8) as u8;
+
+        if interface_number != self.control_interface_number.into() {
+            debug!("Unhandled interface get request for interface {}.", interface_number);
+            return None;
+        }
+
+        let response = match entity_index {
+            FEATURE_UNIT_ID => self.feature_unit_get_request(req.request, control_selector, channel_index, buf),
+            CLOCK_SELECTOR_ID if self.shared.clock_sources.len() > 1 => match (req.request, control_selector) {
+                (CUR, CX_CLOCK_SELECTOR_CONTROL) => {
+                    buf[0] = self.shared.clock_source.load(Ordering::Relaxed) + 1;
+                    InResponse::Accepted(&buf[..1])
+                }
+                _ => InResponse::Rejected,
+            },
+            _ => match self.clock_source_index(entity_index) {
+                Some(index) => self.clock_source_get_request(req.request, control_selector, index, buf),
+                None => {
+                    debug!("Unsupported interface get request for entity {}.", entity_index);
+                    InResponse::Rejected
+                }
+            },
+        };
+        Some(response)
+    }
+}
+
+impl<'d> Handler for Control<'d> {
+    /// Called when a "set alternate setting" control request is done on the interface.
+    fn set_alternate_setting(&mut self, iface: InterfaceNumber, alternate_setting: u8) {
+        if iface != self.streaming_interface_number {
+            return;
+        }
+
+        debug!("USB audio streaming alt setting {}.", alternate_setting);
+        self.shared
+            .alternate_setting
+            .store(alternate_setting, Ordering::Relaxed);
+        self.changed();
+    }
+
+    /// Called after a USB reset after the bus reset sequence is complete.
+    fn reset(&mut self) {
+        let shared = self.shared;
+        shared.audio_settings.lock(|x| x.set(AudioSettings::default()));
+        shared.clock_source.store(0, Ordering::Relaxed);
+        shared.alternate_setting.store(0, Ordering::Relaxed);
+        for (rate, rates) in shared.sample_rate_hz.iter().zip(shared.clock_sources) {
+            rate.store(rates[0], Ordering::Relaxed);
+        }
+
+        shared.changed.store(true, Ordering::Relaxed);
+        shared.waker.borrow_mut().wake();
+    }
+
+    // Handle control set requests.
+    fn control_out(&mut self, req: control::Request, data: &[u8]) -> Option<OutResponse> {
+        match (req.request_type, req.recipient) {
+            (RequestType::Class, Recipient::Interface) => self.interface_set_request(req, data),
+            _ => None,
+        }
+    }
+
+    // Handle control get requests.
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        match (req.request_type, req.recipient) {
+            (RequestType::Class, Recipient::Interface) if req.direction == Direction::In => {
+                self.interface_get_request(req, buf)
+            }
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,551 @@
+//! USB Test and Measurement Class implementation, with the USB488 subclass.
+//!
+//! USBTMC carries the messages of test instruments, such as SCPI commands and their responses, in
+//! the same way as GPIB (IEEE 488). The host writes device dependent messages on the bulk OUT
+//! endpoint, and requests responses on it, which the device sends on the bulk IN endpoint.
+//!
+//! The USB488 subclass adds IEEE 488.2 features: triggers, the status byte, and remote/local
+//! control. There's no interrupt endpoint, so service requests (SRQ) aren't supported, and the
+//! host reads the status byte with a control request.
+//!
+//! The class never stalls its endpoints. Aborted and cleared transfers are dropped, but a transfer
+//! the device is waiting for data of only ends when the host sends its next message.
+
+use core::cell::RefCell;
+use core::future::poll_fn;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use core::task::Poll;
+
+use embassy_sync::waitqueue::WakerRegistration;
+
+use crate::control::{InResponse, Recipient, Request, RequestType};
+use crate::driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointOut};
+use crate::types::InterfaceNumber;
+use crate::{Builder, Handler};
+
+/// This should be used as `device_class` when building the `UsbDevice`.
+pub const USB_CLASS_APPLICATION_SPECIFIC: u8 = 0xFE;
+
+const USBTMC_SUBCLASS: u8 = 0x03;
+const USBTMC_PROTOCOL: u8 = 0x00;
+const USBTMC_PROTOCOL_USB488: u8 = 0x01;
+
+const MSG_DEV_DEP_MSG_OUT: u8 = 1;
+const MSG_REQUEST_DEV_DEP_MSG_IN: u8 = 2;
+const MSG_DEV_DEP_MSG_IN: u8 = 2;
+const MSG_TRIGGER: u8 = 128;
+
+const HEADER_LEN: usize = 12;
+const ATTR_EOM: u8 = 0x01;
+const ATTR_TERM_CHAR_ENABLED: u8 = 0x02;
+
+const REQ_INITIATE_ABORT_BULK_OUT: u8 = 1;
+const REQ_CHECK_ABORT_BULK_OUT_STATUS: u8 = 2;
+const REQ_INITIATE_ABORT_BULK_IN: u8 = 3;
+const REQ_CHECK_ABORT_BULK_IN_STATUS: u8 = 4;
+const REQ_INITIATE_CLEAR: u8 = 5;
+const REQ_CHECK_CLEAR_STATUS: u8 = 6;
+const REQ_GET_CAPABILITIES: u8 = 7;
+const REQ_INDICATOR_PULSE: u8 = 64;
+const REQ_READ_STATUS_BYTE: u8 = 128;
+const REQ_REN_CONTROL: u8 = 160;
+const REQ_GO_TO_LOCAL: u8 = 161;
+const REQ_LOCAL_LOCKOUT: u8 = 162;
+
+const STATUS_SUCCESS: u8 = 0x01;
+const STATUS_FAILED: u8 = 0x80;
+
+/// Configuration of the instrument, reported to the host in its capabilities.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Config {
+    /// Implement the USB488 subclass, with triggers, the status byte and remote/local control.
+    pub usb488: bool,
+    /// The instrument understands SCPI commands. Only used with `usb488`.
+    pub scpi: bool,
+    /// The instrument can show the host which device it is, for example by blinking a LED. See
+    /// [`UsbTmcClass::take_indicator_pulse`].
+    pub indicator_pulse: bool,
+    /// The instrument ends its responses after the termination character requested by the host,
+    /// given in [`Command::Read`].
+    pub term_char: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            usb488: true,
+            scpi: true,
+            indicator_pulse: false,
+            term_char: false,
+        }
+    }
+}
+
+/// A command from the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Command {
+    /// The first `len` bytes of the buffer hold the next part of a device dependent message, such
+    /// as a SCPI command. A message can span several parts, `end` is set on the last one.
+    Write {
+        /// Length of the part.
+        len: usize,
+        /// This is the last part of the message.
+        end: bool,
+    },
+    /// The host requests a response of at most `max_len` bytes, to send with
+    /// [`UsbTmcClass::write_response`].
+    Read {
+        /// Maximum length of the response.
+        max_len: usize,
+        /// The response should end after this character, if it comes first.
+        term_char: Option<u8>,
+    },
+    /// The host triggers the instrument, like the GPIB Group Execute Trigger. Only with USB488.
+    Trigger,
+}
+
+/// Internal state for the USBTMC class.
+pub struct State<'a> {
+    control: MaybeUninit<Control<'a>>,
+    shared: ControlShared,
+}
+
+impl<'a> Default for State<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> State<'a> {
+    /// Create a new `State`.
+    pub fn new() -> Self {
+        Self {
+            control: MaybeUninit::uninit(),
+            shared: ControlShared::default(),
+        }
+    }
+}
+
+/// Shared data between Control and `UsbTmcClass`
+#[derive(Default)]
+struct ControlShared {
+    /// Tag of the DEV_DEP_MSG_OUT transfer in progress, or 0.
+    out_tag: AtomicU8,
+    /// Tag of the pending REQUEST_DEV_DEP_MSG_IN, or 0.
+    in_tag: AtomicU8,
+    abort_out: AtomicBool,
+    abort_in: AtomicBool,
+
+    status_byte: AtomicU8,
+    ren: AtomicBool,
+    remote: AtomicBool,
+    lockout: AtomicBool,
+    indicator_pulse: AtomicBool,
+
+    waker: RefCell<WakerRegistration>,
+    changed: AtomicBool,
+}
+
+impl ControlShared {
+    fn changed(&self) {
+        self.changed.store(true, Ordering::Relaxed);
+        self.waker.borrow_mut().wake();
+    }
+
+    fn abort(&self) {
+        self.out_tag.store(0, Ordering::Relaxed);
+        self.in_tag.store(0, Ordering::Relaxed);
+        self.abort_out.store(true, Ordering::Relaxed);
+        self.abort_in.store(true, Ordering::Relaxed);
+    }
+}
+
+struct Control<'a> {
+    iface: InterfaceNumber,
+    out_ep: u8,
+    in_ep: u8,
+    config: Config,
+    shared: &'a ControlShared,
+}
+
+impl<'a> Control<'a> {
+    fn capabilities<'b>(&self, buf: &'b mut [u8]) -> &'b [u8] {
+        let buf = &mut buf[..24];
+        buf.fill(0);
+        buf[0] = STATUS_SUCCESS;
+        buf[2..4].copy_from_slice(&0x0100u16.to_le_bytes()); // bcdUSBTMC
+        buf[4] = (self.config.indicator_pulse as u8) << 2;
+        buf[5] = self.config.term_char as u8;
+        if self.config.usb488 {
+            buf[12..14].copy_from_slice(&0x0100u16.to_le_bytes()); // bcdUSB488
+            // USB488.2 interface, with REN_CONTROL, GO_TO_LOCAL, LOCAL_LOCKOUT and TRIGGER.
+            buf[14] = 0x07;
+            // SCPI, RL1 (remote/local) and DT1 (device trigger).
+            buf[15] = (self.config.scpi as u8) << 3 | 0x03;
+        }
+        buf
+    }
+}
+
+impl<'d> Handler for Control<'d> {
+    fn reset(&mut self) {
+        self.shared.abort();
+        self.shared.ren.store(false, Ordering::Relaxed);
+        self.shared.remote.store(false, Ordering::Relaxed);
+        self.shared.lockout.store(false, Ordering::Relaxed);
+        self.shared.changed();
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if req.request_type != RequestType::Class {
+            return None;
+        }
+        let for_iface = (req.recipient, req.index) == (Recipient::Interface, self.iface.0 as u16);
+        let for_ep = |addr: u8| (req.recipient, req.index) == (Recipient::Endpoint, addr as u16);
+        let tag = req.value as u8;
+        let shared = self.shared;
+
+        let len = match req.request {
+            REQ_INITIATE_ABORT_BULK_OUT if for_ep(self.out_ep) => {
+                buf[0] = if tag != 0 && shared.out_tag.load(Ordering::Relaxed) == tag {
+                    debug!("usbtmc: abort bulk out");
+                    shared.out_tag.store(0, Ordering::Relaxed);
+                    shared.abort_out.store(true, Ordering::Relaxed);
+                    STATUS_SUCCESS
+                } else {
+                    STATUS_FAILED
+                };
+                buf[1] = tag;
+                2
+            }
+            REQ_INITIATE_ABORT_BULK_IN if for_ep(self.in_ep) => {
+                buf[0] = if tag != 0 && shared.in_tag.load(Ordering::Relaxed) == tag {
+                    debug!("usbtmc: abort bulk in");
+                    shared.in_tag.store(0, Ordering::Relaxed);
+                    shared.abort_in.store(true, Ordering::Relaxed);
+                    STATUS_SUCCESS
+                } else {
+                    STATUS_FAILED
+                };
+                buf[1] = tag;
+                2
+            }
+            // Aborts complete right away, without any data left in the endpoint buffers.
+            REQ_CHECK_ABORT_BULK_OUT_STATUS if for_ep(self.out_ep) => {
+                buf[..8].copy_from_slice(&[STATUS_SUCCESS, 0, 0, 0, 0, 0, 0, 0]);
+                8
+            }
+            REQ_CHECK_ABORT_BULK_IN_STATUS if for_ep(self.in_ep) => {
+                buf[..8].copy_from_slice(&[STATUS_SUCCESS, 0, 0, 0, 0, 0, 0, 0]);
+                8
+            }
+            REQ_INITIATE_CLEAR if for_iface => {
+                debug!("usbtmc: clear");
+                shared.abort();
+                buf[0] = STATUS_SUCCESS;
+                1
+            }
+            REQ_CHECK_CLEAR_STATUS if for_iface => {
+                buf[..2].copy_from_slice(&[STATUS_SUCCESS, 0]);
+                2
+            }
+            REQ_GET_CAPABILITIES if for_iface => self.capabilities(buf).len(),
+            REQ_INDICATOR_PULSE if for_iface => {
+                buf[0] = if self.config.indicator_pulse {
+                    shared.indicator_pulse.store(true, Ordering::Relaxed);
+                    shared.changed();
+                    STATUS_SUCCESS
+                } else {
+                    STATUS_FAILED
+                };
+                1
+            }
+            REQ_READ_STATUS_BYTE if for_iface && self.config.usb488 => {
+                buf[..3].copy_from_slice(&[STATUS_SUCCESS, tag, shared.status_byte.load(Ordering::Relaxed)]);
+                3
+            }
+            REQ_REN_CONTROL if for_iface && self.config.usb488 => {
+                let ren = req.value & 0x01 != 0;
+                shared.ren.store(ren, Ordering::Relaxed);
+                if !ren {
+                    shared.remote.store(false, Ordering::Relaxed);
+                    shared.lockout.store(false, Ordering::Relaxed);
+                }
+                shared.changed();
+                buf[0] = STATUS_SUCCESS;
+                1
+            }
+            REQ_GO_TO_LOCAL if for_iface && self.config.usb488 => {
+                shared.remote.store(false, Ordering::Relaxed);
+                shared.changed();
+                buf[0] = STATUS_SUCCESS;
+                1
+            }
+            REQ_LOCAL_LOCKOUT if for_iface && self.config.usb488 => {
+                shared.lockout.store(true, Ordering::Relaxed);
+                shared.changed();
+                buf[0] = STATUS_SUCCESS;
+                1
+            }
+            _ if for_iface => return Some(InResponse::Rejected),
+            _ => return None,
+        };
+        Some(InResponse::Accepted(&buf[..len]))
+    }
+}
+
+/// A DEV_DEP_MSG_OUT transfer, whose data spans several packets.
+struct OutTransfer {
+    eom: bool,
+    /// Bytes of data left.
+    data_remaining: usize,
+    /// Bytes left in the transfer, including the alignment padding.
+    remaining: usize,
+}
+
+/// USB Test and Measurement class.
+pub struct UsbTmcClass<'d, D: Driver<'d>> {
+    read_ep: D::EndpointOut,
+    write_ep: D::EndpointIn,
+    shared: &'d ControlShared,
+    usb488: bool,
+    out: Option<OutTransfer>,
+    in_max_len: usize,
+}
+
+impl<'d, D: Driver<'d>> UsbTmcClass<'d, D> {
+    /// Creates a new UsbTmcClass with the provided UsbBus and `max_packet_size` in bytes. For
+    /// full-speed devices, `max_packet_size` has to be 64.
+    pub fn new(builder: &mut Builder<'d, D>, state: &'d mut State<'d>, config: Config, max_packet_size: u16) -> Self {
+        let protocol = if config.usb488 {
+            USBTMC_PROTOCOL_USB488
+        } else {
+            USBTMC_PROTOCOL
+        };
+
+        let mut func = builder.function(USB_CLASS_APPLICATION_SPECIFIC, USBTMC_SUBCLASS, protocol);
+        let mut iface = func.interface();
+        let iface_num = iface.interface_number();
+        let mut alt = iface.alt_setting(USB_CLASS_APPLICATION_SPECIFIC, USBTMC_SUBCLASS, protocol, None);
+        let read_ep = alt.endpoint_bulk_out(None, max_packet_size);
+        let write_ep = alt.endpoint_bulk_in(None, max_packet_size);
+        drop(func);
+
+        let control = state.control.write(Control {
+            iface: iface_num,
+            out_ep: read_ep.info().addr.into(),
+            in_ep: write_ep.info().addr.into(),
+            config,
+            shared: &state.shared,
+        });
+        builder.handler(control);
+
+        UsbTmcClass {
+            read_ep,
+            write_ep,
+            shared: &state.shared,
+            usb488: config.usb488,
+            out: None,
+            in_max_len: 0,
+        }
+    }
+
+    /// Gets the maximum packet size in bytes.
+    pub fn max_packet_size(&self) -> u16 {
+        // The size is the same for both endpoints.
+        self.read_ep.info().max_packet_size
+    }
+
+    /// Waits for the USB host to enable this interface
+    pub async fn wait_connection(&mut self) {
+        self.read_ep.wait_enabled().await;
+    }
+
+    /// Sets the IEEE 488.2 status byte, which the host reads with READ_STATUS_BYTE.
+    pub fn set_status_byte(&self, status_byte: u8) {
+        self.shared.status_byte.store(status_byte, Ordering::Relaxed);
+    }
+
+    /// Gets whether the instrument is in the remote state, controlled by the host instead of its
+    /// front panel.
+    pub fn is_remote(&self) -> bool {
+        self.shared.remote.load(Ordering::Relaxed)
+    }
+
+    /// Gets whether the host locked out the front panel "local" control of the instrument.
+    pub fn local_lockout(&self) -> bool {
+        self.shared.lockout.load(Ordering::Relaxed)
+    }
+
+    /// Returns whether the host requested an indicator pulse since the last call.
+    pub fn take_indicator_pulse(&self) -> bool {
+        let pulse = self.shared.indicator_pulse.load(Ordering::Relaxed);
+        self.shared.indicator_pulse.store(false, Ordering::Relaxed);
+        pulse
+    }
+
+    /// Waits for the remote/local state to change, or for an indicator pulse request.
+    pub async fn control_changed(&self) {
+        poll_fn(|cx| {
+            if self.shared.changed.load(Ordering::Relaxed) {
+                self.shared.changed.store(false, Ordering::Relaxed);
+                Poll::Ready(())
+            } else {
+                self.shared.waker.borrow_mut().register(cx.waker());
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    /// Reads the next command from the host.
+    ///
+    /// `buf` must be at least the max packet size, it receives the device dependent messages.
+    pub async fn read_command(&mut self, buf: &mut [u8]) -> Result<Command, EndpointError> {
+        let mps = self.max_packet_size() as usize;
+        assert!(buf.len() >= mps);
+
+        loop {
+            let n = self.read_ep.read(&mut buf[..mps]).await?;
+            if self.shared.abort_out.load(Ordering::Relaxed) {
+                self.shared.abort_out.store(false, Ordering::Relaxed);
+                self.out = None;
+            }
+
+            // Continuation of a message.
+            if let Some(out) = &mut self.out {
+                let len = n.min(out.data_remaining);
+                out.data_remaining -= len;
+                out.remaining = out.remaining.saturating_sub(n);
+                let end = out.eom && out.data_remaining == 0;
+                if out.remaining == 0 || n < mps {
+                    self.out = None;
+                    self.shared.out_tag.store(0, Ordering::Relaxed);
+                }
+                if len == 0 {
+                    // Only padding.
+                    continue;
+                }
+                return Ok(Command::Write { len, end });
+            }
+
+            if n < HEADER_LEN {
+                warn!("usbtmc: message too short");
+                continue;
+            }
+            let (msg_id, tag) = (buf[0], buf[1]);
+            if tag == 0 || buf[2] != !tag {
+                warn!("usbtmc: invalid message tag");
+                continue;
+            }
+            let size = u32::from_le_bytes(buf[4..8].try_into().unwrap()) as usize;
+            let attributes = buf[8];
+
+            match msg_id {
+                MSG_DEV_DEP_MSG_OUT => {
+                    let eom = attributes & ATTR_EOM != 0;
+                    let len = (n - HEADER_LEN).min(size);
+                    let remaining = size.next_multiple_of(4).saturating_sub(n - HEADER_LEN);
+                    if remaining > 0 && n == mps {
+                        self.out = Some(OutTransfer {
+                            eom,
+                            data_remaining: size - len,
+                            remaining,
+                        });
+                        self.shared.out_tag.store(tag, Ordering::Relaxed);
+                    }
+                    buf.copy_within(HEADER_LEN..HEADER_LEN + len, 0);
+                    self.addressed();
+                    return Ok(Command::Write {
+                        len,
+                        end: eom && len == size,
+                    });
+                }
+                MSG_REQUEST_DEV_DEP_MSG_IN => {
+                    self.shared.in_tag.store(tag, Ordering::Relaxed);
+                    self.in_max_len = size;
+                    let term_char = (attributes & ATTR_TERM_CHAR_ENABLED != 0).then_some(buf[9]);
+                    return Ok(Command::Read {
+                        max_len: size,
+                        term_char,
+                    });
+                }
+                MSG_TRIGGER if self.usb488 => {
+                    self.addressed();
+                    return Ok(Command::Trigger);
+                }
+                _ => debug!("usbtmc: unsupported message {}", msg_id),
+            }
+        }
+    }
+
+    /// The host addressed the instrument, which enters the remote state if enabled.
+    fn addressed(&self) {
+        if self.shared.ren.load(Ordering::Relaxed) && !self.shared.remote.load(Ordering::Relaxed) {
+            self.shared.remote.store(true, Ordering::Relaxed);
+            self.shared.changed();
+        }
+    }
+
+    /// Writes the response to the last [`Command::Read`].
+    ///
+    /// The response is cut to the length requested by the host. `end` is set on the last part of
+    /// the response, responses can span several reads. Nothing is sent if the host aborted the
+    /// read, or if there's no read to respond to.
+    pub async fn write_response(&mut self, data: &[u8], end: bool) -> Result<(), EndpointError> {
+        let tag = self.shared.in_tag.load(Ordering::Relaxed);
+        if tag == 0 {
+            debug!("usbtmc: no response requested");
+            return Ok(());
+        }
+        self.shared.abort_in.store(false, Ordering::Relaxed);
+
+        let (data, end) = if data.len() > self.in_max_len {
+            (&data[..self.in_max_len], false)
+        } else {
+            (data, end)
+        };
+
+        let mut header = [0; HEADER_LEN];
+        header[0] = MSG_DEV_DEP_MSG_IN;
+        header[1] = tag;
+        header[2] = !tag;
+        header[4..8].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        header[8] = if end { ATTR_EOM } else { 0 };
+        let padding = &[0; 3][..data.len().next_multiple_of(4) - data.len()];
+
+        let r = self.write_transfer(&[&header, data, padding]).await;
+        self.shared.in_tag.store(0, Ordering::Relaxed);
+        r
+    }
+
+    /// Write the concatenation of `parts` as one transfer, ending it early if it's aborted.
+    async fn write_transfer(&mut self, parts: &[&[u8]]) -> Result<(), EndpointError> {
+        const ABS_MAX_PACKET_SIZE: usize = 512;
+
+        let mps = self.max_packet_size() as usize;
+        let mut packet = [0; ABS_MAX_PACKET_SIZE];
+        let mut len = 0;
+        for mut part in parts.iter().copied() {
+            while !part.is_empty() {
+                let n = part.len().min(mps - len);
+                packet[len..len + n].copy_from_slice(&part[..n]);
+                part = &part[n..];
+                len += n;
+                if len == mps {
+                    if self.shared.abort_in.load(Ordering::Relaxed) {
+                        // End the transfer with a short packet.
+                        return self.write_ep.write(&[]).await;
+                    }
+                    self.write_ep.write(&packet[..mps]).await?;
+                    len = 0;
+                }
+            }
+        }
+        // The last packet is short, or a ZLP if the transfer filled the last packet.
+        self.write_ep.write(&packet[..len]).await
+    }
+}
@@ -0,0 +1,87 @@
+//! Video Device Class Codes as defined in Universal Serial Bus Device Class
+//! Definition for Video Devices, Revision 1.1, Appendix A
+#![allow(dead_code)]
+
+/// The implemented version of the UVC specification (1.1)
+pub const UVC_VERSION: u16 = 0x0110;
+
+// Video Interface Class Code
+pub const CC_VIDEO: u8 = 0x0E;
+
+// Video Interface Subclass Codes
+pub const SC_UNDEFINED: u8 = 0x00;
+pub const SC_VIDEOCONTROL: u8 = 0x01;
+pub const SC_VIDEOSTREAMING: u8 = 0x02;
+pub const SC_VIDEO_INTERFACE_COLLECTION: u8 = 0x03;
+
+// Video Interface Protocol Codes
+pub const PC_PROTOCOL_UNDEFINED: u8 = 0x00;
+
+// Video Class-Specific Descriptor Types
+pub const CS_UNDEFINED: u8 = 0x20;
+pub const CS_DEVICE: u8 = 0x21;
+pub const CS_CONFIGURATION: u8 = 0x22;
+pub const CS_STRING: u8 = 0x23;
+pub const CS_INTERFACE: u8 = 0x24;
+pub const CS_ENDPOINT: u8 = 0x25;
+
+// Video Class-Specific VC Interface Descriptor Subtypes
+pub const VC_DESCRIPTOR_UNDEFINED: u8 = 0x00;
+pub const VC_HEADER: u8 = 0x01;
+pub const VC_INPUT_TERMINAL: u8 = 0x02;
+pub const VC_OUTPUT_TERMINAL: u8 = 0x03;
+pub const VC_SELECTOR_UNIT: u8 = 0x04;
+pub const VC_PROCESSING_UNIT: u8 = 0x05;
+pub const VC_EXTENSION_UNIT: u8 = 0x06;
+
+// Video Class-Specific VS Interface Descriptor Subtypes
+pub const VS_UNDEFINED: u8 = 0x00;
+pub const VS_INPUT_HEADER: u8 = 0x01;
+pub const VS_OUTPUT_HEADER: u8 = 0x02;
+pub const VS_STILL_IMAGE_FRAME: u8 = 0x03;
+pub const VS_FORMAT_UNCOMPRESSED: u8 = 0x04;
+pub const VS_FRAME_UNCOMPRESSED: u8 = 0x05;
+pub const VS_FORMAT_MJPEG: u8 = 0x06;
+pub const VS_FRAME_MJPEG: u8 = 0x07;
+pub const VS_COLORFORMAT: u8 = 0x0D;
+
+// Video Class-Specific Request Codes
+pub const RC_UNDEFINED: u8 = 0x00;
+pub const SET_CUR: u8 = 0x01;
+pub const GET_CUR: u8 = 0x81;
+pub const GET_MIN: u8 = 0x82;
+pub const GET_MAX: u8 = 0x83;
+pub const GET_RES: u8 = 0x84;
+pub const GET_LEN: u8 = 0x85;
+pub const GET_INFO: u8 = 0x86;
+pub const GET_DEF: u8 = 0x87;
+
+// VideoStreaming Interface Control Selectors
+pub const VS_CONTROL_UNDEFINED: u8 = 0x00;
+pub const VS_PROBE_CONTROL: u8 = 0x01;
+pub const VS_COMMIT_CONTROL: u8 = 0x02;
+pub const VS_STILL_PROBE_CONTROL: u8 = 0x03;
+pub const VS_STILL_COMMIT_CONTROL: u8 = 0x04;
+pub const VS_STILL_IMAGE_TRIGGER_CONTROL: u8 = 0x05;
+pub const VS_STREAM_ERROR_CODE_CONTROL: u8 = 0x06;
+pub const VS_GENERATE_KEY_FRAME_CONTROL: u8 = 0x07;
+pub const VS_UPDATE_FRAME_SEGMENT_CONTROL: u8 = 0x08;
+pub const VS_SYNC_DELAY_CONTROL: u8 = 0x09;
+
+// USB Terminal Types
+pub const TT_VENDOR_SPECIFIC: u16 = 0x0100;
+pub const TT_STREAMING: u16 = 0x0101;
+
+// Input Terminal Types
+pub const ITT_VENDOR_SPECIFIC: u16 = 0x0200;
+pub const ITT_CAMERA: u16 = 0x0201;
+pub const ITT_MEDIA_TRANSPORT_INPUT: u16 = 0x0202;
+
+// Payload header bits
+pub const HEADER_FID: u8 = 1 << 0;
+pub const HEADER_EOF: u8 = 1 << 1;
+pub const HEADER_PTS: u8 = 1 << 2;
+pub const HEADER_SCR: u8 = 1 << 3;
+pub const HEADER_STI: u8 = 1 << 5;
+pub const HEADER_ERR: u8 = 1 << 6;
+pub const HEADER_EOH: u8 = 1 << 7;
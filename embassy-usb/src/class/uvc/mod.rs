@@ -0,0 +1,694 @@
+//! USB Video Class implementation, aka webcams.
+//!
+//! Provides a class with a camera and a single video streaming interface (device to host), which
+//! streams frames in the MJPEG or the uncompressed YUY2 format. The host selects the format, the
+//! frame size and the frame interval with the probe and commit controls, and then reads the
+//! frames split into payloads with headers, over a bulk or an isochronous endpoint.
+
+use core::cell::{Cell, RefCell};
+use core::future::poll_fn;
+use core::sync::atomic::{AtomicU8, Ordering};
+use core::task::Poll;
+
+use embassy_sync::blocking_mutex::CriticalSectionMutex;
+use embassy_sync::waitqueue::WakerRegistration;
+use heapless::Vec;
+
+use crate::control::{self, InResponse, OutResponse, Recipient, Request, RequestType};
+use crate::descriptor::{SynchronizationType, UsageType};
+use crate::driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointType};
+use crate::types::InterfaceNumber;
+use crate::{Builder, Handler};
+
+mod class_codes;
+
+use class_codes::*;
+
+/// Arbitrary unique identifier for the camera terminal.
+const CAMERA_TERMINAL_ID: u8 = 0x01;
+
+/// Arbitrary unique identifier for the output terminal.
+const OUTPUT_TERMINAL_ID: u8 = 0x02;
+
+/// Clock frequency reported to the host, unused as payloads have no timestamps.
+const CLOCK_FREQUENCY_HZ: u32 = 48_000_000;
+
+/// Length of the video probe and commit controls.
+const PROBE_LEN: usize = 34;
+
+/// Length of the payload headers, without timestamps.
+const HEADER_LEN: usize = 2;
+
+/// Maximum supported packet size of the streaming endpoint.
+pub const MAX_PACKET_SIZE: usize = 1024;
+
+/// Maximum number of frame intervals of a frame.
+pub const MAX_FRAME_INTERVALS: usize = 8;
+
+/// GUID of the YUY2 format.
+const GUID_YUY2: [u8; 16] = [
+    0x59, 0x55, 0x59, 0x32, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xAA, 0x00, 0x38, 0x9B, 0x71,
+];
+
+/// Encoding of the frames of a format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Encoding {
+    /// Motion JPEG, each frame is a JPEG image.
+    Mjpeg,
+    /// Uncompressed YUV 4:2:2, with 16 bits per pixel in Y0 U Y1 V order.
+    Yuy2,
+}
+
+/// A frame size of a format.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Frame<'d> {
+    /// Width in pixels.
+    pub width: u16,
+    /// Height in pixels.
+    pub height: u16,
+    /// The supported frame intervals in 100 ns units (up to [`MAX_FRAME_INTERVALS`]), for example
+    /// 333_333 for 30 frames per second. The first one is the default.
+    pub intervals: &'d [u32],
+}
+
+impl<'d> Frame<'d> {
+    /// Maximum size of a frame in bytes, the size of an uncompressed frame.
+    pub const fn max_frame_size(&self) -> u32 {
+        self.width as u32 * self.height as u32 * 2
+    }
+
+    /// Bit rate at a frame interval.
+    fn bit_rate(&self, interval: u32) -> u32 {
+        let bits = self.max_frame_size() as u64 * 8;
+        (bits * 10_000_000 / interval.max(1) as u64).min(u32::MAX as u64) as u32
+    }
+}
+
+/// A video format, with its frame sizes.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Format<'d> {
+    /// Encoding of the frames.
+    pub encoding: Encoding,
+    /// The supported frame sizes. The first one is the default.
+    pub frames: &'d [Frame<'d>],
+}
+
+/// Transport of the video payloads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Transport {
+    /// A bulk endpoint, with a payload per frame. The host starts streaming with the commit control.
+    Bulk,
+    /// An isochronous endpoint, with a payload per (micro)frame. The host starts streaming by
+    /// selecting the alternate setting with the endpoint.
+    Isochronous,
+}
+
+/// Configuration of a [`UvcClass`].
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Config<'d> {
+    /// The supported formats. The first one is the default.
+    pub formats: &'d [Format<'d>],
+    /// Transport of the video payloads.
+    pub transport: Transport,
+    /// Max packet size of the streaming endpoint, up to [`MAX_PACKET_SIZE`].
+    pub max_packet_size: u16,
+}
+
+/// The stream settings negotiated by the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StreamSettings {
+    /// Index of the format in [`Config::formats`].
+    pub format: usize,
+    /// Index of the frame in the frames of the format.
+    pub frame: usize,
+    /// Frame interval in 100 ns units.
+    pub interval: u32,
+}
+
+/// Internal state for the USB Video Class.
+pub struct State<'d> {
+    control: Option<Control<'d>>,
+    shared: SharedControl<'d>,
+}
+
+impl<'d> Default for State<'d> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'d> State<'d> {
+    /// Create a new `State`.
+    pub fn new() -> Self {
+        Self {
+            control: None,
+            shared: SharedControl::default(),
+        }
+    }
+}
+
+/// Shared data between [`Control`] and the [`UvcClass`].
+struct SharedControl<'d> {
+    formats: &'d [Format<'d>],
+    transport: Transport,
+    max_packet_size: u16,
+
+    /// Settings of the probe control.
+    probe: CriticalSectionMutex<Cell<Option<StreamSettings>>>,
+    /// Settings of the commit control.
+    commit: CriticalSectionMutex<Cell<Option<StreamSettings>>>,
+    /// The alternate setting of the streaming interface.
+    alternate_setting: AtomicU8,
+
+    waker: RefCell<WakerRegistration>,
+}
+
+impl<'d> Default for SharedControl<'d> {
+    fn default() -> Self {
+        SharedControl {
+            formats: &[],
+            transport: Transport::Bulk,
+            max_packet_size: 0,
+            probe: CriticalSectionMutex::new(Cell::new(None)),
+            commit: CriticalSectionMutex::new(Cell::new(None)),
+            alternate_setting: AtomicU8::new(0),
+            waker: RefCell::new(WakerRegistration::new()),
+        }
+    }
+}
+
+impl<'d> SharedControl<'d> {
+    /// The settings of the stream, while the host streams.
+    fn streaming(&self) -> Option<StreamSettings> {
+        let commit = self.commit.lock(|x| x.get());
+        match self.transport {
+            Transport::Bulk => commit,
+            Transport::Isochronous => commit.filter(|_| self.alternate_setting.load(Ordering::Relaxed) != 0),
+        }
+    }
+
+    fn default_settings(&self) -> StreamSettings {
+        StreamSettings {
+            format: 0,
+            frame: 0,
+            interval: self.formats[0].frames[0].intervals[0],
+        }
+    }
+
+    fn frame(&self, settings: &StreamSettings) -> &Frame<'d> {
+        &self.formats[settings.format].frames[settings.frame]
+    }
+
+    /// Pick supported settings, as close as possible to the ones in the probe or commit `data`.
+    fn negotiate(&self, data: &[u8]) -> StreamSettings {
+        let format = (data[2] as usize)
+            .checked_sub(1)
+            .filter(|&i| i < self.formats.len())
+            .unwrap_or(0);
+        let frames = self.formats[format].frames;
+        let frame = (data[3] as usize)
+            .checked_sub(1)
+            .filter(|&i| i < frames.len())
+            .unwrap_or(0);
+        let intervals = frames[frame].intervals;
+        let requested = u32::from_le_bytes(data[4..8].try_into().unwrap());
+        let interval = match requested {
+            0 => intervals[0],
+            _ => *intervals.iter().min_by_key(|i| i.abs_diff(requested)).unwrap(),
+        };
+        StreamSettings {
+            format,
+            frame,
+            interval,
+        }
+    }
+
+    /// Write a probe or commit control with `settings` to `buf`.
+    fn write_probe<'r>(&self, settings: &StreamSettings, interval: u32, buf: &'r mut [u8]) -> &'r [u8] {
+        let frame = self.frame(settings);
+        let max_payload_size = match self.transport {
+            Transport::Bulk => frame.max_frame_size() + HEADER_LEN as u32,
+            Transport::Isochronous => self.max_packet_size as u32,
+        };
+
+        let buf = &mut buf[..PROBE_LEN];
+        buf.fill(0);
+        buf[2] = settings.format as u8 + 1; // bFormatIndex
+        buf[3] = settings.frame as u8 + 1; // bFrameIndex
+        buf[4..8].copy_from_slice(&interval.to_le_bytes()); // dwFrameInterval
+        buf[18..22].copy_from_slice(&frame.max_frame_size().to_le_bytes()); // dwMaxVideoFrameSize
+        buf[22..26].copy_from_slice(&max_payload_size.to_le_bytes()); // dwMaxPayloadTransferSize
+        buf[26..30].copy_from_slice(&CLOCK_FREQUENCY_HZ.to_le_bytes()); // dwClockFrequency
+        buf[30] = HEADER_FID | HEADER_EOF; // bmFramingInfo
+        buf
+    }
+}
+
+struct Control<'d> {
+    streaming_interface_number: InterfaceNumber,
+    shared: &'d SharedControl<'d>,
+}
+
+impl<'d> Control<'d> {
+    fn changed(&mut self) {
+        self.shared.waker.borrow_mut().wake();
+    }
+}
+
+impl<'d> Handler for Control<'d> {
+    /// Called when a "set alternate setting" control request is done on the interface.
+    fn set_alternate_setting(&mut self, iface: InterfaceNumber, alternate_setting: u8) {
+        if iface != self.streaming_interface_number {
+            return;
+        }
+
+        debug!("USB video streaming alt setting {}.", alternate_setting);
+        self.shared
+            .alternate_setting
+            .store(alternate_setting, Ordering::Relaxed);
+        self.changed();
+    }
+
+    /// Called after a USB reset after the bus reset sequence is complete.
+    fn reset(&mut self) {
+        let shared = self.shared;
+        shared.probe.lock(|x| x.set(None));
+        shared.commit.lock(|x| x.set(None));
+        shared.alternate_setting.store(0, Ordering::Relaxed);
+        self.changed();
+    }
+
+    fn control_out(&mut self, req: control::Request, data: &[u8]) -> Option<OutResponse> {
+        if (req.request_type, req.recipient, req.index)
+            != (
+                RequestType::Class,
+                Recipient::Interface,
+                self.streaming_interface_number.0 as u16,
+            )
+        {
+            return None;
+        }
+
+        // UVC 1.0 hosts send shorter controls, without the fields added in UVC 1.1.
+        let control_selector = (req.value >> 8) as u8;
+        if req.request != SET_CUR || data.len() < 26 {
+            return Some(OutResponse::Rejected);
+        }
+
+        let settings = self.shared.negotiate(data);
+        match control_selector {
+            VS_PROBE_CONTROL => {
+                debug!("Video probe: {:?}", settings);
+                self.shared.probe.lock(|x| x.set(Some(settings)));
+            }
+            VS_COMMIT_CONTROL => {
+                debug!("Video commit: {:?}", settings);
+                self.shared.commit.lock(|x| x.set(Some(settings)));
+                self.changed();
+            }
+            _ => return Some(OutResponse::Rejected),
+        }
+        Some(OutResponse::Accepted)
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if (req.request_type, req.recipient, req.index)
+            != (
+                RequestType::Class,
+                Recipient::Interface,
+                self.streaming_interface_number.0 as u16,
+            )
+        {
+            return None;
+        }
+
+        let control_selector = (req.value >> 8) as u8;
+        let settings = match control_selector {
+            VS_PROBE_CONTROL => self.shared.probe.lock(|x| x.get()),
+            VS_COMMIT_CONTROL => self.shared.commit.lock(|x| x.get()),
+            _ => return Some(InResponse::Rejected),
+        };
+        let settings = settings.unwrap_or_else(|| self.shared.default_settings());
+        let intervals = self.shared.frame(&settings).intervals;
+
+        let response = match req.request {
+            GET_INFO => {
+                // Supports GET and SET requests.
+                buf[0] = 0x03;
+                InResponse::Accepted(&buf[..1])
+            }
+            GET_LEN => {
+                buf[..2].copy_from_slice(&(PROBE_LEN as u16).to_le_bytes());
+                InResponse::Accepted(&buf[..2])
+            }
+            GET_CUR => InResponse::Accepted(self.shared.write_probe(&settings, settings.interval, buf)),
+            GET_DEF => InResponse::Accepted(self.shared.write_probe(&settings, intervals[0], buf)),
+            GET_MIN => {
+                let interval = *intervals.iter().min().unwrap();
+                InResponse::Accepted(self.shared.write_probe(&settings, interval, buf))
+            }
+            GET_MAX => {
+                let interval = *intervals.iter().max().unwrap();
+                InResponse::Accepted(self.shared.write_probe(&settings, interval, buf))
+            }
+            _ => InResponse::Rejected,
+        };
+        Some(response)
+    }
+}
+
+/// USB Video Class device, streaming video to the host.
+pub struct UvcClass<'d, D: Driver<'d>> {
+    write_ep: D::EndpointIn,
+    shared: &'d SharedControl<'d>,
+    /// Frame ID bit of the payload headers, toggled at each frame.
+    fid: bool,
+}
+
+impl<'d, D: Driver<'d>> UvcClass<'d, D> {
+    /// Creates a new [`UvcClass`] with the provided builder and configuration.
+    ///
+    /// With the isochronous transport, `max_packet_size` should hold the data of a (micro)frame at the
+    /// highest bit rate, plus a 2 byte payload header. With the bulk transport, it has to be 64 for
+    /// full-speed and 512 for high-speed devices.
+    ///
+    /// Panics if the configuration has no formats, a format has no frames, or a frame has no or too
+    /// many frame intervals.
+    pub fn new(builder: &mut Builder<'d, D>, state: &'d mut State<'d>, config: Config<'d>) -> Self {
+        assert!(!config.formats.is_empty() && config.formats.len() < u8::MAX as usize);
+        assert!(config.max_packet_size as usize > HEADER_LEN && config.max_packet_size as usize <= MAX_PACKET_SIZE);
+        for format in config.formats {
+            assert!(!format.frames.is_empty() && format.frames.len() < u8::MAX as usize);
+            for frame in format.frames {
+                assert!((1..=MAX_FRAME_INTERVALS).contains(&frame.intervals.len()));
+            }
+        }
+
+        let mut func = builder.function(CC_VIDEO, SC_VIDEO_INTERFACE_COLLECTION, PC_PROTOCOL_UNDEFINED);
+
+        // Video control interface [UVC 3.7]
+        let mut interface = func.interface();
+        let streaming_interface = InterfaceNumber(u8::from(interface.interface_number()) + 1);
+        let mut alt = interface.alt_setting(CC_VIDEO, SC_VIDEOCONTROL, PC_PROTOCOL_UNDEFINED, None);
+
+        // Terminal topology:
+        // Camera terminal -> Output terminal (streaming interface)
+
+        // ===================================================
+        // Class-specific VC Interface Header Descriptor [UVC 3.7.2]
+        const VC_DESCRIPTORS_LENGTH: u16 = 13 + 18 + 9;
+        alt.descriptor(
+            CS_INTERFACE,
+            &[
+                VC_HEADER, // bDescriptorSubtype
+                UVC_VERSION as u8,
+                (UVC_VERSION >> 8) as u8, // bcdUVC
+                VC_DESCRIPTORS_LENGTH as u8,
+                (VC_DESCRIPTORS_LENGTH >> 8) as u8, // wTotalLength
+                CLOCK_FREQUENCY_HZ as u8,
+                (CLOCK_FREQUENCY_HZ >> 8) as u8,
+                (CLOCK_FREQUENCY_HZ >> 16) as u8,
+                (CLOCK_FREQUENCY_HZ >> 24) as u8, // dwClockFrequency
+                0x01,                             // bInCollection (1 streaming interface)
+                streaming_interface.0,            // baInterfaceNr
+            ],
+        );
+
+        // ========================================
+        // Camera Terminal Descriptor [UVC 3.7.2.3]
+        alt.descriptor(
+            CS_INTERFACE,
+            &[
+                VC_INPUT_TERMINAL,  // bDescriptorSubtype
+                CAMERA_TERMINAL_ID, // bTerminalID
+                ITT_CAMERA as u8,
+                (ITT_CAMERA >> 8) as u8, // wTerminalType
+                0x00,                    // bAssocTerminal (none)
+                0x00,                    // iTerminal (none)
+                0x00,
+                0x00, // wObjectiveFocalLengthMin
+                0x00,
+                0x00, // wObjectiveFocalLengthMax
+                0x00,
+                0x00, // wOcularFocalLength
+                0x03, // bControlSize
+                0x00,
+                0x00,
+                0x00, // bmControls (none)
+            ],
+        );
+
+        // ========================================
+        // Output Terminal Descriptor [UVC 3.7.2.2]
+        alt.descriptor(
+            CS_INTERFACE,
+            &[
+                VC_OUTPUT_TERMINAL, // bDescriptorSubtype
+                OUTPUT_TERMINAL_ID, // bTerminalID
+                TT_STREAMING as u8,
+                (TT_STREAMING >> 8) as u8, // wTerminalType
+                0x00,                      // bAssocTerminal (none)
+                CAMERA_TERMINAL_ID,        // bSourceID (the camera)
+                0x00,                      // iTerminal (none)
+            ],
+        );
+
+        // ================================================
+        // Video streaming interface, bulk or zero-bandwidth [UVC 3.9]
+        let mut interface = func.interface();
+        assert_eq!(interface.interface_number(), streaming_interface);
+        let mut alt = interface.alt_setting(CC_VIDEO, SC_VIDEOSTREAMING, PC_PROTOCOL_UNDEFINED, None);
+
+        let (ep_type, interval) = match config.transport {
+            Transport::Bulk => (EndpointType::Bulk, 0),
+            Transport::Isochronous => (EndpointType::Isochronous, 1),
+        };
+        let write_ep = alt.alloc_endpoint_in(ep_type, None, config.max_packet_size, interval);
+
+        // =====================================================
+        // Class-specific VS Interface Input Header Descriptor [UVC 3.9.2.1]
+        let num_formats = config.formats.len();
+        let mut total_descriptor_length = 13 + num_formats;
+        for format in config.formats {
+            total_descriptor_length += match format.encoding {
+                Encoding::Mjpeg => 11,
+                Encoding::Yuy2 => 27,
+            };
+            for frame in format.frames {
+                total_descriptor_length += 26 + 4 * frame.intervals.len();
+            }
+        }
+
+        let mut input_header_descriptor: Vec<u8, { 11 + u8::MAX as usize }> = Vec::from_slice(&[
+            VS_INPUT_HEADER,   // bDescriptorSubtype
+            num_formats as u8, // bNumFormats
+            total_descriptor_length as u8,
+            (total_descriptor_length >> 8) as u8, // wTotalLength
+            write_ep.info().addr.into(),          // bEndpointAddress
+            0x00,                                 // bmInfo (no dynamic format change)
+            OUTPUT_TERMINAL_ID,                   // bTerminalLink
+            0x00,                                 // bStillCaptureMethod (none)
+            0x00,                                 // bTriggerSupport (none)
+            0x00,                                 // bTriggerUsage
+            0x01,                                 // bControlSize
+        ])
+        .unwrap();
+        for _format in config.formats {
+            input_header_descriptor.push(0x00).unwrap(); // bmaControls (none)
+        }
+        alt.descriptor(CS_INTERFACE, &input_header_descriptor);
+
+        for (format_index, format) in config.formats.iter().enumerate() {
+            let format_index = format_index as u8 + 1;
+            let num_frames = format.frames.len() as u8;
+
+            // ===========================================
+            // Format Descriptors [UVC MJPEG 3.1.1, UVC Uncompressed 3.1.1]
+            let frame_subtype = match format.encoding {
+                Encoding::Mjpeg => {
+                    alt.descriptor(
+                        CS_INTERFACE,
+                        &[
+                            VS_FORMAT_MJPEG, // bDescriptorSubtype
+                            format_index,    // bFormatIndex
+                            num_frames,      // bNumFrameDescriptors
+                            0x01,            // bmFlags (fixed size samples)
+                            0x01,            // bDefaultFrameIndex
+                            0x00,            // bAspectRatioX
+                            0x00,            // bAspectRatioY
+                            0x00,            // bmInterlaceFlags
+                            0x00,            // bCopyProtect
+                        ],
+                    );
+                    VS_FRAME_MJPEG
+                }
+                Encoding::Yuy2 => {
+                    let mut descriptor: Vec<u8, 25> =
+                        Vec::from_slice(&[VS_FORMAT_UNCOMPRESSED, format_index, num_frames]).unwrap();
+                    descriptor.extend_from_slice(&GUID_YUY2).unwrap(); // guidFormat
+                    descriptor
+                        .extend_from_slice(&[
+                            16,   // bBitsPerPixel
+                            0x01, // bDefaultFrameIndex
+                            0x00, // bAspectRatioX
+                            0x00, // bAspectRatioY
+                            0x00, // bmInterlaceFlags
+                            0x00, // bCopyProtect
+                        ])
+                        .unwrap();
+                    alt.descriptor(CS_INTERFACE, &descriptor);
+                    VS_FRAME_UNCOMPRESSED
+                }
+            };
+
+            // ===========================================
+            // Frame Descriptors [UVC MJPEG 3.1.2, UVC Uncompressed 3.1.2]
+            for (frame_index, frame) in format.frames.iter().enumerate() {
+                let min_interval = *frame.intervals.iter().min().unwrap();
+                let max_interval = *frame.intervals.iter().max().unwrap();
+
+                let mut descriptor: Vec<u8, { 24 + 4 * MAX_FRAME_INTERVALS }> = Vec::new();
+                descriptor
+                    .extend_from_slice(&[
+                        frame_subtype,         // bDescriptorSubtype
+                        frame_index as u8 + 1, // bFrameIndex
+                        0x00,                  // bmCapabilities (no still images)
+                    ])
+                    .unwrap();
+                descriptor.extend_from_slice(&frame.width.to_le_bytes()).unwrap(); // wWidth
+                descriptor.extend_from_slice(&frame.height.to_le_bytes()).unwrap(); // wHeight
+                descriptor
+                    .extend_from_slice(&frame.bit_rate(max_interval).to_le_bytes())
+                    .unwrap(); // dwMinBitRate
+                descriptor
+                    .extend_from_slice(&frame.bit_rate(min_interval).to_le_bytes())
+                    .unwrap(); // dwMaxBitRate
+                descriptor
+                    .extend_from_slice(&frame.max_frame_size().to_le_bytes())
+                    .unwrap(); // dwMaxVideoFrameBufferSize
+                descriptor.extend_from_slice(&frame.intervals[0].to_le_bytes()).unwrap(); // dwDefaultFrameInterval
+                descriptor.push(frame.intervals.len() as u8).unwrap(); // bFrameIntervalType (discrete)
+                for interval in frame.intervals {
+                    descriptor.extend_from_slice(&interval.to_le_bytes()).unwrap(); // dwFrameInterval
+                }
+                alt.descriptor(CS_INTERFACE, &descriptor);
+            }
+        }
+
+        match config.transport {
+            Transport::Bulk => {
+                alt.endpoint_descriptor(
+                    write_ep.info(),
+                    SynchronizationType::NoSynchronization,
+                    UsageType::DataEndpoint,
+                    &[],
+                );
+            }
+            Transport::Isochronous => {
+                // ===========================================
+                // Video streaming interface, operational [UVC 3.9]
+                let mut alt = interface.alt_setting(CC_VIDEO, SC_VIDEOSTREAMING, PC_PROTOCOL_UNDEFINED, None);
+                alt.endpoint_descriptor(
+                    write_ep.info(),
+                    SynchronizationType::Asynchronous,
+                    UsageType::DataEndpoint,
+                    &[],
+                );
+            }
+        }
+
+        // Free up the builder.
+        drop(func);
+
+        let shared = &mut state.shared;
+        shared.formats = config.formats;
+        shared.transport = config.transport;
+        shared.max_packet_size = config.max_packet_size;
+
+        state.control = Some(Control {
+            streaming_interface_number: streaming_interface,
+            shared: &state.shared,
+        });
+        builder.handler(state.control.as_mut().unwrap());
+
+        Self {
+            write_ep,
+            shared: &state.shared,
+            fid: false,
+        }
+    }
+
+    /// Waits for the USB host to enable this interface.
+    pub async fn wait_connection(&mut self) {
+        self.write_ep.wait_enabled().await;
+    }
+
+    /// Get the stream settings while the host streams video, or `None` otherwise.
+    pub fn stream_settings(&self) -> Option<StreamSettings> {
+        self.shared.streaming()
+    }
+
+    /// Waits for the host to start streaming video, and return the stream settings it committed.
+    ///
+    /// The host can stop the stream or commit other settings at any time, check
+    /// [`stream_settings`](Self::stream_settings) before each frame.
+    pub async fn wait_streaming(&mut self) -> StreamSettings {
+        poll_fn(|cx| match self.shared.streaming() {
+            Some(settings) => Poll::Ready(settings),
+            None => {
+                self.shared.waker.borrow_mut().register(cx.waker());
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    /// Write a frame to the host, in the format and size of the stream settings.
+    ///
+    /// The frame is split into payloads, with a header each.
+    pub async fn write_frame(&mut self, frame: &[u8]) -> Result<(), EndpointError> {
+        let max_packet_size = self.write_ep.info().max_packet_size as usize;
+        let fid = if self.fid { HEADER_FID } else { 0 };
+        self.fid = !self.fid;
+
+        let mut packet = [0; MAX_PACKET_SIZE];
+        packet[0] = HEADER_LEN as u8; // bHeaderLength
+
+        match self.shared.transport {
+            Transport::Bulk => {
+                // A single payload, ended by a short packet.
+                packet[1] = HEADER_EOH | HEADER_EOF | fid; // bmHeaderInfo
+                let (first, rest) = frame.split_at(frame.len().min(max_packet_size - HEADER_LEN));
+                packet[HEADER_LEN..HEADER_LEN + first.len()].copy_from_slice(first);
+                self.write_ep.write(&packet[..HEADER_LEN + first.len()]).await?;
+                for chunk in rest.chunks(max_packet_size) {
+                    self.write_ep.write(chunk).await?;
+                }
+                if (HEADER_LEN + frame.len()).is_multiple_of(max_packet_size) {
+                    self.write_ep.write(&[]).await?;
+                }
+            }
+            Transport::Isochronous => {
+                // A payload per packet.
+                let mut rest = frame;
+                loop {
+                    let (chunk, next) = rest.split_at(rest.len().min(max_packet_size - HEADER_LEN));
+                    rest = next;
+                    packet[1] = HEADER_EOH | fid | if rest.is_empty() { HEADER_EOF } else { 0 };
+                    packet[HEADER_LEN..HEADER_LEN + chunk.len()].copy_from_slice(chunk);
+                    self.write_ep.write(&packet[..HEADER_LEN + chunk.len()]).await?;
+                    if rest.is_empty() {
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
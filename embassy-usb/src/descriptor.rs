@@ -1,8 +1,7 @@
 //! Utilities for writing USB descriptors.
 use embassy_usb_driver::EndpointType;
 
-use crate::CONFIGURATION_VALUE;
-use crate::builder::Config;
+use crate::builder::{Config, Lpm};
 use crate::driver::EndpointInfo;
 use crate::types::{InterfaceNumber, StringIndex};
 
@@ -80,6 +79,7 @@ pub enum UsageType {
 pub(crate) struct DescriptorWriter<'a> {
     pub buf: &'a mut [u8],
     position: usize,
+    configuration_mark: usize,
     num_interfaces_mark: Option<usize>,
     num_endpoints_mark: Option<usize>,
 }
@@ -89,6 +89,7 @@ impl<'a> DescriptorWriter<'a> {
         DescriptorWriter {
             buf,
             position: 0,
+            configuration_mark: 0,
             num_interfaces_mark: None,
             num_endpoints_mark: None,
         }
@@ -125,20 +126,27 @@ impl<'a> DescriptorWriter<'a> {
         self.position = start + total_length;
     }
 
-    pub(crate) fn configuration(&mut self, config: &Config) {
+    pub(crate) fn configuration(
+        &mut self,
+        config: &Config,
+        value: u8,
+        max_power: u16,
+        configuration_string: Option<StringIndex>,
+    ) {
+        self.configuration_mark = self.position;
         self.num_interfaces_mark = Some(self.position + 4);
 
         self.write(
             descriptor_type::CONFIGURATION,
             &[
                 0,
-                0,                   // wTotalLength
-                0,                   // bNumInterfaces
-                CONFIGURATION_VALUE, // bConfigurationValue
-                0,                   // iConfiguration
+                0,                                          // wTotalLength
+                0,                                          // bNumInterfaces
+                value,                                      // bConfigurationValue
+                configuration_string.map_or(0, Into::into), // iConfiguration
                 0x80 | if config.self_powered { 0x40 } else { 0x00 }
                     | if config.supports_remote_wakeup { 0x20 } else { 0x00 }, // bmAttributes
-                (config.max_power / 2) as u8, // bMaxPower
+                (max_power / 2) as u8,                      // bMaxPower
             ],
             &[],
         );
@@ -150,8 +158,9 @@ impl<'a> DescriptorWriter<'a> {
     }
 
     pub(crate) fn end_configuration(&mut self) {
-        let position = self.position as u16;
-        self.buf[2..4].copy_from_slice(&position.to_le_bytes());
+        let mark = self.configuration_mark;
+        let total_length = (self.position - mark) as u16;
+        self.buf[mark + 2..mark + 4].copy_from_slice(&total_length.to_le_bytes());
     }
 
     /// Writes a interface association descriptor. Call from `UsbClass::get_configuration_descriptors`
@@ -323,7 +332,7 @@ impl<'a> DescriptorWriter<'a> {
 ///
 /// All device descriptors are always 18 bytes, so there's no need for
 /// a variable-length buffer or DescriptorWriter.
-pub(crate) fn device_descriptor(config: &Config) -> [u8; 18] {
+pub(crate) fn device_descriptor(config: &Config, num_configurations: u8) -> [u8; 18] {
     [
         18,   // bLength
         0x01, // bDescriptorType
@@ -342,7 +351,7 @@ pub(crate) fn device_descriptor(config: &Config) -> [u8; 18] {
         config.manufacturer.map_or(0, |_| 1),  // iManufacturer
         config.product.map_or(0, |_| 2),       // iProduct
         config.serial_number.map_or(0, |_| 3), // iSerialNumber
-        1,                                     // bNumConfigurations
+        num_configurations,                    // bNumConfigurations
     ]
 }
 
@@ -350,7 +359,7 @@ pub(crate) fn device_descriptor(config: &Config) -> [u8; 18] {
 ///
 /// All device qualifier descriptors are always 10 bytes, so there's no need for
 /// a variable-length buffer or DescriptorWriter.
-pub(crate) fn device_qualifier_descriptor(config: &Config) -> [u8; 10] {
+pub(crate) fn device_qualifier_descriptor(config: &Config, num_configurations: u8) -> [u8; 10] {
     [
         10,   // bLength
         0x06, // bDescriptorType
@@ -360,7 +369,7 @@ pub(crate) fn device_qualifier_descriptor(config: &Config) -> [u8; 10] {
         config.device_sub_class,            // bDeviceSubClass
         config.device_protocol,             // bDeviceProtocol
         config.max_packet_size_0,           // bMaxPacketSize0
-        1,                                  // bNumConfigurations
+        num_configurations,                 // bNumConfigurations
         0,                                  // Reserved
     ]
 }
@@ -389,6 +398,22 @@ fn encode_high_speed_interval(interval_ms: u8) -> u8 {
     exponent + 1
 }
 
+/// bmAttributes of the USB 2.0 extension capability, from the USB 2.0 LPM ECN and its BESL errata.
+fn usb_2_0_extension_attributes(lpm: Option<Lpm>) -> u32 {
+    let Some(lpm) = lpm else {
+        return 0;
+    };
+    // LPM, with BESL values instead of HIRD.
+    let mut attributes = 1 << 1 | 1 << 2;
+    if let Some(besl) = lpm.baseline_besl {
+        attributes |= 1 << 3 | (besl as u32) << 8;
+    }
+    if let Some(besl) = lpm.deep_besl {
+        attributes |= 1 << 4 | (besl as u32) << 12;
+    }
+    attributes
+}
+
 /// A writer for Binary Object Store descriptor.
 pub struct BosWriter<'a> {
     pub(crate) writer: DescriptorWriter<'a>,
@@ -403,7 +428,7 @@ impl<'a> BosWriter<'a> {
         }
     }
 
-    pub(crate) fn bos(&mut self) {
+    pub(crate) fn bos(&mut self, lpm: Option<Lpm>) {
         if (self.writer.buf.len() - self.writer.position) < 5 {
             return;
         }
@@ -417,7 +442,20 @@ impl<'a> BosWriter<'a> {
             &[],
         );
 
-        self.capability(capability_type::USB_2_0_EXTENSION, &[0; 4]);
+        self.capability(
+            capability_type::USB_2_0_EXTENSION,
+            &usb_2_0_extension_attributes(lpm).to_le_bytes(),
+        );
+    }
+
+    /// Rewrites the attributes of the USB 2.0 extension capability, which is the first one of the BOS.
+    pub(crate) fn set_lpm(&mut self, lpm: Option<Lpm>) {
+        // BOS header, then the capability header and its attributes.
+        const ATTRIBUTES: usize = 5 + 3;
+        if self.writer.position >= ATTRIBUTES + 4 {
+            self.writer.buf[ATTRIBUTES..ATTRIBUTES + 4]
+                .copy_from_slice(&usb_2_0_extension_attributes(lpm).to_le_bytes());
+        }
     }
 
     /// Writes capability descriptor to a BOS
@@ -21,13 +21,16 @@ mod config {
     include!(concat!(env!("OUT_DIR"), "/config.rs"));
 }
 
+use core::ops::Range;
+
 use embassy_futures::select::{Either, select};
+use embassy_time::{Duration, Timer};
 use heapless::Vec;
 
 pub use crate::builder::{
-    Builder, Config, FunctionBuilder, InterfaceAltBuilder, InterfaceBuilder, UsbDeviceSpeed, UsbVersion,
+    Builder, Config, FunctionBuilder, InterfaceAltBuilder, InterfaceBuilder, Lpm, UsbDeviceSpeed, UsbVersion,
 };
-use crate::config::{MAX_HANDLER_COUNT, MAX_INTERFACE_COUNT};
+use crate::config::{MAX_CONFIGURATION_COUNT, MAX_HANDLER_COUNT, MAX_INTERFACE_COUNT};
 use crate::control::{InResponse, OutResponse, Recipient, Request, RequestType};
 use crate::descriptor::{descriptor_type, lang_id};
 use crate::descriptor_reader::foreach_endpoint;
@@ -61,7 +64,7 @@ pub enum UsbDeviceState {
 #[derive(PartialEq, Eq, Copy, Clone, Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum RemoteWakeupError {
-    /// The USB device is not suspended, or remote wakeup was not enabled.
+    /// The USB device is not suspended or asleep, or the host didn't allow remote wakeup.
     InvalidState,
     /// The underlying driver doesn't support remote wakeup.
     Unsupported,
@@ -76,9 +79,13 @@ impl From<driver::Unsupported> for RemoteWakeupError {
 /// The bConfiguration value for the not configured state.
 pub const CONFIGURATION_NONE: u8 = 0;
 
-/// The bConfiguration value for the single configuration supported by this device.
+/// The bConfiguration value for the first configuration of the device, the only one unless more are added
+/// with [`Builder::configuration`].
 pub const CONFIGURATION_VALUE: u8 = 1;
 
+/// How long [`UsbDevice::disconnect`] keeps the device disconnected, for the host to notice it.
+const DISCONNECT_TIME: Duration = Duration::from_millis(100);
+
 const STRING_INDEX_MANUFACTURER: u8 = 1;
 const STRING_INDEX_PRODUCT: u8 = 2;
 const STRING_INDEX_SERIAL_NUMBER: u8 = 3;
@@ -107,6 +114,14 @@ pub trait Handler {
     /// Called when remote wakeup feature is enabled or disabled.
     fn remote_wakeup_enabled(&mut self, _enabled: bool) {}
 
+    /// Called when the host has put the link in the L1 sleep state of Link Power Management, or when the
+    /// link has left it.
+    ///
+    /// This only happens if LPM is enabled with [`Config::lpm`]. While asleep, the device keeps its
+    /// configuration and can draw its configured current, but it can lower its power consumption as long
+    /// as it can resume within its advertised BESL.
+    fn sleeping(&mut self, _sleeping: bool) {}
+
     /// Called when a "set alternate setting" control request is done on the interface.
     fn set_alternate_setting(&mut self, iface: InterfaceNumber, alternate_setting: u8) {
         let _ = iface;
@@ -191,6 +206,16 @@ struct Interface {
     num_alt_settings: u8,
 }
 
+/// Where a configuration's parts start. They end where the next configuration's start.
+struct Configuration {
+    /// Offset of the configuration descriptor in the configuration descriptor buffer.
+    descriptor_start: usize,
+    /// Index of the first interface in the device's interfaces.
+    first_interface: usize,
+    /// Index of the first handler in the device's handlers.
+    first_handler: usize,
+}
+
 /// A report of the used size of the runtime allocated buffers
 #[derive(PartialEq, Eq, Copy, Clone, Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -223,8 +248,14 @@ struct Inner<'d, D: Driver<'d>> {
     msos_descriptor: crate::msos::MsOsDescriptorSet<'d>,
 
     device_state: UsbDeviceState,
+    /// The bConfigurationValue selected by the host, or `CONFIGURATION_NONE`.
+    configuration: u8,
     suspended: bool,
     remote_wakeup_enabled: bool,
+    /// The link is in the L1 sleep state of LPM.
+    sleeping: bool,
+    /// The host allowed remote wakeup from the L1 sleep state.
+    sleep_remote_wakeup: bool,
     self_powered: bool,
 
     /// Our device address, or 0 if none.
@@ -235,6 +266,7 @@ struct Inner<'d, D: Driver<'d>> {
     set_address_pending: bool,
 
     interfaces: Vec<Interface, MAX_INTERFACE_COUNT>,
+    configurations: Vec<Configuration, MAX_CONFIGURATION_COUNT>,
     handlers: Vec<&'d mut dyn Handler, MAX_HANDLER_COUNT>,
 }
 
@@ -266,6 +298,8 @@ impl<'d, D: Driver<'d>> UsbDevice<'d, D> {
 
     /// Runs the `UsbDevice` until the bus is suspended.
     ///
+    /// The L1 sleep state of LPM doesn't end it, since the host can resume the link from it at any time.
+    ///
     /// This future may leave the bus in an invalid state if it is dropped.
     /// After dropping the future, [`UsbDevice::disable()`] should be called
     /// before calling any other `UsbDevice` methods to fully reset the
@@ -282,12 +316,15 @@ impl<'d, D: Driver<'d>> UsbDevice<'d, D> {
     }
 
     /// Disables the USB peripheral.
+    ///
+    /// To re-enumerate with different descriptors, use [`UsbDevice::disconnect()`] instead.
     pub async fn disable(&mut self) {
         if self.inner.device_state != UsbDeviceState::Disabled {
             self.inner.bus.disable().await;
             self.inner.device_state = UsbDeviceState::Disabled;
             self.inner.suspended = false;
             self.inner.remote_wakeup_enabled = false;
+            self.inner.sleeping = false;
 
             for h in &mut self.inner.handlers {
                 h.enabled(false);
@@ -295,6 +332,21 @@ impl<'d, D: Driver<'d>> UsbDevice<'d, D> {
         }
     }
 
+    /// Disconnects the device from the host, so that a new device can be built in its place.
+    ///
+    /// This disables the device, then waits long enough for the host to see it disconnect. Once it returns,
+    /// the driver and the buffers the device borrowed are free again: drop the classes of the device, and
+    /// build a new `UsbDevice` from a new [`Builder`] and driver. The host then enumerates it from scratch,
+    /// with its new descriptors. This is how a device switches between two sets of classes at runtime.
+    ///
+    /// This requires a driver whose `disable` detaches the device from the bus, by removing the pull-up
+    /// resistor on D+ or D-.
+    pub async fn disconnect(mut self) {
+        self.disable().await;
+        // Hosts see a disconnect after 2.5 us, but hubs debounce it.
+        Timer::after(DISCONNECT_TIME).await;
+    }
+
     /// Waits for a resume condition on the USB bus.
     ///
     /// This future is cancel-safe.
@@ -308,13 +360,23 @@ impl<'d, D: Driver<'d>> UsbDevice<'d, D> {
     /// Initiates a device remote wakeup on the USB bus.
     ///
     /// If the bus is not suspended or remote wakeup is not enabled, an error
-    /// will be returned.
+    /// will be returned. In the L1 sleep state of LPM, the host allows remote
+    /// wakeup or not each time it puts the link to sleep instead.
     ///
     /// This future may leave the bus in an inconsistent state if dropped.
     /// After dropping the future, [`UsbDevice::disable()`] should be called
     /// before calling any other `UsbDevice` methods to fully reset the peripheral.
     pub async fn remote_wakeup(&mut self) -> Result<(), RemoteWakeupError> {
-        if self.inner.suspended && self.inner.remote_wakeup_enabled {
+        if self.inner.sleeping && self.inner.sleep_remote_wakeup {
+            self.inner.bus.remote_wakeup().await?;
+            self.inner.sleeping = false;
+
+            for h in &mut self.inner.handlers {
+                h.sleeping(false);
+            }
+
+            Ok(())
+        } else if self.inner.suspended && self.inner.remote_wakeup_enabled {
             self.inner.bus.remote_wakeup().await?;
             self.inner.suspended = false;
 
@@ -420,20 +482,34 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
                 self.device_state = UsbDeviceState::Default;
                 self.suspended = false;
                 self.remote_wakeup_enabled = false;
+                self.sleeping = false;
                 self.address = 0;
 
+                self.configuration = CONFIGURATION_NONE;
+
                 for h in &mut self.handlers {
                     h.reset();
                 }
 
-                for (i, iface) in self.interfaces.iter_mut().enumerate() {
-                    iface.current_alt_setting = 0;
+                for index in 0..self.configurations.len() {
+                    let handlers = self.handler_range(index);
+                    let interfaces = self.interface_range(index);
+                    for (i, iface) in self.interfaces[interfaces].iter_mut().enumerate() {
+                        iface.current_alt_setting = 0;
 
-                    for h in &mut self.handlers {
-                        h.set_alternate_setting(InterfaceNumber::new(i as _), 0);
+                        for h in &mut self.handlers[handlers.clone()] {
+                            h.set_alternate_setting(InterfaceNumber::new(i as _), 0);
+                        }
                     }
                 }
             }
+            Event::Resume if self.sleeping => {
+                trace!("usb: wake");
+                self.sleeping = false;
+                for h in &mut self.handlers {
+                    h.sleeping(false);
+                }
+            }
             Event::Resume => {
                 trace!("usb: resume");
                 self.suspended = false;
@@ -443,11 +519,25 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
             }
             Event::Suspend => {
                 trace!("usb: suspend");
+                if self.sleeping {
+                    self.sleeping = false;
+                    for h in &mut self.handlers {
+                        h.sleeping(false);
+                    }
+                }
                 self.suspended = true;
                 for h in &mut self.handlers {
                     h.suspended(true);
                 }
             }
+            Event::Sleep { remote_wakeup, besl } => {
+                trace!("usb: sleep, remote wakeup {}, besl {}", remote_wakeup, besl);
+                self.sleeping = true;
+                self.sleep_remote_wakeup = remote_wakeup;
+                for h in &mut self.handlers {
+                    h.sleeping(true);
+                }
+            }
             Event::PowerDetected => {
                 trace!("usb: power detected");
                 self.bus.enable().await;
@@ -469,9 +559,54 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
         }
     }
 
+    /// Index of the configuration selected by the host, or of the first one if the device is not configured.
+    fn configuration_index(&self) -> usize {
+        self.configuration.max(CONFIGURATION_VALUE) as usize - 1
+    }
+
+    fn configuration_descriptor(&self, index: usize) -> Option<&'d [u8]> {
+        let start = self.configurations.get(index)?.descriptor_start;
+        let end = self
+            .configurations
+            .get(index + 1)
+            .map_or(self.config_descriptor.len(), |c| c.descriptor_start);
+        Some(&self.config_descriptor[start..end])
+    }
+
+    fn interface_range(&self, index: usize) -> Range<usize> {
+        let end = self
+            .configurations
+            .get(index + 1)
+            .map_or(self.interfaces.len(), |c| c.first_interface);
+        self.configurations[index].first_interface..end
+    }
+
+    fn handler_range(&self, index: usize) -> Range<usize> {
+        let end = self
+            .configurations
+            .get(index + 1)
+            .map_or(self.handlers.len(), |c| c.first_handler);
+        self.configurations[index].first_handler..end
+    }
+
+    /// Disables the endpoints of the current configuration, and notifies its handlers.
+    fn unconfigure(&mut self) {
+        let index = self.configuration_index();
+        self.configuration = CONFIGURATION_NONE;
+
+        foreach_endpoint(self.configuration_descriptor(index).unwrap(), |ep| {
+            self.bus.endpoint_set_enabled(ep.ep_address, false);
+        })
+        .unwrap();
+
+        let handlers = self.handler_range(index);
+        for h in &mut self.handlers[handlers] {
+            h.configured(false);
+        }
+    }
+
     fn handle_control_out(&mut self, req: Request, data: &[u8]) -> OutResponse {
         const CONFIGURATION_NONE_U16: u16 = CONFIGURATION_NONE as u16;
-        const CONFIGURATION_VALUE_U16: u16 = CONFIGURATION_VALUE as u16;
 
         match (req.request_type, req.recipient) {
             (RequestType::Standard, Recipient::Device) => match (req.request, req.value) {
@@ -498,41 +633,51 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
                     }
                     OutResponse::Accepted
                 }
-                (Request::SET_CONFIGURATION, CONFIGURATION_VALUE_U16) => {
-                    debug!("SET_CONFIGURATION: configured");
-                    self.device_state = UsbDeviceState::Configured;
-
-                    // Enable all endpoints of selected alt settings.
-                    foreach_endpoint(self.config_descriptor, |ep| {
-                        let iface = &self.interfaces[ep.interface.0 as usize];
-                        self.bus
-                            .endpoint_set_enabled(ep.ep_address, iface.current_alt_setting == ep.interface_alt);
-                    })
-                    .unwrap();
-
-                    // Notify handlers.
-                    for h in &mut self.handlers {
-                        h.configured(true);
-                    }
-
-                    OutResponse::Accepted
-                }
                 (Request::SET_CONFIGURATION, CONFIGURATION_NONE_U16) => {
                     if self.device_state != UsbDeviceState::Default {
                         debug!("SET_CONFIGURATION: unconfigured");
                         self.device_state = UsbDeviceState::Addressed;
+                        self.unconfigure();
+                    }
+                    OutResponse::Accepted
+                }
+                (Request::SET_CONFIGURATION, value) if (value as usize) <= self.configurations.len() => {
+                    let value = value as u8;
+                    debug!("SET_CONFIGURATION: configured {}", value);
 
-                        // Disable all endpoints.
-                        foreach_endpoint(self.config_descriptor, |ep| {
+                    // Leave the current configuration when switching to another one.
+                    if self.configuration != CONFIGURATION_NONE && self.configuration != value {
+                        self.unconfigure();
+                    }
+                    self.device_state = UsbDeviceState::Configured;
+                    self.configuration = value;
+
+                    // Enable all endpoints of selected alt settings. An endpoint can be shared by several
+                    // alt settings, so disable the others first.
+                    let index = self.configuration_index();
+                    let descriptor = self.configuration_descriptor(index).unwrap();
+                    let interfaces = &self.interfaces[self.interface_range(index)];
+                    foreach_endpoint(descriptor, |ep| {
+                        let iface = &interfaces[ep.interface.0 as usize];
+                        if iface.current_alt_setting != ep.interface_alt {
                             self.bus.endpoint_set_enabled(ep.ep_address, false);
-                        })
-                        .unwrap();
-
-                        // Notify handlers.
-                        for h in &mut self.handlers {
-                            h.configured(false);
                         }
+                    })
+                    .unwrap();
+                    foreach_endpoint(descriptor, |ep| {
+                        let iface = &interfaces[ep.interface.0 as usize];
+                        if iface.current_alt_setting == ep.interface_alt {
+                            self.bus.endpoint_set_enabled(ep.ep_address, true);
+                        }
+                    })
+                    .unwrap();
+
+                    // Notify handlers.
+                    let handlers = self.handler_range(index);
+                    for h in &mut self.handlers[handlers] {
+                        h.configured(true);
                     }
+
                     OutResponse::Accepted
                 }
                 // USB 2.0 debug devices use a standard device feature selector,
@@ -544,7 +689,11 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
             },
             (RequestType::Standard, Recipient::Interface) => {
                 let iface_num = InterfaceNumber::new(req.index as _);
-                let Some(iface) = self.interfaces.get_mut(iface_num.0 as usize) else {
+                let index = self.configuration_index();
+                let descriptor = self.configuration_descriptor(index).unwrap();
+                let handlers = self.handler_range(index);
+                let interfaces = self.interface_range(index);
+                let Some(iface) = self.interfaces[interfaces].get_mut(iface_num.0 as usize) else {
                     return OutResponse::Rejected;
                 };
 
@@ -559,18 +708,24 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
 
                         iface.current_alt_setting = new_altsetting;
 
-                        // Enable/disable EPs of this interface as needed.
-                        foreach_endpoint(self.config_descriptor, |ep| {
-                            if ep.interface == iface_num {
-                                self.bus
-                                    .endpoint_set_enabled(ep.ep_address, iface.current_alt_setting == ep.interface_alt);
+                        // Enable/disable EPs of this interface as needed, disabling first for endpoints
+                        // shared by several alt settings.
+                        foreach_endpoint(descriptor, |ep| {
+                            if ep.interface == iface_num && iface.current_alt_setting != ep.interface_alt {
+                                self.bus.endpoint_set_enabled(ep.ep_address, false);
+                            }
+                        })
+                        .unwrap();
+                        foreach_endpoint(descriptor, |ep| {
+                            if ep.interface == iface_num && iface.current_alt_setting == ep.interface_alt {
+                                self.bus.endpoint_set_enabled(ep.ep_address, true);
                             }
                         })
                         .unwrap();
 
                         // TODO check it is valid (not out of range)
 
-                        for h in &mut self.handlers {
+                        for h in &mut self.handlers[handlers] {
                             h.set_alternate_setting(iface_num, new_altsetting);
                         }
                         OutResponse::Accepted
@@ -612,7 +767,7 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
                 Request::GET_DESCRIPTOR => self.handle_get_descriptor(req, buf),
                 Request::GET_CONFIGURATION => {
                     let status = match self.device_state {
-                        UsbDeviceState::Configured => CONFIGURATION_VALUE,
+                        UsbDeviceState::Configured => self.configuration,
                         _ => CONFIGURATION_NONE,
                     };
                     buf[0] = status;
@@ -621,7 +776,8 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
                 _ => InResponse::Rejected,
             },
             (RequestType::Standard, Recipient::Interface) => {
-                let Some(iface) = self.interfaces.get_mut(req.index as usize) else {
+                let interfaces = self.interface_range(self.configuration_index());
+                let Some(iface) = self.interfaces[interfaces].get_mut(req.index as usize) else {
                     return InResponse::Rejected;
                 };
 
@@ -667,7 +823,8 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
     }
 
     fn handle_control_out_delegated(&mut self, req: Request, data: &[u8]) -> OutResponse {
-        for h in &mut self.handlers {
+        let handlers = self.handler_range(self.configuration_index());
+        for h in &mut self.handlers[handlers] {
             if let Some(res) = h.control_out(req, data) {
                 return res;
             }
@@ -680,7 +837,8 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
             core::mem::transmute(r)
         }
 
-        for h in &mut self.handlers {
+        let handlers = self.handler_range(self.configuration_index());
+        for h in &mut self.handlers[handlers] {
             if let Some(res) = h.control_in(req, buf) {
                 // safety: the borrow checker isn't smart enough to know this pattern (returning a
                 // borrowed value from inside the loop) is sound. Workaround by unsafely extending lifetime.
@@ -702,7 +860,10 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
         match dtype {
             descriptor_type::BOS => InResponse::Accepted(self.bos_descriptor),
             descriptor_type::DEVICE => InResponse::Accepted(&self.device_descriptor),
-            descriptor_type::CONFIGURATION => InResponse::Accepted(self.config_descriptor),
+            descriptor_type::CONFIGURATION => match self.configuration_descriptor(index as usize) {
+                Some(descriptor) => InResponse::Accepted(descriptor),
+                None => InResponse::Rejected,
+            },
             descriptor_type::STRING => {
                 if index == 0 {
                     buf[0] = 4; // len
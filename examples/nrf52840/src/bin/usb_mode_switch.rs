@@ -0,0 +1,197 @@
+//! This example switches the device between a USB serial port and a USB drive when button 1 is pressed.
+//!
+//! Each switch disconnects the device with `UsbDevice::disconnect`, then builds a new device with
+//! the other class, which the host enumerates from scratch.
+
+#![no_std]
+#![no_main]
+
+use core::future::Future;
+
+use defmt::{info, panic};
+use defmt_rtt as _;
+use embassy_executor::Spawner;
+use embassy_futures::join::join;
+use embassy_futures::select::select;
+use embassy_nrf::gpio::{Input, Pull};
+use embassy_nrf::usb::Driver;
+use embassy_nrf::usb::vbus_detect::{HardwareVbusDetect, VbusDetect};
+use embassy_nrf::{Peri, bind_interrupts, pac, peripherals, usb};
+use embassy_time::Timer;
+use embassy_usb::class::cdc_acm::{self, CdcAcmClass};
+use embassy_usb::class::msc::{self, BlockDevice, MscClass};
+use embassy_usb::driver::EndpointError;
+use embassy_usb::{Builder, Config, UsbDevice};
+use panic_probe as _;
+
+bind_interrupts!(struct Irqs {
+    USBD => usb::InterruptHandler<peripherals::USBD>;
+    CLOCK_POWER => usb::vbus_detect::InterruptHandler;
+});
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+enum Mode {
+    Serial,
+    Storage,
+}
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let mut p = embassy_nrf::init(Default::default());
+
+    info!("Enabling ext hfosc...");
+    pac::CLOCK.tasks_hfclkstart().write_value(1);
+    while pac::CLOCK.events_hfclkstarted().read() != 1 {}
+
+    let mut button = Input::new(p.P0_11, Pull::Up);
+    let mut disk = RamDisk { data: [0; DISK_SIZE] };
+
+    let mut mode = Mode::Serial;
+    loop {
+        info!("Starting in {} mode", mode);
+        run(mode, p.USBD.reborrow(), &mut button, &mut disk).await;
+
+        mode = match mode {
+            Mode::Serial => Mode::Storage,
+            Mode::Storage => Mode::Serial,
+        };
+    }
+}
+
+/// Builds a device with the class of `mode`, and runs it until the button is pressed.
+async fn run(mode: Mode, usbd: Peri<'_, peripherals::USBD>, button: &mut Input<'_>, disk: &mut RamDisk) {
+    // Create the driver, from the HAL.
+    let driver = Driver::new(usbd, Irqs, HardwareVbusDetect::new(Irqs));
+
+    // Create embassy-usb Config. Using a different product ID in each mode keeps the host from
+    // mixing up the drivers it binds to the device.
+    let (product_id, product) = match mode {
+        Mode::Serial => (0xcafe, "USB-serial example"),
+        Mode::Storage => (0xcaff, "USB drive example"),
+    };
+    let mut config = Config::new(0xc0de, product_id);
+    config.manufacturer = Some("Embassy");
+    config.product = Some(product);
+    config.serial_number = Some("12345678");
+    config.max_power = 100;
+    config.max_packet_size_0 = 64;
+
+    // Create embassy-usb DeviceBuilder using the driver and config.
+    // It needs some buffers for building the descriptors.
+    let mut config_descriptor = [0; 256];
+    let mut bos_descriptor = [0; 256];
+    let mut msos_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+
+    let mut serial_state = cdc_acm::State::new();
+    let mut storage_state = msc::State::new();
+
+    let mut builder = Builder::new(
+        driver,
+        config,
+        &mut config_descriptor,
+        &mut bos_descriptor,
+        &mut msos_descriptor,
+        &mut control_buf,
+    );
+
+    // Create the class of the mode on the builder, and run it.
+    match mode {
+        Mode::Serial => {
+            let mut class = CdcAcmClass::new(&mut builder, &mut serial_state, 64);
+            let mut usb = builder.build();
+
+            let echo_fut = async {
+                loop {
+                    class.wait_connection().await;
+                    info!("Connected");
+                    let _ = echo(&mut class).await;
+                    info!("Disconnected");
+                }
+            };
+            run_until_pressed(&mut usb, echo_fut, button).await;
+            usb.disconnect().await;
+        }
+        Mode::Storage => {
+            let mut class = MscClass::new(&mut builder, &mut storage_state, msc::Config::default(), 64, 1);
+            let mut usb = builder.build();
+
+            let mut buf = [0; BLOCK_SIZE];
+            let msc_fut = class.run(core::slice::from_mut(disk), &mut buf);
+            run_until_pressed(&mut usb, msc_fut, button).await;
+            usb.disconnect().await;
+        }
+    }
+}
+
+/// Runs the device and its class until the button is pressed.
+async fn run_until_pressed<'d, V: VbusDetect + 'd>(
+    usb: &mut UsbDevice<'d, Driver<'d, V>>,
+    class_fut: impl Future,
+    button: &mut Input<'_>,
+) {
+    let button_fut = async {
+        button.wait_for_low().await;
+        Timer::after_millis(50).await;
+        button.wait_for_high().await;
+    };
+    select(join(usb.run(), class_fut), button_fut).await;
+    info!("Switching mode");
+}
+
+struct Disconnected {}
+
+impl From<EndpointError> for Disconnected {
+    fn from(val: EndpointError) -> Self {
+        match val {
+            EndpointError::BufferOverflow => panic!("Buffer overflow"),
+            EndpointError::Disabled => Disconnected {},
+        }
+    }
+}
+
+async fn echo<'d, V: VbusDetect + 'd>(class: &mut CdcAcmClass<'d, Driver<'d, V>>) -> Result<(), Disconnected> {
+    let mut buf = [0; 64];
+    loop {
+        let n = class.read_packet(&mut buf).await?;
+        let data = &buf[..n];
+        info!("data: {:x}", data);
+        class.write_packet(data).await?;
+    }
+}
+
+const BLOCK_SIZE: usize = 512;
+const DISK_SIZE: usize = 64 * BLOCK_SIZE;
+
+/// A drive in RAM. The host has to format it before using it, and its contents are lost on reset.
+struct RamDisk {
+    data: [u8; DISK_SIZE],
+}
+
+impl BlockDevice for RamDisk {
+    type Error = ();
+
+    fn block_size(&self) -> u32 {
+        BLOCK_SIZE as u32
+    }
+
+    fn block_count(&self) -> u64 {
+        (DISK_SIZE / BLOCK_SIZE) as u64
+    }
+
+    async fn read(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), Self::Error> {
+        let start = lba as usize * BLOCK_SIZE;
+        let data = self.data.get(start..start + buf.len()).ok_or(())?;
+        buf.copy_from_slice(data);
+        Ok(())
+    }
+
+    async fn write(&mut self, lba: u64, data: &[u8]) -> Result<(), Self::Error> {
+        let start = lba as usize * BLOCK_SIZE;
+        self.data
+            .get_mut(start..start + data.len())
+            .ok_or(())?
+            .copy_from_slice(data);
+        Ok(())
+    }
+}
@@ -0,0 +1,149 @@
+//! This example shows how to make a USB to serial adapter with the RP2040 chip.
+//!
+//! The USB serial port is forwarded to UART0 on pins 0 and 1, with the baud rate, parity and stop
+//! bits set by the host. DTR is output on pin 2, and breaks requested by the host are sent on the UART.
+
+#![no_std]
+#![no_main]
+
+use defmt::{info, unwrap};
+use defmt_rtt as _;
+use embassy_executor::Spawner;
+use embassy_rp::bind_interrupts;
+use embassy_rp::gpio::{Level, Output};
+use embassy_rp::peripherals::{UART0, USB};
+use embassy_rp::uart::{self, BufferedInterruptHandler, BufferedUart, BufferedUartTx};
+use embassy_rp::usb::{Driver, InterruptHandler};
+use embassy_usb::UsbDevice;
+use embassy_usb::class::cdc_acm::bridge::{BridgeHandler, UartBridge};
+use embassy_usb::class::cdc_acm::{Break, CdcAcmClass, LineCoding, ParityType, State, StopBits};
+use panic_probe as _;
+use static_cell::StaticCell;
+
+bind_interrupts!(struct Irqs {
+    USBCTRL_IRQ => InterruptHandler<USB>;
+    UART0_IRQ => BufferedInterruptHandler<UART0>;
+});
+
+#[embassy_executor::main(executor = "embassy_rp::executor::Executor", entry = "cortex_m_rt::entry")]
+async fn main(spawner: Spawner) {
+    info!("Hello there!");
+
+    let p = embassy_rp::init(Default::default());
+
+    // Create the driver, from the HAL.
+    let driver = Driver::new(p.USB, Irqs);
+
+    // Create embassy-usb Config
+    let config = {
+        let mut config = embassy_usb::Config::new(0xc0de, 0xcafe);
+        config.manufacturer = Some("Embassy");
+        config.product = Some("USB-UART bridge example");
+        config.serial_number = Some("12345678");
+        config.max_power = 100;
+        config.max_packet_size_0 = 64;
+        config
+    };
+
+    // Create embassy-usb DeviceBuilder using the driver and config.
+    // It needs some buffers for building the descriptors.
+    let mut builder = {
+        static CONFIG_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+        static BOS_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+        static CONTROL_BUF: StaticCell<[u8; 64]> = StaticCell::new();
+
+        embassy_usb::Builder::new(
+            driver,
+            config,
+            CONFIG_DESCRIPTOR.init([0; 256]),
+            BOS_DESCRIPTOR.init([0; 256]),
+            &mut [], // no msos descriptors
+            CONTROL_BUF.init([0; 64]),
+        )
+    };
+
+    // Create classes on the builder.
+    let class = {
+        static STATE: StaticCell<State> = StaticCell::new();
+        let state = STATE.init(State::new());
+        CdcAcmClass::new_with_send_break(&mut builder, state, 64)
+    };
+
+    // Build the builder.
+    let usb = builder.build();
+
+    // Run the USB device.
+    spawner.spawn(unwrap!(usb_task(usb)));
+
+    // Create the UART.
+    static TX_BUF: StaticCell<[u8; 256]> = StaticCell::new();
+    let tx_buf = &mut TX_BUF.init([0; 256])[..];
+    static RX_BUF: StaticCell<[u8; 256]> = StaticCell::new();
+    let rx_buf = &mut RX_BUF.init([0; 256])[..];
+    let uart = BufferedUart::new(p.UART0, p.PIN_0, p.PIN_1, Irqs, tx_buf, rx_buf, uart::Config::default());
+    let (mut tx, mut rx) = uart.split();
+
+    let mut handler = Handler {
+        config: uart::Config::default(),
+        dtr: Output::new(p.PIN_2, Level::High),
+    };
+
+    // Forward the serial port to the UART.
+    let mut bridge = UartBridge::new(class);
+    let mut buf = [0; 128];
+    bridge.run(&mut tx, &mut rx, &mut handler, &mut buf).await;
+}
+
+type MyUsbDriver = Driver<'static, USB>;
+type MyUsbDevice = UsbDevice<'static, MyUsbDriver>;
+
+#[embassy_executor::task]
+async fn usb_task(mut usb: MyUsbDevice) -> ! {
+    usb.run().await
+}
+
+struct Handler {
+    config: uart::Config,
+    dtr: Output<'static>,
+}
+
+impl BridgeHandler<BufferedUartTx> for Handler {
+    fn config(&mut self, line_coding: &LineCoding) -> Option<uart::Config> {
+        info!("Line coding: {}", line_coding);
+        let mut config = self.config;
+        config.baudrate = line_coding.data_rate();
+        config.data_bits = match line_coding.data_bits() {
+            5 => uart::DataBits::DataBits5,
+            6 => uart::DataBits::DataBits6,
+            7 => uart::DataBits::DataBits7,
+            8 => uart::DataBits::DataBits8,
+            _ => return None,
+        };
+        config.parity = match line_coding.parity_type() {
+            ParityType::None => uart::Parity::ParityNone,
+            ParityType::Even => uart::Parity::ParityEven,
+            ParityType::Odd => uart::Parity::ParityOdd,
+            ParityType::Mark | ParityType::Space => return None,
+        };
+        config.stop_bits = match line_coding.stop_bits() {
+            StopBits::One => uart::StopBits::STOP1,
+            StopBits::Two => uart::StopBits::STOP2,
+            StopBits::OnePointFive => return None,
+        };
+        self.config = config;
+        Some(config)
+    }
+
+    fn control_lines(&mut self, dtr: bool, _rts: bool) {
+        // The modem control lines are active low.
+        self.dtr.set_level(Level::from(!dtr));
+    }
+
+    async fn send_break(&mut self, tx: &mut BufferedUartTx, request: Break) {
+        // The UART can only send breaks of a given length.
+        if let Break::Millis(ms) = request {
+            tx.send_break((ms as u64 * self.config.baudrate as u64 / 1000) as u32)
+                .await;
+        }
+    }
+}
@@ -0,0 +1,89 @@
+//! This example runs a USB serial port that echos, and exports it over USB/IP.
+//!
+//! Attach it to the local machine with:
+//!
+//! ```sh
+//! sudo modprobe vhci-hcd
+//! sudo usbip attach -r 127.0.0.1 -b 1-1
+//! ```
+
+use embassy_executor::Executor;
+use embassy_usb::UsbDevice;
+use embassy_usb::class::cdc_acm::{CdcAcmClass, State};
+use embassy_usb::driver::{EndpointError, Speed};
+use embassy_usb_usbip::Driver;
+use log::*;
+use static_cell::StaticCell;
+
+#[embassy_executor::task]
+async fn usb_task(mut usb: UsbDevice<'static, Driver>) -> ! {
+    usb.run().await
+}
+
+#[embassy_executor::task]
+async fn main_task(spawner: embassy_executor::Spawner) {
+    // Create the driver, listening on the default USB/IP port.
+    let driver = Driver::new("127.0.0.1:3240", Speed::Full).unwrap();
+
+    // Create embassy-usb Config
+    let mut config = embassy_usb::Config::new(0xc0de, 0xcafe);
+    config.manufacturer = Some("Embassy");
+    config.product = Some("USB-serial example");
+    config.serial_number = Some("12345678");
+    config.max_power = 100;
+    config.max_packet_size_0 = 64;
+
+    // Create embassy-usb DeviceBuilder using the driver and config.
+    // It needs some buffers for building the descriptors.
+    static CONFIG_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+    static BOS_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+    static CONTROL_BUF: StaticCell<[u8; 64]> = StaticCell::new();
+    let mut builder = embassy_usb::Builder::new(
+        driver,
+        config,
+        CONFIG_DESCRIPTOR.init([0; 256]),
+        BOS_DESCRIPTOR.init([0; 256]),
+        &mut [], // no msos descriptors
+        CONTROL_BUF.init([0; 64]),
+    );
+
+    // Create classes on the builder.
+    static STATE: StaticCell<State> = StaticCell::new();
+    let mut class = CdcAcmClass::new(&mut builder, STATE.init(State::new()), 64);
+
+    // Build the builder, and run the USB device.
+    let usb = builder.build();
+    spawner.spawn(usb_task(usb).unwrap());
+
+    // Do stuff with the class!
+    loop {
+        class.wait_connection().await;
+        info!("Connected");
+        let _ = echo(&mut class).await;
+        info!("Disconnected");
+    }
+}
+
+async fn echo(class: &mut CdcAcmClass<'static, Driver>) -> Result<(), EndpointError> {
+    let mut buf = [0; 64];
+    loop {
+        let n = class.read_packet(&mut buf).await?;
+        let data = &buf[..n];
+        info!("data: {:x?}", data);
+        class.write_packet(data).await?;
+    }
+}
+
+static EXECUTOR: StaticCell<Executor> = StaticCell::new();
+
+fn main() {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Debug)
+        .format_timestamp_nanos()
+        .init();
+
+    let executor = EXECUTOR.init(Executor::new());
+    executor.run(|spawner| {
+        spawner.spawn(main_task(spawner).unwrap());
+    });
+}
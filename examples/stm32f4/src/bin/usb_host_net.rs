@@ -0,0 +1,115 @@
+//! This example shows how to connect to a network through a USB Ethernet adapter, an LTE modem or
+//! a phone doing USB tethering.
+//!
+//! The device must support CDC-ECM, CDC-NCM or RNDIS. The address is obtained with DHCP.
+
+#![no_std]
+#![no_main]
+
+use defmt::*;
+use defmt_rtt as _;
+use embassy_executor::Spawner;
+use embassy_futures::select::select;
+use embassy_net::StackResources;
+use embassy_stm32::rng::{self, Rng};
+use embassy_stm32::time::Hertz;
+use embassy_stm32::usb::HostDriver;
+use embassy_stm32::{Config, bind_interrupts, peripherals, usb};
+use embassy_usb_host::class::net::{Device, NetHost, State, select_configuration};
+use embassy_usb_host::{BusRoute, BusState};
+use panic_probe as _;
+use static_cell::StaticCell;
+
+const MTU: usize = 1514;
+
+bind_interrupts!(struct Irqs {
+    OTG_FS => usb::HostInterruptHandler<peripherals::USB_OTG_FS>;
+    HASH_RNG => rng::InterruptHandler<peripherals::RNG>;
+});
+
+#[embassy_executor::task]
+async fn net_task(mut runner: embassy_net::Runner<'static, Device<'static, MTU>>) -> ! {
+    runner.run().await
+}
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    info!("USB Host Network example");
+
+    let mut config = Config::default();
+    {
+        use embassy_stm32::rcc::*;
+        config.rcc.hse = Some(Hse {
+            freq: Hertz(8_000_000),
+            mode: HseMode::Bypass,
+        });
+        config.rcc.pll_src = PllSource::Hse;
+        config.rcc.pll = Some(Pll {
+            prediv: PllPreDiv::Div4,
+            mul: PllMul::Mul168,
+            divp: Some(PllPDiv::Div2), // 168 MHz sysclk
+            divq: Some(PllQDiv::Div7), // 48 MHz USB clock
+            divr: None,
+        });
+        config.rcc.ahb_pre = AHBPrescaler::Div1;
+        config.rcc.apb1_pre = APBPrescaler::Div4;
+        config.rcc.apb2_pre = APBPrescaler::Div2;
+        config.rcc.sys = Sysclk::Pll1P;
+        config.rcc.mux.clk48sel = mux::Clk48sel::Pll1Q;
+    }
+    let p = embassy_stm32::init(config);
+
+    // Generate random seed
+    let mut rng = Rng::new(p.RNG, Irqs);
+    let mut seed = [0; 8];
+    unwrap!(rng.async_fill_bytes(&mut seed).await);
+    let seed = u64::from_le_bytes(seed);
+
+    // Create the host driver (FS mode)
+    let driver = HostDriver::new_fs_host(p.USB_OTG_FS, Irqs, p.PA12, p.PA11);
+
+    static BUS_STATE: BusState = BusState::new();
+    let (mut bus_ctrl, bus) = embassy_usb_host::bus(driver, &BUS_STATE);
+    info!("USB host initialized, waiting for device...");
+
+    // Wait for a device to connect
+    let speed = bus_ctrl.wait_for_connection().await;
+    info!("Device connected at speed {:?}", speed);
+
+    // Enumerate the device
+    let mut config_buf = [0u8; 512];
+    let (enum_info, _) = unwrap!(bus.enumerate(BusRoute::Direct(speed), &mut config_buf).await);
+    info!(
+        "Enumerated: VID={:04x} PID={:04x} addr={}",
+        enum_info.device_desc.vendor_id, enum_info.device_desc.product_id, enum_info.device_address
+    );
+
+    // Some adapters have their CDC configuration after a vendor one, activate it.
+    let config_len = unwrap!(select_configuration(&bus, &enum_info, &mut config_buf).await);
+    let net = unwrap!(NetHost::new(&bus, &config_buf[..config_len], &enum_info).await);
+    info!(
+        "{:?} device, MAC address {:02x}",
+        net.info().protocol,
+        net.mac_address()
+    );
+
+    static NET_STATE: StaticCell<State<MTU, 4, 4>> = StaticCell::new();
+    let (runner, device) = net.into_embassy_net_device(NET_STATE.init(State::new()));
+
+    // Init network stack
+    let config = embassy_net::Config::dhcpv4(Default::default());
+    static RESOURCES: StaticCell<StackResources<3>> = StaticCell::new();
+    let (stack, net_runner) = embassy_net::new(device, config, RESOURCES.init(StackResources::new()), seed);
+
+    spawner.spawn(unwrap!(net_task(net_runner)));
+
+    // Run the device until it is disconnected.
+    select(runner.run(), async {
+        stack.wait_config_up().await;
+        info!("IP address: {:?}", unwrap!(stack.config_v4()).address);
+        core::future::pending::<()>().await
+    })
+    .await;
+
+    info!("Device disconnected");
+}